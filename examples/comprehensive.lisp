@@ -31,8 +31,8 @@
              (list "Grade" "C" "Average"))))
 
 ; Area calculations
-(let ((length 10) (width 5) (height 3))
+(let ((len 10) (width 5) (height 3))
      (list 
-         (* length width)           ; Rectangle area
-         (* 0.5 length height)      ; Triangle area  
-         (* length width height)))  ; Volume
\ No newline at end of file
+         (* len width)           ; Rectangle area
+         (* 0.5 len height)      ; Triangle area  
+         (* len width height)))  ; Volume
\ No newline at end of file
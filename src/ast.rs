@@ -1,30 +1,559 @@
-use serde::{Deserialize, Serialize};
+use crate::symbol::{self, Symbol};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Byte range of an expression in the original source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Convert a byte offset into a 1-based (line, column) pair
+    pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+        let mut line = 1;
+        let mut column = 1;
+        for (pos, ch) in source.char_indices() {
+            if pos >= offset {
+                break;
+            }
+            if ch == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        (line, column)
+    }
+}
+
+/// Where each line of a text starts, to find the line and column of many offsets
+/// without scanning the text from the start for each
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        LineIndex { starts: std::iter::once(0).chain(text.match_indices('\n').map(|(pos, _)| pos + 1)).collect() }
+    }
+
+    /// `Span::line_col` of `offset` in `text`, the text the index was made from
+    pub fn line_col(&self, text: &str, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&start| start <= offset);
+        let start = self.starts[line - 1];
+        let column = text[start..].char_indices().take_while(|(pos, _)| start + pos < offset).count() + 1;
+        (line, column)
+    }
+}
+
+/// A `;` comment, up to but not including the end of its line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+}
+
+/// Source location and macro provenance of an AST node. The tree mirrors the
+/// node's `LispExpr::children`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpanTree {
+    /// Byte range in the source; for code a macro generated, the range of the macro call
+    pub span: Option<Span>,
+    /// 1-based line and column of `span.start`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// Macro whose expansion produced this node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expanded_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    pub children: Vec<SpanTree>,
+}
+
+impl SpanTree {
+    pub fn new(span: Span, children: Vec<SpanTree>) -> Self {
+        SpanTree {
+            span: Some(span),
+            line: None,
+            column: None,
+            expanded_from: None,
+            children,
+        }
+    }
+
+    pub fn map_spans(mut self, f: &impl Fn(Span) -> Span) -> Self {
+        self.span = self.span.map(f);
+        let children = std::mem::take(&mut self.children);
+        self.children = grow_stack(|| children.into_iter().map(|child| child.map_spans(f)).collect());
+        self
+    }
+
+    /// Fill in line and column numbers from the source the spans point into
+    pub fn with_lines(mut self, source: &str) -> Self {
+        if let Some(span) = self.span {
+            let (line, column) = Span::line_col(source, span.start);
+            self.line = Some(line);
+            self.column = Some(column);
+        }
+        let children = std::mem::take(&mut self.children);
+        self.children = grow_stack(|| children.into_iter().map(|child| child.with_lines(source)).collect());
+        self
+    }
+
+    /// The tree for `rewritten`, a transformed or macro-expanded `original` this tree
+    /// describes. Unchanged nodes keep their spans; code generated by one of `macros`
+    /// points at the macro call, except for arguments the macro passed through.
+    pub fn follow(&self, original: &LispExpr, rewritten: &LispExpr, macros: &HashSet<String>) -> SpanTree {
+        grow_stack(|| self.follow_node(original, rewritten, macros))
+    }
+
+    fn follow_node(&self, original: &LispExpr, rewritten: &LispExpr, macros: &HashSet<String>) -> SpanTree {
+        if original == rewritten {
+            return self.clone();
+        }
+
+        let call = match original {
+            LispExpr::List(elements) => elements
+                .first()
+                .and_then(|head| head.as_symbol())
+                .filter(|name| macros.contains(*name))
+                .map(|name| (name, &elements[1..], self.children.get(1..).unwrap_or_default())),
+            LispExpr::MacroCall { name, args } => Some((name.as_str(), &args[..], &self.children[..])),
+            _ => None,
+        };
+        if let Some((name, args, arg_trees)) = call {
+            let args: Vec<(&LispExpr, &SpanTree)> = args.iter().zip(arg_trees).collect();
+            return self.generated(rewritten, Some(name), &args);
+        }
+
+        let (from, to) = (original.children(), rewritten.children());
+        let same_shape = std::mem::discriminant(original) == std::mem::discriminant(rewritten)
+            && from.len() == to.len()
+            && from.len() == self.children.len();
+        let children = if same_shape {
+            self.children
+                .iter()
+                .zip(from.into_iter().zip(to))
+                .map(|(tree, (from, to))| tree.follow(from, to, macros))
+                .collect()
+        } else {
+            // Rewritten beyond recognition: attribute everything to this node
+            to.into_iter().map(|child| self.generated(child, None, &[])).collect()
+        };
+        SpanTree {
+            children,
+            expanded_from: None,
+            ..self.clone()
+        }
+    }
+
+    /// Tree for `expr` produced at this node, reusing the trees of passed-through `args`
+    fn generated(&self, expr: &LispExpr, origin: Option<&str>, args: &[(&LispExpr, &SpanTree)]) -> SpanTree {
+        if let Some((_, tree)) = args.iter().find(|(arg, _)| *arg == expr) {
+            return (*tree).clone();
+        }
+        grow_stack(|| SpanTree {
+            span: self.span,
+            line: self.line,
+            column: self.column,
+            expanded_from: origin.map(str::to_string),
+            children: expr.children().into_iter().map(|child| self.generated(child, origin, args)).collect(),
+        })
+    }
+}
+
+impl Clone for SpanTree {
+    fn clone(&self) -> Self {
+        grow_stack(|| SpanTree {
+            span: self.span,
+            line: self.line,
+            column: self.column,
+            expanded_from: self.expanded_from.clone(),
+            children: self.children.clone(),
+        })
+    }
+}
+
+/// Dropped without recursion, like `LispExpr`
+impl Drop for SpanTree {
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.children);
+        while let Some(mut tree) = pending.pop() {
+            pending.append(&mut tree.children);
+        }
+    }
+}
+
+/// Comments attached to an AST node and its descendants. Like `SpanTree`, the
+/// tree mirrors the node's `LispExpr::children`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommentTree {
+    /// Comments on their own lines before the node
+    pub leading: Vec<Comment>,
+    /// A comment after the node on the line it ends on
+    pub trailing: Option<Comment>,
+    /// Comments after a form's last element, before its closing paren
+    pub inner: Vec<Comment>,
+    pub children: Vec<CommentTree>,
+}
+
+impl Drop for CommentTree {
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.children);
+        while let Some(mut tree) = pending.pop() {
+            pending.append(&mut tree.children);
+        }
+    }
+}
+
+impl CommentTree {
+    /// Attach the comments inside `tree`'s span to its descendants: a comment on the
+    /// line an element ends on trails it, any other leads the next element, and one
+    /// after the last element is `inner`. `tree`'s own leading and trailing comments
+    /// are left to the caller (see `parser::parse_with_comments`).
+    pub fn attach(tree: &SpanTree, comments: &[Comment], source: &str) -> Self {
+        let mut attached = CommentTree::default();
+        attached.children =
+            grow_stack(|| tree.children.iter().map(|child| CommentTree::attach(child, comments, source)).collect());
+        let Some(span) = tree.span else {
+            return attached;
+        };
+        let contains = |outer: Span, comment: &Comment| outer.start < comment.span.start && comment.span.end < outer.end;
+        let child_spans: Vec<Option<Span>> = tree.children.iter().map(|child| child.span).collect();
+
+        for comment in comments {
+            if !contains(span, comment) || child_spans.iter().flatten().any(|child| contains(*child, comment)) {
+                continue;
+            }
+            let previous = child_spans
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(index, child)| child.filter(|child| child.end <= comment.span.start).map(|child| (index, child)));
+            let next = child_spans.iter().position(|child| child.is_some_and(|child| child.start >= comment.span.end));
+            match (previous, next) {
+                (Some((index, child)), _) if !source[child.end..comment.span.start].contains('\n') => {
+                    attached.children[index].trailing = Some(comment.clone());
+                }
+                (_, Some(index)) => attached.children[index].leading.push(comment.clone()),
+                (_, None) => attached.inner.push(comment.clone()),
+            }
+        }
+        attached
+    }
+
+    /// Whether any comment sits inside the node, as opposed to before or after it
+    pub fn has_inner_comments(&self) -> bool {
+        grow_stack(|| {
+            !self.inner.is_empty()
+                || self.children.iter().any(|child| {
+                    !child.leading.is_empty() || child.trailing.is_some() || child.has_inner_comments()
+                })
+        })
+    }
+}
+
+/// Every variant holding expressions (de)serializes them through `serialize_stacked`
+/// and `deserialize_stacked`, since serde recurses once per level of nesting
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub enum LispExpr {
     Number(f64),
-    Symbol(String),
+    Symbol(Symbol),
     String(String),
-    List(Vec<LispExpr>),
+    /// Shared, so cloning a list copies no elements
+    #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    List(Rc<[LispExpr]>),
     Bool(bool),
+    #[default]
     Nil,
     Macro {
-        name: String,
-        parameters: Vec<String>,
-        body: Box<LispExpr>,
+        name: Symbol,
+        parameters: Vec<Symbol>,
+        #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+        body: Rc<LispExpr>,
+        /// `(declare ...)` forms between the parameters and the body
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        declarations: Vec<LispExpr>,
     },
     MacroCall {
-        name: String,
+        name: Symbol,
+        #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
         args: Vec<LispExpr>,
     },
-    Quote(Box<LispExpr>),
-    Quasiquote(Box<LispExpr>),
-    Unquote(Box<LispExpr>),
-    Splice(Box<LispExpr>),
-    Gensym(String),
+    #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    Quote(Rc<LispExpr>),
+    #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    Quasiquote(Rc<LispExpr>),
+    #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    Unquote(Rc<LispExpr>),
+    #[serde(serialize_with = "serialize_stacked", deserialize_with = "deserialize_stacked")]
+    Splice(Rc<LispExpr>),
+    Gensym(Symbol),
+}
+
+fn serialize_stacked<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    grow_stack(|| value.serialize(serializer))
+}
+
+fn deserialize_stacked<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    grow_stack(|| T::deserialize(deserializer))
+}
+
+/// Run `f`, first switching to a fresh stack segment if this one is nearly used up.
+/// Recursive walks over the AST call this once per level so that machine-generated
+/// input nested hundreds of thousands deep cannot overflow the stack.
+pub(crate) fn grow_stack<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, f)
+}
+
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT: usize = 4 * 1024 * 1024;
+
+impl Clone for LispExpr {
+    fn clone(&self) -> Self {
+        grow_stack(|| match self {
+            LispExpr::Number(n) => LispExpr::Number(*n),
+            LispExpr::Symbol(s) => LispExpr::Symbol(s.clone()),
+            LispExpr::String(s) => LispExpr::String(s.clone()),
+            LispExpr::List(elements) => LispExpr::List(elements.clone()),
+            LispExpr::Bool(b) => LispExpr::Bool(*b),
+            LispExpr::Nil => LispExpr::Nil,
+            LispExpr::Macro { name, parameters, body, declarations } => LispExpr::Macro {
+                name: name.clone(),
+                parameters: parameters.clone(),
+                body: body.clone(),
+                declarations: declarations.clone(),
+            },
+            LispExpr::MacroCall { name, args } => LispExpr::MacroCall { name: name.clone(), args: args.clone() },
+            LispExpr::Quote(inner) => LispExpr::Quote(inner.clone()),
+            LispExpr::Quasiquote(inner) => LispExpr::Quasiquote(inner.clone()),
+            LispExpr::Unquote(inner) => LispExpr::Unquote(inner.clone()),
+            LispExpr::Splice(inner) => LispExpr::Splice(inner.clone()),
+            LispExpr::Gensym(s) => LispExpr::Gensym(s.clone()),
+        })
+    }
+}
+
+impl PartialEq for LispExpr {
+    fn eq(&self, other: &LispExpr) -> bool {
+        grow_stack(|| match (self, other) {
+            (LispExpr::Number(a), LispExpr::Number(b)) => a == b,
+            (LispExpr::Symbol(a), LispExpr::Symbol(b)) | (LispExpr::Gensym(a), LispExpr::Gensym(b)) => a == b,
+            (LispExpr::String(a), LispExpr::String(b)) => a == b,
+            (LispExpr::List(a), LispExpr::List(b)) => a == b,
+            (LispExpr::Bool(a), LispExpr::Bool(b)) => a == b,
+            (LispExpr::Nil, LispExpr::Nil) => true,
+            (
+                LispExpr::Macro { name, parameters, body, declarations },
+                LispExpr::Macro { name: other_name, parameters: other_parameters, body: other_body, declarations: other_declarations },
+            ) => name == other_name && parameters == other_parameters && body == other_body && declarations == other_declarations,
+            (LispExpr::MacroCall { name, args }, LispExpr::MacroCall { name: other_name, args: other_args }) => {
+                name == other_name && args == other_args
+            }
+            (LispExpr::Quote(a), LispExpr::Quote(b))
+            | (LispExpr::Quasiquote(a), LispExpr::Quasiquote(b))
+            | (LispExpr::Unquote(a), LispExpr::Unquote(b))
+            | (LispExpr::Splice(a), LispExpr::Splice(b)) => a == b,
+            _ => false,
+        })
+    }
+}
+
+/// Move the expression out of `inner`, cloning it only if the `Rc` is shared. Code
+/// that consumes an expression uses this to take apart quote and macro nodes, which
+/// can't be destructured by value since `LispExpr` implements `Drop`.
+pub fn take_rc(inner: &mut Rc<LispExpr>) -> LispExpr {
+    match Rc::get_mut(inner) {
+        Some(expr) => std::mem::take(expr),
+        None => LispExpr::clone(inner),
+    }
+}
+
+/// Move the elements out of `elements`, cloning them only if the list is shared, like
+/// `take_rc` does for quote and macro nodes
+pub fn take_list(elements: &mut Rc<[LispExpr]>) -> Vec<LispExpr> {
+    match Rc::get_mut(elements) {
+        Some(elements) => elements.iter_mut().map(std::mem::take).collect(),
+        None => elements.to_vec(),
+    }
+}
+
+/// Read-only traversal of an expression tree. Implement `visit_expr`, handle the
+/// nodes the pass cares about, and call `walk_expr` to go on into the rest; the
+/// default visits every node. `'ast` lets a visitor keep references into the tree.
+pub trait Visitor<'ast> {
+    fn visit_expr(&mut self, expr: &'ast LispExpr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visit each of `expr`'s children
+pub fn walk_expr<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, expr: &'ast LispExpr) {
+    grow_stack(|| {
+        for child in expr.children() {
+            visitor.visit_expr(child);
+        }
+    })
+}
+
+/// In-place rewriting traversal, like `Visitor` with mutable access. Shared
+/// subtrees are copied before they are visited, so other owners never see changes.
+pub trait VisitorMut {
+    fn visit_expr_mut(&mut self, expr: &mut LispExpr) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+/// Visit each of `expr`'s children mutably
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut LispExpr) {
+    grow_stack(|| match expr {
+        LispExpr::List(elements) => {
+            for element in Rc::make_mut(elements) {
+                visitor.visit_expr_mut(element);
+            }
+        }
+        LispExpr::MacroCall { args, .. } => {
+            for arg in args {
+                visitor.visit_expr_mut(arg);
+            }
+        }
+        LispExpr::Quote(inner)
+        | LispExpr::Quasiquote(inner)
+        | LispExpr::Unquote(inner)
+        | LispExpr::Splice(inner)
+        | LispExpr::Macro { body: inner, .. } => visitor.visit_expr_mut(Rc::make_mut(inner)),
+        _ => {}
+    })
+}
+
+/// Traversal that consumes a tree and builds a new one. Implement `fold_expr`,
+/// returning replacements for the nodes the pass rewrites and `fold_children` of
+/// the rest; the default rebuilds the tree unchanged.
+pub trait Fold {
+    fn fold_expr(&mut self, expr: LispExpr) -> LispExpr {
+        fold_children(self, expr)
+    }
+}
+
+/// `expr` with each of its children replaced by its fold
+pub fn fold_children<F: Fold + ?Sized>(folder: &mut F, mut expr: LispExpr) -> LispExpr {
+    grow_stack(|| match &mut expr {
+        LispExpr::List(elements) => {
+            *elements = take_list(elements).into_iter().map(|element| folder.fold_expr(element)).collect();
+        }
+        LispExpr::MacroCall { args, .. } => {
+            *args = std::mem::take(args).into_iter().map(|arg| folder.fold_expr(arg)).collect();
+        }
+        LispExpr::Quote(inner)
+        | LispExpr::Quasiquote(inner)
+        | LispExpr::Unquote(inner)
+        | LispExpr::Splice(inner)
+        | LispExpr::Macro { body: inner, .. } => *inner = Rc::new(folder.fold_expr(take_rc(inner))),
+        _ => {}
+    });
+    expr
+}
+
+/// The derived drop would recurse once per nesting level; instead subexpressions
+/// are moved onto a heap stack and dropped one at a time
+impl Drop for LispExpr {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut expr) = pending.pop() {
+            expr.take_children(&mut pending);
+        }
+    }
 }
 
 impl LispExpr {
+    /// Move the subexpressions this node owns outright into `pending`
+    fn take_children(&mut self, pending: &mut Vec<LispExpr>) {
+        match self {
+            LispExpr::List(elements) => {
+                if let Some(elements) = Rc::get_mut(elements) {
+                    pending.extend(elements.iter_mut().map(std::mem::take));
+                }
+            }
+            LispExpr::MacroCall { args, .. } => pending.append(args),
+            LispExpr::Quote(inner)
+            | LispExpr::Quasiquote(inner)
+            | LispExpr::Unquote(inner)
+            | LispExpr::Splice(inner)
+            | LispExpr::Macro { body: inner, .. } => {
+                if let Some(inner) = Rc::get_mut(inner) {
+                    pending.push(std::mem::take(inner));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Direct subexpressions: list elements, the quoted form, a macro's body, or call arguments
+    pub fn children(&self) -> Vec<&LispExpr> {
+        match self {
+            LispExpr::List(elements) => elements.iter().collect(),
+            LispExpr::MacroCall { args, .. } => args.iter().collect(),
+            LispExpr::Quote(inner)
+            | LispExpr::Quasiquote(inner)
+            | LispExpr::Unquote(inner)
+            | LispExpr::Splice(inner)
+            | LispExpr::Macro { body: inner, .. } => vec![inner],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Estimated bytes the expression occupies: a `LispExpr` per node, plus the strings
+    /// it owns and a macro's parameter list. Names are interned, so they add nothing.
+    pub fn footprint(&self) -> usize {
+        struct Footprint(usize);
+
+        impl Visitor<'_> for Footprint {
+            fn visit_expr(&mut self, expr: &LispExpr) {
+                self.0 += std::mem::size_of::<LispExpr>()
+                    + match expr {
+                        LispExpr::String(s) => s.len(),
+                        LispExpr::Macro { parameters, declarations, .. } => {
+                            parameters.len() * std::mem::size_of::<Symbol>() + declarations.iter().map(LispExpr::footprint).sum::<usize>()
+                        }
+                        _ => 0,
+                    };
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut footprint = Footprint(0);
+        footprint.visit_expr(self);
+        footprint.0
+    }
+
+    /// Number of expressions in the tree, counting this one
+    pub fn node_count(&self) -> usize {
+        struct NodeCount(usize);
+
+        impl Visitor<'_> for NodeCount {
+            fn visit_expr(&mut self, expr: &LispExpr) {
+                self.0 += 1;
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut count = NodeCount(0);
+        count.visit_expr(self);
+        count.0
+    }
+
     pub fn is_atom(&self) -> bool {
         matches!(self, 
             LispExpr::Number(_) | 
@@ -47,7 +576,7 @@ impl LispExpr {
         }
     }
     
-    pub fn as_list(&self) -> Option<&Vec<LispExpr>> {
+    pub fn as_list(&self) -> Option<&[LispExpr]> {
         match self {
             LispExpr::List(l) => Some(l),
             _ => None,
@@ -71,9 +600,9 @@ impl LispExpr {
         )
     }
     
-    pub fn as_macro(&self) -> Option<(&str, &Vec<String>, &LispExpr)> {
+    pub fn as_macro(&self) -> Option<(&str, &[Symbol], &LispExpr)> {
         match self {
-            LispExpr::Macro { name, parameters, body } => Some((name, parameters, body)),
+            LispExpr::Macro { name, parameters, body, .. } => Some((name, parameters, body)),
             _ => None,
         }
     }
@@ -119,18 +648,310 @@ impl LispExpr {
             _ => None,
         }
     }
+
+    /// Pretty-prints the expression as Lisp source that parses back to the same AST
+    /// (gensyms come back as plain symbols). Forms wider than `SOURCE_WIDTH` are
+    /// broken across lines, with bodies of defining forms indented by two spaces.
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        self.write_source(&mut out, 0);
+        out
+    }
+
+    /// `closing` is the number of parens that will follow on the same line
+    pub(crate) fn write_source(&self, out: &mut String, closing: usize) {
+        grow_stack(|| self.write_source_unstacked(out, closing))
+    }
+
+    fn write_source_unstacked(&self, out: &mut String, closing: usize) {
+        let column = out.len() - out.rfind('\n').map_or(0, |i| i + 1);
+        if let Some(flat) = self.flat_within(SOURCE_WIDTH.saturating_sub(column + closing)) {
+            out.push_str(&flat);
+            return;
+        }
+
+        match self {
+            LispExpr::Quote(inner) => write_prefixed(out, "'", inner, closing),
+            LispExpr::Quasiquote(inner) => write_prefixed(out, "`", inner, closing),
+            LispExpr::Unquote(inner) => write_prefixed(out, ",", inner, closing),
+            LispExpr::Splice(inner) => write_prefixed(out, ",@", inner, closing),
+            LispExpr::Macro { name, parameters, body, declarations } => {
+                out.push_str(&format!("(defmacro {} ({})", name, symbol::join(parameters, " ")));
+                let forms: Vec<LispExpr> = declarations.iter().chain([body.as_ref()]).cloned().collect();
+                write_body(out, column, &forms, closing);
+            }
+            LispExpr::MacroCall { name, args } => write_call(out, column, name, args, closing),
+            LispExpr::List(elements) => match elements.split_first() {
+                Some((LispExpr::Symbol(head), args)) => write_call(out, column, head, args, closing),
+                _ => {
+                    out.push('(');
+                    write_aligned(out, elements, column + 1, closing);
+                }
+            },
+            _ => out.push_str(&self.to_string()),
+        }
+    }
+
+    /// The expression on one line, if that is at most `width` bytes. Printing stops
+    /// once it is too wide, so checking every level of a deep form stays linear.
+    fn flat_within(&self, width: usize) -> Option<String> {
+        struct Bounded {
+            out: String,
+            width: usize,
+        }
+        impl fmt::Write for Bounded {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                if self.out.len() + s.len() > self.width {
+                    return Err(fmt::Error);
+                }
+                self.out.push_str(s);
+                Ok(())
+            }
+        }
+        let mut bounded = Bounded { out: String::new(), width };
+        fmt::write(&mut bounded, format_args!("{}", self)).ok().map(|_| bounded.out)
+    }
+}
+
+/// Line width `LispExpr::to_source` wraps at
+pub const SOURCE_WIDTH: usize = 80;
+
+/// Number of arguments kept on the first line before a form's indented body
+pub(crate) fn distinguished_args(head: &str) -> Option<usize> {
+    match head {
+        "defun" | "defmacro" => Some(2),
+        "lambda" | "let" | "let*" | "letrec" | "when" | "unless" | "while" | "dotimes" | "dolist" | "declare" => Some(1),
+        "progn" | "begin" => Some(0),
+        _ => None,
+    }
+}
+
+fn write_prefixed(out: &mut String, prefix: &str, inner: &LispExpr, closing: usize) {
+    out.push_str(prefix);
+    inner.write_source(out, closing);
+}
+
+/// `(head a b)` as `(head a` with the rest aligned under `a`, or body-style for defining forms
+fn write_call(out: &mut String, column: usize, head: &str, args: &[LispExpr], closing: usize) {
+    out.push('(');
+    out.push_str(head);
+    match distinguished_args(head) {
+        Some(count) => {
+            let count = count.min(args.len());
+            for (i, arg) in args[..count].iter().enumerate() {
+                out.push(' ');
+                let last = i + 1 == args.len();
+                arg.write_source(out, if last { closing + 1 } else { 0 });
+            }
+            if count == args.len() {
+                out.push(')');
+            } else {
+                write_body(out, column, &args[count..], closing);
+            }
+        }
+        None if args.is_empty() => out.push(')'),
+        None => {
+            out.push(' ');
+            write_aligned(out, args, column + head.len() + 2, closing);
+        }
+    }
+}
+
+fn write_body(out: &mut String, column: usize, body: &[LispExpr], closing: usize) {
+    for (i, expr) in body.iter().enumerate() {
+        out.push('\n');
+        out.push_str(&" ".repeat(column + 2));
+        expr.write_source(out, if i + 1 == body.len() { closing + 1 } else { 0 });
+    }
+    out.push(')');
+}
+
+/// Elements one per line starting at `column`, then the closing paren
+fn write_aligned(out: &mut String, elements: &[LispExpr], column: usize, closing: usize) {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+            out.push_str(&" ".repeat(column));
+        }
+        element.write_source(out, if i + 1 == elements.len() { closing + 1 } else { 0 });
+    }
+    out.push(')');
+}
+
+/// Prints the expression back as Lisp source
+impl fmt::Display for LispExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        grow_stack(|| match self {
+            LispExpr::Number(n) => write!(f, "{}", n),
+            LispExpr::Symbol(s) | LispExpr::Gensym(s) => write!(f, "{}", s),
+            LispExpr::String(s) => {
+                write!(f, "\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        _ => write!(f, "{}", ch)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            LispExpr::Bool(b) => write!(f, "{}", b),
+            LispExpr::Nil => write!(f, "nil"),
+            LispExpr::List(elements) => {
+                write!(f, "(")?;
+                for (i, elem) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, ")")
+            }
+            LispExpr::Macro { name, parameters, body, declarations } => {
+                write!(f, "(defmacro {} ({})", name, symbol::join(parameters, " "))?;
+                for declaration in declarations {
+                    write!(f, " {}", declaration)?;
+                }
+                write!(f, " {})", body)
+            }
+            LispExpr::MacroCall { name, args } => {
+                write!(f, "({}", name)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            LispExpr::Quote(expr) => write!(f, "'{}", expr),
+            LispExpr::Quasiquote(expr) => write!(f, "`{}", expr),
+            LispExpr::Unquote(expr) => write!(f, ",{}", expr),
+            LispExpr::Splice(expr) => write!(f, ",@{}", expr),
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_to_source_round_trips() {
+        let source = "(defmacro unless (c &rest body) `(if ,c nil (progn ,@body)))\n\
+                      (defun describe (items) (dolist (item items) (print (list 'item item \"a \\\"quoted\\\" name\" (* 1.5 (length items)) true nil))))";
+        let program = parse(tokenize(source).unwrap()).unwrap();
+        for expr in &program {
+            let printed = expr.to_source();
+            assert!(printed.lines().all(|line| line.len() <= SOURCE_WIDTH), "{}", printed);
+            assert_eq!(&parse(tokenize(&printed).unwrap()).unwrap()[0], expr);
+        }
+
+        assert_eq!(
+            program[1].to_source(),
+            "(defun describe (items)\n  \
+               (dolist (item items)\n    \
+                 (print (list 'item\n                 \
+                              item\n                 \
+                              \"a \\\"quoted\\\" name\"\n                 \
+                              (* 1.5 (length items))\n                 \
+                              true\n                 \
+                              nil))))"
+        );
+        assert_eq!(program[0].to_source(), "(defmacro unless (c &rest body) `(if ,c nil (progn ,@body)))");
+    }
+
+    #[test]
+    fn test_visitors_and_fold() {
+        struct CountSymbols(usize);
+        impl Visitor<'_> for CountSymbols {
+            fn visit_expr(&mut self, expr: &LispExpr) {
+                match expr {
+                    LispExpr::Symbol(_) => self.0 += 1,
+                    LispExpr::Quote(_) => {}
+                    _ => walk_expr(self, expr),
+                }
+            }
+        }
+        struct Double;
+        impl VisitorMut for Double {
+            fn visit_expr_mut(&mut self, expr: &mut LispExpr) {
+                match expr {
+                    LispExpr::Number(n) => *n *= 2.0,
+                    _ => walk_expr_mut(self, expr),
+                }
+            }
+        }
+        struct Rename;
+        impl Fold for Rename {
+            fn fold_expr(&mut self, expr: LispExpr) -> LispExpr {
+                match expr.as_symbol() {
+                    Some("x") => LispExpr::Symbol("y".into()),
+                    _ => fold_children(self, expr),
+                }
+            }
+        }
+
+        let program = parse(tokenize("(defmacro m (x) `(+ ,x 1)) (f x '(x 2) (g x 3))").unwrap()).unwrap();
+        let mut counter = CountSymbols(0);
+        for expr in &program {
+            counter.visit_expr(expr);
+        }
+        assert_eq!(counter.0, 6);
+
+        let mut doubled = program[1].clone();
+        Double.visit_expr_mut(&mut doubled);
+        assert_eq!(doubled.to_string(), "(f x '(x 4) (g x 6))");
+        assert_eq!(program[1].to_string(), "(f x '(x 2) (g x 3))");
+
+        assert_eq!(Rename.fold_expr(program[0].clone()).to_string(), "(defmacro m (x) `(+ ,y 1))");
+        assert_eq!(Rename.fold_expr(program[1].clone()).to_string(), "(f y '(y 2) (g y 3))");
+    }
+
+    #[test]
+    fn test_footprint() {
+        let node = std::mem::size_of::<LispExpr>();
+        let program = parse(tokenize("(f \"abc\" 'x) (defmacro m (x) x)").unwrap()).unwrap();
+        assert_eq!(program[0].footprint(), 5 * node + 3);
+        assert_eq!(program[1].footprint(), 2 * node + std::mem::size_of::<Symbol>());
+        assert_eq!(program[0].node_count(), 5);
+        assert_eq!(program[1].node_count(), 2);
+    }
+
+    #[test]
+    fn test_span_line_col() {
+        let source = "(+ 1 2)\n(* 3\n   4)";
+        assert_eq!(Span::line_col(source, 0), (1, 1));
+        assert_eq!(Span::line_col(source, 3), (1, 4));
+        assert_eq!(Span::line_col(source, 8), (2, 1));
+        assert_eq!(Span::line_col(source, 16), (3, 4));
+        let index = LineIndex::new(source);
+        for offset in 0..=source.len() + 2 {
+            assert_eq!(index.line_col(source, offset), Span::line_col(source, offset), "at {}", offset);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips_through_parser() {
+        use crate::{lexer::tokenize, parser::parse};
+
+        let source = "(define (f x) (if (< x 1.5) \"a\\\"b\" '(x `(,y ,@z)))) (defmacro m (a) a) nil";
+        let ast = parse(tokenize(source).unwrap()).unwrap();
+        let printed: Vec<String> = ast.iter().map(|e| e.to_string()).collect();
+        let reparsed = parse(tokenize(&printed.join(" ")).unwrap()).unwrap();
+        assert_eq!(ast, reparsed);
+        assert_eq!(printed[1], "(defmacro m (a) a)");
+    }
 
     #[test]
     fn test_new_macro_variants() {
         let macro_def = LispExpr::Macro {
-            name: "when".to_string(),
-            parameters: vec!["condition".to_string(), "body".to_string()],
-            body: Box::new(LispExpr::Symbol("test".to_string())),
+            declarations: Vec::new(),
+            name: "when".into(),
+            parameters: vec!["condition".into(), "body".into()],
+            body: Rc::new(LispExpr::Symbol("test".into())),
         };
         
         assert!(macro_def.is_macro());
@@ -144,10 +965,10 @@ mod tests {
 
     #[test]
     fn test_quote_variants() {
-        let quote_expr = LispExpr::Quote(Box::new(LispExpr::Symbol("x".to_string())));
-        let quasiquote_expr = LispExpr::Quasiquote(Box::new(LispExpr::Symbol("y".to_string())));
-        let unquote_expr = LispExpr::Unquote(Box::new(LispExpr::Symbol("z".to_string())));
-        let splice_expr = LispExpr::Splice(Box::new(LispExpr::List(vec![])));
+        let quote_expr = LispExpr::Quote(Rc::new(LispExpr::Symbol("x".into())));
+        let quasiquote_expr = LispExpr::Quasiquote(Rc::new(LispExpr::Symbol("y".into())));
+        let unquote_expr = LispExpr::Unquote(Rc::new(LispExpr::Symbol("z".into())));
+        let splice_expr = LispExpr::Splice(Rc::new(LispExpr::List(vec![].into())));
         
         assert!(quote_expr.is_quote_family());
         assert!(quasiquote_expr.is_quote_family());
@@ -160,7 +981,7 @@ mod tests {
 
     #[test]
     fn test_gensym_variant() {
-        let gensym = LispExpr::Gensym("unique_123".to_string());
+        let gensym = LispExpr::Gensym("unique_123".into());
         
         assert!(gensym.is_atom());
         assert_eq!(gensym.as_gensym().unwrap(), "unique_123");
@@ -169,7 +990,7 @@ mod tests {
     #[test]
     fn test_macro_call_variant() {
         let macro_call = LispExpr::MacroCall {
-            name: "when".to_string(),
+            name: "when".into(),
             args: vec![LispExpr::Bool(true), LispExpr::Number(42.0)],
         };
 
@@ -193,7 +1014,7 @@ mod tests {
         assert!(json.contains("42.5"));
 
         // Symbol
-        let symbol = LispExpr::Symbol("foo".to_string());
+        let symbol = LispExpr::Symbol("foo".into());
         let json = serde_json::to_string(&symbol).unwrap();
         assert!(json.contains("Symbol"));
         assert!(json.contains("foo"));
@@ -224,7 +1045,7 @@ mod tests {
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
             LispExpr::Number(3.0),
-        ]);
+        ].into());
 
         let json = serde_json::to_string(&list).unwrap();
         assert!(json.contains("List"));
@@ -235,9 +1056,10 @@ mod tests {
         use serde_json;
 
         let macro_def = LispExpr::Macro {
-            name: "when".to_string(),
-            parameters: vec!["cond".to_string(), "body".to_string()],
-            body: Box::new(LispExpr::Symbol("test".to_string())),
+            declarations: Vec::new(),
+            name: "when".into(),
+            parameters: vec!["cond".into(), "body".into()],
+            body: Rc::new(LispExpr::Symbol("test".into())),
         };
 
         let json = serde_json::to_string(&macro_def).unwrap();
@@ -250,19 +1072,19 @@ mod tests {
     fn test_json_serialize_quote_family() {
         use serde_json;
 
-        let quote = LispExpr::Quote(Box::new(LispExpr::Symbol("x".to_string())));
+        let quote = LispExpr::Quote(Rc::new(LispExpr::Symbol("x".into())));
         let json = serde_json::to_string(&quote).unwrap();
         assert!(json.contains("Quote"));
 
-        let quasiquote = LispExpr::Quasiquote(Box::new(LispExpr::Number(42.0)));
+        let quasiquote = LispExpr::Quasiquote(Rc::new(LispExpr::Number(42.0)));
         let json = serde_json::to_string(&quasiquote).unwrap();
         assert!(json.contains("Quasiquote"));
 
-        let unquote = LispExpr::Unquote(Box::new(LispExpr::Symbol("y".to_string())));
+        let unquote = LispExpr::Unquote(Rc::new(LispExpr::Symbol("y".into())));
         let json = serde_json::to_string(&unquote).unwrap();
         assert!(json.contains("Unquote"));
 
-        let splice = LispExpr::Splice(Box::new(LispExpr::List(vec![])));
+        let splice = LispExpr::Splice(Rc::new(LispExpr::List(vec![].into())));
         let json = serde_json::to_string(&splice).unwrap();
         assert!(json.contains("Splice"));
     }
@@ -271,7 +1093,7 @@ mod tests {
     fn test_json_serialize_gensym() {
         use serde_json;
 
-        let gensym = LispExpr::Gensym("unique_123".to_string());
+        let gensym = LispExpr::Gensym("unique_123".into());
         let json = serde_json::to_string(&gensym).unwrap();
         assert!(json.contains("Gensym"));
         assert!(json.contains("unique_123"));
@@ -288,7 +1110,7 @@ mod tests {
         assert_eq!(original, deserialized);
 
         // Symbol
-        let original = LispExpr::Symbol("test".to_string());
+        let original = LispExpr::Symbol("test".into());
         let json = serde_json::to_string(&original).unwrap();
         let deserialized: LispExpr = serde_json::from_str(&json).unwrap();
         assert_eq!(original, deserialized);
@@ -313,17 +1135,17 @@ mod tests {
         // Nested list with macro call
         let original = LispExpr::List(vec![
             LispExpr::MacroCall {
-                name: "when".to_string(),
+                name: "when".into(),
                 args: vec![
                     LispExpr::Bool(true),
                     LispExpr::List(vec![
-                        LispExpr::Symbol("+".to_string()),
+                        LispExpr::Symbol("+".into()),
                         LispExpr::Number(1.0),
                         LispExpr::Number(2.0),
-                    ]),
+                    ].into()),
                 ],
             },
-        ]);
+        ].into());
 
         let json = serde_json::to_string(&original).unwrap();
         let deserialized: LispExpr = serde_json::from_str(&json).unwrap();
@@ -331,9 +1153,10 @@ mod tests {
 
         // Macro with quote
         let original = LispExpr::Macro {
-            name: "test_macro".to_string(),
-            parameters: vec!["x".to_string()],
-            body: Box::new(LispExpr::Quote(Box::new(LispExpr::Symbol("x".to_string())))),
+            declarations: Vec::new(),
+            name: "test_macro".into(),
+            parameters: vec!["x".into()],
+            body: Rc::new(LispExpr::Quote(Rc::new(LispExpr::Symbol("x".into())))),
         };
 
         let json = serde_json::to_string(&original).unwrap();
@@ -352,7 +1175,7 @@ mod tests {
 
         let json_str = r#"{"Symbol":"test"}"#;
         let expr: LispExpr = serde_json::from_str(json_str).unwrap();
-        assert_eq!(expr, LispExpr::Symbol("test".to_string()));
+        assert_eq!(expr, LispExpr::Symbol("test".into()));
     }
 
     #[test]
@@ -360,10 +1183,10 @@ mod tests {
         use serde_json;
 
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
 
         let json = serde_json::to_string_pretty(&expr).unwrap();
         assert!(json.contains("List"));
@@ -0,0 +1,109 @@
+//! Code generators for the expanded program.
+//!
+//! Every target `build --target` can select implements `Backend` and is listed in
+//! `BACKENDS`: `compiler::RustBackend`, the default, then `wat::WatBackend`,
+//! `c::CBackend`, and `bytecode::BytecodeBackend`. The stages before code generation are the same for all, so a new
+//! target only needs a `Backend` and an entry here.
+
+use crate::ast::LispExpr;
+use crate::bytecode::BytecodeBackend;
+use crate::c::CBackend;
+use crate::compiler::RustBackend;
+use crate::explain::CompileError;
+use crate::sandbox::SandboxConfig;
+use crate::source_map::LocationTree;
+use crate::wat::WatBackend;
+
+pub trait Backend: Sync {
+    /// The name `--target` selects it by
+    fn name(&self) -> &'static str;
+
+    /// The extension of the generated file
+    fn extension(&self) -> &'static str;
+
+    /// Whether the generated code can enforce a sandbox and seed random numbers.
+    /// The pipeline rejects sandboxes and seeds for backends that cannot.
+    fn supports_sandbox(&self) -> bool {
+        false
+    }
+
+    /// The code every program starts with, before anything compiled from it
+    fn emit_prelude(&self, sandbox: Option<&SandboxConfig>) -> String;
+
+    /// One expanded expression, without the program around it
+    fn compile_expr(&self, expr: &LispExpr) -> Result<String, CompileError>;
+
+    /// A whole expanded program: its definitions, and an entry point that prints
+    /// the value of each other top-level form
+    fn compile_program(&self, program: &[LispExpr], sandbox: Option<&SandboxConfig>, seed: Option<u64>) -> Result<String, CompileError>;
+
+    /// `compile_program`, starting the code from each form with a comment naming its
+    /// location in its entry of `locations`; see `source_map`
+    fn compile_program_mapped(
+        &self,
+        _program: &[LispExpr],
+        _sandbox: Option<&SandboxConfig>,
+        _seed: Option<u64>,
+        _locations: &[LocationTree],
+    ) -> Result<String, CompileError> {
+        Err(format!("The {} backend cannot map generated code back to Lisp source", self.name()).into())
+    }
+}
+
+impl std::fmt::Debug for dyn Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Backend({})", self.name())
+    }
+}
+
+/// Every backend, the default first
+pub static BACKENDS: &[&dyn Backend] = &[&RustBackend, &WatBackend, &CBackend, &BytecodeBackend];
+
+/// The backend `--target name` selects
+pub fn backend(name: &str) -> Result<&'static dyn Backend, String> {
+    BACKENDS.iter().copied().find(|backend| backend.name() == name).ok_or_else(|| {
+        let names: Vec<&str> = BACKENDS.iter().map(|backend| backend.name()).collect();
+        format!("Unknown target '{}' (expected one of: {})", name, names.join(", "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_backends_by_name() {
+        let names: Vec<&str> = BACKENDS.iter().map(|backend| backend.name()).collect();
+        assert_eq!(names, vec!["rust", "wat", "c", "bytecode"]);
+        assert_eq!(backend("c").unwrap().extension(), "c");
+        assert_eq!(backend("fortran").unwrap_err(), "Unknown target 'fortran' (expected one of: rust, wat, c, bytecode)");
+    }
+
+    #[test]
+    fn test_compile_expr() {
+        let expr = &parse(tokenize("(if (< 1 2) (* 3 4) 0)").unwrap()).unwrap()[0];
+        let compiled: Vec<String> = BACKENDS.iter().map(|backend| backend.compile_expr(expr).unwrap()).collect();
+        assert_eq!(
+            compiled,
+            vec![
+                "if (1 < 2) { (3 * 4) } else { 0 }",
+                "(if (result f64) (f64.lt (f64.const 1) (f64.const 2)) (then (f64.mul (f64.const 3) (f64.const 4))) (else (f64.const 0)))",
+                "((1.0 < 2.0) ? (3.0 * 4.0) : 0.0)",
+                "const 1.0\nconst 2.0\nlt\njump_unless 8\nconst 3.0\nconst 4.0\nmul\njump 9\nconst 0.0",
+            ]
+        );
+
+        let binding = &parse(tokenize("(let ((x 2)) (* x x))").unwrap()).unwrap()[0];
+        assert!(backend("rust").unwrap().compile_expr(binding).is_ok());
+        assert_eq!(
+            backend("wat").unwrap().compile_expr(binding).unwrap_err().message(),
+            "(let ((x 2)) (* x x)) binds variables, which WebAssembly declares only in a function"
+        );
+        assert_eq!(
+            backend("c").unwrap().compile_expr(binding).unwrap_err().message(),
+            "(let ((x 2)) (* x x)) needs statements, which a C expression cannot contain"
+        );
+    }
+}
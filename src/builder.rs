@@ -0,0 +1,194 @@
+//! Building ASTs in Rust code.
+//!
+//! The functions here construct single nodes, and `lisp!` writes a whole tree in
+//! Lisp syntax:
+//!
+//! ```
+//! use lisp_compiler::builder::{list, num, sym};
+//! use lisp_compiler::lisp;
+//!
+//! let limit = 10;
+//! assert_eq!(lisp!((if (< n {limit}) 'small "big")).to_string(), "(if (< n 10) 'small \"big\")");
+//! assert_eq!(lisp!((+ 1 2.5)), list([sym("+"), num(1.0), num(2.5)]));
+//! ```
+//!
+//! `lisp!` reads Rust tokens, so it accepts what Rust can tokenize. `'x` quotes a
+//! symbol; quote anything else with `(quote ...)` and use `(quasiquote ...)` for a
+//! backquote, inside which `,x` and `,@xs` unquote and splice as usual. Names Rust
+//! splits into several tokens, like `string->number` or `even?`, and negative
+//! numbers are interpolated instead: `{...}` inserts any Rust expression that
+//! converts into a `LispExpr`, as in `{sym("even?")}` or `{-1}`.
+
+use crate::ast::LispExpr;
+use crate::symbol::Symbol;
+use std::rc::Rc;
+
+pub fn sym(name: &str) -> LispExpr {
+    LispExpr::Symbol(name.into())
+}
+
+pub fn num(n: f64) -> LispExpr {
+    LispExpr::Number(n)
+}
+
+pub fn string(s: impl Into<String>) -> LispExpr {
+    LispExpr::String(s.into())
+}
+
+pub fn boolean(b: bool) -> LispExpr {
+    LispExpr::Bool(b)
+}
+
+pub fn nil() -> LispExpr {
+    LispExpr::Nil
+}
+
+pub fn list(elements: impl IntoIterator<Item = LispExpr>) -> LispExpr {
+    LispExpr::List(elements.into_iter().collect())
+}
+
+/// `(head args...)` with a symbol head
+pub fn call(head: &str, args: impl IntoIterator<Item = LispExpr>) -> LispExpr {
+    list(std::iter::once(sym(head)).chain(args))
+}
+
+pub fn quote(expr: LispExpr) -> LispExpr {
+    LispExpr::Quote(Rc::new(expr))
+}
+
+pub fn quasiquote(expr: LispExpr) -> LispExpr {
+    LispExpr::Quasiquote(Rc::new(expr))
+}
+
+pub fn unquote(expr: LispExpr) -> LispExpr {
+    LispExpr::Unquote(Rc::new(expr))
+}
+
+pub fn splice(expr: LispExpr) -> LispExpr {
+    LispExpr::Splice(Rc::new(expr))
+}
+
+/// A `defmacro` form, as the parser produces it
+pub fn defmacro<'a>(name: &str, parameters: impl IntoIterator<Item = &'a str>, body: LispExpr) -> LispExpr {
+    LispExpr::Macro {
+        name: name.into(),
+        parameters: parameters.into_iter().map(Symbol::from).collect(),
+        body: Rc::new(body),
+        declarations: Vec::new(),
+    }
+}
+
+impl From<f64> for LispExpr {
+    fn from(n: f64) -> Self {
+        LispExpr::Number(n)
+    }
+}
+
+impl From<i32> for LispExpr {
+    fn from(n: i32) -> Self {
+        LispExpr::Number(n.into())
+    }
+}
+
+impl From<bool> for LispExpr {
+    fn from(b: bool) -> Self {
+        LispExpr::Bool(b)
+    }
+}
+
+/// Rust strings become Lisp strings; use `sym` for symbols
+impl From<&str> for LispExpr {
+    fn from(s: &str) -> Self {
+        LispExpr::String(s.to_string())
+    }
+}
+
+impl From<String> for LispExpr {
+    fn from(s: String) -> Self {
+        LispExpr::String(s)
+    }
+}
+
+impl From<Vec<LispExpr>> for LispExpr {
+    fn from(elements: Vec<LispExpr>) -> Self {
+        LispExpr::List(elements.into())
+    }
+}
+
+/// Write a `LispExpr` in Lisp syntax; see the `builder` module for what it accepts
+#[macro_export]
+macro_rules! lisp {
+    // List elements, munched one at a time so `,` and `,@` can prefix the next
+    (@list [$($done:expr),*]) => {
+        $crate::builder::list([$($done),*])
+    };
+    (@list [$($done:expr),*] , @ $element:tt $($rest:tt)*) => {
+        $crate::lisp!(@list [$($done,)* $crate::builder::splice($crate::lisp!($element))] $($rest)*)
+    };
+    (@list [$($done:expr),*] , $element:tt $($rest:tt)*) => {
+        $crate::lisp!(@list [$($done,)* $crate::builder::unquote($crate::lisp!($element))] $($rest)*)
+    };
+    (@list [$($done:expr),*] $element:tt $($rest:tt)*) => {
+        $crate::lisp!(@list [$($done,)* $crate::lisp!($element)] $($rest)*)
+    };
+
+    (nil) => {
+        $crate::builder::nil()
+    };
+    ({ $value:expr }) => {
+        $crate::ast::LispExpr::from($value)
+    };
+    ($quoted:lifetime) => {
+        $crate::builder::quote($crate::builder::sym(&stringify!($quoted)[1..]))
+    };
+    ($value:literal) => {
+        $crate::ast::LispExpr::from($value)
+    };
+    ((quote $quoted:tt)) => {
+        $crate::builder::quote($crate::lisp!($quoted))
+    };
+    ((quasiquote $template:tt)) => {
+        $crate::builder::quasiquote($crate::lisp!($template))
+    };
+    ((defmacro $name:ident ($($parameter:ident)*) $body:tt)) => {
+        $crate::builder::defmacro(stringify!($name), [$(stringify!($parameter)),*], $crate::lisp!($body))
+    };
+    (($($element:tt)*)) => {
+        $crate::lisp!(@list [] $($element)*)
+    };
+    ($symbol:tt) => {
+        $crate::builder::sym(stringify!($symbol))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn parsed(source: &str) -> LispExpr {
+        parse(tokenize(source).unwrap()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_lisp_macro_matches_parser() {
+        assert_eq!(lisp!((define (square x) (* x x))), parsed("(define (square x) (* x x))"));
+        assert_eq!(lisp!((if (<= n 1) nil "one")), parsed("(if (<= n 1) nil \"one\")"));
+        assert_eq!(lisp!((list 'a (quote (b c)) true 1.5)), parsed("(list 'a '(b c) true 1.5)"));
+        assert_eq!(
+            lisp!((defmacro unless (c body) (quasiquote (if ,c nil (progn ,@body))))),
+            parsed("(defmacro unless (c body) `(if ,c nil (progn ,@body)))")
+        );
+        assert_eq!(lisp!(()), list([]));
+    }
+
+    #[test]
+    fn test_interpolation_and_builders() {
+        let items = vec![num(1.0), num(2.0)];
+        assert_eq!(lisp!((apply + {items.clone()})), call("apply", [sym("+"), list(items)]));
+        assert_eq!(lisp!(({sym("string->number")} {-3} {String::from("s")})).to_string(), "(string->number -3 \"s\")");
+        assert_eq!(quote(sym("x")).to_string(), "'x");
+        assert_eq!(defmacro("id", ["x"], sym("x")), parsed("(defmacro id (x) x)"));
+    }
+}
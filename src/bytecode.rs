@@ -0,0 +1,469 @@
+//! Bytecode for the numeric subset of the language, run by `vm`.
+//!
+//! `build --target bytecode` lowers the expanded program (see `numeric`) to a
+//! `Module` of stack-machine functions and writes it as text, one instruction per
+//! line; `Module::parse` reads it back. `run --engine vm` compiles to a `Module` and
+//! executes it without invoking rustc. Booleans are the numbers 1 and 0.
+//!
+//! ```text
+//! ; rusty-lisp bytecode 1
+//! func fact params 1 locals 1
+//!   load 0
+//!   const 1.0
+//!   le
+//!   jump_unless 6
+//!   const 1.0
+//!   jump 12
+//!   ...
+//! end
+//! main locals 0
+//!   const 5.0
+//!   call fact
+//!   print_number
+//!   return
+//! end
+//! ```
+
+use crate::ast::{grow_stack, LispExpr};
+use crate::explain::CompileError;
+use crate::backend::Backend;
+use crate::numeric::{self, Arithmetic, Comparison, Expr, Function, Node, Type};
+use crate::sandbox::SandboxConfig;
+
+/// The first line of every module, naming the format's version
+pub const HEADER: &str = "; rusty-lisp bytecode 1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Const(f64),
+    /// Push the local in the slot; parameters come first
+    Load(usize),
+    /// Pop into the local in the slot
+    Store(usize),
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    /// Continue at the instruction with this index
+    Jump(usize),
+    /// Pop a boolean and jump when it is false
+    JumpUnless(usize),
+    /// Pop the function's arguments, the first deepest, and push its result
+    Call(usize),
+    /// Leave the function with the value on top of the stack; `main` leaves nothing
+    Return,
+    Pop,
+    PrintNumber,
+    PrintBool,
+    /// Pop a boolean and fail with the message when it is false
+    Check(String),
+}
+
+/// One function's code
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub name: String,
+    pub parameters: usize,
+    /// Slots for the parameters and every other local
+    pub locals: usize,
+    pub code: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub functions: Vec<Chunk>,
+    /// The top-level forms, printing each value
+    pub main: Chunk,
+}
+
+pub struct BytecodeBackend;
+
+impl Backend for BytecodeBackend {
+    fn name(&self) -> &'static str {
+        "bytecode"
+    }
+
+    fn extension(&self) -> &'static str {
+        "lbc"
+    }
+
+    fn emit_prelude(&self, _sandbox: Option<&SandboxConfig>) -> String {
+        format!("{}\n", HEADER)
+    }
+
+    /// The instructions pushing the value of `expr`
+    fn compile_expr(&self, expr: &LispExpr) -> Result<String, CompileError> {
+        let program = numeric::lower_expression(expr, "bytecode")?;
+        let mut code = Vec::new();
+        ChunkWriter::new(&program.main, &mut code).node(&program.main.body[0]);
+        // An expression on its own cannot call functions, so no names are needed
+        Ok(code.iter().map(|op| op.to_text(&[])).collect::<Vec<_>>().join("\n"))
+    }
+
+    fn compile_program(&self, program: &[LispExpr], _sandbox: Option<&SandboxConfig>, _seed: Option<u64>) -> Result<String, CompileError> {
+        Ok(compile(program)?.to_text())
+    }
+}
+
+/// Compile an expanded program to a module
+pub fn compile(program: &[LispExpr]) -> Result<Module, CompileError> {
+    let program = numeric::lower(program, "bytecode")?;
+    let functions = program.functions.iter().map(|function| chunk(function, false)).collect();
+    Ok(Module { functions, main: chunk(&program.main, true) })
+}
+
+fn chunk(function: &Function, main: bool) -> Chunk {
+    let mut code = Vec::new();
+    let mut writer = ChunkWriter::new(function, &mut code);
+    if main {
+        for node in &function.body {
+            writer.node(node);
+            match node.ty {
+                Type::Number => writer.code.push(Op::PrintNumber),
+                Type::Bool => writer.code.push(Op::PrintBool),
+                Type::Never => {}
+            }
+        }
+    } else {
+        writer.body();
+    }
+    writer.code.push(Op::Return);
+    Chunk { name: function.name.to_string(), parameters: function.parameter_count, locals: function.variables.len(), code }
+}
+
+/// Generates the code of one function
+struct ChunkWriter<'a> {
+    function: &'a Function,
+    code: &'a mut Vec<Op>,
+    /// The first instruction of each named let being compiled, by loop number
+    loops: Vec<(usize, usize)>,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(function: &'a Function, code: &'a mut Vec<Op>) -> Self {
+        ChunkWriter { function, code, loops: Vec::new() }
+    }
+
+    /// The function's body, checking its contract, leaving its result on the stack
+    fn body(&mut self) {
+        let function = self.function;
+        for condition in &function.requires {
+            self.node(&condition.check);
+            self.code.push(Op::Check(format!("Precondition of '{}' failed: {}", function.name, condition.source)));
+        }
+        let (last, init) = function.body.split_last().unwrap();
+        for node in init {
+            self.node(node);
+            if node.ty != Type::Never {
+                self.code.push(Op::Pop);
+            }
+        }
+        self.node(last);
+        if let Some(variable) = function.result_variable {
+            self.code.push(Op::Store(variable));
+            for condition in &function.ensures {
+                self.node(&condition.check);
+                self.code.push(Op::Check(format!("Postcondition of '{}' failed: {}", function.name, condition.source)));
+            }
+            self.code.push(Op::Load(variable));
+        }
+    }
+
+    /// Push the value of `node`
+    fn node(&mut self, node: &Node) {
+        grow_stack(|| self.node_unstacked(node))
+    }
+
+    fn node_unstacked(&mut self, node: &Node) {
+        match &node.expr {
+            Expr::Number(n) => self.code.push(Op::Const(*n)),
+            Expr::Bool(b) => self.code.push(Op::Const(if *b { 1.0 } else { 0.0 })),
+            Expr::Variable(index) => self.code.push(Op::Load(*index)),
+            Expr::Negate(operand) => {
+                self.node(operand);
+                self.code.push(Op::Negate);
+            }
+            Expr::Arithmetic(operator, left, right) => {
+                self.node(left);
+                self.node(right);
+                self.code.push(match operator {
+                    Arithmetic::Add => Op::Add,
+                    Arithmetic::Subtract => Op::Subtract,
+                    Arithmetic::Multiply => Op::Multiply,
+                    Arithmetic::Divide => Op::Divide,
+                });
+            }
+            Expr::Compare(comparison, left, right) => {
+                self.node(left);
+                self.node(right);
+                self.code.push(match comparison {
+                    Comparison::Equal => Op::Equal,
+                    Comparison::Less => Op::Less,
+                    Comparison::Greater => Op::Greater,
+                    Comparison::LessEqual => Op::LessEqual,
+                    Comparison::GreaterEqual => Op::GreaterEqual,
+                });
+            }
+            Expr::If(condition, then_node, else_node) => {
+                self.node(condition);
+                let jump_to_else = self.placeholder();
+                self.node(then_node);
+                let jump_to_end = self.placeholder();
+                self.code[jump_to_else] = Op::JumpUnless(self.code.len());
+                self.node(else_node);
+                self.code[jump_to_end] = Op::Jump(self.code.len());
+            }
+            Expr::Let(bindings, body) => {
+                for (variable, value) in bindings {
+                    self.node(value);
+                    self.code.push(Op::Store(*variable));
+                }
+                self.node(body);
+            }
+            Expr::Loop { id, bindings, body, .. } => {
+                for (variable, value) in bindings {
+                    self.node(value);
+                    self.code.push(Op::Store(*variable));
+                }
+                self.loops.push((*id, self.code.len()));
+                self.node(body);
+                self.loops.pop();
+            }
+            Expr::Again { id, bindings } => {
+                // Every value is pushed before the first variable is assigned
+                for (_, value) in bindings {
+                    self.node(value);
+                }
+                for (variable, _) in bindings.iter().rev() {
+                    self.code.push(Op::Store(*variable));
+                }
+                let start = self.loops.iter().rev().find(|(loop_id, _)| loop_id == id).unwrap().1;
+                self.code.push(Op::Jump(start));
+            }
+            Expr::Call(index, args) => {
+                for arg in args {
+                    self.node(arg);
+                }
+                self.code.push(Op::Call(*index));
+            }
+        }
+    }
+
+    /// A jump whose target is filled in once known
+    fn placeholder(&mut self) -> usize {
+        self.code.push(Op::Jump(usize::MAX));
+        self.code.len() - 1
+    }
+}
+
+/// Mnemonics, in `Op` order, for the instructions without an operand
+const MNEMONICS: &[(&str, Op)] = &[
+    ("neg", Op::Negate),
+    ("add", Op::Add),
+    ("sub", Op::Subtract),
+    ("mul", Op::Multiply),
+    ("div", Op::Divide),
+    ("eq", Op::Equal),
+    ("lt", Op::Less),
+    ("gt", Op::Greater),
+    ("le", Op::LessEqual),
+    ("ge", Op::GreaterEqual),
+    ("return", Op::Return),
+    ("pop", Op::Pop),
+    ("print_number", Op::PrintNumber),
+    ("print_bool", Op::PrintBool),
+];
+
+impl Op {
+    /// One line of the text format; calls name their function from `names`
+    fn to_text(&self, names: &[&str]) -> String {
+        match self {
+            Op::Const(n) => format!("const {:?}", n),
+            Op::Load(slot) => format!("load {}", slot),
+            Op::Store(slot) => format!("store {}", slot),
+            Op::Jump(target) => format!("jump {}", target),
+            Op::JumpUnless(target) => format!("jump_unless {}", target),
+            Op::Call(index) => format!("call {}", names.get(*index).copied().unwrap_or("?")),
+            Op::Check(message) => format!("check {}", message.replace('\\', "\\\\").replace('\n', "\\n")),
+            op => MNEMONICS.iter().find(|(_, other)| other == op).unwrap().0.to_string(),
+        }
+    }
+}
+
+impl Module {
+    /// The text format, starting with `HEADER`
+    pub fn to_text(&self) -> String {
+        let names: Vec<&str> = self.functions.iter().map(|function| function.name.as_str()).collect();
+        let mut text = BytecodeBackend.emit_prelude(None);
+        let chunks = self.functions.iter().map(|function| (format!("func {} params {} ", function.name, function.parameters), function));
+        for (header, chunk) in chunks.chain([("main ".to_string(), &self.main)]) {
+            text.push_str(&format!("{}locals {}\n", header, chunk.locals));
+            for op in &chunk.code {
+                text.push_str(&format!("  {}\n", op.to_text(&names)));
+            }
+            text.push_str("end\n");
+        }
+        text
+    }
+
+    /// Read the text format, checking that every jump, local, and call is in range
+    pub fn parse(text: &str) -> Result<Module, String> {
+        let mut lines = text.lines().enumerate().map(|(index, line)| (index + 1, line.trim())).filter(|(_, line)| !line.is_empty());
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(format!("Not a bytecode module: the first line must be '{}'", HEADER));
+        }
+        // Function names are resolved once every function is known
+        let mut chunks: Vec<(Chunk, Vec<(usize, String)>)> = Vec::new();
+        let mut main = None;
+        while let Some((number, line)) = lines.next() {
+            let error = |message: &str| format!("Bytecode line {}: {}", number, message);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, parameters, locals) = match words.as_slice() {
+                ["func", name, "params", parameters, "locals", locals] => (name.to_string(), *parameters, *locals),
+                ["main", "locals", locals] if main.is_none() => ("main".to_string(), "0", *locals),
+                _ => return Err(error("expected 'func NAME params N locals N' or 'main locals N'")),
+            };
+            let count = |text: &str| text.parse::<usize>().map_err(|_| error(&format!("'{}' is not a count", text)));
+            let mut chunk = Chunk { name, parameters: count(parameters)?, locals: count(locals)?, code: Vec::new() };
+            if chunk.parameters > chunk.locals {
+                return Err(error("a function has more parameters than locals"));
+            }
+            let mut calls = Vec::new();
+            loop {
+                let Some((number, line)) = lines.next() else {
+                    return Err(format!("Bytecode: '{}' has no 'end'", chunk.name));
+                };
+                let error = |message: &str| format!("Bytecode line {}: {}", number, message);
+                if line == "end" {
+                    break;
+                }
+                let (mnemonic, operand) = line.split_once(' ').unwrap_or((line, ""));
+                let index = |text: &str| text.parse::<usize>().map_err(|_| error(&format!("'{}' is not an index", text)));
+                let op = match mnemonic {
+                    "const" => Op::Const(operand.parse().map_err(|_| error(&format!("'{}' is not a number", operand)))?),
+                    "load" => Op::Load(index(operand)?),
+                    "store" => Op::Store(index(operand)?),
+                    "jump" => Op::Jump(index(operand)?),
+                    "jump_unless" => Op::JumpUnless(index(operand)?),
+                    "call" => {
+                        calls.push((number, operand.to_string()));
+                        Op::Call(usize::MAX)
+                    }
+                    "check" => Op::Check(unescape(operand)),
+                    _ => match MNEMONICS.iter().find(|(name, _)| *name == line) {
+                        Some((_, op)) => op.clone(),
+                        None => return Err(error(&format!("unknown instruction '{}'", line))),
+                    },
+                };
+                match op {
+                    Op::Load(slot) | Op::Store(slot) if slot >= chunk.locals => {
+                        return Err(error(&format!("local {} is out of range", slot)));
+                    }
+                    _ => chunk.code.push(op),
+                }
+            }
+            if words[0] == "main" {
+                main = Some((chunk, calls));
+            } else {
+                chunks.push((chunk, calls));
+            }
+        }
+        let Some(main) = main else {
+            return Err("Bytecode: the module has no 'main'".to_string());
+        };
+
+        let names: Vec<String> = chunks.iter().map(|(chunk, _)| chunk.name.clone()).collect();
+        let mut resolve = |(mut chunk, calls): (Chunk, Vec<(usize, String)>)| -> Result<Chunk, String> {
+            let mut calls = calls.into_iter();
+            for op in &mut chunk.code {
+                if let Op::Call(index) = op {
+                    let (number, name) = calls.next().unwrap();
+                    *index = names.iter().position(|other| *other == name).ok_or_else(|| format!("Bytecode line {}: unknown function '{}'", number, name))?;
+                }
+            }
+            let length = chunk.code.len();
+            if chunk.code.iter().any(|op| matches!(op, Op::Jump(target) | Op::JumpUnless(target) if *target >= length)) {
+                return Err(format!("Bytecode: '{}' jumps past its last instruction", chunk.name));
+            }
+            Ok(chunk)
+        };
+        let functions = chunks.into_iter().map(&mut resolve).collect::<Result<_, _>>()?;
+        Ok(Module { functions, main: resolve(main)? })
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push('\\'),
+            },
+            (c, false) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn module(source: &str) -> Module {
+        compile(&parse(tokenize(source).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_compile_to_text() {
+        let module = module("(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (fact 5)");
+        assert_eq!(
+            module.to_text(),
+            "; rusty-lisp bytecode 1\n\
+             func fact params 1 locals 1\n  load 0\n  const 1.0\n  le\n  jump_unless 6\n  const 1.0\n  jump 12\n  load 0\n  load 0\n  const 1.0\n  sub\n  call fact\n  mul\n  return\nend\n\
+             main locals 0\n  const 5.0\n  call fact\n  print_number\n  return\nend\n"
+        );
+        assert_eq!(Module::parse(&module.to_text()).unwrap(), module);
+    }
+
+    #[test]
+    fn test_loops_and_contracts_round_trip() {
+        let module = module(
+            "(defun half (x) (declare (requires (>= x 0))) (/ x 2)) \
+             (let loop ((i 0) (acc 0)) (if (> i 10) acc (loop (+ i 1) (+ acc (half i)))))",
+        );
+        let text = module.to_text();
+        assert!(text.contains("  check Precondition of 'half' failed: (>= x 0)\n"));
+        assert!(text.contains("  store 1\n  store 0\n  jump 4\n"));
+        assert_eq!(Module::parse(&text).unwrap(), module);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_modules() {
+        let error = |text: &str| Module::parse(&format!("{}\n{}", HEADER, text)).unwrap_err();
+        assert!(Module::parse("main locals 0\nend").unwrap_err().starts_with("Not a bytecode module"));
+        assert_eq!(error("main locals 1\n  load 1\nend"), "Bytecode line 3: local 1 is out of range");
+        assert_eq!(error("main locals 0\n  jump 5\n  return\nend"), "Bytecode: 'main' jumps past its last instruction");
+        assert_eq!(error("main locals 0\n  call f\nend"), "Bytecode line 3: unknown function 'f'");
+        assert_eq!(error("main locals 0\n  push 1\nend"), "Bytecode line 3: unknown instruction 'push 1'");
+        assert_eq!(error("func f params 0 locals 0\n  const 1.0\n  return\nend"), "Bytecode: the module has no 'main'");
+    }
+
+    #[test]
+    fn test_compile_deeply_nested_expression() {
+        let depth = 100_000;
+        let module = module(&format!("{}1{}", "(+ 1 ".repeat(depth), ")".repeat(depth)));
+        assert_eq!(module.main.code.len(), 2 * depth + 3);
+        assert_eq!(module.main.code[2 * depth..], [Op::Add, Op::PrintNumber, Op::Return]);
+    }
+}
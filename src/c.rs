@@ -0,0 +1,493 @@
+//! C generation for the numeric subset of the language.
+//!
+//! `build --target c` lowers the expanded program to portable C99 that any C compiler
+//! builds, for platforms without a Rust toolchain. Numbers are `double` and booleans
+//! `bool`; see `numeric` for the forms supported. Each `defun` becomes a function
+//! named with a `lisp_` prefix, and `main` prints the value of every other top-level
+//! form the way compiled Rust does. A failed contract prints the condition and aborts.
+
+use crate::ast::{grow_stack, LispExpr};
+use crate::explain::CompileError;
+use crate::numeric::{self, Arithmetic, Comparison, Expr, Function, Node, Program, Type};
+use crate::backend::Backend;
+use crate::sandbox::SandboxConfig;
+
+const HEADERS: &str = "#include <math.h>\n#include <stdbool.h>\n#include <stdio.h>\n#include <stdlib.h>\n";
+
+/// Prints as Rust's `{:?}` prints an `f64`: the fewest digits that read back as the
+/// same number, in scientific notation below 1e-4 and from 1e16
+const PRINT_NUMBER: &str = r#"
+static void print_number(double x) {
+    char text[40], digits[20], *out = text;
+    int precision = 0, count = 0, exponent, place, i;
+    if (isnan(x) || isinf(x)) {
+        puts(isnan(x) ? "NaN" : x > 0 ? "inf" : "-inf");
+        return;
+    }
+    do {
+        snprintf(text, sizeof text, "%.*e", precision, x);
+    } while (strtod(text, NULL) != x && ++precision < 17);
+    for (i = 0; text[i] != 'e'; i++) {
+        if (text[i] >= '0' && text[i] <= '9') {
+            digits[count++] = text[i];
+        }
+    }
+    exponent = atoi(text + i + 1);
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    if (signbit(x)) {
+        *out++ = '-';
+    }
+    if (x != 0 && (exponent < -4 || exponent >= 16)) {
+        *out++ = digits[0];
+        if (count > 1) {
+            *out++ = '.';
+            for (i = 1; i < count; i++) {
+                *out++ = digits[i];
+            }
+        }
+        sprintf(out, "e%d", exponent);
+    } else {
+        for (place = exponent > 0 ? exponent : 0; place >= -1 || place > exponent - count; place--) {
+            i = exponent - place;
+            *out++ = i >= 0 && i < count ? digits[i] : '0';
+            if (place == 0) {
+                *out++ = '.';
+            }
+        }
+        *out = '\0';
+    }
+    puts(text);
+}
+"#;
+
+const PRINT_BOOL: &str = r#"
+static void print_bool(bool b) {
+    puts(b ? "true" : "false");
+}
+"#;
+
+/// Output printed before the failure is flushed, as `abort` discards buffered output
+const CONTRACT_FAILED: &str = r#"
+static void contract_failed(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", message);
+    abort();
+}
+"#;
+
+/// Names a variable cannot have in C: keywords, and what the headers and helpers define
+const RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern", "float", "for", "goto",
+    "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch",
+    "typedef", "union", "unsigned", "void", "volatile", "while", "bool", "true", "false", "NULL", "EOF", "NAN", "INFINITY", "stdin",
+    "stdout", "stderr", "print_number", "print_bool", "contract_failed",
+];
+
+pub struct CBackend;
+
+impl Backend for CBackend {
+    fn name(&self) -> &'static str {
+        "c"
+    }
+
+    fn extension(&self) -> &'static str {
+        "c"
+    }
+
+    /// The headers every program includes; the helpers after them depend on the program
+    fn emit_prelude(&self, _sandbox: Option<&SandboxConfig>) -> String {
+        HEADERS.to_string()
+    }
+
+    /// `expr` as a C expression, which cannot contain the statements of `let`s and
+    /// named lets
+    fn compile_expr(&self, expr: &LispExpr) -> Result<String, CompileError> {
+        let program = numeric::lower_expression(expr, "C")?;
+        let mut writer = FunctionWriter::new(&program, &program.main);
+        let mut statements = Vec::new();
+        let value = writer.expression(&program.main.body[0], &mut statements);
+        if !statements.is_empty() {
+            return Err(CompileError::new("L0403", format!("{} needs statements, which a C expression cannot contain", expr)));
+        }
+        Ok(value)
+    }
+
+    fn compile_program(&self, program: &[LispExpr], _sandbox: Option<&SandboxConfig>, _seed: Option<u64>) -> Result<String, CompileError> {
+        compile_to_c(program)
+    }
+}
+
+/// Compile an expanded program to a C99 program
+pub fn compile_to_c(program: &[LispExpr]) -> Result<String, CompileError> {
+    let program = numeric::lower(program, "C")?;
+    // Only the helpers the program uses, as C compilers warn about unused ones
+    let mut code = CBackend.emit_prelude(None);
+    for (helper, used) in [
+        (PRINT_NUMBER, program.main.body.iter().any(|node| node.ty == Type::Number)),
+        (PRINT_BOOL, program.main.body.iter().any(|node| node.ty == Type::Bool)),
+        (CONTRACT_FAILED, program.functions.iter().any(|function| !function.requires.is_empty() || !function.ensures.is_empty())),
+    ] {
+        if used {
+            code.push_str(helper);
+        }
+    }
+    if !program.functions.is_empty() {
+        code.push('\n');
+        for function in &program.functions {
+            code.push_str(&format!("{};\n", signature(function)));
+        }
+    }
+    for function in &program.functions {
+        let mut writer = FunctionWriter::new(&program, function);
+        let body = writer.body();
+        code.push_str(&format!("\n{} {{\n{}}}\n", signature(function), writer.finish(body)));
+    }
+
+    let mut writer = FunctionWriter::new(&program, &program.main);
+    let mut main = Vec::new();
+    for node in &program.main.body {
+        let value = writer.expression(node, &mut main);
+        match node.ty {
+            Type::Number => main.push(format!("print_number({});", value)),
+            Type::Bool => main.push(format!("print_bool({});", value)),
+            Type::Never => {}
+        }
+    }
+    main.push("return 0;".to_string());
+    code.push_str(&format!("\nint main(void) {{\n{}}}\n", writer.finish(main)));
+    Ok(code)
+}
+
+/// `double lisp_name(double x, bool flag)`
+fn signature(function: &Function) -> String {
+    let parameters: Vec<String> = function.variables[..function.parameter_count]
+        .iter()
+        .map(|parameter| format!("{} {}", c_type(parameter.ty), identifier(&parameter.name)))
+        .collect();
+    let parameters = if parameters.is_empty() { "void".to_string() } else { parameters.join(", ") };
+    format!("{} {}({})", c_type(function.result), function_name(&function.name), parameters)
+}
+
+fn c_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Bool => "bool",
+        Type::Number | Type::Never => "double",
+    }
+}
+
+fn function_name(name: &str) -> String {
+    format!("lisp_{}", identifier(name))
+}
+
+/// A C identifier for a Lisp name: `-` becomes `_` and other punctuation its code
+/// point, so `positive?` becomes `positive_3f_`. Names C reserves get a leading `_`.
+fn identifier(name: &str) -> String {
+    let mut identifier = String::new();
+    for c in name.chars() {
+        match c {
+            '-' => identifier.push('_'),
+            c if c.is_ascii_alphanumeric() || c == '_' => identifier.push(c),
+            c => identifier.push_str(&format!("_{:x}_", c as u32)),
+        }
+    }
+    if identifier.starts_with(|c: char| c.is_ascii_digit()) || RESERVED.contains(&identifier.as_str()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+fn number_literal(n: f64) -> String {
+    match n {
+        _ if n.is_nan() => "NAN".to_string(),
+        _ if n.is_infinite() => if n > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string(),
+        _ => format!("{:?}", n),
+    }
+}
+
+/// `text` as a C string literal
+fn string_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' | '\\' | '?' => literal.extend(['\\', c]),
+            '\n' => literal.push_str("\\n"),
+            c if c.is_ascii_graphic() || c == ' ' => literal.push(c),
+            c => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    literal.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Indent each of `lines` one level
+fn indented(lines: Vec<String>) -> impl Iterator<Item = String> {
+    lines.into_iter().map(|line| format!("    {}", line))
+}
+
+/// Generates one C function. Expressions with statements in them, such as `if`s
+/// holding `let`s and named lets, put those statements before the expression and
+/// leave their values in temporaries.
+struct FunctionWriter<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    temporaries: Vec<Type>,
+}
+
+impl<'a> FunctionWriter<'a> {
+    fn new(program: &'a Program, function: &'a Function) -> Self {
+        FunctionWriter { program, function, temporaries: Vec::new() }
+    }
+
+    /// The declarations of the variables after the parameters and the temporaries, then `body`
+    fn finish(&self, body: Vec<String>) -> String {
+        let mut lines = Vec::new();
+        for (index, variable) in self.function.variables.iter().enumerate().skip(self.function.parameter_count) {
+            lines.push(format!("{} {};", c_type(variable.ty), self.variable(index)));
+        }
+        for (index, ty) in self.temporaries.iter().enumerate() {
+            lines.push(format!("{} _t{};", c_type(*ty), index));
+        }
+        lines.extend(body);
+        indented(lines).map(|line| line + "\n").collect()
+    }
+
+    /// Parameters are named as in the source; other variables are numbered, as a
+    /// function can bind the same name more than once
+    fn variable(&self, index: usize) -> String {
+        let name = identifier(&self.function.variables[index].name);
+        if index < self.function.parameter_count { name } else { format!("{}_{}", name, index) }
+    }
+
+    fn temporary(&mut self, ty: Type) -> String {
+        self.temporaries.push(ty);
+        format!("_t{}", self.temporaries.len() - 1)
+    }
+
+    /// The statements of the function's body, checking its contract
+    fn body(&mut self) -> Vec<String> {
+        let function = self.function;
+        let mut body = Vec::new();
+        for condition in &function.requires {
+            self.check(&condition.check, &format!("Precondition of '{}' failed: {}", function.name, condition.source), &mut body);
+        }
+        let (last, init) = function.body.split_last().unwrap();
+        for node in init {
+            let value = self.expression(node, &mut body);
+            if node.ty != Type::Never {
+                body.push(format!("(void){};", value));
+            }
+        }
+        let result = self.expression(last, &mut body);
+        match function.result_variable {
+            None => body.push(format!("return {};", result)),
+            Some(variable) => {
+                let variable = self.variable(variable);
+                body.push(format!("{} = {};", variable, result));
+                for condition in &function.ensures {
+                    self.check(&condition.check, &format!("Postcondition of '{}' failed: {}", function.name, condition.source), &mut body);
+                }
+                body.push(format!("return {};", variable));
+            }
+        }
+        body
+    }
+
+    fn check(&mut self, condition: &Node, message: &str, out: &mut Vec<String>) {
+        let condition = self.expression(condition, out);
+        out.push(format!("if (!{}) {{", condition));
+        out.push(format!("    contract_failed({});", string_literal(message)));
+        out.push("}".to_string());
+    }
+
+    /// `node` as a C expression, adding the statements that must run first to `out`
+    fn expression(&mut self, node: &Node, out: &mut Vec<String>) -> String {
+        grow_stack(|| self.expression_unstacked(node, out))
+    }
+
+    fn expression_unstacked(&mut self, node: &Node, out: &mut Vec<String>) -> String {
+        match &node.expr {
+            Expr::Number(n) => number_literal(*n),
+            Expr::Bool(b) => b.to_string(),
+            Expr::Variable(index) => self.variable(*index),
+            Expr::Negate(operand) => format!("(- {})", self.expression(operand, out)),
+            Expr::Arithmetic(operator, left, right) => {
+                let operator = match operator {
+                    Arithmetic::Add => "+",
+                    Arithmetic::Subtract => "-",
+                    Arithmetic::Multiply => "*",
+                    Arithmetic::Divide => "/",
+                };
+                format!("({} {} {})", self.expression(left, out), operator, self.expression(right, out))
+            }
+            Expr::Compare(comparison, left, right) => {
+                let operator = match comparison {
+                    Comparison::Equal => "==",
+                    Comparison::Less => "<",
+                    Comparison::Greater => ">",
+                    Comparison::LessEqual => "<=",
+                    Comparison::GreaterEqual => ">=",
+                };
+                format!("({} {} {})", self.expression(left, out), operator, self.expression(right, out))
+            }
+            Expr::If(condition, then_node, else_node) => {
+                let condition = self.expression(condition, out);
+                let (mut then_out, mut else_out) = (Vec::new(), Vec::new());
+                let then_value = self.expression(then_node, &mut then_out);
+                let else_value = self.expression(else_node, &mut else_out);
+                if then_out.is_empty() && else_out.is_empty() && node.ty != Type::Never {
+                    return format!("({} ? {} : {})", condition, then_value, else_value);
+                }
+                let result = (node.ty != Type::Never).then(|| self.temporary(node.ty));
+                for (branch, value, lines) in [(then_node, then_value, &mut then_out), (else_node, else_value, &mut else_out)] {
+                    if let Some(result) = &result
+                        && branch.ty != Type::Never
+                    {
+                        lines.push(format!("{} = {};", result, value));
+                    }
+                }
+                out.push(format!("if ({}) {{", condition));
+                out.extend(indented(then_out));
+                out.push("} else {".to_string());
+                out.extend(indented(else_out));
+                out.push("}".to_string());
+                result.unwrap_or_else(|| "0".to_string())
+            }
+            Expr::Let(bindings, body) => {
+                self.assign(bindings, out);
+                self.expression(body, out)
+            }
+            Expr::Loop { bindings, body, .. } => {
+                self.assign(bindings, out);
+                let result = (node.ty != Type::Never).then(|| self.temporary(node.ty));
+                let mut lines = Vec::new();
+                self.tail(body, result.as_deref(), &mut lines);
+                out.push("for (;;) {".to_string());
+                out.extend(indented(lines));
+                out.push("}".to_string());
+                result.unwrap_or_else(|| "0".to_string())
+            }
+            Expr::Again { .. } => unreachable!("a named let is only called again in tail position"),
+            Expr::Call(index, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.expression(arg, out)).collect();
+                format!("{}({})", function_name(&self.program.functions[*index].name), args.join(", "))
+            }
+        }
+    }
+
+    /// The statements ending a pass through a named let's loop with `node`: going round
+    /// again, or storing the value in `result` and leaving
+    fn tail(&mut self, node: &Node, result: Option<&str>, out: &mut Vec<String>) {
+        grow_stack(|| match &node.expr {
+            Expr::If(condition, then_node, else_node) => {
+                let condition = self.expression(condition, out);
+                let (mut then_out, mut else_out) = (Vec::new(), Vec::new());
+                self.tail(then_node, result, &mut then_out);
+                self.tail(else_node, result, &mut else_out);
+                out.push(format!("if ({}) {{", condition));
+                out.extend(indented(then_out));
+                out.push("} else {".to_string());
+                out.extend(indented(else_out));
+                out.push("}".to_string());
+            }
+            Expr::Again { bindings, .. } => {
+                // Every value is computed before the first variable is reassigned
+                let values: Vec<String> = bindings.iter().map(|(_, value)| self.expression(value, out)).collect();
+                let values: Vec<String> = match values.len() {
+                    1 => values,
+                    _ => values
+                        .into_iter()
+                        .zip(bindings)
+                        .map(|(value, (_, node))| {
+                            let temporary = self.temporary(node.ty);
+                            out.push(format!("{} = {};", temporary, value));
+                            temporary
+                        })
+                        .collect(),
+                };
+                for ((variable, _), value) in bindings.iter().zip(values) {
+                    out.push(format!("{} = {};", self.variable(*variable), value));
+                }
+                out.push("continue;".to_string());
+            }
+            _ => {
+                let value = self.expression(node, out);
+                if let Some(result) = result
+                    && node.ty != Type::Never
+                {
+                    out.push(format!("{} = {};", result, value));
+                }
+                out.push("break;".to_string());
+            }
+        })
+    }
+
+    /// Assign each binding in order
+    fn assign(&mut self, bindings: &[(usize, Node)], out: &mut Vec<String>) {
+        for (variable, value) in bindings {
+            let value = self.expression(value, out);
+            out.push(format!("{} = {};", self.variable(*variable), value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn c(source: &str) -> Result<String, CompileError> {
+        compile_to_c(&parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_compile_functions_and_main() {
+        let code = c("(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (defun positive? (x) (> x 0)) (fact 5) (positive? -1)").unwrap();
+        let helpers = format!("{}{}{}", HEADERS, PRINT_NUMBER, PRINT_BOOL);
+        assert!(code.starts_with(&helpers));
+        assert_eq!(
+            &code[helpers.len()..],
+            "\ndouble lisp_fact(double n);\nbool lisp_positive_3f_(double x);\n\
+             \ndouble lisp_fact(double n) {\n    return ((n <= 1.0) ? 1.0 : (n * lisp_fact((n - 1.0))));\n}\n\
+             \nbool lisp_positive_3f_(double x) {\n    return (x > 0.0);\n}\n\
+             \nint main(void) {\n    print_number(lisp_fact(5.0));\n    print_bool(lisp_positive_3f_(-1.0));\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_statements() {
+        let code = c("(let loop ((i 0) (acc 0)) (if (> i 10) acc (loop (+ i 1) (+ acc i)))) (if (< 1 2) (let ((x 2)) (* x x)) 0)").unwrap();
+        let main = &code[code.find("int main").unwrap()..];
+        assert_eq!(
+            main,
+            "int main(void) {\n    double i_0;\n    double acc_1;\n    double x_2;\n    double _t0;\n    double _t1;\n    double _t2;\n    double _t3;\n\
+             \x20   i_0 = 0.0;\n    acc_1 = 0.0;\n    for (;;) {\n        if ((i_0 > 10.0)) {\n            _t0 = acc_1;\n            break;\n        } else {\n\
+             \x20           _t1 = (i_0 + 1.0);\n            _t2 = (acc_1 + i_0);\n            i_0 = _t1;\n            acc_1 = _t2;\n            continue;\n        }\n    }\n\
+             \x20   print_number(_t0);\n    if ((1.0 < 2.0)) {\n        x_2 = 2.0;\n        _t3 = (x_2 * x_2);\n    } else {\n        _t3 = 0.0;\n    }\n\
+             \x20   print_number(_t3);\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_contracts_and_names() {
+        let code = c("(defun half ((int : number)) (declare (requires (>= int 0)) (ensures (<= result int))) (/ int 2))").unwrap();
+        assert!(code.contains("double lisp_half(double _int) {\n    double result_1;\n    if (!(_int >= 0.0)) {\n        contract_failed(\"Precondition of 'half' failed: (>= int 0)\");\n    }\n"));
+        assert!(code.contains("    result_1 = (_int / 2.0);\n"));
+        assert_eq!(string_literal("a \"b\"?\n"), "\"a \\\"b\\\"\\?\\n\"");
+        assert!(c("(concat \"a\" \"b\")").unwrap_err().message().contains("(the C backend compiles arithmetic"));
+    }
+
+    #[test]
+    fn test_compile_deeply_nested_expression() {
+        let depth = 100_000;
+        let code = c(&format!("{}1{}", "(+ 1 ".repeat(depth), ")".repeat(depth))).unwrap();
+        assert!(code.contains("    print_number((1.0 + (1.0 + "));
+    }
+}
@@ -0,0 +1,430 @@
+use crate::ast::{grow_stack, LispExpr};
+use crate::sandbox::{self, Capability};
+use crate::suggest;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const FILE_READ_OPS: &[&str] = &["read-file"];
+const FILE_WRITE_OPS: &[&str] = &["write-file", "append-file", "delete-file"];
+const NETWORK_OPS: &[&str] = &["http-get", "http-post"];
+const ENV_OPS: &[&str] = &["getenv"];
+const PROCESS_OPS: &[&str] = &["run-command", "spawn-process", "process-spawn", "shell", "exec"];
+const THREAD_OPS: &[&str] = &["spawn", "thread", "async"];
+const TIME_OPS: &[&str] = &["now", "elapsed"];
+const CRATE_OPS: &[&str] = &["require-crate"];
+
+/// One operation in the program that needs a sandbox capability
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityUse {
+    /// Operation that needs the capability, e.g. `read-file`
+    pub operation: String,
+    pub capability: Capability,
+    /// Literal argument of the operation (path, URL, or command), if known statically
+    pub target: Option<String>,
+}
+
+impl CapabilityUse {
+    /// Path, variable, and crate capabilities whose target is not a literal cannot be granted up front
+    pub fn is_resolved(&self) -> bool {
+        match self.capability {
+            Capability::FileRead(_) | Capability::FileWrite(_) | Capability::EnvRead(_) | Capability::Crate(_) => {
+                self.target.is_some()
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Names of the entries a `(declare (capabilities ...))` form may list
+const DECLARED_CAPABILITIES: &[&str] = &[
+    "file-read", "file-write", "env-read", "crate", "network", "network-http", "system-time", "process-spawn", "thread-spawn", "unsafe-rust",
+];
+
+/// Parse one entry of a `(declare (capabilities ...))` form, e.g. `(file-read "/data")`,
+/// `(network "api.example.com" 443)`, `(env-read "HOME")`, `(crate "regex")`, or `process-spawn`
+pub fn parse_declared_capability(expr: &LispExpr) -> Result<Capability, String> {
+    let (name, args): (&str, &[LispExpr]) = match expr {
+        LispExpr::Symbol(name) => (name, &[]),
+        LispExpr::List(elements) if !elements.is_empty() => match elements[0].as_symbol() {
+            Some(name) => (name, &elements[1..]),
+            None => return Err(format!("Invalid capability declaration: {}", expr)),
+        },
+        _ => return Err(format!("Invalid capability declaration: {}", expr)),
+    };
+    let string_arg = || match args {
+        [LispExpr::String(value)] => Ok(value.clone()),
+        _ => Err(format!("'{}' takes one string argument: {}", name, expr)),
+    };
+
+    match name {
+        "file-read" => Ok(Capability::FileRead(PathBuf::from(string_arg()?))),
+        "file-write" => Ok(Capability::FileWrite(PathBuf::from(string_arg()?))),
+        "env-read" => Ok(Capability::EnvRead(string_arg()?)),
+        "crate" => Ok(Capability::Crate(string_arg()?)),
+        "network" => match args {
+            [LispExpr::String(host)] => Ok(Capability::Network { host_pattern: host.clone(), port: None }),
+            [LispExpr::String(host), LispExpr::Number(port)]
+                if port.fract() == 0.0 && (1.0..=65535.0).contains(port) =>
+            {
+                Ok(Capability::Network { host_pattern: host.clone(), port: Some(*port as u16) })
+            }
+            _ => Err(format!("'network' takes a host and an optional port: {}", expr)),
+        },
+        "network-http" if args.is_empty() => Ok(Capability::NetworkHTTP),
+        "system-time" if args.is_empty() => Ok(Capability::SystemTime),
+        "process-spawn" if args.is_empty() => Ok(Capability::ProcessSpawn),
+        "thread-spawn" if args.is_empty() => Ok(Capability::ThreadSpawn),
+        "unsafe-rust" if args.is_empty() => Ok(Capability::UnsafeRust),
+        _ => Err(suggest::with_suggestion(format!("Unknown capability declaration: {}", expr), name, DECLARED_CAPABILITIES.iter().copied())),
+    }
+}
+
+/// Render a capability as a `(declare (capabilities ...))` entry
+pub fn declaration(capability: &Capability) -> String {
+    match capability {
+        Capability::FileRead(path) => format!("(file-read {:?})", path.display().to_string()),
+        Capability::FileWrite(path) => format!("(file-write {:?})", path.display().to_string()),
+        Capability::EnvRead(name) => format!("(env-read {:?})", name),
+        Capability::Crate(name) => format!("(crate {:?})", name),
+        Capability::Network { host_pattern, port: Some(port) } => format!("(network {:?} {})", host_pattern, port),
+        Capability::Network { host_pattern, port: None } => format!("(network {:?})", host_pattern),
+        Capability::NetworkHTTP => "network-http".to_string(),
+        Capability::SystemTime => "system-time".to_string(),
+        Capability::ProcessSpawn => "process-spawn".to_string(),
+        Capability::ThreadSpawn => "thread-spawn".to_string(),
+        Capability::UnsafeRust => "unsafe-rust".to_string(),
+    }
+}
+
+/// Capabilities a `(defun name (params) (declare (capabilities ...)) body...)` declares,
+/// or `None` when it has no capabilities declaration
+pub fn declared_capabilities(defun: &[LispExpr]) -> Option<Result<Vec<Capability>, String>> {
+    let declarations = defun.iter().skip(3).filter_map(|form| match form {
+        LispExpr::List(elements) if elements.first().and_then(|e| e.as_symbol()) == Some("declare") => {
+            Some(&elements[1..])
+        }
+        _ => None,
+    });
+    let mut declared: Option<Vec<LispExpr>> = None;
+    for clauses in declarations {
+        for clause in clauses {
+            if let LispExpr::List(elements) = clause
+                && elements.first().and_then(|e| e.as_symbol()) == Some("capabilities")
+            {
+                declared.get_or_insert_with(Vec::new).extend(elements[1..].iter().cloned());
+            }
+        }
+    }
+    declared.map(|entries| entries.iter().map(parse_declared_capability).collect())
+}
+
+/// Capabilities a program would need to run under `--sandbox-mode`, inferred from its AST
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityReport {
+    uses: Vec<CapabilityUse>,
+}
+
+impl CapabilityReport {
+    /// Walk the program (after macro expansion) and record every capability-requiring operation
+    pub fn infer(program: &[LispExpr]) -> Self {
+        let mut report = CapabilityReport::default();
+        let mut globals: HashMap<String, String> = HashMap::new();
+        for expr in program {
+            report.walk(expr, &globals);
+            // Top-level (define name "literal") makes the literal visible to later forms
+            if let LispExpr::List(elements) = expr
+                && elements.len() == 3
+                && elements[0].as_symbol() == Some("define")
+                && let (Some(name), LispExpr::String(value)) = (elements[1].as_symbol(), &elements[2])
+            {
+                globals.insert(name.to_string(), value.clone());
+            }
+        }
+        report
+    }
+
+    pub fn uses(&self) -> &[CapabilityUse] {
+        &self.uses
+    }
+
+    /// Distinct capabilities needed, in a stable order
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities: Vec<Capability> = Vec::new();
+        for capability_use in self.uses.iter().filter(|u| u.is_resolved()) {
+            if !capabilities.contains(&capability_use.capability) {
+                capabilities.push(capability_use.capability.clone());
+            }
+        }
+        capabilities.sort_by_key(|c| c.to_string());
+        capabilities
+    }
+
+    /// `--allow-capability` flags granting exactly the inferred capabilities
+    pub fn allow_flags(&self) -> Vec<String> {
+        self.capabilities()
+            .iter()
+            .map(|c| format!("--allow-capability {}", c))
+            .collect()
+    }
+
+    /// Human-readable manifest of required capabilities
+    pub fn render(&self, file_name: &str) -> String {
+        let mut output = String::new();
+        if self.uses.is_empty() {
+            output.push_str(&format!("{} requires no capabilities\n", file_name));
+            return output;
+        }
+
+        output.push_str(&format!("Capabilities required by {}:\n", file_name));
+        for capability in self.capabilities() {
+            let operations: Vec<&str> = self
+                .uses
+                .iter()
+                .filter(|u| u.is_resolved() && u.capability == capability)
+                .map(|u| u.operation.as_str())
+                .collect();
+            output.push_str(&format!("  {:<40} ({})\n", capability.to_string(), operations.join(", ")));
+        }
+
+        let unresolved: Vec<&CapabilityUse> = self.uses.iter().filter(|u| !u.is_resolved()).collect();
+        if !unresolved.is_empty() {
+            output.push_str("\nTargets only known at runtime (grant a directory or pattern that covers them):\n");
+            for capability_use in unresolved {
+                let kind = match capability_use.capability {
+                    Capability::FileWrite(_) => "FileWrite",
+                    Capability::EnvRead(_) => "EnvRead",
+                    Capability::Crate(_) => "Crate",
+                    _ => "FileRead",
+                };
+                output.push_str(&format!("  {}:<dynamic>  ({})\n", kind, capability_use.operation));
+            }
+        }
+
+        let flags = self.allow_flags();
+        if !flags.is_empty() {
+            output.push_str("\nGrant with:\n");
+            output.push_str(&format!("  --sandbox-mode {}\n", flags.join(" ")));
+        }
+        output
+    }
+
+    fn walk(&mut self, expr: &LispExpr, literals: &HashMap<String, String>) {
+        grow_stack(|| match expr {
+            LispExpr::List(elements) if !elements.is_empty() => {
+                if let Some(op) = elements[0].as_symbol() {
+                    // Declarations describe the function; they never run
+                    if op == "declare" {
+                        return;
+                    }
+                    if matches!(op, "let" | "let*")
+                        && let Some(LispExpr::List(bindings)) = elements.get(1)
+                    {
+                        let scope = Self::bind_let(bindings, literals);
+                        for binding in bindings.iter() {
+                            self.walk(binding, literals);
+                        }
+                        for body in &elements[2..] {
+                            self.walk(body, &scope);
+                        }
+                        return;
+                    }
+                    let target = elements.get(1).and_then(|arg| Self::literal(arg, literals));
+                    self.record(op, target);
+                }
+                for elem in elements.iter() {
+                    self.walk(elem, literals);
+                }
+            }
+            LispExpr::List(elements) => {
+                for elem in elements.iter() {
+                    self.walk(elem, literals);
+                }
+            }
+            // Quoted data is never executed
+            LispExpr::Quote(_) => {}
+            LispExpr::Quasiquote(inner) | LispExpr::Unquote(inner) | LispExpr::Splice(inner) => {
+                self.walk(inner, literals)
+            }
+            LispExpr::Macro { body, .. } => self.walk(body, literals),
+            LispExpr::MacroCall { args, .. } => {
+                for arg in args {
+                    self.walk(arg, literals);
+                }
+            }
+            _ => {}
+        })
+    }
+
+    fn record(&mut self, op: &str, target: Option<String>) {
+        let capability = if FILE_READ_OPS.contains(&op) {
+            Capability::FileRead(PathBuf::from(target.clone().unwrap_or_default()))
+        } else if FILE_WRITE_OPS.contains(&op) {
+            Capability::FileWrite(PathBuf::from(target.clone().unwrap_or_default()))
+        } else if NETWORK_OPS.contains(&op) {
+            // A literal URL only needs its own host and port
+            match target.as_deref().and_then(sandbox::url_host) {
+                Some(host) => Capability::Network {
+                    host_pattern: host.to_lowercase(),
+                    port: target.as_deref().and_then(sandbox::url_port),
+                },
+                None => Capability::NetworkHTTP,
+            }
+        } else if ENV_OPS.contains(&op) {
+            Capability::EnvRead(target.clone().unwrap_or_default())
+        } else if PROCESS_OPS.contains(&op) {
+            Capability::ProcessSpawn
+        } else if THREAD_OPS.contains(&op) {
+            Capability::ThreadSpawn
+        } else if TIME_OPS.contains(&op) {
+            Capability::SystemTime
+        } else if CRATE_OPS.contains(&op) {
+            Capability::Crate(target.clone().unwrap_or_default())
+        } else if op.starts_with("rust-unsafe") || op.starts_with("ffi-") {
+            Capability::UnsafeRust
+        } else {
+            return;
+        };
+
+        self.uses.push(CapabilityUse {
+            operation: op.to_string(),
+            capability,
+            target,
+        });
+    }
+
+    fn literal(expr: &LispExpr, literals: &HashMap<String, String>) -> Option<String> {
+        match expr {
+            LispExpr::String(s) => Some(s.clone()),
+            LispExpr::Symbol(name) => literals.get(name.as_str()).cloned(),
+            _ => None,
+        }
+    }
+
+    fn bind_let(bindings: &[LispExpr], literals: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut scope = literals.clone();
+        for binding in bindings {
+            if let LispExpr::List(pair) = binding
+                && let Some(name) = pair.first().and_then(|n| n.as_symbol())
+            {
+                match pair.get(1).and_then(|value| Self::literal(value, literals)) {
+                    Some(value) => scope.insert(name.to_string(), value),
+                    None => scope.remove(name),
+                };
+            }
+        }
+        scope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn infer(source: &str) -> CapabilityReport {
+        CapabilityReport::infer(&parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_infer_file_network_and_process() {
+        let report = infer(r#"
+            (read-file "/data/in.txt")
+            (write-file "/tmp/out.txt" "done")
+            (http-get "https://example.com")
+            (run-command "ls")
+            (rust-unsafe "code")
+            (getenv "HOME")
+            (spawn (+ 1 2))
+            (require-crate "regex" "1")
+        "#);
+
+        let flags = report.allow_flags();
+        assert_eq!(flags, vec![
+            "--allow-capability Crate:regex",
+            "--allow-capability EnvRead:HOME",
+            "--allow-capability FileRead:/data/in.txt",
+            "--allow-capability FileWrite:/tmp/out.txt",
+            "--allow-capability Network:example.com:443",
+            "--allow-capability ProcessSpawn",
+            "--allow-capability ThreadSpawn",
+            "--allow-capability UnsafeRust",
+        ]);
+    }
+
+    #[test]
+    fn test_infer_resolves_let_and_define_literals() {
+        let report = infer(r#"
+            (define config "/etc/app.conf")
+            (read-file config)
+            (let ((out "/tmp/log")) (write-file out "x"))
+        "#);
+
+        assert_eq!(report.capabilities(), vec![
+            Capability::FileRead(PathBuf::from("/etc/app.conf")),
+            Capability::FileWrite(PathBuf::from("/tmp/log")),
+        ]);
+    }
+
+    #[test]
+    fn test_declared_capabilities() {
+        let program = parse(tokenize(r#"
+            (defun load (name)
+              (declare (capabilities (file-read "/data") (network "api.example.com" 443) process-spawn))
+              (read-file name))
+        "#).unwrap()).unwrap();
+        let LispExpr::List(defun) = &program[0] else { panic!("expected a list") };
+
+        assert_eq!(declared_capabilities(defun).unwrap().unwrap(), vec![
+            Capability::FileRead(PathBuf::from("/data")),
+            Capability::Network { host_pattern: "api.example.com".to_string(), port: Some(443) },
+            Capability::ProcessSpawn,
+        ]);
+        assert!(CapabilityReport::infer(&program).uses().iter().all(|u| u.operation == "read-file"));
+
+        for capability in declared_capabilities(defun).unwrap().unwrap() {
+            let expr = parse(tokenize(&declaration(&capability)).unwrap()).unwrap().remove(0);
+            assert_eq!(parse_declared_capability(&expr).unwrap(), capability);
+        }
+    }
+
+    #[test]
+    fn test_invalid_capability_declarations() {
+        for source in ["(file-read)", "(network \"a.com\" 70000)", "(teleport)", "42"] {
+            let expr = parse(tokenize(source).unwrap()).unwrap().remove(0);
+            assert!(parse_declared_capability(&expr).is_err(), "{} should be rejected", source);
+        }
+        let expr = parse(tokenize("proces-spawn").unwrap()).unwrap().remove(0);
+        assert_eq!(parse_declared_capability(&expr).unwrap_err(), "Unknown capability declaration: proces-spawn (did you mean 'process-spawn'?)");
+    }
+
+    #[test]
+    fn test_dynamic_urls_need_network_http() {
+        let report = infer("(http-get (read-line))");
+        assert_eq!(report.capabilities(), vec![Capability::NetworkHTTP]);
+    }
+
+    #[test]
+    fn test_clock_needs_system_time() {
+        let report = infer("(let ((start (now))) (random) (elapsed start))");
+        assert_eq!(report.capabilities(), vec![Capability::SystemTime]);
+        assert_eq!(report.allow_flags(), vec!["--allow-capability SystemTime"]);
+    }
+
+    #[test]
+    fn test_dynamic_paths_are_unresolved() {
+        let report = infer("(read-file (read-line))");
+
+        assert_eq!(report.uses().len(), 1);
+        assert!(!report.uses()[0].is_resolved());
+        assert!(report.allow_flags().is_empty());
+        assert!(report.render("dyn.lisp").contains("FileRead:<dynamic>"));
+    }
+
+    #[test]
+    fn test_quoted_data_needs_nothing() {
+        let report = infer("'(run-command \"rm -rf /\") (+ 1 2)");
+        assert!(report.uses().is_empty());
+        assert_eq!(report.render("pure.lisp"), "pure.lisp requires no capabilities\n");
+    }
+}
@@ -0,0 +1,687 @@
+//! Command-line definitions. Each subcommand's arguments are a struct, so parsing,
+//! `--option=value`, unambiguous option prefixes, and `--help` come from clap.
+
+use crate::{load_capability_manifest, parse_capability, IrStage};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use lisp_compiler::sandbox::{parse_duration, parse_memory_size};
+use lisp_compiler::{backend, ir, lexer, lint, logging, manifest, pipeline, report, runner, sandbox, visualizer};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "lisp-compiler",
+    version,
+    about = "Compile Lisp to Rust through macro expansion, validation, and an optional sandbox",
+    after_help = "Without a subcommand, arguments are passed to `build`: `lisp-compiler example.lisp` compiles example.lisp.",
+    infer_long_args = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /// More detail on stderr: -v adds each pipeline stage's summary and timing, -vv each transform and macro expansion
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Print errors only
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// How messages on stderr are written: text, or json with one object per line
+    #[arg(long, value_name = "FORMAT", value_parser = logging::LogFormat::parse, default_value = "text", global = true)]
+    pub log_format: logging::LogFormat,
+    /// Comma-separated features that `#+feature` and `(when-feature ...)` conditionals keep code for
+    #[arg(long, value_delimiter = ',', value_name = "LIST", global = true)]
+    pub features: Vec<String>,
+    /// Look for included files in this directory after the including file's; may be repeated, and searched before RUSTY_LISP_PATH
+    #[arg(long = "include-path", value_name = "DIR", global = true)]
+    pub include_paths: Vec<PathBuf>,
+    /// Reject source with a symbol or number longer than this many bytes (sandbox mode: 4096)
+    #[arg(long, value_name = "BYTES", global = true)]
+    pub max_token_length: Option<usize>,
+    /// Reject source of more than this many tokens (sandbox mode: 1000000)
+    #[arg(long, value_name = "N", global = true)]
+    pub max_tokens: Option<usize>,
+    /// Reject source with a string literal larger than this, such as 64KB (sandbox mode: 1MB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_size, global = true)]
+    pub max_string_size: Option<usize>,
+}
+
+impl Cli {
+    /// The lexer limits given on the command line; sandbox mode fills in the others
+    pub fn lexer_limits(&self) -> lexer::LexerLimits {
+        lexer::LexerLimits { max_token_length: self.max_token_length, max_tokens: self.max_tokens, max_string_size: self.max_string_size }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compile a Lisp program to Rust, WebAssembly text, C, or bytecode (the default subcommand)
+    Build(CompileArgs),
+    /// Compile a Lisp program, then build and execute it
+    Run(RunArgs),
+    /// Report all diagnostics without generating code; exit 0 clean, 1 warnings (with --deny), 2 errors
+    Check(CheckArgs),
+    /// Precompile a library of macros and functions into a bundle that --lib loads without expanding it again
+    CompileLib(CompileLibArgs),
+    /// Run the file's deftest forms and summarize the results
+    Test(InputArgs),
+    /// Time the file's defbench forms
+    Bench(BenchArgs),
+    /// Run a program in the interpreter, stepping through evaluation
+    Debug(DebugArgs),
+    /// Evaluate forms interactively, keeping history in ~/.rusty_lisp_history
+    Repl(ReplArgs),
+    /// Evaluate a file in the interpreter, re-evaluating changed forms and their dependents when it is saved
+    Watch(WatchArgs),
+    /// Format files in place; --check lists unformatted ones
+    Fmt(FmtArgs),
+    /// Browse the AST in the HTML visualizer, reloading when the file changes
+    Serve(ServeArgs),
+    /// List the capabilities the program would need in the sandbox
+    Capabilities(CapabilitiesArgs),
+    /// Show which definitions use which, and the groups recompiled together
+    Deps(DepsArgs),
+    /// Print the JSON Schema of the IR
+    IrSchema,
+    /// Validate a JSON IR document against the schema
+    IrCheck(InputArgs),
+    /// Print IR (any format) back as Lisp source
+    IrToLisp(InputArgs),
+    /// Check that random programs read back from their source and from the printer's output
+    Selftest(SelftestArgs),
+    /// Explain an error code such as L0201, with an example and a fix; without a code, list them all
+    Explain(ExplainArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct InputArgs {
+    pub input: String,
+}
+
+#[derive(Args, Debug)]
+pub struct BuildArgs {
+    /// Lisp source, or IR with --from-ir / --from-ir-stream
+    pub input: String,
+
+    /// Comma-separated list of transforms to apply (available: echo)
+    #[arg(long, value_delimiter = ',', value_name = "LIST")]
+    pub transforms: Vec<String>,
+    /// Load a library bundle from compile-lib ahead of the program; may be repeated
+    #[arg(long = "lib", value_name = "FILE")]
+    pub libraries: Vec<String>,
+
+    /// Read IR as input instead of Lisp source
+    #[arg(long, help_heading = "IR")]
+    pub from_ir: bool,
+    /// Output IR (JSON by default) instead of Rust code
+    #[arg(long, help_heading = "IR")]
+    pub to_ir: bool,
+    /// IR encoding for --to-ir/--from-ir: json (default), cbor, msgpack; --from-ir detects it when omitted
+    #[arg(long, value_name = "FORMAT", value_parser = ir::IrFormat::parse, help_heading = "IR")]
+    pub ir_format: Option<ir::IrFormat>,
+    /// Output newline-delimited JSON IR, one expression per line
+    #[arg(long, help_heading = "IR")]
+    pub to_ir_stream: bool,
+    /// Read newline-delimited JSON IR line by line
+    #[arg(long, help_heading = "IR")]
+    pub from_ir_stream: bool,
+    /// With --to-ir: byte-identical output for identical programs (sorted keys, compact JSON, renumbered gensyms)
+    #[arg(long, help_heading = "IR")]
+    pub ir_canonical: bool,
+    /// With --to-ir: record each node's source span and the macro it came from
+    #[arg(long, help_heading = "IR")]
+    pub ir_include_spans: bool,
+    /// With --to-ir: write each symbol name once, in a string table, and refer to it by index
+    #[arg(long, help_heading = "IR")]
+    pub ir_intern: bool,
+    /// With --to-ir(-stream): export the AST as parsed, transformed, or expanded (default)
+    #[arg(long, value_name = "STAGE", value_parser = IrStage::parse, help_heading = "IR")]
+    pub ir_stage: Option<IrStage>,
+
+    /// Enable AST validation (type safety, resource bounds, FFI restrictions, complexity limits, taint tracking)
+    #[arg(long, help_heading = "Validation")]
+    pub validate_safety: bool,
+    /// With --validate-safety: validate the program before macro expansion (pre), after (post), or both (default)
+    #[arg(long, value_name = "STAGE", value_parser = pipeline::ValidationStage::parse, help_heading = "Validation")]
+    pub validation_stage: Option<pipeline::ValidationStage>,
+    /// Write all validation findings to a report file
+    #[arg(long, value_name = "PATH", help_heading = "Validation")]
+    pub validation_report: Option<String>,
+    /// Report format: text, json, or sarif (default: from file extension)
+    #[arg(long, value_name = "FORMAT", value_parser = report::ReportFormat::parse, help_heading = "Validation")]
+    pub validation_report_format: Option<report::ReportFormat>,
+    /// Rewrite the input file with unambiguous validator fixes
+    #[arg(long, help_heading = "Validation")]
+    pub apply_suggestions: bool,
+
+    /// Start `random` and `random-int` from this seed, so every run draws the same numbers
+    #[arg(long, value_name = "N")]
+    pub seed: Option<u64>,
+    /// Byte-identical output for identical input and flags: gensyms numbered per form,
+    /// canonical IR, and `random` seeded with 0 unless --seed is given
+    #[arg(long)]
+    pub deterministic: bool,
+    #[command(flatten)]
+    pub contracts: ContractArgs,
+
+    #[command(flatten)]
+    pub sandbox: SandboxOptions,
+
+    #[command(flatten)]
+    pub lints: LintArgs,
+
+    /// Output the AST as dot, html, mermaid, svg, tree, or expansion (HTML stepping through each macro expansion)
+    #[arg(long, value_name = "FORMAT", value_parser = visualizer::VisualFormat::parse, help_heading = "Visualization")]
+    pub visualize: Option<visualizer::VisualFormat>,
+    /// With --visualize: show the AST as parsed (default), transformed, or expanded
+    #[arg(long, value_name = "STAGE", value_parser = IrStage::parse, help_heading = "Visualization")]
+    pub visualize_stage: Option<IrStage>,
+    /// With --visualize dot|html: every pipeline step's input and output
+    #[arg(long, help_heading = "Visualization")]
+    pub visualize_pipeline: bool,
+    /// Write the generated code, IR, or visualization to a file instead of stdout
+    #[arg(short, long, value_name = "PATH", conflicts_with = "out_dir")]
+    pub output: Option<String>,
+    /// Write outputs into this directory, named after the input (example.rs, example.ir.json, ...);
+    /// a relative --validation-report path is placed there too
+    #[arg(long, value_name = "DIR")]
+    pub out_dir: Option<String>,
+    /// Write a Cargo project into this directory: Cargo.toml with the program's require-crate
+    /// dependencies, and the generated Rust as src/main.rs
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output", "out_dir"])]
+    pub emit_project: Option<String>,
+    /// Mark the generated Rust with the Lisp location of each form, as `// lisp:` comments
+    /// or in a JSON map written next to the output (<output>.map)
+    #[arg(long, value_name = "MODE", value_enum)]
+    pub source_map: Option<SourceMapMode>,
+    /// Write the Rust name of each function, struct, field, trait, and method, and
+    /// whether it is exported, to a JSON file
+    #[arg(long, value_name = "PATH")]
+    pub name_map: Option<String>,
+    /// Start the generated Rust with a comment recording the compiler version, input
+    /// hashes, transforms, validators, sandbox, and build time, also written as JSON
+    /// next to the output (<output>.provenance.json)
+    #[arg(long)]
+    pub provenance: bool,
+    /// With --visualize dot: draw N levels below each top-level form
+    #[arg(long, value_name = "N", help_heading = "Visualization")]
+    pub dot_max_depth: Option<usize>,
+    /// With --visualize dot: summarize subtrees larger than this many nodes
+    #[arg(long, value_name = "NODES", help_heading = "Visualization")]
+    pub dot_collapse: Option<usize>,
+    /// With --visualize dot: draw only the definition of this function or macro
+    #[arg(long, value_name = "NAME", help_heading = "Visualization")]
+    pub dot_focus: Option<String>,
+    /// Same as --visualize dot
+    #[arg(long, help_heading = "Visualization")]
+    pub ast_dot: bool,
+    /// Same as --visualize html
+    #[arg(long, help_heading = "Visualization")]
+    pub ast_visual: bool,
+}
+
+/// Sandbox settings; see `impl SandboxOptions` for how they are layered
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "Sandbox")]
+pub struct SandboxOptions {
+    /// Enable sandbox execution with security restrictions
+    #[arg(long)]
+    pub sandbox_mode: bool,
+    /// Start from a preset (strict, standard, permissive) or a [profiles.<name>] table in the manifest
+    #[arg(long = "sandbox-profile", value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Load granted capabilities and limits from a TOML manifest
+    #[arg(long = "capabilities", value_name = "FILE", value_parser = load_capability_manifest)]
+    pub manifest: Option<manifest::CapabilityManifest>,
+    /// Set maximum memory limit (e.g., 100MB, 1GB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_size)]
+    pub max_memory: Option<usize>,
+    /// Set maximum execution time (e.g., 30s, 5m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+    /// Abort after evaluating this many forms (deterministic limit)
+    #[arg(long, value_name = "FORMS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_fuel: Option<u64>,
+    /// Fail compilation when one macro call generates more than this many nodes
+    #[arg(long, value_name = "NODES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_expansion_nodes: Option<u64>,
+    /// Grant a capability (see below); may be repeated
+    #[arg(long = "allow-capability", value_name = "CAP", value_parser = parse_capability)]
+    pub granted: Vec<sandbox::Capability>,
+    /// Revoke a capability, overriding profiles, manifests, and grants
+    #[arg(long = "deny-capability", value_name = "CAP", value_parser = parse_capability)]
+    pub denied: Vec<sandbox::Capability>,
+    /// Append every capability check of the sandboxed run to a JSONL file
+    #[arg(long = "sandbox-audit-log", value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CompileArgs {
+    /// Generate rust, or wat (WebAssembly text), c (C99), or bytecode for programs using only numbers, booleans, and functions
+    #[arg(long, value_name = "TARGET", value_parser = backend::backend, default_value = "rust")]
+    pub target: &'static dyn backend::Backend,
+    #[command(flatten)]
+    pub build: BuildArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Execute natively or in the embedded WASM runtime (wasm needs the `wasm` feature)
+    #[arg(long, value_name = "TARGET", value_parser = runner::Target::parse, default_value = "native")]
+    pub target: runner::Target,
+    /// Build with rustc, or run bytecode in the built-in VM (numeric programs only)
+    #[arg(long, value_name = "ENGINE", value_parser = runner::Engine::parse, default_value = "rustc")]
+    pub engine: runner::Engine,
+    #[command(flatten)]
+    pub build: BuildArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CompileLibArgs {
+    pub input: String,
+    /// Where to write the bundle (default: the input with the extension .rlib.json)
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<String>,
+    /// A library bundle whose macros this library uses; may be repeated
+    #[arg(long = "lib", value_name = "FILE")]
+    pub libraries: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    pub input: String,
+    #[command(flatten)]
+    pub lints: LintArgs,
+    /// Diagnostic output format: text, json, or sarif
+    #[arg(long, value_name = "FORMAT", value_parser = report::ReportFormat::parse, default_value = "text")]
+    pub format: report::ReportFormat,
+    /// Write the diagnostics to a file instead of stdout
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<String>,
+    /// Include each function's cyclomatic complexity, form count, and argument count
+    #[arg(long)]
+    pub stats: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceMapMode {
+    Comments,
+    Json,
+}
+
+/// Lint levels, combined by `LintArgs::levels`
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "Lints")]
+pub struct LintArgs {
+    /// Do not report a lint: deprecated, shadowed-builtins, or warnings for every lint; may be repeated
+    #[arg(long, value_name = "LINT", value_parser = lint::Lint::parse)]
+    pub allow: Vec<lint::Lint>,
+    /// Report a lint as a warning, the default
+    #[arg(long, value_name = "LINT", value_parser = lint::Lint::parse)]
+    pub warn: Vec<lint::Lint>,
+    /// Report a lint as an error; with check, --deny warnings also exits 1 for any warning
+    #[arg(long, value_name = "LINT", value_parser = lint::Lint::parse)]
+    pub deny: Vec<lint::Lint>,
+}
+
+impl LintArgs {
+    /// A lint named under several levels gets the strictest
+    pub fn levels(&self) -> lint::LintLevels {
+        let given = [(&self.allow, lint::LintLevel::Allow), (&self.warn, lint::LintLevel::Warn), (&self.deny, lint::LintLevel::Deny)];
+        given.into_iter().fold(lint::LintLevels::new(), |levels, (lints, level)| {
+            lints.iter().fold(levels, |levels, lint| levels.with_level(*lint, level))
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    pub input: String,
+    /// Untimed runs of each benchmark before measuring
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub warmup: usize,
+    /// Timed runs of each benchmark
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub iterations: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct DebugArgs {
+    pub input: String,
+    /// Stop at calls to this function or macro (repeatable); without any, stop at the first form
+    #[arg(short = 'b', long = "break", value_name = "NAME")]
+    pub breakpoints: Vec<String>,
+    #[command(flatten)]
+    pub contracts: ContractArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    pub input: String,
+    /// Milliseconds between checks for changes
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub interval: u64,
+    #[command(flatten)]
+    pub contracts: ContractArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ReplArgs {
+    #[command(flatten)]
+    pub contracts: ContractArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ContractArgs {
+    /// Check `(declare (requires ...) (ensures ...))` on each call (runtime) or skip the checks (off)
+    #[arg(long = "contracts", value_name = "MODE", value_enum, default_value_t = ContractMode::Runtime)]
+    pub mode: ContractMode,
+}
+
+impl ContractArgs {
+    pub fn enabled(&self) -> bool {
+        self.mode == ContractMode::Runtime
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContractMode {
+    Runtime,
+    Off,
+}
+
+#[derive(Args, Debug)]
+pub struct FmtArgs {
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// List files that are not formatted and exit 1 instead of rewriting them
+    #[arg(long)]
+    pub check: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    pub input: String,
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+}
+
+#[derive(Args, Debug)]
+pub struct CapabilitiesArgs {
+    pub input: String,
+    /// Also write the inferred capabilities as a manifest for --capabilities
+    #[arg(long, value_name = "MANIFEST")]
+    pub write_manifest: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct DepsArgs {
+    pub input: String,
+    /// Output format
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = DepsFormat::Text)]
+    pub format: DepsFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepsFormat {
+    Text,
+    Dot,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    /// Programs to generate and check
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub cases: u64,
+    /// Seed of the first program; case k is generated from seed + k
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct ExplainArgs {
+    /// The code from a diagnostic, such as L0201
+    pub code: Option<String>,
+}
+
+/// Parse the process arguments, exiting with a usage message on errors
+pub fn parse() -> Cli {
+    parse_from(std::env::args()).unwrap_or_else(|err| err.exit())
+}
+
+/// Parse `args`, treating them as `build` arguments when no subcommand is named
+pub fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Cli, clap::Error> {
+    let mut args: Vec<String> = args.into_iter().collect();
+    let command = command();
+    // Logging options may come before the subcommand
+    let mut first = 1;
+    while let Some(arg) = args.get(first) {
+        match arg.as_str() {
+            "-q" | "--quiet" | "--verbose" => first += 1,
+            "--log-format" => first += 2,
+            arg if arg.starts_with("--log-format=") || (arg.len() > 1 && arg[1..].bytes().all(|b| b == b'v')) => first += 1,
+            _ => break,
+        }
+    }
+    let named = args.get(first).is_none_or(|first| {
+        matches!(first.as_str(), "-h" | "--help" | "-V" | "--version" | "help")
+            || command.get_subcommands().any(|subcommand| subcommand.get_name() == first)
+    });
+    if !named {
+        args.insert(1, "build".to_string());
+    }
+    Cli::from_arg_matches(&command.try_get_matches_from(args)?)
+}
+
+/// Report an invalid combination of `subcommand`'s arguments the way clap reports its own errors
+pub fn usage_error(subcommand: &str, message: impl Display) -> ! {
+    let mut command = command();
+    command.build();
+    let subcommand = command.find_subcommand_mut(subcommand).expect("usage error for a defined subcommand");
+    subcommand.error(clap::error::ErrorKind::ArgumentConflict, message).exit()
+}
+
+fn command() -> clap::Command {
+    let sandbox_help = sandbox_help();
+    Cli::command()
+        .mut_subcommand("build", |build| build.after_help(sandbox_help.clone()))
+        .mut_subcommand("run", |run| run.after_help(sandbox_help))
+}
+
+/// The capability and profile names the sandbox options accept, with examples
+fn sandbox_help() -> String {
+    let mut help = String::from(
+        "Capabilities:\n\
+         \x20 FileRead:<path>             Allow reading from specific file path\n\
+         \x20 FileWrite:<path>            Allow writing to specific file path\n\
+         \x20 NetworkHTTP                 Allow HTTP network requests to any host\n\
+         \x20 EnvRead:<name>              Allow reading environment variables matching a name or glob\n\
+         \x20 Network:<host>[:<port>]     Allow HTTP requests to one host (or *.domain), optionally one port\n\
+         \x20 SystemTime                  Allow accessing system time\n\
+         \x20 ProcessSpawn                Allow spawning child processes\n\
+         \x20 ThreadSpawn                 Allow spawning threads (spawn, thread, async)\n\
+         \x20 UnsafeRust                  Allow using unsafe Rust features\n\
+         \x20 Crate:<name>                Allow require-crate of crates matching a name or glob\n\
+         \nSandbox profiles:\n",
+    );
+    for profile in sandbox::SandboxProfile::ALL {
+        help.push_str(&format!("  {:<28}{}\n", profile.name(), profile.description()));
+    }
+    help.push_str(&format!(
+        "\nThe wasm run target needs the `wasm` feature and the {} rustc target.\n\
+         \nExamples:\n\
+         \x20 lisp-compiler example.lisp                     # Compile Lisp to Rust\n\
+         \x20 lisp-compiler --validate-safety example.lisp   # Compile with validation\n\
+         \x20 lisp-compiler --to-ir example.lisp > out.json  # Convert Lisp to JSON IR\n\
+         \x20 lisp-compiler --from-ir out.json               # Compile JSON IR to Rust\n\
+         \x20 lisp-compiler --target wat -o out.wat example.lisp  # Compile to WebAssembly text\n\
+         \x20 lisp-compiler --target c -o out.c example.lisp    # Compile to C99\n\
+         \x20 lisp-compiler run --engine vm --sandbox-mode --max-fuel 1000000 example.lisp\n\
+         \x20 lisp-compiler --validation-report out.sarif example.lisp  # Write SARIF findings\n\
+         \x20 lisp-compiler run --sandbox-mode --max-memory=100MB --timeout=30s example.lisp\n\
+         \x20 lisp-compiler run --sandbox-mode --allow-capability=FileRead:/tmp example.lisp\n\
+         \x20 lisp-compiler --sandbox-profile standard --allow-capability NetworkHTTP example.lisp\n\
+         \x20 lisp-compiler --ast-dot example.lisp | dot -Tpng > ast.png  # Visualize AST as PNG\n\
+         \x20 lisp-compiler --visualize html --visualize-stage expanded -o ast.html example.lisp",
+        runner::WASM_TARGET
+    ));
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Cli, clap::Error> {
+        parse_from(std::iter::once("lisp-compiler").chain(args.split_whitespace()).map(String::from))
+    }
+
+    #[test]
+    fn test_command_definition() {
+        command().debug_assert();
+    }
+
+    #[test]
+    fn test_build_is_the_default_command() {
+        let Command::Build(args) = parse_args("--to-ir --ir-stage=parsed example.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.target.name(), "rust");
+        assert_eq!(args.build.input, "example.lisp");
+        assert!(args.build.to_ir);
+        assert_eq!(args.build.ir_stage, Some(IrStage::Parsed));
+
+        let Command::Build(args) = parse_args("build --transforms echo,echo check").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.build.input, "check");
+        assert_eq!(args.build.transforms, vec!["echo", "echo"]);
+
+        let Command::Build(args) = parse_args("--to-ir --out-dir build x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.build.out_dir.as_deref(), Some("build"));
+        assert!(parse_args("-o x.rs --out-dir build x.lisp").is_err());
+
+        let Command::Build(args) = parse_args("--target wat x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.target.name(), "wat");
+        let Command::Build(args) = parse_args("build --target c x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.target.name(), "c");
+        assert!(parse_args("run --target wat x.lisp").is_err());
+        assert!(parse_args("--target fortran x.lisp").is_err());
+        let Command::Run(args) = parse_args("run --engine vm --max-fuel 1000 x.lisp").unwrap().command else {
+            panic!("expected run");
+        };
+        assert_eq!(args.engine, runner::Engine::Vm);
+        assert!(parse_args("--engine vm x.lisp").is_err());
+    }
+
+    #[test]
+    fn test_option_values_and_abbreviations() {
+        let Command::Run(args) =
+            parse_args("run --target wasm --max-mem=1MB --allow-cap FileRead:/tmp --allow-cap=SystemTime x.lisp")
+                .unwrap()
+                .command
+        else {
+            panic!("expected run");
+        };
+        assert_eq!(args.target, runner::Target::Wasm);
+        assert_eq!(args.engine, runner::Engine::Rustc);
+        assert_eq!(args.build.sandbox.max_memory, Some(1024 * 1024));
+        assert_eq!(args.build.sandbox.granted.len(), 2);
+
+        // `--ir` is a prefix of several options
+        assert!(parse_args("--ir x.lisp").is_err());
+        assert!(parse_args("--max-fuel 0 x.lisp").is_err());
+        assert!(parse_args("--target wasm x.lisp").is_err());
+        assert!(parse_args("--ir-stage compiled x.lisp").unwrap_err().to_string().contains("Unknown IR stage"));
+        let Command::Build(args) = parse_args("--source-map json -o x.rs x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.build.source_map, Some(SourceMapMode::Json));
+        let Command::Build(args) = parse_args("--name-map names.json x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.build.name_map.as_deref(), Some("names.json"));
+        let Command::Build(args) = parse_args("--provenance --deterministic -o x.rs x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert!(args.build.provenance && args.build.deterministic);
+        assert!(args.build.contracts.enabled());
+        assert!(parse_args("--source-map lines x.lisp").is_err());
+        let Command::Run(args) = parse_args("run --contracts off x.lisp").unwrap().command else {
+            panic!("expected run");
+        };
+        assert!(!args.build.contracts.enabled());
+    }
+
+    #[test]
+    fn test_logging_options() {
+        let cli = parse_args("-vv --log-format json check x.lisp").unwrap();
+        assert!(matches!(cli.command, Command::Check(_)));
+        assert_eq!((cli.verbose, cli.log_format), (2, logging::LogFormat::Json));
+
+        let cli = parse_args("-q x.lisp").unwrap();
+        assert!(cli.quiet && matches!(cli.command, Command::Build(_)));
+        assert_eq!(parse_args("build x.lisp -v").unwrap().verbose, 1);
+        assert_eq!(parse_args("check --features sandbox,wasm x.lisp").unwrap().features, vec!["sandbox", "wasm"]);
+        assert_eq!(parse_args("build --include-path a x.lisp --include-path b").unwrap().include_paths, vec![PathBuf::from("a"), PathBuf::from("b")]);
+        assert!(parse_args("-q -v x.lisp").is_err());
+        let limits = parse_args("check --max-tokens 100 --max-string-size 64KB x.lisp").unwrap().lexer_limits();
+        assert_eq!(limits, lexer::LexerLimits { max_token_length: None, max_tokens: Some(100), max_string_size: Some(64 * 1024) });
+    }
+
+    #[test]
+    fn test_subcommand_arguments() {
+        let Command::Check(args) = parse_args("check --deny warnings --format=json x.lisp").unwrap().command else {
+            panic!("expected check");
+        };
+        assert_eq!(args.lints.deny, vec![lint::Lint::Warnings]);
+        assert_eq!(args.format, report::ReportFormat::Json);
+        let Command::Build(args) = parse_args("--allow deprecated --deny deprecated --allow-cap SystemTime x.lisp").unwrap().command else {
+            panic!("expected build");
+        };
+        assert_eq!(args.build.lints.levels().level(lint::Lint::Deprecated), lint::LintLevel::Deny);
+        assert_eq!(args.build.sandbox.granted, vec![sandbox::Capability::SystemTime]);
+        assert!(parse_args("check --warn unused x.lisp").unwrap_err().to_string().contains("Unknown lint 'unused'"));
+
+        let Command::Bench(args) = parse_args("bench --iter 5 x.lisp").unwrap().command else {
+            panic!("expected bench");
+        };
+        assert_eq!((args.warmup, args.iterations), (10, 5));
+
+        let Command::Debug(args) = parse_args("debug -b fact --break=loop x.lisp").unwrap().command else {
+            panic!("expected debug");
+        };
+        assert_eq!(args.breakpoints, vec!["fact", "loop"]);
+
+        let Command::CompileLib(args) = parse_args("compile-lib --lib base.rlib.json prelude.lisp -o out.json").unwrap().command else {
+            panic!("expected compile-lib");
+        };
+        assert_eq!((args.input.as_str(), args.output.as_deref(), args.libraries), ("prelude.lisp", Some("out.json"), vec!["base.rlib.json".to_string()]));
+
+        assert!(parse_args("fmt --check").is_err());
+        assert!(matches!(parse_args("ir-schema").unwrap().command, Command::IrSchema));
+        assert!(matches!(parse_args("repl").unwrap().command, Command::Repl(ReplArgs { contracts: ContractArgs { mode: ContractMode::Runtime } })));
+        let Command::Watch(args) = parse_args("watch --contracts off x.lisp").unwrap().command else {
+            panic!("expected watch");
+        };
+        assert!(!args.contracts.enabled());
+        assert!(parse_args("debug --contracts sometimes x.lisp").is_err());
+        assert!(matches!(parse_args("deps --format dot x.lisp").unwrap().command, Command::Deps(DepsArgs { format: DepsFormat::Dot, .. })));
+        assert!(matches!(parse_args("watch x.lisp").unwrap().command, Command::Watch(WatchArgs { interval: 500, .. })));
+        assert!(matches!(parse_args("selftest --seed 7").unwrap().command, Command::Selftest(SelftestArgs { cases: 1000, seed: 7 })));
+        assert!(matches!(parse_args("explain L0201").unwrap().command, Command::Explain(ExplainArgs { code: Some(code) }) if code == "L0201"));
+        let help = parse_args("serve --help").unwrap_err();
+        assert_eq!(help.kind(), clap::error::ErrorKind::DisplayHelp);
+        assert!(help.to_string().contains("--port <PORT>"));
+        let help = parse_args("help").unwrap_err().to_string();
+        assert!(help.contains("build         Compile a Lisp program to Rust, WebAssembly text, C, or bytecode"), "{}", help);
+    }
+}
@@ -1,189 +1,2560 @@
-use crate::ast::LispExpr;
+use crate::ast::{grow_stack, walk_expr, walk_expr_mut, LispExpr, Visitor, VisitorMut};
+use crate::backend::Backend;
+use crate::explain::CompileError;
+use crate::pattern::{MatchError, Pattern};
+use crate::sandbox::{self, Capability, SandboxConfig, SandboxMonitor};
+use crate::source_map::{LocationTree, SourceLocation};
+use crate::contracts::{self, Contract};
+use crate::crates;
+use crate::declarations::{self, Declarations};
+use crate::deps::DefinitionKind;
+use crate::lambda_list::{self, LambdaList, Parameter};
+use crate::mangle::{self, identifier};
+use crate::structs::StructDef;
+use crate::suggest;
+use crate::symbol::Symbol;
+use crate::traits::{ImplDef, TraitDef};
+use crate::types::{self, Typed};
+use crate::validator::{InferredType, TypeSafetyValidator};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
 
-pub fn compile_to_rust(expressions: &[LispExpr]) -> Result<String, String> {
-    let mut compiler = RustCompiler::new();
+pub fn compile_to_rust(expressions: &[LispExpr]) -> Result<String, CompileError> {
+    compile_program(expressions, None, None)
+}
+
+/// The Rust expression for one form, without the program around it
+pub fn compile_expression(expr: &LispExpr) -> Result<String, CompileError> {
+    RustCompiler::new(None).compile_expression(&rename_gensyms(std::slice::from_ref(expr))[0])
+}
+
+/// The Rust for `expr` where the top-level definitions among `definitions` are in
+/// scope: the items they compile to, then the expression, or only the items when
+/// `expr` is itself a definition
+pub fn compile_expression_with(definitions: &[LispExpr], expr: &LispExpr) -> Result<String, CompileError> {
+    let mut program: Vec<LispExpr> = definitions.iter().filter(|definition| is_item(definition)).cloned().collect();
+    program.push(expr.clone());
+    let program = rename_gensyms(&program);
+    let (expr, items) = program.split_last().unwrap();
+    let mut compiler = RustCompiler::new(None);
+    if is_item(expr) {
+        compiler.define_items(&program)?;
+        return Ok(compiler.items.trim_end().to_string());
+    }
+    compiler.define_items(items)?;
+    let compiled = compiler.compile_expression(expr)?;
+    Ok(format!("{}{}", compiler.items, compiled))
+}
+
+/// Compile with sandbox enforcement: the output embeds a `sandbox_rt` module and
+/// routes file, network, and process operations and allocations through it
+pub fn compile_to_rust_sandboxed(expressions: &[LispExpr], config: &SandboxConfig) -> Result<String, CompileError> {
+    compile_program(expressions, Some(config), None)
+}
+
+/// Compile with `random` and `random-int` starting from `seed` instead of the clock,
+/// so every run of the program draws the same numbers
+pub fn compile_to_rust_seeded(expressions: &[LispExpr], sandbox: Option<&SandboxConfig>, seed: u64) -> Result<String, CompileError> {
+    compile_program(expressions, sandbox, Some(seed))
+}
+
+/// Compile with the code from each expression, and from each form in a function
+/// body, preceded by a `// lisp:` comment naming its location in `locations`, which
+/// holds one entry per expression
+pub fn compile_to_rust_mapped(
+    expressions: &[LispExpr],
+    sandbox: Option<&SandboxConfig>,
+    seed: Option<u64>,
+    locations: &[LocationTree],
+) -> Result<String, CompileError> {
+    compile_program_at(expressions, sandbox, seed, locations)
+}
+
+/// The Rust generator, the default backend
+pub struct RustBackend;
+
+impl Backend for RustBackend {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn extension(&self) -> &'static str {
+        "rs"
+    }
+
+    fn supports_sandbox(&self) -> bool {
+        true
+    }
+
+    /// The `sandbox_rt` module of sandboxed programs. The other runtime modules are
+    /// only emitted for programs that call into them.
+    fn emit_prelude(&self, sandbox: Option<&SandboxConfig>) -> String {
+        sandbox.map(|config| sandbox_runtime(config) + "\n").unwrap_or_default()
+    }
+
+    fn compile_expr(&self, expr: &LispExpr) -> Result<String, CompileError> {
+        compile_expression(expr)
+    }
+
+    fn compile_program(&self, program: &[LispExpr], sandbox: Option<&SandboxConfig>, seed: Option<u64>) -> Result<String, CompileError> {
+        crate::pipeline::compile_rust(program, sandbox, seed)
+    }
+
+    fn compile_program_mapped(
+        &self,
+        program: &[LispExpr],
+        sandbox: Option<&SandboxConfig>,
+        seed: Option<u64>,
+        locations: &[LocationTree],
+    ) -> Result<String, CompileError> {
+        crate::pipeline::compile_rust_mapped(program, sandbox, seed, locations)
+    }
+}
+
+fn compile_program(expressions: &[LispExpr], sandbox: Option<&SandboxConfig>, seed: Option<u64>) -> Result<String, CompileError> {
+    compile_program_at(expressions, sandbox, seed, &[])
+}
+
+fn compile_program_at(
+    expressions: &[LispExpr],
+    sandbox: Option<&SandboxConfig>,
+    seed: Option<u64>,
+    locations: &[LocationTree],
+) -> Result<String, CompileError> {
+    let expressions = &*rename_gensyms(expressions);
+    let mut compiler = RustCompiler::new(sandbox);
+    compiler.locations = locations;
+    compiler.define_items(expressions)?;
     
-    let mut rust_code = String::new();
-    rust_code.push_str("fn main() {\n");
+    let mut main = String::from("fn main() {\n");
+    if sandbox.is_some() {
+        main.push_str("    sandbox_rt::start();\n");
+    }
     
-    for expr in expressions {
+    // Tests and benchmarks only run under `test` and `bench`, like `#[cfg(test)]` code
+    // Definitions are items before `main`, not statements in it
+    for (index, expr) in expressions.iter().enumerate().filter(|(_, expr)| test_name(expr).is_none() && bench_name(expr).is_none() && !is_item(expr)) {
         let compiled_expr = compiler.compile_expression(expr)?;
-        rust_code.push_str(&format!("    println!(\"{{:?}}\", {});\n", compiled_expr));
+        if let Some(location) = compiler.location(index) {
+            main.push_str(&location.comment("    "));
+        }
+        main.push_str(&format!("    println!(\"{{:?}}\", {});\n", compiled_expr));
+    }
+    main.push_str("}\n");
+
+    let mut rust_code = RustBackend.emit_prelude(sandbox);
+    rust_code.push_str(&compiler.support_modules(seed));
+    rust_code.push_str(&main);
+    Ok(rust_code)
+}
+
+/// The `Json` type behind `json-parse`, `json-get`, and `json-stringify`. Objects keep
+/// their members in source order; malformed JSON panics like a failed `string->number`.
+const JSON_RUNTIME: &str = r#"#[allow(dead_code)]
+mod lisp_json {
+    use std::fmt;
+
+    #[derive(Clone, PartialEq)]
+    pub enum Json {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    pub fn parse(text: &str) -> Json {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value();
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            parser.fail("the end of the input");
+        }
+        value
+    }
+
+    /// The member `key` of an object, or the element at index `key` of an array; null when missing
+    pub fn get(value: &Json, key: impl fmt::Display) -> Json {
+        let key = key.to_string();
+        let found = match value {
+            Json::Object(entries) => entries.iter().find(|(name, _)| *name == key).map(|(_, member)| member),
+            Json::Array(elements) => key.parse::<usize>().ok().and_then(|index| elements.get(index)),
+            _ => None,
+        };
+        found.cloned().unwrap_or(Json::Null)
+    }
+
+    pub fn stringify<T: Clone + Into<Json>>(value: &T) -> String {
+        value.clone().into().to_string()
+    }
+
+    struct Parser<'a> {
+        text: &'a str,
+        pos: usize,
+    }
+
+    impl Parser<'_> {
+        fn fail(&self, expected: &str) -> ! {
+            panic!("json-parse: expected {} at byte {}", expected, self.pos)
+        }
+
+        fn skip_whitespace(&mut self) {
+            let rest = &self.text[self.pos..];
+            self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+        }
+
+        fn eat(&mut self, token: &str) -> bool {
+            self.skip_whitespace();
+            let found = self.text[self.pos..].starts_with(token);
+            if found {
+                self.pos += token.len();
+            }
+            found
+        }
+
+        fn value(&mut self) -> Json {
+            self.skip_whitespace();
+            match self.text[self.pos..].chars().next() {
+                Some('n') if self.eat("null") => Json::Null,
+                Some('t') if self.eat("true") => Json::Bool(true),
+                Some('f') if self.eat("false") => Json::Bool(false),
+                Some('"') => Json::String(self.string()),
+                Some('[') => {
+                    self.pos += 1;
+                    let mut elements = Vec::new();
+                    if !self.eat("]") {
+                        loop {
+                            elements.push(self.value());
+                            if self.eat("]") {
+                                break;
+                            }
+                            if !self.eat(",") {
+                                self.fail("',' or ']'");
+                            }
+                        }
+                    }
+                    Json::Array(elements)
+                }
+                Some('{') => {
+                    self.pos += 1;
+                    let mut entries = Vec::new();
+                    if !self.eat("}") {
+                        loop {
+                            self.skip_whitespace();
+                            if !self.text[self.pos..].starts_with('"') {
+                                self.fail("a string key");
+                            }
+                            let key = self.string();
+                            if !self.eat(":") {
+                                self.fail("':'");
+                            }
+                            entries.push((key, self.value()));
+                            if self.eat("}") {
+                                break;
+                            }
+                            if !self.eat(",") {
+                                self.fail("',' or '}'");
+                            }
+                        }
+                    }
+                    Json::Object(entries)
+                }
+                Some(c) if c == '-' || c.is_ascii_digit() => {
+                    let rest = &self.text[self.pos..];
+                    let len = rest.find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E')).unwrap_or(rest.len());
+                    let number = rest[..len].parse().unwrap_or_else(|_| self.fail("a number"));
+                    self.pos += len;
+                    Json::Number(number)
+                }
+                _ => self.fail("a value"),
+            }
+        }
+
+        /// The string literal whose opening quote is at `pos`
+        fn string(&mut self) -> String {
+            let text = self.text;
+            let mut chars = text[self.pos + 1..].char_indices();
+            let mut string = String::new();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        self.pos += i + 2;
+                        return string;
+                    }
+                    '\\' => match chars.next().map(|(_, escaped)| escaped) {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some('r') => string.push('\r'),
+                        Some('b') => string.push('\u{8}'),
+                        Some('f') => string.push('\u{c}'),
+                        Some('u') => {
+                            let mut code = hex4(&mut chars);
+                            // A high surrogate combines with the low surrogate escaped after it
+                            if (0xD800..0xDC00).contains(&code) && chars.as_str().starts_with("\\u") {
+                                chars.nth(1);
+                                code = 0x10000 + ((code - 0xD800) << 10) + (hex4(&mut chars).wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            string.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        Some(escaped @ ('"' | '\\' | '/')) => string.push(escaped),
+                        _ => self.fail("an escape sequence"),
+                    },
+                    c => string.push(c),
+                }
+            }
+            self.fail("a closing quote")
+        }
+    }
+
+    fn hex4(chars: &mut std::str::CharIndices) -> u32 {
+        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+        u32::from_str_radix(&hex, 16).unwrap_or(0xFFFD)
+    }
+
+    fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in s.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+
+    /// Compact JSON text
+    impl fmt::Display for Json {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Json::Null => write!(f, "null"),
+                Json::Bool(b) => write!(f, "{}", b),
+                Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+                Json::Number(_) => write!(f, "null"),
+                Json::String(s) => write_string(f, s),
+                Json::Array(elements) => {
+                    write!(f, "[")?;
+                    for (i, element) in elements.iter().enumerate() {
+                        write!(f, "{}{}", if i > 0 { "," } else { "" }, element)?;
+                    }
+                    write!(f, "]")
+                }
+                Json::Object(entries) => {
+                    write!(f, "{{")?;
+                    for (i, (key, value)) in entries.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ",")?;
+                        }
+                        write_string(f, key)?;
+                        write!(f, ":{}", value)?;
+                    }
+                    write!(f, "}}")
+                }
+            }
+        }
+    }
+
+    impl fmt::Debug for Json {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self)
+        }
+    }
+
+    impl From<f64> for Json {
+        fn from(n: f64) -> Self {
+            Json::Number(n)
+        }
+    }
+
+    impl From<i32> for Json {
+        fn from(n: i32) -> Self {
+            Json::Number(n.into())
+        }
+    }
+
+    impl From<i64> for Json {
+        fn from(n: i64) -> Self {
+            Json::Number(n as f64)
+        }
+    }
+
+    impl From<bool> for Json {
+        fn from(b: bool) -> Self {
+            Json::Bool(b)
+        }
+    }
+
+    impl From<&str> for Json {
+        fn from(s: &str) -> Self {
+            Json::String(s.to_string())
+        }
+    }
+
+    impl From<String> for Json {
+        fn from(s: String) -> Self {
+            Json::String(s)
+        }
+    }
+
+    impl From<()> for Json {
+        fn from(_: ()) -> Self {
+            Json::Null
+        }
+    }
+
+    impl<T: Into<Json>> From<Vec<T>> for Json {
+        fn from(elements: Vec<T>) -> Self {
+            Json::Array(elements.into_iter().map(Into::into).collect())
+        }
+    }
+}
+"#;
+
+/// The clock behind `now` and `elapsed`: the wall-clock time of the first reading,
+/// advanced by a monotonic `Instant`, so later readings are never earlier ones
+const CLOCK_RUNTIME: &str = r#"#[allow(dead_code)]
+mod lisp_clock {
+    use std::sync::OnceLock;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    static START: OnceLock<(Instant, f64)> = OnceLock::new();
+
+    /// Milliseconds since the Unix epoch
+    pub fn now() -> f64 {
+        let (start, epoch) = START.get_or_init(|| {
+            (Instant::now(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64() * 1000.0)
+        });
+        epoch + start.elapsed().as_secs_f64() * 1000.0
+    }
+}
+"#;
+
+/// The splitmix64 generator behind `random` and `random-int`, the same one the
+/// interpreter uses. Without a seed it starts from the clock.
+fn random_runtime(seed: Option<u64>) -> String {
+    format!(
+        r#"#[allow(dead_code)]
+mod lisp_rng {{
+    use std::sync::OnceLock;
+    use std::sync::atomic::{{AtomicU64, Ordering}};
+
+    const SEED: Option<u64> = {seed:?};
+    const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+    static STATE: OnceLock<AtomicU64> = OnceLock::new();
+
+    fn next() -> u64 {{
+        let state = STATE.get_or_init(|| {{
+            AtomicU64::new(SEED.unwrap_or_else(|| {{
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+            }}))
+        }});
+        let mut z = state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }}
+
+    pub fn random() -> f64 {{
+        (next() >> 11) as f64 / (1u64 << 53) as f64
+    }}
+
+    /// An integer from `lo` up to but not including `hi`
+    pub fn random_int(lo: impl Into<f64>, hi: impl Into<f64>) -> i64 {{
+        let (lo, hi) = (lo.into(), hi.into());
+        if lo.fract() != 0.0 || hi.fract() != 0.0 || hi <= lo {{
+            panic!("'random-int' requires integers lo < hi, got {{}} and {{}}", lo, hi);
+        }}
+        lo as i64 + (next() % (hi - lo) as u64) as i64
+    }}
+}}
+"#
+    )
+}
+
+/// The name of a `(deftest name body...)` form
+pub fn test_name(expr: &LispExpr) -> Option<&str> {
+    definition_name(expr, "deftest")
+}
+
+/// The name of a `(defbench name expr)` form
+pub fn bench_name(expr: &LispExpr) -> Option<&str> {
+    definition_name(expr, "defbench")
+}
+
+/// Operators and built-in functions `compile_call` generates code for, offered as
+/// suggestions for unknown functions
+pub const BUILTIN_CALLS: &[&str] = &[
+    "+", "-", "*", "/", "mod", "=", "<", ">", "<=", ">=", "if", "assert", "let", "let*", "destructuring-bind", "values", "let-values",
+    "list", "string->number", "number->string", "symbol->string", "to-bool", "read-file", "write-file", "run-command",
+    "http-get", "http-post", "getenv", "now", "elapsed", "random", "random-int", "json-parse", "json-get", "json-stringify",
+    "spawn", "thread", "async",
+];
+
+/// Forms compiled to Rust items before `main` rather than to statements in it
+const ITEM_FORMS: &[&str] = &["defstruct", "deftrait", "defimpl", "defun", "require-crate"];
+
+/// Whether `expr` is a definition compiled to a Rust item: a struct, trait, impl, function, or `use`
+pub fn is_item(expr: &LispExpr) -> bool {
+    matches!(expr.as_list().and_then(|elements| elements.first()).and_then(LispExpr::as_symbol), Some(form) if ITEM_FORMS.contains(&form))
+}
+
+fn definition_name<'a>(expr: &'a LispExpr, keyword: &str) -> Option<&'a str> {
+    match expr.as_list()? {
+        [head, LispExpr::Symbol(name), ..] if head.as_symbol() == Some(keyword) => Some(name),
+        _ => None,
+    }
+}
+
+/// Each `(keyword name body...)` form's name and body
+fn definitions<'a>(expressions: &'a [LispExpr], keyword: &str) -> Result<Vec<(&'a str, &'a [LispExpr])>, CompileError> {
+    let mut found = Vec::new();
+    for expr in expressions {
+        if let Some(name) = definition_name(expr, keyword) {
+            found.push((name, &expr.as_list().unwrap()[2..]));
+        } else if expr.as_list().and_then(|elements| elements.first()).and_then(LispExpr::as_symbol) == Some(keyword) {
+            return Err(CompileError::new("L0406", format!("'{}' requires a name symbol: {}", keyword, expr)));
+        }
+    }
+    Ok(found)
+}
+
+/// Compile the `deftest` forms among `expressions` into a program that runs every
+/// test, prints a summary with the expected and actual values of failed
+/// assertions, and exits with 1 if any failed. Other forms are not run.
+///
+/// A test body may use `(assert-equal expected expr)` and `(assert-true expr)`;
+/// any other form is evaluated for its effects. A panic fails the test.
+pub fn compile_tests(expressions: &[LispExpr]) -> Result<String, CompileError> {
+    let expressions = &*rename_gensyms(expressions);
+    let mut compiler = RustCompiler::new(None);
+    compiler.define_items(expressions)?;
+    let mut tests = String::new();
+    for (name, forms) in definitions(expressions, "deftest")? {
+        let mut body = String::new();
+        for form in forms {
+            body.push_str(&format!("            {}\n", compiler.with_float_literals(|compiler| compiler.compile_test_form(form))?));
+        }
+        tests.push_str(&format!("        ({:?}, || {{\n{}            Ok(())\n        }}),\n", name, body));
+    }
+    let support = compiler.support_modules(None);
+
+    Ok(format!(
+        r#"#![allow(unused_parens)]
+{support}fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {{
+    match payload.downcast::<String>() {{
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or("unknown panic".to_string(), |s| s.to_string()),
+    }}
+}}
+
+fn main() {{
+    std::panic::set_hook(Box::new(|_| {{}}));
+    let tests: Vec<(&str, fn() -> Result<(), String>)> = vec![
+{tests}    ];
+    println!("running {{}} tests", tests.len());
+    let mut failures = Vec::new();
+    for (name, test) in &tests {{
+        match std::panic::catch_unwind(*test).unwrap_or_else(|payload| Err(format!("panicked: {{}}", panic_message(payload)))) {{
+            Ok(()) => println!("test {{}} ... ok", name),
+            Err(message) => {{
+                println!("test {{}} ... FAILED", name);
+                failures.push((name, message));
+            }}
+        }}
+    }}
+    if !failures.is_empty() {{
+        println!("
+failures:");
+        for (name, message) in &failures {{
+            println!("
+---- {{}} ----
+{{}}", name, message);
+        }}
+    }}
+    let status = if failures.is_empty() {{ "ok" }} else {{ "FAILED" }};
+    println!("
+test result: {{}}. {{}} passed; {{}} failed", status, tests.len() - failures.len(), failures.len());
+    if !failures.is_empty() {{
+        std::process::exit(1);
+    }}
+}}
+"#
+    ))
+}
+
+/// Compile the `defbench` forms among `expressions` into a program that times each
+/// benchmark's expression `iterations` times after `warmup` untimed runs, and
+/// prints the mean, median, and standard deviation of one run. Other forms are not run.
+pub fn compile_benches(expressions: &[LispExpr], warmup: usize, iterations: usize) -> Result<String, CompileError> {
+    if iterations == 0 {
+        return Err("a benchmark needs at least one iteration".into());
+    }
+    let expressions = &*rename_gensyms(expressions);
+    let mut compiler = RustCompiler::new(None);
+    compiler.define_items(expressions)?;
+    let mut benches = String::new();
+    for (name, body) in definitions(expressions, "defbench")? {
+        let [expr] = body else {
+            return Err(CompileError::new("L0406", format!("'defbench' takes a name and one expression: {}", name)));
+        };
+        benches.push_str(&format!(
+            "        ({:?}, || {{ std::hint::black_box({}); }}),\n",
+            name,
+            compiler.compile_expression(expr)?
+        ));
+    }
+    let support = compiler.support_modules(None);
+
+    Ok(format!(
+        r#"#![allow(unused_parens)]
+{support}fn format_nanos(nanos: f64) -> String {{
+    match nanos {{
+        n if n >= 1e9 => format!("{{:.2}} s", n / 1e9),
+        n if n >= 1e6 => format!("{{:.2}} ms", n / 1e6),
+        n if n >= 1e3 => format!("{{:.2}} µs", n / 1e3),
+        n => format!("{{:.0}} ns", n),
+    }}
+}}
+
+fn main() {{
+    let benches: Vec<(&str, fn())> = vec![
+{benches}    ];
+    println!("running {{}} benchmarks ({warmup} warmup, {iterations} iterations)", benches.len());
+    for (name, bench) in &benches {{
+        for _ in 0..{warmup} {{
+            bench();
+        }}
+        let mut samples: Vec<f64> = (0..{iterations})
+            .map(|_| {{
+                let start = std::time::Instant::now();
+                bench();
+                start.elapsed().as_nanos() as f64
+            }})
+            .collect();
+        samples.sort_by(f64::total_cmp);
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let middle = samples.len() / 2;
+        let median = if samples.len() % 2 == 0 {{ (samples[middle - 1] + samples[middle]) / 2.0 }} else {{ samples[middle] }};
+        let stddev = (samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / samples.len() as f64).sqrt();
+        println!(
+            "bench {{}} ... mean {{}}, median {{}}, stddev {{}}",
+            name,
+            format_nanos(mean),
+            format_nanos(median),
+            format_nanos(stddev)
+        );
+    }}
+}}
+"#
+    ))
+}
+
+/// Paths granted for reading or writing, from both allowed paths and capabilities
+fn granted_paths(config: &SandboxConfig, write: bool) -> Vec<String> {
+    let mut paths: Vec<&PathBuf> = config.allowed_file_paths.iter().collect();
+    for capability in &config.capabilities {
+        match capability {
+            Capability::FileRead(path) if !write => paths.push(path),
+            Capability::FileWrite(path) if write => paths.push(path),
+            _ => {}
+        }
+    }
+    quoted_paths(paths)
+}
+
+/// Paths denied for reading or writing; checked before any grant
+fn denied_paths(config: &SandboxConfig, write: bool) -> Vec<String> {
+    let paths = config
+        .denied_capabilities
+        .iter()
+        .filter_map(|capability| match capability {
+            Capability::FileRead(path) if !write => Some(path),
+            Capability::FileWrite(path) if write => Some(path),
+            _ => None,
+        })
+        .collect();
+    quoted_paths(paths)
+}
+
+fn quoted_paths(paths: Vec<&PathBuf>) -> Vec<String> {
+    let mut paths: Vec<String> = paths.iter().map(|p| format!("{:?}", p.display().to_string())).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The host matcher `sandbox` compiles, embedded as source so `sandbox_rt` matches hosts the same way
+const HOST_MATCHES_SOURCE: &str = include_str!("host_match.rs");
+
+/// Source of the `sandbox_rt` module embedded in sandboxed programs.
+/// Violations print the same messages as `SandboxViolation` and exit with status 101.
+fn sandbox_runtime(config: &SandboxConfig) -> String {
+    let network = config.network_unrestricted();
+    format!(
+        r#"#[allow(dead_code)]
+mod sandbox_rt {{
+    use std::path::{{Component, Path}};
+    use std::io::Write;
+    use std::sync::{{Mutex, OnceLock}};
+    use std::sync::atomic::{{AtomicU64, AtomicUsize, Ordering}};
+    use std::time::{{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+    const MAX_MEMORY: usize = {max_memory};
+    const MAX_EXECUTION_TIME: Duration = Duration::from_millis({max_millis});
+    const MAX_FUEL: u64 = {max_fuel};
+    const READ_PATHS: &[&str] = &[{read_paths}];
+    const WRITE_PATHS: &[&str] = &[{write_paths}];
+    const READ_DENY: &[&str] = &[{read_deny}];
+    const WRITE_DENY: &[&str] = &[{write_deny}];
+    const NETWORK_HTTP: bool = {network};
+    const ALLOWED_HOSTS: &[&str] = &[{hosts}];
+    /// (host pattern, port) pairs; port 0 matches any port
+    const NETWORK_RULES: &[(&str, u16)] = &[{network_rules}];
+    const NETWORK_DENY: &[(&str, u16)] = &[{network_deny}];
+    const PROCESS_SPAWN: bool = {process};
+    const THREAD_SPAWN: bool = {thread};
+    const SYSTEM_TIME: bool = {system_time};
+    const ENV_VARS: &[&str] = &[{env_vars}];
+    const ENV_DENY: &[&str] = &[{env_deny}];
+    const AUDIT_LOG: Option<&str> = {audit_log};
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    static MEMORY: AtomicUsize = AtomicUsize::new(0);
+    static FUEL_USED: AtomicU64 = AtomicU64::new(0);
+    static CURRENT_FORM: Mutex<&'static str> = Mutex::new("");
+
+    /// A capability a function declared: kind, path, host, or name pattern, and port (0 for any)
+    pub type Grant = (&'static str, &'static str, u16);
+    static SCOPES: Mutex<Vec<&'static [Grant]>> = Mutex::new(Vec::new());
+
+    /// The capability scope of a function that declared its capabilities, left when dropped
+    pub struct Scope;
+
+    impl Drop for Scope {{
+        fn drop(&mut self) {{
+            SCOPES.lock().unwrap().pop();
+        }}
+    }}
+
+    /// Enter a function that declared `grants`; until the scope is dropped, checks
+    /// must pass both the configuration and this declaration
+    pub fn enter(grants: &'static [Grant]) -> Scope {{
+        SCOPES.lock().unwrap().push(grants);
+        Scope
+    }}
+
+    /// Whether every active function scope declared a capability covering the request,
+    /// so a callee's declaration can only narrow its caller's
+    fn scope_allows(kind: &str, value: &str, port: u16) -> bool {{
+        SCOPES.lock().unwrap().iter().all(|grants| {{
+            grants.iter().any(|&(granted, pattern, rule_port)| match (granted, kind) {{
+                ("FileRead", "FileRead") | ("FileWrite", "FileWrite") => path_matches(pattern, value),
+                ("NetworkHTTP", "Network") => true,
+                ("Network", "Network") => host_matches(pattern, value) && (rule_port == 0 || rule_port == port),
+                ("EnvRead", "EnvRead") => glob_match(pattern.as_bytes(), value.as_bytes()),
+                _ => granted == kind,
+            }})
+        }})
+    }}
+
+    fn violation(message: String) -> ! {{
+        eprintln!("Sandbox violation: {{}}", message);
+        std::process::exit(101);
+    }}
+
+    /// Record the Lisp form about to run, for the audit log
+    pub fn form(source: &'static str) {{
+        *CURRENT_FORM.lock().unwrap() = source;
+    }}
+
+    fn json_string(value: &str) -> String {{
+        let mut out = String::from("\"");
+        for c in value.chars() {{
+            match c {{
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{{:04x}}", c as u32)),
+                c => out.push(c),
+            }}
+        }}
+        out.push('"');
+        out
+    }}
+
+    /// Append one JSONL entry per capability check when an audit log is configured
+    fn audit(check: &str, capability: &str, permitted: bool) {{
+        let Some(path) = AUDIT_LOG else {{ return }};
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+        let form = *CURRENT_FORM.lock().unwrap();
+        let line = format!(
+            "{{{{\"timestamp\":{{:.3}},\"check\":\"{{}}\",\"capability\":{{}},\"decision\":\"{{}}\",\"form\":{{}}}}}}\n",
+            timestamp,
+            check,
+            json_string(capability),
+            if permitted {{ "allow" }} else {{ "deny" }},
+            if form.is_empty() {{ "null".to_string() }} else {{ json_string(form) }},
+        );
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path);
+        if let Ok(mut file) = file {{
+            let _ = file.write_all(line.as_bytes());
+        }}
+    }}
+
+    /// Start the execution clock and a watchdog that enforces the time limit.
+    /// Targets without threads (wasm32-wasip1) rely on the host to interrupt instead.
+    pub fn start() {{
+        START.get_or_init(Instant::now);
+        let _ = std::thread::Builder::new().spawn(|| {{
+            std::thread::sleep(MAX_EXECUTION_TIME);
+            check_time();
+            violation(format!("Execution time exceeded: limit={{:?}}", MAX_EXECUTION_TIME));
+        }});
+    }}
+
+    pub fn check_time() {{
+        let elapsed = START.get_or_init(Instant::now).elapsed();
+        if elapsed > MAX_EXECUTION_TIME {{
+            violation(format!("Execution time exceeded: limit={{:?}}, elapsed={{:?}}", MAX_EXECUTION_TIME, elapsed));
+        }}
+    }}
+
+    /// Consume one unit of fuel for an evaluated form
+    pub fn tick() {{
+        if FUEL_USED.fetch_add(1, Ordering::Relaxed) >= MAX_FUEL {{
+            violation(format!("Fuel exhausted: limit={{}} forms", MAX_FUEL));
+        }}
+    }}
+
+    pub fn allocate(bytes: usize) {{
+        let total = MEMORY.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if total > MAX_MEMORY {{
+            violation(format!("Memory limit exceeded: limit={{}} bytes, attempted={{}} bytes", MAX_MEMORY, total));
+        }}
+    }}
+
+    pub fn track<T>(values: Vec<T>) -> Vec<T> {{
+        allocate(values.len() * std::mem::size_of::<T>());
+        values
+    }}
+
+    fn glob_match(pattern: &[u8], text: &[u8]) -> bool {{
+        match pattern.first() {{
+            None => text.is_empty(),
+            Some(b'*') if pattern.get(1) == Some(&b'*') => {{
+                (0..=text.len()).any(|i| glob_match(&pattern[2..], &text[i..]))
+            }}
+            Some(b'*') => (0..=text.len())
+                .take_while(|&i| i == 0 || text[i - 1] != b'/')
+                .any(|i| glob_match(&pattern[1..], &text[i..])),
+            Some(b'?') => !text.is_empty() && text[0] != b'/' && glob_match(&pattern[1..], &text[1..]),
+            Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+        }}
+    }}
+
+    fn path_matches(pattern: &str, path: &str) -> bool {{
+        if pattern.contains(['*', '?']) {{
+            glob_match(pattern.as_bytes(), path.as_bytes())
+        }} else {{
+            Path::new(path).starts_with(pattern)
+        }}
+    }}
+
+    fn check_path(kind: &str, path: &str, allowed: &[&str], denied: &[&str]) {{
+        let escapes = Path::new(path).components().any(|c| matches!(c, Component::ParentDir));
+        let permitted = allowed.iter().any(|pattern| path_matches(pattern, path))
+            && !denied.iter().any(|pattern| path_matches(pattern, path))
+            && scope_allows(kind, path, 0);
+        audit("file_access", &format!("{{}}:{{}}", kind, path), permitted && !escapes);
+        if escapes || !permitted {{
+            violation(format!("Unauthorized file access: {{}}", path));
+        }}
+    }}
+
+{host_matches}
+
+    fn check_url(url: &str) {{
+        let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {{
+            audit("network_access", url, false);
+            violation(format!("Only http and https URLs are allowed: {{}}", url));
+        }}
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+        let authority = authority.rsplit('@').next().unwrap_or("");
+        let (host, port) = authority.split_once(':').unwrap_or((authority, ""));
+        let host = host.to_lowercase();
+        let port: u16 = port.parse().unwrap_or(match scheme.to_lowercase().as_str() {{
+            "http" => 80,
+            "https" => 443,
+            _ => 0,
+        }});
+        let rule_matches =
+            |(pattern, rule_port): &(&str, u16)| host_matches(pattern, &host) && (*rule_port == 0 || *rule_port == port);
+        let permitted = if NETWORK_HTTP {{
+            ALLOWED_HOSTS.is_empty() || ALLOWED_HOSTS.iter().any(|pattern| host_matches(pattern, &host))
+        }} else {{
+            NETWORK_RULES.iter().any(rule_matches)
+        }};
+        let permitted = permitted && !NETWORK_DENY.iter().any(rule_matches) && scope_allows("Network", &host, port);
+        audit("network_access", &format!("Network:{{}}:{{}}", host, port), permitted);
+        if !permitted {{
+            violation(format!("Unauthorized network host: {{}}:{{}}", host, port));
+        }}
+    }}
+
+    pub fn read_file(path: &str) -> String {{
+        check_time();
+        check_path("FileRead", path, READ_PATHS, READ_DENY);
+        let contents = std::fs::read_to_string(path).unwrap();
+        allocate(contents.len());
+        contents
+    }}
+
+    pub fn write_file(path: &str, contents: &str) -> usize {{
+        check_time();
+        check_path("FileWrite", path, WRITE_PATHS, WRITE_DENY);
+        std::fs::write(path, contents).unwrap();
+        contents.len()
+    }}
+
+    fn curl(url: &str, post: Option<&str>) -> String {{
+        check_time();
+        if !NETWORK_HTTP && NETWORK_RULES.is_empty() {{
+            audit("network_access", url, false);
+            violation("Unauthorized network access attempted".to_string());
+        }}
+        if url.starts_with('-') {{
+            audit("network_access", url, false);
+            violation(format!("URL would be read as a curl option: {{}}", url));
+        }}
+        check_url(url);
+        let mut command = std::process::Command::new("curl");
+        command.arg("-s").arg("--proto").arg("=http,https");
+        if let Some(body) = post {{
+            command.arg("-X").arg("POST").arg("--data-raw").arg(body);
+        }}
+        let output = command.arg("--").arg(url).output().unwrap();
+        let body = String::from_utf8_lossy(&output.stdout).to_string();
+        allocate(body.len());
+        body
+    }}
+
+    pub fn http_get(url: &str) -> String {{
+        curl(url, None)
+    }}
+
+    pub fn http_post(url: &str, body: &str) -> String {{
+        curl(url, Some(body))
+    }}
+
+    pub fn now() -> f64 {{
+        check_time();
+        let permitted = SYSTEM_TIME && scope_allows("SystemTime", "", 0);
+        audit("capability", "SystemTime", permitted);
+        if !permitted {{
+            violation("System time access not permitted in sandbox mode".to_string());
+        }}
+        // Read like `lisp_clock::now`, which unsandboxed programs use
+        static CLOCK: OnceLock<(Instant, f64)> = OnceLock::new();
+        let (start, epoch) = CLOCK.get_or_init(|| {{
+            (Instant::now(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64() * 1000.0)
+        }});
+        epoch + start.elapsed().as_secs_f64() * 1000.0
+    }}
+
+    pub fn getenv(name: &str) -> String {{
+        check_time();
+        let permitted = ENV_VARS.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+            && !ENV_DENY.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+            && scope_allows("EnvRead", name, 0);
+        audit("env_access", &format!("EnvRead:{{}}", name), permitted);
+        if !permitted {{
+            violation(format!("Unauthorized environment variable access: {{}}", name));
+        }}
+        let value = std::env::var(name).unwrap_or_default();
+        allocate(value.len());
+        value
+    }}
+
+    pub fn run_command(program: &str, args: &[&str]) -> String {{
+        check_time();
+        let permitted = PROCESS_SPAWN && scope_allows("ProcessSpawn", "", 0);
+        audit("capability", "ProcessSpawn", permitted);
+        if !permitted {{
+            violation("Process spawning not permitted in sandbox mode".to_string());
+        }}
+        let output = std::process::Command::new(program).args(args).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        allocate(stdout.len());
+        stdout
+    }}
+
+    pub fn spawn<T: Send + 'static>(body: impl FnOnce() -> T + Send + 'static) -> T {{
+        check_time();
+        let permitted = THREAD_SPAWN && scope_allows("ThreadSpawn", "", 0);
+        audit("capability", "ThreadSpawn", permitted);
+        if !permitted {{
+            violation("Thread spawning not permitted in sandbox mode".to_string());
+        }}
+        match std::thread::Builder::new().spawn(body) {{
+            Ok(handle) => handle.join().unwrap_or_else(|_| violation("Spawned thread panicked".to_string())),
+            Err(e) => violation(format!("Cannot spawn thread: {{}}", e)),
+        }}
+    }}
+}}
+"#,
+        max_memory = config.max_memory,
+        max_millis = config.max_execution_time.as_millis(),
+        max_fuel = config.max_fuel.map_or("u64::MAX".to_string(), |fuel| fuel.to_string()),
+        read_paths = granted_paths(config, false).join(", "),
+        write_paths = granted_paths(config, true).join(", "),
+        read_deny = denied_paths(config, false).join(", "),
+        write_deny = denied_paths(config, true).join(", "),
+        network = network,
+        hosts = config
+            .allowed_network_hosts
+            .iter()
+            .map(|h| format!("{:?}", h.to_lowercase()))
+            .collect::<Vec<_>>()
+            .join(", "),
+        network_rules = network_rules(config.network_rules()),
+        network_deny = network_rules(
+            config
+                .denied_capabilities
+                .iter()
+                .filter_map(|capability| match capability {
+                    Capability::Network { host_pattern, port } => Some((host_pattern.as_str(), *port)),
+                    _ => None,
+                })
+                .collect()
+        ),
+        process = config.has_capability(&Capability::ProcessSpawn),
+        thread = config.has_capability(&Capability::ThreadSpawn),
+        system_time = config.has_capability(&Capability::SystemTime),
+        env_vars = env_patterns(&config.capabilities),
+        env_deny = env_patterns(&config.denied_capabilities),
+        audit_log = match &config.audit_log {
+            Some(path) => format!("Some({:?})", path.display().to_string()),
+            None => "None".to_string(),
+        },
+        host_matches = HOST_MATCHES_SOURCE.lines().map(|line| format!("    {}", line).trim_end().to_string()).collect::<Vec<_>>().join("\n"),
+    )
+}
+
+/// A declared capability as a `sandbox_rt::Grant` literal
+fn runtime_grant(capability: &Capability) -> String {
+    let (kind, pattern, port) = match capability {
+        Capability::FileRead(path) => ("FileRead".to_string(), path.display().to_string(), 0),
+        Capability::FileWrite(path) => ("FileWrite".to_string(), path.display().to_string(), 0),
+        Capability::Network { host_pattern, port } => ("Network".to_string(), host_pattern.to_lowercase(), port.unwrap_or(0)),
+        Capability::EnvRead(pattern) => ("EnvRead".to_string(), pattern.clone(), 0),
+        Capability::Crate(pattern) => ("Crate".to_string(), pattern.clone(), 0),
+        other => (other.to_string(), String::new(), 0),
+    };
+    format!("({:?}, {:?}, {})", kind, pattern, port)
+}
+
+/// `EnvRead` patterns as Rust string literals
+fn env_patterns<'a>(capabilities: impl IntoIterator<Item = &'a Capability>) -> String {
+    let mut patterns: Vec<String> = capabilities
+        .into_iter()
+        .filter_map(|capability| match capability {
+            Capability::EnvRead(pattern) => Some(format!("{:?}", pattern)),
+            _ => None,
+        })
+        .collect();
+    patterns.sort();
+    patterns.join(", ")
+}
+
+/// `Network` rules as Rust tuple literals, with port 0 standing for any port
+fn network_rules(mut rules: Vec<(&str, Option<u16>)>) -> String {
+    rules.sort();
+    rules
+        .iter()
+        .map(|(host, port)| format!("({:?}, {})", host.to_lowercase(), port.unwrap_or(0)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `let` statements binding `pattern` to `source`. A list pattern binds `source` to
+/// `temp`, panics unless its length fits, and binds each element by index. A Rust
+/// `Vec` holds elements of one type, so a list pattern's elements must be all
+/// variables or all list patterns; `RustCompiler::compile_pattern_binding` lifts
+/// this for values written as `(list ...)`.
+fn compile_pattern(pattern: &Pattern, source: &str, temp: &str, code: &mut String) -> Result<(), CompileError> {
+    let (elements, rest) = match pattern {
+        Pattern::Variable(name) => {
+            code.push_str(&format!(" let {} = {};", identifier(name), source));
+            return Ok(());
+        }
+        Pattern::List { elements, rest } => (elements, rest),
+    };
+    if mixes_lists(elements, rest) {
+        return Err(CompileError::new(
+            "L0403",
+            format!("Pattern {} mixes variables and list patterns, which compiled code can only match against a list written as (list ...): its lists hold elements of one type", pattern),
+        ));
+    }
+    let (comparison, at_least) = if rest.is_some() { ("<", "at least ") } else { ("!=", "") };
+    code.push_str(&format!(
+        " let {temp} = {source}; if {temp}.len() {comparison} {n} {{ panic!(\"pattern {{}} expects {at_least}{n} element(s), got {{}}\", {pattern:?}, {temp}.len()) }}",
+        n = elements.len(),
+        pattern = pattern.to_string(),
+    ));
+    for (index, element) in elements.iter().enumerate() {
+        compile_pattern(element, &format!("{}[{}].clone()", temp, index), &format!("{}_{}", temp, index), code)?;
+    }
+    if let Some(rest) = rest {
+        code.push_str(&format!(" let {} = {}[{}..].to_vec();", identifier(rest), temp, elements.len()));
+    }
+    Ok(())
+}
+
+/// Whether a list pattern's elements are neither all variables nor all list patterns
+fn mixes_lists(elements: &[Pattern], rest: &Option<Symbol>) -> bool {
+    let lists = elements.iter().filter(|element| matches!(element, Pattern::List { .. })).count();
+    lists != 0 && (lists != elements.len() || rest.is_some())
+}
+
+/// Whether `pattern` is a list pattern and `value` a `(list ...)` form
+fn is_list_form_matched(pattern: &Pattern, value: &LispExpr) -> bool {
+    matches!(pattern, Pattern::List { .. }) && matches!(value.as_list(), Some([head, ..]) if head.as_symbol() == Some("list"))
+}
+
+/// Whether `pattern` or a pattern nested in it mixes variables and list patterns
+fn mixes_lists_anywhere(pattern: &Pattern) -> bool {
+    match pattern {
+        Pattern::Variable(_) => false,
+        Pattern::List { elements, rest } => mixes_lists(elements, rest) || elements.iter().any(mixes_lists_anywhere),
+    }
+}
+
+/// The structs, traits, and functions a program defines at the top level
+#[derive(Default)]
+struct Definitions {
+    structs: Vec<StructDef>,
+    traits: Vec<TraitDef>,
+    functions: Vec<Function>,
+}
+
+/// A definition in the order it appears in the program
+enum Item {
+    Struct(usize),
+    Trait(usize),
+    Impl(ImplDef),
+    Function(usize),
+}
+
+/// A top-level `defun` compiled to a Rust function
+struct Function {
+    /// The name without type parameters
+    name: String,
+    /// The type parameters as written, such as `A,B:Clone`
+    generics: Option<String>,
+    /// The required parameters
+    parameters: Vec<Typed>,
+    /// All the parameters, for matching calls with optional, rest, and keyword arguments
+    lambda_list: LambdaList,
+    return_type: Option<Symbol>,
+    declarations: Declarations,
+    contract: Contract,
+    body: Vec<LispExpr>,
+}
+
+impl Function {
+    /// `(defun name<T> (params...) [: type] body...)`. Optional and keyword
+    /// parameters need a default, whose type is theirs.
+    fn parse(args: &[LispExpr]) -> Result<Function, CompileError> {
+        let [LispExpr::Symbol(name), LispExpr::List(parameters), body @ ..] = args else {
+            return Err(CompileError::new("L0406", "'defun' requires a name, a parameter list, and a body"));
+        };
+        let (base, generics) = types::split_generics(name);
+        let lambda_list = LambdaList::parse(parameters)?;
+        if let Some(parameter) = lambda_list.optional.iter().chain(&lambda_list.keys).find(|parameter| parameter.default.is_none()) {
+            return Err(CompileError::new(
+                "L0403",
+                format!("'{}' parameter '{}' needs a default, such as ({} 0), since compiled code has no nil of its type", base, parameter.name, parameter.name),
+            ));
+        }
+        let (return_type, body) = types::return_type(body);
+        let declarations = Declarations::parse(body.iter().take_while(|form| declarations::is_declaration(form)), DefinitionKind::Function)
+            .map_err(|e| format!("In '{}': {}", base, e))?;
+        let (contract, body) = Contract::parse(body)?;
+        if body.is_empty() {
+            return Err(CompileError::new("L0406", format!("'defun' {} requires a body", base)));
+        }
+        Ok(Function {
+            name: base.to_string(),
+            generics: generics.map(str::to_string),
+            parameters: lambda_list.required.iter().zip(&lambda_list.required_types).map(|(name, type_name)| Typed { name: name.clone(), type_name: type_name.clone() }).collect(),
+            lambda_list,
+            return_type,
+            declarations,
+            contract,
+            body: body.to_vec(),
+        })
+    }
+
+    fn generic_names(&self) -> Vec<&str> {
+        self.generics.as_deref().map(types::generic_names).unwrap_or_default()
+    }
+
+    /// The optional and keyword parameters, typed by their defaults
+    fn defaulted(&self) -> impl Iterator<Item = Typed> + '_ {
+        self.lambda_list.optional.iter().chain(&self.lambda_list.keys).map(default_typed)
+    }
+}
+
+/// An optional or keyword parameter, with the type of its default: a string, a
+/// bool, or otherwise a number
+fn default_typed(parameter: &Parameter) -> Typed {
+    let type_name = match parameter.default {
+        Some(LispExpr::String(_)) => "string",
+        Some(LispExpr::Bool(_)) => "bool",
+        _ => "number",
+    };
+    Typed { name: parameter.name.clone(), type_name: Some(Symbol::from(type_name)) }
+}
+
+/// Types each unannotated required parameter of `functions` by the arguments calls
+/// in `program` pass it, when they evidently are strings or bools, so that
+/// `(greet "Ann")` makes `greet`'s first parameter a string. A parameter passed
+/// arguments of two types must be annotated.
+fn infer_parameter_types(functions: &mut [Function], program: &[LispExpr]) -> Result<(), CompileError> {
+    struct Calls<'a> {
+        functions: &'a [Function],
+        scope: TypeSafetyValidator,
+        /// The types passed to each function's parameters, by function and parameter
+        passed: HashMap<(usize, usize), Vec<InferredType>>,
+    }
+
+    impl<'a> Visitor<'a> for Calls<'a> {
+        fn visit_expr(&mut self, expr: &'a LispExpr) {
+            let Some([head, args @ ..]) = expr.as_list() else {
+                // Quoted lists are data, not calls
+                if !matches!(expr, LispExpr::Quote(_)) {
+                    walk_expr(self, expr);
+                }
+                return;
+            };
+            let forms = match head.as_symbol() {
+                // A parameter list is not a call
+                Some("defun") => args.get(2..).unwrap_or_default(),
+                Some("lambda") => args.get(1..).unwrap_or_default(),
+                _ => args,
+            };
+            if let Some(index) = head.as_symbol().and_then(|name| self.functions.iter().position(|function| function.name == name)) {
+                let function = &self.functions[index];
+                let matched = function.lambda_list.match_arguments(&function.name, args.iter().collect(), |arg| match arg {
+                    LispExpr::Symbol(s) if lambda_list::is_keyword(s) => Some(s.as_str()),
+                    _ => None,
+                });
+                // Calls that do not match are reported when they are compiled
+                if let Ok(matched) = matched {
+                    for (parameter, arg) in matched.required.into_iter().enumerate() {
+                        let inferred = self.scope.infer_type(arg);
+                        if function.parameters[parameter].type_name.is_none() && matches!(inferred, InferredType::Number | InferredType::String | InferredType::Bool) {
+                            let passed = self.passed.entry((index, parameter)).or_default();
+                            if !passed.contains(&inferred) {
+                                passed.push(inferred);
+                            }
+                        }
+                    }
+                }
+            }
+            grow_stack(|| {
+                for form in forms {
+                    self.visit_expr(form);
+                }
+            })
+        }
+    }
+
+    let mut calls = Calls { functions, scope: TypeSafetyValidator::new(), passed: HashMap::new() };
+    for expr in program {
+        calls.visit_expr(expr);
+    }
+    let mut passed: Vec<_> = calls.passed.into_iter().collect();
+    passed.sort_by_key(|(key, _)| *key);
+    for ((index, parameter), types) in passed {
+        let function = &mut functions[index];
+        let type_name = match types.as_slice() {
+            [InferredType::String] => "string",
+            [InferredType::Bool] => "bool",
+            [_] => continue,
+            types => {
+                let names: Vec<String> = types.iter().map(InferredType::to_string).collect();
+                let name = &function.parameters[parameter].name;
+                return Err(CompileError::new(
+                    "L0403",
+                    format!("'{}' parameter '{}' is passed a {}, but compiled code gives each parameter one type", function.name, name, names.join(" and a ")),
+                ));
+            }
+        };
+        function.parameters[parameter].type_name = Some(Symbol::from(type_name));
+    }
+    Ok(())
+}
+
+/// The Rust identifier for a gensym before collisions are resolved: `temp#g1`
+/// becomes `__g_temp_1`, with characters Rust does not allow replaced by `_`
+fn gensym_identifier(name: &str) -> String {
+    let (prefix, counter) = name.rsplit_once("#g").unwrap_or((name, ""));
+    let prefix: String = prefix.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    match counter {
+        "" => format!("__g_{}", prefix),
+        _ => format!("__g_{}_{}", prefix, counter),
+    }
+}
+
+/// The program with its gensyms replaced by symbols naming valid Rust identifiers.
+/// A name one of the program's own symbols already uses gets trailing `_`s until it
+/// is free, so macro-introduced bindings never capture or shadow user variables.
+fn rename_gensyms(expressions: &[LispExpr]) -> Cow<'_, [LispExpr]> {
+    #[derive(Default)]
+    struct Names<'a> {
+        symbols: HashSet<&'a str>,
+        gensyms: Vec<&'a str>,
+    }
+
+    impl<'a> Visitor<'a> for Names<'a> {
+        fn visit_expr(&mut self, expr: &'a LispExpr) {
+            match expr {
+                LispExpr::Symbol(name) => {
+                    self.symbols.insert(name.as_str());
+                }
+                LispExpr::Gensym(name) => self.gensyms.push(name.as_str()),
+                _ => walk_expr(self, expr),
+            }
+        }
+    }
+
+    struct Rename(HashMap<String, String>);
+
+    impl VisitorMut for Rename {
+        fn visit_expr_mut(&mut self, expr: &mut LispExpr) {
+            match expr {
+                LispExpr::Gensym(name) => *expr = LispExpr::Symbol(self.0[name.as_str()].as_str().into()),
+                _ => walk_expr_mut(self, expr),
+            }
+        }
+    }
+
+    let mut names = Names::default();
+    for expr in expressions {
+        names.visit_expr(expr);
+    }
+    if names.gensyms.is_empty() {
+        return Cow::Borrowed(expressions);
+    }
+    let mut taken: HashSet<String> = names.symbols.iter().map(|symbol| identifier(symbol)).collect();
+    let mut renames = HashMap::new();
+    for gensym in names.gensyms {
+        if renames.contains_key(gensym) {
+            continue;
+        }
+        let mut renamed = gensym_identifier(gensym);
+        while taken.contains(&renamed) {
+            renamed.push('_');
+        }
+        taken.insert(renamed.clone());
+        renames.insert(gensym.to_string(), renamed);
+    }
+    let mut rename = Rename(renames);
+    let mut expressions = expressions.to_vec();
+    for expr in &mut expressions {
+        rename.visit_expr_mut(expr);
+    }
+    Cow::Owned(expressions)
+}
+
+/// `n` as an `f64` literal
+fn float_literal(n: f64) -> String {
+    format!("{:?}", n)
+}
+
+/// `targets = values;` assigning all the values at once, as a tuple when there are several
+fn parallel_assignment(keyword: &str, targets: &[String], values: &[String]) -> String {
+    match (targets, values) {
+        ([], []) => String::new(),
+        ([target], [value]) => format!("{}{} = {};", keyword, target, value),
+        _ => format!("{}({}) = ({});", keyword, targets.join(", "), values.join(", ")),
     }
-    
-    rust_code.push_str("}\n");
-    Ok(rust_code)
 }
 
-struct RustCompiler {
-    variable_counter: usize,
+struct RustCompiler<'a> {
+    sandbox: Option<&'a SandboxConfig>,
+    /// Names of the named `let` loops being compiled, innermost last
+    loops: Vec<String>,
+    /// Whether the program calls into `lisp_rng`
+    uses_random: bool,
+    /// Whether the program calls into `lisp_json`
+    uses_json: bool,
+    /// Whether the program calls into `lisp_clock`
+    uses_clock: bool,
+    /// The program's top-level structs, traits, and functions
+    definitions: Rc<Definitions>,
+    /// The compiled items, each followed by a blank line
+    items: String,
+    /// Where each top-level expression is in the Lisp source, for `// lisp:` comments
+    locations: &'a [LocationTree],
+    /// Whether number literals are written as `f64`: in function bodies, where they
+    /// meet parameters that are numbers, and throughout programs that define functions
+    float_literals: bool,
+    /// The functions of the `letrec` forms being compiled, innermost last
+    local_functions: Vec<Rc<Function>>,
 }
 
-impl RustCompiler {
-    fn new() -> Self {
-        Self {
-            variable_counter: 0,
+impl<'a> RustCompiler<'a> {
+    fn new(sandbox: Option<&'a SandboxConfig>) -> Self {
+        RustCompiler {
+            sandbox,
+            loops: Vec::new(),
+            uses_random: false,
+            uses_json: false,
+            uses_clock: false,
+            definitions: Rc::default(),
+            items: String::new(),
+            locations: &[],
+            float_literals: false,
+            local_functions: Vec::new(),
         }
     }
-    
-    fn next_temp_var(&mut self) -> String {
-        let var = format!("temp_{}", self.variable_counter);
-        self.variable_counter += 1;
-        var
+
+    fn location(&self, index: usize) -> Option<SourceLocation> {
+        self.locations.get(index).and_then(|tree| tree.location.clone())
+    }
+
+    /// The runtime modules the compiled forms call into and the program's structs,
+    /// each followed by a blank line
+    fn support_modules(&self, seed: Option<u64>) -> String {
+        let mut modules = String::new();
+        if self.uses_random {
+            modules.push_str(&random_runtime(seed));
+            modules.push('\n');
+        }
+        if self.uses_json {
+            modules.push_str(JSON_RUNTIME);
+            modules.push('\n');
+        }
+        if self.uses_clock {
+            modules.push_str(CLOCK_RUNTIME);
+            modules.push('\n');
+        }
+        modules.push_str(&self.items);
+        modules
+    }
+
+    /// Compile the program's top-level definitions into items, in source order. All
+    /// of them are known before any body is compiled, so they can refer to each other.
+    fn define_items(&mut self, expressions: &[LispExpr]) -> Result<(), CompileError> {
+        for requirement in crates::requirements(expressions)? {
+            if let Some(config) = self.sandbox
+                && !config.crate_allowed(&requirement.name)
+            {
+                return Err(CompileError::new(
+                    "L0407",
+                    format!("Sandbox violation: 'require-crate' {} requires the {} capability", requirement.name, requirement.capability()),
+                ));
+            }
+            self.items.push_str(&requirement.use_statement());
+            self.items.push('\n');
+        }
+
+        let mut definitions = Definitions::default();
+        let mut items = Vec::new();
+        let mut defined: Vec<(&str, String)> = Vec::new();
+        for (index, expr) in expressions.iter().enumerate().filter(|(_, expr)| is_item(expr) && !crates::is_require_crate(expr)) {
+            let elements = expr.as_list().unwrap();
+            let (form, args) = (elements[0].as_symbol().unwrap(), &elements[1..]);
+            let (name, item) = match form {
+                "defstruct" => {
+                    definitions.structs.push(StructDef::parse(args)?);
+                    (definitions.structs.last().unwrap().name.to_string(), Item::Struct(definitions.structs.len() - 1))
+                }
+                "deftrait" => {
+                    definitions.traits.push(TraitDef::parse(args)?);
+                    (definitions.traits.last().unwrap().name.to_string(), Item::Trait(definitions.traits.len() - 1))
+                }
+                "defimpl" => {
+                    let implementation = ImplDef::parse(args)?;
+                    (format!("{} for {}", implementation.trait_name, implementation.type_name), Item::Impl(implementation))
+                }
+                _ => {
+                    definitions.functions.push(Function::parse(args)?);
+                    (definitions.functions.last().unwrap().name.clone(), Item::Function(definitions.functions.len() - 1))
+                }
+            };
+            if defined.iter().any(|(other_form, other)| *other_form == form && *other == name) {
+                return Err(CompileError::new("L0405", format!("'{}' {} is defined more than once", form, name)));
+            }
+            if let Some((_, other)) = defined.iter().find(|(other_form, other)| *other_form == "defun" && form == "defun" && identifier(other) == identifier(&name)) {
+                return Err(CompileError::new("L0405", format!("'defun' {} and {} are both named {} in Rust", other, name, identifier(&name))));
+            }
+            defined.push((form, name));
+            items.push((index, item));
+        }
+        infer_parameter_types(&mut definitions.functions, expressions)?;
+        self.definitions = Rc::new(definitions);
+        // Parameters and results are numbers, so a program with functions writes every
+        // literal as one, letting top-level code mix literals with function results
+        self.float_literals = !self.definitions.functions.is_empty();
+
+        for (index, item) in items {
+            if let Some(location) = self.location(index) {
+                self.items.push_str(&location.comment(""));
+            }
+            let code = match item {
+                Item::Struct(index) => self.compile_struct(index)?,
+                Item::Trait(index) => self.compile_trait(index)?,
+                Item::Impl(implementation) => self.compile_impl(&implementation)?,
+                Item::Function(function) => self.compile_function(function, self.locations.get(index))?,
+            };
+            self.items.push_str(&code);
+            self.items.push('\n');
+        }
+        Ok(())
+    }
+
+    /// `#[derive(...)] struct Name { ... }`, and an impl block with its methods if it has any
+    fn compile_struct(&mut self, index: usize) -> Result<String, CompileError> {
+        let definitions = Rc::clone(&self.definitions);
+        let definition = &definitions.structs[index];
+        let mut derives = vec!["Debug".to_string()];
+        derives.extend(definition.derives.iter().map(|derive| derive.to_string()).filter(|derive| derive != "Debug"));
+        let (attributes, visibility) = match &definition.declarations.export {
+            Some(export) => (mangle::export_attributes(&definition.name, &definition.rust_name(), export, false), "pub "),
+            None => (String::new(), ""),
+        };
+        let fields: String = definition
+            .fields
+            .iter()
+            .map(|field| format!("    {}{}: {},\n", visibility, identifier(&field.name), self.rust_type(field.type_name.clone())))
+            .collect();
+        let mut code = format!("{}#[derive({})]\n{}struct {} {{\n{}}}\n", attributes, derives.join(", "), visibility, definition.rust_name(), fields);
+
+        if !definition.methods.is_empty() {
+            let methods = definition
+                .methods
+                .iter()
+                .map(|method| self.compile_method(&method.name, &method.parameters, &method.parameters, method.return_type.clone(), Some(&method.body)))
+                .collect::<Result<Vec<_>, _>>()?;
+            code.push_str(&format!("\nimpl {} {{\n{}}}\n", definition.rust_name(), methods.join("\n")));
+        }
+        Ok(code)
+    }
+
+    /// `trait Name { ... }`, with a body for the methods that have a default
+    fn compile_trait(&mut self, index: usize) -> Result<String, CompileError> {
+        let definitions = Rc::clone(&self.definitions);
+        let definition = &definitions.traits[index];
+        let methods = definition
+            .methods
+            .iter()
+            .map(|method| self.compile_method(&method.name, &method.parameters, &method.parameters, Some(method.return_type.clone()), method.default.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("trait {} {{\n{}}}\n", definition.rust_name(), methods.join("\n")))
+    }
+
+    /// `impl Trait for Type { ... }`, with the types of the trait's declarations
+    fn compile_impl(&mut self, implementation: &ImplDef) -> Result<String, CompileError> {
+        let definitions = Rc::clone(&self.definitions);
+        let Some(definition) = definitions.traits.iter().find(|definition| definition.name == implementation.trait_name) else {
+            return Err(format!("'defimpl' refers to unknown trait '{}'", implementation.trait_name).into());
+        };
+        let mut methods = Vec::new();
+        for (declared, method) in implementation.resolve(definition)? {
+            if let Some(method) = method {
+                methods.push(self.compile_method(&declared.name, &declared.parameters, &method.parameters, Some(declared.return_type.clone()), Some(&method.body))?);
+            }
+        }
+        Ok(format!(
+            "impl {} for {} {{\n{}}}\n",
+            definition.rust_name(),
+            self.rust_type(Some(implementation.type_name.clone())),
+            methods.join("\n")
+        ))
+    }
+
+    /// One method of an impl block or trait: `types` gives each parameter's type and
+    /// `parameters` the names the body uses. Without a body it is only a declaration.
+    fn compile_method(
+        &mut self,
+        name: &str,
+        types: &[Typed],
+        parameters: &[Typed],
+        return_type: Option<Symbol>,
+        body: Option<&LispExpr>,
+    ) -> Result<String, CompileError> {
+        let parameters: String = parameters
+            .iter()
+            .zip(types)
+            .map(|(parameter, typed)| format!(", {}: {}", identifier(&parameter.name), self.rust_type(typed.type_name.clone())))
+            .collect();
+        let signature = format!("    fn {}(&self{}) -> {}", identifier(name), parameters, self.return_type(return_type.clone()));
+        match body {
+            Some(body) => {
+                let body = self.with_float_literals(|compiler| compiler.compile_expression(body))?;
+                Ok(format!("{} {{\n        {}\n    }}\n", signature, self.converted(body, return_type, &[])))
+            }
+            None => Ok(format!("{};\n", signature)),
+        }
+    }
+
+    /// `fn name<T>(params) -> type { ... }`. With the `defun`'s `locations`, each body
+    /// form's code starts with a `// lisp:` comment.
+    fn compile_function(&mut self, index: usize, locations: Option<&LocationTree>) -> Result<String, CompileError> {
+        let definitions = Rc::clone(&self.definitions);
+        self.with_float_literals(|compiler| compiler.compile_function_body(&definitions.functions[index], locations))
+    }
+
+    /// An optional or keyword parameter as an `Option`, and the statement in `body`
+    /// that replaces `None` with its default
+    fn compile_default(&mut self, parameter: &Parameter, body: &mut String) -> Result<String, CompileError> {
+        let typed = default_typed(parameter);
+        let (name, rust_type) = (identifier(&parameter.name), self.rust_type(typed.type_name.clone()));
+        let default = self.compile_expression(parameter.default.as_ref().unwrap())?;
+        body.push_str(&format!(
+            "    let {name}: {rust_type} = match {name} {{ Some({name}) => {name}, None => {} }};\n",
+            self.converted(default, typed.type_name, &[])
+        ));
+        Ok(format!("{}: Option<{}>", name, rust_type))
+    }
+
+    /// Compile with number literals written as `f64`
+    fn with_float_literals<T>(&mut self, compile: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::replace(&mut self.float_literals, true);
+        let compiled = compile(self);
+        self.float_literals = outer;
+        compiled
+    }
+
+    /// A function whose optional and keyword parameters take an `Option` each,
+    /// replaced by its default when `None`, and whose `&rest` parameter takes a `Vec`.
+    /// Its contract is asserted on entry and before returning.
+    fn compile_function_body(&mut self, function: &Function, locations: Option<&LocationTree>) -> Result<String, CompileError> {
+        let generics = function.generic_names();
+        let mut parameters: Vec<String> = function
+            .parameters
+            .iter()
+            .map(|parameter| format!("{}: {}", identifier(&parameter.name), self.rust_type(parameter.type_name.clone())))
+            .collect();
+
+        let mut body = String::new();
+        if self.sandbox.is_some()
+            && let Some(capabilities) = &function.declarations.capabilities
+        {
+            let grants: Vec<String> = capabilities.iter().map(runtime_grant).collect();
+            body.push_str(&format!("    let _scope = sandbox_rt::enter(&[{}]);\n", grants.join(", ")));
+        }
+        let lambda_list = &function.lambda_list;
+        for parameter in &lambda_list.optional {
+            parameters.push(self.compile_default(parameter, &mut body)?);
+        }
+        if let Some(rest) = lambda_list.rest.clone() {
+            parameters.push(format!("{}: Vec<f64>", identifier(&rest)));
+        }
+        for parameter in &lambda_list.keys {
+            parameters.push(self.compile_default(parameter, &mut body)?);
+        }
+        for condition in &function.contract.requires {
+            let message = format!("Precondition of '{}' failed: {}", function.name, condition);
+            body.push_str(&format!("    assert!({}, \"{{}}\", {:?});\n", self.compile_expression(condition)?, message));
+        }
+        // The body forms end the `defun`
+        let body_locations = locations.map_or(&[][..], |tree| &tree.children[tree.children.len().saturating_sub(function.body.len())..]);
+        let mark = |body: &mut String, index: usize| {
+            if let Some(location) = body_locations.get(index).and_then(|tree| tree.location.as_ref()) {
+                body.push_str(&location.comment("    "));
+            }
+        };
+        let (last, init) = function.body.split_last().unwrap();
+        for (index, form) in init.iter().enumerate() {
+            let form = self.compile_expression(form)?;
+            mark(&mut body, index);
+            body.push_str(&format!("    let _ = {};\n", form));
+        }
+        let mut value = self.compile_expression(last)?;
+        mark(&mut body, init.len());
+        if !function.contract.ensures.is_empty() {
+            body.push_str(&format!("    let {} = {};\n", contracts::RESULT, value));
+            for condition in &function.contract.ensures {
+                let message = format!("Postcondition of '{}' failed: {}", function.name, condition);
+                body.push_str(&format!("    assert!({}, \"{{}}\", {:?});\n", self.compile_expression(condition)?, message));
+            }
+            value = contracts::RESULT.to_string();
+        }
+
+        // An unannotated function whose last form evidently makes a string returns a
+        // `String`, as if annotated
+        let return_type = function.return_type.clone().or_else(|| {
+            (Self::parameter_scope(function).infer_type(last) == InferredType::String).then(|| Symbol::from("string"))
+        });
+        let mut attributes = String::new();
+        if let Some(deprecation) = &function.declarations.deprecated {
+            attributes.push_str(&format!("/// Deprecated{}\n", deprecation.note.as_ref().map(|note| format!(": {}", note)).unwrap_or_default()));
+        }
+        if let Some(since) = &function.declarations.since {
+            attributes.push_str(&format!("/// Since: {}\n", since));
+        }
+        if function.declarations.inline {
+            attributes.push_str("#[inline]\n");
+        }
+        let name = identifier(&function.name);
+        let visibility = match &function.declarations.export {
+            Some(_) if !function.lambda_list.is_fixed() => {
+                return Err(CompileError::new("L0403", format!("'{}' has &optional, &rest, or &key parameters, so it cannot be exported with a C signature", function.name)));
+            }
+            Some(_) if function.generics.is_some() => {
+                return Err(CompileError::new("L0403", format!("'{}' has type parameters, so it cannot be exported under one symbol", function.name)));
+            }
+            Some(export) => {
+                attributes.push_str(&mangle::export_attributes(&function.name, &name, export, true));
+                "pub "
+            }
+            None => "",
+        };
+        Ok(format!(
+            "{}{}fn {}{}({}) -> {} {{\n{}    {}\n}}\n",
+            attributes,
+            visibility,
+            name,
+            function.generics.as_ref().map(|generics| format!("<{}>", generics)).unwrap_or_default(),
+            parameters.join(", "),
+            match &return_type {
+                Some(_) => self.return_type(return_type.clone()),
+                None => self.inferred_return_type(function, last).unwrap_or_else(|| self.return_type(None)),
+            },
+            body,
+            self.converted(value, return_type, &generics)
+        ))
+    }
+
+    /// The function's parameters typed for inferring its result
+    fn parameter_scope(function: &Function) -> TypeSafetyValidator {
+        let rest = function.lambda_list.rest.clone().map(|name| Typed { name, type_name: Some(Symbol::from("list")) });
+        function.parameters.iter().cloned().chain(function.defaulted()).chain(rest).fold(TypeSafetyValidator::new(), |scope, parameter| {
+            let inferred = match parameter.type_name.as_ref().map(Symbol::as_str) {
+                None | Some("number") => InferredType::Number,
+                Some("bool") => InferredType::Bool,
+                Some("string") => InferredType::String,
+                _ => InferredType::Unknown,
+            };
+            scope.with_binding(parameter.name.to_string(), inferred)
+        })
+    }
+
+    /// The type of an unannotated function's result when its last form shows it: a
+    /// number, a bool, or several of them returned with `values`
+    fn inferred_return_type(&self, function: &Function, last: &LispExpr) -> Option<String> {
+        let scope = Self::parameter_scope(function);
+        let rust_type = |expr: &LispExpr| match scope.infer_type(expr) {
+            InferredType::Number => Some("f64"),
+            InferredType::Bool => Some("bool"),
+            _ => None,
+        };
+        match last.as_list() {
+            Some([head, values @ ..]) if head.as_symbol() == Some("values") && values.len() != 1 => {
+                let types = values.iter().map(rust_type).collect::<Option<Vec<_>>>()?;
+                Some(format!("({})", types.join(", ")))
+            }
+            _ => rust_type(last).map(str::to_string),
+        }
+    }
+
+    /// The Rust type for an annotation; unannotated fields and parameters are numbers
+    fn rust_type(&self, type_name: Option<Symbol>) -> String {
+        match type_name.as_ref().map(Symbol::as_str) {
+            None | Some("number") => "f64".to_string(),
+            Some("string") => "String".to_string(),
+            Some("bool") => "bool".to_string(),
+            Some(name) if self.definitions.traits.iter().any(|definition| definition.name == name) => {
+                format!("impl {}", types::rust_type_name(name))
+            }
+            Some(name) if self.definitions.structs.iter().any(|definition| definition.name == name) => types::rust_type_name(name),
+            Some(name) => name.to_string(),
+        }
+    }
+
+    /// Unannotated results only need to be printable
+    fn return_type(&self, type_name: Option<Symbol>) -> String {
+        match type_name {
+            Some(_) => self.rust_type(type_name),
+            None => "impl std::fmt::Debug".to_string(),
+        }
+    }
+
+    /// `code` converted with `.into()` to a concrete annotated type, so that integer
+    /// literals can be passed as numbers and string literals as strings. Values of
+    /// type parameters and traits are left alone, as are unannotated results.
+    fn converted(&self, code: String, type_name: Option<Symbol>, generics: &[&str]) -> String {
+        let concrete = match type_name.as_ref().map(Symbol::as_str) {
+            Some(name) => !generics.contains(&name) && !self.definitions.traits.iter().any(|definition| definition.name == name),
+            None => false,
+        };
+        if concrete { format!("({}).into()", code) } else { code }
+    }
+
+    /// A call of one of the program's functions, or a constructor, field accessor,
+    /// or method of one of its structs and traits
+    fn compile_item_call(&mut self, function: &str, args: &[LispExpr]) -> Option<Result<String, CompileError>> {
+        let definitions = Rc::clone(&self.definitions);
+        let arity = |expected: usize| {
+            if args.len() == expected { Ok(()) } else { Err(CompileError::new("L0402", format!("'{}' expects {} argument(s), got {}", function, expected, args.len()))) }
+        };
+
+        let local = self.local_functions.iter().rev().find(|definition| definition.name == function).cloned();
+        if let Some(definition) = local.as_deref().or_else(|| definitions.functions.iter().find(|definition| definition.name == function)) {
+            return Some(self.compile_function_call(definition, function, args));
+        }
+        if let Some(definition) = definitions.structs.iter().find(|definition| definition.constructor() == function) {
+            return Some(arity(definition.fields.len()).and_then(|_| {
+                let args = self.compile_arguments(args, &definition.fields, &[])?;
+                let fields: Vec<String> = definition.fields.iter().zip(args).map(|(field, arg)| format!("{}: {}", identifier(&field.name), arg)).collect();
+                Ok(format!("{} {{ {} }}", definition.rust_name(), fields.join(", ")))
+            }));
+        }
+        if let Some(field) = definitions.structs.iter().find_map(|definition| definition.field_of_accessor(function)) {
+            return Some(arity(1).and_then(|_| Ok(format!("{}.{}.clone()", self.compile_expression(&args[0])?, identifier(&field.name)))));
+        }
+
+        let struct_methods = definitions.structs.iter().flat_map(|definition| &definition.methods).map(|method| (method.name.clone(), &method.parameters));
+        let trait_methods = definitions.traits.iter().flat_map(|definition| &definition.methods).map(|method| (method.name.clone(), &method.parameters));
+        let (_, parameters) = struct_methods.chain(trait_methods).find(|(name, _)| *name == function)?;
+        let Some((instance, args)) = args.split_first() else {
+            return Some(Err(CompileError::new("L0402", format!("Method '{}' requires an instance to call it on", function))));
+        };
+        if args.len() != parameters.len() {
+            return Some(Err(CompileError::new("L0402", format!("Method '{}' expects {} argument(s) after the instance, got {}", function, parameters.len(), args.len()))));
+        }
+        Some(self.compile_expression(instance).and_then(|instance| {
+            let args = self.compile_arguments(args, parameters, &[])?;
+            Ok(format!("{}.{}({})", instance, identifier(function), args.join(", ")))
+        }))
+    }
+
+    /// A call sorted into the function's parameters: required arguments converted to
+    /// their types, optional and keyword ones wrapped in `Some` or passed as `None`,
+    /// and the rest collected into a `Vec`
+    fn compile_function_call(&mut self, definition: &Function, function: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let matched = definition.lambda_list.match_arguments(function, args.iter().collect(), |arg| match arg {
+            LispExpr::Symbol(s) if lambda_list::is_keyword(s) => Some(s.as_str()),
+            _ => None,
+        })?;
+        let required: Vec<LispExpr> = matched.required.into_iter().cloned().collect();
+        let mut compiled = self.compile_arguments(&required, &definition.parameters, &definition.generic_names())?;
+        let mut defaulted = definition.defaulted();
+        for arg in matched.optional {
+            compiled.push(self.compile_optional_argument(arg, defaulted.next().unwrap())?);
+        }
+        if definition.lambda_list.rest.is_some() {
+            let rest = matched.rest.into_iter().map(|arg| Ok(format!("({}).into()", self.compile_expression(arg)?))).collect::<Result<Vec<_>, CompileError>>()?;
+            compiled.push(format!("vec![{}]", rest.join(", ")));
+        }
+        for arg in matched.keys {
+            compiled.push(self.compile_optional_argument(arg, defaulted.next().unwrap())?);
+        }
+        Ok(format!("{}({})", identifier(function), compiled.join(", ")))
+    }
+
+    fn compile_optional_argument(&mut self, arg: Option<&LispExpr>, parameter: Typed) -> Result<String, CompileError> {
+        match arg {
+            Some(arg) => {
+                let arg = self.compile_expression(arg)?;
+                Ok(format!("Some({})", self.converted(arg, parameter.type_name, &[])))
+            }
+            None => Ok("None".to_string()),
+        }
+    }
+
+    /// The error for a call to `function`, suggesting the built-in or definition it
+    /// is closest to
+    fn unknown_function(&self, function: &str) -> CompileError {
+        let definitions = &self.definitions;
+        let structs = definitions.structs.iter().flat_map(|definition| {
+            let accessors = definition.fields.iter().map(|field| definition.accessor(field));
+            let methods = definition.methods.iter().map(|method| method.name.to_string());
+            std::iter::once(definition.constructor()).chain(accessors).chain(methods)
+        });
+        let trait_methods = definitions.traits.iter().flat_map(|definition| &definition.methods).map(|method| method.name.to_string());
+        let defined: Vec<String> = definitions.functions.iter().map(|definition| definition.name.clone()).chain(structs).chain(trait_methods).collect();
+        let known = BUILTIN_CALLS.iter().copied().chain(defined.iter().map(String::as_str));
+        CompileError::new("L0401", suggest::with_suggestion(format!("Unknown function: {}", function), function, known))
+    }
+
+    /// Arguments converted to their parameters' types; unannotated parameters are numbers
+    fn compile_arguments(&mut self, args: &[LispExpr], parameters: &[Typed], generics: &[&str]) -> Result<Vec<String>, CompileError> {
+        args.iter()
+            .zip(parameters)
+            .map(|(arg, parameter)| {
+                let type_name = parameter.type_name.clone().unwrap_or_else(|| Symbol::from("number"));
+                let arg = self.compile_expression(arg)?;
+                Ok(self.converted(arg, Some(type_name), generics))
+            })
+            .collect()
     }
     
-    fn compile_expression(&mut self, expr: &LispExpr) -> Result<String, String> {
+    fn compile_expression(&mut self, expr: &LispExpr) -> Result<String, CompileError> {
         match expr {
+            LispExpr::Number(n) if self.float_literals => Ok(float_literal(*n)),
             LispExpr::Number(n) => Ok(n.to_string()),
-            LispExpr::String(s) => Ok(format!("\"{}\"", s.replace('"', "\\\""))),
+            LispExpr::String(s) => Ok(format!("{:?}", s)),
             LispExpr::Bool(b) => Ok(b.to_string()),
             LispExpr::Nil => Ok("()".to_string()),
             LispExpr::Symbol(s) => {
                 match s.as_str() {
                     "pi" => Ok("std::f64::consts::PI".to_string()),
                     "e" => Ok("std::f64::consts::E".to_string()),
-                    _ => Ok(s.clone()) // Allow symbols as variable names
+                    // The receiver of a method
+                    "self" => Ok("self".to_string()),
+                    _ => Ok(identifier(s)),
                 }
             },
-            LispExpr::List(elements) => self.compile_list(elements),
+            LispExpr::List(elements) => grow_stack(|| self.compile_list(elements)),
             LispExpr::Macro { name, .. } => {
-                Err(format!("Macro definitions are not yet supported in code generation: {}", name))
+                Err(CompileError::new("L0403", format!("Macro definitions are not yet supported in code generation: {}", name)))
             },
             LispExpr::MacroCall { name, .. } => {
-                Err(format!("Macro calls are not yet supported in code generation: {}", name))
+                Err(CompileError::new("L0403", format!("Macro calls are not yet supported in code generation: {}", name)))
             },
             LispExpr::Quote(_expr) => {
-                Err("Quote expressions are not yet supported in code generation".to_string())
+                Err(CompileError::new("L0403", "Quote expressions are not yet supported in code generation"))
             },
             LispExpr::Quasiquote(_expr) => {
-                Err("Quasiquote expressions are not yet supported in code generation".to_string())
+                Err(CompileError::new("L0403", "Quasiquote expressions are not yet supported in code generation"))
             },
             LispExpr::Unquote(_expr) => {
-                Err("Unquote expressions are not yet supported in code generation".to_string())
+                Err(CompileError::new("L0403", "Unquote expressions are not yet supported in code generation"))
             },
             LispExpr::Splice(_expr) => {
-                Err("Splice expressions are not yet supported in code generation".to_string())
+                Err(CompileError::new("L0403", "Splice expressions are not yet supported in code generation"))
             },
-            LispExpr::Gensym(name) => {
-                // Gensym symbols are hygienic generated symbols
-                // Use valid Rust identifier format
-                Ok(name.replace('#', "_"))
+            // `rename_gensyms` has already replaced the gensyms of whole programs
+            LispExpr::Gensym(name) => Ok(gensym_identifier(name)),
+        }
+    }
+    
+    /// One statement of a test body; failed assertions return the failure message.
+    /// `assert-equal` compares the values as printed, since a function's result may
+    /// only be known to be printable.
+    fn compile_test_form(&mut self, form: &LispExpr) -> Result<String, CompileError> {
+        let source = format!("{:?}", form.to_string());
+        match form.as_list() {
+            Some([head, expected, actual]) if head.as_symbol() == Some("assert-equal") => Ok(format!(
+                "{{ let expected = format!(\"{{:?}}\", {}); let actual = format!(\"{{:?}}\", {}); if expected != actual {{ return Err(format!(\"{{}} failed\\n  - expected: {{}}\\n  + actual:   {{}}\", {}, expected, actual)); }} }}",
+                self.compile_expression(expected)?,
+                self.compile_expression(actual)?,
+                source
+            )),
+            Some([head, condition]) if head.as_symbol() == Some("assert-true") => Ok(format!(
+                "if !{} {{ return Err(format!(\"{{}} failed: the condition was false\", {})); }}",
+                self.compile_expression(condition)?,
+                source
+            )),
+            Some([head, ..]) if matches!(head.as_symbol(), Some("assert-equal" | "assert-true")) => {
+                Err(CompileError::new("L0402", format!("Wrong number of arguments to '{}' in {}", head, form)))
+            }
+            _ => Ok(format!("let _ = {};", self.compile_expression(form)?)),
+        }
+    }
+
+    fn compile_list(&mut self, elements: &[LispExpr]) -> Result<String, CompileError> {
+        if elements.is_empty() {
+            return Ok("vec![]".to_string());
+        }
+        
+        let compiled = self.compile_call(elements)?;
+        // With a fuel budget every evaluated form costs one unit
+        if self.sandbox.is_some_and(|config| config.max_fuel.is_some()) {
+            Ok(format!("{{ sandbox_rt::tick(); {} }}", compiled))
+        } else {
+            Ok(compiled)
+        }
+    }
+    
+    fn compile_call(&mut self, elements: &[LispExpr]) -> Result<String, CompileError> {
+        let first = &elements[0];
+        let args = &elements[1..];
+        
+        match first.as_symbol() {
+            Some("+") => self.compile_arithmetic_op("+", args),
+            Some("-") => self.compile_arithmetic_op("-", args),
+            Some("*") => self.compile_arithmetic_op("*", args),
+            Some("/") => self.compile_arithmetic_op("/", args),
+            Some("mod") => self.compile_mod(args),
+            Some("=") => self.compile_comparison_op("==", args),
+            Some("<") => self.compile_comparison_op("<", args),
+            Some(">") => self.compile_comparison_op(">", args),
+            Some("<=") => self.compile_comparison_op("<=", args),
+            Some(">=") => self.compile_comparison_op(">=", args),
+            Some("if") => self.compile_if(args),
+            Some("assert") => self.compile_assert(args),
+            Some("let") if matches!(args.first(), Some(LispExpr::Symbol(_))) => self.compile_named_let(args),
+            Some(form @ ("let" | "let*")) => self.compile_let(form, args),
+            Some("destructuring-bind") => self.compile_destructuring_bind(args),
+            Some("values") => self.compile_values(args),
+            Some("let-values") => self.compile_let_values(args),
+            Some("letrec") => self.compile_letrec(args),
+            Some("list") => self.compile_list_creation(args),
+            Some(op @ ("string->number" | "number->string" | "symbol->string" | "to-bool")) => self.compile_conversion(op, args),
+            Some(op @ ("read-file" | "write-file")) => self.compile_io_op(op, args),
+            Some("run-command") => self.compile_run_command(args),
+            Some(op @ ("http-get" | "http-post")) => self.compile_http(op, args),
+            Some("getenv") => self.compile_getenv(args),
+            Some(op @ ("now" | "elapsed")) => self.compile_time(op, args),
+            Some(op @ ("random" | "random-int")) => self.compile_random(op, args),
+            Some(op @ ("json-parse" | "json-get" | "json-stringify")) => self.compile_json(op, args),
+            Some(op @ ("spawn" | "thread" | "async")) => self.compile_spawn(op, args),
+            Some(form @ ("defstruct" | "deftrait" | "defimpl" | "defun" | "require-crate")) => Err(CompileError::new("L0404", format!("'{}' can only appear at the top level of a program", form))),
+            Some(func_name) if self.loops.iter().any(|name| name == func_name) => {
+                Err(CompileError::new("L0404", format!("'{}' can only be called in tail position of its named let", func_name)))
+            }
+            Some(func_name) => self
+                .compile_item_call(func_name, args)
+                .unwrap_or_else(|| Err(self.unknown_function(func_name))),
+            None => Err(CompileError::new("L0406", "First element of list must be a symbol")),
+        }
+    }
+    
+    fn compile_arithmetic_op(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.is_empty() {
+            return Err(CompileError::new("L0402", format!("Arithmetic operation '{}' requires at least one argument", op)));
+        }
+        
+        let compiled_args = self.compile_operands(args)?;
+        
+        if compiled_args.len() == 1 {
+            match op {
+                "-" => Ok(format!("-({})", compiled_args[0])),
+                _ => Ok(compiled_args[0].clone()),
+            }
+        } else {
+            Ok(format!("({})", compiled_args.join(&format!(" {} ", op))))
+        }
+    }
+    
+    fn compile_comparison_op(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 2 {
+            return Err(CompileError::new("L0402", format!("Comparison operation '{}' requires exactly 2 arguments", op)));
+        }
+        
+        let operands = self.compile_operands(args)?;
+        
+        Ok(format!("({} {} {})", operands[0], op, operands[1]))
+    }
+
+    /// `(mod a b)`, the remainder with the sign of `b` as the interpreter computes it
+    fn compile_mod(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 2 {
+            return Err(CompileError::new("L0402", "'mod' requires exactly 2 arguments"));
+        }
+        let operands = self.with_float_literals(|compiler| compiler.compile_operands(args))?;
+        Ok(format!("f64::rem_euclid({}, {})", operands[0], operands[1]))
+    }
+
+    /// The operands of an arithmetic or comparison operator, with whole number
+    /// literals written as `f64` when another operand is a fraction
+    fn compile_operands(&mut self, args: &[LispExpr]) -> Result<Vec<String>, CompileError> {
+        let mixed = args.iter().any(|arg| matches!(arg, LispExpr::Number(n) if n.fract() != 0.0));
+        args.iter()
+            .map(|arg| match arg {
+                LispExpr::Number(n) if mixed => Ok(float_literal(*n)),
+                _ => self.compile_expression(arg),
+            })
+            .collect()
+    }
+    
+    fn compile_if(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 3 {
+            return Err(CompileError::new("L0402", "'if' requires exactly 3 arguments: condition, then-expr, else-expr"));
+        }
+        
+        let condition = self.compile_expression(&args[0])?;
+        let then_expr = self.compile_expression(&args[1])?;
+        let else_expr = self.compile_expression(&args[2])?;
+        
+        Ok(format!("if {} {{ {} }} else {{ {} }}", condition, then_expr, else_expr))
+    }
+    
+    /// `let` and `let*` both bind in sequence, each value seeing the bindings before it
+    fn compile_let(&mut self, form: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 2 {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly 2 arguments: bindings and body", form)));
+        }
+
+        let LispExpr::List(bindings) = &args[0] else {
+            return Err(CompileError::new("L0406", format!("First argument to '{}' must be a list of bindings", form)));
+        };
+        let mut rust_code = String::new();
+        rust_code.push('{');
+        for binding in bindings.iter() {
+            let Some([pattern, value]) = binding.as_list() else {
+                return Err(CompileError::new("L0406", "Each binding must be a list of [pattern, value]"));
+            };
+            let pattern = Pattern::parse(pattern).map_err(|e| e.to_string())?;
+            self.compile_pattern_binding(&pattern, value, &mut rust_code)?;
+        }
+
+        let body = self.compile_expression(&args[1])?;
+        rust_code.push_str(&format!(" {} }}", body));
+
+        Ok(rust_code)
+    }
+
+    /// `(values a b ...)` as a tuple; a single value is just that value
+    fn compile_values(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let values = args.iter().map(|arg| self.compile_expression(arg)).collect::<Result<Vec<_>, _>>()?;
+        Ok(match values.as_slice() {
+            [value] => value.clone(),
+            _ => format!("({})", values.join(", ")),
+        })
+    }
+
+    /// `(let-values (((x y) expr) ...) body)` as tuple destructuring; every value form
+    /// is evaluated before any of the variables are bound
+    fn compile_let_values(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let [LispExpr::List(bindings), body] = args else {
+            return Err(CompileError::new("L0402", "'let-values' requires exactly 2 arguments: a list of bindings and a body"));
+        };
+        let mut patterns = Vec::new();
+        let mut values = Vec::new();
+        for binding in bindings.iter() {
+            let Some([LispExpr::List(formals), value]) = binding.as_list() else {
+                return Err(CompileError::new("L0406", format!("Each 'let-values' binding must be a list of [(variables...), value]: {}", binding)));
+            };
+            let variables = formals
+                .iter()
+                .map(|formal| match formal {
+                    LispExpr::Symbol(variable) => Ok(identifier(variable)),
+                    other => Err(CompileError::new("L0406", format!("'let-values' variables must be symbols, got {}", other))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            patterns.push(match variables.as_slice() {
+                [variable] => variable.clone(),
+                _ => format!("({})", variables.join(", ")),
+            });
+            values.push(self.compile_expression(value)?);
+        }
+        let body = self.compile_expression(body)?;
+        Ok(format!("{{ {} {} }}", parallel_assignment("let ", &patterns, &values), body))
+    }
+
+    /// `(destructuring-bind pattern list body)`, indexing into the list after checking its length
+    fn compile_destructuring_bind(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let [pattern, value, body] = args else {
+            return Err(CompileError::new("L0402", "'destructuring-bind' requires exactly 3 arguments: pattern, value, and body"));
+        };
+        let pattern = Pattern::parse(pattern).map_err(|e| e.to_string())?;
+        let mut rust_code = String::from("{");
+        self.compile_pattern_binding(&pattern, value, &mut rust_code)?;
+        rust_code.push_str(&format!(" {} }}", self.compile_expression(body)?));
+        Ok(rust_code)
+    }
+
+    /// `let` statements binding `pattern` to `value` as `compile_pattern` does, except
+    /// that a pattern mixing variables and list patterns, which `compile_pattern`
+    /// rejects, takes a `(list ...)` form apart where it is written, as it would a
+    /// tuple: `(a (b c) &rest r)` matches `(list 1 (list 2 3) 4 5)`. Every argument
+    /// is evaluated, in order, before any variable is bound.
+    fn compile_pattern_binding(&mut self, pattern: &Pattern, value: &LispExpr, code: &mut String) -> Result<(), CompileError> {
+        if !mixes_lists_anywhere(pattern) || !is_list_form_matched(pattern, value) {
+            return compile_pattern(pattern, &self.compile_expression(value)?, "destructured", code);
+        }
+        let mut bindings = Vec::new();
+        self.evaluate_pattern_value(pattern, value, "destructured", code, &mut bindings)?;
+        for (pattern, temp) in bindings {
+            compile_pattern(&pattern, &temp, &temp, code)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates `value` into `temp`, or, for a `(list ...)` form `pattern` matches,
+    /// each of its arguments into a temporary of its own, adding the pattern that
+    /// binds each temporary to `bindings`
+    fn evaluate_pattern_value(&mut self, pattern: &Pattern, value: &LispExpr, temp: &str, code: &mut String, bindings: &mut Vec<(Pattern, String)>) -> Result<(), CompileError> {
+        let (Pattern::List { elements, rest }, true) = (pattern, is_list_form_matched(pattern, value)) else {
+            code.push_str(&format!(" let {} = {};", temp, self.compile_expression(value)?));
+            bindings.push((pattern.clone(), temp.to_string()));
+            return Ok(());
+        };
+        let args = &value.as_list().unwrap()[1..];
+        if args.len() < elements.len() || (rest.is_none() && args.len() != elements.len()) {
+            let error = MatchError::Arity { expected: elements.len(), rest: rest.is_some(), actual: args.len() };
+            return Err(CompileError::new("L0406", format!("Pattern {} cannot match {}: {}", pattern, value, error)));
+        }
+        for (index, (element, arg)) in elements.iter().zip(args).enumerate() {
+            self.evaluate_pattern_value(element, arg, &format!("{}_{}", temp, index), code, bindings)?;
+        }
+        if let Some(rest) = rest {
+            let temp = format!("{}_rest", temp);
+            code.push_str(&format!(" let {} = {};", temp, self.compile_list_creation(&args[elements.len()..])?));
+            bindings.push((Pattern::Variable(rest.clone()), temp));
+        }
+        Ok(())
+    }
+
+    /// `(letrec ((name (lambda (params) body...)) ...) body)` as a block of nested
+    /// `fn` items, which may call each other, followed by the body. Like any `fn`
+    /// item they cannot refer to the local variables around the `letrec`.
+    fn compile_letrec(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let [bindings, body] = args else {
+            return Err(CompileError::new("L0402", "'letrec' requires exactly 2 arguments: a list of bindings and a body"));
+        };
+        let mut functions = Vec::new();
+        for (name, value) in self.let_bindings("letrec", bindings)? {
+            let lambda = match value.as_list() {
+                Some([head, parameters, body @ ..]) if head.as_symbol() == Some("lambda") => {
+                    let definition: Vec<LispExpr> = [LispExpr::Symbol(name.into()), parameters.clone()].into_iter().chain(body.iter().cloned()).collect();
+                    Function::parse(&definition).map_err(|e| format!("In 'letrec' binding '{}': {}", name, e))?
+                }
+                _ => return Err(CompileError::new("L0403", format!("'letrec' binds functions in compiled code, so the value of '{}' must be a lambda, got {}", name, value))),
+            };
+            functions.push(Rc::new(lambda));
+        }
+
+        let scope = self.local_functions.len();
+        self.local_functions.extend(functions.iter().cloned());
+        let compiled = self.compile_letrec_block(&functions, body);
+        self.local_functions.truncate(scope);
+        compiled
+    }
+
+    fn compile_letrec_block(&mut self, functions: &[Rc<Function>], body: &LispExpr) -> Result<String, CompileError> {
+        let mut rust_code = String::from("{ ");
+        for function in functions {
+            rust_code.push_str(&self.with_float_literals(|compiler| compiler.compile_function_body(function, None))?);
+        }
+        rust_code.push_str(&format!("{} }}", self.compile_expression(body)?));
+        Ok(rust_code)
+    }
+
+    fn let_bindings<'e>(&self, form: &str, bindings: &'e LispExpr) -> Result<Vec<(&'e str, &'e LispExpr)>, CompileError> {
+        let LispExpr::List(bindings) = bindings else {
+            return Err(CompileError::new("L0406", format!("First argument to '{}' must be a list of bindings", form)));
+        };
+        bindings
+            .iter()
+            .map(|binding| match binding.as_list() {
+                Some([LispExpr::Symbol(name), value]) => Ok((name.as_str(), value)),
+                Some([_, _]) => Err(CompileError::new("L0406", "Variable name must be a symbol")),
+                _ => Err(CompileError::new("L0406", "Each binding must be a list of [variable, value]")),
+            })
+            .collect()
+    }
+
+    /// `(let name ((var init) ...) body)` as a Rust `loop`. Calls to `name` in tail
+    /// position rebind the variables and go round again; any other result ends the loop.
+    fn compile_named_let(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let [LispExpr::Symbol(name), bindings, body] = args else {
+            return Err(CompileError::new("L0402", "Named 'let' requires exactly 3 arguments: name, bindings, and body"));
+        };
+        let bindings = self.let_bindings("let", bindings)?;
+        let variables: Vec<String> = bindings.iter().map(|(variable, _)| identifier(variable)).collect();
+        let values = bindings.iter().map(|(_, value)| self.compile_expression(value)).collect::<Result<Vec<_>, _>>()?;
+        let mutable: Vec<String> = variables.iter().map(|variable| format!("mut {}", variable)).collect();
+
+        self.loops.push(name.to_string());
+        let body = self.compile_loop_tail(name, &variables, body);
+        self.loops.pop();
+        Ok(format!("{{ {} loop {{ {} }} }}", parallel_assignment("let ", &mutable, &values), body?))
+    }
+
+    fn compile_loop_tail(&mut self, name: &str, variables: &[String], expr: &LispExpr) -> Result<String, CompileError> {
+        let Some(elements) = expr.as_list() else {
+            return Ok(format!("break {}", self.compile_expression(expr)?));
+        };
+        match elements.split_first() {
+            Some((head, [condition, then_expr, else_expr])) if head.as_symbol() == Some("if") => Ok(format!(
+                "if {} {{ {} }} else {{ {} }}",
+                self.compile_expression(condition)?,
+                self.compile_loop_tail(name, variables, then_expr)?,
+                self.compile_loop_tail(name, variables, else_expr)?
+            )),
+            Some((head, args)) if head.as_symbol() == Some(name) => {
+                if args.len() != variables.len() {
+                    return Err(CompileError::new("L0402", format!("'{}' expects {} argument(s), got {}", name, variables.len(), args.len())));
+                }
+                let values = args.iter().map(|arg| self.compile_expression(arg)).collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("{{ {} continue }}", parallel_assignment("", variables, &values)))
+            }
+            _ => Ok(format!("break {}", self.compile_expression(expr)?)),
+        }
+    }
+
+    /// `(assert condition "message")` panics with the message, or the condition, when it is false
+    fn compile_assert(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let (condition, message) = match args {
+            [condition] => (condition, format!("{:?}", condition.to_string())),
+            [condition, message] => (condition, self.compile_expression(message)?),
+            _ => return Err(CompileError::new("L0402", "'assert' requires a condition and an optional message")),
+        };
+        Ok(format!("assert!({}, \"Assertion failed: {{}}\", {})", self.compile_expression(condition)?, message))
+    }
+
+    /// Conversions between numbers, strings, symbols, and booleans
+    fn compile_conversion(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 1 {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly 1 argument", op)));
+        }
+
+        match (op, &args[0]) {
+            // Generated code has no symbol values, so only a quoted symbol has a name to take
+            ("symbol->string", LispExpr::Quote(quoted)) => match quoted.as_ref() {
+                LispExpr::Symbol(name) => Ok(format!("{:?}.to_string()", name.as_str())),
+                other => Err(format!("'symbol->string' requires a symbol, got {}", other).into()),
             },
+            ("symbol->string", other) => Err(format!("'symbol->string' requires a quoted symbol, got {}", other).into()),
+            // Only false and nil are false; known literals are decided here
+            ("to-bool", LispExpr::Bool(b)) => Ok(b.to_string()),
+            ("to-bool", LispExpr::Nil) => Ok("false".to_string()),
+            ("to-bool", LispExpr::Number(_) | LispExpr::String(_)) => Ok("true".to_string()),
+            ("to-bool", arg) => {
+                let value = self.compile_expression(arg)?;
+                Ok(format!(
+                    "{{ let value = {}; let value: &dyn std::any::Any = &value; value.downcast_ref::<bool>() != Some(&false) && !value.is::<()>() }}",
+                    value
+                ))
+            }
+            ("number->string", arg) => Ok(format!("format!(\"{{}}\", {})", self.compile_expression(arg)?)),
+            (_, arg) => Ok(format!("{}.trim().parse::<f64>().unwrap()", self.compile_expression(arg)?)),
         }
     }
-    
-    fn compile_list(&mut self, elements: &[LispExpr]) -> Result<String, String> {
-        if elements.is_empty() {
-            return Ok("vec![]".to_string());
+
+    /// File operations; sandboxed builds call into `sandbox_rt`
+    fn compile_io_op(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let arity = if op == "write-file" { 2 } else { 1 };
+        if args.len() != arity {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly {} argument(s)", op, arity)));
         }
         
-        let first = &elements[0];
-        let args = &elements[1..];
+        let compiled_args: Vec<String> = args
+            .iter()
+            .map(|arg| self.compile_expression(arg))
+            .collect::<Result<_, _>>()?;
         
-        match first.as_symbol() {
-            Some("+") => self.compile_arithmetic_op("+", args),
-            Some("-") => self.compile_arithmetic_op("-", args),
-            Some("*") => self.compile_arithmetic_op("*", args),
-            Some("/") => self.compile_arithmetic_op("/", args),
-            Some("=") => self.compile_comparison_op("==", args),
-            Some("<") => self.compile_comparison_op("<", args),
-            Some(">") => self.compile_comparison_op(">", args),
-            Some("<=") => self.compile_comparison_op("<=", args),
-            Some(">=") => self.compile_comparison_op(">=", args),
-            Some("if") => self.compile_if(args),
-            Some("let") => self.compile_let(args),
-            Some("list") => self.compile_list_creation(args),
-            Some(func_name) => Err(format!("Unknown function: {}", func_name)),
-            None => Err("First element of list must be a symbol".to_string()),
+        if self.sandbox.is_some() {
+            let function = op.replace('-', "_");
+            let refs: Vec<String> = compiled_args.iter().map(|arg| format!("&{}", arg)).collect();
+            return Ok(self.audited(op, args, format!("sandbox_rt::{}({})", function, refs.join(", "))));
         }
+        
+        let arg = &compiled_args[0];
+        Ok(match op {
+            "read-file" => format!("std::fs::read_to_string(&{}).unwrap()", arg),
+            _ => format!("std::fs::write(&{}, &{}).unwrap()", arg, compiled_args[1]),
+        })
     }
-    
-    fn compile_arithmetic_op(&mut self, op: &str, args: &[LispExpr]) -> Result<String, String> {
+
+    /// `(run-command program args...)` runs the program directly, without a shell, and
+    /// evaluates to its standard output. Sandboxed builds without `ProcessSpawn` are
+    /// rejected here; `sandbox_rt::run_command` checks again at runtime.
+    fn compile_run_command(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
         if args.is_empty() {
-            return Err(format!("Arithmetic operation '{}' requires at least one argument", op));
+            return Err(CompileError::new("L0402", "'run-command' requires a program and its arguments"));
         }
-        
-        let compiled_args: Result<Vec<String>, String> = args
+        if let Some(config) = self.sandbox
+            && !config.has_capability(&Capability::ProcessSpawn)
+        {
+            return Err(CompileError::new("L0407", "Sandbox violation: Process spawning not permitted in sandbox mode (run-command)"));
+        }
+        let compiled_args: Vec<String> = args
             .iter()
             .map(|arg| self.compile_expression(arg))
-            .collect();
-        
-        let compiled_args = compiled_args?;
-        
-        if compiled_args.len() == 1 {
-            match op {
-                "-" => Ok(format!("-({})", compiled_args[0])),
-                _ => Ok(compiled_args[0].clone()),
-            }
-        } else {
-            Ok(format!("({})", compiled_args.join(&format!(" {} ", op))))
+            .collect::<Result<_, _>>()?;
+        let (program, argv) = compiled_args.split_first().expect("checked above");
+
+        if self.sandbox.is_some() {
+            let argv: Vec<String> = argv.iter().map(|arg| format!("&{} as &str", arg)).collect();
+            let call = format!("sandbox_rt::run_command(&{}, &[{}])", program, argv.join(", "));
+            return Ok(self.audited("run-command", args, call));
         }
+        let argv: String = argv.iter().map(|arg| format!(".arg(&{})", arg)).collect();
+        Ok(format!(
+            "String::from_utf8_lossy(&std::process::Command::new(&{}){}.output().unwrap().stdout).to_string()",
+            program, argv
+        ))
     }
     
-    fn compile_comparison_op(&mut self, op: &str, args: &[LispExpr]) -> Result<String, String> {
-        if args.len() != 2 {
-            return Err(format!("Comparison operation '{}' requires exactly 2 arguments", op));
+    /// `(http-get url)` and `(http-post url body)` through `curl`. Sandboxed builds reject
+    /// literal URLs no network capability covers, and any URL when none is granted, at
+    /// compile time; `sandbox_rt` checks the URL again at runtime. The URL follows a
+    /// `--`, and a literal one starting with `-` is rejected, so curl never reads it as an option.
+    /// Only `http` and `https` URLs are accepted: a literal one with another scheme is
+    /// rejected, and curl is run with `--proto =http,https` for the rest.
+    fn compile_http(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let arity = if op == "http-post" { 2 } else { 1 };
+        if args.len() != arity {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly {} argument(s)", op, arity)));
         }
-        
-        let left = self.compile_expression(&args[0])?;
-        let right = self.compile_expression(&args[1])?;
-        
-        Ok(format!("({} {} {})", left, op, right))
-    }
-    
-    fn compile_if(&mut self, args: &[LispExpr]) -> Result<String, String> {
-        if args.len() != 3 {
-            return Err("'if' requires exactly 3 arguments: condition, then-expr, else-expr".to_string());
+        let compiled_args: Vec<String> = args
+            .iter()
+            .map(|arg| self.compile_expression(arg))
+            .collect::<Result<_, _>>()?;
+        if let LispExpr::String(url) = &args[0]
+            && url.starts_with('-')
+        {
+            return Err(format!("'{}' URL would be read as a curl option: {}", op, url).into());
         }
-        
-        let condition = self.compile_expression(&args[0])?;
-        let then_expr = self.compile_expression(&args[1])?;
-        let else_expr = self.compile_expression(&args[2])?;
-        
-        Ok(format!("if {} {{ {} }} else {{ {} }}", condition, then_expr, else_expr))
+        if let LispExpr::String(url) = &args[0]
+            && !sandbox::is_http_url(url)
+        {
+            return Err(format!("'{}' only accepts http and https URLs: {}", op, url).into());
+        }
+
+        let Some(config) = self.sandbox else {
+            let post = match compiled_args.get(1) {
+                Some(body) => format!(".arg(\"-X\").arg(\"POST\").arg(\"--data-raw\").arg(&{})", body),
+                None => String::new(),
+            };
+            return Ok(format!(
+                "String::from_utf8_lossy(&std::process::Command::new(\"curl\").arg(\"-s\").arg(\"--proto\").arg(\"=http,https\"){}.arg(\"--\").arg(&{}).output().unwrap().stdout).to_string()",
+                post, compiled_args[0]
+            ));
+        };
+        match &args[0] {
+            LispExpr::String(url) => SandboxMonitor::new(config.clone())
+                .check_network_access(url)
+                .map_err(|violation| CompileError::new("L0407", format!("Sandbox violation: {}", violation)))?,
+            _ if !config.network_enabled() => {
+                return Err(CompileError::new("L0407", format!("Sandbox violation: '{}' requires a NetworkHTTP or Network capability", op)));
+            }
+            _ => {}
+        }
+        let refs: Vec<String> = compiled_args.iter().map(|arg| format!("&{}", arg)).collect();
+        Ok(self.audited(op, args, format!("sandbox_rt::{}({})", op.replace('-', "_"), refs.join(", "))))
     }
-    
-    fn compile_let(&mut self, args: &[LispExpr]) -> Result<String, String> {
-        if args.len() != 2 {
-            return Err("'let' requires exactly 2 arguments: bindings and body".to_string());
+
+    /// Sandboxed builds reject variables no `EnvRead` capability covers at compile time,
+    /// and check names only known at runtime in `sandbox_rt`
+    fn compile_getenv(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.len() != 1 {
+            return Err(CompileError::new("L0402", "'getenv' requires exactly 1 argument"));
         }
-        
-        let bindings = match &args[0] {
-            LispExpr::List(bindings) => bindings,
-            _ => return Err("First argument to 'let' must be a list of bindings".to_string()),
+        let name = self.compile_expression(&args[0])?;
+
+        let Some(config) = self.sandbox else {
+            return Ok(format!("std::env::var(&{}).unwrap_or_default()", name));
         };
-        
-        let mut rust_code = String::new();
-        rust_code.push('{');
-        
-        for binding in bindings {
-            match binding {
-                LispExpr::List(binding_pair) if binding_pair.len() == 2 => {
-                    let var_name = match &binding_pair[0] {
-                        LispExpr::Symbol(name) => name,
-                        _ => return Err("Variable name must be a symbol".to_string()),
-                    };
-                    let value = self.compile_expression(&binding_pair[1])?;
-                    rust_code.push_str(&format!(" let {} = {};", var_name, value));
-                },
-                _ => return Err("Each binding must be a list of [variable, value]".to_string()),
+        match &args[0] {
+            LispExpr::String(literal) => SandboxMonitor::new(config.clone())
+                .check_env_access(literal)
+                .map_err(|violation| CompileError::new("L0407", format!("Sandbox violation: {}", violation)))?,
+            _ if !config.capabilities.iter().any(|c| matches!(c, Capability::EnvRead(_))) => {
+                return Err(CompileError::new("L0407", "Sandbox violation: 'getenv' requires an EnvRead capability"));
             }
+            _ => {}
         }
-        
-        let body = self.compile_expression(&args[1])?;
-        rust_code.push_str(&format!(" {} }}", body));
-        
-        Ok(rust_code)
+        Ok(self.audited("getenv", args, format!("sandbox_rt::getenv(&{})", name)))
+    }
+
+    /// Milliseconds since the Unix epoch, and since a `(now)` value, which is evaluated
+    /// before the clock is read. Sandboxed builds without `SystemTime` are rejected
+    /// here; `sandbox_rt::now` checks again at runtime.
+    fn compile_time(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let arity = if op == "elapsed" { 1 } else { 0 };
+        if args.len() != arity {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly {} argument(s)", op, arity)));
+        }
+        let now = match self.sandbox {
+            Some(config) if !config.has_capability(&Capability::SystemTime) => {
+                return Err(CompileError::new("L0407", format!("Sandbox violation: System time access not permitted in sandbox mode ({})", op)));
+            }
+            Some(_) => self.audited(op, args, "sandbox_rt::now()".to_string()),
+            None => {
+                self.uses_clock = true;
+                "lisp_clock::now()".to_string()
+            }
+        };
+        match args.first() {
+            Some(start) => Ok(format!("{{ let start: f64 = {}; {} - start }}", self.compile_expression(start)?, now)),
+            None => Ok(now),
+        }
+    }
+
+    fn compile_random(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let arity = if op == "random-int" { 2 } else { 0 };
+        if args.len() != arity {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly {} argument(s)", op, arity)));
+        }
+        let compiled_args: Vec<String> = args
+            .iter()
+            .map(|arg| self.compile_expression(arg))
+            .collect::<Result<_, _>>()?;
+        self.uses_random = true;
+        Ok(format!("lisp_rng::{}({})", op.replace('-', "_"), compiled_args.join(", ")))
+    }
+
+    fn compile_json(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        let arity = if op == "json-get" { 2 } else { 1 };
+        if args.len() != arity {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly {} argument(s)", op, arity)));
+        }
+        let compiled_args: Vec<String> = args
+            .iter()
+            .map(|arg| self.compile_expression(arg))
+            .collect::<Result<_, _>>()?;
+        self.uses_json = true;
+        Ok(match op {
+            "json-parse" => format!("lisp_json::parse(&{})", compiled_args[0]),
+            "json-get" => format!("lisp_json::get(&{}, {})", compiled_args[0], compiled_args[1]),
+            _ => format!("lisp_json::stringify(&{})", compiled_args[0]),
+        })
+    }
+
+    /// Runs the body on its own thread and waits for its value. Sandboxed builds without
+    /// `ThreadSpawn` are rejected here; `sandbox_rt::spawn` checks again at runtime.
+    fn compile_spawn(&mut self, op: &str, args: &[LispExpr]) -> Result<String, CompileError> {
+        if args.is_empty() {
+            return Err(CompileError::new("L0406", format!("'{}' requires a body", op)));
+        }
+        if let Some(config) = self.sandbox
+            && !config.has_capability(&Capability::ThreadSpawn)
+        {
+            return Err(CompileError::new("L0407", format!("Sandbox violation: Thread spawning not permitted in sandbox mode ({})", op)));
+        }
+        let body: Vec<String> = args
+            .iter()
+            .map(|arg| self.compile_expression(arg))
+            .collect::<Result<_, _>>()?;
+        let closure = format!("move || {{ {} }}", body.join("; "));
+
+        if self.sandbox.is_some() {
+            Ok(self.audited(op, args, format!("sandbox_rt::spawn({})", closure)))
+        } else {
+            Ok(format!("std::thread::spawn({}).join().unwrap()", closure))
+        }
+    }
+
+    /// With an audit log, tell the runtime which form a checked call came from
+    fn audited(&self, op: &str, args: &[LispExpr], call: String) -> String {
+        if self.sandbox.is_none_or(|config| config.audit_log.is_none()) {
+            return call;
+        }
+        let mut form = vec![LispExpr::Symbol(op.into())];
+        form.extend(args.iter().cloned());
+        format!("{{ sandbox_rt::form({:?}); {} }}", LispExpr::List(form.into()).to_string(), call)
     }
     
-    fn compile_list_creation(&mut self, args: &[LispExpr]) -> Result<String, String> {
-        let compiled_args: Result<Vec<String>, String> = args
+    fn compile_list_creation(&mut self, args: &[LispExpr]) -> Result<String, CompileError> {
+        let compiled_args: Result<Vec<String>, CompileError> = args
             .iter()
             .map(|arg| self.compile_expression(arg))
             .collect();
         
         let compiled_args = compiled_args?;
-        Ok(format!("vec![{}]", compiled_args.join(", ")))
+        if self.sandbox.is_some() {
+            Ok(format!("sandbox_rt::track(vec![{}])", compiled_args.join(", ")))
+        } else {
+            Ok(format!("vec![{}]", compiled_args.join(", ")))
+        }
     }
 }
 
@@ -192,6 +2563,20 @@ mod tests {
     use super::*;
     use crate::lexer::tokenize;
     use crate::parser::parse;
+
+    /// What the program compiled from `source` prints, built with rustc
+    fn run_output(source: &str) -> String {
+        static BUILDS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let rust_code = compile_to_rust(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let build = BUILDS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("rusty-lisp-compiler-test-{}-{}", std::process::id(), build));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = crate::runner::build(&rust_code, crate::runner::Target::Native, &dir).unwrap_or_else(|e| panic!("{}\n{}", e, rust_code));
+        let output = std::process::Command::new(program).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
     
     #[test]
     fn test_compile_arithmetic() {
@@ -210,4 +2595,607 @@ mod tests {
         
         assert!(rust_code.contains("((1 + 2) * 3)"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compile_deeply_nested_expression() {
+        let depth = 100_000;
+        let source = format!("(defun two () 2)\n{}(two){}", "(+ 1 ".repeat(depth), ")".repeat(depth));
+        let rust_code = compile_to_rust(&parse(tokenize(&source).unwrap()).unwrap()).unwrap();
+        assert!(rust_code.contains(&format!("{}two()", "(1.0 + ".repeat(depth))));
+    }
+    
+    #[test]
+    fn test_compile_let_forms() {
+        let compile = |source: &str| compile_expression(&parse(tokenize(source).unwrap()).unwrap()[0]);
+        assert_eq!(compile("(let* ((x 2) (y (* x 3))) (+ x y))").unwrap(), "{ let x = 2; let y = (x * 3); (x + y) }");
+        assert_eq!(
+            compile("(let loop ((i 0) (acc 1)) (if (> i 5) acc (loop (+ i 1) (* acc 2))))").unwrap(),
+            "{ let (mut i, mut acc) = (0, 1); loop { if (i > 5) { break acc } else { { (i, acc) = ((i + 1), (acc * 2)); continue } } } }"
+        );
+        assert_eq!(compile("(let down ((n 3)) (if (= n 0) 0 (down (- n 1))))").unwrap(), "{ let mut n = 3; loop { if (n == 0) { break 0 } else { { n = (n - 1); continue } } } }");
+        assert_eq!(compile("(let loop ((i 0)) (+ 1 (loop i)))").unwrap_err().message(), "'loop' can only be called in tail position of its named let");
+        assert_eq!(compile("(let loop ((i 0)) (loop))").unwrap_err().message(), "'loop' expects 1 argument(s), got 0");
+        assert_eq!(compile("(letrec ((f 1)) f)").unwrap_err().message(), "'letrec' binds functions in compiled code, so the value of 'f' must be a lambda, got 1");
+
+        let source = "(letrec ((even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))\n         (odd? (lambda (n) (if (= n 0) false (even? (- n 1))))))\n  (even? 10))";
+        assert!(compile(source).unwrap().starts_with("{ fn even_3f_(n: f64) -> bool {\n    if (n == 0.0) { true } else { odd_3f_(((n - 1.0)).into()) }\n}\n"));
+        let factorial = "(defun fact (n) (letrec ((go (lambda (i acc k) (if (> i k) acc (go (+ i 1) (* acc i) k))))) (go 1 1 n)))\n(fact 5)";
+        assert_eq!(run_output(&format!("{}\n{}", source, factorial)), "true\n120.0\n");
+    }
+
+    #[test]
+    fn test_compile_string_to_number() {
+        let tokens = tokenize("(string->number \"4.5\")").unwrap();
+        let ast = parse(tokens).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        
+        assert!(rust_code.contains("\"4.5\".trim().parse::<f64>().unwrap()"));
+    }
+
+    #[test]
+    fn test_compile_conversions() {
+        let compile = |source: &str| compile_expression(&parse(tokenize(source).unwrap()).unwrap()[0]);
+        assert_eq!(compile("(number->string (+ 1 2))").unwrap(), "format!(\"{}\", (1 + 2))");
+        assert_eq!(compile("(symbol->string 'apple)").unwrap(), "\"apple\".to_string()");
+        assert_eq!(compile("(symbol->string x)").unwrap_err().message(), "'symbol->string' requires a quoted symbol, got x");
+        assert_eq!(compile("(to-bool nil)").unwrap(), "false");
+        assert_eq!(compile("(to-bool 0)").unwrap(), "true");
+        assert!(compile("(to-bool (< 1 2))").unwrap().contains("downcast_ref::<bool>() != Some(&false)"));
+        assert_eq!(compile("(number->string 1 2)").unwrap_err().message(), "'number->string' requires exactly 1 argument");
+    }
+    
+    #[test]
+    fn test_compile_values() {
+        let compile = |source: &str| compile_expression(&parse(tokenize(source).unwrap()).unwrap()[0]);
+        assert_eq!(compile("(values 1 \"two\")").unwrap(), "(1, \"two\")");
+        assert_eq!(compile("(values 1)").unwrap(), "1");
+        assert_eq!(
+            compile("(let-values (((q r) (values 17 5)) ((one) 1)) (+ q one))").unwrap(),
+            "{ let ((q, r), one) = ((17, 5), 1); (q + one) }"
+        );
+        assert_eq!(compile("(let-values (((q 1) (values 1 2))) q)").unwrap_err().message(), "'let-values' variables must be symbols, got 1");
+
+        // Functions return several numbers as a tuple
+        let source = "(defun divmod (n d) (values (/ (- n (mod n d)) d) (mod n d)))\n(let-values (((q r) (divmod 17 5))) (list q r))";
+        let rust_code = compile_to_rust(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        assert!(rust_code.contains("fn divmod(n: f64, d: f64) -> (f64, f64) {\n    (((n - f64::rem_euclid(n, d)) / d), f64::rem_euclid(n, d))\n}"), "{}", rust_code);
+        assert_eq!(run_output(source), "[3.0, 2.0]\n");
+        assert_eq!(run_output("(mod -7 3)\n(+ 1.5 2)"), "2.0\n3.5\n");
+        // Top-level literals are `f64`s like function results once there are functions
+        assert_eq!(run_output("(defun sq (x) (* x x))\n(+ (sq 2) 1)\n(< 3 (sq 2))"), "5.0\ntrue\n");
+
+        // and strings as a `String`, which string parameters and I/O built-ins take
+        let source = "(defun path () \"Cargo.toml\")\n(defun echo ((s : string)) s)\n(echo (path))\n(= (read-file (path)) (read-file \"Cargo.toml\"))";
+        let rust_code = compile_to_rust(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        assert!(rust_code.contains("fn path() -> String {\n    (\"Cargo.toml\").into()\n}"), "{}", rust_code);
+        assert_eq!(run_output(source), "\"Cargo.toml\"\ntrue\n");
+    }
+
+    #[test]
+    fn test_compile_defstruct() {
+        let program = parse(tokenize(
+            "(defstruct point (x (label : string)) :derive (Clone Debug PartialEq) :impl ((scaled (self k) : point (make-point (* (point-x self) k) (point-label self))) (describe (self) (point-label self)))) \
+             (scaled (make-point 1 \"a\") 2)",
+        ).unwrap()).unwrap();
+        let rust_code = compile_to_rust(&program).unwrap();
+        assert!(rust_code.starts_with("#[derive(Debug, Clone, PartialEq)]\nstruct Point {\n    x: f64,\n    label: String,\n}\n\nimpl Point {\n"));
+        assert!(rust_code.contains("    fn scaled(&self, k: f64) -> Point {\n        (Point { x: ((self.x.clone() * k)).into(), label: (self.label.clone()).into() }).into()\n    }\n"));
+        assert!(rust_code.contains("    fn describe(&self) -> impl std::fmt::Debug {"));
+        assert!(rust_code.contains("println!(\"{:?}\", Point { x: (1).into(), label: (\"a\").into() }.scaled((2).into()));"));
+
+        let compile = |source: &str| compile_to_rust(&parse(tokenize(source).unwrap()).unwrap());
+        assert_eq!(compile("(defstruct p (x)) (defstruct p (y))").unwrap_err().message(), "'defstruct' p is defined more than once");
+        assert_eq!(compile("(defstruct p (x)) (make-p 1 2)").unwrap_err().message(), "'make-p' expects 1 argument(s), got 2");
+        assert_eq!(compile("(list (defstruct p (x)))").unwrap_err().message(), "'defstruct' can only appear at the top level of a program");
+        assert_eq!(compile("(defstruct point (x)) (make-pont 1)").unwrap_err().message(), "Unknown function: make-pont (did you mean 'make-point'?)");
+        assert_eq!(compile("(defstruct point (x)) (point-y (make-point 1))").unwrap_err().message(), "Unknown function: point-y (did you mean 'point-x'?)");
+        assert_eq!(compile("(lst 1 2)").unwrap_err().message(), "Unknown function: lst (did you mean 'list'?)");
+    }
+
+    #[test]
+    fn test_compile_traits_and_generics() {
+        let program = parse(tokenize(
+            "(defstruct square (side)) \
+             (deftrait shape (area (self) number) (describe (self) : string \"a shape\")) \
+             (defimpl shape square (area (self) (* (square-side self) (square-side self)))) \
+             (defun id<T> ((x : T)) : T x) \
+             (defun total ((s : shape) n) : number (declare (requires (> n 0))) (* n (area s))) \
+             (total (id (make-square 2)) 3)",
+        ).unwrap()).unwrap();
+        let rust_code = compile_to_rust(&program).unwrap();
+        assert!(rust_code.contains("trait Shape {\n    fn area(&self) -> f64;\n\n    fn describe(&self) -> String {\n        (\"a shape\").into()\n    }\n}\n"));
+        assert!(rust_code.contains("impl Shape for Square {\n    fn area(&self) -> f64 {\n        ((self.side.clone() * self.side.clone())).into()\n    }\n}\n"));
+        assert!(rust_code.contains("fn id<T>(x: T) -> T {\n    x\n}\n"));
+        assert!(rust_code.contains(
+            "fn total(s: impl Shape, n: f64) -> f64 {\n    assert!((n > 0.0), \"{}\", \"Precondition of 'total' failed: (> n 0)\");\n    ((n * s.area())).into()\n}\n"
+        ));
+        assert!(rust_code.contains("println!(\"{:?}\", total(id(Square { side: (2.0).into() }), (3.0).into()));"));
+
+        let compile = |source: &str| compile_to_rust(&parse(tokenize(source).unwrap()).unwrap());
+        assert_eq!(compile("(defimpl shape square (area (self) 1))").unwrap_err().message(), "'defimpl' refers to unknown trait 'shape'");
+        assert_eq!(compile("(deftrait t (f (self) number)) (defimpl t number)").unwrap_err().message(), "'defimpl' t for number is missing method 'f'");
+        assert_eq!(compile("(defun f (&optional x) x)").unwrap_err().message(), "'f' parameter 'x' needs a default, such as (x 0), since compiled code has no nil of its type");
+        assert_eq!(compile("(defun f (x) x) (f)").unwrap_err().message(), "'f' expects 1 argument(s), got 0");
+    }
+
+    #[test]
+    fn test_compile_optional_and_keyword_parameters() {
+        let source = "(defun greet ((name : string) &optional (times 1) &key (greeting \"Hello\") (punctuation \"!\"))\n  (list greeting name punctuation))";
+        let rust_code = compile_to_rust(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        assert!(rust_code.contains(
+            "fn greet(name: String, times: Option<f64>, greeting: Option<String>, punctuation: Option<String>) -> impl std::fmt::Debug {\n    \
+             let times: f64 = match times { Some(times) => times, None => (1.0).into() };\n"
+        ));
+        let program = format!("{}\n(greet \"Ann\")\n(greet \"Ann\" 2 :punctuation \"?\")\n(greet \"Bob\" :greeting \"Hi\")", source);
+        assert_eq!(run_output(&program), "[\"Hello\", \"Ann\", \"!\"]\n[\"Hello\", \"Ann\", \"?\"]\n[\"Hi\", \"Bob\", \"!\"]\n");
+
+        // Defaults see the parameters before them; &rest collects a Vec
+        let source = "(defun scaled (x &optional (factor (* x 2))) (* x factor))\n(scaled 3)\n(scaled 3 1)\n(defun others (a &rest more) more)\n(others 1 2 3)";
+        assert_eq!(run_output(source), "18.0\n3.0\n[2.0, 3.0]\n");
+
+        // Unannotated parameters passed strings or bools take them
+        let source = "(defun greet (name &optional (title \"\") &key (greeting \"Hello\"))\n  (list greeting title name))\n(greet \"Ann\" :greeting \"Hi\")\n\
+                      (defun either (flag a b) (if flag a b))\n(either (> 2 1) 1 2)";
+        let rust_code = compile_to_rust(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        assert!(rust_code.contains("fn greet(name: String, title: Option<String>, greeting: Option<String>)"), "{}", rust_code);
+        assert!(rust_code.contains("fn either(flag: bool, a: f64, b: f64)"), "{}", rust_code);
+        assert_eq!(run_output(source), "[\"Hi\", \"\", \"Ann\"]\n1.0\n");
+
+        let compile = |source: &str| compile_to_rust(&parse(tokenize(source).unwrap()).unwrap());
+        assert_eq!(
+            compile("(defun id (x) x) (id \"a\") (id 1)").unwrap_err().message(),
+            "'id' parameter 'x' is passed a string and a number, but compiled code gives each parameter one type"
+        );
+        assert_eq!(
+            compile(&format!("{}\n(greet \"Ann\" :colour 1)", "(defun greet ((name : string) &key (greeting \"Hello\")) greeting)")).unwrap_err().message(),
+            "'greet' has no keyword argument :colour (expected one of :greeting)"
+        );
+        assert!(compile("(defun f (&key (k 1)) (declare (export)) k)").unwrap_err().message().contains("cannot be exported"));
+    }
+
+    #[test]
+    fn test_compile_declarations() {
+        let program = parse(tokenize(
+            "(defun double (x) : number (declare (inline) (since \"0.3\") (deprecated \"use twice\")) (* x 2)) (double 4)",
+        ).unwrap()).unwrap();
+        let rust_code = compile_to_rust(&program).unwrap();
+        assert!(rust_code.contains("/// Deprecated: use twice\n/// Since: 0.3\n#[inline]\nfn double(x: f64) -> f64 {\n"));
+
+        let compile = |source: &str| compile_to_rust(&parse(tokenize(source).unwrap()).unwrap());
+        assert_eq!(compile("(defun f () (declare (fast)) 1)").unwrap_err().message(), "In 'f': Unknown declaration 'fast' (expected one of inline, pure, deprecated, since, export, capabilities, requires, ensures, precondition, postcondition)");
+    }
+
+    #[test]
+    fn test_compile_mangled_names() {
+        let program = parse(tokenize(
+            "(defun reset! (type) (declare (export)) (let ((new-value? (+ type 1))) new-value?)) \
+             (defun step (n) (declare (export \"lisp_step\")) (let loop ((i n)) (if (> i 0) (loop (- i 1)) i))) \
+             (defstruct line-segment (start-x) (declare (export))) \
+             (reset! (step 2))",
+        ).unwrap()).unwrap();
+        let rust_code = compile_to_rust(&program).unwrap();
+        assert!(rust_code.contains("#[doc(alias = \"reset!\")]\n#[no_mangle]\npub fn reset_21_(r#type: f64) -> impl std::fmt::Debug {\n    { let new_value_3f_ = (r#type + 1.0); new_value_3f_ }\n}\n"));
+        assert!(rust_code.contains("#[export_name = \"lisp_step\"]\npub fn step(n: f64)"));
+        assert!(rust_code.contains("let mut i = n; loop { if (i > 0.0) { { i = (i - 1.0); continue } } else { break i } }"));
+        assert!(rust_code.contains("#[doc(alias = \"line-segment\")]\n#[derive(Debug)]\npub struct LineSegment {\n    pub start_x: f64,\n}\n"));
+        assert!(rust_code.contains("println!(\"{:?}\", reset_21_((step((2.0).into())).into()));"));
+
+        let compile = |source: &str| compile_to_rust(&parse(tokenize(source).unwrap()).unwrap());
+        assert_eq!(compile("(defun a-b () 1) (defun a_b () 2)").unwrap_err().message(), "'defun' a-b and a_b are both named a_b in Rust");
+        assert_eq!(compile("(defun id<T> ((x : T)) (declare (export)) x)").unwrap_err().message(), "'id' has type parameters, so it cannot be exported under one symbol");
+    }
+
+    #[test]
+    fn test_compile_require_crate() {
+        let program = parse(tokenize(
+            "(require-crate \"regex\" \"1\" :use (Regex)) (defstruct matcher ((pattern : Regex))) (+ 1 2)",
+        ).unwrap()).unwrap();
+        let rust_code = compile_to_rust(&program).unwrap();
+        assert!(rust_code.starts_with("#[allow(unused_imports)]\nuse regex::{Regex};\n\n#[derive(Debug)]\nstruct Matcher {\n    pattern: Regex,\n}\n"));
+        assert!(!rust_code.contains("println!(\"{:?}\", require"));
+
+        let mut config = SandboxConfig::new();
+        assert_eq!(
+            compile_to_rust_sandboxed(&program, &config).unwrap_err().message(),
+            "Sandbox violation: 'require-crate' regex requires the Crate:regex capability"
+        );
+        config.add_capability(Capability::Crate("regex".to_string()));
+        assert!(compile_to_rust_sandboxed(&program, &config).is_ok());
+        assert_eq!(
+            compile_expression(&parse(tokenize("(if 1 (require-crate \"regex\" \"1\") 2)").unwrap()).unwrap()[0]).unwrap_err().message(),
+            "'require-crate' can only appear at the top level of a program"
+        );
+    }
+
+    #[test]
+    fn test_compile_destructuring() {
+        let compile = |source: &str| compile_expression(&parse(tokenize(source).unwrap()).unwrap()[0]);
+        assert_eq!(
+            compile("(destructuring-bind (a &rest r) (list 1 2) a)").unwrap(),
+            "{ let destructured = vec![1, 2]; if destructured.len() < 1 { panic!(\"pattern {} expects at least 1 element(s), got {}\", \"(a &rest r)\", destructured.len()) } \
+             let a = destructured[0].clone(); let r = destructured[1..].to_vec(); a }"
+        );
+        let nested = compile("(let ((((x) (y)) pairs)) y)").unwrap();
+        assert!(nested.contains("let destructured_1 = destructured[1].clone(); if destructured_1.len() != 1"));
+        assert!(nested.contains("let y = destructured_1[0].clone();"));
+        assert_eq!(
+            compile("(destructuring-bind (a (b c) &rest r) xs a)").unwrap_err().message(),
+            "Pattern (a (b c) &rest r) mixes variables and list patterns, which compiled code can only match against a list written as (list ...): its lists hold elements of one type"
+        );
+        // A (list ...) form is taken apart where it is written, so the pattern may mix them
+        assert_eq!(
+            compile("(destructuring-bind (a (b c) &rest r) (list 1 (list 2 3) 4 5) a)").unwrap(),
+            "{ let destructured_0 = 1; let destructured_1_0 = 2; let destructured_1_1 = 3; let destructured_rest = vec![4, 5]; \
+             let a = destructured_0; let b = destructured_1_0; let c = destructured_1_1; let r = destructured_rest; a }"
+        );
+        assert_eq!(
+            run_output("(destructuring-bind (a (b c) &rest r) (list 1 (list 2 3) 4 5) (values (+ a b c) r))\n(let ((x 1) (y 2)) (let (((x (y)) (list y (list x)))) (list x y)))"),
+            "(6, [4, 5])\n[2, 1]\n"
+        );
+        assert_eq!(
+            compile("(let (((a (b)) (list 1))) a)").unwrap_err().message(),
+            "Pattern (a (b)) cannot match (list 1): expected 2 element(s), got 1"
+        );
+        assert_eq!(
+            run_output("(destructuring-bind ((a b) (c d)) (list (list 1 2) (list 3 4)) (+ a d))\n(destructuring-bind (a b &rest r) (list 1 2 3 4) (list a b))"),
+            "5\n[1, 2]\n"
+        );
+        assert_eq!(compile("(let ((1 2)) 3)").unwrap_err().message(), "Invalid pattern '1': Patterns are symbols or lists of patterns");
+        assert!(compile("(destructuring-bind (a) (list 1))").is_err());
+    }
+
+    #[test]
+    fn test_compile_assert() {
+        let compile = |source: &str| compile_expression(&parse(tokenize(source).unwrap()).unwrap()[0]);
+        assert_eq!(compile("(assert (> 2 1) \"order\")").unwrap(), "assert!((2 > 1), \"Assertion failed: {}\", \"order\")");
+        assert_eq!(compile("(assert (= 1 2))").unwrap(), "assert!((1 == 2), \"Assertion failed: {}\", \"(= 1 2)\")");
+        assert_eq!(compile("(assert)").unwrap_err().message(), "'assert' requires a condition and an optional message");
+    }
+
+    #[test]
+    fn test_compile_io_without_sandbox() {
+        let tokens = tokenize("(read-file \"/tmp/in.txt\")").unwrap();
+        let ast = parse(tokens).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        
+        assert!(rust_code.contains("std::fs::read_to_string(&\"/tmp/in.txt\").unwrap()"));
+        assert!(!rust_code.contains("sandbox_rt"));
+    }
+    
+    #[test]
+    fn test_compile_sandboxed_routes_through_runtime() {
+        let tokens = tokenize("(read-file \"/tmp/in.txt\") (run-command \"ls\" \"-la\") (list 1 2)").unwrap();
+        let ast = parse(tokens).unwrap();
+        let mut config = SandboxConfig::new().with_max_memory(4096);
+        config.add_capability(Capability::FileRead(PathBuf::from("/tmp")));
+        config.add_capability(Capability::ProcessSpawn);
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        
+        assert!(rust_code.contains("mod sandbox_rt"));
+        assert!(rust_code.contains("sandbox_rt::start();"));
+        assert!(rust_code.contains("sandbox_rt::read_file(&\"/tmp/in.txt\")"));
+        assert!(rust_code.contains("sandbox_rt::run_command(&\"ls\", &[&\"-la\" as &str])"));
+        assert!(rust_code.contains("sandbox_rt::track(vec![1, 2])"));
+        assert!(rust_code.contains("const MAX_MEMORY: usize = 4096;"));
+        assert!(rust_code.contains("const READ_PATHS: &[&str] = &[\"/tmp\"];"));
+        assert!(rust_code.contains("const PROCESS_SPAWN: bool = true;"));
+    }
+    
+    #[test]
+    fn test_compile_sandboxed_embeds_network_rules() {
+        let ast = parse(tokenize("(http-get \"https://api.example.com\")").unwrap()).unwrap();
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::Network { host_pattern: "API.example.com".to_string(), port: Some(443) });
+        config.deny_capability(Capability::Network { host_pattern: "*.evil.com".to_string(), port: None });
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+
+        assert!(rust_code.contains("const NETWORK_HTTP: bool = false;"));
+        assert!(rust_code.contains("const NETWORK_RULES: &[(&str, u16)] = &[(\"api.example.com\", 443)];"));
+        assert!(rust_code.contains("const NETWORK_DENY: &[(&str, u16)] = &[(\"*.evil.com\", 0)];"));
+    }
+
+    #[test]
+    fn test_compile_http() {
+        let ast = parse(tokenize("(http-post \"https://api.example.com/v1\" \"{}\")").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.contains(".arg(\"-X\").arg(\"POST\").arg(\"--data-raw\").arg(&\"{}\").arg(\"--\").arg(&\"https://api.example.com/v1\")"));
+        let option = parse(tokenize("(http-get \"-o/tmp/x\")").unwrap()).unwrap();
+        assert_eq!(compile_to_rust(&option).unwrap_err().message(), "'http-get' URL would be read as a curl option: -o/tmp/x");
+
+        let error = compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).unwrap_err();
+        assert_eq!(error.message(), "Sandbox violation: Unauthorized network access attempted");
+
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::Network { host_pattern: "*.example.com".to_string(), port: None });
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("sandbox_rt::http_post(&\"https://api.example.com/v1\", &\"{}\")"));
+        let other_host = parse(tokenize("(http-get \"https://evil.com\")").unwrap()).unwrap();
+        assert!(compile_to_rust_sandboxed(&other_host, &config).unwrap_err().message().contains("evil.com"));
+
+        let dynamic = parse(tokenize("(let ((url \"https://a.b\")) (http-get url))").unwrap()).unwrap();
+        let error = compile_to_rust_sandboxed(&dynamic, &SandboxConfig::new()).unwrap_err();
+        assert_eq!(error.message(), "Sandbox violation: 'http-get' requires a NetworkHTTP or Network capability");
+        let rust_code = compile_to_rust_sandboxed(&dynamic, &SandboxConfig::new().with_network_access(true)).unwrap();
+        assert!(rust_code.contains("sandbox_rt::http_get(&url)"));
+
+        // A URL only known at runtime is checked before curl sees it
+        let option = parse(tokenize("(let ((url \"-o/tmp/x\")) (http-get url))").unwrap()).unwrap();
+        let rust_code = compile_to_rust_sandboxed(&option, &SandboxConfig::new().with_network_access(true)).unwrap();
+        assert_eq!(crate::runner::run(&rust_code, crate::runner::Target::Native, &SandboxConfig::new()).unwrap(), 101);
+    }
+
+    #[test]
+    fn test_runtime_host_matcher_agrees_with_sandbox() {
+        let cases = [
+            ("*example.com", "evilexample.com"),
+            ("*.example.com", "api.example.com"),
+            ("*.example.com", "example.com"),
+            ("*.Example.COM", "API.example.com"),
+            ("Example.COM", "example.com"),
+            ("api.example.com", "api.example.com.evil"),
+            ("*", "anything.net"),
+        ];
+        let checks: String = cases
+            .iter()
+            .map(|(pattern, host)| {
+                format!(
+                    "    assert_eq!(sandbox_rt::host_matches({:?}, {:?}), {});\n",
+                    pattern,
+                    host,
+                    crate::sandbox::host_matches(pattern, host)
+                )
+            })
+            .collect();
+        let program = format!("{}\nfn main() {{\n{}}}\n", sandbox_runtime(&SandboxConfig::new()), checks);
+        assert_eq!(crate::runner::run(&program, crate::runner::Target::Native, &SandboxConfig::new()).unwrap(), 0);
+
+        // A pattern without the dot is an exact host, at runtime as at compile time
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::Network { host_pattern: "*example.com".to_string(), port: None });
+        let dynamic = parse(tokenize("(let ((url \"http://evilexample.com\")) (http-get url))").unwrap()).unwrap();
+        let rust_code = compile_to_rust_sandboxed(&dynamic, &config).unwrap();
+        assert_eq!(crate::runner::run(&rust_code, crate::runner::Target::Native, &config).unwrap(), 101);
+    }
+
+    #[test]
+    fn test_compile_capability_scopes() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("*".to_string()));
+        let functions = "(defun home () (declare (capabilities (env-read \"HOME\"))) (getenv \"HOME\"))\n\
+                         (defun named ((n : string)) (declare (capabilities (env-read \"HOME\"))) (getenv n))\n\
+                         (defun outer () (declare (capabilities (env-read \"*\"))) (named \"PATH\"))\n\
+                         (defun wide () (declare (capabilities (env-read \"*\"))) (getenv \"PATH\"))\n\
+                         (defun narrow () (declare (capabilities (env-read \"HOME\"))) (wide))\n";
+        let exit_code = |calls: &str| {
+            let program = parse(tokenize(&format!("{}{}", functions, calls)).unwrap()).unwrap();
+            let rust_code = compile_to_rust_sandboxed(&program, &config).unwrap();
+            crate::runner::run(&rust_code, crate::runner::Target::Native, &config).unwrap()
+        };
+        let rust_code = compile_to_rust_sandboxed(&parse(tokenize(functions).unwrap()).unwrap(), &config).unwrap();
+        assert!(rust_code.contains("fn home() -> String {\n    let _scope = sandbox_rt::enter(&[(\"EnvRead\", \"HOME\", 0)]);\n"));
+        assert!(!compile_to_rust(&parse(tokenize(functions).unwrap()).unwrap()).unwrap().contains("sandbox_rt"));
+
+        // Outside any scope the configuration alone decides; inside, every enclosing declaration must allow it
+        assert_eq!(exit_code("(home)\n(named \"HOME\")\n(getenv \"PATH\")\n(wide)"), 0);
+        assert_eq!(exit_code("(named \"PATH\")"), 101);
+        assert_eq!(exit_code("(outer)"), 101);
+        // A callee's broader declaration cannot widen its caller's
+        assert_eq!(exit_code("(narrow)"), 101);
+    }
+
+    #[test]
+    fn test_compile_run_command() {
+        let ast = parse(tokenize("(let ((dir \"/tmp\")) (run-command \"ls\" \"-la\" dir))").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.contains("std::process::Command::new(&\"ls\").arg(&\"-la\").arg(&dir).output().unwrap().stdout"));
+
+        let error = compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).unwrap_err();
+        assert!(error.message().contains("Process spawning not permitted"));
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::ProcessSpawn);
+        config.deny_capability(Capability::ProcessSpawn);
+        assert!(compile_to_rust_sandboxed(&ast, &config).is_err());
+        assert!(compile_expression(&parse(tokenize("(run-command)").unwrap()).unwrap()[0]).is_err());
+    }
+
+    #[test]
+    fn test_compile_time_and_random() {
+        let ast = parse(tokenize("(let ((start (now)) (roll (random-int 1 7))) (+ (elapsed start) (random)))").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.contains("lisp_rng::random_int(1, 7)"));
+        assert!(rust_code.contains("const SEED: Option<u64> = None;"));
+        assert!(rust_code.contains("{ let start: f64 = start; lisp_clock::now() - start }"));
+        assert!(rust_code.contains("mod lisp_clock"));
+        // The start is read before the clock, so an elapsed time is never negative
+        let elapsed = run_output("(elapsed (now))");
+        assert!(elapsed.trim().parse::<f64>().unwrap() >= 0.0, "{}", elapsed);
+        assert!(!compile_to_rust(&parse(tokenize("(+ 1 2)").unwrap()).unwrap()).unwrap().contains("lisp_rng"));
+
+        let seeded = compile_to_rust_seeded(&ast, None, 42).unwrap();
+        assert!(seeded.contains("const SEED: Option<u64> = Some(42);"));
+
+        let error = compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).unwrap_err();
+        assert!(error.message().contains("System time access not permitted"), "{}", error);
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::SystemTime);
+        let rust_code = compile_to_rust_seeded(&ast, Some(&config), 7).unwrap();
+        assert!(rust_code.contains("{ let start: f64 = start; sandbox_rt::now() - start }"));
+        assert!(rust_code.contains("const SYSTEM_TIME: bool = true;"));
+        assert!(rust_code.contains("const SEED: Option<u64> = Some(7);"));
+    }
+
+    #[test]
+    fn test_compile_json() {
+        let ast = parse(tokenize("(json-get (json-get (json-parse (read-file \"in.json\")) \"items\") 0) (json-stringify (list 1 2))").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.starts_with("#[allow(dead_code)]\nmod lisp_json {"));
+        assert!(rust_code.contains("lisp_json::get(&lisp_json::get(&lisp_json::parse(&std::fs::read_to_string(&\"in.json\").unwrap()), \"items\"), 0)"));
+        assert!(rust_code.contains("lisp_json::stringify(&vec![1, 2])"));
+        assert_eq!(rust_code.matches("mod lisp_json").count(), 1);
+        // JSON escapes survive into the Rust string literal
+        assert_eq!(compile_expression(&LispExpr::String("\\u00e9\"\n".to_string())).unwrap(), r#""\\u00e9\"\n""#);
+        assert_eq!(compile_expression(&parse(tokenize("(json-get x)").unwrap()).unwrap()[0]).unwrap_err().message(), "'json-get' requires exactly 2 argument(s)");
+    }
+
+    #[test]
+    fn test_compile_sandboxed_meters_fuel() {
+        let ast = parse(tokenize("(+ 1 (* 2 3))").unwrap()).unwrap();
+        let unmetered = compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).unwrap();
+        assert!(unmetered.contains("const MAX_FUEL: u64 = u64::MAX;"));
+        assert!(!unmetered.contains("sandbox_rt::tick()"));
+
+        let config = SandboxConfig::new().with_max_fuel(100);
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("const MAX_FUEL: u64 = 100;"));
+        assert_eq!(rust_code.matches("sandbox_rt::tick();").count(), 2);
+    }
+
+    #[test]
+    fn test_compile_sandboxed_audit_log() {
+        let ast = parse(tokenize("(read-file \"/data/in.txt\")").unwrap()).unwrap();
+        let plain = compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).unwrap();
+        assert!(plain.contains("const AUDIT_LOG: Option<&str> = None;"));
+        assert!(!plain.contains("sandbox_rt::form("));
+
+        let config = SandboxConfig::new().with_audit_log(PathBuf::from("/tmp/audit.jsonl"));
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("const AUDIT_LOG: Option<&str> = Some(\"/tmp/audit.jsonl\");"));
+        assert!(rust_code.contains(r#"sandbox_rt::form("(read-file \"/data/in.txt\")"); sandbox_rt::read_file(&"/data/in.txt")"#));
+    }
+
+    #[test]
+    fn test_compile_getenv() {
+        let ast = parse(tokenize("(getenv \"HOME\")").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.contains("std::env::var(&\"HOME\").unwrap_or_default()"));
+
+        let sandboxed = compile_to_rust_sandboxed(&ast, &SandboxConfig::new());
+        assert_eq!(
+            sandboxed.unwrap_err().message(),
+            "Sandbox violation: Unauthorized environment variable access: HOME"
+        );
+
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("HOME".to_string()));
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("sandbox_rt::getenv(&\"HOME\")"));
+        assert!(rust_code.contains("const ENV_VARS: &[&str] = &[\"HOME\"];"));
+    }
+
+    #[test]
+    fn test_compile_sandboxed_dynamic_getenv_needs_env_read() {
+        let ast = parse(tokenize("(let ((name \"HOME\")) (getenv name))").unwrap()).unwrap();
+        assert!(compile_to_rust_sandboxed(&ast, &SandboxConfig::new()).is_err());
+
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("APP_*".to_string()));
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("sandbox_rt::getenv(&name)"));
+    }
+
+    #[test]
+    fn test_compile_sandboxed_embeds_denies() {
+        let ast = parse(tokenize("(read-file \"/tmp/in.txt\")").unwrap()).unwrap();
+        let mut config = SandboxConfig::new().with_network_access(true);
+        config.add_capability(Capability::ProcessSpawn);
+        config.add_capability(Capability::FileRead(PathBuf::from("/")));
+        config.deny_capability(Capability::ProcessSpawn);
+        config.deny_capability(Capability::NetworkHTTP);
+        config.deny_capability(Capability::FileRead(PathBuf::from("/etc")));
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        
+        assert!(rust_code.contains("const PROCESS_SPAWN: bool = false;"));
+        assert!(rust_code.contains("const NETWORK_HTTP: bool = false;"));
+        assert!(rust_code.contains("const READ_DENY: &[&str] = &[\"/etc\"];"));
+    }
+
+    #[test]
+    fn test_compile_spawn() {
+        let ast = parse(tokenize("(spawn (+ 1 2))").unwrap()).unwrap();
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(rust_code.contains("std::thread::spawn(move || { (1 + 2) }).join().unwrap()"));
+
+        let mut config = SandboxConfig::new();
+        let error = compile_to_rust_sandboxed(&ast, &config).unwrap_err();
+        assert!(error.message().contains("Thread spawning not permitted"));
+
+        config.add_capability(Capability::ThreadSpawn);
+        let rust_code = compile_to_rust_sandboxed(&ast, &config).unwrap();
+        assert!(rust_code.contains("const THREAD_SPAWN: bool = true;"));
+        assert!(rust_code.contains("sandbox_rt::spawn(move || {"));
+    }
+
+    #[test]
+    fn test_compile_tests() {
+        let ast = parse(tokenize("(deftest sums (assert-equal 3 (+ 1 2)) (assert-true (< 1 2)))\n(+ 4 5)").unwrap()).unwrap();
+        assert_eq!(test_name(&ast[0]), Some("sums"));
+
+        let rust_code = compile_tests(&ast).unwrap();
+        assert!(rust_code.contains("(\"sums\", || {"));
+        assert!(rust_code.contains("let expected = format!(\"{:?}\", 3.0); let actual = format!(\"{:?}\", (1.0 + 2.0)); if expected != actual {"));
+        assert!(rust_code.contains("if !(1.0 < 2.0) { return Err("));
+        assert!(!rust_code.contains("(4 + 5)"));
+
+        // Tests are left out of the regular program
+        let rust_code = compile_to_rust(&ast).unwrap();
+        assert!(!rust_code.contains("sums"));
+        assert!(rust_code.contains("(4 + 5)"));
+
+        let ast = parse(tokenize("(deftest bad (assert-equal 1))").unwrap()).unwrap();
+        assert!(compile_tests(&ast).unwrap_err().message().contains("Wrong number of arguments to 'assert-equal'"));
+        let ast = parse(tokenize("(deftest \"unnamed\" (+ 1 2))").unwrap()).unwrap();
+        assert!(compile_tests(&ast).unwrap_err().message().contains("requires a name symbol"));
+    }
+
+    #[test]
+    fn test_compile_tests_over_defuns() {
+        let source = "(defun square (x) (* x x))\n(deftest squares (assert-equal 4 (square 2)))\n(deftest wrong (assert-equal 5 (square 2)))";
+        let rust_code = compile_tests(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let dir = std::env::temp_dir().join(format!("rusty-lisp-compiler-tests-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = crate::runner::build(&rust_code, crate::runner::Target::Native, &dir).unwrap_or_else(|e| panic!("{}\n{}", e, rust_code));
+        let output = std::process::Command::new(program).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(!output.status.success());
+        assert!(stdout.contains("test squares ... ok"), "{}", stdout);
+        assert!(stdout.contains("test wrong ... FAILED"), "{}", stdout);
+        assert!(stdout.contains("  - expected: 5.0\n  + actual:   4.0"), "{}", stdout);
+    }
+
+    #[test]
+    fn test_compile_benches() {
+        let ast = parse(tokenize("(defbench sum (+ 1 2))\n(+ 4 5)").unwrap()).unwrap();
+        assert_eq!(bench_name(&ast[0]), Some("sum"));
+
+        let rust_code = compile_benches(&ast, 3, 20).unwrap();
+        assert!(rust_code.contains("(\"sum\", || { std::hint::black_box((1 + 2)); }),"));
+        assert!(rust_code.contains("for _ in 0..3 {"));
+        assert!(rust_code.contains("(0..20)"));
+        assert!(!rust_code.contains("(4 + 5)"));
+        assert!(!compile_to_rust(&ast).unwrap().contains("sum"));
+
+        assert!(compile_benches(&ast, 3, 0).is_err());
+        let ast = parse(tokenize("(defbench two (+ 1 2) (+ 3 4))").unwrap()).unwrap();
+        assert!(compile_benches(&ast, 3, 20).unwrap_err().message().contains("takes a name and one expression"));
+    }
+
+    #[test]
+    fn test_gensyms_are_rust_identifiers() {
+        assert_eq!(gensym_identifier("temp#g1"), "__g_temp_1");
+        assert_eq!(gensym_identifier("tmp-val?#g12"), "__g_tmp_val__12");
+        assert_eq!(gensym_identifier("imported"), "__g_imported");
+
+        let gensym = |name: &str| LispExpr::Gensym(name.into());
+        let program = vec![LispExpr::List(vec![
+            LispExpr::Symbol("let".into()),
+            LispExpr::List(vec![LispExpr::List(vec![gensym("tmp-val#g1"), LispExpr::Symbol("__g_tmp_val_1".into())].into())].into()),
+            LispExpr::List(vec![LispExpr::Symbol("+".into()), gensym("tmp-val#g1"), gensym("tmp-val#g1")].into()),
+        ].into())];
+        // The user's `__g_tmp_val_1` keeps its name and the gensym moves aside
+        assert_eq!(compile_expression(&program[0]).unwrap(), "{ let __g_tmp_val_1_ = __g_tmp_val_1; (__g_tmp_val_1_ + __g_tmp_val_1_) }");
+        assert!(compile_to_rust(&program).unwrap().contains("{ let __g_tmp_val_1_ = __g_tmp_val_1; (__g_tmp_val_1_ + __g_tmp_val_1_) }"));
+        assert!(matches!(rename_gensyms(&parse(tokenize("(+ 1 2)").unwrap()).unwrap()), Cow::Borrowed(_)));
+    }
+
+}
@@ -0,0 +1,116 @@
+//! Function contracts.
+//!
+//! A function body may start with `(declare (requires expr...) (ensures expr...))`.
+//! Preconditions are evaluated with the parameters bound; postconditions also see
+//! the return value as `result`. `precondition` and `postcondition` are accepted as
+//! longer spellings of `requires` and `ensures`. Other declaration clauses, such as
+//! `capabilities`, are left to `declarations`.
+
+use crate::ast::LispExpr;
+use crate::declarations::is_declaration;
+
+/// Name the return value is bound to in postconditions
+pub const RESULT: &str = "result";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Contract {
+    pub requires: Vec<LispExpr>,
+    pub ensures: Vec<LispExpr>,
+}
+
+impl Contract {
+    /// The contract declared by the leading `declare` forms of a function body,
+    /// and the body after them
+    pub fn parse(body: &[LispExpr]) -> Result<(Contract, &[LispExpr]), String> {
+        let mut contract = Contract::default();
+        let start = body.iter().take_while(|form| is_declaration(form)).count();
+        for declaration in &body[..start] {
+            for clause in &declaration.as_list().unwrap()[1..] {
+                let Some([head, conditions @ ..]) = clause.as_list() else {
+                    continue;
+                };
+                let conditions_of = match head.as_symbol() {
+                    Some("requires" | "precondition") => &mut contract.requires,
+                    Some("ensures" | "postcondition") => &mut contract.ensures,
+                    _ => continue,
+                };
+                if conditions.is_empty() {
+                    return Err(format!("'{}' requires at least one condition: {}", head, clause));
+                }
+                conditions_of.extend(conditions.iter().cloned());
+            }
+        }
+        Ok((contract, &body[start..]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requires.is_empty() && self.ensures.is_empty()
+    }
+}
+
+/// `form` without its contract when it is a `defun`, for compiling with contracts
+/// off: contract clauses are dropped from its declarations, and declarations left
+/// empty are dropped too
+pub fn strip(form: &LispExpr) -> LispExpr {
+    let Some(elements @ [head, ..]) = form.as_list() else {
+        return form.clone();
+    };
+    if head.as_symbol() != Some("defun") {
+        return form.clone();
+    }
+    let is_contract = |clause: &LispExpr| {
+        matches!(clause.as_list().and_then(|clause| clause.first()).and_then(LispExpr::as_symbol), Some("requires" | "precondition" | "ensures" | "postcondition"))
+    };
+    let elements: Vec<LispExpr> = elements
+        .iter()
+        .filter_map(|element| {
+            if !is_declaration(element) {
+                return Some(element.clone());
+            }
+            let clauses = element.as_list().unwrap();
+            let kept: Vec<LispExpr> = clauses.iter().filter(|clause| !is_contract(clause)).cloned().collect();
+            (kept.len() > 1 || kept.len() == clauses.len()).then(|| LispExpr::List(kept.into()))
+        })
+        .collect();
+    LispExpr::List(elements.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explain::CompileError;
+    use crate::lexer::tokenize;
+    use crate::parser;
+
+    fn parse(source: &str) -> Result<Vec<LispExpr>, CompileError> {
+        parser::parse(tokenize(source)?)
+    }
+
+    #[test]
+    fn test_parse_contract() {
+        let body = parse("(declare (capabilities) (requires (> n 0)) (ensures (>= result n))) (declare (precondition (< n 10))) (* n n)").unwrap();
+        let (contract, rest) = Contract::parse(&body).unwrap();
+        assert_eq!(contract.requires, parse("(> n 0) (< n 10)").unwrap());
+        assert_eq!(contract.ensures, parse("(>= result n)").unwrap());
+        assert_eq!(rest, &body[2..]);
+
+        let body = parse("(+ 1 2) (declare (requires false))").unwrap();
+        let (contract, rest) = Contract::parse(&body).unwrap();
+        assert!(contract.is_empty());
+        assert_eq!(rest.len(), 2);
+
+        let body = parse("(declare (ensures)) 1").unwrap();
+        assert!(Contract::parse(&body).unwrap_err().contains("'ensures' requires at least one condition"));
+    }
+
+    #[test]
+    fn test_strip_contract() {
+        let strip_one = |source: &str| strip(&parse(source).unwrap()[0]);
+        let stripped = parse("(defun f (n) (declare (inline)) (* n n))").unwrap();
+        assert_eq!(strip_one("(defun f (n) (declare (requires (> n 0)) (inline) (ensures (> result 0))) (* n n))"), stripped[0]);
+        let stripped = parse("(defun f (n) : number (* n n))").unwrap();
+        assert_eq!(strip_one("(defun f (n) : number (declare (precondition (> n 0))) (* n n))"), stripped[0]);
+        let untouched = parse("(defmacro m (n) (declare (requires n)) n)").unwrap();
+        assert_eq!(strip_one("(defmacro m (n) (declare (requires n)) n)"), untouched[0]);
+    }
+}
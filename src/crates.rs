@@ -0,0 +1,164 @@
+//! Rust crates that compiled programs depend on.
+//!
+//! `(require-crate "regex" "1" :use (Regex))` records a dependency on `regex` with the
+//! Cargo version requirement `1` and brings `regex::Regex` into scope. Code generation
+//! emits the `use` statements; `build --emit-project` also writes the dependencies into
+//! the generated Cargo.toml. Sandboxed builds need a `Crate:<name>` capability for each
+//! crate, and the crate validator rejects malformed or conflicting requirements.
+
+use crate::ast::LispExpr;
+use crate::sandbox::Capability;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrateRequirement {
+    /// The package name as published, e.g. `serde-json` or `regex`
+    pub name: String,
+    /// A Cargo version requirement, e.g. `1`, `^0.4`, or `>=1.2, <2`
+    pub version: String,
+    /// Paths brought into scope from the crate root, e.g. `Regex` or `bytes::Regex`
+    pub uses: Vec<String>,
+}
+
+impl CrateRequirement {
+    /// The arguments of a `require-crate` form: a name, a version, then an optional `:use (paths...)`
+    pub fn parse(args: &[LispExpr]) -> Result<CrateRequirement, String> {
+        let (name, version, uses) = match args {
+            [LispExpr::String(name), LispExpr::String(version)] => (name, version, &[][..]),
+            [LispExpr::String(name), LispExpr::String(version), option, LispExpr::List(uses)] if option.as_symbol() == Some(":use") => {
+                (name, version, &uses[..])
+            }
+            _ => return Err("'require-crate' requires a crate name, a version string, and optionally :use (paths...)".to_string()),
+        };
+        if !is_crate_name(name) {
+            return Err(format!("'require-crate' {:?} is not a valid crate name", name));
+        }
+        if version.trim().is_empty() || !version.chars().all(|c| c.is_ascii_alphanumeric() || " .,*^~=<>+-".contains(c)) {
+            return Err(format!("'require-crate' {} has an invalid version requirement {:?}", name, version));
+        }
+        let uses = uses
+            .iter()
+            .map(|path| match path.as_symbol() {
+                Some(path) if path.split("::").all(is_rust_identifier) => Ok(path.to_string()),
+                _ => Err(format!("'require-crate' {} can only :use Rust paths such as Regex or bytes::Regex, got {}", name, path)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CrateRequirement { name: name.clone(), version: version.clone(), uses })
+    }
+
+    /// The name Rust code refers to the crate by: `serde-json` becomes `serde_json`
+    pub fn rust_name(&self) -> String {
+        self.name.replace('-', "_")
+    }
+
+    /// `use regex::{Regex};`, or `use regex;` when nothing is imported. Imports the
+    /// program never refers to are not worth a warning in generated code.
+    pub fn use_statement(&self) -> String {
+        match self.uses.as_slice() {
+            [] => format!("#[allow(unused_imports)]\nuse {};\n", self.rust_name()),
+            uses => format!("#[allow(unused_imports)]\nuse {}::{{{}}};\n", self.rust_name(), uses.join(", ")),
+        }
+    }
+
+    /// The sandbox capability that allows depending on this crate
+    pub fn capability(&self) -> Capability {
+        Capability::Crate(self.name.clone())
+    }
+}
+
+/// Whether `form` is a `(require-crate ...)` form
+pub fn is_require_crate(form: &LispExpr) -> bool {
+    form.as_list().and_then(|elements| elements.first()).and_then(LispExpr::as_symbol) == Some("require-crate")
+}
+
+/// The crates a program's top-level `require-crate` forms depend on, in order. Requiring
+/// a crate twice merges its imports; requiring two different versions is an error.
+pub fn requirements(program: &[LispExpr]) -> Result<Vec<CrateRequirement>, String> {
+    let mut requirements: Vec<CrateRequirement> = Vec::new();
+    for form in program.iter().filter(|form| is_require_crate(form)) {
+        let requirement = CrateRequirement::parse(&form.as_list().unwrap()[1..])?;
+        match requirements.iter_mut().find(|earlier| earlier.name == requirement.name) {
+            Some(earlier) if earlier.version != requirement.version => {
+                return Err(format!(
+                    "'require-crate' {} is required with both version {:?} and {:?}",
+                    requirement.name, earlier.version, requirement.version
+                ));
+            }
+            Some(earlier) => {
+                for path in requirement.uses {
+                    if !earlier.uses.contains(&path) {
+                        earlier.uses.push(path);
+                    }
+                }
+            }
+            None => requirements.push(requirement),
+        }
+    }
+    Ok(requirements)
+}
+
+/// A Cargo.toml for the package `name` whose binary is the compiled program
+pub fn cargo_manifest(name: &str, requirements: &[CrateRequirement]) -> String {
+    let mut manifest = format!("[package]\nname = {:?}\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n", name);
+    for requirement in requirements {
+        manifest.push_str(&format!("{} = {:?}\n", requirement.name, requirement.version));
+    }
+    manifest
+}
+
+/// A valid Cargo package name made from `stem`, e.g. the input file's name
+pub fn package_name(stem: &str) -> String {
+    let name: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' }).collect();
+    match name.chars().next() {
+        Some(first) if first.is_ascii_alphabetic() => name,
+        _ => format!("lisp-{}", name),
+    }
+}
+
+fn is_crate_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn is_rust_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn program(source: &str) -> Vec<LispExpr> {
+        parse(tokenize(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_requirements() {
+        let required = requirements(&program(
+            r#"(require-crate "serde-json" "1.0") (require-crate "regex" "^1.10" :use (Regex)) (require-crate "regex" "^1.10" :use (bytes::Regex Regex))"#,
+        ))
+        .unwrap();
+        assert_eq!(required.len(), 2);
+        assert_eq!(required[0].use_statement(), "#[allow(unused_imports)]\nuse serde_json;\n");
+        assert_eq!(required[1].uses, vec!["Regex", "bytes::Regex"]);
+        assert_eq!(required[1].capability(), Capability::Crate("regex".to_string()));
+        assert_eq!(
+            cargo_manifest("demo", &required),
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\nserde-json = \"1.0\"\nregex = \"^1.10\"\n"
+        );
+        assert_eq!(package_name("2 fast"), "lisp-2-fast");
+    }
+
+    #[test]
+    fn test_malformed_requirements() {
+        let error = |source: &str| requirements(&program(source)).unwrap_err();
+        assert_eq!(
+            error(r#"(require-crate "regex" "1") (require-crate "regex" "2")"#),
+            "'require-crate' regex is required with both version \"1\" and \"2\""
+        );
+        assert!(error(r#"(require-crate regex "1")"#).contains("requires a crate name, a version string"));
+        assert!(error(r#"(require-crate "../regex" "1")"#).contains("is not a valid crate name"));
+        assert!(error(r#"(require-crate "regex" "1\"; evil")"#).contains("invalid version requirement"));
+        assert!(error(r#"(require-crate "regex" "1" :use ("Regex"))"#).contains("can only :use Rust paths"));
+    }
+}
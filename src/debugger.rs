@@ -0,0 +1,210 @@
+//! Step debugger for the interpreter: stops before calls and special forms, shows the
+//! form and the bindings in scope, and reads commands until told to go on.
+//!
+//! A breakpoint is a function or macro name; it stops at every form that calls it.
+
+use crate::ast::LispExpr;
+use crate::interpreter::{Environment, EvalObserver, Interpreter};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+const HELP: &str = "\
+Commands:
+  step (s)          evaluate this form, stopping at the first form inside it
+  next (n)          evaluate this form, stopping at the next form at this depth
+  continue (c)      run until a breakpoint
+  print (p) [NAME]  show NAME, or every binding in scope
+  break (b) NAME    stop at calls to the function or macro NAME
+  delete (d) NAME   remove the breakpoint on NAME
+  quit (q)          stop the program
+An empty line steps.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Step,
+    /// Stop at the next form no deeper than this
+    Next(usize),
+    Continue,
+}
+
+pub struct Debugger<R, W> {
+    input: R,
+    output: W,
+    breakpoints: HashSet<String>,
+    mode: Mode,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    /// Stops at the first form, or at the first breakpoint when there are any
+    pub fn new(input: R, output: W, breakpoints: Vec<String>) -> Self {
+        let mode = if breakpoints.is_empty() { Mode::Step } else { Mode::Continue };
+        Debugger { input, output, breakpoints: breakpoints.into_iter().collect(), mode }
+    }
+
+    pub fn output(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    fn breakpoint_at<'a>(&self, expr: &'a LispExpr) -> Option<&'a str> {
+        let name = expr.as_list()?.first()?.as_symbol()?;
+        self.breakpoints.contains(name).then_some(name)
+    }
+
+    fn write(&mut self, text: impl std::fmt::Display) -> Result<(), String> {
+        write!(self.output, "{}", text).and_then(|_| self.output.flush()).map_err(|e| e.to_string())
+    }
+
+    /// Every binding visible from `env`, innermost scope first
+    fn print_bindings(&mut self, env: &Rc<Environment>) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        let mut scope = Some(env.clone());
+        while let Some(current) = scope {
+            for name in current.names() {
+                if let Some(value) = current.get(&name)
+                    && seen.insert(name.clone())
+                {
+                    self.write(format_args!("  {} = {}\n", name, value))?;
+                }
+            }
+            scope = current.parent().cloned();
+        }
+        Ok(())
+    }
+
+    /// Read commands until one resumes evaluation
+    fn prompt(&mut self, env: &Rc<Environment>, depth: usize) -> Result<(), String> {
+        loop {
+            self.write("(debug) ")?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                self.write("\n")?;
+                self.mode = Mode::Continue;
+                return Ok(());
+            }
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("step");
+            let argument = words.next();
+            match (command, argument) {
+                ("s" | "step", _) => self.mode = Mode::Step,
+                ("n" | "next", _) => self.mode = Mode::Next(depth),
+                ("c" | "continue", _) => self.mode = Mode::Continue,
+                ("q" | "quit", _) => return Err("Debugging stopped".to_string()),
+                ("p" | "print", None) => {
+                    self.print_bindings(env)?;
+                    continue;
+                }
+                ("p" | "print", Some(name)) => {
+                    let shown = env.get(name).map_or_else(|| "undefined".to_string(), |value| value.to_string());
+                    self.write(format_args!("  {} = {}\n", name, shown))?;
+                    continue;
+                }
+                ("b" | "break", Some(name)) => {
+                    self.breakpoints.insert(name.to_string());
+                    self.write(format_args!("Breakpoint set on {}\n", name))?;
+                    continue;
+                }
+                ("d" | "delete", Some(name)) => {
+                    let removed = self.breakpoints.remove(name);
+                    self.write(format_args!("{} {}\n", if removed { "Removed breakpoint on" } else { "No breakpoint on" }, name))?;
+                    continue;
+                }
+                _ => {
+                    self.write(format_args!("{}\n", HELP))?;
+                    continue;
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> EvalObserver for Debugger<R, W> {
+    fn before_eval(&mut self, expr: &LispExpr, env: &Rc<Environment>, depth: usize) -> Result<(), String> {
+        let breakpoint = self.breakpoint_at(expr).map(str::to_string);
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(level) => depth <= level || breakpoint.is_some(),
+            Mode::Continue => breakpoint.is_some(),
+        };
+        if !stop {
+            return Ok(());
+        }
+        if let Some(name) = breakpoint {
+            self.write(format_args!("Breakpoint: {}\n", name))?;
+        }
+        self.write(format_args!("[{}] {}\n", depth, expr))?;
+        self.prompt(env, depth)
+    }
+}
+
+/// Run `program` in the interpreter under a debugger reading commands from `input`.
+/// The debugger's output and the value of each top-level form go to `output`;
+/// function contracts are checked on each call when `contracts` is set.
+pub fn debug_program<R: BufRead + 'static, W: Write + 'static>(
+    program: &[LispExpr],
+    breakpoints: Vec<String>,
+    contracts: bool,
+    input: R,
+    output: W,
+) -> Result<W, String> {
+    let debugger = Rc::new(RefCell::new(Debugger::new(input, output, breakpoints)));
+    let mut interpreter = Interpreter::new().with_contracts(contracts).with_observer(debugger.clone());
+    for expr in program {
+        let value = interpreter.eval_program(std::slice::from_ref(expr))?;
+        writeln!(debugger.borrow_mut().output(), "{}", value).map_err(|e| e.to_string())?;
+    }
+    drop(interpreter);
+    match Rc::try_unwrap(debugger) {
+        Ok(debugger) => Ok(debugger.into_inner().into_output()),
+        Err(_) => Err("The debugger is still referenced after the program finished".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use std::io::Cursor;
+
+    fn transcript(source: &str, breakpoints: &[&str], commands: &str) -> Result<String, String> {
+        let program = parse(tokenize(source).unwrap()).unwrap();
+        let breakpoints = breakpoints.iter().map(|name| name.to_string()).collect();
+        let output = debug_program(&program, breakpoints, true, Cursor::new(commands.to_string()), Vec::new())?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_stepping() {
+        let output = transcript("(+ 1 (* 2 3))", &[], "s\nn\nc\n").unwrap();
+        assert_eq!(output, "[1] (+ 1 (* 2 3))\n(debug) [2] (* 2 3)\n(debug) 7\n");
+
+        // `next` skips the forms inside the current one
+        let output = transcript("(+ 1 (* 2 3)) (- 1)", &[], "n\n\nc\n").unwrap();
+        assert_eq!(output, "[1] (+ 1 (* 2 3))\n(debug) 7\n[1] (- 1)\n(debug) -1\n");
+    }
+
+    #[test]
+    fn test_breakpoints_and_bindings() {
+        let source = "(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (fact 3)";
+        let output = transcript(source, &["fact"], "c\np n\nd fact\nc\n").unwrap();
+        assert_eq!(
+            output,
+            "fact\nBreakpoint: fact\n[1] (fact 3)\n(debug) Breakpoint: fact\n[2] (fact (- n 1))\n(debug)   n = 3\n(debug) Removed breakpoint on fact\n(debug) 6\n"
+        );
+
+        let output = transcript("(let ((x 1)) (+ x 2))", &[], "s\np\nq\n").unwrap_err();
+        assert_eq!(output, "Debugging stopped");
+    }
+
+    #[test]
+    fn test_end_of_input_continues() {
+        assert_eq!(transcript("(list 1 2)", &[], "").unwrap(), "[1] (list 1 2)\n(debug) \n(1 2)\n");
+    }
+}
@@ -0,0 +1,231 @@
+//! Declarations attached to definitions.
+//!
+//! `defun`, `(define (name ...) ...)`, `defmacro`, and `defstruct` forms may carry
+//! `(declare clause...)` forms: at the start of a function body, between a macro's
+//! parameters and its body, or among a struct's options. Each clause is checked
+//! against `SCHEMA`:
+//!
+//! - `(inline)` asks for the generated function to be inlined
+//! - `(pure)` promises the function has no side effects, which the validator checks
+//! - `(deprecated ["note"])` and `(since "version")` document the definition
+//! - `(export ["symbol"])` makes the generated Rust item public (see `mangle`)
+//! - `(capabilities entry...)` limits what a function may do (see `capabilities`)
+//! - `(requires expr...)` and `(ensures expr...)` are a function's contract (see `contracts`)
+
+use crate::ast::LispExpr;
+use crate::capabilities;
+use crate::deps::DefinitionKind;
+use crate::sandbox::Capability;
+use crate::types;
+
+use DefinitionKind::{Function, Macro, Struct};
+
+/// Each declaration clause and the definitions it may be attached to
+pub const SCHEMA: &[(&str, &[DefinitionKind])] = &[
+    ("inline", &[Function]),
+    ("pure", &[Function]),
+    ("deprecated", &[Function, Macro, Struct]),
+    ("since", &[Function, Macro, Struct]),
+    ("export", &[Function, Struct]),
+    ("capabilities", &[Function]),
+    ("requires", &[Function]),
+    ("ensures", &[Function]),
+    ("precondition", &[Function]),
+    ("postcondition", &[Function]),
+];
+
+/// A definition's `deprecated` declaration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deprecation {
+    /// What to use instead, or why the definition is going away
+    pub note: Option<String>,
+}
+
+/// A definition's `export` declaration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    /// The linker symbol of an exported function, when it is not the Rust name
+    pub symbol: Option<String>,
+}
+
+/// The metadata a definition declares. Contract clauses are parsed by `Contract`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Declarations {
+    pub inline: bool,
+    pub pure: bool,
+    pub deprecated: Option<Deprecation>,
+    pub since: Option<String>,
+    pub export: Option<Export>,
+    /// `None` when the definition does not restrict its capabilities
+    pub capabilities: Option<Vec<Capability>>,
+}
+
+impl Declarations {
+    /// Check the clauses of `forms`, each a `(declare ...)` form on a definition of `kind`
+    pub fn parse<'a>(forms: impl IntoIterator<Item = &'a LispExpr>, kind: DefinitionKind) -> Result<Declarations, String> {
+        let mut declarations = Declarations::default();
+        for form in forms {
+            for clause in form.as_list().and_then(|elements| elements.get(1..)).unwrap_or_default() {
+                declarations.add(clause, kind)?;
+            }
+        }
+        Ok(declarations)
+    }
+
+    fn add(&mut self, clause: &LispExpr, kind: DefinitionKind) -> Result<(), String> {
+        let Some((key, args)) = clause.as_list().and_then(|elements| Some((elements.first()?.as_symbol()?, &elements[1..]))) else {
+            return Err(format!("A declaration must be a list starting with its name: {}", clause));
+        };
+        let Some((_, kinds)) = SCHEMA.iter().find(|(name, _)| *name == key) else {
+            let known: Vec<&str> = SCHEMA.iter().map(|(name, _)| *name).collect();
+            return Err(format!("Unknown declaration '{}' (expected one of {})", key, known.join(", ")));
+        };
+        if !kinds.contains(&kind) {
+            return Err(format!("'{}' cannot be declared on a {}", key, kind));
+        }
+        let repeated = |set: bool| match set {
+            true => Err(format!("'{}' is declared more than once", key)),
+            false => Ok(()),
+        };
+        match (key, args) {
+            ("inline" | "pure", [_, ..]) => return Err(format!("'{}' takes no arguments: {}", key, clause)),
+            ("inline", []) => self.inline = true,
+            ("pure", []) => self.pure = true,
+            ("deprecated", [] | [LispExpr::String(_)]) => {
+                repeated(self.deprecated.is_some())?;
+                let note = match args {
+                    [LispExpr::String(note)] => Some(note.clone()),
+                    _ => None,
+                };
+                self.deprecated = Some(Deprecation { note });
+            }
+            ("deprecated", _) => return Err(format!("'deprecated' takes an optional string note: {}", clause)),
+            ("since", [LispExpr::String(version)]) => {
+                repeated(self.since.is_some())?;
+                self.since = Some(version.clone());
+            }
+            ("since", _) => return Err(format!("'since' takes one string version: {}", clause)),
+            ("export", []) => {
+                repeated(self.export.is_some())?;
+                self.export = Some(Export { symbol: None });
+            }
+            ("export", [LispExpr::String(symbol)]) if kind == Function => {
+                repeated(self.export.is_some())?;
+                self.export = Some(Export { symbol: Some(symbol.clone()) });
+            }
+            ("export", _) => return Err(format!("'export' takes an optional string symbol on a function, and nothing on a struct: {}", clause)),
+            ("capabilities", entries) => {
+                let entries = entries.iter().map(capabilities::parse_declared_capability).collect::<Result<Vec<_>, _>>()?;
+                self.capabilities.get_or_insert_with(Vec::new).extend(entries);
+            }
+            (_, []) => return Err(format!("'{}' requires at least one condition: {}", key, clause)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Declarations::default()
+    }
+}
+
+/// A definition that can carry declarations, with its `declare` forms
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declared<'a> {
+    pub name: &'a str,
+    pub kind: DefinitionKind,
+    pub forms: Vec<&'a LispExpr>,
+}
+
+impl Declared<'_> {
+    pub fn declarations(&self) -> Result<Declarations, String> {
+        Declarations::parse(self.forms.iter().copied(), self.kind)
+    }
+}
+
+/// The name, kind, and `declare` forms of a `defun`, `(define (name ...) ...)`,
+/// `defmacro`, or `defstruct` form
+pub fn declared(definition: &LispExpr) -> Option<Declared<'_>> {
+    let (name, kind, forms): (&str, _, Vec<&LispExpr>) = match definition {
+        LispExpr::Macro { name, declarations, .. } => (name, Macro, declarations.iter().collect()),
+        LispExpr::List(elements) => match &elements[..] {
+            [head, LispExpr::Symbol(name), LispExpr::List(_), body @ ..] if head.as_symbol() == Some("defun") => {
+                (types::split_generics(name).0, Function, leading_declarations(types::return_type(body).1))
+            }
+            [head, LispExpr::List(signature), body @ ..] if head.as_symbol() == Some("define") => {
+                (signature.first()?.as_symbol()?, Function, leading_declarations(body))
+            }
+            [head, LispExpr::Symbol(name), options @ ..] if head.as_symbol() == Some("defstruct") => {
+                (name.as_str(), Struct, options.iter().filter(|option| is_declaration(option)).collect())
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(Declared { name, kind, forms })
+}
+
+/// The `declare` forms a function body starts with
+fn leading_declarations(body: &[LispExpr]) -> Vec<&LispExpr> {
+    body.iter().take_while(|form| is_declaration(form)).collect()
+}
+
+/// Whether `form` is a `(declare ...)` form
+pub fn is_declaration(form: &LispExpr) -> bool {
+    matches!(form.as_list().and_then(|elements| elements.first()), Some(head) if head.as_symbol() == Some("declare"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser;
+
+    fn declarations(source: &str) -> Result<Declarations, String> {
+        let program = parser::parse(tokenize(source)?)?;
+        declared(&program[0]).ok_or("not a definition")?.declarations()
+    }
+
+    #[test]
+    fn test_declarations_on_each_definition() {
+        let function = declarations("(defun f (x) : number (declare (inline) (pure)) (declare (since \"1.2\") (requires (> x 0))) x)").unwrap();
+        assert!(function.inline && function.pure);
+        assert_eq!(function.since.as_deref(), Some("1.2"));
+        assert_eq!(function.capabilities, None);
+
+        let function = declarations("(define (g) (declare (capabilities process-spawn)) 1)").unwrap();
+        assert_eq!(function.capabilities, Some(vec![Capability::ProcessSpawn]));
+
+        let declared_macro = declarations("(defmacro old (x) (declare (deprecated \"use new\")) `(new ,x))").unwrap();
+        assert_eq!(declared_macro.deprecated, Some(Deprecation { note: Some("use new".to_string()) }));
+        let declared_struct = declarations("(defstruct point (x y) (declare (deprecated)) :derive (Clone))").unwrap();
+        assert_eq!(declared_struct.deprecated, Some(Deprecation { note: None }));
+        let exported = declarations("(defun reset! () (declare (export \"lisp_reset\")) 0)").unwrap();
+        assert_eq!(exported.export, Some(Export { symbol: Some("lisp_reset".to_string()) }));
+
+        assert!(declarations("(defun f (x) x (declare (pure)))").unwrap().is_empty());
+        assert!(declared(&LispExpr::Symbol("x".into())).is_none());
+    }
+
+    #[test]
+    fn test_declarations_are_schema_checked() {
+        let errors = [
+            ("(defun f () (declare (fast)) 1)", "Unknown declaration 'fast' (expected one of inline, pure,"),
+            ("(defun f () (declare (inline 2)) 1)", "'inline' takes no arguments: (inline 2)"),
+            ("(defun f () (declare (since 2)) 1)", "'since' takes one string version"),
+            ("(defun f () (declare (since \"1\") (since \"2\")) 1)", "'since' is declared more than once"),
+            ("(defun f () (declare (deprecated \"a\" \"b\")) 1)", "'deprecated' takes an optional string note"),
+            ("(defun f () (declare (ensures)) 1)", "'ensures' requires at least one condition"),
+            ("(defun f () (declare inline) 1)", "A declaration must be a list starting with its name: inline"),
+            ("(defmacro m () (declare (pure)) 1)", "'pure' cannot be declared on a macro"),
+            ("(defstruct p (x) (declare (capabilities)))", "'capabilities' cannot be declared on a struct"),
+            ("(defun f () (declare (capabilities (teleport))) 1)", "Unknown capability declaration: (teleport)"),
+            ("(defstruct p (x) (declare (export \"p\")))", "'export' takes an optional string symbol on a function, and nothing on a struct"),
+            ("(defmacro m () (declare (export)) 1)", "'export' cannot be declared on a macro"),
+        ];
+        for (source, expected) in errors {
+            let error = declarations(source).unwrap_err();
+            assert!(error.starts_with(expected) || error.contains(expected), "{}: {}", source, error);
+        }
+    }
+}
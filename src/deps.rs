@@ -0,0 +1,328 @@
+//! Dependency graph between a program's top-level definitions.
+//!
+//! A definition depends on every other definition whose name it mentions outside
+//! quoted data. Mutually recursive definitions form a strongly connected component
+//! and are always recompiled together; when a definition changes, its component and
+//! every component that depends on it, directly or transitively, are affected.
+
+use crate::ast::{grow_stack, walk_expr, LispExpr, Visitor};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefinitionKind {
+    Function,
+    Macro,
+    Constant,
+    Struct,
+}
+
+impl fmt::Display for DefinitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DefinitionKind::Function => write!(f, "function"),
+            DefinitionKind::Macro => write!(f, "macro"),
+            DefinitionKind::Constant => write!(f, "constant"),
+            DefinitionKind::Struct => write!(f, "struct"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Definition {
+    pub name: String,
+    pub kind: DefinitionKind,
+    /// Other definitions of the same program this one mentions, sorted
+    pub references: Vec<String>,
+}
+
+/// The name and kind of what a top-level `defun`, `define`, or `defmacro` form defines
+pub fn defined_name(expr: &LispExpr) -> Option<(&str, DefinitionKind)> {
+    match expr {
+        LispExpr::Macro { name, .. } => Some((name.as_str(), DefinitionKind::Macro)),
+        LispExpr::List(elements) => match &elements[..] {
+            [head, LispExpr::Symbol(name), ..] if head.as_symbol() == Some("defun") => Some((name.as_str(), DefinitionKind::Function)),
+            [head, LispExpr::Symbol(name), ..] if head.as_symbol() == Some("define") => Some((name.as_str(), DefinitionKind::Constant)),
+            [head, LispExpr::List(signature), ..] if head.as_symbol() == Some("define") => {
+                signature.first().and_then(LispExpr::as_symbol).map(|name| (name, DefinitionKind::Function))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Every symbol `expr` mentions outside quoted data, including macro call names
+pub fn referenced_symbols(expr: &LispExpr) -> HashSet<&str> {
+    let mut references = References(HashSet::new());
+    references.visit_expr(expr);
+    references.0
+}
+
+struct References<'a>(HashSet<&'a str>);
+
+impl<'a> Visitor<'a> for References<'a> {
+    fn visit_expr(&mut self, expr: &'a LispExpr) {
+        match expr {
+            LispExpr::Symbol(name) => {
+                self.0.insert(name.as_str());
+            }
+            // Quoted data is never evaluated
+            LispExpr::Quote(_) => {}
+            LispExpr::MacroCall { name, .. } => {
+                self.0.insert(name);
+                walk_expr(self, expr);
+            }
+            _ => walk_expr(self, expr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DependencyGraph {
+    definitions: Vec<Definition>,
+    #[serde(skip)]
+    index: HashMap<String, usize>,
+}
+
+impl DependencyGraph {
+    /// The graph of `program`'s top-level definitions. A name defined twice keeps its
+    /// last definition, as it would when evaluated.
+    pub fn new(program: &[LispExpr]) -> Self {
+        let defined: HashSet<&str> = program.iter().filter_map(defined_name).map(|(name, _)| name).collect();
+        let mut graph = DependencyGraph::default();
+        for expr in program {
+            let Some((name, kind)) = defined_name(expr) else {
+                continue;
+            };
+            let mut references: Vec<String> = referenced_symbols(expr)
+                .into_iter()
+                .filter(|symbol| *symbol != name && defined.contains(symbol))
+                .map(str::to_string)
+                .collect();
+            references.sort();
+            graph.insert(Definition { name: name.to_string(), kind, references });
+        }
+        graph
+    }
+
+    /// The graph of already-collected definitions, such as the calls a validator
+    /// follows; references to names not among them are dropped
+    pub fn from_definitions(definitions: Vec<Definition>) -> Self {
+        let defined: HashSet<String> = definitions.iter().map(|definition| definition.name.clone()).collect();
+        let mut graph = DependencyGraph::default();
+        for mut definition in definitions {
+            definition.references.retain(|reference| *reference != definition.name && defined.contains(reference));
+            definition.references.sort();
+            definition.references.dedup();
+            graph.insert(definition);
+        }
+        graph
+    }
+
+    /// Adds `definition`, replacing an earlier one of the same name in its place
+    fn insert(&mut self, definition: Definition) {
+        match self.index.get(&definition.name) {
+            Some(&existing) => self.definitions[existing] = definition,
+            None => {
+                self.index.insert(definition.name.clone(), self.definitions.len());
+                self.definitions.push(definition);
+            }
+        }
+    }
+
+    /// Definitions in the order they first appear
+    pub fn definitions(&self) -> &[Definition] {
+        &self.definitions
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Definition> {
+        self.index.get(name).map(|&index| &self.definitions[index])
+    }
+
+    /// Definitions that mention `name`, in source order
+    pub fn dependents(&self, name: &str) -> Vec<&str> {
+        self.definitions
+            .iter()
+            .filter(|definition| definition.references.iter().any(|reference| reference == name))
+            .map(|definition| definition.name.as_str())
+            .collect()
+    }
+
+    /// Strongly connected components, each in source order, listed so that every
+    /// component comes after the components it depends on
+    pub fn components(&self) -> Vec<Vec<String>> {
+        let mut tarjan = Tarjan {
+            graph: self,
+            next_index: 0,
+            indices: vec![None; self.definitions.len()],
+            lowlinks: vec![0; self.definitions.len()],
+            stack: Vec::new(),
+            on_stack: vec![false; self.definitions.len()],
+            components: Vec::new(),
+        };
+        for node in 0..self.definitions.len() {
+            if tarjan.indices[node].is_none() {
+                tarjan.connect(node);
+            }
+        }
+        tarjan
+            .components
+            .into_iter()
+            .map(|mut component| {
+                component.sort();
+                component.into_iter().map(|node| self.definitions[node].name.clone()).collect()
+            })
+            .collect()
+    }
+
+    /// The definitions to recompile when `changed` change: the changed ones and
+    /// every definition that depends on one, in source order
+    pub fn affected(&self, changed: &[&str]) -> Vec<String> {
+        let mut affected: HashSet<&str> = changed.iter().copied().collect();
+        let mut pending: Vec<&str> = changed.to_vec();
+        while let Some(name) = pending.pop() {
+            for dependent in self.dependents(name) {
+                if affected.insert(dependent) {
+                    pending.push(dependent);
+                }
+            }
+        }
+        self.definitions
+            .iter()
+            .filter(|definition| affected.contains(definition.name.as_str()))
+            .map(|definition| definition.name.clone())
+            .collect()
+    }
+
+    /// Each definition with what it uses, then the recompilation groups
+    pub fn render(&self, file_name: &str) -> String {
+        let mut output = format!("{}: {} definition(s)\n", file_name, self.definitions.len());
+        for definition in &self.definitions {
+            output.push_str(&format!("  {} ({})", definition.name, definition.kind));
+            if !definition.references.is_empty() {
+                output.push_str(&format!(" -> {}", definition.references.join(", ")));
+            }
+            output.push('\n');
+        }
+        if !self.definitions.is_empty() {
+            output.push_str("Recompilation groups, dependencies first:\n");
+            for component in self.components() {
+                output.push_str(&format!("  {}\n", component.join(", ")));
+            }
+        }
+        output
+    }
+
+    /// Graphviz DOT, with an edge from each definition to each one it uses
+    pub fn to_dot(&self) -> String {
+        let mut output = String::from("digraph dependencies {\n");
+        for definition in &self.definitions {
+            output.push_str(&format!("  {:?} [label={:?}];\n", definition.name, format!("{}\n{}", definition.name, definition.kind)));
+        }
+        for definition in &self.definitions {
+            for reference in &definition.references {
+                output.push_str(&format!("  {:?} -> {:?};\n", definition.name, reference));
+            }
+        }
+        output.push_str("}\n");
+        output
+    }
+}
+
+/// Tarjan's algorithm over definition indices
+struct Tarjan<'a> {
+    graph: &'a DependencyGraph,
+    next_index: usize,
+    indices: Vec<Option<usize>>,
+    lowlinks: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn connect(&mut self, node: usize) {
+        self.indices[node] = Some(self.next_index);
+        self.lowlinks[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        let graph = self.graph;
+        for reference in &graph.definitions[node].references {
+            let next = graph.index[reference];
+            match self.indices[next] {
+                None => {
+                    grow_stack(|| self.connect(next));
+                    self.lowlinks[node] = self.lowlinks[node].min(self.lowlinks[next]);
+                }
+                Some(index) if self.on_stack[next] => self.lowlinks[node] = self.lowlinks[node].min(index),
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.lowlinks[node]) == self.indices[node] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn graph(source: &str) -> DependencyGraph {
+        DependencyGraph::new(&parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_references_and_kinds() {
+        let graph = graph(
+            "(defmacro twice (x) `(+ ,x ,x))
+             (define limit 10)
+             (defun square (n) (* n n))
+             (define (area r) (twice (square r)))
+             (defun quoted () '(square limit))
+             (area limit)",
+        );
+        let area = graph.get("area").unwrap();
+        assert_eq!(area.kind, DefinitionKind::Function);
+        assert_eq!(area.references, vec!["square", "twice"]);
+        assert_eq!(graph.get("limit").unwrap().kind, DefinitionKind::Constant);
+        assert_eq!(graph.get("twice").unwrap().kind, DefinitionKind::Macro);
+        assert!(graph.get("quoted").unwrap().references.is_empty());
+        assert_eq!(graph.dependents("square"), vec!["area"]);
+    }
+
+    #[test]
+    fn test_components_and_affected() {
+        let graph = graph(
+            "(defun even? (n) (if (= n 0) true (odd? (- n 1))))
+             (defun odd? (n) (if (= n 0) false (even? (- n 1))))
+             (defun parity (n) (if (even? n) \"even\" \"odd\"))
+             (defun unrelated () 1)",
+        );
+        assert_eq!(graph.components(), vec![vec!["even?", "odd?"], vec!["parity"], vec!["unrelated"]]);
+        assert_eq!(graph.affected(&["odd?"]), vec!["even?", "odd?", "parity"]);
+        assert_eq!(graph.affected(&["parity"]), vec!["parity"]);
+        assert!(graph.affected(&["removed"]).is_empty());
+
+        let rendered = graph.render("parity.lisp");
+        assert!(rendered.starts_with("parity.lisp: 4 definition(s)\n  even? (function) -> odd?\n"));
+        assert!(rendered.ends_with("  even?, odd?\n  parity\n  unrelated\n"));
+        assert!(graph.to_dot().contains("  \"parity\" -> \"even?\";\n"));
+    }
+}
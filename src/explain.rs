@@ -0,0 +1,811 @@
+//! Stable codes for compiler errors, and the long-form explanations `explain <code>`
+//! prints.
+//!
+//! Codes are grouped by the stage that reports them: `L01xx` reading source,
+//! `L02xx` macro expansion, `L03xx` validation (one per `ValidationRule`), `L04xx`
+//! code generation, and `L05xx` transforms, includes, and compilation limits. A code
+//! never changes meaning once assigned. Typed errors know their own code
+//! (`MacroError::code`, `ValidationRule::code`, `TransformError::code`), and the
+//! other stages give theirs to the `CompileError` they fail with.
+//! Codes start with `L`, so they are not mistaken for rustc's `E` codes, which
+//! errors in the generated Rust keep.
+
+/// One error code and what it means
+#[derive(Debug)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    /// One-line summary, shown in the list of codes
+    pub title: &'static str,
+    /// What causes the error, an example, and how to fix it
+    pub explanation: &'static str,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}: {}", self.code, self.title)?;
+        writeln!(f)?;
+        write!(f, "{}", self.explanation.trim())
+    }
+}
+
+/// An error from reading, code generation, or the pipeline as a whole, with the code
+/// `explain` describes it under when it has one. The code is given where the error
+/// arises; errors only other stages' messages wrap, such as an observer's, have none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    code: Option<&'static str>,
+    message: String,
+}
+
+impl CompileError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        debug_assert!(lookup(code).is_some(), "unknown error code {}", code);
+        CompileError { code: Some(code), message: message.into() }
+    }
+
+    pub fn code(&self) -> Option<&'static str> {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The error with its message rewritten by `f`, keeping its code
+    pub fn map_message(self, f: impl FnOnce(String) -> String) -> Self {
+        CompileError { code: self.code, message: f(self.message) }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// An error without a code
+impl From<String> for CompileError {
+    fn from(message: String) -> Self {
+        CompileError { code: None, message }
+    }
+}
+
+impl From<&str> for CompileError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+/// The message alone, for callers that only show text
+impl From<CompileError> for String {
+    fn from(error: CompileError) -> Self {
+        error.message
+    }
+}
+
+/// Every code, in order
+pub const ERROR_CODES: &[ErrorCode] = &[
+    ErrorCode {
+        code: "L0101",
+        title: "Invalid number literal",
+        explanation: r#"
+A token that starts like a number could not be read as one.
+
+Example:
+    (+ -. 4)
+
+A token beginning with a digit, `-`, or `+` followed by more characters is read
+as a number. `-.` has a sign and a decimal point but no digits.
+
+Fix: write the number with its digits (`-0.5`), or, if a symbol was meant, start
+it with a letter.
+"#,
+    },
+    ErrorCode {
+        code: "L0102",
+        title: "Input over a lexer limit",
+        explanation: r#"
+The source has a token, a string, or a number of tokens over the lexer's limits.
+
+Example:
+    lisp-compiler --max-string-size 16 build input.lisp
+    (print "a string well over sixteen bytes")
+
+The limits protect the compiler from inputs crafted to exhaust its memory. The
+message gives the limit and where in the source it was passed.
+
+Fix: split the input, or raise the limit with `--max-token-length`,
+`--max-tokens`, or `--max-string-size` when the input is trusted.
+"#,
+    },
+    ErrorCode {
+        code: "L0103",
+        title: "Unbalanced parentheses",
+        explanation: r#"
+A list is missing its closing `)`, or a `)` has no list to close.
+
+Example:
+    (defun square (x)
+      (* x x)
+
+The definition opens two lists on its first line but closes only one of them.
+
+Fix: add the missing `)` at the end of the form, or remove the extra one. `fmt`
+indents each form by its nesting, which makes the mismatch easy to spot.
+"#,
+    },
+    ErrorCode {
+        code: "L0104",
+        title: "Malformed defmacro",
+        explanation: r#"
+A `defmacro` form does not have the shape `(defmacro NAME (PARAMS...) BODY)`.
+
+Example:
+    (defmacro (x) `(+ ,x 1))
+
+The name is missing, so the parameter list is read where the name should be.
+
+Fix: give the macro a symbol name, a parenthesized list of parameter symbols
+(`&rest` may precede the last one), and exactly one body expression.
+"#,
+    },
+    ErrorCode {
+        code: "L0105",
+        title: "Malformed quote",
+        explanation: r#"
+A quote, quasiquote, unquote, or unquote-splicing has no expression to apply to,
+or its long form has more than one.
+
+Example:
+    (list 1 ')
+    (quote a b)
+
+Fix: follow `'`, `` ` ``, `,`, or `,@` with exactly one expression, and give the
+long forms (`quote`, `quasiquote`, `unquote`, `unquote-splicing`) exactly one
+argument.
+"#,
+    },
+    ErrorCode {
+        code: "L0106",
+        title: "Malformed feature conditional",
+        explanation: r#"
+A `#+`/`#-` reader conditional or a `when-feature` form is missing its feature or
+the form it guards.
+
+Example:
+    (list 1 #+debug)
+
+Fix: write the feature, then the form to keep when it is enabled (`#+`) or
+disabled (`#-`): `#+debug (log-state)`. `when-feature` takes a feature and then
+the forms it guards.
+"#,
+    },
+    ErrorCode {
+        code: "L0201",
+        title: "Undefined macro",
+        explanation: r#"
+A macro was called that has not been defined.
+
+Example:
+    (unless (ready) (wait))
+
+Macros are expanded in order, so a macro must be defined with `defmacro` before
+the first form that calls it, in the same file, an included file, or a library
+loaded with `--lib`.
+
+Fix: move the `defmacro` above its first use, or load the library defining it.
+"#,
+    },
+    ErrorCode {
+        code: "L0202",
+        title: "Macro called with the wrong number of arguments",
+        explanation: r#"
+A macro call passes more or fewer arguments than the macro's parameter list takes.
+
+Example:
+    (defmacro twice (x) `(+ ,x ,x))
+    (twice 1 2)
+
+Fix: pass one argument per parameter. A macro that takes any number of arguments
+declares a `&rest` parameter last: `(defmacro sum (&rest xs) `(+ ,@xs))`.
+"#,
+    },
+    ErrorCode {
+        code: "L0203",
+        title: "Macro expansion too deep",
+        explanation: r#"
+Expanding a macro produced calls to macros nested deeper than the expansion depth
+limit, which usually means a macro expands to a call to itself forever.
+
+Example:
+    (defmacro forever (x) `(forever ,x))
+    (forever 1)
+
+Only calls that make no progress count toward the limit: a recursive call whose
+arguments are smaller than the enclosing call to the same macro, in one argument
+or in total, does not.
+
+Fix: make sure every recursive macro has a base case that expands to code without
+another call to itself, and that each recursive call shrinks an argument.
+"#,
+    },
+    ErrorCode {
+        code: "L0204",
+        title: "Time limit reached during macro expansion",
+        explanation: r#"
+The compilation deadline passed while macros were still being expanded.
+
+Example:
+    lisp-compiler build --timeout 1s generated.lisp
+
+The command line reports this as a compilation over its time limit (L0506); the
+code is for library users calling `MacroExpander::with_deadline`.
+
+Fix: raise the time limit, or reduce how much code the macros generate.
+"#,
+    },
+    ErrorCode {
+        code: "L0205",
+        title: "Allocation limit reached during macro expansion",
+        explanation: r#"
+The code macro expansion generated is estimated to be larger than the memory
+limit.
+
+Example:
+    lisp-compiler build --max-memory 1MB generated.lisp
+
+The command line reports this as a compilation over its memory limit (L0507); the
+code is for library users calling `MacroExpander::with_allocation_limit`.
+
+Fix: raise the memory limit, or reduce how much code the macros generate.
+"#,
+    },
+    ErrorCode {
+        code: "L0206",
+        title: "Sandbox violation during macro expansion",
+        explanation: r#"
+In a sandboxed build, expanding a macro broke the sandbox's limits: it used up
+the fuel, ran past the time limit, generated more nodes in one call than
+`--max-expansion-nodes` allows, or needed a capability that was not granted.
+
+Example:
+    lisp-compiler build --sandbox-mode --max-expansion-nodes 100 unroll.lisp
+
+Fix: raise `--max-fuel` or `--max-expansion-nodes`, grant the capability the
+message names, or change the macro to generate less code per call.
+"#,
+    },
+    ErrorCode {
+        code: "L0207",
+        title: "Macro expansion failed",
+        explanation: r#"
+A macro could not be expanded: for example, a splice (`,@`) produced something
+other than a list, or an argument did not have the shape of the list pattern
+that destructures it.
+
+Example:
+    (defmacro sum (xs) `(+ ,@xs))
+    (sum 5)
+
+The message and its context name the value that could not be used.
+
+Fix: splice only values that are lists, and pass list arguments where the
+macro's parameters destructure a list.
+"#,
+    },
+    ErrorCode {
+        code: "L0208",
+        title: "Malformed macro definition",
+        explanation: r#"
+A macro definition was read but cannot be used. The reason line says which part
+of it is wrong.
+
+Fix: check the definition against the shape `(defmacro NAME (PARAMS...) BODY)`.
+"#,
+    },
+    ErrorCode {
+        code: "L0209",
+        title: "Invalid macro parameter pattern",
+        explanation: r#"
+A macro parameter list is not a valid pattern: a parameter is not a symbol, or
+`&rest` is not followed by exactly one name at the end of its list.
+
+Example:
+    (defmacro m (x &rest) x)
+
+Fix: use symbols for parameters, `&rest name` last for the remaining arguments,
+and nested lists of those to destructure list arguments: `(a (b c) &rest r)`.
+"#,
+    },
+    ErrorCode {
+        code: "L0210",
+        title: "Macro not exported",
+        explanation: r#"
+A call named a macro of another module with `module:name`, but the module's
+`defmodule` form does not list the macro in its exports.
+
+Example:
+    (defmodule retry (export with-retry))
+    (defmacro backoff (n) `(* ,n 2))
+    ...
+    (retry:backoff 3)
+
+Fix: call one of the exported macros, or add the macro to the module's
+`(export ...)` list. `module::name` reaches a macro that is not exported.
+"#,
+    },
+    ErrorCode {
+        code: "L0301",
+        title: "Type mismatch (TypeSafety)",
+        explanation: r#"
+An operation is applied to values whose types it does not accept, as far as types
+can be inferred before the program runs.
+
+Example:
+    (+ "hello" 42)
+
+Fix: convert the operand first, for example with `string->number`. The finding's
+help line suggests a conversion when one is known, and `--apply-suggestions`
+applies it.
+"#,
+    },
+    ErrorCode {
+        code: "L0302",
+        title: "Unbounded recursion (ResourceBounds)",
+        explanation: r#"
+A function calls itself on every path, so it has no base case and never returns.
+
+Example:
+    (defun loop (n) (loop (+ n 1)))
+
+Fix: guard the recursive call with a condition that stops it:
+    (defun count-down (n) (if (= n 0) 0 (count-down (- n 1))))
+"#,
+    },
+    ErrorCode {
+        code: "L0303",
+        title: "Unsafe Rust or FFI not allowed (FFIRestrictions)",
+        explanation: r#"
+The program calls a `rust-unsafe` or `ffi-` form, which is rejected unless the
+embedder allows that function.
+
+Example:
+    (rust-unsafe "ptr.read()")
+
+Fix: do the work with safe forms. Library users who trust the code allow a
+function with `FFIRestrictionsValidator::allow_function`.
+"#,
+    },
+    ErrorCode {
+        code: "L0304",
+        title: "Complexity limit exceeded (ComplexityLimits)",
+        explanation: r#"
+An expression or function is nested too deeply, has too many forms or arguments,
+or has a cyclomatic complexity over the configured limit.
+
+Example:
+    (defun classify (x) (cond ((= x 1) 'a) ((= x 2) 'b) ... ))
+
+`check --stats` shows each function's complexity.
+
+Fix: split the function into smaller ones, or replace long conditionals with a
+lookup.
+"#,
+    },
+    ErrorCode {
+        code: "L0305",
+        title: "Untrusted data reaches a sensitive form (TaintTracking)",
+        explanation: r#"
+Data from an untrusted source, such as input or the network, flows into FFI,
+unsafe code, or process execution without being sanitized.
+
+Example:
+    (exec (read-line))
+
+Fix: pass only values the program builds itself, for example by choosing from a
+fixed set of commands based on the input instead of passing the input through.
+"#,
+    },
+    ErrorCode {
+        code: "L0306",
+        title: "Capability used outside its declaration (CapabilityScope)",
+        explanation: r#"
+A function that declares its capabilities uses one it did not declare.
+
+Example:
+    (defun fetch (url)
+      (declare (capabilities NetworkHTTP))
+      (read-file url))
+
+Fix: add the capability to the function's `(declare (capabilities ...))`, or move
+the operation into a function that declares it.
+"#,
+    },
+    ErrorCode {
+        code: "L0307",
+        title: "Allocation over the sandbox memory limit (MemoryBounds)",
+        explanation: r#"
+Data whose size is known before the program runs, such as a literal or a list
+built to a constant length, cannot fit in the sandbox memory limit.
+
+Example:
+    lisp-compiler build --sandbox-mode --max-memory 1KB big-table.lisp
+
+Fix: raise `--max-memory`, or build the data incrementally so less of it is held
+at once.
+"#,
+    },
+    ErrorCode {
+        code: "L0308",
+        title: "Contract error (Contracts)",
+        explanation: r#"
+A `(declare (requires ...) (ensures ...))` contract refers to something other than
+its function's parameters (and `result` in `ensures`), or a call with literal
+arguments is known to break it.
+
+Example:
+    (defun sqrt* (x) (declare (requires (>= x 0))) (sqrt x))
+    (sqrt* -4)
+
+Fix: pass arguments that satisfy the precondition, or correct the contract.
+"#,
+    },
+    ErrorCode {
+        code: "L0309",
+        title: "Call does not match the parameter list (Arity)",
+        explanation: r#"
+A call passes too many or too few arguments, or a keyword the function's
+parameter list does not accept.
+
+Example:
+    (defun area (w h) (* w h))
+    (area 3)
+
+Fix: pass one argument per required parameter; optional, rest, and keyword
+parameters are declared with `&optional`, `&rest`, and `&key`.
+"#,
+    },
+    ErrorCode {
+        code: "L0310",
+        title: "Invalid crate dependency (Crates)",
+        explanation: r#"
+A `require-crate` form is malformed, not at the top level, conflicts with another
+requirement of the same crate, or names a crate the sandbox does not allow.
+
+Example:
+    (require-crate "serde" "1.0")
+    (require-crate "serde" "0.9")
+
+Fix: require each crate once, at the top level, with a valid version.
+"#,
+    },
+    ErrorCode {
+        code: "L0311",
+        title: "Invalid declaration (Declarations)",
+        explanation: r#"
+A `(declare ...)` clause is not in the declaration schema, has arguments of the
+wrong shape, or a function declared `pure` has side effects.
+
+Example:
+    (defun greet (name) (declare pure) (print name))
+
+Fix: remove `pure` from functions with side effects, and use only the clauses
+the schema lists, with their documented arguments.
+"#,
+    },
+    ErrorCode {
+        code: "L0312",
+        title: "Use of a deprecated definition (Deprecated)",
+        explanation: r#"
+A function, macro, or struct declared `(deprecated ...)` is used. This is the
+`deprecated` lint: a warning by default, an error with `--deny deprecated`.
+
+Example:
+    (defun old-area (r) (declare (deprecated "use area")) (area r))
+    (old-area 2)
+
+Fix: use the replacement the deprecation names, or `--allow deprecated` to
+silence the lint.
+"#,
+    },
+    ErrorCode {
+        code: "L0313",
+        title: "Definition or binding shadows a built-in (ShadowedBuiltin)",
+        explanation: r#"
+A macro, function, parameter, or `let` variable is named after a built-in form
+or function such as `if`, `let`, or `+`. Macro hygiene and code generation
+treat those names as the built-ins, so the definition is silently ignored or
+a template's use of the built-in turns into the user's binding. This is the
+`shadowed-builtins` lint: a warning by default, an error with
+`--deny shadowed-builtins`.
+
+Example:
+    (defmacro if (c a b) `(cond (,c ,a) (t ,b)))
+    (let ((list (range 3))) (length list))
+
+Fix: rename the definition or variable, e.g. `my-if` or `items`, or
+`--allow shadowed-builtins` to silence the lint.
+"#,
+    },
+    ErrorCode {
+        code: "L0401",
+        title: "Unknown function",
+        explanation: r#"
+A call names a function that is neither built in nor defined with `defun`.
+
+Example:
+    (prnt "hello")
+
+Fix: correct the name, or define the function with `defun` before generating
+code for the call.
+"#,
+    },
+    ErrorCode {
+        code: "L0402",
+        title: "Wrong number of arguments to a form",
+        explanation: r#"
+A built-in form or function is called with a number of arguments it cannot take.
+
+Example:
+    (if (> x 0) "positive")
+
+`if` needs a condition, a then-branch, and an else-branch.
+
+Fix: pass the arguments the message lists.
+"#,
+    },
+    ErrorCode {
+        code: "L0403",
+        title: "Not supported in code generation",
+        explanation: r#"
+The program uses a form the backend cannot generate code for yet, such as a
+macro call that was never expanded, or a quote in a position that needs a value.
+
+Example:
+    (defun greet (name &optional title) ...)
+
+Fix: rewrite the form with a supported one; the message suggests an alternative
+when there is one. The interpreter (`debug`, `repl`) supports more forms than
+code generation.
+"#,
+    },
+    ErrorCode {
+        code: "L0404",
+        title: "Form in the wrong position",
+        explanation: r#"
+A form appears where it cannot be compiled: definitions (`defun`, `defstruct`,
+`deftrait`, `defimpl`, `require-crate`) only at the top level, and a named let's
+loop call only in tail position.
+
+Example:
+    (let ((x 1)) (defun f () x))
+
+Fix: move the definition to the top level and pass what it needs as arguments.
+"#,
+    },
+    ErrorCode {
+        code: "L0405",
+        title: "Duplicate definition",
+        explanation: r#"
+Two definitions have the same name, or names that become the same identifier in
+the generated Rust.
+
+Example:
+    (defun my-fn () 1)
+    (defun my_fn () 2)
+
+Fix: rename one of the definitions.
+"#,
+    },
+    ErrorCode {
+        code: "L0406",
+        title: "Malformed special form",
+        explanation: r#"
+A special form such as `let`, `defun`, or a call does not have the parts it needs
+in the places it needs them.
+
+Example:
+    (let (x 1) x)
+
+Each `let` binding is itself a list: `(let ((x 1)) x)`.
+
+Fix: compare the form with its documented shape; the message names the part that
+is wrong.
+"#,
+    },
+    ErrorCode {
+        code: "L0407",
+        title: "Sandbox violation during code generation",
+        explanation: r#"
+A sandboxed build uses an operation none of its capabilities grant, such as
+reading the clock, spawning a process, or starting a thread. The operation is
+rejected when the code is generated rather than when the program runs.
+
+Example:
+    lisp-compiler --sandbox-mode timer.lisp
+    (now)
+
+The default strict sandbox has no `SystemTime` capability.
+
+Fix: grant the capability (`--allow-capability=SystemTime`), or remove the
+operation from the program.
+"#,
+    },
+    ErrorCode {
+        code: "L0501",
+        title: "Transform failed",
+        explanation: r#"
+A transform given with `--transforms` reported an error while rewriting the
+program.
+
+Fix: the rest of the message comes from the transform; check its documentation
+for the input it expects.
+"#,
+    },
+    ErrorCode {
+        code: "L0502",
+        title: "Transform met an invalid AST",
+        explanation: r#"
+A transform found a form it cannot process, usually one produced by an earlier
+transform.
+
+Fix: run the transforms one at a time to find which produces the form, and check
+the order given to `--transforms`.
+"#,
+    },
+    ErrorCode {
+        code: "L0503",
+        title: "Unknown transform",
+        explanation: r#"
+`--transforms` names a transform that is not registered.
+
+Example:
+    lisp-compiler build --transforms ech example.lisp
+
+Fix: use one of the names `build --help` lists for `--transforms`.
+"#,
+    },
+    ErrorCode {
+        code: "L0504",
+        title: "Cannot include a file",
+        explanation: r#"
+An `(include "path")` could not be read: the file was not found on the search
+path, could not be opened, or a sandboxed build has no read access to it.
+
+Example:
+    (include "lib/math.lisp")
+
+Fix: check the path, which is tried next to the including file and then in each
+`--include-path` and `RUSTY_LISP_PATH` directory. In a sandboxed build, grant
+read access with `--allow-capability FileRead:lib`.
+"#,
+    },
+    ErrorCode {
+        code: "L0505",
+        title: "Include cycle",
+        explanation: r#"
+A file includes itself, directly or through other files. The message lists the
+chain of includes.
+
+Fix: move the shared definitions into a file that both include, so neither
+includes the other.
+"#,
+    },
+    ErrorCode {
+        code: "L0506",
+        title: "Compilation over its time limit",
+        explanation: r#"
+Compiling took longer than `--timeout`. The message names the stage that was
+running.
+
+Example:
+    lisp-compiler build --timeout 100ms generated.lisp
+
+Fix: raise `--timeout`, or reduce the program or the code its macros generate.
+"#,
+    },
+    ErrorCode {
+        code: "L0507",
+        title: "Compilation over its memory limit",
+        explanation: r#"
+The program, or the code its macros generate, is estimated to need more memory
+than `--max-memory` allows while it compiles.
+
+Example:
+    lisp-compiler build --max-memory 1MB generated.lisp
+
+Fix: raise `--max-memory`, or reduce the program or the code its macros generate.
+"#,
+    },
+];
+
+/// The code named `code`, ignoring case: `L0201` or `l0201`
+pub fn lookup(code: &str) -> Option<&'static ErrorCode> {
+    ERROR_CODES.iter().find(|known| known.code.eq_ignore_ascii_case(code.trim()))
+}
+
+/// Whether `code` has the form of a rustc error code, such as `E0369`, which
+/// `rustc --explain` describes rather than `lookup`
+pub fn is_rustc_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == 5 && code.starts_with(['E', 'e']) && code[1..].bytes().all(|byte| byte.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macro_expander::MacroError;
+    use crate::pipeline::{check_validation_results, Pipeline};
+    use crate::sandbox::{SandboxConfig, SandboxViolation};
+    use crate::transform::TransformError;
+    use crate::validator::{Severity, ValidationError, ValidationRule};
+
+    #[test]
+    fn test_codes_are_unique_and_ordered() {
+        for pair in ERROR_CODES.windows(2) {
+            assert!(pair[0].code < pair[1].code, "{} before {}", pair[0].code, pair[1].code);
+        }
+        assert_eq!(lookup("l0201").unwrap().title, "Undefined macro");
+        assert!(lookup("L9999").is_none());
+        assert!(lookup("E0201").is_none());
+        assert!(ERROR_CODES.iter().all(|known| !is_rustc_code(known.code)));
+        assert!(is_rustc_code("E0369") && is_rustc_code("e0308"));
+        assert!(!is_rustc_code("E03") && !is_rustc_code("Eabcd"));
+        assert!(lookup("L0201").unwrap().to_string().starts_with("L0201: Undefined macro\n\nA macro was called"));
+    }
+
+    #[test]
+    fn test_typed_errors_carry_their_code() {
+        let macro_errors = [
+            MacroError::UndefinedMacro("m".into()),
+            MacroError::ParameterCountMismatch { macro_name: "m".into(), expected: 1, actual: 2 },
+            MacroError::MaxDepthExceeded { depth: 100, macro_name: "m".into() },
+            MacroError::DeadlineExceeded { macro_name: "m".into() },
+            MacroError::AllocationLimitExceeded { macro_name: "m".into(), allocated: 10 },
+            MacroError::SandboxViolation { macro_name: "m".into(), violation: SandboxViolation::FuelExhausted { limit: 1 } },
+            MacroError::ExpansionError { message: "Unclosed list".into(), context: None },
+            MacroError::MalformedDefinition { macro_name: "m".into(), reason: "r".into() },
+            MacroError::InvalidPattern { pattern: "1".into(), reason: "r".into() },
+            MacroError::NotExported { module: "m".into(), macro_name: "n".into() },
+        ];
+        for error in macro_errors {
+            let code = error.code();
+            let error = CompileError::from(error);
+            assert_eq!(error.code(), Some(code), "{}", error);
+            assert!(error.message().starts_with("Macro expansion error: "));
+        }
+
+        for error in [TransformError::TransformFailed("x".into()), TransformError::InvalidAst("x".into()), TransformError::PluginNotFound("x".into())] {
+            let code = error.code();
+            assert_eq!(CompileError::from(error).code(), Some(code));
+        }
+
+        for rule in ValidationRule::ALL {
+            let error = ValidationError { rule, severity: Severity::Error, message: "m".into(), context: None, suggestion: None, fix: None };
+            assert_eq!(check_validation_results(vec![Err(vec![error])]).unwrap_err().code(), Some(rule.code()));
+            assert!(lookup(rule.code()).unwrap().title.ends_with(&format!("({:?})", rule)));
+        }
+
+        // Errors from plain messages have no code
+        assert_eq!(CompileError::from("something else entirely").code(), None);
+    }
+
+    #[test]
+    fn test_pipeline_error_codes() {
+        let code = |source: &str| Pipeline::new().with_validation(true).compile(source).unwrap_err().code();
+        assert_eq!(code("(+ 1 2"), Some("L0103"));
+        assert_eq!(code("(+ -. 4)"), Some("L0101"));
+        assert_eq!(code("(undefined-fn 1)"), Some("L0401"));
+        assert_eq!(code("(if 1 2)"), Some("L0402"));
+        assert_eq!(code("(defmacro twice (x) `(+ ,x ,x))\n(twice 1 2)"), Some("L0202"));
+        assert_eq!(code("(defun f (n) (f n))"), Some("L0302"));
+
+        // Sandbox violations found while generating code have theirs too
+        let config = SandboxConfig::new();
+        let error = Pipeline::new().with_sandbox(&config).compile("(now)").unwrap_err();
+        assert_eq!(error.code(), Some("L0407"), "{}", error);
+        assert!(error.message().starts_with("Sandbox violation: "), "{}", error);
+    }
+}
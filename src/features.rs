@@ -0,0 +1,107 @@
+//! Conditional compilation.
+//!
+//! The parser keeps or drops forms according to the enabled features, so the same
+//! source can target different configurations, such as sandboxed and unrestricted
+//! builds. Dropped forms are never validated, expanded, or compiled.
+//!
+//! - `#+feature form` keeps `form` only when the feature is enabled, and
+//!   `#-feature form` only when it is not
+//! - `(when-feature feature form...)` and `(unless-feature feature form...)` do the
+//!   same for several forms, which take the place of the conditional in the
+//!   enclosing list or at the top level
+//!
+//! A feature is a name, or `(and ...)`, `(or ...)`, or `(not ...)` of features, as in
+//! `#+(or sandbox wasm) form`. The parser tests them against the `Features` its
+//! `Reader` was given (`--features` on the command line).
+
+use crate::ast::LispExpr;
+use std::collections::BTreeSet;
+
+/// Heads of the conditional forms
+pub const WHEN_FEATURE: &str = "when-feature";
+pub const UNLESS_FEATURE: &str = "unless-feature";
+
+/// The features enabled for reading a program; none by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features(BTreeSet<String>);
+
+impl Features {
+    pub fn new<S: Into<String>>(features: impl IntoIterator<Item = S>) -> Self {
+        Features(features.into_iter().map(Into::into).collect())
+    }
+
+    /// These features and `feature`
+    pub fn with(mut self, feature: impl Into<String>) -> Self {
+        self.0.insert(feature.into());
+        self
+    }
+
+    pub fn enabled(&self) -> Vec<String> {
+        self.0.iter().cloned().collect()
+    }
+
+    pub fn is_enabled(&self, feature: &str) -> bool {
+        self.0.contains(feature)
+    }
+
+    /// Whether a feature expression holds for these features
+    pub fn holds(&self, expr: &LispExpr) -> Result<bool, String> {
+        let elements = match expr {
+            LispExpr::Symbol(name) => return Ok(self.is_enabled(name)),
+            LispExpr::List(elements) => elements,
+            _ => return Err(format!("A feature must be a name or an and/or/not of features: {}", expr)),
+        };
+        match elements.split_first().map(|(head, args)| (head.as_symbol(), args)) {
+            Some((Some("and"), args)) => args.iter().try_fold(true, |all, arg| Ok(all && self.holds(arg)?)),
+            Some((Some("or"), args)) => args.iter().try_fold(false, |any, arg| Ok(any || self.holds(arg)?)),
+            Some((Some("not"), [arg])) => Ok(!self.holds(arg)?),
+            _ => Err(format!("A feature must be a name or an and/or/not of features: {}", expr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, Reader};
+
+    fn parse(source: &str) -> Result<String, String> {
+        read(&Features::new(["sandbox"]), source)
+    }
+
+    fn read(features: &Features, source: &str) -> Result<String, String> {
+        let program = Reader::new().with_features(features.clone()).read(source)?;
+        Ok(program.iter().map(|expr| expr.to_string()).collect::<Vec<_>>().join(" "))
+    }
+
+    #[test]
+    fn test_feature_conditionals() {
+        assert_eq!(Features::new(["sandbox"]).enabled(), vec!["sandbox"]);
+        assert_eq!(parse("#+sandbox (a) #-sandbox (b) #+wasm (c) #-wasm (d)").unwrap(), "(a) (d)");
+        assert_eq!(parse("(list 1 #+wasm 2 #+(or wasm sandbox) 3 #+(and wasm sandbox) 4 #+(not wasm) 5)").unwrap(), "(list 1 3 5)");
+        assert_eq!(parse("(when-feature sandbox (a) (b)) (unless-feature sandbox (c)) (f (when-feature sandbox 1 2))").unwrap(), "(a) (b) (f 1 2)");
+        assert_eq!(parse("#+wasm #+sandbox (a) #+sandbox #-wasm (b)").unwrap(), "(b)");
+        // Quoted data is read the same way, while disabled code still has to be well-formed
+        assert_eq!(parse("'(#+wasm a b)").unwrap(), "'(b)");
+        assert_eq!(parse("#+wasm (a").unwrap_err(), "Unclosed list - missing ')'");
+
+        assert_eq!(parse("(list #+sandbox)").unwrap_err(), "Expected a form after '#+sandbox'");
+        assert_eq!(parse("(when-feature)").unwrap_err(), "'when-feature' requires a feature");
+        assert_eq!(parse("#+(xor a b) 1").unwrap_err(), "A feature must be a name or an and/or/not of features: (xor a b)");
+
+        let source = "(defun f () 1)\n#+wasm (defun g () 2)\n(when-feature sandbox\n  (f))";
+        let forms = Reader::new().with_features(Features::new(["sandbox"])).read_with_span_trees(source).unwrap();
+        let spans: Vec<&str> = forms.iter().map(|(_, tree)| tree.span.unwrap()).map(|span| &source[span.start..span.end]).collect();
+        assert_eq!(spans, vec!["(defun f () 1)", "(f)"]);
+        let (commented, _) = parser::parse_with_comments(source).unwrap();
+        assert_eq!(commented.len(), 4, "the formatter sees conditionals as written");
+    }
+
+    #[test]
+    fn test_readers_keep_their_own_features() {
+        let source = "#+sandbox (a) #-sandbox (b)";
+        assert_eq!(read(&Features::default(), source).unwrap(), "(b)");
+        assert_eq!(read(&Features::default().with("sandbox"), source).unwrap(), "(a)");
+        assert_eq!(parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap().len(), 1);
+    }
+}
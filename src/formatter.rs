@@ -0,0 +1,227 @@
+use crate::ast::{distinguished_args, grow_stack, Comment, CommentTree, LispExpr};
+use crate::parser::{parse_with_comments, CommentedForm};
+use crate::symbol;
+
+/// Reformat Lisp source: every top-level form is pretty-printed by
+/// `LispExpr::to_source`, comments are kept, and runs of blank lines between forms
+/// shrink to one. Comments inside a form break its lines where they need to.
+///
+/// Fails if the source does not parse, or if the result would not parse back
+/// to the same program with the same comments.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let (forms, detached) = parse_with_comments(source)?;
+
+    let mut items = Vec::new();
+    let comment_item = |comment: &Comment| (comment.span.start, comment.span.end, comment.text.clone(), false);
+    items.extend(detached.iter().map(comment_item));
+    for CommentedForm { expr, spans, comments } in &forms {
+        let span = spans.span.ok_or("top-level form without a source span")?;
+        let mut text = String::new();
+        write_commented(expr, comments, &mut text, 0);
+        items.extend(comments.leading.iter().map(comment_item));
+        items.push((span.start, span.end, text, true));
+        items.extend(comments.trailing.iter().map(comment_item));
+    }
+    items.sort_by_key(|(start, ..)| *start);
+
+    let mut output = String::new();
+    let mut previous: Option<(usize, bool)> = None;
+    for (start, end, text, is_form) in items {
+        if let Some((previous_end, previous_is_form)) = previous {
+            let newlines = source[previous_end..start].matches('\n').count();
+            output.push_str(match newlines {
+                // A comment trailing a form stays on its line
+                0 if previous_is_form && !is_form => " ",
+                0 | 1 => "\n",
+                _ => "\n\n",
+            });
+        }
+        output.push_str(&text);
+        previous = Some((end, is_form));
+    }
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    let comment_count = |forms: &[CommentedForm], detached: &[Comment]| {
+        fn count(tree: &CommentTree) -> usize {
+            tree.leading.len() + usize::from(tree.trailing.is_some()) + tree.inner.len()
+                + grow_stack(|| tree.children.iter().map(count).sum::<usize>())
+        }
+        detached.len() + forms.iter().map(|form| count(&form.comments)).sum::<usize>()
+    };
+    let unchanged = parse_with_comments(&output).is_ok_and(|(reparsed, reparsed_detached)| {
+        reparsed.len() == forms.len()
+            && reparsed.iter().zip(&forms).all(|(a, b)| a.expr == b.expr)
+            && comment_count(&reparsed, &reparsed_detached) == comment_count(&forms, &detached)
+    });
+    if !unchanged {
+        return Err("formatting would change the program; please report this as a bug".to_string());
+    }
+    Ok(output)
+}
+
+/// `LispExpr::write_source`, except that a form with comments inside is broken one
+/// element per line, with each comment kept next to the element it is attached to
+fn write_commented(expr: &LispExpr, comments: &CommentTree, out: &mut String, closing: usize) {
+    grow_stack(|| write_commented_unstacked(expr, comments, out, closing))
+}
+
+fn write_commented_unstacked(expr: &LispExpr, comments: &CommentTree, out: &mut String, closing: usize) {
+    if !comments.has_inner_comments() {
+        expr.write_source(out, closing);
+        return;
+    }
+    let column = out.len() - out.rfind('\n').map_or(0, |i| i + 1);
+    fn elements<'a>(exprs: &'a [LispExpr], comments: &'a CommentTree) -> Vec<(&'a LispExpr, &'a CommentTree)> {
+        exprs.iter().zip(&comments.children).collect()
+    }
+
+    match expr {
+        LispExpr::Quote(inner) => write_prefixed(out, "'", inner, comments, closing),
+        LispExpr::Quasiquote(inner) => write_prefixed(out, "`", inner, comments, closing),
+        LispExpr::Unquote(inner) => write_prefixed(out, ",", inner, comments, closing),
+        LispExpr::Splice(inner) => write_prefixed(out, ",@", inner, comments, closing),
+        LispExpr::Macro { name, parameters, body, declarations } => {
+            out.push_str(&format!("(defmacro {} ({})", name, symbol::join(parameters, " ")));
+            // Comments are only tracked for the body
+            let uncommented = CommentTree::default();
+            let mut forms: Vec<(&LispExpr, &CommentTree)> = declarations.iter().map(|declaration| (declaration, &uncommented)).collect();
+            forms.push((body.as_ref(), &comments.children[0]));
+            write_elements(out, &forms, column + 2, 0, comments, closing);
+        }
+        LispExpr::MacroCall { name, args } => write_call(out, column, name, &elements(args, comments), comments, closing),
+        LispExpr::List(items) => match items.split_first() {
+            // A comment after the head moves the arguments to their own lines anyway
+            Some((LispExpr::Symbol(head), _)) if comments.children[0].trailing.is_none() => {
+                write_call(out, column, head, &elements(items, comments)[1..], comments, closing);
+            }
+            _ => {
+                out.push('(');
+                write_elements(out, &elements(items, comments), column + 1, 1, comments, closing);
+            }
+        },
+        _ => expr.write_source(out, closing),
+    }
+}
+
+fn write_prefixed(out: &mut String, prefix: &str, inner: &LispExpr, comments: &CommentTree, closing: usize) {
+    out.push_str(prefix);
+    write_commented(inner, &comments.children[0], out, closing);
+}
+
+/// `(head a` with the rest aligned under `a`, or body-style for defining forms
+fn write_call(
+    out: &mut String,
+    column: usize,
+    head: &str,
+    args: &[(&LispExpr, &CommentTree)],
+    comments: &CommentTree,
+    closing: usize,
+) {
+    out.push('(');
+    out.push_str(head);
+    out.push(' ');
+    match distinguished_args(head) {
+        Some(count) => write_elements(out, args, column + 2, count, comments, closing),
+        None => write_elements(out, args, column + head.len() + 2, 1, comments, closing),
+    }
+}
+
+/// Writes the first `on_first_line` elements after the opening already in `out`,
+/// then one per line at `indent`, then the closing paren
+fn write_elements(
+    out: &mut String,
+    elements: &[(&LispExpr, &CommentTree)],
+    indent: usize,
+    on_first_line: usize,
+    comments: &CommentTree,
+    closing: usize,
+) {
+    let newline = |out: &mut String| {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    };
+    // Whether the line so far ends in a comment
+    let mut commented = false;
+    for (i, (expr, tree)) in elements.iter().enumerate() {
+        let first_line = i < on_first_line && !commented && tree.leading.is_empty();
+        if !first_line {
+            if out.ends_with(' ') {
+                out.pop();
+            }
+            for comment in &tree.leading {
+                newline(out);
+                out.push_str(&comment.text);
+            }
+            newline(out);
+        } else if i > 0 {
+            out.push(' ');
+        }
+        let last = i + 1 == elements.len() && tree.trailing.is_none() && comments.inner.is_empty();
+        write_commented(expr, tree, out, if last { closing + 1 } else { 0 });
+        commented = tree.trailing.is_some();
+        if let Some(comment) = &tree.trailing {
+            out.push(' ');
+            out.push_str(&comment.text);
+        }
+    }
+    if out.ends_with(' ') {
+        out.pop();
+    }
+    for comment in &comments.inner {
+        newline(out);
+        out.push_str(&comment.text);
+        commented = true;
+    }
+    if commented {
+        newline(out);
+    }
+    out.push(')');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_source() {
+        let source = "; Doubling\n\n\n(defun double (x)   (* x 2))  ; twice\n(print (quote done))(double\n  21)\n";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "; Doubling\n\n(defun double (x) (* x 2)) ; twice\n(print 'done)\n(double 21)\n"
+        );
+
+        let long = format!("(defun f (x) (list {}))", "x ".repeat(40).trim_end());
+        let formatted = format_source(&long).unwrap();
+        assert!(formatted.starts_with("(defun f (x)\n  (list x\n        x\n"));
+        assert!(formatted.lines().all(|line| line.len() <= crate::ast::SOURCE_WIDTH));
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_formats_forms_with_inner_comments() {
+        let source = "(list 1   ; one\n      2)\n(list  3)";
+        assert_eq!(format_source(source).unwrap(), "(list 1 ; one\n      2)\n(list 3)\n");
+
+        let source = "(defun area (w h)\n   ;; width times height\n   (* w h)  ; square units\n  )";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(formatted, "(defun area (w h)\n  ;; width times height\n  (* w h) ; square units\n  )\n");
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+
+        let source = "(if ; test first\n (> x 0) 'pos\n ; otherwise\n 'neg)";
+        assert_eq!(format_source(source).unwrap(), "(if ; test first\n (> x 0)\n 'pos\n ; otherwise\n 'neg)\n");
+
+        assert_eq!(format_source("").unwrap(), "");
+        assert!(format_source("(list 1").is_err());
+    }
+
+    #[test]
+    fn test_formats_deeply_nested_source() {
+        let depth = 20_000;
+        let source = format!("{}1{} ; done", "(list ".repeat(depth), ")".repeat(depth));
+        let formatted = format_source(&source).unwrap();
+        assert!(formatted.starts_with("(list (list "));
+        assert!(formatted.ends_with(") ; done\n"));
+    }
+}
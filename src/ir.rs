@@ -0,0 +1,808 @@
+use crate::ast::{grow_stack, LispExpr, SpanTree};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::io::{BufRead, Write};
+
+/// Version of the IR envelope this build writes. The major version changes when
+/// older readers can no longer load the IR; the minor version when `LispExpr`
+/// gains variants, which older readers load only if the program doesn't use them,
+/// or the envelope gains optional fields, which older readers ignore.
+/// 1.1 added `spans`; 1.2 added `declarations` to `Macro`; 1.3 added `strings`.
+pub const IR_VERSION: &str = "1.3";
+
+/// CBOR self-describe tag (55799), written before CBOR IR so it can be detected
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Encoding of the IR on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrFormat {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl IrFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "json" => Ok(IrFormat::Json),
+            "cbor" => Ok(IrFormat::Cbor),
+            "msgpack" => Ok(IrFormat::MessagePack),
+            other => Err(format!("Unknown IR format '{}' (expected json, cbor, or msgpack)", other)),
+        }
+    }
+
+    /// File extension for IR in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            IrFormat::Json => "json",
+            IrFormat::Cbor => "cbor",
+            IrFormat::MessagePack => "msgpack",
+        }
+    }
+
+    /// Guess the format from the leading bytes: the CBOR self-describe tag or a
+    /// CBOR map, a MessagePack map or array, and JSON otherwise
+    pub fn detect(bytes: &[u8]) -> Self {
+        match bytes {
+            [0xd9, 0xd9, 0xf7, ..] | [0xa0..=0xbf, ..] => IrFormat::Cbor,
+            [0x80..=0x9f | 0xdc..=0xdf, ..] => IrFormat::MessagePack,
+            _ => IrFormat::Json,
+        }
+    }
+}
+
+/// Versioned IR: `{ "version": "1.0", "exprs": [...] }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IrDocument {
+    pub version: String,
+    pub exprs: Vec<LispExpr>,
+    /// Source spans and macro provenance of every node, one tree per expression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spans: Option<Vec<SpanTree>>,
+    /// Encode with a string table; see `intern_strings`. Set when a document read
+    /// directly has one, whose indices only `from_value` resolves.
+    #[serde(rename(deserialize = "strings"), skip_serializing, default, deserialize_with = "is_present")]
+    pub interned: bool,
+}
+
+fn is_present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    serde::de::IgnoredAny::deserialize(deserializer).map(|_| true)
+}
+
+impl IrDocument {
+    pub fn new(exprs: Vec<LispExpr>) -> Self {
+        IrDocument {
+            version: IR_VERSION.to_string(),
+            exprs,
+            spans: None,
+            interned: false,
+        }
+    }
+
+    pub fn with_spans(mut self, spans: Vec<SpanTree>) -> Self {
+        self.spans = Some(spans);
+        self
+    }
+
+    /// Write each symbol and gensym name once, in a `strings` table
+    pub fn with_interned_strings(mut self) -> Self {
+        self.interned = true;
+        self
+    }
+}
+
+/// Serialize a program as a versioned IR document
+pub fn to_json(exprs: &[LispExpr]) -> Result<String, String> {
+    pretty_json(&IrDocument::new(exprs.to_vec())).map(|bytes| String::from_utf8(bytes).expect("serde_json writes UTF-8"))
+}
+
+/// Load JSON IR written by this or an older version, migrating it to the current schema.
+/// Documents of the current major version without a string table are read directly;
+/// the others go through a `Value`. Neither path limits how deeply the program nests.
+pub fn from_json(source: &str) -> Result<Vec<LispExpr>, String> {
+    let mut deserializer = serde_json::Deserializer::from_str(source);
+    deserializer.disable_recursion_limit();
+    if let Ok(document) = IrDocument::deserialize(&mut deserializer)
+        && deserializer.end().is_ok()
+        && reads_directly(&document)
+    {
+        return Ok(document.exprs);
+    }
+    let mut deserializer = serde_json::Deserializer::from_str(source);
+    deserializer.disable_recursion_limit();
+    let value = StackedValue::deserialize(&mut deserializer)
+        .and_then(|value| deserializer.end().map(|()| value.0))
+        .map_err(|e| format!("JSON deserialization error: {}", e))?;
+    from_value(value)
+}
+
+/// Serialize a program as a versioned IR document in `format`
+pub fn encode(exprs: &[LispExpr], format: IrFormat) -> Result<Vec<u8>, String> {
+    encode_document(&IrDocument::new(exprs.to_vec()), format)
+}
+
+/// Serialize a document built by the caller, e.g. one carrying `spans`
+pub fn encode_document(document: &IrDocument, format: IrFormat) -> Result<Vec<u8>, String> {
+    if document.interned {
+        let mut value = serde_json::to_value(document).map_err(|e| format!("JSON serialization error: {}", e))?;
+        intern_strings(&mut value);
+        let encoded = encode_value(&value, format, false);
+        drop_value(value);
+        return encoded;
+    }
+    match format {
+        IrFormat::Json => pretty_json(document),
+        IrFormat::Cbor => {
+            let mut bytes = CBOR_MAGIC.to_vec();
+            ciborium::into_writer(document, &mut bytes).map_err(|e| format!("CBOR serialization error: {}", e))?;
+            Ok(bytes)
+        }
+        IrFormat::MessagePack => {
+            rmp_serde::to_vec_named(document).map_err(|e| format!("MessagePack serialization error: {}", e))
+        }
+    }
+}
+
+/// Encode `document` so identical programs always produce identical bytes: gensyms
+/// are renumbered per top-level form in order of first appearance, `-0.0` becomes
+/// `0.0`, object keys are sorted, and JSON is written compactly. Floats use the
+/// shortest representation that round-trips.
+pub fn encode_canonical(mut document: IrDocument, format: IrFormat) -> Result<Vec<u8>, String> {
+    canonicalize_program(&mut document.exprs);
+    // serde_json::Map keeps keys sorted
+    let mut value = serde_json::to_value(&document).map_err(|e| format!("JSON serialization error: {}", e))?;
+    if document.interned {
+        intern_strings(&mut value);
+    }
+    let encoded = encode_value(&value, format, true);
+    drop_value(value);
+    encoded
+}
+
+/// Encode an IR document already converted to a `Value`, with compact JSON when `compact`
+fn encode_value(value: &Value, format: IrFormat, compact: bool) -> Result<Vec<u8>, String> {
+    let value = &StackedRef(value);
+    match format {
+        IrFormat::Json if compact => serde_json::to_vec(value).map_err(|e| format!("JSON serialization error: {}", e)),
+        IrFormat::Json => pretty_json(value),
+        IrFormat::Cbor => {
+            let mut bytes = CBOR_MAGIC.to_vec();
+            ciborium::into_writer(value, &mut bytes).map_err(|e| format!("CBOR serialization error: {}", e))?;
+            Ok(bytes)
+        }
+        IrFormat::MessagePack => rmp_serde::to_vec(value).map_err(|e| format!("MessagePack serialization error: {}", e)),
+    }
+}
+
+/// A `Value` read with each nested array and object on a growing stack; `Value`'s
+/// own `Deserialize` recurses once per level, which deep programs overflow
+struct StackedValue(Value);
+
+impl<'de> Deserialize<'de> for StackedValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{MapAccess, SeqAccess, Visitor};
+
+        struct ValueVisitor;
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("any JSON value")
+            }
+            fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+                Ok(Value::Bool(b))
+            }
+            fn visit_i64<E>(self, n: i64) -> Result<Value, E> {
+                Ok(Value::from(n))
+            }
+            fn visit_u64<E>(self, n: u64) -> Result<Value, E> {
+                Ok(Value::from(n))
+            }
+            fn visit_f64<E>(self, n: f64) -> Result<Value, E> {
+                Ok(Value::from(n))
+            }
+            fn visit_str<E>(self, s: &str) -> Result<Value, E> {
+                Ok(Value::from(s))
+            }
+            fn visit_string<E>(self, s: String) -> Result<Value, E> {
+                Ok(Value::String(s))
+            }
+            fn visit_unit<E>(self) -> Result<Value, E> {
+                Ok(Value::Null)
+            }
+            fn visit_none<E>(self) -> Result<Value, E> {
+                Ok(Value::Null)
+            }
+            fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+                StackedValue::deserialize(deserializer).map(|value| value.0)
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+                let mut items = Vec::new();
+                while let Some(StackedValue(item)) = grow_stack(|| seq.next_element())? {
+                    items.push(item);
+                }
+                Ok(Value::Array(items))
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+                let mut fields = serde_json::Map::new();
+                while let Some((key, StackedValue(field))) = grow_stack(|| map.next_entry())? {
+                    fields.insert(key, field);
+                }
+                Ok(Value::Object(fields))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor).map(StackedValue)
+    }
+}
+
+/// Serializes a `Value` with each nested array and object on a growing stack
+struct StackedRef<'a>(&'a Value);
+
+impl Serialize for StackedRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{SerializeMap, SerializeSeq};
+
+        match self.0 {
+            Value::Array(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    grow_stack(|| seq.serialize_element(&StackedRef(item)))?;
+                }
+                seq.end()
+            }
+            Value::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (key, field) in fields {
+                    grow_stack(|| map.serialize_entry(key, &StackedRef(field)))?;
+                }
+                map.end()
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+/// Drop `value` a level at a time, where dropping it in place would recurse once per level
+fn drop_value(value: Value) {
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Array(items) => pending.extend(items),
+            Value::Object(fields) => pending.extend(fields.into_iter().map(|(_, field)| field)),
+            _ => {}
+        }
+    }
+}
+
+/// Levels of nesting `pretty_json` indents; deeper ones line up with the last, so
+/// the output stays linear in the size of the program however deeply it nests
+const MAX_IR_INDENT: usize = 32;
+
+/// JSON indented like `serde_json::to_vec_pretty`, up to `MAX_IR_INDENT` levels
+fn pretty_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut bytes, IrFormatter::default());
+    value.serialize(&mut serializer).map_err(|e| format!("JSON serialization error: {}", e))?;
+    Ok(bytes)
+}
+
+#[derive(Default)]
+struct IrFormatter {
+    depth: usize,
+    has_value: bool,
+}
+
+impl IrFormatter {
+    fn indent<W: ?Sized + Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&b"  ".repeat(self.depth.min(MAX_IR_INDENT)))
+    }
+
+    fn begin<W: ?Sized + Write>(&mut self, writer: &mut W, open: &[u8]) -> std::io::Result<()> {
+        self.depth += 1;
+        self.has_value = false;
+        writer.write_all(open)
+    }
+
+    fn end<W: ?Sized + Write>(&mut self, writer: &mut W, close: &[u8]) -> std::io::Result<()> {
+        self.depth -= 1;
+        if self.has_value {
+            writer.write_all(b"\n")?;
+            self.indent(writer)?;
+        }
+        writer.write_all(close)
+    }
+
+    fn entry<W: ?Sized + Write>(&self, writer: &mut W, first: bool) -> std::io::Result<()> {
+        writer.write_all(if first { b"\n" } else { b",\n" })?;
+        self.indent(writer)
+    }
+}
+
+impl serde_json::ser::Formatter for IrFormatter {
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.begin(writer, b"[")
+    }
+
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.end(writer, b"]")
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(&mut self, writer: &mut W, first: bool) -> std::io::Result<()> {
+        self.entry(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + Write>(&mut self, _writer: &mut W) -> std::io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.begin(writer, b"{")
+    }
+
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.end(writer, b"}")
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(&mut self, writer: &mut W, first: bool) -> std::io::Result<()> {
+        self.entry(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(b": ")
+    }
+
+    fn end_object_value<W: ?Sized + Write>(&mut self, _writer: &mut W) -> std::io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+}
+
+/// Replace the name of every `Symbol` and `Gensym` in `document`'s `exprs` with its
+/// index in a `strings` table added to the document, in order of first appearance:
+/// `{"strings": ["+", "x"], "exprs": [{"List": [{"Symbol": 0}, {"Symbol": 1}]}]}`.
+/// Large programs repeat the same few hundred names, so the table makes IR much
+/// smaller; `resolve_strings` reverses it.
+pub fn intern_strings(document: &mut Value) {
+    fn intern(value: &mut Value, strings: &mut Vec<String>, indices: &mut HashMap<String, usize>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields.iter_mut() {
+                    match (key.as_str(), &*field) {
+                        ("Symbol" | "Gensym", Value::String(name)) => {
+                            let index = *indices.entry(name.clone()).or_insert_with(|| {
+                                strings.push(name.clone());
+                                strings.len() - 1
+                            });
+                            *field = Value::from(index);
+                        }
+                        _ => grow_stack(|| intern(field, strings, indices)),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| grow_stack(|| intern(item, strings, indices))),
+            _ => {}
+        }
+    }
+
+    let mut strings = Vec::new();
+    if let Some(exprs) = document.get_mut("exprs") {
+        intern(exprs, &mut strings, &mut HashMap::new());
+    }
+    document["strings"] = Value::from(strings);
+}
+
+/// Put the names back into a document written by `intern_strings`, removing its
+/// `strings` table; documents without one are left as they are
+pub fn resolve_strings(document: &mut Value) -> Result<(), String> {
+    fn resolve(value: &mut Value, strings: &[String]) -> Result<(), String> {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields.iter_mut() {
+                    match (key.as_str(), &*field) {
+                        ("Symbol" | "Gensym", Value::Number(index)) => {
+                            let name = index
+                                .as_u64()
+                                .and_then(|index| strings.get(index as usize))
+                                .ok_or_else(|| format!("IR {} refers to string {}, but the table has {} strings", key, index, strings.len()))?;
+                            *field = Value::from(name.as_str());
+                        }
+                        _ => grow_stack(|| resolve(field, strings))?,
+                    }
+                }
+                Ok(())
+            }
+            Value::Array(items) => items.iter_mut().try_for_each(|item| grow_stack(|| resolve(item, strings))),
+            _ => Ok(()),
+        }
+    }
+
+    let Some(strings) = document.as_object_mut().and_then(|fields| fields.remove("strings")) else {
+        return Ok(());
+    };
+    let strings: Vec<String> = serde_json::from_value(strings).map_err(|e| format!("IR \"strings\" must be an array of strings: {}", e))?;
+    match document.get_mut("exprs") {
+        Some(exprs) => resolve(exprs, &strings),
+        None => Ok(()),
+    }
+}
+
+/// Renumber the gensyms of each top-level form in order of first appearance, and
+/// write `-0.0` as `0.0`, so a form's IR and generated code do not depend on the
+/// forms expanded before it
+pub fn canonicalize_program(exprs: &mut [LispExpr]) {
+    // A gensym is scoped to the expansion that created it, which never spans forms
+    for expr in exprs {
+        canonicalize(expr, &mut HashMap::new());
+    }
+}
+
+/// Gensyms are `prefix#gN` with a counter shared by the whole expansion, so
+/// unrelated macro calls earlier in the program shift every later name
+fn canonicalize(expr: &mut LispExpr, gensyms: &mut HashMap<String, String>) {
+    match expr {
+        LispExpr::Number(n) if *n == 0.0 => *n = 0.0,
+        LispExpr::Gensym(name) => {
+            let next = gensyms.len() + 1;
+            let prefix = name.rsplit_once("#g").map_or(name.as_str(), |(prefix, _)| prefix);
+            let renamed = format!("{}#g{}", prefix, next);
+            *name = gensyms.entry(name.to_string()).or_insert(renamed).as_str().into();
+        }
+        LispExpr::List(elements) => {
+            Rc::make_mut(elements).iter_mut().for_each(|element| grow_stack(|| canonicalize(element, gensyms)))
+        }
+        LispExpr::MacroCall { args, .. } => {
+            args.iter_mut().for_each(|arg| grow_stack(|| canonicalize(arg, gensyms)))
+        }
+        LispExpr::Quote(inner)
+        | LispExpr::Quasiquote(inner)
+        | LispExpr::Unquote(inner)
+        | LispExpr::Splice(inner)
+        | LispExpr::Macro { body: inner, .. } => grow_stack(|| canonicalize(Rc::make_mut(inner), gensyms)),
+        _ => {}
+    }
+}
+
+/// Whether `document` has this compiler's major version and no string table, so it
+/// needs no migration or resolving
+fn reads_directly(document: &IrDocument) -> bool {
+    let major = |version: &str| parse_version(version).map(|version| version.0);
+    !document.interned && major(&document.version).is_ok_and(|version| Ok(version) == major(IR_VERSION))
+}
+
+/// Load IR in `format`. Documents of the current major version are read directly;
+/// anything else goes through the version checks and migrations of `from_json`.
+pub fn decode(bytes: &[u8], format: IrFormat) -> Result<Vec<LispExpr>, String> {
+    let value: Value = match format {
+        IrFormat::Json => {
+            let source = std::str::from_utf8(bytes).map_err(|e| format!("JSON IR is not valid UTF-8: {}", e))?;
+            return from_json(source);
+        }
+        IrFormat::Cbor => {
+            let body = bytes.strip_prefix(&CBOR_MAGIC[..]).unwrap_or(bytes);
+            fn read<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ciborium::de::Error<std::io::Error>> {
+                ciborium::de::from_reader_with_recursion_limit(body, usize::MAX)
+            }
+            if let Ok(document) = read::<IrDocument>(body)
+                && reads_directly(&document)
+            {
+                return Ok(document.exprs);
+            }
+            read(body).map(|StackedValue(value)| value).map_err(|e| format!("CBOR deserialization error: {}", e))?
+        }
+        IrFormat::MessagePack => {
+            fn read<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, rmp_serde::decode::Error> {
+                let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+                deserializer.set_max_depth(usize::MAX);
+                T::deserialize(&mut deserializer)
+            }
+            if let Ok(document) = read::<IrDocument>(bytes)
+                && reads_directly(&document)
+            {
+                return Ok(document.exprs);
+            }
+            read(bytes).map(|StackedValue(value)| value).map_err(|e| format!("MessagePack deserialization error: {}", e))?
+        }
+    };
+    from_value(value)
+}
+
+/// Load an already-parsed IR document, applying the same version checks and migrations as `from_json`
+pub fn from_value(value: Value) -> Result<Vec<LispExpr>, String> {
+    let (version, value) = match value {
+        // IR written before the envelope existed is a bare array of expressions
+        Value::Array(exprs) => ((0, 0), envelope("0.0", exprs)),
+        Value::Object(ref fields) => {
+            let version = fields
+                .get("version")
+                .and_then(Value::as_str)
+                .ok_or("IR document has no \"version\" string")?;
+            (parse_version(version)?, value)
+        }
+        _ => return Err("IR must be an object with \"version\" and \"exprs\"".to_string()),
+    };
+
+    let current = parse_version(IR_VERSION)?;
+    if version.0 > current.0 {
+        return Err(format!(
+            "IR version {}.{} is not supported by this compiler (reads up to {}.x)",
+            version.0, version.1, current.0
+        ));
+    }
+    let mut value = migrate(version, value)?;
+    resolve_strings(&mut value)?;
+    let document: IrDocument = serde_json::from_value(value).map_err(|e| {
+        if version > current {
+            format!(
+                "IR version {}.{} is newer than this compiler ({}) and uses unsupported forms: {}",
+                version.0, version.1, IR_VERSION, e
+            )
+        } else {
+            format!("IR deserialization error: {}", e)
+        }
+    })?;
+    Ok(document.exprs)
+}
+
+/// Writes newline-delimited IR: a `{"version": ...}` header line, then each
+/// expression as its own JSON document on one line
+pub struct StreamWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, String> {
+        writeln!(writer, "{}", serde_json::json!({ "version": IR_VERSION })).map_err(|e| format!("Error writing IR stream: {}", e))?;
+        Ok(StreamWriter { writer })
+    }
+
+    pub fn write(&mut self, expr: &LispExpr) -> Result<(), String> {
+        serde_json::to_writer(&mut self.writer, expr).map_err(|e| format!("JSON serialization error: {}", e))?;
+        writeln!(self.writer).map_err(|e| format!("Error writing IR stream: {}", e))
+    }
+
+    pub fn finish(mut self) -> Result<W, String> {
+        self.writer.flush().map_err(|e| format!("Error writing IR stream: {}", e))?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads newline-delimited IR one line at a time. The version header is optional;
+/// blank lines are skipped.
+pub struct StreamReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    line_number: usize,
+}
+
+impl<R: BufRead> StreamReader<R> {
+    pub fn new(reader: R) -> Self {
+        StreamReader { lines: reader.lines(), line_number: 0 }
+    }
+
+    fn check_header(header: &serde_json::Map<String, Value>) -> Result<(), String> {
+        let version = header.get("version").and_then(Value::as_str).ok_or("IR stream header has no \"version\" string")?;
+        let (major, minor) = parse_version(version)?;
+        let current = parse_version(IR_VERSION)?;
+        if major != current.0 {
+            return Err(format!("IR stream version {}.{} is not supported by this compiler (reads {}.x)", major, minor, current.0));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for StreamReader<R> {
+    type Item = Result<LispExpr, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(format!("Error reading IR stream: {}", e))),
+            };
+            self.line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let at_line = |e: String| format!("IR stream line {}: {}", self.line_number, e);
+
+            // Expressions are single-key objects named after their variant, never "version"
+            let value: Value = match serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(e) => return Some(Err(at_line(format!("JSON deserialization error: {}", e)))),
+            };
+            if let Value::Object(fields) = &value
+                && fields.contains_key("version")
+            {
+                if let Err(e) = Self::check_header(fields) {
+                    return Some(Err(at_line(e)));
+                }
+                continue;
+            }
+            return Some(serde_json::from_value(value).map_err(|e| at_line(format!("IR deserialization error: {}", e))));
+        }
+    }
+}
+
+fn envelope(version: &str, exprs: Vec<Value>) -> Value {
+    serde_json::json!({ "version": version, "exprs": exprs })
+}
+
+fn parse_version(version: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid IR version '{}' (expected major.minor)", version);
+    let (major, minor) = version.split_once('.').ok_or_else(invalid)?;
+    Ok((major.parse().map_err(|_| invalid())?, minor.parse().map_err(|_| invalid())?))
+}
+
+/// Upgrade a document one major version at a time until it matches `IR_VERSION`
+fn migrate(mut version: (u32, u32), mut value: Value) -> Result<Value, String> {
+    loop {
+        value = match version.0 {
+            // 0.x: bare expression arrays; the expressions themselves are unchanged in 1.0
+            0 => {
+                let exprs = match value.get_mut("exprs").map(Value::take) {
+                    Some(Value::Array(exprs)) => exprs,
+                    _ => Vec::new(),
+                };
+                envelope("1.0", exprs)
+            }
+            _ => return Ok(value),
+        };
+        version = (version.0 + 1, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_writes_envelope() {
+        let exprs = vec![LispExpr::List(vec![LispExpr::Symbol("+".into()), LispExpr::Number(1.0)].into())];
+        let json = to_json(&exprs).unwrap();
+
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], IR_VERSION);
+        assert_eq!(from_json(&json).unwrap(), exprs);
+    }
+
+    #[test]
+    fn test_binary_formats_round_trip_and_are_detected() {
+        let exprs = vec![
+            LispExpr::Macro {
+                declarations: Vec::new(),
+                name: "twice".into(),
+                parameters: vec!["x".into()],
+                body: Rc::new(LispExpr::Quasiquote(Rc::new(LispExpr::Symbol("x".into())))),
+            },
+            LispExpr::List(vec![LispExpr::String("a".to_string()), LispExpr::Nil, LispExpr::Bool(true)].into()),
+        ];
+        for format in [IrFormat::Json, IrFormat::Cbor, IrFormat::MessagePack] {
+            let bytes = encode(&exprs, format).unwrap();
+            assert_eq!(IrFormat::detect(&bytes), format);
+            assert_eq!(decode(&bytes, format).unwrap(), exprs);
+        }
+        assert!(encode(&exprs, IrFormat::Cbor).unwrap().len() < to_json(&exprs).unwrap().len());
+    }
+
+    #[test]
+    fn test_binary_formats_check_version() {
+        let document = IrDocument { version: "2.0".to_string(), ..IrDocument::new(vec![]) };
+        let bytes = rmp_serde::to_vec_named(&document).unwrap();
+        assert!(decode(&bytes, IrFormat::MessagePack).unwrap_err().contains("IR version 2.0 is not supported"));
+        assert!(IrFormat::parse("bincode").is_err());
+        assert_eq!(IrFormat::MessagePack.extension(), "msgpack");
+    }
+
+    #[test]
+    fn test_canonical_encoding_round_trips() {
+        let form = |counter: usize| {
+            LispExpr::List(vec![
+                LispExpr::Gensym(format!("tmp#g{}", counter).into()),
+                LispExpr::Number(-0.0),
+                LispExpr::Gensym(format!("tmp#g{}", counter).into()),
+            ].into())
+        };
+        for format in [IrFormat::Json, IrFormat::Cbor, IrFormat::MessagePack] {
+            let bytes = encode_canonical(IrDocument::new(vec![form(7)]), format).unwrap();
+            assert_eq!(bytes, encode_canonical(IrDocument::new(vec![form(3)]), format).unwrap());
+            assert_eq!(IrFormat::detect(&bytes), format);
+            assert_eq!(decode(&bytes, format).unwrap()[0].to_string(), "(tmp#g1 0 tmp#g1)");
+        }
+    }
+
+    #[test]
+    fn test_interned_strings_round_trip() {
+        let program = crate::parser::parse(crate::lexer::tokenize("(defun add (x y) (+ x y)) (add (add 1 2) \"add\")").unwrap()).unwrap();
+        let document = IrDocument::new(program.clone()).with_interned_strings();
+        let json: Value = serde_json::from_slice(&encode_document(&document, IrFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["strings"], serde_json::json!(["defun", "add", "x", "y", "+"]));
+        assert_eq!(json["exprs"][1]["List"][0], serde_json::json!({"Symbol": 1}));
+        assert_eq!(json["exprs"][1]["List"][2], serde_json::json!({"String": "add"}));
+
+        for format in [IrFormat::Json, IrFormat::Cbor, IrFormat::MessagePack] {
+            let interned = encode_document(&document, format).unwrap();
+            let repeated: Vec<LispExpr> = (0..20).flat_map(|_| program.clone()).collect();
+            let smaller = encode_document(&IrDocument::new(repeated.clone()).with_interned_strings(), format).unwrap();
+            assert!(smaller.len() < encode(&repeated, format).unwrap().len());
+            assert_eq!(decode(&interned, format).unwrap(), program);
+            assert_eq!(decode(&encode_canonical(document.clone(), format).unwrap(), format).unwrap(), program);
+        }
+
+        let error = from_json(r#"{"version":"1.3","strings":["x"],"exprs":[{"Gensym":1}]}"#).unwrap_err();
+        assert_eq!(error, "IR Gensym refers to string 1, but the table has 1 strings");
+        assert!(from_json(r#"{"version":"1.3","strings":[1],"exprs":[]}"#).unwrap_err().contains("must be an array of strings"));
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let exprs = vec![
+            LispExpr::List(vec![LispExpr::Symbol("print".into()), LispExpr::String("a\nb".to_string())].into()),
+            LispExpr::Nil,
+        ];
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        for expr in &exprs {
+            writer.write(expr).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with(&format!("{{\"version\":\"{}\"}}\n", IR_VERSION)));
+        let read: Result<Vec<_>, _> = StreamReader::new(&bytes[..]).collect();
+        assert_eq!(read.unwrap(), exprs);
+    }
+
+    #[test]
+    fn test_stream_errors_name_the_line() {
+        let headerless = "{\"Symbol\":\"x\"}\n\n{\"Number\":1.0}\n";
+        assert_eq!(StreamReader::new(headerless.as_bytes()).count(), 2);
+
+        let errors: Vec<_> = StreamReader::new("{\"version\":\"1.0\"}\n{\"Lambda\":1}\n".as_bytes()).collect();
+        assert!(errors[0].as_ref().unwrap_err().starts_with("IR stream line 2: IR deserialization error"));
+
+        let newer = StreamReader::new("{\"version\":\"2.0\"}\n".as_bytes()).next().unwrap();
+        assert!(newer.unwrap_err().contains("version 2.0 is not supported"));
+    }
+
+    #[test]
+    fn test_migrates_bare_arrays() {
+        let exprs = from_json(r#"[{"Symbol":"x"}]"#).unwrap();
+        assert_eq!(exprs, vec![LispExpr::Symbol("x".into())]);
+    }
+
+    #[test]
+    fn test_rejects_incompatible_versions() {
+        let newer_major = from_json(r#"{"version":"2.0","exprs":[]}"#).unwrap_err();
+        assert!(newer_major.contains("IR version 2.0 is not supported"));
+
+        let newer_minor = from_json(r#"{"version":"1.9","exprs":[{"Lambda":{}}]}"#).unwrap_err();
+        assert!(newer_minor.contains("newer than this compiler"));
+        assert!(from_json(r#"{"version":"1.9","exprs":[{"Symbol":"x"}],"comments":[]}"#).is_ok());
+
+        assert!(from_json(r#"{"exprs":[]}"#).unwrap_err().contains("no \"version\""));
+        assert!(from_json(r#"{"version":"one","exprs":[]}"#).unwrap_err().contains("Invalid IR version"));
+    }
+
+    #[test]
+    fn test_deeply_nested_ir_round_trips() {
+        let depth = 20_000;
+        let mut expr = LispExpr::Number(1.0);
+        for _ in 0..depth {
+            expr = LispExpr::List(vec![LispExpr::Symbol("list".into()), expr].into());
+        }
+        let exprs = vec![expr];
+
+        let json = to_json(&exprs).unwrap();
+        // Indentation stops growing, so the document stays linear in the program
+        assert!(json.len() < 1_000 * depth);
+        assert_eq!(from_json(&json).unwrap(), exprs);
+        for format in [IrFormat::Json, IrFormat::Cbor, IrFormat::MessagePack] {
+            let document = IrDocument::new(exprs.clone()).with_interned_strings();
+            assert_eq!(decode(&encode_document(&document, format).unwrap(), format).unwrap(), exprs, "{:?}", format);
+            assert_eq!(decode(&encode_canonical(document, format).unwrap(), format).unwrap(), exprs, "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// JSON Schema (draft 2020-12) for JSON IR documents of the current version
+pub fn schema() -> Value {
+    let expr = json!({ "$ref": "#/$defs/Expr" });
+    let string = json!({ "type": "string" });
+    // A name, or with `--ir-intern` its index in `strings`
+    let name = json!({ "type": ["string", "integer"], "minimum": 0 });
+    let variant = |name: &str, schema: Value| {
+        json!({
+            "title": name,
+            "type": "object",
+            "properties": { name: schema },
+            "required": [name],
+            "additionalProperties": false
+        })
+    };
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "rusty-lisp IR",
+        "type": "object",
+        "properties": {
+            "version": { "type": "string", "description": "major.minor IR version" },
+            "exprs": { "type": "array", "items": expr },
+            "spans": { "type": "array", "items": { "$ref": "#/$defs/SpanTree" } },
+            "strings": { "type": "array", "items": string }
+        },
+        "required": ["version", "exprs"],
+        "$defs": {
+            "Expr": {
+                "oneOf": [
+                    { "title": "Nil", "const": "Nil" },
+                    variant("Number", json!({ "type": "number" })),
+                    variant("Symbol", name.clone()),
+                    variant("String", string.clone()),
+                    variant("List", json!({ "type": "array", "items": expr })),
+                    variant("Bool", json!({ "type": "boolean" })),
+                    variant("Macro", json!({
+                        "type": "object",
+                        "properties": {
+                            "name": string,
+                            "parameters": { "type": "array", "items": string },
+                            "body": expr,
+                            "declarations": { "type": "array", "items": expr }
+                        },
+                        "required": ["name", "parameters", "body"],
+                        "additionalProperties": false
+                    })),
+                    variant("MacroCall", json!({
+                        "type": "object",
+                        "properties": {
+                            "name": string,
+                            "args": { "type": "array", "items": expr }
+                        },
+                        "required": ["name", "args"],
+                        "additionalProperties": false
+                    })),
+                    variant("Quote", expr.clone()),
+                    variant("Quasiquote", expr.clone()),
+                    variant("Unquote", expr.clone()),
+                    variant("Splice", expr.clone()),
+                    variant("Gensym", name)
+                ]
+            },
+            "SpanTree": {
+                "type": "object",
+                "properties": {
+                    "span": {
+                        "type": ["object", "null"],
+                        "properties": {
+                            "start": { "type": "integer", "minimum": 0 },
+                            "end": { "type": "integer", "minimum": 0 }
+                        },
+                        "required": ["start", "end"],
+                        "additionalProperties": false
+                    },
+                    "line": { "type": "integer", "minimum": 1 },
+                    "column": { "type": "integer", "minimum": 1 },
+                    "expanded_from": string,
+                    "children": { "type": "array", "items": { "$ref": "#/$defs/SpanTree" } }
+                },
+                "additionalProperties": false
+            }
+        }
+    })
+}
+
+/// A schema violation at a JSON Pointer into the checked document
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+/// Check a JSON IR document against `schema()`, then that this compiler can load
+/// its version. Returns every violation found.
+pub fn check(document: &Value) -> Vec<SchemaError> {
+    let schema = schema();
+    let mut errors = Vec::new();
+    Checker { root: &schema }.check(&schema, document, "", &mut errors);
+    if errors.is_empty()
+        && let Err(message) = crate::ir::from_value(document.clone())
+    {
+        errors.push(SchemaError { path: String::new(), message });
+    }
+    errors
+}
+
+/// Evaluates the subset of JSON Schema that `schema()` uses
+struct Checker<'a> {
+    root: &'a Value,
+}
+
+impl Checker<'_> {
+    fn check(&self, schema: &Value, instance: &Value, path: &str, errors: &mut Vec<SchemaError>) {
+        let error = |errors: &mut Vec<SchemaError>, path: &str, message: String| {
+            errors.push(SchemaError { path: path.to_string(), message })
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference.strip_prefix('#').and_then(|pointer| self.root.pointer(pointer)) {
+                Some(target) => self.check(target, instance, path, errors),
+                None => error(errors, path, format!("unresolvable schema reference '{}'", reference)),
+            }
+            return;
+        }
+        if let Some(expected) = schema.get("const")
+            && instance != expected
+        {
+            error(errors, path, format!("expected {}, found {}", expected, instance));
+            return;
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                other => other.as_str().into_iter().collect(),
+            };
+            if !types.iter().any(|t| has_type(instance, t)) {
+                error(errors, path, format!("expected {}, found {}", types.join(" or "), type_name(instance)));
+                return;
+            }
+        }
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64)
+            && let Some(value) = instance.as_f64()
+            && value < minimum
+        {
+            error(errors, path, format!("must be at least {}", minimum));
+        }
+        if let Value::Object(fields) = instance {
+            self.check_object(schema, fields, path, errors);
+        }
+        if let (Some(items), Value::Array(elements)) = (schema.get("items"), instance) {
+            for (index, element) in elements.iter().enumerate() {
+                self.check(items, element, &format!("{}/{}", path, index), errors);
+            }
+        }
+        if let Some(alternatives) = schema.get("oneOf").and_then(Value::as_array) {
+            self.check_one_of(alternatives, instance, path, errors);
+        }
+    }
+
+    fn check_object(&self, schema: &Value, fields: &Map<String, Value>, path: &str, errors: &mut Vec<SchemaError>) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            if let Some(name) = required.as_str()
+                && !fields.contains_key(name)
+            {
+                errors.push(SchemaError {
+                    path: path.to_string(),
+                    message: format!("missing required property '{}'", name),
+                });
+            }
+        }
+        for (name, value) in fields {
+            let field_path = format!("{}/{}", path, escape(name));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.check(property, value, &field_path, errors),
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    errors.push(SchemaError { path: field_path, message: "unexpected property".to_string() });
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// When no alternative matches, report the errors of the one the instance
+    /// selects by its property names (the enum variant key), so paths point inside it
+    fn check_one_of(&self, alternatives: &[Value], instance: &Value, path: &str, errors: &mut Vec<SchemaError>) {
+        let mut results = Vec::new();
+        for alternative in alternatives {
+            let mut alternative_errors = Vec::new();
+            self.check(alternative, instance, path, &mut alternative_errors);
+            if alternative_errors.is_empty() {
+                return;
+            }
+            results.push((alternative, alternative_errors));
+        }
+
+        let selected: Vec<_> = results
+            .iter()
+            .filter(|(alternative, _)| {
+                let required = alternative.get("required").and_then(Value::as_array);
+                matches!((required, instance), (Some(required), Value::Object(fields))
+                    if required.iter().all(|name| name.as_str().is_some_and(|name| fields.contains_key(name))))
+            })
+            .collect();
+        if let [(_, alternative_errors)] = selected.as_slice() {
+            errors.extend(alternative_errors.iter().cloned());
+            return;
+        }
+
+        let titles: Vec<&str> = alternatives.iter().filter_map(|a| a.get("title").and_then(Value::as_str)).collect();
+        errors.push(SchemaError {
+            path: path.to_string(),
+            message: format!("expected one of {}, found {}", titles.join(", "), describe(instance)),
+        });
+    }
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => instance.is_u64() || instance.is_i64(),
+        "boolean" => instance.is_boolean(),
+        "null" => instance.is_null(),
+        _ => false,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn describe(instance: &Value) -> String {
+    match instance {
+        Value::Object(fields) => {
+            let keys: Vec<&str> = fields.keys().map(String::as_str).collect();
+            format!("object with keys [{}]", keys.join(", "))
+        }
+        Value::String(s) => format!("\"{}\"", s),
+        other => type_name(other).to_string(),
+    }
+}
+
+/// JSON Pointer escaping of a property name
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{LispExpr, Span, SpanTree};
+    use std::rc::Rc;
+    use crate::ir::{self, IrDocument, IrFormat};
+
+    fn errors(document: &str) -> Vec<String> {
+        check(&serde_json::from_str(document).unwrap()).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_accepts_documents_written_by_the_compiler() {
+        let exprs = vec![
+            LispExpr::Macro {
+                declarations: Vec::new(),
+                name: "twice".into(),
+                parameters: vec!["x".into()],
+                body: Rc::new(LispExpr::Quasiquote(Rc::new(LispExpr::List(vec![
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+                    LispExpr::Splice(Rc::new(LispExpr::Gensym("g_1".into()))),
+                ].into())))),
+            },
+            LispExpr::MacroCall { name: "twice".into(), args: vec![LispExpr::Number(1.5)] },
+            LispExpr::List(vec![
+                LispExpr::Quote(Rc::new(LispExpr::String("s".to_string()))),
+                LispExpr::Bool(false),
+                LispExpr::Nil,
+            ].into()),
+        ];
+        let spans = vec![SpanTree::new(Span::new(0, 3), vec![SpanTree::new(Span::new(1, 2), vec![])]).with_lines("abc")];
+        let document = IrDocument::new(exprs).with_spans(spans);
+        let json = ir::encode_document(&document, IrFormat::Json).unwrap();
+
+        assert_eq!(check(&serde_json::from_slice(&json).unwrap()), vec![]);
+        let interned = ir::encode_document(&document.with_interned_strings(), IrFormat::Json).unwrap();
+        assert_eq!(check(&serde_json::from_slice(&interned).unwrap()), vec![]);
+    }
+
+    #[test]
+    fn test_reports_precise_paths() {
+        assert_eq!(
+            errors(r#"{"version":"1.0","exprs":[{"List":[{"Symbol":"+"},{"Number":"1"}]}]}"#),
+            vec!["/exprs/0/List/1/Number: expected number, found string"]
+        );
+        assert_eq!(
+            errors(r#"{"version":"1.0","exprs":[{"Macro":{"name":"m","parameters":[]}}]}"#),
+            vec!["/exprs/0/Macro: missing required property 'body'"]
+        );
+        let unknown = errors(r#"{"version":"1.0","exprs":[{"Lambda":{}}]}"#);
+        assert_eq!(unknown.len(), 1);
+        assert!(unknown[0].starts_with("/exprs/0: expected one of Nil, Number,"));
+        assert!(unknown[0].ends_with("found object with keys [Lambda]"));
+
+        assert_eq!(errors(r#"{"exprs":[]}"#), vec!["/: missing required property 'version'"]);
+        assert_eq!(
+            errors(r#"{"version":"1.0","exprs":[],"spans":[{"span":{"start":-1,"end":2}}]}"#),
+            vec!["/spans/0/span/start: must be at least 0"]
+        );
+        assert_eq!(errors(r#"{"version":"1.0","exprs":[],"spans":[{"span":null}]}"#), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_reports_unsupported_versions() {
+        assert_eq!(
+            errors(r#"{"version":"2.0","exprs":[]}"#),
+            vec!["/: IR version 2.0 is not supported by this compiler (reads up to 1.x)"]
+        );
+    }
+}
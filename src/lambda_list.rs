@@ -0,0 +1,220 @@
+//! Function parameter lists with optional, rest, and keyword parameters.
+//!
+//! `(a b &optional (c 1) &key (greeting "Hello") verbose)` takes two required
+//! arguments, an optional third defaulting to 1, and the keyword arguments
+//! `:greeting` and `:verbose`. A parameter without a default defaults to nil.
+//! Required parameters may carry a type annotation, `(x : number)`.
+//! `&rest` collects the arguments after the optional ones and cannot be combined
+//! with `&key`. Matching arguments against a lambda list is shared by the
+//! interpreter, which binds the values, and the arity validator, which checks
+//! call sites before the program runs.
+
+use crate::ast::LispExpr;
+use crate::symbol::Symbol;
+use crate::types::Typed;
+
+/// `:name` symbols evaluate to themselves and name keyword arguments
+pub fn is_keyword(name: &str) -> bool {
+    name.len() > 1 && name.starts_with(':')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: Symbol,
+    /// Evaluated when the argument is not passed, with the earlier parameters bound
+    pub default: Option<LispExpr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LambdaList {
+    pub required: Vec<Symbol>,
+    /// The annotated type of each required parameter
+    pub required_types: Vec<Option<Symbol>>,
+    pub optional: Vec<Parameter>,
+    pub rest: Option<Symbol>,
+    pub keys: Vec<Parameter>,
+}
+
+/// Arguments sorted into the parameters they are bound to; `None` where the default applies
+#[derive(Debug, PartialEq)]
+pub struct Arguments<T> {
+    pub required: Vec<T>,
+    pub optional: Vec<Option<T>>,
+    pub rest: Vec<T>,
+    pub keys: Vec<Option<T>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Required,
+    Optional,
+    Rest,
+    Key,
+}
+
+impl LambdaList {
+    pub fn parse(parameters: &[LispExpr]) -> Result<LambdaList, String> {
+        let mut list = LambdaList::default();
+        let mut section = Section::Required;
+        let mut parameters = parameters.iter().peekable();
+        while let Some(parameter) = parameters.next() {
+            match (parameter.as_symbol(), section) {
+                (Some("&optional"), Section::Required) => section = Section::Optional,
+                (Some("&rest"), Section::Required | Section::Optional) => {
+                    match (parameters.next(), parameters.peek().and_then(|p| p.as_symbol())) {
+                        (Some(LispExpr::Symbol(s) | LispExpr::Gensym(s)), None | Some("&key")) if !s.starts_with('&') => list.rest = Some(s.clone()),
+                        _ => return Err("'&rest' must be followed by exactly one parameter name".to_string()),
+                    }
+                    section = Section::Rest;
+                }
+                (Some("&key"), Section::Rest) => return Err("'&rest' and '&key' cannot be combined".to_string()),
+                (Some("&key"), Section::Required | Section::Optional) => section = Section::Key,
+                (Some(marker), _) if marker.starts_with('&') => {
+                    return Err(format!("'{}' is out of place; the order is required, &optional, &rest, &key", marker));
+                }
+                (_, Section::Required) => {
+                    let typed = match parameter {
+                        LispExpr::Symbol(s) | LispExpr::Gensym(s) => Typed { name: s.clone(), type_name: None },
+                        other => Typed::annotated(other).ok_or_else(|| format!("Parameter names must be symbols, got {}", other))?,
+                    };
+                    list.required.push(typed.name);
+                    list.required_types.push(typed.type_name);
+                }
+                (_, Section::Optional | Section::Key) => {
+                    let parameter = Self::parameter(parameter)?;
+                    if section == Section::Optional { list.optional.push(parameter) } else { list.keys.push(parameter) }
+                }
+                (_, Section::Rest) => unreachable!("'&rest' takes exactly one name"),
+            }
+        }
+        Ok(list)
+    }
+
+    /// `name` or `(name default)`
+    fn parameter(parameter: &LispExpr) -> Result<Parameter, String> {
+        match parameter {
+            LispExpr::Symbol(s) | LispExpr::Gensym(s) => Ok(Parameter { name: s.clone(), default: None }),
+            LispExpr::List(elements) => match &elements[..] {
+                [LispExpr::Symbol(s) | LispExpr::Gensym(s), default] => Ok(Parameter { name: s.clone(), default: Some(default.clone()) }),
+                _ => Err(format!("A parameter with a default must be (name default), got {}", parameter)),
+            },
+            other => Err(format!("Parameter names must be symbols, got {}", other)),
+        }
+    }
+
+    /// Every variable the list binds, in binding order
+    pub fn variables(&self) -> Vec<Symbol> {
+        let optional = self.optional.iter().map(|p| p.name.clone());
+        let keys = self.keys.iter().map(|p| p.name.clone());
+        self.required.iter().cloned().chain(optional).chain(self.rest.clone()).chain(keys).collect()
+    }
+
+    /// Only required parameters
+    pub fn is_fixed(&self) -> bool {
+        self.optional.is_empty() && self.rest.is_none() && self.keys.is_empty()
+    }
+
+    /// Sort a call's arguments into parameters. Optional parameters are filled
+    /// positionally, stopping at the first keyword when the list has `&key`;
+    /// everything after must be keyword/value pairs.
+    pub fn match_arguments<T>(
+        &self,
+        function: &str,
+        args: Vec<T>,
+        keyword_of: impl Fn(&T) -> Option<&str>,
+    ) -> Result<Arguments<T>, String> {
+        let count = args.len();
+        if count < self.required.len() || (self.rest.is_none() && self.keys.is_empty() && count > self.required.len() + self.optional.len()) {
+            return Err(format!("'{}' expects {} argument(s), got {}", function, self.expected(), count));
+        }
+
+        let mut args = args.into_iter().peekable();
+        let required = args.by_ref().take(self.required.len()).collect();
+        let mut optional = Vec::new();
+        for _ in &self.optional {
+            let at_keyword = !self.keys.is_empty() && args.peek().is_some_and(|arg| keyword_of(arg).is_some());
+            optional.push(if at_keyword { None } else { args.next() });
+        }
+        let rest = if self.rest.is_some() { args.by_ref().collect() } else { Vec::new() };
+
+        let mut keys: Vec<Option<T>> = self.keys.iter().map(|_| None).collect();
+        while let Some(arg) = args.next() {
+            let Some(keyword) = keyword_of(&arg) else {
+                return Err(format!("'{}' expects keyword arguments after its {} positional argument(s), got {} argument(s)", function, self.expected(), count));
+            };
+            let Some(index) = self.keys.iter().position(|key| keyword[1..] == *key.name) else {
+                let known: Vec<String> = self.keys.iter().map(|key| format!(":{}", key.name)).collect();
+                return Err(format!("'{}' has no keyword argument {} (expected one of {})", function, keyword, known.join(", ")));
+            };
+            let keyword = keyword.to_string();
+            let Some(value) = args.next() else {
+                return Err(format!("'{}' keyword argument {} is missing a value", function, keyword));
+            };
+            if keys[index].replace(value).is_some() {
+                return Err(format!("'{}' keyword argument {} is passed more than once", function, keyword));
+            }
+        }
+        Ok(Arguments { required, optional, rest, keys })
+    }
+
+    /// How many positional arguments the list takes, for messages
+    fn expected(&self) -> String {
+        let (min, max) = (self.required.len(), self.required.len() + self.optional.len());
+        match self.rest {
+            Some(_) => format!("at least {}", min),
+            None if min == max => min.to_string(),
+            None => format!("{} to {}", min, max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn lambda_list(source: &str) -> Result<LambdaList, String> {
+        LambdaList::parse(parse(tokenize(source).unwrap()).unwrap()[0].as_list().unwrap())
+    }
+
+    fn keyword_of<'a>(arg: &'a &str) -> Option<&'a str> {
+        is_keyword(arg).then_some(*arg)
+    }
+
+    #[test]
+    fn test_parse_lambda_lists() {
+        let list = lambda_list("(a &optional (b 2) c &key (greeting \"Hello\") verbose)").unwrap();
+        assert_eq!(list.required, vec![Symbol::from("a")]);
+        assert_eq!(list.optional[0].default, Some(LispExpr::Number(2.0)));
+        assert_eq!(list.keys[0].name, "greeting");
+        assert_eq!(list.variables().len(), 5);
+        assert!(!list.is_fixed());
+        assert_eq!(lambda_list("(a &rest r)").unwrap().rest, Some(Symbol::from("r")));
+        assert_eq!(lambda_list("((a : number) b)").unwrap().required_types, vec![Some("number".into()), None]);
+
+        assert_eq!(lambda_list("(&rest r &key k)").unwrap_err(), "'&rest' and '&key' cannot be combined");
+        assert_eq!(lambda_list("(&rest)").unwrap_err(), "'&rest' must be followed by exactly one parameter name");
+        assert_eq!(lambda_list("(&key a &optional b)").unwrap_err(), "'&optional' is out of place; the order is required, &optional, &rest, &key");
+        assert!(lambda_list("(&key (a 1 2))").unwrap_err().contains("(name default)"));
+    }
+
+    #[test]
+    fn test_match_arguments() {
+        let list = lambda_list("(name &optional title &key (greeting \"Hello\") punctuation)").unwrap();
+        let matched = list.match_arguments("greet", vec!["Ann", ":punctuation", "!"], keyword_of).unwrap();
+        assert_eq!(matched, Arguments { required: vec!["Ann"], optional: vec![None], rest: vec![], keys: vec![None, Some("!")] });
+        let matched = list.match_arguments("greet", vec!["Ann", "Dr", ":greeting", "Hi"], keyword_of).unwrap();
+        assert_eq!(matched.optional, vec![Some("Dr")]);
+
+        let error = |args: Vec<&'static str>| list.match_arguments("greet", args, keyword_of).unwrap_err();
+        assert_eq!(error(vec![]), "'greet' expects 1 to 2 argument(s), got 0");
+        assert_eq!(error(vec!["Ann", ":colour", "red"]), "'greet' has no keyword argument :colour (expected one of :greeting, :punctuation)");
+        assert_eq!(error(vec!["Ann", ":greeting"]), "'greet' keyword argument :greeting is missing a value");
+        assert_eq!(error(vec!["Ann", ":greeting", "a", ":greeting", "b"]), "'greet' keyword argument :greeting is passed more than once");
+        assert!(error(vec!["Ann", "Dr", "extra"]).starts_with("'greet' expects keyword arguments after"));
+
+        let fixed = lambda_list("(x)").unwrap();
+        assert_eq!(fixed.match_arguments("f", vec!["1", "2"], keyword_of).unwrap_err(), "'f' expects 1 argument(s), got 2");
+    }
+}
@@ -1,3 +1,8 @@
+use crate::ast::{Comment, Span};
+use crate::explain::CompileError;
+use crate::logging;
+use std::time::Instant;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     LeftParen,
@@ -13,8 +18,86 @@ pub enum Token {
     Splice,         // ,@
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+pub fn tokenize(input: &str) -> Result<Vec<Token>, CompileError> {
+    let spanned = tokenize_with_spans(input)?;
+    Ok(spanned.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Tokenize input, recording the source span of every token
+pub fn tokenize_with_spans(input: &str) -> Result<Vec<(Token, Span)>, CompileError> {
+    tokenize_with_comments(input).map(|(tokens, _)| tokens)
+}
+
+/// Tokens with their spans, as `tokenize_with_spans` returns them
+pub type SpannedTokens = Vec<(Token, Span)>;
+
+/// Bounds on what the lexer reads, so hostile input fails at the first token past a
+/// limit instead of growing without bound. Unset limits are not checked. A `Reader`
+/// tokenizes within the limits it was given (`--max-token-length`, `--max-tokens`, and
+/// `--max-string-size` on the command line, with `sandbox()` for the rest in sandbox
+/// mode).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LexerLimits {
+    /// The longest symbol or number, in bytes
+    pub max_token_length: Option<usize>,
+    pub max_tokens: Option<usize>,
+    /// The longest string literal, in bytes after escapes
+    pub max_string_size: Option<usize>,
+}
+
+impl LexerLimits {
+    pub const fn unlimited() -> Self {
+        LexerLimits { max_token_length: None, max_tokens: None, max_string_size: None }
+    }
+
+    /// Conservative limits for sandboxed builds, far above what hand-written programs reach
+    pub const fn sandbox() -> Self {
+        LexerLimits { max_token_length: Some(4096), max_tokens: Some(1_000_000), max_string_size: Some(1024 * 1024) }
+    }
+
+    pub fn with_max_token_length(mut self, max_token_length: usize) -> Self {
+        self.max_token_length = Some(max_token_length);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_max_string_size(mut self, max_string_size: usize) -> Self {
+        self.max_string_size = Some(max_string_size);
+        self
+    }
+
+    /// These limits, taking the ones not set from `defaults`
+    pub fn or(self, defaults: LexerLimits) -> Self {
+        LexerLimits {
+            max_token_length: self.max_token_length.or(defaults.max_token_length),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            max_string_size: self.max_string_size.or(defaults.max_string_size),
+        }
+    }
+}
+
+/// `what` for the text from `start` to `end`, located by line and column
+fn limit_error(input: &str, start: usize, end: usize, what: &str) -> CompileError {
+    let (line, column) = Span::line_col(input, start);
+    CompileError::new("L0102", format!("{} at {}:{} (bytes {}..{})", what, line, column, start, end))
+}
+
+/// Tokenize input, also returning the comments the tokens skip over
+pub fn tokenize_with_comments(input: &str) -> Result<(SpannedTokens, Vec<Comment>), CompileError> {
+    tokenize_with_limits(input, LexerLimits::unlimited())
+}
+
+/// Tokenize input as `tokenize_with_comments` does, within `limits`
+pub fn tokenize_with_limits(input: &str, limits: LexerLimits) -> Result<(SpannedTokens, Vec<Comment>), CompileError> {
+    let too_long = |length: usize, limit: Option<usize>| limit.is_some_and(|limit| length > limit);
+    let start = Instant::now();
+    let mut comments = Vec::new();
     let mut tokens = Vec::new();
+    let mut spans = Vec::new();
     let mut chars = input.char_indices().peekable();
     
     while let Some((pos, ch)) = chars.next() {
@@ -25,7 +108,7 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                 let mut string_content = String::new();
                 let mut escaped = false;
                 
-                while let Some((_, ch)) = chars.next() {
+                for (_, ch) in chars.by_ref() {
                     if escaped {
                         match ch {
                             'n' => string_content.push('\n'),
@@ -56,6 +139,9 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                     }
                     chars.next();
                 }
+                let end = chars.peek().map(|(next_pos, _)| *next_pos).unwrap_or(input.len());
+                let text = input[pos..end].trim_end().to_string();
+                comments.push(Comment { span: Span::new(pos, pos + text.len()), text });
             },
             '\'' => tokens.push(Token::Quote),
             '`' => tokens.push(Token::Quasiquote),
@@ -93,7 +179,7 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                 } else {
                     match number_str.parse::<f64>() {
                         Ok(num) => tokens.push(Token::Number(num)),
-                        Err(_) => return Err(format!("Invalid number at position {}: {}", start_pos, number_str)),
+                        Err(_) => return Err(CompileError::new("L0101", format!("Invalid number at position {}: {}", start_pos, number_str))),
                     }
                 }
             },
@@ -102,8 +188,10 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                 symbol.push(ch);
                 
                 while let Some((_, next_ch)) = chars.peek() {
+                    // Commas separate type parameters inside `name<A,B>` rather than unquoting
+                    let in_generics = symbol.find('<').is_some_and(|start| start > 0) && symbol.matches('<').count() > symbol.matches('>').count();
                     if next_ch.is_whitespace() || *next_ch == '(' || *next_ch == ')' || *next_ch == '"' || 
-                       *next_ch == '\'' || *next_ch == '`' || *next_ch == ',' {
+                       *next_ch == '\'' || *next_ch == '`' || (*next_ch == ',' && !in_generics) {
                         break;
                     }
                     symbol.push(*next_ch);
@@ -118,9 +206,34 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                 }
             }
         }
+
+        let end = chars.peek().map(|(next_pos, _)| *next_pos).unwrap_or(input.len());
+        // Each token is checked as soon as it is read
+        let exceeded = match tokens.last().filter(|_| spans.len() < tokens.len()) {
+            _ if too_long(tokens.len(), limits.max_tokens) => limits.max_tokens.map(|limit| format!("More than the limit of {} tokens", limit)),
+            Some(Token::String(string)) if too_long(string.len(), limits.max_string_size) => {
+                limits.max_string_size.map(|limit| format!("String longer than the limit of {} bytes", limit))
+            }
+            Some(Token::Symbol(_) | Token::Number(_)) if too_long(end - pos, limits.max_token_length) => {
+                limits.max_token_length.map(|limit| format!("Token longer than the limit of {} bytes", limit))
+            }
+            _ => None,
+        };
+        if let Some(what) = exceeded {
+            return Err(limit_error(input, pos, end, &what));
+        }
+        while spans.len() < tokens.len() {
+            spans.push(Span::new(pos, end));
+        }
     }
     
-    Ok(tokens)
+    let elapsed = start.elapsed();
+    logging::debug(
+        "lexer",
+        format_args!("{} tokens, {} comments from {} bytes in {:.2?}", tokens.len(), comments.len(), input.len(), elapsed),
+        &[("tokens", tokens.len().into()), ("comments", comments.len().into()), ("elapsed_us", logging::micros(elapsed))],
+    );
+    Ok((tokens.into_iter().zip(spans).collect(), comments))
 }
 
 #[cfg(test)]
@@ -165,6 +278,32 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_token_spans() {
+        let input = "(foo \"bar\")\n'x";
+        let tokens = tokenize_with_spans(input).unwrap();
+        let spans: Vec<Span> = tokens.iter().map(|(_, span)| *span).collect();
+        assert_eq!(spans, vec![
+            Span::new(0, 1),
+            Span::new(1, 4),
+            Span::new(5, 10),
+            Span::new(10, 11),
+            Span::new(12, 13),
+            Span::new(13, 14),
+        ]);
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "; header\n(+ 1 ; one\n 2)";
+        let (tokens, comments) = tokenize_with_comments(input).unwrap();
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(comments, vec![
+            Comment { text: "; header".to_string(), span: Span::new(0, 8) },
+            Comment { text: "; one".to_string(), span: Span::new(14, 19) },
+        ]);
+    }
+
     #[test]
     fn test_quote_tokens() {
         let input = "'(+ 1 2)";
@@ -179,6 +318,22 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_generic_symbols() {
+        let tokens = tokenize("(pair<A,B> `(<,x))").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LeftParen,
+            Token::Symbol("pair<A,B>".to_string()),
+            Token::Quasiquote,
+            Token::LeftParen,
+            Token::Symbol("<".to_string()),
+            Token::Unquote,
+            Token::Symbol("x".to_string()),
+            Token::RightParen,
+            Token::RightParen,
+        ]);
+    }
+
     #[test]
     fn test_quasiquote_tokens() {
         let input = "`(+ ,x ,(* 2 3))";
@@ -230,4 +385,23 @@ mod tests {
             Token::RightParen,
         ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_limits() {
+        let tokens = |input: &str, limits: LexerLimits| tokenize_with_limits(input, limits).map(|(tokens, _)| tokens.len());
+        let input = "(print \"abc\\n\" long-name)\n(+ 12345 1)";
+        assert_eq!(tokens(input, LexerLimits::unlimited()), Ok(10));
+        assert_eq!(tokens(input, LexerLimits::sandbox()), Ok(10));
+
+        let limits = LexerLimits::unlimited().with_max_token_length(9).with_max_tokens(10).with_max_string_size(4);
+        assert_eq!(tokens(input, limits), Ok(10));
+        assert_eq!(tokens(input, limits.with_max_token_length(8)).unwrap_err().message(), "Token longer than the limit of 8 bytes at 1:16 (bytes 15..24)");
+        assert_eq!(tokens(input, limits.with_max_token_length(4)).unwrap_err().message(), "Token longer than the limit of 4 bytes at 1:2 (bytes 1..6)");
+        assert_eq!(tokens(input, limits.with_max_string_size(3)).unwrap_err().message(), "String longer than the limit of 3 bytes at 1:8 (bytes 7..14)");
+        assert_eq!(tokens(input, limits.with_max_tokens(9)).unwrap_err().message(), "More than the limit of 9 tokens at 2:11 (bytes 36..37)");
+
+        let defaults = LexerLimits::sandbox();
+        assert_eq!(limits.or(defaults), limits);
+        assert_eq!(LexerLimits::unlimited().with_max_tokens(5).or(defaults), LexerLimits { max_tokens: Some(5), ..defaults });
+    }
+}
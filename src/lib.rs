@@ -0,0 +1,50 @@
+pub mod lexer;
+pub mod parser;
+pub mod pattern;
+pub mod pipeline;
+pub mod provenance;
+pub mod c;
+pub mod compiler;
+pub mod contracts;
+pub mod crates;
+pub mod debugger;
+pub mod declarations;
+pub mod deps;
+pub mod explain;
+pub mod features;
+pub mod include;
+pub mod ast;
+pub mod backend;
+pub mod builder;
+pub mod bytecode;
+pub mod formatter;
+pub mod interpreter;
+pub mod ir;
+pub mod ir_schema;
+pub mod lambda_list;
+pub mod library;
+pub mod lint;
+pub mod mangle;
+pub mod logging;
+pub mod macro_expander;
+pub mod numeric;
+pub mod transform;
+pub mod validator;
+pub mod sandbox;
+pub mod capabilities;
+pub mod manifest;
+pub mod visualizer;
+pub mod vm;
+pub mod repl;
+pub mod report;
+pub mod runner;
+pub mod server;
+pub mod source_map;
+pub mod structs;
+pub mod suggest;
+pub mod symbol;
+pub mod testing;
+pub mod traits;
+pub mod types;
+pub mod wasm;
+pub mod wat;
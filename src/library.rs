@@ -0,0 +1,139 @@
+//! Precompiled libraries.
+//!
+//! `compile-lib` reads a library of macros and functions once and saves its macro
+//! definitions and its other forms, already expanded, as a bundle of two IR documents.
+//! A program built with `--lib bundle` starts with the library's macros defined and
+//! its forms compiled ahead of the program's own, without parsing or expanding the
+//! library again. A library whose source starts with `(defmodule name (export ...))`
+//! keeps its macros in that module, so the program calls them as `name:macro`, or
+//! unqualified when no macro of its own shadows them. Bundles go through the IR version checks and migrations when loaded.
+
+use crate::ast::LispExpr;
+use crate::explain::CompileError;
+use crate::ir::{self, IrDocument};
+use crate::macro_expander::{MacroExpander, DEFMODULE};
+use serde_json::{json, Value};
+
+/// Identifies a library bundle
+pub const LIBRARY_FORMAT: &str = "rusty-lisp-library";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Library {
+    /// The `defmacro` forms as written, after the `defmodule` form if there is one
+    pub macros: Vec<LispExpr>,
+    /// The other forms, expanded
+    pub forms: Vec<LispExpr>,
+}
+
+impl Library {
+    /// Expand a library's `program`, after defining the macros of the libraries it depends on
+    pub fn compile(program: Vec<LispExpr>, dependencies: &[Library]) -> Result<Self, CompileError> {
+        let mut expander = MacroExpander::new();
+        for dependency in dependencies {
+            dependency.define_macros(&mut expander)?;
+        }
+        let mut library = Library { macros: Vec::new(), forms: Vec::new() };
+        for expr in program {
+            if is_definition(&expr) {
+                library.macros.push(expr.clone());
+            }
+            match expander.expand_all(expr)? {
+                LispExpr::Nil => {}
+                expanded => library.forms.push(expanded),
+            }
+        }
+        Ok(library)
+    }
+
+    /// Define the library's macros in `expander`
+    pub fn define_macros(&self, expander: &mut MacroExpander) -> Result<(), CompileError> {
+        for definition in &self.macros {
+            expander.expand_all(definition.clone())?;
+        }
+        // The program and later libraries define their macros outside this library's module
+        expander.end_module();
+        Ok(())
+    }
+
+    /// Names of the library's macros
+    pub fn macro_names(&self) -> impl Iterator<Item = &str> {
+        self.macros.iter().filter_map(|definition| match definition {
+            LispExpr::Macro { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// `{"format": "rusty-lisp-library", "macros": <IR>, "forms": <IR>}`
+    pub fn to_json(&self) -> Result<String, String> {
+        let document = |exprs: &[LispExpr]| serde_json::to_value(IrDocument::new(exprs.to_vec()));
+        let bundle = json!({
+            "format": LIBRARY_FORMAT,
+            "macros": document(&self.macros).map_err(|e| format!("JSON serialization error: {}", e))?,
+            "forms": document(&self.forms).map_err(|e| format!("JSON serialization error: {}", e))?,
+        });
+        serde_json::to_string(&bundle).map_err(|e| format!("JSON serialization error: {}", e))
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, String> {
+        let mut bundle: Value = serde_json::from_str(source).map_err(|e| format!("JSON deserialization error: {}", e))?;
+        if bundle["format"] != LIBRARY_FORMAT {
+            return Err(format!("Not a library compiled with compile-lib (no \"format\": \"{}\")", LIBRARY_FORMAT));
+        }
+        let macros = ir::from_value(bundle["macros"].take())?;
+        if let Some(form) = macros.iter().find(|expr| !is_definition(expr)) {
+            return Err(format!("Library macros must be defmacro or defmodule forms: {}", form));
+        }
+        Ok(Library { macros, forms: ir::from_value(bundle["forms"].take())? })
+    }
+}
+
+/// Whether `expr` is kept with the library's macros: a `defmacro`, or the
+/// `defmodule` form that puts the macros after it in a module
+fn is_definition(expr: &LispExpr) -> bool {
+    match expr {
+        LispExpr::Macro { .. } => true,
+        LispExpr::List(elements) => elements.first().and_then(LispExpr::as_symbol) == Some(DEFMODULE),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_library_round_trip() {
+        let base = Library::compile(parse(tokenize("(defmacro twice (x) `(+ ,x ,x))").unwrap()).unwrap(), &[]).unwrap();
+        let source = "(defmacro quad (x) `(twice (twice ,x)))\n(defun eight () (twice 4))";
+        let library = Library::compile(parse(tokenize(source).unwrap()).unwrap(), std::slice::from_ref(&base)).unwrap();
+        assert_eq!(library.macro_names().collect::<Vec<_>>(), vec!["quad"]);
+        assert_eq!(library.forms.iter().map(|form| form.to_string()).collect::<Vec<_>>(), vec!["(defun eight () (+ 4 4))"]);
+
+        let loaded = Library::from_json(&library.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, library);
+        let mut expander = MacroExpander::new();
+        base.define_macros(&mut expander).unwrap();
+        loaded.define_macros(&mut expander).unwrap();
+        let expanded = expander.expand_all(parse(tokenize("(quad 1)").unwrap()).unwrap().remove(0)).unwrap();
+        assert_eq!(expanded.to_string(), "(+ (+ 1 1) (+ 1 1))");
+
+        // A module library's macros are reached through its exports
+        let source = "(defmodule shapes (export square))\n(defmacro times (a b) `(* ,a ,b))\n(defmacro square (x) `(times ,x ,x))\n(defun nine () (square 3))";
+        let library = Library::compile(parse(tokenize(source).unwrap()).unwrap(), &[]).unwrap();
+        assert_eq!(library.forms.iter().map(|form| form.to_string()).collect::<Vec<_>>(), vec!["(defun nine () (* 3 3))"]);
+        let loaded = Library::from_json(&library.to_json().unwrap()).unwrap();
+        let mut expander = MacroExpander::new();
+        loaded.define_macros(&mut expander).unwrap();
+        expander.expand_all(parse(tokenize("(defmacro quad (x) `(* 4 ,x))").unwrap()).unwrap().remove(0)).unwrap();
+        assert_eq!(expander.macro_names(), vec!["quad", "shapes::square", "shapes::times"]);
+        let expanded = expander.expand_all(parse(tokenize("(shapes:square (square 2))").unwrap()).unwrap().remove(0)).unwrap();
+        assert_eq!(expanded.to_string(), "(* (* 2 2) (* 2 2))");
+
+        assert_eq!(
+            Library::from_json("{\"version\": \"1.2\", \"exprs\": []}").unwrap_err(),
+            "Not a library compiled with compile-lib (no \"format\": \"rusty-lisp-library\")"
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//! Lints: diagnostics about code that works but should change.
+//!
+//! Each lint has a level: allowed lints are not reported, warned ones are reported
+//! as warnings, and denied ones as errors. `warnings` sets the level of every lint
+//! not named on its own. `deprecated` reports uses of definitions that
+//! `(declare (deprecated ...))`, and `shadowed-builtins` reports macros, functions,
+//! and variables named after built-in forms, which macro hygiene and code generation
+//! go on treating as the built-ins.
+
+use crate::ast::{grow_stack, LispExpr, Span, SpanTree};
+use crate::compiler::BUILTIN_CALLS;
+use crate::declarations::{self, Deprecation};
+use crate::deps::DefinitionKind;
+use crate::interpreter::{BUILTINS, SPECIAL_FORMS};
+use crate::lambda_list::LambdaList;
+use crate::macro_expander::{DEFINE_COMPILER_MACRO, DEFMODULE, HYGIENE_BUILTINS, MACRO_IF};
+use crate::pattern::Pattern;
+use crate::symbol::Symbol;
+use crate::types;
+use crate::validator::{Applicability, Severity, SuggestedFix, ValidationError, ValidationRule};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// Every lint
+    Warnings,
+    /// Calls to deprecated functions, macros, and struct constructors
+    Deprecated,
+    /// Macros, functions, parameters, and variables named after built-in forms
+    ShadowedBuiltins,
+}
+
+impl Lint {
+    /// Parse a lint name as given on the command line
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "warnings" => Ok(Lint::Warnings),
+            "deprecated" => Ok(Lint::Deprecated),
+            "shadowed-builtins" => Ok(Lint::ShadowedBuiltins),
+            other => Err(format!("Unknown lint '{}' (expected warnings, deprecated, or shadowed-builtins)", other)),
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lint::Warnings => write!(f, "warnings"),
+            Lint::Deprecated => write!(f, "deprecated"),
+            Lint::ShadowedBuiltins => write!(f, "shadowed-builtins"),
+        }
+    }
+}
+
+/// Ordered from least to most strict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl LintLevel {
+    /// The severity findings of a lint at this level are reported with, if they are reported
+    pub fn severity(self) -> Option<Severity> {
+        match self {
+            LintLevel::Allow => None,
+            LintLevel::Warn => Some(Severity::Warning),
+            LintLevel::Deny => Some(Severity::Error),
+        }
+    }
+}
+
+/// The level of each lint; lints not set are warnings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LintLevels {
+    levels: HashMap<Lint, LintLevel>,
+}
+
+impl LintLevels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `lint` to `level`, unless it is already set to a stricter one
+    pub fn with_level(mut self, lint: Lint, level: LintLevel) -> Self {
+        let current = self.levels.entry(lint).or_insert(level);
+        *current = (*current).max(level);
+        self
+    }
+
+    /// The level set for `lint`, or else for `warnings`
+    pub fn level(&self, lint: Lint) -> LintLevel {
+        self.levels
+            .get(&lint)
+            .or_else(|| self.levels.get(&Lint::Warnings))
+            .copied()
+            .unwrap_or(LintLevel::Warn)
+    }
+}
+
+/// A lint finding, at the severity its lint's level gives it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintFinding {
+    pub lint: Lint,
+    pub error: ValidationError,
+    pub span: Option<Span>,
+}
+
+/// Every finding of every lint not allowed by `levels`, lint by lint in source order
+pub fn lint_program(program: &[LispExpr], trees: Option<&[SpanTree]>, levels: &LintLevels) -> Vec<LintFinding> {
+    let deprecated = deprecated_uses(program, trees).into_iter().filter_map(|deprecated| {
+        let error = deprecated.to_error(levels.level(Lint::Deprecated))?;
+        Some(LintFinding { lint: Lint::Deprecated, error, span: deprecated.span })
+    });
+    let shadowed = shadowed_builtins(program, trees).into_iter().filter_map(|shadowed| {
+        let error = shadowed.to_error(levels.level(Lint::ShadowedBuiltins))?;
+        Some(LintFinding { lint: Lint::ShadowedBuiltins, error, span: shadowed.span })
+    });
+    deprecated.chain(shadowed).collect()
+}
+
+/// A call to a definition declared `deprecated`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeprecatedUse {
+    /// The name called, e.g. `make-point` for a deprecated struct `point`
+    pub name: String,
+    pub kind: DefinitionKind,
+    pub note: Option<String>,
+    /// Where the call is in the source, when spans are known
+    pub span: Option<Span>,
+    /// The call as written
+    pub call: LispExpr,
+}
+
+impl DeprecatedUse {
+    /// The name to call instead, when the note is `"use <name>"`; for a struct, its constructor
+    pub fn replacement(&self) -> Option<String> {
+        let name = self.note.as_deref()?.strip_prefix("use ")?.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        Some(match self.kind {
+            DefinitionKind::Struct => format!("make-{}", name),
+            _ => name.to_string(),
+        })
+    }
+
+    /// The finding for this use at `level`, or `None` when the lint is allowed
+    pub fn to_error(&self, level: LintLevel) -> Option<ValidationError> {
+        let note = self.note.as_ref().map(|note| format!(": {}", note)).unwrap_or_default();
+        let replacement = self.replacement();
+        let fix = replacement.as_deref().and_then(|replacement| {
+            let mut elements = self.call.as_list()?.to_vec();
+            elements[0] = LispExpr::Symbol(replacement.into());
+            Some(Box::new(SuggestedFix::new(self.call.clone(), LispExpr::List(elements.into()), Applicability::MaybeIncorrect)))
+        });
+        Some(ValidationError {
+            rule: ValidationRule::Deprecated,
+            severity: level.severity()?,
+            message: format!("{} '{}' is deprecated{}", capitalized(self.kind), self.name, note),
+            context: Some(self.call.to_string()),
+            suggestion: replacement.map(|replacement| format!("Call '{}' instead", replacement)),
+            fix,
+        })
+    }
+}
+
+fn capitalized(kind: DefinitionKind) -> String {
+    let kind = kind.to_string();
+    kind[..1].to_uppercase() + &kind[1..]
+}
+
+/// Every call in `program` to a definition it declares deprecated, in source order.
+/// `trees`, when given, holds the span tree of each top-level form. Uses inside the
+/// deprecated definition itself, and inside quoted data, are not reported.
+pub fn deprecated_uses(program: &[LispExpr], trees: Option<&[SpanTree]>) -> Vec<DeprecatedUse> {
+    let mut deprecated: HashMap<String, (DefinitionKind, Deprecation)> = HashMap::new();
+    for expr in program {
+        let Some(declared) = declarations::declared(expr) else { continue };
+        let Some(deprecation) = declared.declarations().ok().and_then(|declarations| declarations.deprecated) else {
+            continue;
+        };
+        let name = match declared.kind {
+            DefinitionKind::Struct => format!("make-{}", declared.name),
+            _ => declared.name.to_string(),
+        };
+        deprecated.insert(name, (declared.kind, deprecation));
+    }
+
+    let mut uses = Vec::new();
+    if deprecated.is_empty() {
+        return uses;
+    }
+    for (index, expr) in program.iter().enumerate() {
+        let defining = declarations::declared(expr).map(|declared| declared.name);
+        let tree = trees.and_then(|trees| trees.get(index));
+        collect_uses(expr, tree, defining, &deprecated, &mut uses);
+    }
+    uses
+}
+
+fn collect_uses(
+    expr: &LispExpr,
+    tree: Option<&SpanTree>,
+    defining: Option<&str>,
+    deprecated: &HashMap<String, (DefinitionKind, Deprecation)>,
+    uses: &mut Vec<DeprecatedUse>,
+) {
+    let called = match expr {
+        LispExpr::List(elements) => elements.first().and_then(|head| head.as_symbol()),
+        LispExpr::MacroCall { name, .. } => Some(name.as_str()),
+        LispExpr::Quote(_) => return,
+        _ => None,
+    };
+    if let Some((name, (kind, deprecation))) = called.and_then(|name| deprecated.get_key_value(name))
+        && defining.is_none_or(|defining| *name != defining && *name != format!("make-{}", defining))
+    {
+        uses.push(DeprecatedUse {
+            name: name.clone(),
+            kind: *kind,
+            note: deprecation.note.clone(),
+            span: tree.and_then(|tree| tree.span),
+            call: expr.clone(),
+        });
+    }
+    grow_stack(|| {
+        for (index, child) in expr.children().into_iter().enumerate() {
+            collect_uses(child, tree.and_then(|tree| tree.children.get(index)), defining, deprecated, uses);
+        }
+    });
+}
+
+/// What a name shadowing a built-in was bound as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingKind {
+    Macro,
+    Function,
+    Parameter,
+    Variable,
+}
+
+impl fmt::Display for BindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindingKind::Macro => write!(f, "macro"),
+            BindingKind::Function => write!(f, "function"),
+            BindingKind::Parameter => write!(f, "parameter"),
+            BindingKind::Variable => write!(f, "variable"),
+        }
+    }
+}
+
+/// Whether `name` is a special form or built-in function of the interpreter, the
+/// compiler, or macro hygiene. Lambda-list markers and `:` cannot be bound.
+pub fn is_builtin(name: &str) -> bool {
+    !name.starts_with('&')
+        && name != ":"
+        && (["defmacro", DEFMODULE, DEFINE_COMPILER_MACRO, MACRO_IF].contains(&name) || [SPECIAL_FORMS, BUILTINS, BUILTIN_CALLS, HYGIENE_BUILTINS].iter().any(|names| names.contains(&name)))
+}
+
+/// A macro, function, parameter, or variable named after a built-in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowedBuiltin {
+    pub name: String,
+    pub kind: BindingKind,
+    /// Where the binding form is in the source, when spans are known
+    pub span: Option<Span>,
+    /// The form that binds the name: the definition, `lambda`, or `let`
+    pub form: LispExpr,
+}
+
+impl ShadowedBuiltin {
+    /// The finding for this binding at `level`, or `None` when the lint is allowed
+    pub fn to_error(&self, level: LintLevel) -> Option<ValidationError> {
+        let kind = self.kind.to_string();
+        Some(ValidationError {
+            rule: ValidationRule::ShadowedBuiltin,
+            severity: level.severity()?,
+            message: format!("{}{} '{}' shadows the built-in '{}'", kind[..1].to_uppercase(), &kind[1..], self.name, self.name),
+            context: Some(self.form.to_string()),
+            suggestion: Some(format!("Rename the {}; macro expansion and code generation still treat '{}' as the built-in", kind, self.name)),
+            fix: None,
+        })
+    }
+}
+
+/// Every macro, function, function parameter, and variable in `program` named after a
+/// built-in, in source order. `trees`, when given, holds the span tree of each top-level form.
+/// Bindings inside quoted data are not reported.
+pub fn shadowed_builtins(program: &[LispExpr], trees: Option<&[SpanTree]>) -> Vec<ShadowedBuiltin> {
+    let mut shadowed = Vec::new();
+    for (index, expr) in program.iter().enumerate() {
+        collect_shadowed(expr, trees.and_then(|trees| trees.get(index)), &mut shadowed);
+    }
+    shadowed
+}
+
+fn collect_shadowed(expr: &LispExpr, tree: Option<&SpanTree>, shadowed: &mut Vec<ShadowedBuiltin>) {
+    let mut report = |name: &str, kind: BindingKind| {
+        if is_builtin(name) {
+            shadowed.push(ShadowedBuiltin { name: name.to_string(), kind, span: tree.and_then(|tree| tree.span), form: expr.clone() });
+        }
+    };
+    match expr {
+        LispExpr::Quote(_) => return,
+        // A macro's parameters are substituted into its template, so they never reach
+        // the generated code to shadow anything
+        LispExpr::Macro { name, .. } => report(name, BindingKind::Macro),
+        LispExpr::List(elements) => match &elements[..] {
+            [head, LispExpr::Symbol(name), LispExpr::List(parameters), ..] if head.as_symbol() == Some("defun") => {
+                report(types::split_generics(name).0, BindingKind::Function);
+                parameter_names(parameters).iter().for_each(|parameter| report(parameter, BindingKind::Parameter));
+            }
+            [head, LispExpr::List(signature), ..] if head.as_symbol() == Some("define") => {
+                if let Some((LispExpr::Symbol(name), parameters)) = signature.split_first() {
+                    report(name, BindingKind::Function);
+                    parameter_names(parameters).iter().for_each(|parameter| report(parameter, BindingKind::Parameter));
+                }
+            }
+            [head, LispExpr::Symbol(name), ..] if head.as_symbol() == Some("define") => report(name, BindingKind::Variable),
+            [head, LispExpr::List(parameters), ..] if matches!(head.as_symbol(), Some("lambda" | "fn")) => {
+                parameter_names(parameters).iter().for_each(|parameter| report(parameter, BindingKind::Parameter));
+            }
+            [head, LispExpr::List(bindings), ..] if matches!(head.as_symbol(), Some("let" | "let*" | "letrec")) => {
+                for binding in bindings.iter() {
+                    if let Some([pattern, _]) = binding.as_list() {
+                        pattern_names(pattern).iter().for_each(|variable| report(variable, BindingKind::Variable));
+                    }
+                }
+            }
+            [head, pattern, ..] if head.as_symbol() == Some("destructuring-bind") => {
+                pattern_names(pattern).iter().for_each(|variable| report(variable, BindingKind::Variable));
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+    grow_stack(|| {
+        for (index, child) in expr.children().into_iter().enumerate() {
+            collect_shadowed(child, tree.and_then(|tree| tree.children.get(index)), shadowed);
+        }
+    });
+}
+
+/// The variables a lambda list binds; malformed lists are left for validation to report
+fn parameter_names(parameters: &[LispExpr]) -> Vec<Symbol> {
+    LambdaList::parse(parameters).map(|list| list.variables()).unwrap_or_default()
+}
+
+fn pattern_names(pattern: &LispExpr) -> Vec<Symbol> {
+    Pattern::parse(pattern).map(|pattern| pattern.variables()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize_with_spans;
+    use crate::parser::parse_with_span_trees;
+
+    #[test]
+    fn test_lint_levels() {
+        let levels = LintLevels::new();
+        assert_eq!(levels.level(Lint::Deprecated), LintLevel::Warn);
+        let levels = levels.with_level(Lint::Warnings, LintLevel::Deny);
+        assert_eq!(levels.level(Lint::Deprecated), LintLevel::Deny);
+        let levels = levels.with_level(Lint::Deprecated, LintLevel::Allow);
+        assert_eq!(levels.level(Lint::Deprecated), LintLevel::Allow);
+        // The strictest level given for a lint wins
+        let levels = levels.with_level(Lint::Deprecated, LintLevel::Warn).with_level(Lint::Deprecated, LintLevel::Allow);
+        assert_eq!(levels.level(Lint::Deprecated), LintLevel::Warn);
+        assert_eq!(Lint::parse("unused").unwrap_err(), "Unknown lint 'unused' (expected warnings, deprecated, or shadowed-builtins)");
+        assert_eq!(Lint::parse("shadowed-builtins").unwrap().to_string(), "shadowed-builtins");
+    }
+
+    #[test]
+    fn test_deprecated_uses() {
+        let source = "(defun old (x) (declare (deprecated \"use new\")) (old x))\n\
+                      (defmacro twice (x) (declare (deprecated)) `(+ ,x ,x))\n\
+                      (defstruct point (x) (declare (deprecated \"use vec\")))\n\
+                      (print (old 1) '(old 2) (twice 3) (make-point 4))";
+        let (program, trees): (Vec<LispExpr>, Vec<SpanTree>) = parse_with_span_trees(tokenize_with_spans(source).unwrap()).unwrap().into_iter().unzip();
+        let uses = deprecated_uses(&program, Some(&trees));
+        let found: Vec<(&str, &str)> = uses.iter().map(|u| (u.name.as_str(), &source[u.span.unwrap().start..u.span.unwrap().end])).collect();
+        assert_eq!(found, vec![("old", "(old 1)"), ("twice", "(twice 3)"), ("make-point", "(make-point 4)")]);
+
+        let error = uses[0].to_error(LintLevel::Warn).unwrap();
+        assert_eq!((error.severity, error.message.as_str()), (Severity::Warning, "Function 'old' is deprecated: use new"));
+        assert_eq!(error.suggestion.as_deref(), Some("Call 'new' instead"));
+        assert_eq!(error.fix.unwrap().replacement.to_string(), "(new 1)");
+        let error = uses[1].to_error(LintLevel::Deny).unwrap();
+        assert_eq!((error.severity, error.message.as_str(), error.fix), (Severity::Error, "Macro 'twice' is deprecated", None));
+        assert_eq!(uses[2].replacement().as_deref(), Some("make-vec"));
+        assert!(uses[2].to_error(LintLevel::Allow).is_none());
+    }
+
+    #[test]
+    fn test_shadowed_builtins() {
+        let source = "(defmacro if (c a b) `(cond (,c ,a) (t ,b)))\n\
+                      (defmacro swap (list x) `(,x ,list))\n\
+                      (defmacro my-sum (first &rest rest) `(+ ,first ,@rest))\n\
+                      (defun + (a &optional (not 1)) a)\n\
+                      (let ((total 0) ((car more) '(1 2))) (lambda (print) '(let ((if 1)) if)))\n\
+                      (define (area r) (* r r))";
+        let (program, trees): (Vec<LispExpr>, Vec<SpanTree>) = parse_with_span_trees(tokenize_with_spans(source).unwrap()).unwrap().into_iter().unzip();
+        let shadowed = shadowed_builtins(&program, Some(&trees));
+        let found: Vec<(&str, BindingKind)> = shadowed.iter().map(|s| (s.name.as_str(), s.kind)).collect();
+        assert_eq!(
+            found,
+            vec![
+                ("if", BindingKind::Macro),
+                ("+", BindingKind::Function),
+                ("not", BindingKind::Parameter),
+                ("car", BindingKind::Variable),
+                ("print", BindingKind::Parameter),
+            ]
+        );
+        let span = shadowed[4].span.unwrap();
+        assert_eq!(&source[span.start..span.end], "(lambda (print) '(let ((if 1)) if))");
+
+        let error = shadowed[0].to_error(LintLevel::Warn).unwrap();
+        assert_eq!((error.severity, error.message.as_str()), (Severity::Warning, "Macro 'if' shadows the built-in 'if'"));
+        assert_eq!(shadowed[3].to_error(LintLevel::Deny).unwrap().severity, Severity::Error);
+        assert!(shadowed[3].to_error(LintLevel::Allow).is_none());
+
+        let levels = LintLevels::new().with_level(Lint::Deprecated, LintLevel::Allow);
+        let findings = lint_program(&program, Some(&trees), &levels);
+        assert_eq!(findings.len(), 5);
+        assert!(findings.iter().all(|finding| finding.lint == Lint::ShadowedBuiltins));
+        assert!(!is_builtin("&rest") && !is_builtin("area") && is_builtin("defmacro"));
+    }
+}
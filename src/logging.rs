@@ -0,0 +1,163 @@
+//! Leveled messages from the command line and the pipeline, written to stderr as
+//! text or as one JSON object per line.
+//!
+//! The level and format are process-wide, set once by `init`. Pipeline stages log
+//! at `Debug` (a summary per stage, with timings) and `Trace` (each transform and
+//! macro expansion), so they are silent unless asked for.
+
+use serde_json::{Map, Value};
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// `Error` when quiet, otherwise `Info` and one level more per `-v`
+    pub fn from_verbosity(quiet: bool, verbose: u8) -> Self {
+        if quiet {
+            return Level::Error;
+        }
+        Level::ALL[(Level::Info as usize + verbose as usize).min(Level::ALL.len() - 1)]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format '{}' (expected text or json)", other)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicU8 = AtomicU8::new(0);
+
+/// Set the most detailed level that is written, and how messages are written
+pub fn init(level: Level, format: LogFormat) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(u8::from(format == LogFormat::Json), Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are written; check before building costly messages
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+fn format() -> LogFormat {
+    if JSON.load(Ordering::Relaxed) == 1 { LogFormat::Json } else { LogFormat::Text }
+}
+
+pub fn error(message: impl Display) {
+    log(Level::Error, None, message, &[]);
+}
+
+pub fn warn(message: impl Display) {
+    log(Level::Warn, None, message, &[]);
+}
+
+pub fn info(message: impl Display) {
+    log(Level::Info, None, message, &[]);
+}
+
+/// A pipeline stage's summary, with machine-readable `fields` for the JSON format
+pub fn debug(stage: &str, message: impl Display, fields: &[(&str, Value)]) {
+    log(Level::Debug, Some(stage), message, fields);
+}
+
+/// A single step within a pipeline stage
+pub fn trace(stage: &str, message: impl Display, fields: &[(&str, Value)]) {
+    log(Level::Trace, Some(stage), message, fields);
+}
+
+pub fn log(level: Level, stage: Option<&str>, message: impl Display, fields: &[(&str, Value)]) {
+    if !enabled(level) {
+        return;
+    }
+    let line = format_event(format(), level, stage, &message.to_string(), fields);
+    let _ = writeln!(std::io::stderr().lock(), "{}", line);
+}
+
+/// Text keeps errors and notes as written, prefixes warnings with `Warning:`, and
+/// tags stage messages as `[debug] stage:`; JSON puts `fields` next to `level`,
+/// `stage`, and `message`
+pub fn format_event(format: LogFormat, level: Level, stage: Option<&str>, message: &str, fields: &[(&str, Value)]) -> String {
+    match format {
+        LogFormat::Text => match (level, stage) {
+            (Level::Warn, _) => format!("Warning: {}", message),
+            (Level::Debug | Level::Trace, Some(stage)) => format!("[{}] {}: {}", level.name(), stage, message),
+            _ => message.to_string(),
+        },
+        LogFormat::Json => {
+            let mut event = Map::new();
+            event.insert("level".to_string(), level.name().into());
+            if let Some(stage) = stage {
+                event.insert("stage".to_string(), stage.into());
+            }
+            event.insert("message".to_string(), message.into());
+            for (key, value) in fields {
+                event.insert(key.to_string(), value.clone());
+            }
+            Value::Object(event).to_string()
+        }
+    }
+}
+
+/// Microseconds, the unit of the `elapsed_us` field stage summaries carry
+pub fn micros(elapsed: Duration) -> Value {
+    Value::from(elapsed.as_micros() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(Level::from_verbosity(false, 0), Level::Info);
+        assert_eq!(Level::from_verbosity(false, 2), Level::Trace);
+        assert_eq!(Level::from_verbosity(false, 9), Level::Trace);
+        assert_eq!(Level::from_verbosity(true, 0), Level::Error);
+        assert!(Level::Warn < Level::Debug);
+        assert!(LogFormat::parse("yaml").is_err());
+    }
+
+    #[test]
+    fn test_format_event() {
+        let text = |level, stage| format_event(LogFormat::Text, level, stage, "3 forms", &[]);
+        assert_eq!(text(Level::Error, None), "3 forms");
+        assert_eq!(text(Level::Warn, None), "Warning: 3 forms");
+        assert_eq!(text(Level::Debug, Some("parser")), "[debug] parser: 3 forms");
+
+        let json = format_event(LogFormat::Json, Level::Trace, Some("parser"), "3 forms", &[("forms", 3.into())]);
+        assert_eq!(json, r#"{"forms":3,"level":"trace","message":"3 forms","stage":"parser"}"#);
+    }
+}
@@ -1,12 +1,54 @@
-use crate::ast::LispExpr;
+use crate::ast::{fold_children, grow_stack, take_list, take_rc, walk_expr, Fold, LispExpr, Visitor};
+use crate::explain::CompileError;
+use crate::interpreter::{Environment, Interpreter, Value};
+use crate::lambda_list;
+use crate::logging;
+use crate::pattern::{MatchError, Pattern, PatternError};
+use crate::sandbox::{Capability, SandboxConfig, SandboxMonitor, SandboxViolation};
+use crate::symbol::Symbol;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Built-in forms a macro's template may use without them being renamed for hygiene
+pub const HYGIENE_BUILTINS: &[&str] = &[
+    "let", "let*", "letrec", "let-values", "values", "destructuring-bind", "defstruct", "deftrait", "defimpl", "require-crate", ":", "&rest", "&optional", "&key", "if", "define", "lambda", "quote", "quasiquote", "unquote", "unquote-splicing",
+    "+", "-", "*", "/", "=", "<", ">", "<=", ">=",
+    "and", "or", "not", "list", "car", "cdr", "cons",
+    "set!", "begin", "progn", "assert",
+    "string->number", "number->string", "symbol->string", "to-bool", "read-file", "write-file", "http-get", "http-post", "run-command", "getenv",
+    "now", "elapsed", "random", "random-int", "json-parse", "json-get", "json-stringify",
+];
+
+/// Conditional evaluated while expanding a macro: `(macro-if condition then [else])`
+pub const MACRO_IF: &str = "macro-if";
+
+/// Starts a module of macros: `(defmodule name [(export macro ...)])`
+pub const DEFMODULE: &str = "defmodule";
+
+/// Attaches a compiler macro to a function: `(define-compiler-macro name (parameter ...) body ...)`
+pub const DEFINE_COMPILER_MACRO: &str = "define-compiler-macro";
 
 #[derive(Debug)]
 pub struct MacroExpander {
     macros: HashMap<String, MacroDefinition>,
-    expansion_depth: usize,
+    /// Modules in the order they were declared, which decides the shadowing between
+    /// their exports
+    modules: Vec<Module>,
+    /// The module `defmacro` defines macros in, since its `defmodule` form
+    current_module: Option<String>,
+    /// Rewrites of calls to functions, applied by `apply_compiler_macros`
+    compiler_macros: HashMap<String, MacroDefinition>,
+    chain: CallChain,
     max_depth: usize,
     gensym_counter: usize,
+    trace: Option<Vec<ExpansionStep>>,
+    expansion_count: usize,
+    deadline: Option<Instant>,
+    allocation_limit: Option<usize>,
+    /// Estimated bytes of every expansion so far, counted with an allocation limit
+    allocated: usize,
+    sandbox: Option<SandboxMonitor>,
 }
 
 #[derive(Debug, Clone)]
@@ -14,6 +56,52 @@ pub struct MacroDefinition {
     pub name: String,
     pub parameters: Vec<String>,
     pub body: LispExpr,
+    /// The module the macro was defined in, if any
+    pub module: Option<String>,
+}
+
+/// A namespace of macros started by `defmodule`
+#[derive(Debug, Clone)]
+struct Module {
+    name: String,
+    /// The macros reachable from outside as `module:name` or unqualified; every
+    /// macro of the module without an export list
+    exports: Option<Vec<String>>,
+}
+
+impl Module {
+    fn exports(&self, name: &str) -> bool {
+        self.exports.as_ref().is_none_or(|exports| exports.iter().any(|export| export == name))
+    }
+}
+
+/// The key a module's macro is registered under, which is also how it is called
+/// from anywhere: `module::name`
+fn qualified(module: &str, name: &str) -> String {
+    format!("{}::{}", module, name)
+}
+
+impl MacroDefinition {
+    /// How a call to the macro looks, e.g. `(with-retry attempts &rest body)`
+    pub fn signature(&self) -> String {
+        let words: Vec<&str> = std::iter::once(self.name.as_str()).chain(self.parameters.iter().map(String::as_str)).collect();
+        format!("({})", words.join(" "))
+    }
+}
+
+/// One macro call rewritten by the expander, recorded when tracing is enabled
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpansionStep {
+    pub macro_name: String,
+    /// The call as written, e.g. `(swap a b)`
+    pub call: LispExpr,
+    /// The macro body after hygiene renaming and argument substitution, before
+    /// any macro calls it contains are expanded (those are later steps)
+    pub expansion: LispExpr,
+    /// Symbols the macro introduced and the gensyms they were renamed to
+    pub renames: Vec<(String, String)>,
+    /// Nesting level: 0 for calls in the source, 1 for calls produced by those, ...
+    pub depth: usize,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -34,6 +122,23 @@ pub enum MacroError {
         macro_name: String,
     },
 
+    /// The deadline passed before this call could be expanded
+    DeadlineExceeded {
+        macro_name: String,
+    },
+
+    /// Expanding this call took the expansions past the allocation limit
+    AllocationLimitExceeded {
+        macro_name: String,
+        allocated: usize,
+    },
+
+    /// Expanding this call broke the limits or capabilities of the sandbox
+    SandboxViolation {
+        macro_name: String,
+        violation: SandboxViolation,
+    },
+
     /// Generic expansion error with context
     ExpansionError {
         message: String,
@@ -51,47 +156,94 @@ pub enum MacroError {
         pattern: String,
         reason: String,
     },
+
+    /// A `module:name` call to a macro its module does not export
+    NotExported {
+        module: String,
+        macro_name: String,
+    },
 }
 
 impl std::fmt::Display for MacroError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             MacroError::UndefinedMacro(name) => {
-                write!(f, "Undefined macro: '{}'\n", name)?;
+                writeln!(f, "Undefined macro: '{}'", name)?;
                 write!(f, "  Help: Check that the macro is defined with 'defmacro' before use")
             }
             MacroError::ParameterCountMismatch { macro_name, expected, actual } => {
-                write!(f, "Parameter count mismatch in macro '{}'\n", macro_name)?;
-                write!(f, "  Expected: {} argument{}\n", expected, if *expected == 1 { "" } else { "s" })?;
-                write!(f, "  Got: {} argument{}\n", actual, if *actual == 1 { "" } else { "s" })?;
+                writeln!(f, "Parameter count mismatch in macro '{}'", macro_name)?;
+                writeln!(f, "  Expected: {} argument{}", expected, if *expected == 1 { "" } else { "s" })?;
+                writeln!(f, "  Got: {} argument{}", actual, if *actual == 1 { "" } else { "s" })?;
                 write!(f, "  Help: Check the macro definition and ensure you pass the correct number of arguments")
             }
             MacroError::MaxDepthExceeded { depth, macro_name } => {
-                write!(f, "Maximum expansion depth ({}) exceeded in macro '{}'\n", depth, macro_name)?;
-                write!(f, "  Help: This likely indicates infinite recursion in your macro expansion.\n")?;
-                write!(f, "        Check that recursive macros have a proper base case.")
+                writeln!(f, "Maximum expansion depth ({}) exceeded in macro '{}'", depth, macro_name)?;
+                writeln!(f, "  Help: This likely indicates infinite recursion in your macro expansion.")?;
+                writeln!(f, "        Check that recursive macros have a proper base case.")?;
+                write!(f, "        Recursive calls only count toward the limit when no argument shrinks.")
+            }
+            MacroError::DeadlineExceeded { macro_name } => {
+                write!(f, "Time limit reached before expanding macro '{}'", macro_name)
             }
+            MacroError::AllocationLimitExceeded { macro_name, allocated } => {
+                write!(f, "Allocation limit reached expanding macro '{}' ({} bytes expanded)", macro_name, allocated)
+            }
+            MacroError::SandboxViolation { macro_name, violation } => {
+                write!(f, "Sandbox violation expanding macro '{}': {}", macro_name, violation)
+            }
+            // Stages report macro errors as "Macro expansion error: ...", so the message
+            // carries no prefix of its own
             MacroError::ExpansionError { message, context } => {
-                write!(f, "Macro expansion error: {}", message)?;
+                f.write_str(message)?;
                 if let Some(ctx) = context {
                     write!(f, "\n  Context: {}", ctx)?;
                 }
                 Ok(())
             }
             MacroError::MalformedDefinition { macro_name, reason } => {
-                write!(f, "Malformed macro definition for '{}'\n", macro_name)?;
-                write!(f, "  Reason: {}\n", reason)?;
+                writeln!(f, "Malformed macro definition for '{}'", macro_name)?;
+                writeln!(f, "  Reason: {}", reason)?;
                 write!(f, "  Help: Check the syntax of your defmacro form")
             }
             MacroError::InvalidPattern { pattern, reason } => {
-                write!(f, "Invalid parameter pattern: '{}'\n", pattern)?;
-                write!(f, "  Reason: {}\n", reason)?;
+                writeln!(f, "Invalid parameter pattern: '{}'", pattern)?;
+                writeln!(f, "  Reason: {}", reason)?;
                 write!(f, "  Help: Valid patterns include simple parameters and &rest patterns")
             }
+            MacroError::NotExported { module, macro_name } => {
+                writeln!(f, "Macro '{}' is not exported by module '{}'", macro_name, module)?;
+                write!(f, "  Help: Add it to the module's (export ...) list, or call it as '{}::{}'", module, macro_name)
+            }
         }
     }
 }
 
+impl MacroError {
+    /// Stable error code, explained by `explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            MacroError::UndefinedMacro(_) => "L0201",
+            MacroError::ParameterCountMismatch { .. } => "L0202",
+            MacroError::MaxDepthExceeded { .. } => "L0203",
+            MacroError::DeadlineExceeded { .. } => "L0204",
+            MacroError::AllocationLimitExceeded { .. } => "L0205",
+            MacroError::SandboxViolation { .. } => "L0206",
+            MacroError::ExpansionError { .. } => "L0207",
+            MacroError::MalformedDefinition { .. } => "L0208",
+            MacroError::InvalidPattern { .. } => "L0209",
+            MacroError::NotExported { .. } => "L0210",
+        }
+    }
+}
+
+/// The error as a stage reports it, after "Macro expansion error: "
+impl From<MacroError> for CompileError {
+    fn from(error: MacroError) -> Self {
+        CompileError::new(error.code(), format!("Macro expansion error: {}", error))
+    }
+}
+
 impl std::error::Error for MacroError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
@@ -102,21 +254,89 @@ impl MacroExpander {
     pub fn new() -> Self {
         Self {
             macros: HashMap::new(),
-            expansion_depth: 0,
+            modules: Vec::new(),
+            current_module: None,
+            compiler_macros: HashMap::new(),
+            chain: CallChain::default(),
             max_depth: 100, // Prevent infinite recursion
             gensym_counter: 0,
+            trace: None,
+            expansion_count: 0,
+            deadline: None,
+            allocation_limit: None,
+            allocated: 0,
+            sandbox: None,
         }
     }
 
+    /// Fail a call once `max_depth` calls that make no progress are being expanded
+    /// within one another; see `CallChain`
     pub fn with_max_depth(max_depth: usize) -> Self {
         Self {
             macros: HashMap::new(),
-            expansion_depth: 0,
+            modules: Vec::new(),
+            current_module: None,
+            compiler_macros: HashMap::new(),
+            chain: CallChain::default(),
             max_depth,
             gensym_counter: 0,
+            trace: None,
+            expansion_count: 0,
+            deadline: None,
+            allocation_limit: None,
+            allocated: 0,
+            sandbox: None,
+        }
+    }
+
+    /// Record every expansion step, for visualizing how a program expands
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    /// Fail any macro call expanded after `deadline`, so runaway expansion stops
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fail the macro call whose expansion takes the estimated size of all expansions
+    /// so far (see `LispExpr::footprint`) past `bytes`, so runaway expansion stops
+    pub fn with_allocation_limit(mut self, bytes: usize) -> Self {
+        self.allocation_limit = Some(bytes);
+        self
+    }
+
+    /// Run macro calls under `monitor`: each call expanded consumes one unit of fuel
+    /// and must start within the time limit, and may generate no more than
+    /// `max_expansion_nodes` nodes
+    pub fn with_sandbox(mut self, monitor: SandboxMonitor) -> Self {
+        self.sandbox = Some(monitor);
+        self
+    }
+
+    /// Check `capability` for I/O that expanding `macro_name` does at compile time.
+    /// Without a sandbox everything is allowed.
+    pub fn check_capability(&self, macro_name: &str, capability: &Capability) -> Result<(), MacroError> {
+        match &self.sandbox {
+            Some(monitor) => monitor
+                .check_capability(capability)
+                .map_err(|violation| MacroError::SandboxViolation { macro_name: macro_name.to_string(), violation }),
+            None => Ok(()),
         }
     }
 
+    /// Steps recorded so far, in the order the calls were expanded
+    pub fn trace(&self) -> &[ExpansionStep] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Macro calls expanded so far, counting calls produced by other expansions
+    pub fn expansion_count(&self) -> usize {
+        self.expansion_count
+    }
+
     /// Generate a unique symbol for hygienic macros
     pub fn gensym(&mut self, prefix: &str) -> String {
         self.gensym_counter += 1;
@@ -125,111 +345,395 @@ impl MacroExpander {
 
     /// Generate a gensym expression
     pub fn gen_gensym_expr(&mut self, prefix: &str) -> LispExpr {
-        LispExpr::Gensym(self.gensym(prefix))
+        LispExpr::Gensym(self.gensym(prefix).into())
+    }
+
+    /// Whether a call to `name` reaches a macro defined with `defmacro`, or names a
+    /// module's macro it cannot reach (which expanding the call reports)
+    pub fn is_macro(&self, name: &str) -> bool {
+        !matches!(self.resolve(name), Ok(None))
+    }
+
+    /// Names of the macros defined so far, sorted: a module's macros as `module::name`
+    pub fn macro_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.macros.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The definition of the macro a call to `name` reaches: its parameters and body as written
+    pub fn macro_definition(&self, name: &str) -> Option<&MacroDefinition> {
+        self.resolve(name).ok().flatten().and_then(|key| self.macros.get(&key))
+    }
+
+    /// The macros defined so far, sorted by module (the program's own first), then name
+    pub fn macro_definitions(&self) -> Vec<&MacroDefinition> {
+        let mut definitions: Vec<&MacroDefinition> = self.macros.values().collect();
+        definitions.sort_by(|a, b| (&a.module, &a.name).cmp(&(&b.module, &b.name)));
+        definitions
+    }
+
+    /// Forget the macro a call to `name` reaches, returning whether there was one
+    pub fn remove_macro(&mut self, name: &str) -> bool {
+        match self.resolve(name) {
+            Ok(Some(key)) => self.macros.remove(&key).is_some(),
+            _ => false,
+        }
     }
 
-    /// Register a macro definition
+    /// Register a macro definition, in the current module if there is one
     pub fn define_macro(&mut self, name: String, parameters: Vec<String>, body: LispExpr) {
+        let module = self.current_module.clone();
+        let key = match &module {
+            Some(module) => qualified(module, &name),
+            None => name.clone(),
+        };
+        let definition = MacroDefinition {
+            name,
+            parameters,
+            body,
+            module,
+        };
+        self.macros.insert(key, definition);
+    }
+
+    /// Define the macros that follow in the module `name`, until `end_module`. Only
+    /// `exports` are reachable from outside unqualified or as `name:macro`; all of
+    /// the module's macros are without an export list. Declaring a module again
+    /// replaces its export list.
+    pub fn begin_module(&mut self, name: &str, exports: Option<Vec<String>>) {
+        self.modules.retain(|module| module.name != name);
+        self.modules.push(Module { name: name.to_string(), exports });
+        self.current_module = Some(name.to_string());
+    }
+
+    /// Define the macros that follow in the program itself rather than a module
+    pub fn end_module(&mut self) {
+        self.current_module = None;
+    }
+
+    /// Names of the modules declared so far, in order
+    pub fn module_names(&self) -> Vec<&str> {
+        self.modules.iter().map(|module| module.name.as_str()).collect()
+    }
+
+    /// Start the module a `(defmodule name [(export macro ...)])` form declares
+    fn declare_module(&mut self, elements: &[LispExpr]) -> Result<(), MacroError> {
+        let Some(LispExpr::Symbol(name)) = elements.get(1) else {
+            return Err(MacroError::MalformedDefinition {
+                macro_name: DEFMODULE.to_string(),
+                reason: "expected the module's name".to_string(),
+            });
+        };
+        let malformed = |reason: &str| MacroError::MalformedDefinition { macro_name: name.to_string(), reason: reason.to_string() };
+        if name.as_str().contains(':') {
+            return Err(malformed("a module's name cannot contain ':'"));
+        }
+        let exports = match &elements[2..] {
+            [] => None,
+            [LispExpr::List(clause)] if clause.first().and_then(LispExpr::as_symbol) == Some("export") => Some(
+                clause[1..]
+                    .iter()
+                    .map(|export| export.as_symbol().map(str::to_string).ok_or_else(|| malformed("exports must be macro names")))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            _ => return Err(malformed("expected an optional (export macro ...) list after the module's name")),
+        };
+        self.begin_module(name.as_str(), exports);
+        Ok(())
+    }
+
+    /// The key of the macro a call to `name` reaches, if any:
+    /// - `module::name` reaches any macro of the module, and `module:name` only an exported one
+    /// - an unqualified name reaches the current module's macro, then the program's
+    ///   own, then that of the last module declared that exports it
+    ///
+    /// Qualified names of modules that were never declared are not macro calls.
+    fn resolve(&self, name: &str) -> Result<Option<String>, MacroError> {
+        if let Some((module, _)) = name.split_once("::") {
+            if !self.modules.iter().any(|declared| declared.name == module) {
+                return Ok(None);
+            }
+            return match self.macros.contains_key(name) {
+                true => Ok(Some(name.to_string())),
+                false => Err(MacroError::UndefinedMacro(name.to_string())),
+            };
+        }
+        if let Some((module_name, local)) = name.split_once(':')
+            && !module_name.is_empty()
+        {
+            let Some(module) = self.modules.iter().find(|declared| declared.name == module_name) else {
+                return Ok(None);
+            };
+            let key = qualified(module_name, local);
+            if !self.macros.contains_key(&key) {
+                return Err(MacroError::UndefinedMacro(name.to_string()));
+            }
+            if !module.exports(local) {
+                return Err(MacroError::NotExported { module: module_name.to_string(), macro_name: local.to_string() });
+            }
+            return Ok(Some(key));
+        }
+        if let Some(module) = &self.current_module {
+            let key = qualified(module, name);
+            if self.macros.contains_key(&key) {
+                return Ok(Some(key));
+            }
+        }
+        if self.macros.contains_key(name) {
+            return Ok(Some(name.to_string()));
+        }
+        Ok(self
+            .modules
+            .iter()
+            .rev()
+            .filter(|module| module.exports(name))
+            .map(|module| qualified(&module.name, name))
+            .find(|key| self.macros.contains_key(key)))
+    }
+
+    /// Whether `name` has a compiler macro from `define-compiler-macro`
+    pub fn is_compiler_macro(&self, name: &str) -> bool {
+        self.compiler_macros.contains_key(name)
+    }
+
+    /// Names of the functions with compiler macros, sorted
+    pub fn compiler_macro_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.compiler_macros.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Register a compiler macro for calls to the function `name`
+    pub fn define_compiler_macro(&mut self, name: String, parameters: Vec<String>, body: LispExpr) {
         let definition = MacroDefinition {
             name: name.clone(),
             parameters,
             body,
+            module: None,
+        };
+        self.compiler_macros.insert(name, definition);
+    }
+
+    /// Register `(define-compiler-macro name (parameter ...) body ...)`; several body
+    /// forms run in order, as in `progn`
+    fn register_compiler_macro(&mut self, elements: &mut [LispExpr]) -> Result<(), MacroError> {
+        let name = match elements.get(1) {
+            Some(LispExpr::Symbol(name)) => name.to_string(),
+            _ => {
+                return Err(MacroError::MalformedDefinition {
+                    macro_name: DEFINE_COMPILER_MACRO.to_string(),
+                    reason: "expected the name of the function the compiler macro rewrites calls to".to_string(),
+                });
+            }
         };
-        self.macros.insert(name, definition);
+        let malformed = |reason: String| MacroError::MalformedDefinition { macro_name: name.clone(), reason };
+        let parameters = match elements.get(2) {
+            Some(LispExpr::List(parameters)) => parameters
+                .iter()
+                .map(|parameter| match parameter {
+                    LispExpr::Symbol(parameter) => Ok(parameter.to_string()),
+                    other => Err(malformed(format!("parameter '{}' is not a symbol", other))),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(LispExpr::Nil) => Vec::new(),
+            _ => return Err(malformed("expected a parameter list after the name".to_string())),
+        };
+        Pattern::from_parameters(&parameters)
+            .map_err(|PatternError { pattern, reason }| MacroError::InvalidPattern { pattern, reason })?;
+        let body = match &mut elements[3..] {
+            [] => return Err(malformed("expected a body after the parameter list".to_string())),
+            [body] => std::mem::take(body),
+            forms => LispExpr::List(std::iter::once(LispExpr::Symbol("progn".into())).chain(forms.iter_mut().map(std::mem::take)).collect()),
+        };
+        self.define_compiler_macro(name, parameters, body);
+        Ok(())
+    }
+
+    /// Rewrite calls to functions with compiler macros, innermost calls first. Each
+    /// compiler macro runs in the interpreter like a `macro-if` condition, with its
+    /// parameters bound to the call's arguments as quoted data, and returns the code
+    /// to replace the call with, or `nil` to leave it as written. A call its
+    /// parameter list doesn't fit is left as written too. Quoted data is not code
+    /// and is never rewritten.
+    pub fn apply_compiler_macros(&mut self, expr: LispExpr) -> Result<LispExpr, MacroError> {
+        if self.compiler_macros.is_empty() {
+            return Ok(expr);
+        }
+        self.rewrite_calls(expr, 0)
+    }
+
+    /// `apply_compiler_macros` for `expr`, which `depth` rewrites produced: the code
+    /// a rewrite returns is rewritten again, up to `max_depth` times
+    fn rewrite_calls(&mut self, mut expr: LispExpr, depth: usize) -> Result<LispExpr, MacroError> {
+        grow_stack(move || {
+            let LispExpr::List(elements) = &mut expr else {
+                return Ok(expr);
+            };
+            *elements = take_list(elements)
+                .into_iter()
+                .map(|element| self.rewrite_calls(element, depth))
+                .collect::<Result<_, _>>()?;
+            let Some(definition) = elements.first().and_then(LispExpr::as_symbol).and_then(|name| self.compiler_macros.get(name)) else {
+                return Ok(expr);
+            };
+            let definition = definition.clone();
+            let Ok(bindings) = self.match_parameters(&definition.name, &definition.parameters, &elements[1..]) else {
+                return Ok(expr);
+            };
+            let failed = |error: String| MacroError::ExpansionError {
+                message: format!("compiler macro for '{}' failed: {}", definition.name, error),
+                context: Some(expr.to_string()),
+            };
+            let rewritten = match self.evaluate(&definition.body, &bindings).map_err(failed)? {
+                Value::Nil => return Ok(expr),
+                value => value.to_expr().map_err(failed)?,
+            };
+            if depth >= self.max_depth {
+                return Err(MacroError::MaxDepthExceeded { depth: self.max_depth, macro_name: definition.name });
+            }
+            logging::trace(
+                "expand",
+                format_args!("rewrote a call to {} with its compiler macro", definition.name),
+                &[("function", definition.name.as_str().into()), ("depth", depth.into())],
+            );
+            self.rewrite_calls(rewritten, depth + 1)
+        })
     }
 
     /// Expand all macro calls in an expression recursively
     pub fn expand_all(&mut self, expr: LispExpr) -> Result<LispExpr, MacroError> {
-        self.expansion_depth = 0;
+        self.chain = CallChain::default();
         self.expand_expression(expr)
     }
 
-    fn expand_expression(&mut self, expr: LispExpr) -> Result<LispExpr, MacroError> {
-
-        match expr {
+    fn expand_expression(&mut self, mut expr: LispExpr) -> Result<LispExpr, MacroError> {
+        grow_stack(move || match &mut expr {
             // Handle macro definitions - add them to our registry
-            LispExpr::Macro { name, parameters, body } => {
-                self.define_macro(name.clone(), parameters, *body);
+            LispExpr::Macro { name, parameters, body, .. } => {
+                self.define_macro(name.to_string(), parameters.iter().map(Symbol::to_string).collect(), take_rc(body));
                 // Macro definitions don't expand to anything in the output
                 Ok(LispExpr::Nil)
             }
 
             // Handle macro calls - expand them (this is for backward compatibility)
-            LispExpr::MacroCall { name, args } => {
-                if self.expansion_depth > self.max_depth {
-                    return Err(MacroError::MaxDepthExceeded {
-                        depth: self.max_depth,
-                        macro_name: name.clone(),
-                    });
-                }
-                self.expansion_depth += 1;
-                let result = self.expand_macro_call(&name, args);
-                self.expansion_depth -= 1;
-                result
-            }
+            LispExpr::MacroCall { name, args } => self.expand_call(name, std::mem::take(args)),
 
             // Handle lists - check if they're macro calls, otherwise expand recursively
             LispExpr::List(elements) => {
                 if elements.is_empty() {
-                    return Ok(LispExpr::List(elements));
+                    return Ok(expr);
                 }
 
                 // Check if this is a macro call (first element is a symbol that matches a macro)
-                if let LispExpr::Symbol(name) = &elements[0] {
-                    if self.macros.contains_key(name) {
-                        // Check depth before expanding
-                        if self.expansion_depth > self.max_depth {
-                            return Err(MacroError::MaxDepthExceeded {
-                                depth: self.max_depth,
-                                macro_name: name.clone(),
-                            });
-                        }
+                if let LispExpr::Symbol(name) = &elements[0]
+                    && self.is_macro(name.as_str())
+                {
+                    // This is a macro call - convert and expand
+                    let name = name.clone();
+                    let args = take_list(elements).split_off(1);
+                    return self.expand_call(&name, args);
+                }
 
-                        // This is a macro call - convert and expand
-                        let args = elements[1..].to_vec();
-                        self.expansion_depth += 1;
-                        let result = self.expand_macro_call(name, args);
-                        self.expansion_depth -= 1;
-                        return result;
-                    }
+                if elements[0].as_symbol() == Some(DEFMODULE) {
+                    self.declare_module(elements)?;
+                    return Ok(LispExpr::Nil);
+                }
+                if elements[0].as_symbol() == Some(DEFINE_COMPILER_MACRO) {
+                    self.register_compiler_macro(&mut take_list(elements))?;
+                    // Like macro definitions, compiler macros don't appear in the output
+                    return Ok(LispExpr::Nil);
                 }
 
                 // Not a macro call - expand elements recursively
                 let mut expanded_elements = Vec::new();
-                for element in elements {
+                for element in take_list(elements) {
                     let expanded = self.expand_expression(element)?;
                     // Skip Nil expressions (from macro definitions)
                     if !matches!(expanded, LispExpr::Nil) {
                         expanded_elements.push(expanded);
                     }
                 }
-                Ok(LispExpr::List(expanded_elements))
+                Ok(LispExpr::List(expanded_elements.into()))
             }
 
             // Handle quote family - these should not be expanded
-            LispExpr::Quote(expr) => Ok(LispExpr::Quote(expr)),
-            LispExpr::Quasiquote(expr) => {
+            LispExpr::Quote(_) => Ok(expr),
+            LispExpr::Quasiquote(inner) => {
                 // Quasiquote requires special handling - expand unquotes but not the rest
-                let expanded_inner = self.expand_quasiquote(*expr)?;
-                Ok(LispExpr::Quasiquote(Box::new(expanded_inner)))
+                let expanded_inner = self.expand_quasiquote(take_rc(inner))?;
+                Ok(LispExpr::Quasiquote(Rc::new(expanded_inner)))
             }
-            LispExpr::Unquote(expr) => {
+            LispExpr::Unquote(inner) => {
                 // Unquote should expand its contents
-                let expanded = self.expand_expression(*expr)?;
-                Ok(LispExpr::Unquote(Box::new(expanded)))
+                let expanded = self.expand_expression(take_rc(inner))?;
+                Ok(LispExpr::Unquote(Rc::new(expanded)))
             }
-            LispExpr::Splice(expr) => {
+            LispExpr::Splice(inner) => {
                 // Splice should expand its contents
-                let expanded = self.expand_expression(*expr)?;
-                Ok(LispExpr::Splice(Box::new(expanded)))
+                let expanded = self.expand_expression(take_rc(inner))?;
+                Ok(LispExpr::Splice(Rc::new(expanded)))
             }
 
             // Atomic expressions don't need expansion
             _ => Ok(expr),
+        })
+    }
+
+    /// Expand a call within the chain of calls being expanded, checking the depth first
+    fn expand_call(&mut self, name: &str, args: Vec<LispExpr>) -> Result<LispExpr, MacroError> {
+        let key = self.resolve(name)?.ok_or_else(|| MacroError::UndefinedMacro(name.to_string()))?;
+        let name = key.as_str();
+        let call = self.chain.call(name, &args);
+        if call.measure.is_none() && self.chain.depth > self.max_depth {
+            return Err(MacroError::MaxDepthExceeded { depth: self.max_depth, macro_name: name.to_string() });
         }
+        self.chain.enter(call);
+        let result = self.expand_macro_call(name, args);
+        self.chain.exit();
+        result
     }
 
     fn expand_macro_call(&mut self, name: &str, args: Vec<LispExpr>) -> Result<LispExpr, MacroError> {
+        let expansion = self.expand_step(name, args)?;
+        // Recursively expand the result in case it contains more macro calls
+        self.expand_expression(expansion)
+    }
+
+    /// Expand `expr` one step if it is a macro call: the macro's body with hygiene
+    /// and the arguments applied, leaving the macro calls it contains unexpanded.
+    /// Any other form comes back unchanged.
+    pub fn expand_once(&mut self, mut expr: LispExpr) -> Result<LispExpr, MacroError> {
+        let (name, args) = match &mut expr {
+            LispExpr::MacroCall { name, args } => (name.to_string(), std::mem::take(args)),
+            LispExpr::List(elements) => match elements.first() {
+                Some(LispExpr::Symbol(name)) if self.is_macro(name.as_str()) => (name.to_string(), take_list(elements).split_off(1)),
+                _ => return Ok(expr),
+            },
+            _ => return Ok(expr),
+        };
+        let name = self.resolve(&name)?.ok_or(MacroError::UndefinedMacro(name))?;
+        self.chain = CallChain::default();
+        let call = self.chain.call(&name, &args);
+        self.chain.enter(call);
+        let result = self.expand_step(&name, args);
+        self.chain.exit();
+        result
+    }
+
+    /// One step of expanding a call: `expand_once` for the call to the macro
+    /// registered as `name` with `args`
+    fn expand_step(&mut self, name: &str, args: Vec<LispExpr>) -> Result<LispExpr, MacroError> {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(MacroError::DeadlineExceeded { macro_name: name.to_string() });
+        }
+        if let Some(monitor) = &mut self.sandbox {
+            monitor.set_current_form(Some(name.to_string()));
+            monitor
+                .check_time_limit()
+                .and_then(|()| monitor.consume_fuel(1))
+                .map_err(|violation| MacroError::SandboxViolation { macro_name: name.to_string(), violation })?;
+        }
         let macro_def = self.macros.get(name)
             .ok_or_else(|| MacroError::UndefinedMacro(name.to_string()))?
             .clone();
@@ -240,9 +744,18 @@ impl MacroExpander {
         // Create parameter bindings using pattern matching
         let bindings = self.match_parameters(&macro_name, &macro_def.parameters, &args)?;
 
-        // Apply hygiene: collect symbols introduced by the macro (not parameters)
+        // Pick the branch of each expansion-time conditional
+        let body = self.resolve_conditionals(&macro_name, &macro_def.body, &bindings, false)?;
+
+        // Apply hygiene: collect the variables the macro body binds (not parameters)
         let param_names: Vec<String> = bindings.keys().cloned().collect();
-        let introduced_symbols = self.collect_introduced_symbols(&macro_def.body, &param_names);
+
+        // A module's macro calls the module's other macros wherever it is expanded
+        let body = match &macro_def.module {
+            Some(module) => ModuleCalls { module, macros: &self.macros, parameters: &param_names }.fold_expr(body),
+            None => body,
+        };
+        let introduced_symbols = self.collect_introduced_symbols(&body, &param_names);
 
         // Create hygiene renaming map for introduced symbols
         let mut hygiene_map = HashMap::new();
@@ -251,99 +764,138 @@ impl MacroExpander {
             hygiene_map.insert(symbol, renamed);
         }
 
-        // Apply hygiene renaming to macro body first
-        let hygienic_body = self.apply_hygiene_renaming(&macro_def.body, &hygiene_map);
+        // Apply hygiene renaming to macro body first, within the scope of each binding
+        let hygienic_body = HygieneRenaming { renames: &hygiene_map, scope: Vec::new(), quasiquoted: false }.fold_expr(body);
 
         // Substitute parameters in the hygienic macro body
         let substituted_body = self.substitute_parameters(&hygienic_body, &bindings)?;
-
-        // Recursively expand the result in case it contains more macro calls
-        self.expand_expression(substituted_body)
-    }
-
-    /// Match macro parameters against arguments, supporting &rest and other patterns
-    fn match_parameters(&self, macro_name: &str, parameters: &[String], args: &[LispExpr]) -> Result<HashMap<String, LispExpr>, MacroError> {
-        let mut bindings = HashMap::new();
-
-        // Find if there's a &rest parameter
-        let rest_position = parameters.iter().position(|p| p == "&rest");
-
-        if let Some(rest_idx) = rest_position {
-            // Handle &rest parameter pattern
-            if rest_idx + 1 >= parameters.len() {
-                return Err(MacroError::InvalidPattern {
-                    pattern: "&rest".to_string(),
-                    reason: "&rest must be followed by a parameter name".to_string(),
-                });
+        if let Some(monitor) = &self.sandbox {
+            monitor
+                .check_expansion_size(substituted_body.node_count())
+                .map_err(|violation| MacroError::SandboxViolation { macro_name: macro_name.clone(), violation })?;
+        }
+        if let Some(limit) = self.allocation_limit {
+            self.allocated += substituted_body.footprint();
+            if self.allocated > limit {
+                return Err(MacroError::AllocationLimitExceeded { macro_name, allocated: self.allocated });
             }
+        }
+        self.expansion_count += 1;
+        logging::trace(
+            "expand",
+            format_args!("expanded {} at depth {}", macro_name, self.chain.nesting()),
+            &[("macro", macro_name.as_str().into()), ("depth", self.chain.nesting().into())],
+        );
 
-            let rest_param_name = &parameters[rest_idx + 1];
-            let required_params = &parameters[..rest_idx];
-
-            // Check we have at least the required parameters
-            if args.len() < required_params.len() {
-                return Err(MacroError::ParameterCountMismatch {
-                    macro_name: macro_name.to_string(),
-                    expected: required_params.len(),
-                    actual: args.len(),
-                });
-            }
+        if let Some(trace) = &mut self.trace {
+            let mut renames: Vec<(String, String)> = hygiene_map.into_iter().collect();
+            renames.sort();
+            let call = std::iter::once(LispExpr::Symbol(macro_name.as_str().into())).chain(args).collect();
+            trace.push(ExpansionStep {
+                macro_name,
+                call: LispExpr::List(call),
+                expansion: substituted_body.clone(),
+                renames,
+                depth: self.chain.nesting(),
+            });
+        }
+        Ok(substituted_body)
+    }
 
-            // Bind required parameters
-            for (param, arg) in required_params.iter().zip(args.iter()) {
-                bindings.insert(param.clone(), arg.clone());
+    /// Replace each `(macro-if condition then [else])` in a macro body with the branch
+    /// its condition picks. Conditions are evaluated by the interpreter, under the
+    /// sandbox if there is one, with each parameter bound to its argument as quoted
+    /// data, so `(macro-if (> (length args) 2) ...)` branches on the arguments' shape.
+    /// Inside a quasiquote only unquoted parts are expansion-time code.
+    fn resolve_conditionals(
+        &self,
+        macro_name: &str,
+        expr: &LispExpr,
+        bindings: &HashMap<String, LispExpr>,
+        quasiquoted: bool,
+    ) -> Result<LispExpr, MacroError> {
+        grow_stack(|| match expr {
+            LispExpr::List(elements) if !quasiquoted && elements.first().and_then(LispExpr::as_symbol) == Some(MACRO_IF) => {
+                let (condition, then, otherwise) = match &elements[1..] {
+                    [condition, then] => (condition, then, &LispExpr::Nil),
+                    [condition, then, otherwise] => (condition, then, otherwise),
+                    _ => {
+                        return Err(MacroError::ExpansionError {
+                            message: format!("'{}' in macro '{}' takes a condition, a branch, and an optional else branch", MACRO_IF, macro_name),
+                            context: Some(expr.to_string()),
+                        });
+                    }
+                };
+                let branch = if self.evaluate_condition(macro_name, condition, bindings)? { then } else { otherwise };
+                self.resolve_conditionals(macro_name, branch, bindings, false)
             }
+            LispExpr::List(elements) => elements
+                .iter()
+                .map(|element| self.resolve_conditionals(macro_name, element, bindings, quasiquoted))
+                .collect::<Result<_, _>>()
+                .map(LispExpr::List),
+            LispExpr::Quasiquote(inner) => Ok(LispExpr::Quasiquote(Rc::new(self.resolve_conditionals(macro_name, inner, bindings, true)?))),
+            LispExpr::Unquote(inner) => Ok(LispExpr::Unquote(Rc::new(self.resolve_conditionals(macro_name, inner, bindings, false)?))),
+            LispExpr::Splice(inner) => Ok(LispExpr::Splice(Rc::new(self.resolve_conditionals(macro_name, inner, bindings, false)?))),
+            _ => Ok(expr.clone()),
+        })
+    }
 
-            // Collect remaining arguments into a list for the rest parameter
-            let rest_args: Vec<LispExpr> = args[required_params.len()..].to_vec();
-            bindings.insert(rest_param_name.clone(), LispExpr::List(rest_args));
-
-            // Check for any parameters after the rest parameter name (which would be an error)
-            if rest_idx + 2 < parameters.len() {
-                return Err(MacroError::InvalidPattern {
-                    pattern: format!("&rest {}", rest_param_name),
-                    reason: "Parameters cannot appear after &rest parameter".to_string(),
-                });
-            }
-        } else {
-            // No &rest parameter - exact match required
-            if parameters.len() != args.len() {
-                return Err(MacroError::ParameterCountMismatch {
-                    macro_name: macro_name.to_string(),
-                    expected: parameters.len(),
-                    actual: args.len(),
-                });
-            }
+    fn evaluate_condition(&self, macro_name: &str, condition: &LispExpr, bindings: &HashMap<String, LispExpr>) -> Result<bool, MacroError> {
+        self.evaluate(condition, bindings).map(|value| value.is_truthy()).map_err(|error| MacroError::ExpansionError {
+            message: format!("'{}' condition in macro '{}' failed: {}", MACRO_IF, macro_name, error),
+            context: Some(condition.to_string()),
+        })
+    }
 
-            // Simple binding
-            for (param, arg) in parameters.iter().zip(args.iter()) {
-                bindings.insert(param.clone(), arg.clone());
-            }
+    /// Evaluate expansion-time code in the interpreter, with each of `bindings` bound
+    /// to its expression as quoted data. It runs under the expander's sandbox, or
+    /// without one under a sandbox that grants nothing: compiling a program must not
+    /// touch files, the network, or other processes.
+    fn evaluate(&self, expr: &LispExpr, bindings: &HashMap<String, LispExpr>) -> Result<Value, String> {
+        let config = self.sandbox.as_ref().map_or_else(SandboxConfig::new, |monitor| monitor.config().clone());
+        // A fixed seed keeps expansion reproducible
+        let mut interpreter = Interpreter::new().with_seed(0).with_sandbox(config);
+        let env = Environment::child(interpreter.global());
+        for (name, value) in bindings {
+            env.define(name, Value::from_expr(value));
         }
+        interpreter.eval_in(expr, &env).map_err(|error| error.to_string())
+    }
 
-        Ok(bindings)
+    /// Match macro parameters against arguments with the lambda-list pattern matcher
+    fn match_parameters(&self, macro_name: &str, parameters: &[String], args: &[LispExpr]) -> Result<HashMap<String, LispExpr>, MacroError> {
+        let pattern = Pattern::from_parameters(parameters)
+            .map_err(|PatternError { pattern, reason }| MacroError::InvalidPattern { pattern, reason })?;
+        let mut bindings = Vec::new();
+        pattern.bind_elements(args, &mut bindings).map_err(|error| match error {
+            MatchError::Arity { expected, actual, .. } => MacroError::ParameterCountMismatch {
+                macro_name: macro_name.to_string(),
+                expected,
+                actual,
+            },
+            MatchError::NotAList(value) => MacroError::ExpansionError {
+                message: format!("'{}' expected a list, got {}", macro_name, value),
+                context: None,
+            },
+        })?;
+        Ok(bindings.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
     }
 
-    /// Collect symbols introduced by the macro (excluding parameters)
+    /// Collect the variables the macro body binds with `let`, `lambda`, and the other
+    /// binding forms (excluding parameters). Free symbols, such as references to global
+    /// functions, are the caller's to resolve and are not collected.
     fn collect_introduced_symbols(&self, expr: &LispExpr, parameters: &[String]) -> Vec<String> {
         let mut symbols = Vec::new();
 
-        // For quasiquoted bodies, we need to collect symbols that are NOT inside unquotes
-        self.collect_non_parameter_symbols(expr, &mut symbols, parameters);
+        // For quasiquoted bodies, we need to collect binders that are NOT inside unquotes
+        IntroducedSymbols { parameters, symbols: &mut symbols, quasiquoted: false }.visit_expr(expr);
 
-        // Built-in forms that should not be renamed
-        const BUILTIN_FORMS: &[&str] = &[
-            "let", "if", "define", "lambda", "quote", "quasiquote", "unquote", "unquote-splicing",
-            "+", "-", "*", "/", "=", "<", ">", "<=", ">=",
-            "and", "or", "not", "list", "car", "cdr", "cons",
-            "set!", "begin", "progn",
-        ];
-
-        // Filter out built-in forms
-        symbols.retain(|s| !BUILTIN_FORMS.contains(&s.as_str()));
+        // Filter out built-in forms, and keywords, which name arguments rather than bindings
+        symbols.retain(|s| !HYGIENE_BUILTINS.contains(&s.as_str()) && !lambda_list::is_keyword(s));
 
         // Filter out macro names - they should be resolved, not renamed
-        symbols.retain(|s| !self.macros.contains_key(s));
+        symbols.retain(|s| !self.is_macro(s));
 
         // Remove duplicates
         symbols.sort();
@@ -352,150 +904,24 @@ impl MacroExpander {
         symbols
     }
 
-    fn collect_non_parameter_symbols(&self, expr: &LispExpr, symbols: &mut Vec<String>, parameters: &[String]) {
-        match expr {
-            LispExpr::Symbol(name) => {
-                // Only collect if not a parameter
-                if !parameters.contains(name) {
-                    symbols.push(name.clone());
-                }
-            }
-            LispExpr::List(elements) => {
-                for element in elements {
-                    self.collect_non_parameter_symbols(element, symbols, parameters);
-                }
-            }
-            LispExpr::Quote(_) => {
-                // Don't collect symbols inside quotes
-            }
-            LispExpr::Quasiquote(inner) => {
-                // In quasiquote, collect symbols that are NOT inside unquotes
-                self.collect_symbols_in_quasiquote(inner, symbols, parameters);
-            }
-            LispExpr::Unquote(_) | LispExpr::Splice(_) => {
-                // Don't collect from unquoted parts - these are parameters
-            }
-            LispExpr::Macro { body, .. } => {
-                self.collect_non_parameter_symbols(body, symbols, parameters);
-            }
-            _ => {}
-        }
-    }
-
-    fn collect_symbols_in_quasiquote(&self, expr: &LispExpr, symbols: &mut Vec<String>, parameters: &[String]) {
-        match expr {
-            LispExpr::Unquote(_) | LispExpr::Splice(_) => {
-                // Skip unquoted parts - these contain parameters
-            }
-            LispExpr::Symbol(name) => {
-                // Collect symbols in the quasiquoted part (not unquoted)
-                if !parameters.contains(name) {
-                    symbols.push(name.clone());
-                }
-            }
-            LispExpr::List(elements) => {
-                for element in elements {
-                    self.collect_symbols_in_quasiquote(element, symbols, parameters);
-                }
-            }
-            _ => {}
-        }
-    }
-
-    /// Apply hygiene renaming to symbols
-    fn apply_hygiene_renaming(&self, expr: &LispExpr, hygiene_map: &HashMap<String, String>) -> LispExpr {
-        match expr {
-            LispExpr::Symbol(name) => {
-                if let Some(renamed) = hygiene_map.get(name) {
-                    LispExpr::Gensym(renamed.clone())
-                } else {
-                    expr.clone()
-                }
-            }
-            LispExpr::List(elements) => {
-                LispExpr::List(
-                    elements.iter()
-                        .map(|e| self.apply_hygiene_renaming(e, hygiene_map))
-                        .collect()
-                )
-            }
-            LispExpr::Quote(inner) => {
-                // Don't rename inside quotes
-                LispExpr::Quote(inner.clone())
-            }
-            LispExpr::Quasiquote(inner) => {
-                LispExpr::Quasiquote(Box::new(
-                    self.apply_hygiene_renaming_in_quasiquote(inner, hygiene_map)
-                ))
-            }
-            LispExpr::Unquote(inner) => {
-                LispExpr::Unquote(Box::new(
-                    self.apply_hygiene_renaming(inner, hygiene_map)
-                ))
-            }
-            LispExpr::Splice(inner) => {
-                LispExpr::Splice(Box::new(
-                    self.apply_hygiene_renaming(inner, hygiene_map)
-                ))
-            }
-            LispExpr::Macro { name, parameters, body } => {
-                LispExpr::Macro {
-                    name: name.clone(),
-                    parameters: parameters.clone(),
-                    body: Box::new(self.apply_hygiene_renaming(body, hygiene_map)),
-                }
-            }
-            _ => expr.clone(),
-        }
-    }
-
-    fn apply_hygiene_renaming_in_quasiquote(&self, expr: &LispExpr, hygiene_map: &HashMap<String, String>) -> LispExpr {
-        match expr {
-            LispExpr::Unquote(inner) => {
-                // Don't rename inside unquotes - those are parameters
-                LispExpr::Unquote(inner.clone())
-            }
-            LispExpr::Splice(inner) => {
-                // Don't rename inside splices - those are parameters
-                LispExpr::Splice(inner.clone())
-            }
-            LispExpr::Symbol(name) => {
-                // Rename symbols in the quasiquoted part
-                if let Some(renamed) = hygiene_map.get(name) {
-                    LispExpr::Gensym(renamed.clone())
-                } else {
-                    expr.clone()
-                }
-            }
-            LispExpr::List(elements) => {
-                LispExpr::List(
-                    elements.iter()
-                        .map(|e| self.apply_hygiene_renaming_in_quasiquote(e, hygiene_map))
-                        .collect()
-                )
-            }
-            _ => expr.clone(),
-        }
-    }
-
-    fn expand_quasiquote(&mut self, expr: LispExpr) -> Result<LispExpr, MacroError> {
-        match expr {
+    fn expand_quasiquote(&mut self, mut expr: LispExpr) -> Result<LispExpr, MacroError> {
+        grow_stack(move || match &mut expr {
             LispExpr::Unquote(inner) => {
                 // Expand the unquoted expression
-                self.expand_expression(*inner)
+                self.expand_expression(take_rc(inner))
             }
             LispExpr::List(elements) => {
                 let mut expanded_elements = Vec::new();
-                for element in elements {
-                    if let LispExpr::Splice(splice_expr) = element {
+                for mut element in take_list(elements) {
+                    if let LispExpr::Splice(splice_expr) = &mut element {
                         // Handle splice - expand and flatten
-                        let expanded = self.expand_expression(*splice_expr)?;
-                        if let LispExpr::List(splice_elements) = expanded {
-                            expanded_elements.extend(splice_elements);
+                        let mut expanded = self.expand_expression(take_rc(splice_expr))?;
+                        if let LispExpr::List(splice_elements) = &mut expanded {
+                            expanded_elements.extend(take_list(splice_elements));
                         } else {
                             return Err(MacroError::ExpansionError {
                                 message: "Splice (unquote-splicing) must expand to a list".to_string(),
-                                context: Some(format!("Got: {:?}", expanded)),
+                                context: Some(format!("Got: {}", expanded)),
                             });
                         }
                     } else {
@@ -503,34 +929,34 @@ impl MacroExpander {
                         expanded_elements.push(expanded);
                     }
                 }
-                Ok(LispExpr::List(expanded_elements))
+                Ok(LispExpr::List(expanded_elements.into()))
             }
             _ => Ok(expr),
-        }
+        })
     }
 
     fn expand_quasiquote_with_substitution(
         &self,
-        expr: LispExpr,
+        mut expr: LispExpr,
         bindings: &HashMap<String, LispExpr>,
     ) -> Result<LispExpr, MacroError> {
-        match expr {
+        grow_stack(move || match &mut expr {
             LispExpr::Unquote(inner) => {
                 // Unquoted expressions should be substituted directly
-                self.substitute_parameters(&inner, bindings)
+                self.substitute_parameters(inner, bindings)
             }
             LispExpr::List(elements) => {
                 let mut expanded_elements = Vec::new();
-                for element in elements {
-                    if let LispExpr::Splice(splice_expr) = element {
+                for element in take_list(elements) {
+                    if let LispExpr::Splice(splice_expr) = &element {
                         // Handle splice - substitute and flatten
-                        let substituted = self.substitute_parameters(&splice_expr, bindings)?;
-                        if let LispExpr::List(splice_elements) = substituted {
-                            expanded_elements.extend(splice_elements);
+                        let mut substituted = self.substitute_parameters(splice_expr, bindings)?;
+                        if let LispExpr::List(splice_elements) = &mut substituted {
+                            expanded_elements.extend(take_list(splice_elements));
                         } else {
                             return Err(MacroError::ExpansionError {
                                 message: "Splice (unquote-splicing) must expand to a list".to_string(),
-                                context: Some(format!("Got: {:?}", substituted)),
+                                context: Some(format!("Got: {}", substituted)),
                             });
                         }
                     } else {
@@ -538,10 +964,10 @@ impl MacroExpander {
                         expanded_elements.push(expanded);
                     }
                 }
-                Ok(LispExpr::List(expanded_elements))
+                Ok(LispExpr::List(expanded_elements.into()))
             }
             _ => Ok(expr),
-        }
+        })
     }
 
     fn substitute_parameters(
@@ -549,9 +975,9 @@ impl MacroExpander {
         expr: &LispExpr,
         bindings: &HashMap<String, LispExpr>,
     ) -> Result<LispExpr, MacroError> {
-        match expr {
+        grow_stack(|| match expr {
             LispExpr::Symbol(name) => {
-                if let Some(replacement) = bindings.get(name) {
+                if let Some(replacement) = bindings.get(name.as_str()) {
                     Ok(replacement.clone())
                 } else {
                     Ok(expr.clone())
@@ -559,10 +985,10 @@ impl MacroExpander {
             }
             LispExpr::List(elements) => {
                 let mut substituted_elements = Vec::new();
-                for element in elements {
+                for element in elements.iter() {
                     substituted_elements.push(self.substitute_parameters(element, bindings)?);
                 }
-                Ok(LispExpr::List(substituted_elements))
+                Ok(LispExpr::List(substituted_elements.into()))
             }
             LispExpr::Quote(inner) => {
                 // Don't substitute inside quotes
@@ -576,15 +1002,316 @@ impl MacroExpander {
             LispExpr::Unquote(inner) => {
                 // Substitute inside unquotes
                 let substituted = self.substitute_parameters(inner, bindings)?;
-                Ok(LispExpr::Unquote(Box::new(substituted)))
+                Ok(LispExpr::Unquote(Rc::new(substituted)))
             }
             LispExpr::Splice(inner) => {
                 // Substitute inside splices
                 let substituted = self.substitute_parameters(inner, bindings)?;
-                Ok(LispExpr::Splice(Box::new(substituted)))
+                Ok(LispExpr::Splice(Rc::new(substituted)))
             }
             // Other expressions are returned as-is
             _ => Ok(expr.clone()),
+        })
+    }
+}
+
+impl Default for MacroExpander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The macro calls being expanded within one another, outermost first. A call to a
+/// macro already in the chain makes progress when a measure of its arguments, one
+/// argument's size or their total size, is smaller than in the nearest enclosing call
+/// to the same macro, and smaller by the same measure that call shrank by, if any.
+/// Arguments are finite, so a measure cannot shrink forever, and recursion that keeps
+/// making progress ends; an accumulator may grow meanwhile. Only the calls that make
+/// no progress count toward the depth limit.
+#[derive(Debug, Default)]
+struct CallChain {
+    calls: Vec<ActiveCall>,
+    /// Calls in the chain that made no progress
+    depth: usize,
+}
+
+#[derive(Debug)]
+struct ActiveCall {
+    name: String,
+    /// Size of each argument, in nodes
+    sizes: Vec<usize>,
+    /// The measure that shrank since the enclosing call to the same macro, if one did
+    measure: Option<Measure>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Measure {
+    Argument(usize),
+    Total,
+}
+
+impl ActiveCall {
+    fn size(&self, measure: Measure) -> Option<usize> {
+        match measure {
+            Measure::Argument(index) => self.sizes.get(index).copied(),
+            Measure::Total => Some(self.sizes.iter().sum()),
+        }
+    }
+}
+
+impl CallChain {
+    /// A call to `name` with `args`, and whether it makes progress, for `enter`
+    fn call(&self, name: &str, args: &[LispExpr]) -> ActiveCall {
+        let mut call = ActiveCall { name: name.to_string(), sizes: args.iter().map(LispExpr::node_count).collect(), measure: None };
+        if let Some(enclosing) = self.calls.iter().rev().find(|enclosing| enclosing.name == name) {
+            // Arguments by position only compare when the calls pass the same number
+            let positions = if call.sizes.len() == enclosing.sizes.len() { call.sizes.len() } else { 0 };
+            let mut measures: Vec<Measure> = (0..positions).map(Measure::Argument).chain([Measure::Total]).collect();
+            if let Some(measure) = enclosing.measure {
+                measures.retain(|candidate| *candidate == measure);
+            }
+            call.measure = measures.into_iter().find(|measure| match (call.size(*measure), enclosing.size(*measure)) {
+                (Some(size), Some(enclosing)) => size < enclosing,
+                _ => false,
+            });
+        }
+        call
+    }
+
+    fn enter(&mut self, call: ActiveCall) {
+        self.depth += usize::from(call.measure.is_none());
+        self.calls.push(call);
+    }
+
+    fn exit(&mut self) {
+        if let Some(call) = self.calls.pop() {
+            self.depth -= usize::from(call.measure.is_none());
+        }
+    }
+
+    /// Nesting level of the innermost call: 0 for calls in the source, 1 for calls produced by those, ...
+    fn nesting(&self) -> usize {
+        self.calls.len().saturating_sub(1)
+    }
+}
+
+/// Collects the variables a macro body binds: those in the binding positions of
+/// binding forms outside quoted data and, inside a quasiquote, outside unquotes,
+/// since unquoted parts hold parameters
+struct IntroducedSymbols<'a> {
+    parameters: &'a [String],
+    symbols: &'a mut Vec<String>,
+    quasiquoted: bool,
+}
+
+impl Visitor<'_> for IntroducedSymbols<'_> {
+    fn visit_expr(&mut self, expr: &LispExpr) {
+        match expr {
+            LispExpr::List(elements) => {
+                if let Some(binding) = BindingForm::of(elements) {
+                    let bound = binding.bound().into_iter().filter(|name| !self.parameters.contains(name));
+                    self.symbols.extend(bound);
+                }
+                walk_expr(self, expr)
+            }
+            // Don't collect symbols inside quotes, or from unquoted parts
+            LispExpr::Quote(_) | LispExpr::Unquote(_) | LispExpr::Splice(_) => {}
+            _ if self.quasiquoted => {}
+            LispExpr::Quasiquote(_) => {
+                self.quasiquoted = true;
+                walk_expr(self, expr);
+                self.quasiquoted = false;
+            }
+            LispExpr::Macro { .. } => walk_expr(self, expr),
+            _ => {}
+        }
+    }
+}
+
+/// A form in a macro body that binds variables, and where it names them
+enum BindingForm<'a> {
+    /// `let`, `let*`, `letrec`, and `let-values`: `(let ((pattern value) ...) body ...)`
+    Let { bindings: &'a [LispExpr] },
+    /// Named `let`, binding the loop's name as well: `(let loop ((name value) ...) body ...)`
+    NamedLet { name: &'a LispExpr, bindings: &'a [LispExpr] },
+    /// `(destructuring-bind pattern value body ...)`
+    DestructuringBind { pattern: &'a LispExpr },
+    /// `(lambda (parameter ...) body ...)`, `fn`, and the parameters of `defun` and `(define (name parameter ...) ...)`
+    Lambda { parameters: &'a [LispExpr] },
+}
+
+impl<'a> BindingForm<'a> {
+    fn of(elements: &'a [LispExpr]) -> Option<Self> {
+        Some(match (elements.first()?.as_symbol()?, &elements[1..]) {
+            ("let" | "let*" | "letrec" | "let-values", [LispExpr::List(bindings), ..]) => BindingForm::Let { bindings },
+            ("let", [name @ LispExpr::Symbol(_), LispExpr::List(bindings), ..]) => BindingForm::NamedLet { name, bindings },
+            ("destructuring-bind", [pattern, _, ..]) => BindingForm::DestructuringBind { pattern },
+            ("lambda" | "fn", [LispExpr::List(parameters), ..]) | ("defun", [_, LispExpr::List(parameters), ..]) => BindingForm::Lambda { parameters },
+            ("define", [LispExpr::List(signature), ..]) => BindingForm::Lambda { parameters: signature.get(1..)? },
+            _ => return None,
+        })
+    }
+
+    /// The names of every variable the form binds
+    fn bound(&self) -> Vec<String> {
+        let binding_names = |bindings: &[LispExpr]| -> Vec<String> {
+            bindings
+                .iter()
+                .filter_map(|binding| match binding.as_list() {
+                    Some([pattern, _]) => Some(bound_names(pattern)),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        };
+        match self {
+            BindingForm::Let { bindings } => binding_names(bindings),
+            BindingForm::NamedLet { name, bindings } => bound_names(name).into_iter().chain(binding_names(bindings)).collect(),
+            BindingForm::DestructuringBind { pattern } => bound_names(pattern),
+            BindingForm::Lambda { parameters } => parameters
+                .iter()
+                // `name`, `(name default)`, or `(name : type)`
+                .flat_map(|parameter| bound_names(parameter.as_list().and_then(|parameter| parameter.first()).unwrap_or(parameter)))
+                .collect(),
+        }
+    }
+}
+
+/// The variables `pattern` binds that hygiene renames: not built-ins or keywords
+fn bound_names(pattern: &LispExpr) -> Vec<String> {
+    let mut names = Vec::new();
+    pattern_names(pattern, &mut names);
+    names.retain(|name| !HYGIENE_BUILTINS.contains(&name.as_str()) && !lambda_list::is_keyword(name));
+    names
+}
+
+/// The literal symbols in a pattern; unquoted parts are parameters, and `&rest` marks the rest
+fn pattern_names(pattern: &LispExpr, names: &mut Vec<String>) {
+    match pattern {
+        LispExpr::Symbol(name) if !name.starts_with('&') => names.push(name.to_string()),
+        LispExpr::List(elements) => elements.iter().for_each(|element| pattern_names(element, names)),
+        _ => {}
+    }
+}
+
+/// Renames the variables a macro binds to their gensyms within the scope of their
+/// binding, leaving free references, quoted data and, inside a quasiquote, unquoted
+/// parameters alone
+struct HygieneRenaming<'a> {
+    renames: &'a HashMap<String, String>,
+    /// Names bound by the binding forms around the expression being renamed
+    scope: Vec<String>,
+    quasiquoted: bool,
+}
+
+impl HygieneRenaming<'_> {
+    /// Rename `expr` with `bound` added to the scope
+    fn fold_in_scope(&mut self, expr: LispExpr, bound: Vec<String>) -> LispExpr {
+        let depth = self.scope.len();
+        self.scope.extend(bound);
+        let renamed = self.fold_expr(expr);
+        self.scope.truncate(depth);
+        renamed
+    }
+
+    /// Rename a list, which may be a binding form: each part sees only the bindings
+    /// in scope there, as `let` values see the names outside, `let*` values the earlier
+    /// bindings, and `letrec` values all of them
+    fn fold_list(&mut self, elements: Vec<LispExpr>) -> Vec<LispExpr> {
+        let Some(binding) = BindingForm::of(&elements) else {
+            return elements.into_iter().map(|element| self.fold_expr(element)).collect();
+        };
+        let bound = binding.bound();
+        let named = matches!(binding, BindingForm::NamedLet { .. });
+        let form = elements[0].as_symbol().unwrap_or_default().to_string();
+        let mut elements = elements.into_iter();
+        let mut renamed: Vec<LispExpr> = elements.next().into_iter().collect();
+        if named {
+            // The loop's name is in scope in the body, like the variables; the values are outside
+            renamed.extend(elements.next().map(|name| self.fold_in_scope(name, bound.clone())));
+        }
+        match form.as_str() {
+            "let" | "let*" | "letrec" | "let-values" => {
+                let mut visible = if form == "letrec" { bound.clone() } else { Vec::new() };
+                let bindings = elements.next().and_then(|bindings| bindings.as_list().map(<[LispExpr]>::to_vec)).unwrap_or_default();
+                let bindings = bindings.into_iter().map(|binding| match binding.as_list() {
+                    Some([pattern, value]) => {
+                        let names = bound_names(pattern);
+                        let value = self.fold_in_scope(value.clone(), visible.clone());
+                        let pattern = self.fold_in_scope(pattern.clone(), names.clone());
+                        if form == "let*" {
+                            visible.extend(names);
+                        }
+                        LispExpr::List(vec![pattern, value].into())
+                    }
+                    _ => self.fold_in_scope(binding, visible.clone()),
+                });
+                renamed.push(LispExpr::List(bindings.collect()));
+            }
+            "destructuring-bind" => {
+                let pattern = elements.next().map(|pattern| self.fold_in_scope(pattern, bound.clone()));
+                renamed.extend(pattern);
+                renamed.extend(elements.next().map(|value| self.fold_expr(value)));
+            }
+            // A `defun`'s name is a global definition, not a binding
+            "defun" => renamed.extend(elements.next()),
+            _ => {}
+        }
+        renamed.extend(elements.map(|element| self.fold_in_scope(element, bound.clone())));
+        renamed
+    }
+}
+
+/// Qualifies the calls in a module's macro body to the module's other macros as
+/// `module::name`, so the expansion reaches them from outside the module too
+struct ModuleCalls<'a> {
+    module: &'a str,
+    macros: &'a HashMap<String, MacroDefinition>,
+    parameters: &'a [String],
+}
+
+impl Fold for ModuleCalls<'_> {
+    fn fold_expr(&mut self, expr: LispExpr) -> LispExpr {
+        if matches!(expr, LispExpr::Quote(_)) {
+            return expr;
+        }
+        let mut expr = fold_children(self, expr);
+        if let LispExpr::List(elements) = &mut expr
+            && let Some(LispExpr::Symbol(name)) = elements.first()
+            && !self.parameters.iter().any(|parameter| parameter == name)
+        {
+            let key = qualified(self.module, name);
+            if self.macros.contains_key(&key) {
+                Rc::make_mut(elements)[0] = LispExpr::Symbol(key.as_str().into());
+            }
+        }
+        expr
+    }
+}
+
+impl Fold for HygieneRenaming<'_> {
+    fn fold_expr(&mut self, expr: LispExpr) -> LispExpr {
+        match &expr {
+            LispExpr::Symbol(name) if self.scope.iter().any(|bound| bound == name) => match self.renames.get(name.as_str()) {
+                Some(renamed) => LispExpr::Gensym(renamed.into()),
+                None => expr,
+            },
+            LispExpr::Symbol(_) | LispExpr::Quote(_) => expr,
+            _ if self.quasiquoted && !expr.is_list() => expr,
+            LispExpr::Quasiquote(_) => {
+                self.quasiquoted = true;
+                let renamed = fold_children(self, expr);
+                self.quasiquoted = false;
+                renamed
+            }
+            LispExpr::List(_) => {
+                let mut expr = expr;
+                if let LispExpr::List(elements) = &mut expr {
+                    *elements = grow_stack(|| self.fold_list(take_list(elements))).into();
+                }
+                expr
+            }
+            _ => fold_children(self, expr),
         }
     }
 }
@@ -592,18 +1319,20 @@ impl MacroExpander {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lisp;
+    use std::time::Duration;
 
     #[test]
     fn test_simple_macro_definition() {
         let mut expander = MacroExpander::new();
         
         // Define a simple macro: (defmacro double (x) `(* ,x 2))
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
-                LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
+                LispExpr::Symbol("*".into()),
+                LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
                 LispExpr::Number(2.0),
-            ])
+            ].into())
         ));
         
         expander.define_macro(
@@ -614,18 +1343,18 @@ mod tests {
         
         // Test macro call: (double 5)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("double".to_string()),
+            LispExpr::Symbol("double".into()),
             LispExpr::Number(5.0),
-        ]);
+        ].into());
         
         let result = expander.expand_all(macro_call).unwrap();
         
         // Should expand to: (* 5 2)
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("*".to_string()),
+            LispExpr::Symbol("*".into()),
             LispExpr::Number(5.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
         
         assert_eq!(result, expected);
     }
@@ -635,16 +1364,16 @@ mod tests {
         let mut expander = MacroExpander::new();
         
         // Define macro: (defmacro add-and-multiply (a b c) `(* (+ ,a ,b) ,c))
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
+                LispExpr::Symbol("*".into()),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("+".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("a".to_string()))),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("b".to_string()))),
-                ]),
-                LispExpr::Unquote(Box::new(LispExpr::Symbol("c".to_string()))),
-            ])
+                    LispExpr::Symbol("+".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("a".into()))),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("b".into()))),
+                ].into()),
+                LispExpr::Unquote(Rc::new(LispExpr::Symbol("c".into()))),
+            ].into())
         ));
         
         expander.define_macro(
@@ -655,24 +1384,24 @@ mod tests {
         
         // Test macro call: (add-and-multiply 1 2 3)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("add-and-multiply".to_string()),
+            LispExpr::Symbol("add-and-multiply".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
             LispExpr::Number(3.0),
-        ]);
+        ].into());
         
         let result = expander.expand_all(macro_call).unwrap();
         
         // Should expand to: (* (+ 1 2) 3)
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("*".to_string()),
+            LispExpr::Symbol("*".into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
+                LispExpr::Symbol("+".into()),
                 LispExpr::Number(1.0),
                 LispExpr::Number(2.0),
-            ]),
+            ].into()),
             LispExpr::Number(3.0),
-        ]);
+        ].into());
         
         assert_eq!(result, expected);
     }
@@ -683,9 +1412,9 @@ mod tests {
         
         // A function call that's not a macro should pass through unchanged
         let function_call = LispExpr::List(vec![
-            LispExpr::Symbol("undefined_function".to_string()),
+            LispExpr::Symbol("undefined_function".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
         
         let result = expander.expand_all(function_call.clone()).unwrap();
         
@@ -701,14 +1430,14 @@ mod tests {
         expander.define_macro(
             "test_macro".to_string(),
             vec!["a".to_string(), "b".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
         
         // Call with wrong number of arguments
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("test_macro".to_string()),
+            LispExpr::Symbol("test_macro".into()),
             LispExpr::Number(1.0), // Only 1 argument
-        ]);
+        ].into());
         
         let result = expander.expand_all(macro_call);
         assert!(result.is_err());
@@ -728,9 +1457,10 @@ mod tests {
         
         // Expand a macro definition - should register the macro and return Nil
         let macro_def = LispExpr::Macro {
-            name: "test".to_string(),
-            parameters: vec!["x".to_string()],
-            body: Box::new(LispExpr::Symbol("x".to_string())),
+            declarations: Vec::new(),
+            name: "test".into(),
+            parameters: vec!["x".into()],
+            body: Rc::new(LispExpr::Symbol("x".into())),
         };
         
         let result = expander.expand_all(macro_def).unwrap();
@@ -743,56 +1473,14 @@ mod tests {
     #[test]
     fn test_nested_macro_expansion() {
         let mut expander = MacroExpander::new();
-        
-        // Define first macro: (defmacro double (x) `(* ,x 2))
-        expander.define_macro(
-            "double".to_string(),
-            vec!["x".to_string()],
-            LispExpr::Quasiquote(Box::new(
-                LispExpr::List(vec![
-                    LispExpr::Symbol("*".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-                    LispExpr::Number(2.0),
-                ])
-            )),
-        );
-        
-        // Define second macro that uses the first: (defmacro quadruple (x) `(double (double ,x)))
-        expander.define_macro(
-            "quadruple".to_string(),
-            vec!["x".to_string()],
-            LispExpr::Quasiquote(Box::new(
-                LispExpr::List(vec![
-                    LispExpr::Symbol("double".to_string()),
-                    LispExpr::List(vec![
-                        LispExpr::Symbol("double".to_string()),
-                        LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-                    ]),
-                ])
-            )),
-        );
-        
-        // This test shows that nested macro calls are properly expanded recursively
-        
-        let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("quadruple".to_string()),
-            LispExpr::Number(5.0),
-        ]);
-        
-        let result = expander.expand_all(macro_call).unwrap();
-        
-        // Should expand to: (* (* 5 2) 2) - quadruple means 4x, so (5 * 2) * 2 = 20
-        let expected = LispExpr::List(vec![
-            LispExpr::Symbol("*".to_string()),
-            LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
-                LispExpr::Number(5.0),
-                LispExpr::Number(2.0),
-            ]),
-            LispExpr::Number(2.0),
-        ]);
-        
-        assert_eq!(result, expected);
+
+        expander.expand_all(lisp!((defmacro double (x) (quasiquote (* ,x 2))))).unwrap();
+        // A macro that uses the first
+        expander.expand_all(lisp!((defmacro quadruple (x) (quasiquote (double (double ,x)))))).unwrap();
+
+        // Nested macro calls are expanded recursively
+        let result = expander.expand_all(lisp!((quadruple 5))).unwrap();
+        assert_eq!(result, lisp!((* (* 5 2) 2)));
     }
 
     #[test]
@@ -801,11 +1489,11 @@ mod tests {
 
         // Define a recursive macro (infinite loop)
         // This should expand to a list that calls itself
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("recursive_macro".to_string()),
-                LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-            ])
+                LispExpr::Symbol("recursive_macro".into()),
+                LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+            ].into())
         ));
 
         expander.define_macro(
@@ -816,9 +1504,9 @@ mod tests {
 
         // Use a List instead of MacroCall since that's what our parser produces
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("recursive_macro".to_string()),
+            LispExpr::Symbol("recursive_macro".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
         assert!(result.is_err());
@@ -831,6 +1519,276 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_depth_counts_only_calls_without_progress() {
+        // Each inner call's argument is part of the outer one's, so nesting deeper
+        // than the limit still expands
+        let mut expander = MacroExpander::with_max_depth(5);
+        expander.expand_all(lisp!((defmacro wrap (x) (quasiquote (list ,x))))).unwrap();
+        let source = format!("{}1{}", "(wrap ".repeat(20), ")".repeat(20));
+        let program = crate::parser::parse(crate::lexer::tokenize(&source).unwrap()).unwrap();
+        let expanded = expander.expand_all(program[0].clone()).unwrap();
+        assert_eq!(expanded.to_string(), format!("{}1{}", "(list ".repeat(20), ")".repeat(20)));
+
+        // Recursion whose arguments grow is still stopped
+        expander.expand_all(lisp!((defmacro grow (x) (quasiquote (grow (list ,x)))))).unwrap();
+        let error = expander.expand_all(lisp!((grow 1))).unwrap_err();
+        assert_eq!(error, MacroError::MaxDepthExceeded { depth: 5, macro_name: "grow".to_string() });
+    }
+
+    #[test]
+    fn test_call_chain_progress() {
+        let mut chain = CallChain::default();
+        let call = chain.call("rev", &[lisp!((a b c)), lisp!(())]);
+        assert_eq!(call.measure, None);
+        chain.enter(call);
+        // The list shrinks while the accumulator grows
+        let call = chain.call("rev", &[lisp!((b c)), lisp!((a))]);
+        assert_eq!(call.measure, Some(Measure::Argument(0)));
+        chain.enter(call);
+        // Calls to other macros in between don't matter
+        chain.enter(chain.call("other", &[]));
+        // The accumulator shrinking doesn't count once the list was the measure
+        let call = chain.call("rev", &[lisp!((b c d)), lisp!(())]);
+        assert_eq!(call.measure, None);
+        chain.enter(call);
+        assert_eq!((chain.depth, chain.nesting()), (3, 3));
+        chain.exit();
+        chain.exit();
+        assert_eq!(chain.depth, 1);
+
+        // A call passing fewer arguments makes progress when they are smaller in total
+        let mut chain = CallChain::default();
+        chain.enter(chain.call("unroll", &[lisp!(1), lisp!(2), lisp!(3)]));
+        assert_eq!(chain.call("unroll", &[lisp!(2), lisp!(3)]).measure, Some(Measure::Total));
+        assert_eq!(chain.call("unroll", &[lisp!((2 3 4))]).measure, None);
+    }
+
+    #[test]
+    fn test_macro_if() {
+        let source = "(defmacro my-list (&rest args) (macro-if (> (length args) 2) `(big ,@args) `(small ,@args)))\n\
+                      (defmacro num-or-zero (x) `(list ,(macro-if (number? x) x 0) (macro-if c 1)))\n";
+        assert_eq!(expand_source(&format!("{}(my-list 1 2 3)", source)), "(big 1 2 3)");
+        assert_eq!(expand_source(&format!("{}(my-list 1)", source)), "(small 1)");
+        // Inside a quasiquote, only unquoted conditionals are evaluated
+        assert_eq!(expand_source(&format!("{}(num-or-zero 5)", source)), "(list 5 (macro-if c 1))");
+        assert_eq!(expand_source(&format!("{}(num-or-zero y)", source)), "(list 0 (macro-if c 1))");
+
+        // Recursion ends once the condition picks the base case, however many arguments
+        let mut expander = MacroExpander::with_max_depth(5);
+        let source = "(defmacro print-all (&rest xs) (macro-if (empty? xs) `(progn) `(print-each ,@xs)))\n\
+                      (defmacro print-each (x &rest more) `(progn (print ,x) (print-all ,@more)))";
+        expand_source_with(&mut expander, source).unwrap();
+        let call = LispExpr::List(std::iter::once(LispExpr::Symbol("print-all".into())).chain((1..=20).map(|n| LispExpr::Number(n as f64))).collect());
+        let expanded = expander.expand_all(call).unwrap().to_string();
+        assert!(expanded.starts_with("(progn (print 1) (progn (print 2) ") && expanded.contains("(print 20) (progn)"), "{}", expanded);
+
+        let mut expander = MacroExpander::new();
+        let error = expand_source_with(&mut expander, "(defmacro bad (x) (macro-if (frobnicate x) 1 2))\n(bad 1)").unwrap_err().to_string();
+        assert!(error.starts_with("'macro-if' condition in macro 'bad' failed: Undefined symbol: frobnicate"), "{}", error);
+        let error = expand_source_with(&mut expander, "(defmacro worse (x) (macro-if x))\n(worse 1)").unwrap_err().to_string();
+        assert!(error.contains("'macro-if' in macro 'worse' takes a condition, a branch, and an optional else branch"), "{}", error);
+
+        // Without a sandbox, conditions can't do I/O either
+        let mut expander = MacroExpander::new();
+        let error = expand_source_with(&mut expander, "(defmacro spawn (x) (macro-if (run-command \"true\") 1 2))\n(spawn 1)").unwrap_err().to_string();
+        assert!(error.contains("condition in macro 'spawn' failed: Sandbox violation"), "{}", error);
+
+        // Conditions run under the expander's sandbox
+        let mut expander = MacroExpander::new().with_sandbox(SandboxMonitor::new(SandboxConfig::new()));
+        let error = expand_source_with(&mut expander, "(defmacro peek (path) (macro-if (read-file path) 1 2))\n(peek \"/etc/hostname\")").unwrap_err().to_string();
+        assert!(error.contains("condition in macro 'peek' failed: Sandbox violation: Unauthorized file access"), "{}", error);
+    }
+
+    #[test]
+    fn test_modules() {
+        let mut expander = MacroExpander::new();
+        let library = "(defmodule retry (export with-retry))\n\
+                       (defmacro backoff (n) `(* ,n 2))\n\
+                       (defmacro with-retry (n body) `(retry-loop (backoff ,n) ,body))";
+        expand_source_with(&mut expander, library).unwrap();
+        expander.end_module();
+        assert_eq!(expander.module_names(), vec!["retry"]);
+        assert_eq!(expander.macro_names(), vec!["retry::backoff", "retry::with-retry"]);
+
+        // The expansion reaches the module's own macros, exported or not
+        assert_eq!(expand_source_with(&mut expander, "(with-retry 3 (go))").unwrap().to_string(), "(retry-loop (* 3 2) (go))");
+        assert_eq!(expand_source_with(&mut expander, "(retry:with-retry 3 (go))").unwrap().to_string(), "(retry-loop (* 3 2) (go))");
+        assert_eq!(expand_source_with(&mut expander, "(retry::backoff 3)").unwrap().to_string(), "(* 3 2)");
+        // From outside, unexported macros need `module::name`
+        assert_eq!(expand_source_with(&mut expander, "(backoff 3)").unwrap().to_string(), "(backoff 3)");
+        let error = expand_source_with(&mut expander, "(retry:backoff 3)").unwrap_err();
+        assert_eq!(error, MacroError::NotExported { module: "retry".to_string(), macro_name: "backoff".to_string() });
+        assert!(error.to_string().contains("call it as 'retry::backoff'"), "{}", error);
+        assert_eq!(expand_source_with(&mut expander, "(retry:missing 3)"), Err(MacroError::UndefinedMacro("retry:missing".to_string())));
+        // Other names with colons are not macro calls
+        assert_eq!(expand_source_with(&mut expander, "(other:with-retry :key 1)").unwrap().to_string(), "(other:with-retry :key 1)");
+
+        // The program's own macros shadow a module's exports, which stay reachable qualified
+        expand_source_with(&mut expander, "(defmacro with-retry (n body) body)").unwrap();
+        assert_eq!(expand_source_with(&mut expander, "(with-retry 3 (go))").unwrap().to_string(), "(go)");
+        assert_eq!(expand_source_with(&mut expander, "(retry:with-retry 3 (go))").unwrap().to_string(), "(retry-loop (* 3 2) (go))");
+        assert_eq!(expander.macro_definition("with-retry").unwrap().module, None);
+        assert_eq!(expander.macro_definition("retry:with-retry").unwrap().module.as_deref(), Some("retry"));
+        // Between modules, the one declared last wins
+        expander.remove_macro("with-retry");
+        expand_source_with(&mut expander, "(defmodule fast)\n(defmacro with-retry (n body) `(fast-loop ,body))").unwrap();
+        expander.end_module();
+        assert_eq!(expand_source_with(&mut expander, "(with-retry 3 (go))").unwrap().to_string(), "(fast-loop (go))");
+
+        let error = expand_source_with(&mut expander, "(defmodule)").unwrap_err().to_string();
+        assert!(error.contains("expected the module's name"), "{}", error);
+        let error = expand_source_with(&mut expander, "(defmodule m (import x))").unwrap_err().to_string();
+        assert!(error.contains("expected an optional (export macro ...) list"), "{}", error);
+    }
+
+    #[test]
+    fn test_inspection() {
+        let mut expander = MacroExpander::new();
+        let source = "(defmacro swap! (a b) `(let ((tmp ,a)) (set! ,a ,b) (set! ,b tmp)))\n\
+                      (defmacro swap-twice (a b) `(progn (swap! ,a ,b) (swap! ,a ,b)))";
+        expand_source_with(&mut expander, source).unwrap();
+        let names: Vec<&str> = expander.macro_definitions().iter().map(|definition| definition.name.as_str()).collect();
+        assert_eq!(names, vec!["swap!", "swap-twice"]);
+        let definition = expander.macro_definition("swap!").unwrap();
+        assert_eq!(definition.parameters, vec!["a", "b"]);
+        assert_eq!(definition.signature(), "(swap! a b)");
+        assert!(expander.macro_definition("swap").is_none());
+
+        // One step leaves the macro calls the expansion contains
+        let call = crate::parser::parse(crate::lexer::tokenize("(swap-twice x y)").unwrap()).unwrap().remove(0);
+        assert_eq!(expander.expand_once(call.clone()).unwrap().to_string(), "(progn (swap! x y) (swap! x y))");
+        let call = crate::parser::parse(crate::lexer::tokenize("(swap! x y)").unwrap()).unwrap().remove(0);
+        assert_eq!(expander.expand_once(call).unwrap().to_string(), "(let ((tmp#g1 x)) (set! x y) (set! y tmp#g1))");
+        assert_eq!(expander.expand_once(lisp!((+ 1 2))), Ok(lisp!((+ 1 2))));
+        assert_eq!(expander.expand_once(LispExpr::Number(1.0)), Ok(LispExpr::Number(1.0)));
+    }
+
+    #[test]
+    fn test_compiler_macros() {
+        let mut expander = MacroExpander::new();
+        let source = "(define-compiler-macro member (x items)\n\
+                        (if (and (list? items) (= (car items) 'quote) (= (length (car (cdr items))) 1))\n\
+                            (list '= x (list 'quote (car (car (cdr items)))))\n\
+                            nil))";
+        assert_eq!(expand_source_with(&mut expander, source), Ok(LispExpr::Nil));
+        assert!(expander.is_compiler_macro("member"));
+        assert_eq!(expander.compiler_macro_names(), vec!["member".to_string()]);
+
+        let rewrite = |expander: &mut MacroExpander, source: &str| {
+            let expr = expand_source_with(expander, source).unwrap();
+            expander.apply_compiler_macros(expr).map(|expr| expr.to_string())
+        };
+        assert_eq!(rewrite(&mut expander, "(if (member x '(a)) 1 2)"), Ok("(if (= x 'a) 1 2)".to_string()));
+        // Calls the compiler macro declines, or whose arguments don't fit its
+        // parameters, and quoted data are left as written
+        assert_eq!(rewrite(&mut expander, "(member x '(a b))"), Ok("(member x '(a b))".to_string()));
+        assert_eq!(rewrite(&mut expander, "(member x)"), Ok("(member x)".to_string()));
+        assert_eq!(rewrite(&mut expander, "'(member x '(a))"), Ok("'(member x '(a))".to_string()));
+        // Arguments are rewritten first, and rewrites are rewritten again
+        let mut expander = MacroExpander::with_max_depth(10);
+        expand_source_with(&mut expander, "(define-compiler-macro double (x) (if (number? x) (* x 2) (list '+ x x)))").unwrap();
+        assert_eq!(rewrite(&mut expander, "(double (double 3))"), Ok("12".to_string()));
+        expand_source_with(&mut expander, "(define-compiler-macro twice (x) (list 'double (list 'twice x)))").unwrap();
+        assert_eq!(
+            rewrite(&mut expander, "(twice 1)"),
+            Err(MacroError::MaxDepthExceeded { depth: 10, macro_name: "twice".to_string() })
+        );
+
+        let error = expand_source_with(&mut expander, "(define-compiler-macro 5 (x) x)").unwrap_err();
+        assert!(matches!(error, MacroError::MalformedDefinition { .. }), "{}", error);
+        let error = expand_source_with(&mut expander, "(define-compiler-macro f (x))").unwrap_err().to_string();
+        assert!(error.contains("expected a body after the parameter list"), "{}", error);
+        expand_source_with(&mut expander, "(define-compiler-macro f (x) (list 'g (lambda () x)))").unwrap();
+        let error = rewrite(&mut expander, "(f 1)").unwrap_err().to_string();
+        assert!(error.starts_with("compiler macro for 'f' failed: a function is not code"), "{}", error);
+
+        // Like macro-if conditions, compiler macros can't do I/O without a sandbox
+        let marker = std::env::temp_dir().join(format!("lisp-compiler-compiler-macro-{}", std::process::id()));
+        let source = format!("(define-compiler-macro g (x) (write-file {:?} \"pwned\"))", marker.display().to_string());
+        expand_source_with(&mut expander, &source).unwrap();
+        let error = rewrite(&mut expander, "(g 1)").unwrap_err().to_string();
+        assert!(error.contains("compiler macro for 'g' failed: Sandbox violation"), "{}", error);
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        let mut expander = MacroExpander::new().with_deadline(Instant::now());
+        expander.expand_all(lisp!((defmacro double (x) (quasiquote (* ,x 2))))).unwrap();
+        assert_eq!(expander.expand_all(lisp!((+ 1 2))), Ok(lisp!((+ 1 2))));
+        let error = expander.expand_all(lisp!((double 5))).unwrap_err();
+        assert_eq!(error, MacroError::DeadlineExceeded { macro_name: "double".to_string() });
+        assert_eq!(error.to_string(), "Time limit reached before expanding macro 'double'");
+
+        let mut expander = MacroExpander::new().with_allocation_limit(1000);
+        expander.expand_all(lisp!((defmacro double (x) (quasiquote (* ,x 2))))).unwrap();
+        assert_eq!(expander.expand_all(lisp!((double 5))), Ok(lisp!((* 5 2))));
+        let error = expander.expand_all(lisp!((double (double (double (double 5)))))).unwrap_err();
+        assert!(matches!(error, MacroError::AllocationLimitExceeded { ref macro_name, allocated } if macro_name == "double" && allocated > 1000));
+    }
+
+    #[test]
+    fn test_sandbox_limits_expansion() {
+        let sandboxed = |config: SandboxConfig| {
+            let mut expander = MacroExpander::new().with_sandbox(SandboxMonitor::new(config));
+            expander.expand_all(lisp!((defmacro double (x) (quasiquote (* ,x 2))))).unwrap();
+            expander
+        };
+
+        let mut expander = sandboxed(SandboxConfig::new().with_max_fuel(3));
+        assert_eq!(expander.expand_all(lisp!((double (double 5)))), Ok(lisp!((* (* 5 2) 2))));
+        let error = expander.expand_all(lisp!((double (double 5)))).unwrap_err();
+        assert_eq!(error.to_string(), "Sandbox violation expanding macro 'double': Fuel exhausted: limit=3 forms");
+
+        let mut expander = sandboxed(SandboxConfig::new().with_max_expansion_nodes(4));
+        assert_eq!(expander.expand_all(lisp!((double 5))), Ok(lisp!((* 5 2))));
+        let error = expander.expand_all(lisp!((double (+ 1 2)))).unwrap_err();
+        assert_eq!(
+            error,
+            MacroError::SandboxViolation {
+                macro_name: "double".to_string(),
+                violation: SandboxViolation::ExpansionTooLarge { limit: 4, nodes: 7 },
+            }
+        );
+
+        let mut expander = sandboxed(SandboxConfig::new().with_max_execution_time(Duration::ZERO));
+        let error = expander.expand_all(lisp!((double 5))).unwrap_err();
+        assert!(matches!(error, MacroError::SandboxViolation { violation: SandboxViolation::ExecutionTimeExceeded { .. }, .. }));
+
+        let expander = sandboxed(SandboxConfig::new());
+        let error = expander.check_capability("slurp", &Capability::FileRead("/etc/passwd".into())).unwrap_err();
+        assert_eq!(error.to_string(), "Sandbox violation expanding macro 'slurp': Missing required capability: FileRead(\"/etc/passwd\")");
+        assert!(MacroExpander::new().check_capability("slurp", &Capability::SystemTime).is_ok());
+    }
+
+    #[test]
+    fn test_trace_records_each_expansion() {
+        let double = LispExpr::Quasiquote(Rc::new(LispExpr::List(vec![
+            LispExpr::Symbol("*".into()),
+            LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+            LispExpr::Number(2.0),
+        ].into())));
+        let call = |arg| LispExpr::List(vec![LispExpr::Symbol("double".into()), arg].into());
+
+        let mut untraced = MacroExpander::new();
+        untraced.define_macro("double".to_string(), vec!["x".to_string()], double.clone());
+        untraced.expand_all(call(LispExpr::Number(5.0))).unwrap();
+        assert!(untraced.trace().is_empty());
+
+        let mut expander = MacroExpander::new().with_trace();
+        expander.define_macro("double".to_string(), vec!["x".to_string()], double);
+        let result = expander.expand_all(call(call(LispExpr::Number(5.0)))).unwrap();
+        assert_eq!(result.to_string(), "(* (* 5 2) 2)");
+
+        let trace = expander.trace();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].call.to_string(), "(double (double 5))");
+        assert_eq!(trace[0].expansion.to_string(), "(* (double 5) 2)");
+        assert_eq!((trace[0].depth, trace[1].depth), (0, 1));
+        assert_eq!(trace[1].call.to_string(), "(double 5)");
+    }
+
     // Hygiene tests
 
     #[test]
@@ -858,26 +1816,26 @@ mod tests {
 
         // Define a macro that introduces a temporary variable 'temp'
         // (defmacro swap (a b) `(let ((temp ,a)) (set! ,a ,b) (set! ,b temp)))
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("let".to_string()),
+                LispExpr::Symbol("let".into()),
                 LispExpr::List(vec![
                     LispExpr::List(vec![
-                        LispExpr::Symbol("temp".to_string()),
-                        LispExpr::Unquote(Box::new(LispExpr::Symbol("a".to_string()))),
-                    ]),
-                ]),
+                        LispExpr::Symbol("temp".into()),
+                        LispExpr::Unquote(Rc::new(LispExpr::Symbol("a".into()))),
+                    ].into()),
+                ].into()),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("set!".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("a".to_string()))),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("b".to_string()))),
-                ]),
+                    LispExpr::Symbol("set!".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("a".into()))),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("b".into()))),
+                ].into()),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("set!".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("b".to_string()))),
-                    LispExpr::Symbol("temp".to_string()),
-                ]),
-            ])
+                    LispExpr::Symbol("set!".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("b".into()))),
+                    LispExpr::Symbol("temp".into()),
+                ].into()),
+            ].into())
         ));
 
         expander.define_macro(
@@ -888,10 +1846,10 @@ mod tests {
 
         // Call the macro with arguments
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("swap".to_string()),
-            LispExpr::Symbol("x".to_string()),
-            LispExpr::Symbol("y".to_string()),
-        ]);
+            LispExpr::Symbol("swap".into()),
+            LispExpr::Symbol("x".into()),
+            LispExpr::Symbol("y".into()),
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
@@ -919,12 +1877,12 @@ mod tests {
 
         // Define a macro: (defmacro use-param (x) `(+ ,x ,x))
         // The parameter 'x' should NOT be renamed
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
-                LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-                LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-            ])
+                LispExpr::Symbol("+".into()),
+                LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+                LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+            ].into())
         ));
 
         expander.define_macro(
@@ -935,18 +1893,18 @@ mod tests {
 
         // Call with a value
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("use-param".to_string()),
+            LispExpr::Symbol("use-param".into()),
             LispExpr::Number(5.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
         // Should expand to: (+ 5 5)
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(5.0),
             LispExpr::Number(5.0),
-        ]);
+        ].into());
 
         assert_eq!(result, expected);
     }
@@ -957,17 +1915,17 @@ mod tests {
 
         // Macro that introduces a let binding
         // (defmacro with-temp (x) `(let ((result ,x)) result))
-        let macro_body = LispExpr::Quasiquote(Box::new(
+        let macro_body = LispExpr::Quasiquote(Rc::new(
             LispExpr::List(vec![
-                LispExpr::Symbol("let".to_string()),
+                LispExpr::Symbol("let".into()),
                 LispExpr::List(vec![
                     LispExpr::List(vec![
-                        LispExpr::Symbol("result".to_string()),
-                        LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-                    ]),
-                ]),
-                LispExpr::Symbol("result".to_string()),
-            ])
+                        LispExpr::Symbol("result".into()),
+                        LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+                    ].into()),
+                ].into()),
+                LispExpr::Symbol("result".into()),
+            ].into())
         ));
 
         expander.define_macro(
@@ -977,9 +1935,9 @@ mod tests {
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("with-temp".to_string()),
+            LispExpr::Symbol("with-temp".into()),
             LispExpr::Number(42.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
@@ -987,17 +1945,17 @@ mod tests {
         fn check_let_has_gensym(expr: &LispExpr) -> bool {
             match expr {
                 LispExpr::List(elements) => {
-                    if let Some(LispExpr::Symbol(first)) = elements.first() {
-                        if first == "let" && elements.len() >= 3 {
-                            // Check if bindings contain gensym
-                            if let LispExpr::List(bindings) = &elements[1] {
-                                for binding in bindings {
-                                    if let LispExpr::List(pair) = binding {
-                                        if matches!(pair.first(), Some(LispExpr::Gensym(_))) {
-                                            return true;
-                                        }
-                                    }
-                                }
+                    if let Some(LispExpr::Symbol(first)) = elements.first()
+                        && first == "let"
+                        && elements.len() >= 3
+                        // Check if bindings contain gensym
+                        && let LispExpr::List(bindings) = &elements[1]
+                    {
+                        for binding in bindings.iter() {
+                            if let LispExpr::List(pair) = binding
+                                && matches!(pair.first(), Some(LispExpr::Gensym(_)))
+                            {
+                                return true;
                             }
                         }
                     }
@@ -1019,17 +1977,17 @@ mod tests {
         expander.define_macro(
             "with-x".to_string(),
             vec!["val".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("let".to_string()),
+                    LispExpr::Symbol("let".into()),
                     LispExpr::List(vec![
                         LispExpr::List(vec![
-                            LispExpr::Symbol("x".to_string()),
-                            LispExpr::Unquote(Box::new(LispExpr::Symbol("val".to_string()))),
-                        ]),
-                    ]),
-                    LispExpr::Symbol("x".to_string()),
-                ])
+                            LispExpr::Symbol("x".into()),
+                            LispExpr::Unquote(Rc::new(LispExpr::Symbol("val".into()))),
+                        ].into()),
+                    ].into()),
+                    LispExpr::Symbol("x".into()),
+                ].into())
             )),
         );
 
@@ -1037,28 +1995,28 @@ mod tests {
         expander.define_macro(
             "with-y".to_string(),
             vec!["val".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("let".to_string()),
+                    LispExpr::Symbol("let".into()),
                     LispExpr::List(vec![
                         LispExpr::List(vec![
-                            LispExpr::Symbol("y".to_string()),
-                            LispExpr::Unquote(Box::new(LispExpr::Symbol("val".to_string()))),
-                        ]),
-                    ]),
-                    LispExpr::Symbol("y".to_string()),
-                ])
+                            LispExpr::Symbol("y".into()),
+                            LispExpr::Unquote(Rc::new(LispExpr::Symbol("val".into()))),
+                        ].into()),
+                    ].into()),
+                    LispExpr::Symbol("y".into()),
+                ].into())
             )),
         );
 
         // Nested macro call
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("with-x".to_string()),
+            LispExpr::Symbol("with-x".into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("with-y".to_string()),
+                LispExpr::Symbol("with-y".into()),
                 LispExpr::Number(10.0),
-            ]),
-        ]);
+            ].into()),
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
@@ -1086,8 +2044,8 @@ mod tests {
 
         // Macro with quoted symbol that should not be renamed
         // (defmacro get-quoted (x) `'symbol)
-        let macro_body = LispExpr::Quasiquote(Box::new(
-            LispExpr::Quote(Box::new(LispExpr::Symbol("symbol".to_string())))
+        let macro_body = LispExpr::Quasiquote(Rc::new(
+            LispExpr::Quote(Rc::new(LispExpr::Symbol("symbol".into())))
         ));
 
         expander.define_macro(
@@ -1097,21 +2055,65 @@ mod tests {
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("get-quoted".to_string()),
+            LispExpr::Symbol("get-quoted".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
         // Should expand to: 'symbol (not renamed)
-        if let LispExpr::Quote(inner) = result {
-            assert!(matches!(*inner, LispExpr::Symbol(_)),
+        if let LispExpr::Quote(inner) = &result {
+            assert!(matches!(**inner, LispExpr::Symbol(_)),
                 "Quoted symbols should not be renamed to gensyms");
         } else {
             panic!("Expected Quote expression");
         }
     }
 
+    /// Expand each form of `source` in turn with `expander`, returning the last one's expansion
+    fn expand_source_with(expander: &mut MacroExpander, source: &str) -> Result<LispExpr, MacroError> {
+        let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        let mut expanded = LispExpr::Nil;
+        for expr in program {
+            expanded = expander.expand_all(expr)?;
+        }
+        Ok(expanded)
+    }
+
+    fn expand_source(source: &str) -> String {
+        expand_source_with(&mut MacroExpander::new(), source).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_hygiene_renames_bound_variables_to_prevent_capture() {
+        // The macro's `tmp` is renamed, so the caller's `tmp` still reaches `b`
+        let source = "(defmacro my-or (a b) `(let ((tmp ,a)) (if tmp tmp ,b)))\n(let ((tmp 5)) (my-or 0 tmp))";
+        assert_eq!(expand_source(source), "(let ((tmp 5)) (let ((tmp#g1 0)) (if tmp#g1 tmp#g1 tmp)))");
+        let source = "(defmacro call-with (f x) `((lambda (value &optional (scale 2)) (,f (* value scale))) ,x))\n(call-with show value)";
+        assert_eq!(expand_source(source), "((lambda (value#g2 &optional (scale#g1 2)) (show (* value#g2 scale#g1))) value)");
+        // A named let binds the loop's name and its variables
+        let source = "(defmacro count-to (n) `(let next ((i 0)) (if (> i ,n) i (next (+ i 1)))))\n(let ((i 5)) (count-to i))";
+        assert_eq!(expand_source(source), "(let ((i 5)) (let next#g2 ((i#g1 0)) (if (> i#g1 i) i#g1 (next#g2 (+ i#g1 1)))))");
+    }
+
+    #[test]
+    fn test_hygiene_keeps_free_references() {
+        // Global functions and variables the macro refers to are not renamed
+        let source = "(defun log-value (x) (print x))\n(defmacro log-twice (x) `(progn (log-value ,x) (log-value ,x) counter))\n(log-twice 3)";
+        assert_eq!(expand_source(source), "(progn (log-value 3) (log-value 3) counter)");
+        // A let's values are outside its scope, so `(+ count 1)` is the global `count`
+        let source = "(defmacro bump () `(let ((count (+ count 1))) count))\n(bump)";
+        assert_eq!(expand_source(source), "(let ((count#g1 (+ count 1))) count#g1)");
+        // The same name outside the binding form is a free reference
+        let source = "(defmacro scoped (x) `(list (let* ((y ,x) (z y)) z) y (destructuring-bind (z) (list 1) z) z))\n(scoped 1)";
+        assert_eq!(expand_source(source), "(list (let* ((y#g1 1) (z#g2 y#g1)) z#g2) y (destructuring-bind (z#g2) (list 1) z#g2) z)");
+        // A defun's name is a global definition
+        let source = "(defmacro defhelper (body) `(defun helper (n) (+ n ,body)))\n(defhelper 1)";
+        assert_eq!(expand_source(source), "(defun helper (n#g1) (+ n#g1 1))");
+        let source = "(defmacro defsquare () `(define (square n) (* n n)))\n(defsquare)";
+        assert_eq!(expand_source(source), "(define (square n#g1) (* n#g1 n#g1))");
+    }
+
     // Pattern matching tests
 
     #[test]
@@ -1122,34 +2124,34 @@ mod tests {
         expander.define_macro(
             "my-list".to_string(),
             vec!["first".to_string(), "&rest".to_string(), "rest".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("list".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("first".to_string()))),
-                    LispExpr::Splice(Box::new(LispExpr::Symbol("rest".to_string()))),
-                ])
+                    LispExpr::Symbol("list".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("first".into()))),
+                    LispExpr::Splice(Rc::new(LispExpr::Symbol("rest".into()))),
+                ].into())
             )),
         );
 
         // Call: (my-list 1 2 3 4)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("my-list".to_string()),
+            LispExpr::Symbol("my-list".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
             LispExpr::Number(3.0),
             LispExpr::Number(4.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
         // Should expand to: (list 1 2 3 4)
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("list".to_string()),
+            LispExpr::Symbol("list".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
             LispExpr::Number(3.0),
             LispExpr::Number(4.0),
-        ]);
+        ].into());
 
         assert_eq!(result, expected);
     }
@@ -1162,28 +2164,28 @@ mod tests {
         expander.define_macro(
             "my-list".to_string(),
             vec!["first".to_string(), "&rest".to_string(), "rest".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("list".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("first".to_string()))),
-                    LispExpr::Splice(Box::new(LispExpr::Symbol("rest".to_string()))),
-                ])
+                    LispExpr::Symbol("list".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("first".into()))),
+                    LispExpr::Splice(Rc::new(LispExpr::Symbol("rest".into()))),
+                ].into())
             )),
         );
 
         // Call with only required parameter: (my-list 1)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("my-list".to_string()),
+            LispExpr::Symbol("my-list".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
         // Should expand to: (list 1) - rest is empty
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("list".to_string()),
+            LispExpr::Symbol("list".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         assert_eq!(result, expected);
     }
@@ -1196,43 +2198,43 @@ mod tests {
         expander.define_macro(
             "add-first-two-then-rest".to_string(),
             vec!["a".to_string(), "b".to_string(), "&rest".to_string(), "rest".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("+".to_string()),
+                    LispExpr::Symbol("+".into()),
                     LispExpr::List(vec![
-                        LispExpr::Symbol("+".to_string()),
-                        LispExpr::Unquote(Box::new(LispExpr::Symbol("a".to_string()))),
-                        LispExpr::Unquote(Box::new(LispExpr::Symbol("b".to_string()))),
-                    ]),
-                    LispExpr::Splice(Box::new(LispExpr::Symbol("rest".to_string()))),
-                ])
+                        LispExpr::Symbol("+".into()),
+                        LispExpr::Unquote(Rc::new(LispExpr::Symbol("a".into()))),
+                        LispExpr::Unquote(Rc::new(LispExpr::Symbol("b".into()))),
+                    ].into()),
+                    LispExpr::Splice(Rc::new(LispExpr::Symbol("rest".into()))),
+                ].into())
             )),
         );
 
         // Call: (add-first-two-then-rest 1 2 3 4 5)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("add-first-two-then-rest".to_string()),
+            LispExpr::Symbol("add-first-two-then-rest".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
             LispExpr::Number(3.0),
             LispExpr::Number(4.0),
             LispExpr::Number(5.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
         // Should expand to: (+ (+ 1 2) 3 4 5)
         let expected = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
+                LispExpr::Symbol("+".into()),
                 LispExpr::Number(1.0),
                 LispExpr::Number(2.0),
-            ]),
+            ].into()),
             LispExpr::Number(3.0),
             LispExpr::Number(4.0),
             LispExpr::Number(5.0),
-        ]);
+        ].into());
 
         assert_eq!(result, expected);
     }
@@ -1245,14 +2247,14 @@ mod tests {
         expander.define_macro(
             "needs-two-plus".to_string(),
             vec!["a".to_string(), "b".to_string(), "&rest".to_string(), "rest".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
 
         // Call with only 1 arg (need at least 2)
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("needs-two-plus".to_string()),
+            LispExpr::Symbol("needs-two-plus".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
 
@@ -1274,13 +2276,13 @@ mod tests {
         expander.define_macro(
             "bad-macro".to_string(),
             vec!["a".to_string(), "&rest".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("bad-macro".to_string()),
+            LispExpr::Symbol("bad-macro".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
 
@@ -1301,14 +2303,14 @@ mod tests {
         expander.define_macro(
             "bad-macro".to_string(),
             vec!["a".to_string(), "&rest".to_string(), "rest".to_string(), "extra".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("bad-macro".to_string()),
+            LispExpr::Symbol("bad-macro".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
 
@@ -1329,37 +2331,37 @@ mod tests {
         expander.define_macro(
             "when".to_string(),
             vec!["condition".to_string(), "&rest".to_string(), "body".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("if".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("condition".to_string()))),
+                    LispExpr::Symbol("if".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("condition".into()))),
                     LispExpr::List(vec![
-                        LispExpr::Symbol("progn".to_string()),
-                        LispExpr::Splice(Box::new(LispExpr::Symbol("body".to_string()))),
-                    ]),
+                        LispExpr::Symbol("progn".into()),
+                        LispExpr::Splice(Rc::new(LispExpr::Symbol("body".into()))),
+                    ].into()),
                     LispExpr::Nil,
-                ])
+                ].into())
             )),
         );
 
         // Call: (when (> x 5) (print "big") (+ x 1))
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("when".to_string()),
+            LispExpr::Symbol("when".into()),
             LispExpr::List(vec![
-                LispExpr::Symbol(">".to_string()),
-                LispExpr::Symbol("x".to_string()),
+                LispExpr::Symbol(">".into()),
+                LispExpr::Symbol("x".into()),
                 LispExpr::Number(5.0),
-            ]),
+            ].into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("print".to_string()),
+                LispExpr::Symbol("print".into()),
                 LispExpr::String("big".to_string()),
-            ]),
+            ].into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
-                LispExpr::Symbol("x".to_string()),
+                LispExpr::Symbol("+".into()),
+                LispExpr::Symbol("x".into()),
                 LispExpr::Number(1.0),
-            ]),
-        ]);
+            ].into()),
+        ].into());
 
         let result = expander.expand_all(macro_call).unwrap();
 
@@ -1367,12 +2369,12 @@ mod tests {
         // Note: The macro expander may filter out Nil in some contexts
         if let LispExpr::List(elements) = &result {
             assert!(elements.len() >= 3, "Expected at least 3 elements in if expression, got {}", elements.len());
-            assert_eq!(elements[0], LispExpr::Symbol("if".to_string()));
+            assert_eq!(elements[0], LispExpr::Symbol("if".into()));
 
             // Check the progn body contains both expressions
             if let LispExpr::List(progn_parts) = &elements[2] {
                 assert_eq!(progn_parts.len(), 3); // progn + 2 body expressions
-                assert_eq!(progn_parts[0], LispExpr::Symbol("progn".to_string()));
+                assert_eq!(progn_parts[0], LispExpr::Symbol("progn".into()));
             } else {
                 panic!("Expected List for progn body");
             }
@@ -1389,9 +2391,9 @@ mod tests {
 
         // Try to expand undefined macro - these pass through as regular function calls
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("undefined".to_string()),
+            LispExpr::Symbol("undefined".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call.clone());
         // Undefined macros pass through unchanged (they might be regular functions)
@@ -1406,13 +2408,13 @@ mod tests {
         expander.define_macro(
             "my-macro".to_string(),
             vec!["x".to_string(), "y".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("my-macro".to_string()),
+            LispExpr::Symbol("my-macro".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
         assert!(result.is_err());
@@ -1435,18 +2437,18 @@ mod tests {
         expander.define_macro(
             "infinite".to_string(),
             vec!["x".to_string()],
-            LispExpr::Quasiquote(Box::new(
+            LispExpr::Quasiquote(Rc::new(
                 LispExpr::List(vec![
-                    LispExpr::Symbol("infinite".to_string()),
-                    LispExpr::Unquote(Box::new(LispExpr::Symbol("x".to_string()))),
-                ])
+                    LispExpr::Symbol("infinite".into()),
+                    LispExpr::Unquote(Rc::new(LispExpr::Symbol("x".into()))),
+                ].into())
             )),
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("infinite".to_string()),
+            LispExpr::Symbol("infinite".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
         assert!(result.is_err());
@@ -1469,13 +2471,13 @@ mod tests {
         expander.define_macro(
             "bad".to_string(),
             vec!["a".to_string(), "&rest".to_string()],
-            LispExpr::Symbol("body".to_string()),
+            LispExpr::Symbol("body".into()),
         );
 
         let macro_call = LispExpr::List(vec![
-            LispExpr::Symbol("bad".to_string()),
+            LispExpr::Symbol("bad".into()),
             LispExpr::Number(1.0),
-        ]);
+        ].into());
 
         let result = expander.expand_all(macro_call);
         assert!(result.is_err());
@@ -1528,4 +2530,28 @@ mod tests {
         assert_eq!(error3, error4);
         assert_ne!(error1, error3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_deeply_nested_expansion() {
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        let depth = 100_000;
+        let source = format!(
+            "(defmacro wrap (x) `(list ,x)) (wrap {}(wrap 1){})",
+            "(f ".repeat(depth),
+            ")".repeat(depth)
+        );
+        let program = parse(tokenize(&source).unwrap()).unwrap();
+        let mut expander = MacroExpander::new();
+        expander.expand_all(program[0].clone()).unwrap();
+        let expanded = expander.expand_all(program[1].clone()).unwrap();
+
+        // (list (f (f ... (list 1))))
+        let mut expr = &expanded;
+        for _ in 0..=depth {
+            expr = &expr.as_list().unwrap()[1];
+        }
+        assert_eq!(expr.to_string(), "(list 1)");
+    }
+}
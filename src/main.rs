@@ -1,290 +1,1418 @@
-mod lexer;
-mod parser;
-mod compiler;
-mod ast;
-mod macro_expander;
-mod transform;
-mod validator;
-mod sandbox;
-mod visualizer;
+mod cli;
 
+use cli::{BenchArgs, BuildArgs, CompileArgs, CompileLibArgs, CapabilitiesArgs, CheckArgs, Command, DebugArgs, DepsArgs, DepsFormat, ExplainArgs, FmtArgs, SandboxOptions, SelftestArgs, ServeArgs, SourceMapMode, WatchArgs};
+use lisp_compiler::{
+    ast, capabilities, crates, explain, features, suggest, include, library, lint, logging, mangle, provenance, compiler, debugger, deps, formatter, ir, ir_schema, lexer, macro_expander, manifest, parser, repl, report, runner, sandbox, server, testing, visualizer, wasm,
+};
+use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io::{self, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use transform::{TransformRegistry, EchoTransform};
-use serde_json;
-use validator::{
-    CompositeValidator, TypeSafetyValidator, ResourceBoundsValidator,
-    FFIRestrictionsValidator, ComplexityLimitsValidator,
-};
+use lisp_compiler::transform::{TransformRegistry, EchoTransform};
+use lisp_compiler::backend::Backend;
+use lisp_compiler::bytecode::BytecodeBackend;
+use lisp_compiler::compiler::RustBackend;
+use lisp_compiler::explain::CompileError;
+use lisp_compiler::pipeline::{self, validate_ast, CompileBudget, Pipeline, PipelineObserver};
+use lisp_compiler::source_map::SourceMap;
+use lisp_compiler::validator::{self, CompositeValidator, Severity};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    let mut input_file: Option<&String> = None;
-    let mut transform_names: Vec<String> = Vec::new();
-    let mut from_ir = false;
-    let mut to_ir = false;
-    let mut validate_safety = false;
-    let mut sandbox_mode = false;
-    let mut sandbox_config = sandbox::SandboxConfig::new();
-    let mut ast_dot = false;
-    let mut ast_visual = false;
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--transforms" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --transforms requires an argument");
-                    print_usage(&args[0]);
-                    process::exit(1);
-                }
-                i += 1;
-                transform_names = args[i].split(',').map(|s| s.trim().to_string()).collect();
-            }
-            "--from-ir" => {
-                from_ir = true;
-            }
-            "--to-ir" => {
-                to_ir = true;
+    let cli = cli::parse();
+    logging::init(logging::Level::from_verbosity(cli.quiet, cli.verbose), cli.log_format);
+    let reader = parser::Reader::new()
+        .with_limits(cli.lexer_limits())
+        .with_features(features::Features::new(cli.features))
+        .with_search_path(include::search_path_from(cli.include_paths, env::var_os(include::PATH_VARIABLE).as_deref()));
+    match cli.command {
+        Command::Build(CompileArgs { target, build }) => compile_command(build, target, None, &reader),
+        Command::Run(args) => match args.engine {
+            runner::Engine::Rustc => compile_command(args.build, &RustBackend, Some(args.target), &reader),
+            runner::Engine::Vm if args.target == runner::Target::Wasm => {
+                cli::usage_error("run", "--engine vm runs bytecode in the compiler's own process and cannot be combined with --target wasm")
             }
-            "--validate-safety" => {
-                validate_safety = true;
+            runner::Engine::Vm => compile_command(args.build, &BytecodeBackend, Some(args.target), &reader),
+        },
+        Command::Check(args) => run_check_command(args, &reader),
+        Command::CompileLib(args) => run_compile_lib_command(args, &reader),
+        Command::Test(args) => run_test_command(&args.input, &reader),
+        Command::Bench(args) => run_bench_command(args, &reader),
+        Command::Debug(args) => run_debug_command(args, &reader),
+        Command::Watch(args) => run_watch_command(args, &reader),
+        Command::Repl(args) => {
+            if let Err(err) = repl::run(repl::history_path(), args.contracts.enabled(), reader) {
+                logging::error(format_args!("REPL error: {}", err));
+                process::exit(1);
             }
-            "--sandbox-mode" => {
-                sandbox_mode = true;
+        }
+        Command::Fmt(args) => run_fmt_command(args),
+        Command::Serve(args) => run_serve_command(args, &reader),
+        Command::Capabilities(args) => run_capabilities_command(args, &reader),
+        Command::Deps(args) => run_deps_command(args, &reader),
+        Command::IrSchema => {
+            println!("{}", serde_json::to_string_pretty(&ir_schema::schema()).expect("schema serializes"));
+        }
+        Command::IrCheck(args) => run_ir_check_command(&args.input),
+        Command::IrToLisp(args) => run_ir_to_lisp_command(&args.input),
+        Command::Selftest(args) => run_selftest_command(args),
+        Command::Explain(args) => run_explain_command(args),
+    }
+}
+
+/// `build`: print the code `backend` generates, IR, or a visualization. With
+/// `run_target` (`run`), build the program for that target and execute it instead,
+/// or with the bytecode backend execute it in the VM.
+fn compile_command(args: BuildArgs, backend: &dyn Backend, run_target: Option<runner::Target>, reader: &parser::Reader) {
+    let build = Build::new(args, backend, run_target, reader);
+    if build.args.from_ir_stream {
+        build.compile_from_ir_stream();
+        return;
+    }
+
+    let (source, ir_input) = build.read_input();
+    let registry = transform_registry(&build.args.transforms);
+    if let Some(report_path) = &build.args.validation_report {
+        build.write_validation_report(report_path, &source, &registry, ir_input);
+    }
+
+    if let Some(format) = build.args.visualize.filter(|_| build.args.visualize_pipeline) {
+        build.visualize_pipeline(format, &source, &registry);
+    } else if let Some(format) = build.args.visualize {
+        build.visualize(format, &source, &registry);
+    } else if let Some(format) = ir_input {
+        build.compile_from_ir(&source, format);
+    } else if build.args.to_ir_stream {
+        build.compile_to_ir_stream(&source, registry);
+    } else if build.args.to_ir {
+        build.compile_to_ir(&source, registry);
+    } else {
+        build.compile(&source, registry);
+    }
+}
+
+/// One `build` or `run`: its options, and what every stage of it shares
+struct Build<'a> {
+    args: BuildArgs,
+    backend: &'a dyn Backend,
+    run_target: Option<runner::Target>,
+    reader: parser::Reader,
+    /// Whether the program is built sandboxed, as `--sandbox-mode` or the target requires
+    sandbox_mode: bool,
+    sandbox_config: sandbox::SandboxConfig,
+    libraries: Vec<library::Library>,
+}
+
+impl<'a> Build<'a> {
+    /// Check `args`, exiting with a usage error for options that cannot be combined,
+    /// and load what the build needs
+    fn new(mut args: BuildArgs, backend: &'a dyn Backend, run_target: Option<runner::Target>, reader: &parser::Reader) -> Self {
+        args.visualize = args
+            .visualize
+            .or(args.ast_dot.then_some(visualizer::VisualFormat::Dot))
+            .or(args.ast_visual.then_some(visualizer::VisualFormat::Html));
+        check_build_args(&args, backend, run_target.is_some());
+        // Reproducible builds draw the same random numbers on every run, and write canonical IR
+        args.seed = args.seed.or((args.deterministic && backend.name() == "rust").then_some(0));
+        args.ir_canonical |= args.deterministic;
+
+        let libraries = load_libraries(&args.libraries);
+        // The WASM backend maps the sandbox config onto WASI, so it always runs sandboxed
+        let sandbox_mode = args.sandbox.enabled() || run_target == Some(runner::Target::Wasm);
+        // Sandboxed builds can leave out code that needs capabilities with `#-sandbox`, and
+        // read their source within conservative limits unless others are given
+        let mut reader = reader.clone();
+        if sandbox_mode {
+            let features = reader.features().clone().with("sandbox");
+            let limits = reader.limits().or(lexer::LexerLimits::sandbox());
+            reader = reader.with_features(features).with_limits(limits);
+        }
+
+        let sandbox_config = args.sandbox.build().unwrap_or_else(|e| {
+            logging::error(format_args!("Error configuring sandbox: {}", e));
+            process::exit(1);
+        });
+        for (granted, denied) in sandbox_config.conflicts() {
+            logging::warn(format_args!(
+                "capability {} is granted but --deny-capability {} revokes it; the deny takes precedence",
+                granted, denied
+            ));
+        }
+        Build { args, backend, run_target, reader, sandbox_mode, sandbox_config, libraries }
+    }
+
+    fn run(&self) -> bool {
+        self.run_target.is_some()
+    }
+
+    fn sandbox(&self) -> Option<&sandbox::SandboxConfig> {
+        self.sandbox_mode.then_some(&self.sandbox_config)
+    }
+
+    /// The target and sandbox to execute the built program with, when running it
+    fn running(&self) -> Option<(runner::Target, &sandbox::SandboxConfig)> {
+        self.run_target.map(|target| (target, &self.sandbox_config))
+    }
+
+    /// Where an output with `extension` goes: `-o` names the artifact; `--out-dir`
+    /// names it after the input. `None` is standard output.
+    fn artifact(&self, extension: &str) -> Option<PathBuf> {
+        let stem = Path::new(&self.args.input).file_stem().map_or("out".into(), |stem| stem.to_string_lossy());
+        self.args.output.as_ref().map(PathBuf::from).or_else(|| {
+            self.args.out_dir.as_ref().map(|dir| Path::new(dir).join(format!("{}.{}", stem, extension)))
+        })
+    }
+
+    /// The input file, with `--apply-suggestions` applied to it, and the IR format to
+    /// decode it in with `--from-ir`
+    fn read_input(&self) -> (Vec<u8>, Option<ir::IrFormat>) {
+        let input_file = &self.args.input;
+        let mut source = match fs::read(input_file) {
+            Ok(content) => content,
+            Err(err) => {
+                logging::error(format_args!("Error reading file '{}': {}", input_file, err));
+                process::exit(1);
             }
-            "--max-memory" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --max-memory requires an argument");
-                    print_usage(&args[0]);
+        };
+        // IR input is decoded in the given format, or the one its leading bytes indicate
+        let ir_input = self.args.from_ir.then(|| self.args.ir_format.unwrap_or_else(|| ir::IrFormat::detect(&source)));
+
+        if self.args.apply_suggestions {
+            let (fixed_source, applied) = match apply_suggested_fixes(&source, &self.reader, ir_input) {
+                Ok(result) => result,
+                Err(err) => {
+                    logging::error(format_args!("Error applying suggestions: {}", err));
                     process::exit(1);
                 }
-                i += 1;
-                let memory_str = &args[i];
-                let memory_bytes = parse_memory_size(memory_str).unwrap_or_else(|e| {
-                    eprintln!("Error parsing --max-memory: {}", e);
-                    process::exit(1);
-                });
-                sandbox_config = sandbox_config.with_max_memory(memory_bytes);
-            }
-            "--timeout" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --timeout requires an argument");
-                    print_usage(&args[0]);
+            };
+            if applied > 0 {
+                if let Err(err) = fs::write(input_file, &fixed_source) {
+                    logging::error(format_args!("Error writing file '{}': {}", input_file, err));
                     process::exit(1);
                 }
-                i += 1;
-                let timeout_str = &args[i];
-                let timeout = parse_duration(timeout_str).unwrap_or_else(|e| {
-                    eprintln!("Error parsing --timeout: {}", e);
-                    process::exit(1);
-                });
-                sandbox_config = sandbox_config.with_max_execution_time(timeout);
+                logging::info(format_args!("Applied {} suggested fix(es) to {}", applied, input_file));
+                source = fixed_source;
             }
-            "--allow-capability" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --allow-capability requires an argument");
-                    print_usage(&args[0]);
-                    process::exit(1);
-                }
-                i += 1;
-                let capability_str = &args[i];
-                let capability = parse_capability(capability_str).unwrap_or_else(|e| {
-                    eprintln!("Error parsing --allow-capability: {}", e);
-                    process::exit(1);
-                });
-                sandbox_config.add_capability(capability);
+        }
+        (source, ir_input)
+    }
+
+    /// The Lisp source in `source`, with its includes spliced in
+    fn source_code(&self, source: &[u8]) -> include::Source {
+        include::Source::resolve(&self.args.input, self.source_text(source), &self.reader, self.sandbox()).unwrap_or_else(|err| {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        })
+    }
+
+    /// `source_code`, and the forms read from it with their span trees
+    fn read_source(&self, source: &[u8]) -> (include::Source, parser::SpannedForms) {
+        let resolved = include::Source::resolve_read(&self.args.input, self.source_text(source), &self.reader, self.sandbox());
+        // Without includes, the forms read while looking for them are the program's
+        resolved
+            .and_then(|(source, forms)| match forms {
+                Some(forms) => Ok((source, forms)),
+                None => self.reader.read_with_span_trees(&source.text).map(|forms| (source, forms)),
+            })
+            .unwrap_or_else(|err| {
+                log_error("Compilation error", &err);
+                process::exit(1);
+            })
+    }
+
+    /// The text of `source`, exiting when it is not Lisp source
+    fn source_text(&self, source: &[u8]) -> String {
+        lisp_text(source).map(str::to_string).unwrap_or_else(|err| {
+            logging::error(format_args!("Error reading file '{}': {}", self.args.input, err));
+            process::exit(1);
+        })
+    }
+
+    /// `--validation-report`: write the findings of validating `source` to `report_path`
+    fn write_validation_report(&self, report_path: &str, source: &[u8], registry: &TransformRegistry, ir_input: Option<ir::IrFormat>) {
+        // Reports given as relative paths sit next to the other outputs
+        let report_path = match &self.args.out_dir {
+            Some(dir) => Path::new(dir).join(report_path).to_string_lossy().into_owned(),
+            None => report_path.to_string(),
+        };
+        let format = self.args.validation_report_format.unwrap_or_else(|| report::ReportFormat::from_path(&report_path));
+        let stage = self.args.validation_stage.unwrap_or_default();
+        let validation = match build_validation_report(source, &self.reader, &self.args.input, registry, ir_input, self.sandbox(), stage, &self.libraries) {
+            Ok(r) => r,
+            Err(err) => {
+                logging::error(format_args!("Validation report error: {}", err));
+                process::exit(1);
             }
-            "--ast-dot" => {
-                ast_dot = true;
+        };
+        write_artifact(Some(Path::new(&report_path)), validation.render(format).as_bytes());
+    }
+
+    fn dot_visualizer(&self) -> visualizer::DotVisualizer {
+        let mut dot = visualizer::DotVisualizer::new();
+        if let Some(depth) = self.args.dot_max_depth {
+            dot = dot.with_max_depth(depth);
+        }
+        if let Some(nodes) = self.args.dot_collapse {
+            dot = dot.with_collapse_above(nodes);
+        }
+        if let Some(name) = &self.args.dot_focus {
+            dot = dot.with_focus(name);
+        }
+        dot
+    }
+
+    /// `--visualize-pipeline`: the program before and after every transform and macro expansion
+    fn visualize_pipeline(&self, format: visualizer::VisualFormat, source: &[u8], registry: &TransformRegistry) {
+        let stages = pipeline_stages(&self.source_code(source).text, &self.reader, registry).unwrap_or_else(|err| {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        });
+        let rendered = match format {
+            visualizer::VisualFormat::Dot => self.dot_visualizer().visualize_pipeline(&stages),
+            visualizer::VisualFormat::Html => visualizer::PipelineVisualizer::new().visualize(&stages),
+            _ => {
+                logging::error("Error: --visualize-pipeline supports the dot and html formats");
+                process::exit(1);
             }
-            "--ast-visual" => {
-                ast_visual = true;
+        };
+        write_artifact(self.artifact(format.extension()).as_deref(), rendered.as_bytes());
+    }
+
+    /// `--visualize`: render the AST as it stands after the chosen stage
+    fn visualize(&self, format: visualizer::VisualFormat, source: &[u8], registry: &TransformRegistry) {
+        let stage = self.args.visualize_stage.unwrap_or(IrStage::Parsed);
+        if format == visualizer::VisualFormat::Expansion && stage == IrStage::Expanded {
+            logging::error("Error: --visualize expansion shows macros expanding, so the stage must be parsed or transformed");
+            process::exit(1);
+        }
+        let source = self.source_code(source).text;
+        let (ast, spans) = program_at_stage(&source, &self.reader, registry, stage).unwrap_or_else(|err| {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        });
+        let rendered = match format {
+            // Color the tree only when it goes straight to a terminal
+            visualizer::VisualFormat::Tree => {
+                let color = self.artifact("txt").is_none() && std::io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+                visualizer::TreeVisualizer::new().with_color(color).visualize(&ast)
             }
-            arg if arg.starts_with("--") => {
-                eprintln!("Error: unknown option '{}'", arg);
-                print_usage(&args[0]);
-                process::exit(1);
+            visualizer::VisualFormat::Html => {
+                visualizer::HtmlVisualizer::new().with_spans(spans).with_source(source).visualize(&ast)
             }
-            _ => {
-                if input_file.is_some() {
-                    eprintln!("Error: multiple input files specified");
-                    print_usage(&args[0]);
+            visualizer::VisualFormat::Dot => {
+                if let Some(name) = &self.args.dot_focus
+                    && visualizer::find_definitions(&ast, name).is_empty()
+                {
+                    logging::error(format_args!("Error: no function or macro named '{}' to focus on", name));
                     process::exit(1);
                 }
-                input_file = Some(&args[i]);
+                self.dot_visualizer().visualize(&ast)
+            }
+            _ => format.render(&ast),
+        };
+        write_artifact(self.artifact(format.extension()).as_deref(), rendered.as_bytes());
+    }
+
+    /// The pipeline compiling IR input to Rust, validating it at the stages
+    /// `--validation-stage` selects as a build from source would
+    fn ir_pipeline(&self) -> Pipeline<'_> {
+        let args = &self.args;
+        rust_pipeline(TransformRegistry::new(), args.validate_safety, self.sandbox(), args.seed)
+            .with_validation_stage(args.validation_stage.unwrap_or_default())
+            .with_deterministic(args.deterministic)
+            .with_contracts(args.contracts.enabled())
+            .with_budget(CompileBudget { time: args.sandbox.timeout, memory: args.sandbox.max_memory })
+    }
+
+    /// `--from-ir-stream`: compile streamed IR, read line by line rather than loaded whole
+    fn compile_from_ir_stream(&self) {
+        let input_file = &self.args.input;
+        let file = fs::File::open(input_file).unwrap_or_else(|err| {
+            logging::error(format_args!("Error reading file '{}': {}", input_file, err));
+            process::exit(1);
+        });
+        match compile_from_ir_stream(BufReader::new(file), self.ir_pipeline()) {
+            Ok(rust_code) => emit_program(&rust_code, self.running(), self.artifact("rs")),
+            Err(err) => {
+                log_error("Compilation error", &err);
+                process::exit(1);
             }
         }
-        i += 1;
     }
 
-    let input_file = match input_file {
-        Some(f) => f,
-        None => {
-            eprintln!("Error: no input file specified");
-            print_usage(&args[0]);
+    /// `--from-ir`: compile IR in `format` to Rust
+    fn compile_from_ir(&self, source: &[u8], format: ir::IrFormat) {
+        match compile_from_ir(source, format, self.ir_pipeline()) {
+            Ok(rust_code) => emit_program(&rust_code, self.running(), self.artifact("rs")),
+            Err(err) => {
+                log_error("Compilation error", &err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// `--to-ir-stream`: write the program as NDJSON IR, a form at a time
+    fn compile_to_ir_stream(&self, source: &[u8], registry: TransformRegistry) {
+        let stage = self.args.ir_stage.unwrap_or(IrStage::Expanded);
+        let out: Box<dyn Write> = match self.artifact("ir.ndjson") {
+            Some(path) => Box::new(create_artifact(&path)),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        let source = self.source_code(source);
+        if let Err(err) = compile_to_ir_stream(&source.text, &self.reader, registry, self.args.validate_safety, stage, self.args.deterministic, out) {
+            log_error("Compilation error", &err);
             process::exit(1);
         }
-    };
+    }
 
-    let source_code = match fs::read_to_string(input_file) {
-        Ok(content) => content,
-        Err(err) => {
-            eprintln!("Error reading file '{}': {}", input_file, err);
+    /// `--to-ir`: write the program as IR
+    fn compile_to_ir(&self, source: &[u8], registry: TransformRegistry) {
+        let args = &self.args;
+        let output = IrOutput {
+            stage: args.ir_stage.unwrap_or(IrStage::Expanded),
+            format: args.ir_format.unwrap_or(ir::IrFormat::Json),
+            include_spans: args.ir_include_spans,
+            canonical: args.ir_canonical,
+            intern: args.ir_intern,
+        };
+        match compile_to_ir(&self.source_code(source).text, &self.reader, registry, args.validate_safety, output) {
+            Ok(mut ir_bytes) => {
+                if output.format == ir::IrFormat::Json {
+                    ir_bytes.push(b'\n');
+                }
+                write_artifact(self.artifact(&format!("ir.{}", output.format.extension())).as_deref(), &ir_bytes);
+            }
+            Err(err) => {
+                log_error("Compilation error", &err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Compile the program with the backend, then write it, emit a Cargo project, or run it
+    fn compile(&self, source: &[u8], registry: TransformRegistry) {
+        let (args, backend, run) = (&self.args, self.backend, self.run());
+        // The program is read once, for the lints, the pipeline, and the crates it requires
+        let (source, forms) = self.read_source(source);
+        report_lints(&source, &forms, &args.lints.levels());
+        let mut required = RequiredCrates::default();
+        let mut pipeline = match backend.name() {
+            "rust" => rust_pipeline(registry, args.validate_safety, self.sandbox(), args.seed),
+            _ => Pipeline::new().with_transforms(registry).with_validation(args.validate_safety).with_backend(backend),
+        };
+        pipeline = pipeline.with_reader(self.reader.clone());
+        // --timeout and --max-memory bound compiling the program as well as running it
+        let budget = CompileBudget { time: args.sandbox.timeout, memory: args.sandbox.max_memory };
+        pipeline = pipeline
+            .with_validation_stage(args.validation_stage.unwrap_or_default())
+            .with_deterministic(args.deterministic)
+            .with_contracts(args.contracts.enabled())
+            .with_budget(budget);
+        for library in &self.libraries {
+            pipeline = pipeline.with_library(library);
+        }
+        // Runs mark the code so rustc errors can be reported at their Lisp forms
+        if backend.name() == "rust" && (args.source_map.is_some() || run) {
+            pipeline = pipeline.with_source_map(&source);
+        }
+        if let Some(path) = &args.name_map {
+            pipeline = pipeline.observe(NameMap { path: PathBuf::from(path), names: Vec::new() });
+        }
+        if backend.name() == "rust" && (args.emit_project.is_some() || run) {
+            pipeline = pipeline.observe(&mut required);
+        }
+        let record = args.provenance.then(|| {
+            let sandbox = self.sandbox_mode.then_some((&args.sandbox, &self.sandbox_config));
+            provenance_record(backend, &source, &args.libraries, &args.transforms, args.validate_safety, sandbox, args.deterministic)
+        });
+        let compiled = pipeline.compile_read(forms);
+        drop(pipeline);
+        let required = required.0;
+        if run && !required.is_empty() {
+            let names: Vec<&str> = required.iter().map(|requirement| requirement.name.as_str()).collect();
+            logging::error(format_args!(
+                "Run error: the program requires crates ({}), which rustc alone cannot fetch; build it with --emit-project and run it with cargo",
+                names.join(", ")
+            ));
             process::exit(1);
         }
-    };
+        // The header comes first so a JSON source map counts its lines
+        let compiled = compiled.map(|code| match &record {
+            Some(record) => record.header() + &code,
+            None => code,
+        });
+        // A JSON map replaces the comments it is read from
+        let compiled = compiled.map(|code| match args.source_map {
+            Some(SourceMapMode::Json) => {
+                let (code, map) = SourceMap::read(&code);
+                let mut path = self.artifact(backend.extension()).expect("checked above").into_os_string();
+                path.push(".map");
+                write_artifact(Some(Path::new(&path)), format!("{}\n", map.to_json()).as_bytes());
+                code.trim_end().to_string()
+            }
+            _ => code,
+        });
+        let code = compiled.unwrap_or_else(|err| {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        });
+        match &args.emit_project {
+            Some(dir) => write_project(Path::new(dir), &args.input, &code, &required),
+            None if run && backend.name() == BytecodeBackend.name() => match runner::run_bytecode(&code, self.sandbox()) {
+                Ok(Ok(())) => process::exit(0),
+                // The exit code of a compiled program that panics
+                Ok(Err(err)) => {
+                    logging::error(format_args!("Runtime error: {}", err));
+                    process::exit(101);
+                }
+                Err(err) => {
+                    logging::error(format_args!("Run error: {}", err));
+                    process::exit(1);
+                }
+            },
+            None => {
+                let output = self.artifact(backend.extension());
+                emit_program(&code, self.running(), output.clone());
+                if let (Some(record), Some(output)) = (record, output) {
+                    let record = record.with_output(&output.to_string_lossy(), format!("{}\n", code).as_bytes());
+                    let mut path = output.into_os_string();
+                    path.push(".provenance.json");
+                    write_artifact(Some(Path::new(&path)), format!("{}\n", record.to_json()).as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Exit with a usage error when `args` combines options that cannot be used together
+fn check_build_args(args: &BuildArgs, backend: &dyn Backend, run: bool) {
+    let usage_error = |message| cli::usage_error(if run { "run" } else { "build" }, message);
+    if args.ir_stage.is_some() && !args.to_ir && !args.to_ir_stream {
+        usage_error("--ir-stage is only used with --to-ir or --to-ir-stream");
+    }
+    if (args.ir_include_spans || args.ir_canonical || args.ir_intern) && !args.to_ir {
+        usage_error("--ir-include-spans, --ir-canonical, and --ir-intern are only used with --to-ir");
+    }
+    if args.to_ir_stream && (args.to_ir || args.from_ir || args.from_ir_stream || args.ir_format.is_some() || args.visualize.is_some() || run) {
+        usage_error("--to-ir-stream writes NDJSON IR and cannot be combined with other IR, output, or run options");
+    }
+    if args.from_ir_stream && (args.from_ir || args.ir_format.is_some() || args.apply_suggestions || args.validation_report.is_some() || args.visualize.is_some()) {
+        usage_error("--from-ir-stream cannot be combined with --from-ir, --ir-format, --apply-suggestions, --validation-report, or AST output");
+    }
+    if args.ir_format.is_some() && !args.to_ir && !args.from_ir {
+        usage_error("--ir-format is only used with --to-ir or --from-ir");
+    }
+    if run && (args.to_ir || args.visualize.is_some()) {
+        usage_error("run cannot be combined with --to-ir or --visualize");
+    }
+    if (args.visualize_stage.is_some() || args.visualize_pipeline) && args.visualize.is_none() {
+        usage_error("--visualize-stage and --visualize-pipeline are only used with --visualize");
+    }
+    if run && (args.output.is_some() || args.out_dir.is_some()) {
+        usage_error("run executes the program and cannot be combined with -o or --out-dir");
+    }
+    if args.emit_project.is_some() && (run || args.to_ir || args.to_ir_stream || args.from_ir || args.from_ir_stream || args.visualize.is_some()) {
+        usage_error("--emit-project writes a Cargo project from Lisp source and cannot be combined with IR, visualization, or run options");
+    }
+    let rust_options = args.from_ir || args.from_ir_stream || args.to_ir || args.to_ir_stream || args.visualize.is_some() || args.emit_project.is_some();
+    if run && backend.name() != "rust" && (rust_options || args.seed.is_some()) {
+        usage_error("--engine vm runs Lisp source only and cannot be combined with IR, visualization, or --seed");
+    }
+    // The VM meters fuel and time itself; only generated Rust embeds a sandbox
+    if !run && backend.name() != "rust" && (rust_options || args.seed.is_some() || args.sandbox.enabled()) {
+        usage_error(&format!(
+            "--target {} compiles Lisp source only and cannot be combined with IR, visualization, --emit-project, --seed, or sandbox options",
+            backend.name()
+        ));
+    }
+    if args.source_map.is_some() && (run || args.from_ir || args.from_ir_stream || args.to_ir || args.to_ir_stream || args.visualize.is_some() || backend.name() != "rust") {
+        usage_error("--source-map maps generated Rust back to Lisp source and cannot be combined with run, IR, visualization, or other targets");
+    }
+    if args.name_map.is_some() && (run || args.from_ir || args.from_ir_stream || args.to_ir || args.to_ir_stream || args.visualize.is_some() || backend.name() != "rust") {
+        usage_error("--name-map lists the names of generated Rust and cannot be combined with run, IR, visualization, or other targets");
+    }
+    if args.provenance && (run || args.from_ir || args.from_ir_stream || args.to_ir || args.to_ir_stream || args.visualize.is_some() || args.emit_project.is_some() || backend.name() != "rust") {
+        usage_error("--provenance records how Rust source was generated and cannot be combined with run, IR, visualization, --emit-project, or other targets");
+    }
+    if args.provenance && args.output.is_none() && args.out_dir.is_none() {
+        usage_error("--provenance writes a manifest next to the output and needs -o or --out-dir");
+    }
+    if args.source_map == Some(SourceMapMode::Json) && args.output.is_none() && args.out_dir.is_none() {
+        usage_error("--source-map json writes the map next to the output and needs -o or --out-dir");
+    }
+    if args.validation_stage.is_some() && !args.validate_safety {
+        usage_error("--validation-stage is only used with --validate-safety");
+    }
+    let dot_options = args.dot_max_depth.is_some() || args.dot_collapse.is_some() || args.dot_focus.is_some();
+    if dot_options && args.visualize != Some(visualizer::VisualFormat::Dot) {
+        usage_error("--dot-max-depth, --dot-collapse, and --dot-focus are only used with --visualize dot");
+    }
+    if args.visualize_pipeline && args.visualize_stage.is_some() {
+        usage_error("--visualize-pipeline shows every stage and cannot be combined with --visualize-stage");
+    }
+    if args.visualize.is_some() && (args.from_ir || args.to_ir) {
+        usage_error("--visualize reads Lisp source and cannot be combined with --from-ir or --to-ir");
+    }
+    if !args.libraries.is_empty() && (args.from_ir || args.from_ir_stream || args.to_ir || args.to_ir_stream || args.visualize.is_some()) {
+        usage_error("--lib compiles libraries with Lisp source and cannot be combined with IR or visualization options");
+    }
+}
 
-    // Build transform registry from CLI args
+/// The transforms named by `--transform`, exiting on an unknown name
+fn transform_registry(names: &[String]) -> TransformRegistry {
     let mut registry = TransformRegistry::new();
-    for name in &transform_names {
+    for name in names {
         match name.as_str() {
             "echo" => registry.register(Box::new(EchoTransform::new())),
             other => {
-                eprintln!("Error: unknown transform '{}'", other);
-                eprintln!("Available transforms: echo");
+                let message = suggest::with_suggestion(format!("unknown transform '{}'", other), other, ["echo"]);
+                logging::error(format_args!("Error: {}\nAvailable transforms: echo", message));
                 process::exit(1);
             }
         }
     }
+    registry
+}
+
+/// Log a failed compilation as `kind: err`, with the error's code and how to read
+/// its explanation when it has one
+fn log_error(kind: &str, err: &CompileError) {
+    match err.code() {
+        Some(code) => {
+            logging::error(format_args!("{}[{}]: {}", kind, code, err));
+            logging::info(format_args!("For more information about this error, try `lisp-compiler explain {}`", code));
+        }
+        None => logging::error(format_args!("{}: {}", kind, err)),
+    }
+}
+
+/// Log each lint finding at its lint's level, and exit when any lint with findings is
+/// denied. Syntax errors are left for compilation to report.
+fn report_lints(source: &include::Source, forms: &[(ast::LispExpr, ast::SpanTree)], lints: &lint::LintLevels) {
+    let (program, trees): (Vec<ast::LispExpr>, Vec<ast::SpanTree>) = forms.iter().cloned().unzip();
+    let findings = lint::lint_program(&program, Some(&trees), lints);
+    for finding in &findings {
+        let error = &finding.error;
+        let location = match finding.span {
+            Some(span) => source.location(span.start),
+            None => source.files[0].path.clone(),
+        };
+        let help = error.suggestion.as_ref().map(|help| format!("\n  help: {}", help)).unwrap_or_default();
+        match error.severity {
+            Severity::Error => logging::error(format_args!("Compilation error[{}]: {}: {} [{}]{}", error.rule.code(), location, error.message, finding.lint, help)),
+            _ => logging::warn(format_args!("{}: {} [{}]{}", location, error.message, finding.lint, help)),
+        }
+    }
+    if findings.iter().any(|finding| finding.error.severity == Severity::Error) {
+        process::exit(1);
+    }
+}
+
+/// Write the generated Rust to `output` or stdout, or with `run` build and execute
+/// it and exit with its status
+fn emit_program(rust_code: &str, run: Option<(runner::Target, &sandbox::SandboxConfig)>, output: Option<PathBuf>) {
+    let Some((target, config)) = run else {
+        write_artifact(output.as_deref(), format!("{}\n", rust_code).as_bytes());
+        return;
+    };
+    if target == runner::Target::Wasm {
+        // Grants WASI cannot enforce are refused when the module runs
+        for capability in wasm::WasiPermissions::from_config(config).map(|permissions| permissions.unsupported).unwrap_or_default() {
+            logging::warn(format_args!("{} is not available under --target wasm; those operations will fail", capability));
+        }
+    }
+    match runner::run(rust_code, target, config) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            logging::error(format_args!("Run error: {}", err));
+            process::exit(1);
+        }
+    }
+}
+
+/// Write a Cargo project to `dir`: a Cargo.toml with the program's crate dependencies,
+/// named after the input, and the generated Rust as src/main.rs
+fn write_project(dir: &Path, input_file: &str, rust_code: &str, required: &[crates::CrateRequirement]) {
+    let stem = Path::new(input_file).file_stem().map_or("out".into(), |stem| stem.to_string_lossy());
+    let manifest = crates::cargo_manifest(&crates::package_name(&stem), required);
+    write_artifact(Some(&dir.join("Cargo.toml")), manifest.as_bytes());
+    write_artifact(Some(&dir.join("src").join("main.rs")), format!("{}\n", rust_code).as_bytes());
+    logging::info(format_args!("Wrote Cargo project to {} (build it with cargo build --manifest-path {})", dir.display(), dir.join("Cargo.toml").display()));
+}
+
+/// `capabilities <input.lisp>`: print the capabilities the program would need in the sandbox
+fn run_capabilities_command(args: CapabilitiesArgs, reader: &parser::Reader) {
+    let CapabilitiesArgs { input: input_file, write_manifest: manifest_path } = args;
+    let source = read_source(&input_file, reader);
+
+    let capability_report = match infer_capabilities(&source.text, reader) {
+        Ok(capability_report) => capability_report,
+        Err(err) => {
+            logging::error(format_args!("Capability inference error: {}", err));
+            process::exit(1);
+        }
+    };
+    print!("{}", capability_report.render(&input_file));
+
+    if let Some(path) = &manifest_path {
+        let written = manifest::CapabilityManifest::from_report(&capability_report)
+            .to_toml()
+            .and_then(|toml| fs::write(path, toml).map_err(|e| e.to_string()));
+        if let Err(err) = written {
+            logging::error(format_args!("Error writing manifest '{}': {}", path, err));
+            process::exit(1);
+        }
+        println!("\nWrote capability manifest to {} (use with --capabilities {})", path, path);
+    }
+}
+
+/// `deps [--format text|dot|json] <input.lisp>`: print the dependency graph between
+/// the program's definitions
+fn run_deps_command(args: DepsArgs, reader: &parser::Reader) {
+    let DepsArgs { input: input_file, format } = args;
+    let source = read_source(&input_file, reader);
+
+    let program = match reader.read(&source.text) {
+        Ok(program) => program,
+        Err(err) => {
+            log_error("Parse error", &err);
+            process::exit(1);
+        }
+    };
+    let graph = deps::DependencyGraph::new(&program);
+    match format {
+        DepsFormat::Text => print!("{}", graph.render(&input_file)),
+        DepsFormat::Dot => print!("{}", graph.to_dot()),
+        DepsFormat::Json => {
+            let report = serde_json::json!({
+                "definitions": graph.definitions(),
+                "components": graph.components(),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).expect("dependency graph serializes"));
+        }
+    }
+}
 
-    if ast_dot || ast_visual {
-        // Visualization mode - parse AST and output visualization
-        let tokens = match lexer::tokenize(&source_code) {
-            Ok(t) => t,
+/// `fmt [--check] <file.lisp>...`: rewrite files in canonical style, or with
+/// `--check` list the files that are not and exit 1
+fn run_fmt_command(args: FmtArgs) {
+    let FmtArgs { inputs: input_files, check } = args;
+    let mut failed = false;
+    for input_file in &input_files {
+        let source = match fs::read_to_string(input_file) {
+            Ok(content) => content,
             Err(err) => {
-                eprintln!("Lexer error: {}", err);
+                logging::error(format_args!("Error reading file '{}': {}", input_file, err));
                 process::exit(1);
             }
         };
-
-        let ast = match parser::parse(tokens) {
-            Ok(a) => a,
+        let formatted = match formatter::format_source(&source) {
+            Ok(formatted) => formatted,
             Err(err) => {
-                eprintln!("Parser error: {}", err);
-                process::exit(1);
+                logging::error(format_args!("Error formatting '{}': {}", input_file, err));
+                failed = true;
+                continue;
             }
         };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("Would reformat {}", input_file);
+            failed = true;
+        } else if let Err(err) = fs::write(input_file, formatted) {
+            logging::error(format_args!("Error writing file '{}': {}", input_file, err));
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
 
-        if ast_dot {
-            let mut viz = visualizer::DotVisualizer::new();
-            println!("{}", viz.visualize(&ast));
-        } else if ast_visual {
-            let viz = visualizer::HtmlVisualizer::new();
-            println!("{}", viz.visualize(&ast));
-        }
-    } else if from_ir {
-        // Read from JSON IR and compile to Rust
-        match compile_from_ir(&source_code, registry, validate_safety) {
-            Ok(rust_code) => println!("{}", rust_code),
-            Err(err) => {
-                eprintln!("Compilation error: {}", err);
-                process::exit(1);
+/// `check [--allow|--warn|--deny LINT]... [--format text|json|sarif] <file.lisp>`: report
+/// every diagnostic up to code generation. Exits 0 when clean, 1 for warnings under
+/// `--deny warnings`, and 2 for errors, including invalid usage and denied lints.
+fn run_check_command(args: CheckArgs, reader: &parser::Reader) {
+    let CheckArgs { input: input_file, lints, format, output, stats } = args;
+    let source = match fs::read_to_string(&input_file) {
+        Ok(content) => content,
+        Err(err) => {
+            logging::error(format_args!("Error reading file '{}': {}", input_file, err));
+            process::exit(2);
+        }
+    };
+
+    let diagnostics = check_program(&source, reader, &input_file, stats, &lints.levels());
+    write_artifact(output.as_deref().map(Path::new), diagnostics.render(format).as_bytes());
+    if diagnostics.count_severity(Severity::Error) > 0 {
+        process::exit(2);
+    }
+    if lints.deny.contains(&lint::Lint::Warnings) && diagnostics.count_severity(Severity::Warning) > 0 {
+        process::exit(1);
+    }
+}
+
+/// Parse, validate, lint, and expand macros without generating code, collecting every
+/// diagnostic. Validation sees the program before and after expansion, as when compiling.
+/// With `stats`, the report includes each function's complexity metrics.
+fn check_program(source: &str, reader: &parser::Reader, file_name: &str, stats: bool, lints: &lint::LintLevels) -> report::ValidationReport {
+    let mut diagnostics = report::ValidationReport::new(file_name);
+    let source = match include::Source::resolve(file_name, source.to_string(), reader, None) {
+        Ok(source) => source,
+        Err(err) => {
+            diagnostics.add_diagnostic("Include", err.code(), Severity::Error, err.message(), None, &include::Source::new(file_name, source));
+            return diagnostics;
+        }
+    };
+    let source = &source;
+    let forms = match reader.read_with_span_trees(&source.text) {
+        Ok(forms) => forms,
+        Err(err) => {
+            diagnostics.add_diagnostic("Syntax", err.code(), Severity::Error, err.message(), None, source);
+            return diagnostics;
+        }
+    };
+    let (program, trees): (Vec<ast::LispExpr>, Vec<ast::SpanTree>) = forms.into_iter().unzip();
+    let spans: Vec<ast::Span> = trees.iter().map(|tree| tree.span.expect("parsed forms have spans")).collect();
+
+    let results = CompositeValidator::with_all_validators().validate_program(&program);
+    for (result, span) in results.iter().zip(&spans) {
+        if let Err(errors) = result {
+            diagnostics.add_errors(errors, Some(*span), source);
+        }
+    }
+    for finding in lint::lint_program(&program, Some(&trees), lints) {
+        diagnostics.add_errors(&[finding.error], finding.span, source);
+    }
+    if stats {
+        diagnostics.set_metrics(validator::complexity_metrics(&program));
+    }
+
+    let mut expander = macro_expander::MacroExpander::new();
+    let expanded = pipeline::expand_forms(&mut expander, program);
+    for (index, result) in &expanded {
+        if let Err(err) = result {
+            diagnostics.add_diagnostic("MacroExpansion", Some(err.code()), Severity::Error, &err.to_string(), Some(spans[*index]), source);
+        }
+    }
+    // Then validate the expanded program, as compiling does, leaving out what a form
+    // was already reported for before expansion
+    for (index, result) in pipeline::validate_expanded_forms(&expanded) {
+        let reported = results[index].as_ref().err().map(Vec::as_slice).unwrap_or_default();
+        let errors: Vec<_> = result
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|error| !reported.iter().any(|seen| seen.message == error.message))
+            .collect();
+        diagnostics.add_errors(&errors, Some(spans[index]), source);
+    }
+    diagnostics
+}
+
+/// `compile-lib [--lib FILE]... [-o PATH] <lib.lisp>`: expand a library once and write
+/// its macros and expanded forms as a bundle for `--lib`
+fn run_compile_lib_command(args: CompileLibArgs, reader: &parser::Reader) {
+    let CompileLibArgs { input: input_file, output, libraries } = args;
+    let dependencies = load_libraries(&libraries);
+    let source = read_source(&input_file, reader);
+    let compiled = reader
+        .read(&source.text)
+        .and_then(|program| library::Library::compile(program, &dependencies))
+        .and_then(|library| library.to_json().map_err(CompileError::from));
+    let bundle = compiled.unwrap_or_else(|err| {
+        log_error("Compilation error", &err);
+        process::exit(1);
+    });
+    let output = output.unwrap_or_else(|| Path::new(&input_file).with_extension("rlib.json").to_string_lossy().into_owned());
+    write_artifact(Some(Path::new(&output)), format!("{}\n", bundle).as_bytes());
+    logging::info(format_args!("Wrote library {} (use with --lib {})", output, output));
+}
+
+/// `test <file.lisp>`: build the file's `deftest` forms into a test program and run
+/// it, exiting 1 if any test fails
+fn run_test_command(input_file: &str, reader: &parser::Reader) {
+    let source = read_source(input_file, reader);
+
+    let rust_code = match test_program(&source.text, reader) {
+        Ok(Some(rust_code)) => rust_code,
+        Ok(None) => {
+            println!("No deftest forms found in {}", input_file);
+            return;
+        }
+        Err(err) => {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        }
+    };
+    match runner::run(&rust_code, runner::Target::Native, &sandbox::SandboxConfig::new()) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            logging::error(format_args!("Run error: {}", err));
+            process::exit(1);
+        }
+    }
+}
+
+/// The test program for `source` after macro expansion, or `None` without tests
+fn test_program(source: &str, reader: &parser::Reader) -> Result<Option<String>, CompileError> {
+    let (program, _) = program_at_stage(source, reader, &TransformRegistry::new(), IrStage::Expanded)?;
+    if !program.iter().any(|expr| compiler::test_name(expr).is_some()) {
+        return Ok(None);
+    }
+    compiler::compile_tests(&program).map(Some)
+}
+
+/// `debug [--break NAME]... [--contracts MODE] <file.lisp>`: run the file in the interpreter under the
+/// step debugger, reading commands from stdin
+fn run_debug_command(args: DebugArgs, reader: &parser::Reader) {
+    let source = read_source(&args.input, reader);
+    // Macros stay unexpanded so breakpoints on macro names can stop at their calls
+    let program = match program_at_stage(&source.text, reader, &TransformRegistry::new(), IrStage::Transformed) {
+        Ok((program, _)) => program,
+        Err(err) => {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = debugger::debug_program(&program, args.breakpoints, args.contracts.enabled(), io::stdin().lock(), io::stdout()) {
+        logging::error(format_args!("Runtime error: {}", err));
+        process::exit(1);
+    }
+}
+
+/// `watch [--interval MS] [--contracts MODE] <file.lisp>`: evaluate the file in the interpreter, then
+/// re-evaluate its new and changed forms, and the forms that depend on them, whenever it changes
+fn run_watch_command(args: WatchArgs, reader: &parser::Reader) {
+    let path = Path::new(&args.input);
+    let mut session = repl::Repl::new().with_contracts(args.contracts.enabled()).with_reader(reader.clone());
+    let mut version = String::new();
+    loop {
+        let current = server::file_version(path);
+        if current != version {
+            version = current;
+            let source = fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| include::Source::resolve(&args.input, text, reader, None).map_err(String::from));
+            match source.and_then(|source| session.reload(&args.input, &source.text)) {
+                Ok(reload) => {
+                    for (_, value) in &reload.evaluated {
+                        println!("{}", value);
+                    }
+                    println!("{}", reload.summary(&args.input));
+                    if let Some(err) = reload.error {
+                        logging::error(format_args!("Runtime error: {}", err));
+                    }
+                }
+                Err(err) => logging::error(format_args!("Error reloading '{}': {}", args.input, err)),
             }
         }
-    } else if to_ir {
-        // Compile to JSON IR
-        match compile_to_ir(&source_code, registry, validate_safety) {
-            Ok(json_ir) => println!("{}", json_ir),
-            Err(err) => {
-                eprintln!("Compilation error: {}", err);
+        std::thread::sleep(std::time::Duration::from_millis(args.interval));
+    }
+}
+
+/// `bench [--warmup N] [--iterations N] <file.lisp>`: build the file's `defbench`
+/// forms into a program that times them, and run it
+fn run_bench_command(args: BenchArgs, reader: &parser::Reader) {
+    let BenchArgs { input: input_file, warmup, iterations } = args;
+    let source = read_source(&input_file, reader);
+
+    let rust_code = match bench_program(&source.text, reader, warmup, iterations) {
+        Ok(Some(rust_code)) => rust_code,
+        Ok(None) => {
+            println!("No defbench forms found in {}", input_file);
+            return;
+        }
+        Err(err) => {
+            log_error("Compilation error", &err);
+            process::exit(1);
+        }
+    };
+    match runner::run(&rust_code, runner::Target::Native, &sandbox::SandboxConfig::new()) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            logging::error(format_args!("Run error: {}", err));
+            process::exit(1);
+        }
+    }
+}
+
+/// The benchmark program for `source` after macro expansion, or `None` without benchmarks
+fn bench_program(source: &str, reader: &parser::Reader, warmup: usize, iterations: usize) -> Result<Option<String>, CompileError> {
+    let (program, _) = program_at_stage(source, reader, &TransformRegistry::new(), IrStage::Expanded)?;
+    if !program.iter().any(|expr| compiler::bench_name(expr).is_some()) {
+        return Ok(None);
+    }
+    compiler::compile_benches(&program, warmup, iterations).map(Some)
+}
+
+/// `serve <file.lisp> [--port N]`: browse the HTML visualizer, reloading on file changes
+fn run_serve_command(args: ServeArgs, reader: &parser::Reader) {
+    let ServeArgs { input: input_file, port } = args;
+    let server = match server::VisualizerServer::bind(&input_file, port) {
+        Ok(server) => server.with_reader(reader.clone()),
+        Err(err) => {
+            logging::error(format_args!("Error: {}", err));
+            process::exit(1);
+        }
+    };
+    if let Ok(address) = server.local_addr() {
+        println!("Serving {} at http://{} (Ctrl+C to stop)", input_file, address);
+    }
+    if let Err(err) = server.run() {
+        logging::error(format_args!("Server error: {}", err));
+        process::exit(1);
+    }
+}
+
+/// `ir-check <file.json>`: validate a JSON IR document against the IR schema
+fn run_ir_check_command(input_file: &str) {
+    let source = match fs::read_to_string(input_file) {
+        Ok(content) => content,
+        Err(err) => {
+            logging::error(format_args!("Error reading file '{}': {}", input_file, err));
+            process::exit(1);
+        }
+    };
+    let errors = match serde_json::from_str(&source) {
+        Ok(document) => ir_schema::check(&document),
+        Err(err) => {
+            logging::error(format_args!("{}: invalid JSON: {}", input_file, err));
+            process::exit(1);
+        }
+    };
+
+    if errors.is_empty() {
+        println!("{}: valid IR", input_file);
+        return;
+    }
+    let mut message = format!("{}: {} schema error(s)", input_file, errors.len());
+    for error in &errors {
+        message.push_str(&format!("\n  {}", error));
+    }
+    logging::error(message);
+    process::exit(1);
+}
+
+/// `ir-to-lisp <file>`: print IR in any format back as Lisp source
+/// `selftest`: generate programs from consecutive seeds and check each reads back
+/// from its source and from the printer's output, stopping at the first that does not
+fn run_selftest_command(args: SelftestArgs) {
+    let SelftestArgs { cases, seed } = args;
+    for case in 0..cases {
+        let case_seed = seed.wrapping_add(case);
+        if let Err(err) = testing::check_case(case_seed) {
+            logging::error(format_args!("Case {} failed (reproduce with --seed {} --cases 1): {}", case, case_seed, err));
+            process::exit(1);
+        }
+    }
+    println!("{} cases passed (seeds {} to {})", cases, seed, seed.wrapping_add(cases.saturating_sub(1)));
+}
+
+/// `explain [CODE]`: print the explanation of an error code, or list every code
+fn run_explain_command(args: ExplainArgs) {
+    match args.code {
+        Some(code) => match explain::lookup(&code) {
+            Some(known) => println!("{}", known),
+            None if explain::is_rustc_code(&code) => {
+                logging::error(format_args!(
+                    "'{}' is a rustc error code, from compiling the generated Rust; run `rustc --explain {}`",
+                    code.trim(),
+                    code.trim()
+                ));
                 process::exit(1);
             }
-        }
-    } else {
-        // Normal compilation to Rust
-        match compile_lisp(&source_code, registry, validate_safety) {
-            Ok(rust_code) => println!("{}", rust_code),
-            Err(err) => {
-                eprintln!("Compilation error: {}", err);
+            None => {
+                logging::error(format_args!("Unknown error code '{}'; run `explain` without a code to list them", code));
                 process::exit(1);
             }
+        },
+        None => {
+            for known in explain::ERROR_CODES {
+                println!("{}  {}", known.code, known.title);
+            }
+        }
+    }
+}
+
+fn run_ir_to_lisp_command(input_file: &str) {
+    let source = match fs::read(input_file) {
+        Ok(content) => content,
+        Err(err) => {
+            logging::error(format_args!("Error reading file '{}': {}", input_file, err));
+            process::exit(1);
+        }
+    };
+    match ir::decode(&source, ir::IrFormat::detect(&source)) {
+        Ok(exprs) => print!("{}", ir_to_lisp(&exprs)),
+        Err(err) => {
+            logging::error(format_args!("{}: {}", input_file, err));
+            process::exit(1);
+        }
+    }
+}
+
+/// Top-level forms as source, separated by blank lines
+fn ir_to_lisp(exprs: &[ast::LispExpr]) -> String {
+    exprs.iter().map(|expr| expr.to_source() + "\n").collect::<Vec<_>>().join("\n")
+}
+
+/// Loads and validates a capability manifest file
+/// Read a Lisp source file with the files it includes spliced in, exiting when it
+/// cannot be read or an include cannot be resolved
+fn read_source(path: &str, reader: &parser::Reader) -> include::Source {
+    let text = fs::read_to_string(path).unwrap_or_else(|err| {
+        logging::error(format_args!("Error reading file '{}': {}", path, err));
+        process::exit(1);
+    });
+    include::Source::resolve(path, text, reader, None).unwrap_or_else(|err| {
+        log_error("Compilation error", &err);
+        process::exit(1);
+    })
+}
+
+/// Load the library bundles at `paths`, exiting when one cannot be loaded
+fn load_libraries(paths: &[String]) -> Vec<library::Library> {
+    paths
+        .iter()
+        .map(|path| {
+            fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|source| library::Library::from_json(&source)).unwrap_or_else(|err| {
+                logging::error(format_args!("Error loading library '{}': {}", path, err));
+                process::exit(1);
+            })
+        })
+        .collect()
+}
+
+fn load_capability_manifest(path: &str) -> Result<manifest::CapabilityManifest, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("cannot read '{}': {}", path, e))?;
+    manifest::CapabilityManifest::from_toml(&source)
+}
+
+impl SandboxOptions {
+    /// Whether to sandbox the program; naming a profile, manifest, denied
+    /// capability, or audit log implies `--sandbox-mode`
+    fn enabled(&self) -> bool {
+        self.sandbox_mode
+            || self.profile.is_some()
+            || self.manifest.is_some()
+            || !self.denied.is_empty()
+            || self.audit_log.is_some()
+    }
+
+    /// Layers the settings regardless of flag order: profile, then manifest grants,
+    /// then individual `--max-memory`/`--timeout`/`--max-fuel`/`--max-expansion-nodes`/`--allow-capability` flags, and
+    /// finally `--deny-capability`, which overrides everything before it
+    fn build(&self) -> Result<sandbox::SandboxConfig, String> {
+        let mut config = match (&self.profile, &self.manifest) {
+            (Some(name), Some(manifest)) => manifest.profile_config(name)?,
+            (Some(name), None) => sandbox::SandboxProfile::parse(name)?.config(),
+            (None, Some(manifest)) => manifest.base_config()?,
+            (None, None) => sandbox::SandboxConfig::new(),
+        };
+        if let Some(manifest) = &self.manifest {
+            config = manifest.apply_to(config)?;
+        }
+        if let Some(bytes) = self.max_memory {
+            config = config.with_max_memory(bytes);
+        }
+        if let Some(timeout) = self.timeout {
+            config = config.with_max_execution_time(timeout);
+        }
+        if let Some(fuel) = self.max_fuel {
+            config = config.with_max_fuel(fuel);
+        }
+        if let Some(nodes) = self.max_expansion_nodes {
+            config = config.with_max_expansion_nodes(nodes as usize);
+        }
+        for capability in &self.granted {
+            config.add_capability(capability.clone());
+        }
+        for capability in &self.denied {
+            config.deny_capability(capability.clone());
+        }
+        if let Some(path) = &self.audit_log {
+            config = config.with_audit_log(path.clone());
         }
+        Ok(config)
+    }
+}
+
+/// Infers required capabilities after macro expansion, so macro-generated calls are included
+fn infer_capabilities(source: &str, reader: &parser::Reader) -> Result<capabilities::CapabilityReport, String> {
+    let ast = reader.read(source)?;
+
+    let mut expander = macro_expander::MacroExpander::new();
+    let mut expanded_ast = Vec::new();
+    for expr in ast {
+        let expanded = expander.expand_all(expr)
+            .map_err(|e| format!("Macro expansion error: {}", e))?;
+        if !matches!(expanded, ast::LispExpr::Nil) {
+            expanded_ast.push(expanded);
+        }
+    }
+
+    Ok(capabilities::CapabilityReport::infer(&expanded_ast))
+}
+
+#[cfg(test)]
+fn compile_lisp(
+    source: &str,
+    registry: TransformRegistry,
+    validate_safety: bool,
+    sandbox: Option<&sandbox::SandboxConfig>,
+    seed: Option<u64>,
+) -> Result<String, CompileError> {
+    rust_pipeline(registry, validate_safety, sandbox, seed).compile(source)
+}
+
+/// The pipeline generating Rust with these settings
+fn rust_pipeline<'a>(
+    registry: TransformRegistry,
+    validate_safety: bool,
+    sandbox: Option<&'a sandbox::SandboxConfig>,
+    seed: Option<u64>,
+) -> Pipeline<'a> {
+    let mut pipeline = Pipeline::new().with_transforms(registry).with_validation(validate_safety);
+    if let Some(config) = sandbox {
+        pipeline = pipeline.with_sandbox(config);
+    }
+    if let Some(seed) = seed {
+        pipeline = pipeline.with_seed(seed);
+    }
+    pipeline
+}
+
+/// What `--provenance` records about a build of `source`
+fn provenance_record(
+    backend: &dyn Backend,
+    source: &include::Source,
+    library_paths: &[String],
+    transforms: &[String],
+    validate_safety: bool,
+    sandbox: Option<(&SandboxOptions, &sandbox::SandboxConfig)>,
+    deterministic: bool,
+) -> provenance::Provenance {
+    let mut record = provenance::Provenance::new(backend.name()).with_transforms(transforms.to_vec());
+    for file in &source.files {
+        record = record.with_input(&file.path, file.text.as_bytes());
+    }
+    for path in library_paths {
+        // Libraries were loaded already, so they can be read
+        record = record.with_input(path, &fs::read(path).unwrap_or_default());
+    }
+    let mut validators: Vec<String> = Vec::new();
+    if validate_safety {
+        validators.extend(CompositeValidator::with_all_validators().enabled_rules().iter().map(|rule| format!("{:?}", rule)));
+    }
+    if let Some((options, config)) = sandbox {
+        // Sandboxed code is checked against the memory limit
+        validators.push(format!("{:?}", validator::ValidationRule::MemoryBounds));
+        let sorted = |capabilities: &HashSet<sandbox::Capability>| {
+            let mut names: Vec<String> = capabilities.iter().map(ToString::to_string).collect();
+            names.sort();
+            names
+        };
+        record = record.with_sandbox(provenance::SandboxRecord {
+            profile: options.profile.clone(),
+            granted: sorted(&config.capabilities),
+            denied: sorted(&config.denied_capabilities),
+        });
+    }
+    record = record.with_validators(validators);
+    if !deterministic {
+        record = record.with_timestamp(provenance::timestamp(env::var(provenance::SOURCE_DATE_EPOCH).ok().as_deref()));
+    }
+    record
+}
+
+/// Writes `--name-map` once the program has compiled
+struct NameMap {
+    path: PathBuf,
+    names: Vec<mangle::MangledName>,
+}
+
+impl PipelineObserver for NameMap {
+    fn on_expanded(&mut self, program: &[ast::LispExpr]) -> Result<(), String> {
+        self.names = mangle::names(program);
+        Ok(())
+    }
+
+    fn on_codegen(&mut self, _code: &str) -> Result<(), String> {
+        fs::write(&self.path, format!("{}\n", mangle::to_json(&self.names)))
+            .map_err(|e| format!("Cannot write name map '{}': {}", self.path.display(), e))
+    }
+}
+
+/// Records the crates the expanded program requires with `require-crate`
+#[derive(Default)]
+struct RequiredCrates(Vec<crates::CrateRequirement>);
+
+impl PipelineObserver for RequiredCrates {
+    fn on_expanded(&mut self, program: &[ast::LispExpr]) -> Result<(), String> {
+        // Errors finding the crates are reported when the program is compiled
+        self.0 = crates::requirements(program).unwrap_or_default();
+        Ok(())
+    }
+}
+
+/// Pipeline stage whose AST `--to-ir` exports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IrStage {
+    /// Straight from the parser, before transforms
+    Parsed,
+    /// After transforms; macro definitions and calls are kept
+    Transformed,
+    /// After macro expansion, ready to compile
+    Expanded,
+}
+
+impl IrStage {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "parsed" => Ok(IrStage::Parsed),
+            "transformed" => Ok(IrStage::Transformed),
+            "expanded" => Ok(IrStage::Expanded),
+            other => Err(format!("Unknown IR stage '{}' (expected parsed, transformed, or expanded)", other)),
+        }
+    }
+}
+
+/// Write an output file, or to stdout without a path
+fn write_artifact(path: Option<&Path>, contents: &[u8]) {
+    let written = match path {
+        Some(path) => create_artifact(path).write_all(contents),
+        None => std::io::stdout().write_all(contents),
+    };
+    if let Err(err) = written {
+        logging::error(format_args!("Error writing '{}': {}", path.map_or("stdout".into(), Path::to_string_lossy), err));
+        process::exit(1);
     }
 }
 
-fn print_usage(program_name: &str) {
-    eprintln!("Usage: {} [OPTIONS] <input.lisp>", program_name);
-    eprintln!();
-    eprintln!("Options:");
-    eprintln!("  --transforms <list>         Comma-separated list of transforms to apply");
-    eprintln!("                              Available: echo");
-    eprintln!("  --from-ir                   Read JSON IR as input instead of Lisp source");
-    eprintln!("  --to-ir                     Output JSON IR instead of Rust code");
-    eprintln!("  --validate-safety           Enable AST validation (type safety, resource bounds,");
-    eprintln!("                              FFI restrictions, complexity limits)");
-    eprintln!("  --sandbox-mode              Enable sandbox execution with security restrictions");
-    eprintln!("  --max-memory <size>         Set maximum memory limit (e.g., 100MB, 1GB)");
-    eprintln!("  --timeout <duration>        Set maximum execution time (e.g., 30s, 5m)");
-    eprintln!("  --allow-capability <cap>    Grant specific capability (see below)");
-    eprintln!("  --ast-dot                   Output AST as DOT graph (for Graphviz)");
-    eprintln!("  --ast-visual                Output interactive HTML AST visualization");
-    eprintln!();
-    eprintln!("Capabilities:");
-    eprintln!("  FileRead:<path>             Allow reading from specific file path");
-    eprintln!("  FileWrite:<path>            Allow writing to specific file path");
-    eprintln!("  NetworkHTTP                 Allow HTTP network requests");
-    eprintln!("  SystemTime                  Allow accessing system time");
-    eprintln!("  ProcessSpawn                Allow spawning child processes");
-    eprintln!("  UnsafeRust                  Allow using unsafe Rust features");
-    eprintln!();
-    eprintln!("Examples:");
-    eprintln!("  {} example.lisp                     # Compile Lisp to Rust", program_name);
-    eprintln!("  {} --validate-safety example.lisp   # Compile with validation", program_name);
-    eprintln!("  {} --to-ir example.lisp > out.json  # Convert Lisp to JSON IR", program_name);
-    eprintln!("  {} --from-ir out.json               # Compile JSON IR to Rust", program_name);
-    eprintln!("  {} --sandbox-mode --max-memory=100MB --timeout=30s example.lisp", program_name);
-    eprintln!("  {} --sandbox-mode --allow-capability=FileRead:/tmp example.lisp", program_name);
-    eprintln!("  {} --ast-dot example.lisp | dot -Tpng > ast.png  # Visualize AST as PNG", program_name);
-    eprintln!("  {} --ast-visual example.lisp > ast.html         # Interactive HTML visualization", program_name);
-}
-
-fn compile_lisp(source: &str, registry: TransformRegistry, validate_safety: bool) -> Result<String, String> {
-    let tokens = lexer::tokenize(source)?;
-    let ast = parser::parse(tokens)?;
-
-    // Apply AST transformations (between parsing and macro expansion)
-    let mut transformed_ast = Vec::new();
-    for mut expr in ast {
-        registry.apply_all(&mut expr)
-            .map_err(|e| format!("Transform error: {}", e))?;
-        transformed_ast.push(expr);
+/// Create an output file and any missing parent directories
+fn create_artifact(path: &Path) -> fs::File {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    parent.map_or(Ok(()), fs::create_dir_all).and_then(|()| fs::File::create(path)).unwrap_or_else(|err| {
+        logging::error(format_args!("Error writing '{}': {}", path.display(), err));
+        process::exit(1);
+    })
+}
+
+/// The parsed program, then the program after each transform and after macro expansion
+fn pipeline_stages(source: &str, reader: &parser::Reader, registry: &TransformRegistry) -> Result<Vec<visualizer::PipelineStage>, CompileError> {
+    let ast = reader.read(source)?;
+    let mut stages = vec![visualizer::PipelineStage::new("parsed", ast)];
+    let transformed = registry.apply_each(&stages[0].exprs)?;
+    stages.extend(transformed.into_iter().map(|(name, exprs)| visualizer::PipelineStage::new(format!("transform: {}", name), exprs)));
+
+    let mut expander = macro_expander::MacroExpander::new();
+    let mut expanded_ast = Vec::new();
+    for expr in stages.last().map(|stage| stage.exprs.clone()).unwrap_or_default() {
+        let expanded = expander.expand_all(expr)?;
+        if !matches!(expanded, ast::LispExpr::Nil) {
+            expanded_ast.push(expanded);
+        }
     }
+    stages.push(visualizer::PipelineStage::new("macro expansion", expanded_ast));
+    Ok(stages)
+}
 
-    // Validate AST if safety checks are enabled (pre-macro expansion)
-    if validate_safety {
-        validate_ast(&transformed_ast)?;
+/// The program as it stands after `stage` of the pipeline, with each top-level
+/// expression's source spans (line and column included) followed through the rewrites
+fn program_at_stage(
+    source: &str,
+    reader: &parser::Reader,
+    registry: &TransformRegistry,
+    stage: IrStage,
+) -> Result<(Vec<ast::LispExpr>, Vec<ast::SpanTree>), CompileError> {
+    let (mut ast, mut spans): (Vec<ast::LispExpr>, Vec<ast::SpanTree>) = reader.read_with_span_trees(source)?.into_iter().unzip();
+    spans = spans.into_iter().map(|tree| tree.with_lines(source)).collect();
+    if stage == IrStage::Parsed {
+        return Ok((ast, spans));
+    }
+    let no_macros = HashSet::new();
+    for (expr, tree) in ast.iter_mut().zip(spans.iter_mut()) {
+        let original = expr.clone();
+        registry.apply_all(expr)?;
+        *tree = tree.follow(&original, expr, &no_macros);
+    }
+    if stage == IrStage::Transformed {
+        return Ok((ast, spans));
     }
 
-    // Expand macros in the transformed AST
     let mut expander = macro_expander::MacroExpander::new();
+    let mut macros = HashSet::new();
     let mut expanded_ast = Vec::new();
-
-    for expr in transformed_ast {
+    let mut expanded_spans = Vec::new();
+    for (expr, tree) in ast.into_iter().zip(spans) {
+        if let ast::LispExpr::Macro { name, .. } = &expr {
+            macros.insert(name.to_string());
+        }
+        let original = expr.clone();
         let expanded = expander.expand_all(expr)
-            .map_err(|e| format!("Macro expansion error: {}", e))?;
-
-        // Skip Nil expressions (from macro definitions)
+            .and_then(|expanded| expander.apply_compiler_macros(expanded))?;
+        macros.extend(expander.compiler_macro_names());
         if !matches!(expanded, ast::LispExpr::Nil) {
+            expanded_spans.push(tree.follow(&original, &expanded, &macros));
             expanded_ast.push(expanded);
         }
     }
+    Ok((expanded_ast, expanded_spans))
+}
 
-    let rust_code = compiler::compile_to_rust(&expanded_ast)?;
-    Ok(rust_code)
+/// How `--to-ir` exports the program
+#[derive(Debug, Clone, Copy)]
+struct IrOutput {
+    stage: IrStage,
+    format: ir::IrFormat,
+    include_spans: bool,
+    canonical: bool,
+    intern: bool,
 }
 
-fn compile_to_ir(source: &str, registry: TransformRegistry, validate_safety: bool) -> Result<String, String> {
-    let tokens = lexer::tokenize(source)?;
-    let ast = parser::parse(tokens)?;
+impl Default for IrOutput {
+    fn default() -> Self {
+        IrOutput { stage: IrStage::Expanded, format: ir::IrFormat::Json, include_spans: false, canonical: false, intern: false }
+    }
+}
+
+fn compile_to_ir(
+    source: &str,
+    reader: &parser::Reader,
+    registry: TransformRegistry,
+    validate_safety: bool,
+    output: IrOutput,
+) -> Result<Vec<u8>, CompileError> {
+    let IrOutput { stage, format, include_spans, canonical, intern } = output;
+    let (ast, parsed_spans): (Vec<ast::LispExpr>, Vec<ast::SpanTree>) = reader.read_with_span_trees(source)?.into_iter().unzip();
+    let parsed_ast = (stage == IrStage::Parsed).then(|| ast.clone());
+    let encode = |exprs: Vec<ast::LispExpr>, spans: Vec<ast::SpanTree>| {
+        let mut document = ir::IrDocument::new(exprs);
+        if include_spans {
+            document = document.with_spans(spans.into_iter().map(|tree| tree.with_lines(source)).collect());
+        }
+        if intern {
+            document = document.with_interned_strings();
+        }
+        let encoded = if canonical {
+            ir::encode_canonical(document, format)
+        } else {
+            ir::encode_document(&document, format)
+        };
+        Ok(encoded?)
+    };
 
-    // Apply AST transformations
+    // Apply AST transformations, following each node's span through the rewrite
+    let no_macros = HashSet::new();
     let mut transformed_ast = Vec::new();
-    for mut expr in ast {
-        registry.apply_all(&mut expr)
-            .map_err(|e| format!("Transform error: {}", e))?;
+    let mut spans = Vec::new();
+    for (mut expr, tree) in ast.into_iter().zip(parsed_spans.iter()) {
+        let original = include_spans.then(|| expr.clone());
+        registry.apply_all(&mut expr)?;
+        if let Some(original) = original {
+            spans.push(tree.follow(&original, &expr, &no_macros));
+        }
         transformed_ast.push(expr);
     }
 
@@ -293,120 +1421,211 @@ fn compile_to_ir(source: &str, registry: TransformRegistry, validate_safety: boo
         validate_ast(&transformed_ast)?;
     }
 
-    // Expand macros
+    if let Some(parsed_ast) = parsed_ast {
+        return encode(parsed_ast, parsed_spans);
+    }
+    if stage == IrStage::Transformed {
+        return encode(transformed_ast, spans);
+    }
+
+    // Expand macros; generated nodes record the macro call they came from
     let mut expander = macro_expander::MacroExpander::new();
+    let mut macros = HashSet::new();
     let mut expanded_ast = Vec::new();
+    let mut expanded_spans = Vec::new();
 
-    for expr in transformed_ast {
+    for (index, expr) in transformed_ast.into_iter().enumerate() {
+        if let ast::LispExpr::Macro { name, .. } = &expr {
+            macros.insert(name.to_string());
+        }
+        let original = include_spans.then(|| expr.clone());
         let expanded = expander.expand_all(expr)
-            .map_err(|e| format!("Macro expansion error: {}", e))?;
+            .and_then(|expanded| expander.apply_compiler_macros(expanded))?;
+        macros.extend(expander.compiler_macro_names());
 
         // Skip Nil expressions (from macro definitions)
         if !matches!(expanded, ast::LispExpr::Nil) {
+            if let Some(original) = original {
+                expanded_spans.push(spans[index].follow(&original, &expanded, &macros));
+            }
             expanded_ast.push(expanded);
         }
     }
 
-    // Serialize to JSON
-    serde_json::to_string_pretty(&expanded_ast)
-        .map_err(|e| format!("JSON serialization error: {}", e))
+    encode(expanded_ast, expanded_spans)
 }
 
-fn compile_from_ir(json_source: &str, _registry: TransformRegistry, validate_safety: bool) -> Result<String, String> {
-    // Deserialize JSON IR to AST
-    let ast: Vec<ast::LispExpr> = serde_json::from_str(json_source)
-        .map_err(|e| format!("JSON deserialization error: {}", e))?;
+fn compile_from_ir(ir_source: &[u8], format: ir::IrFormat, pipeline: Pipeline) -> Result<String, CompileError> {
+    // Deserialize IR to AST
+    let ast = ir::decode(ir_source, format)?;
+    compile_ir_exprs(ast.into_iter().map(Ok), pipeline)
+}
 
-    // Validate if safety checks are enabled (even for IR input)
-    if validate_safety {
-        validate_ast(&ast)?;
-    }
+/// Compile newline-delimited IR, read a line at a time
+fn compile_from_ir_stream(reader: impl std::io::BufRead, pipeline: Pipeline) -> Result<String, CompileError> {
+    compile_ir_exprs(ir::StreamReader::new(reader), pipeline)
+}
 
-    // Note: Transforms and macro expansion are already applied in IR
-    // Just compile to Rust
-    let rust_code = compiler::compile_to_rust(&ast)?;
-    Ok(rust_code)
+/// Compile IR with `pipeline`, which validates, expands, and generates it as it does
+/// source. Transforms are already applied in IR. Macros are too, unless it was exported
+/// from an earlier --ir-stage, so the pipeline expands whatever is left.
+fn compile_ir_exprs(exprs: impl Iterator<Item = Result<ast::LispExpr, String>>, mut pipeline: Pipeline) -> Result<String, CompileError> {
+    let program = exprs.collect::<Result<Vec<_>, _>>()?;
+    pipeline.compile_ast(program)
 }
 
-/// Validates AST expressions using all available validators
-fn validate_ast(ast: &[ast::LispExpr]) -> Result<(), String> {
-    let composite_validator = CompositeValidator::new()
-        .add_validator(Box::new(TypeSafetyValidator::new()))
-        .add_validator(Box::new(ResourceBoundsValidator::new()))
-        .add_validator(Box::new(FFIRestrictionsValidator::new()))
-        .add_validator(Box::new(ComplexityLimitsValidator::new()));
+/// Like `compile_to_ir`, but writes NDJSON IR, each expression as soon as it is ready
+fn compile_to_ir_stream(
+    source: &str,
+    reader: &parser::Reader,
+    registry: TransformRegistry,
+    validate_safety: bool,
+    stage: IrStage,
+    deterministic: bool,
+    out: impl Write,
+) -> Result<(), CompileError> {
+    let mut ast = reader.read(source)?;
+    let mut writer = ir::StreamWriter::new(out)?;
+    if stage == IrStage::Parsed {
+        if validate_safety {
+            validate_ast(&ast)?;
+        }
+        for expr in &ast {
+            writer.write(expr)?;
+        }
+        writer.finish()?;
+        return Ok(());
+    }
+
+    for expr in &mut ast {
+        registry.apply_all(expr)?;
+    }
+    if validate_safety {
+        validate_ast(&ast)?;
+    }
 
+    let mut expander = macro_expander::MacroExpander::new();
     for expr in ast {
-        if let Err(errors) = composite_validator.validate_all(expr) {
-            // Format all validation errors into a single error message
-            let error_messages: Vec<String> = errors
-                .iter()
-                .map(|e| format!("  - {}", e))
-                .collect();
-            return Err(format!(
-                "Validation failed with {} error(s):\n{}",
-                errors.len(),
-                error_messages.join("\n")
-            ));
+        if stage == IrStage::Transformed {
+            writer.write(&expr)?;
+            continue;
+        }
+        let mut expanded = expander.expand_all(expr)
+            .and_then(|expanded| expander.apply_compiler_macros(expanded))?;
+        if deterministic {
+            ir::canonicalize_program(std::slice::from_mut(&mut expanded));
+        }
+        if !matches!(expanded, ast::LispExpr::Nil) {
+            writer.write(&expanded)?;
         }
     }
-
+    writer.finish()?;
     Ok(())
 }
 
-/// Parse memory size string (e.g., "100MB", "1GB", "512KB") into bytes
-fn parse_memory_size(s: &str) -> Result<usize, String> {
-    let s = s.trim().to_uppercase();
-
-    // Try to extract number and unit
-    let (num_str, unit) = if let Some(pos) = s.find(|c: char| c.is_alphabetic()) {
-        s.split_at(pos)
+/// Runs all validators over the program as `build --validate-safety` does at `stage`,
+/// and collects every finding that stops it, located by the span of its top-level form
+#[allow(clippy::too_many_arguments)]
+fn build_validation_report(
+    source: &[u8],
+    reader: &parser::Reader,
+    file_name: &str,
+    registry: &TransformRegistry,
+    ir_input: Option<ir::IrFormat>,
+    sandbox: Option<&sandbox::SandboxConfig>,
+    stage: pipeline::ValidationStage,
+    libraries: &[library::Library],
+) -> Result<report::ValidationReport, String> {
+    let (program, spans, text): (Vec<ast::LispExpr>, Vec<Option<ast::Span>>, include::Source) = if let Some(format) = ir_input {
+        let ast = ir::decode(source, format)?;
+        let spans = vec![None; ast.len()];
+        (ast, spans, include::Source::new(file_name, ""))
     } else {
-        // No unit specified, assume bytes
-        return s.parse::<usize>()
-            .map_err(|e| format!("Invalid memory size: {}", e));
-    };
-
-    let num: usize = num_str.trim().parse()
-        .map_err(|e| format!("Invalid memory size number: {}", e))?;
-
-    let multiplier = match unit.trim() {
-        "B" => 1,
-        "KB" => 1024,
-        "MB" => 1024 * 1024,
-        "GB" => 1024 * 1024 * 1024,
-        other => return Err(format!("Unknown memory unit: {}", other)),
+        let text = include::Source::resolve(file_name, lisp_text(source)?.to_string(), reader, sandbox)?;
+        let mut program = Vec::new();
+        let mut spans = Vec::new();
+        for (mut expr, span) in reader.read_with_spans(&text.text)? {
+            registry.apply_all(&mut expr)
+                .map_err(|e| format!("Transform error: {}", e))?;
+            program.push(expr);
+            spans.push(Some(span));
+        }
+        (program, spans, text)
     };
 
-    Ok(num * multiplier)
+    let mut validation_report = report::ValidationReport::new(file_name);
+    validation_report.set_metrics(validator::complexity_metrics(&program));
+    // The findings compiling stops on: those before expansion, else those after it
+    if stage.pre() {
+        let results = CompositeValidator::with_all_validators().validate_program(&program);
+        if results.iter().any(Result::is_err) {
+            for (result, span) in results.into_iter().zip(&spans) {
+                if let Err(errors) = result {
+                    validation_report.add_errors(&errors, *span, &text);
+                }
+            }
+            return Ok(validation_report);
+        }
+    }
+    if stage.post() {
+        let mut expander = macro_expander::MacroExpander::new();
+        for library in libraries {
+            library.define_macros(&mut expander)?;
+        }
+        // Compiling stops at the first form that fails to expand
+        let mut expanded = pipeline::expand_forms(&mut expander, program);
+        if let Some(failed) = expanded.iter().position(|(_, result)| result.is_err()) {
+            expanded.truncate(failed);
+        }
+        for (index, result) in pipeline::validate_expanded_forms(&expanded) {
+            if let Err(errors) = result {
+                validation_report.add_errors(&errors, spans[index], &text);
+            }
+        }
+    }
+    Ok(validation_report)
 }
 
-/// Parse duration string (e.g., "30s", "5m", "1h") into Duration
-fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
-    let s = s.trim();
-
-    // Try to extract number and unit
-    let (num_str, unit) = if let Some(pos) = s.find(|c: char| c.is_alphabetic()) {
-        s.split_at(pos)
-    } else {
-        // No unit specified, assume seconds
-        let secs: u64 = s.parse()
-            .map_err(|e| format!("Invalid duration: {}", e))?;
-        return Ok(std::time::Duration::from_secs(secs));
-    };
+/// Applies machine-applicable validator fixes to the program, returning the
+/// rewritten source and the number of fixes applied. Only the top-level forms
+/// that changed are re-printed, so formatting elsewhere is preserved.
+fn apply_suggested_fixes(source: &[u8], reader: &parser::Reader, ir_input: Option<ir::IrFormat>) -> Result<(Vec<u8>, usize), String> {
+    let composite_validator = CompositeValidator::with_all_validators();
+
+    if let Some(format) = ir_input {
+        let mut ast = ir::decode(source, format)?;
+        let applied = composite_validator.apply_fixes(&mut ast);
+        if applied == 0 {
+            return Ok((source.to_vec(), 0));
+        }
+        return Ok((ir::encode(&ast, format)?, applied));
+    }
 
-    let num: u64 = num_str.trim().parse()
-        .map_err(|e| format!("Invalid duration number: {}", e))?;
+    let source = lisp_text(source)?;
+    let (original, spans): (Vec<ast::LispExpr>, Vec<ast::Span>) = reader.read_with_spans(source)?.into_iter().unzip();
+    let mut program = original.clone();
+    let applied = composite_validator.apply_fixes(&mut program);
 
-    match unit.trim() {
-        "s" | "sec" | "secs" => Ok(std::time::Duration::from_secs(num)),
-        "m" | "min" | "mins" => Ok(std::time::Duration::from_secs(num * 60)),
-        "h" | "hour" | "hours" => Ok(std::time::Duration::from_secs(num * 3600)),
-        other => Err(format!("Unknown duration unit: {}", other)),
+    // Splice back to front so earlier spans stay valid
+    let mut fixed = source.to_string();
+    for ((before, after), span) in original.iter().zip(&program).zip(&spans).rev() {
+        if before != after {
+            fixed.replace_range(span.start..span.end, &after.to_string());
+        }
     }
+
+    Ok((fixed.into_bytes(), applied))
+}
+
+/// Lisp source files must be UTF-8; only IR may be binary
+fn lisp_text(source: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(source).map_err(|e| format!("source is not valid UTF-8: {}", e))
 }
 
 /// Parse capability string into Capability enum
+/// Capability names `parse_capability` accepts, before the `:` of those that take an argument
+const CAPABILITY_NAMES: &[&str] = &["FileRead", "FileWrite", "EnvRead", "Crate", "Network", "NetworkHTTP", "SystemTime", "ProcessSpawn", "ThreadSpawn", "UnsafeRust"];
+
 fn parse_capability(s: &str) -> Result<sandbox::Capability, String> {
     use std::path::PathBuf;
 
@@ -416,13 +1635,44 @@ fn parse_capability(s: &str) -> Result<sandbox::Capability, String> {
         Ok(sandbox::Capability::FileRead(PathBuf::from(path_str)))
     } else if let Some(path_str) = s.strip_prefix("FileWrite:") {
         Ok(sandbox::Capability::FileWrite(PathBuf::from(path_str)))
+    } else if let Some(name) = s.strip_prefix("EnvRead:") {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '*' | '?')) {
+            return Err(format!("Invalid variable name in capability (expected EnvRead:NAME): {}", s));
+        }
+        Ok(sandbox::Capability::EnvRead(name.to_string()))
+    } else if let Some(name) = s.strip_prefix("Crate:") {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*' | '?')) {
+            return Err(format!("Invalid crate name in capability (expected Crate:NAME): {}", s));
+        }
+        Ok(sandbox::Capability::Crate(name.to_string()))
+    } else if let Some(target) = s.strip_prefix("Network:") {
+        let (host, port) = match target.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse::<u16>().map_err(|_| format!("Invalid port in capability: {}", s))?;
+                (host, Some(port))
+            }
+            None => (target, None),
+        };
+        if host.is_empty() || host.contains(['/', ':', '@']) {
+            return Err(format!("Invalid host in capability (expected Network:host[:port]): {}", s));
+        }
+        Ok(sandbox::Capability::Network { host_pattern: host.to_string(), port })
     } else {
         match s {
             "NetworkHTTP" => Ok(sandbox::Capability::NetworkHTTP),
             "SystemTime" => Ok(sandbox::Capability::SystemTime),
             "ProcessSpawn" => Ok(sandbox::Capability::ProcessSpawn),
+            "ThreadSpawn" => Ok(sandbox::Capability::ThreadSpawn),
             "UnsafeRust" => Ok(sandbox::Capability::UnsafeRust),
-            other => Err(format!("Unknown capability: {}", other)),
+            other => {
+                // Suggest by the name before any `:`, keeping what follows it
+                let (name, argument) = other.find(':').map_or((other, ""), |colon| other.split_at(colon));
+                let message = format!("Unknown capability: {}", other);
+                Err(match suggest::closest(name, CAPABILITY_NAMES.iter().copied()) {
+                    Some(known) => format!("{} (did you mean '{}{}'?)", message, known, argument),
+                    None => message,
+                })
+            }
         }
     }
 }
@@ -430,6 +1680,7 @@ fn parse_capability(s: &str) -> Result<sandbox::Capability, String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use lisp_compiler::sandbox::{parse_duration, parse_memory_size};
 
     #[test]
     fn test_pipeline_simple_macro() {
@@ -439,7 +1690,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (* 5 2) and compile to Rust
         assert!(result.contains("(5 * 2)"));
@@ -453,7 +1704,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (* (+ 1 2) 3)
         assert!(result.contains("((1 + 2) * 3)"));
@@ -468,7 +1719,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should fully expand nested macros to (* (* 5 2) 2)
         assert!(result.contains("((5 * 2) * 2)"));
@@ -482,7 +1733,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (+ (* 3 3) (* 4 4))
         assert!(result.contains("((3 * 3) + (4 * 4))"));
@@ -496,13 +1747,13 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false);
+        let result = compile_lisp(source, registry, false, None, None);
 
         // Should error with max depth exceeded
         assert!(result.is_err());
         let error_msg = result.unwrap_err();
-        assert!(error_msg.contains("Maximum expansion depth"));
-        assert!(error_msg.contains("infinite"));
+        assert!(error_msg.message().contains("Maximum expansion depth"));
+        assert!(error_msg.message().contains("infinite"));
     }
 
     #[test]
@@ -513,11 +1764,11 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false);
+        let result = compile_lisp(source, registry, false, None, None);
 
         // Should error with parameter count mismatch
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Parameter count mismatch"));
+        assert!(result.unwrap_err().message().contains("Parameter count mismatch"));
     }
 
     #[test]
@@ -529,7 +1780,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand when macro to if expression
         assert!(result.contains("if"));
@@ -547,7 +1798,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (+ (+ 5 1) (- 10 1))
         assert!(result.contains("((5 + 1) + (10 - 1))"));
@@ -563,7 +1814,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (+ 1 2 3 4 5)
         assert!(result.contains("(1 + 2 + 3 + 4 + 5)"));
@@ -577,7 +1828,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (+ 42) which compiles to just 42
         assert!(result.contains("42"));
@@ -591,7 +1842,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand to (+ (+ 1 2) 3 4)
         assert!(result.contains("((1 + 2) + 3 + 4)"));
@@ -605,11 +1856,11 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false);
+        let result = compile_lisp(source, registry, false, None, None);
 
         // Should error - need at least 2 args but got only 1
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Parameter count mismatch"));
+        assert!(result.unwrap_err().message().contains("Parameter count mismatch"));
     }
 
     #[test]
@@ -621,7 +1872,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Should expand both my-list calls
         assert!(result.contains("vec![1, 2, 3]"));
@@ -638,7 +1889,7 @@ mod tests {
 
         let mut registry = TransformRegistry::new();
         registry.register(Box::new(EchoTransform::new()));
-        let result = compile_lisp(source, registry, false).unwrap();
+        let result = compile_lisp(source, registry, false, None, None).unwrap();
 
         // Echo transform should not affect output
         assert!(result.contains("(1 + 2)"));
@@ -653,12 +1904,12 @@ mod tests {
 
         // Test with no transforms
         let registry1 = TransformRegistry::new();
-        let result1 = compile_lisp(source, registry1, false).unwrap();
+        let result1 = compile_lisp(source, registry1, false, None, None).unwrap();
 
         // Test with echo transform
         let mut registry2 = TransformRegistry::new();
         registry2.register(Box::new(EchoTransform::new()));
-        let result2 = compile_lisp(source, registry2, false).unwrap();
+        let result2 = compile_lisp(source, registry2, false, None, None).unwrap();
 
         // Results should be identical
         assert_eq!(result1, result2);
@@ -673,13 +1924,13 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
 
         assert!(result.is_err());
         let error = result.unwrap_err();
-        assert!(error.contains("Validation failed"));
-        assert!(error.contains("Type mismatch"));
-        assert!(error.contains("arithmetic operation"));
+        assert!(error.message().contains("Validation failed"));
+        assert!(error.message().contains("Type mismatch"));
+        assert!(error.message().contains("arithmetic operation"));
     }
 
     #[test]
@@ -689,7 +1940,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
 
         assert!(result.is_ok());
         assert!(result.unwrap().contains("(1 + 2)"));
@@ -702,12 +1953,12 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
 
         assert!(result.is_err());
         let error = result.unwrap_err();
-        assert!(error.contains("Validation failed"));
-        assert!(error.contains("Infinite recursion"));
+        assert!(error.message().contains("Validation failed"));
+        assert!(error.message().contains("Infinite recursion"));
     }
 
     #[test]
@@ -717,13 +1968,30 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
+
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert!(error.message().contains("Validation failed"));
+        assert!(error.message().contains("FFI restriction"));
+        assert!(error.message().contains("unsafe operation"));
+    }
+
+    #[test]
+    fn test_validation_taint_tracking_error() {
+        let source = r#"
+            (let ((payload (read-file "untrusted.txt")))
+              (run-command payload))
+        "#;
+
+        let registry = TransformRegistry::new();
+        let result = compile_lisp(source, registry, true, None, None);
 
         assert!(result.is_err());
         let error = result.unwrap_err();
-        assert!(error.contains("Validation failed"));
-        assert!(error.contains("FFI restriction"));
-        assert!(error.contains("unsafe operation"));
+        assert!(error.message().contains("Validation failed"));
+        assert!(error.message().contains("Taint violation"));
+        assert!(error.message().contains("'run-command'"));
     }
 
     #[test]
@@ -734,7 +2002,7 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, false);
+        let result = compile_lisp(source, registry, false, None, None);
 
         // Should compile (even though it's invalid) when validation is disabled
         assert!(result.is_ok());
@@ -748,12 +2016,10 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
 
-        // Validation happens before macro expansion, so the macro definition itself passes
-        // (the macro body is not evaluated during validation of the defmacro)
-        // This test verifies that validation integrates properly with macros
-        assert!(result.is_ok() || result.is_err());  // Depends on implementation detail
+        // The quasiquoted template is checked before expansion, and its expansion after
+        assert!(result.unwrap_err().message().contains("requires numeric operands"));
     }
 
     #[test]
@@ -763,12 +2029,555 @@ mod tests {
         "#;
 
         let registry = TransformRegistry::new();
-        let result = compile_lisp(source, registry, true);
+        let result = compile_lisp(source, registry, true, None, None);
 
         assert!(result.is_ok());
         assert!(result.unwrap().contains("(1 + (2 * 3))"));
     }
 
+    #[test]
+    fn test_validation_report_collects_all_findings() {
+        let source = "(+ 1 2)\n(+ \"hello\" 42)\n(rust-unsafe \"code\")";
+
+        let registry = TransformRegistry::new();
+        let validation = build_validation_report(source.as_bytes(), &parser::Reader::new(), "example.lisp", &registry, None, None, pipeline::ValidationStage::default(), &[]).unwrap();
+
+        let findings = validation.findings();
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].rule, "TypeSafety");
+        assert_eq!(findings[0].span.as_ref().unwrap().start_line, 2);
+        assert_eq!(findings[1].rule, "FFIRestrictions");
+        assert_eq!(findings[1].span.as_ref().unwrap().start_line, 3);
+        assert!(findings[1].suggested_fix.is_some());
+
+        // The failure compiling reports counts the same findings as the report
+        let error = Pipeline::new().with_validation(true).compile(source).unwrap_err();
+        assert!(error.message().starts_with("Validation failed with 2 error(s):\n"), "{}", error);
+        assert_eq!(validation.count_severity(Severity::Error), 2);
+
+        // Without findings before expansion, those after it
+        let source = "(defmacro call-op (op x) `(,op ,x))\n(+ 1 2)\n(call-op ffi-call 1)";
+        let validation = build_validation_report(source.as_bytes(), &parser::Reader::new(), "example.lisp", &registry, None, None, pipeline::ValidationStage::default(), &[]).unwrap();
+        let findings = validation.findings();
+        assert_eq!(findings.len(), 1);
+        assert_eq!((findings[0].rule.as_str(), findings[0].span.as_ref().unwrap().start_line), ("FFIRestrictions", 3));
+        let error = Pipeline::new().with_validation(true).compile(source).unwrap_err();
+        assert!(error.message().starts_with("Validation after macro expansion failed with 1 error(s)"), "{}", error);
+    }
+
+    #[test]
+    fn test_validation_tracks_definitions_across_expressions() {
+        let source = r#"(define x "s") (+ x 1)"#;
+
+        let tokens = lexer::tokenize(source).unwrap();
+        let ast = parser::parse(tokens).unwrap();
+        let result = validate_ast(&ast);
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().message().contains("TypeSafety"));
+    }
+
+    #[test]
+    fn test_ir_stages() {
+        let source = "(defmacro twice (x) `(+ ,x ,x)) (twice 4)";
+
+        for (stage, macro_kept) in [(IrStage::Parsed, true), (IrStage::Transformed, true), (IrStage::Expanded, false)] {
+            let json = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput { stage, ..IrOutput::default() }).unwrap();
+            let ir = ir::decode(&json, ir::IrFormat::Json).unwrap();
+            assert_eq!(matches!(ir[0], ast::LispExpr::Macro { .. }), macro_kept, "{:?}", stage);
+
+            // IR from any stage still compiles
+            let rust_code = compile_from_ir(&json, ir::IrFormat::Json, Pipeline::new()).unwrap();
+            assert!(rust_code.contains("(4 + 4)"));
+        }
+        assert!(IrStage::parse("lowered").is_err());
+    }
+
+    #[test]
+    fn test_ir_validated_after_expansion() {
+        // Only the expanded program adds a string to a number
+        let source = "(defmacro add (x y) `(+ ,x ,y)) (add \"s\" 1)";
+        let json = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput { stage: IrStage::Parsed, ..IrOutput::default() }).unwrap();
+        let error = compile_from_ir(&json, ir::IrFormat::Json, Pipeline::new().with_validation(true)).unwrap_err();
+        assert!(error.message().starts_with("Validation after macro expansion failed"), "{}", error);
+        let pre_only = Pipeline::new().with_validation(true).with_validation_stage(pipeline::ValidationStage::Pre);
+        assert!(compile_from_ir(&json, ir::IrFormat::Json, pre_only).is_ok());
+
+        let mut ndjson = Vec::new();
+        compile_to_ir_stream(source, &parser::Reader::new(), TransformRegistry::new(), false, IrStage::Parsed, false, &mut ndjson).unwrap();
+        assert!(compile_from_ir_stream(&ndjson[..], Pipeline::new().with_validation(true)).is_err());
+    }
+
+    #[test]
+    fn test_deeply_nested_ir() {
+        let nested = |depth| format!("{}1{}", "(list ".repeat(depth), ")".repeat(depth));
+
+        let ir = compile_to_ir(&nested(20_000), &parser::Reader::new(), TransformRegistry::new(), false, IrOutput::default()).unwrap();
+        assert!(ir::decode(&ir, ir::IrFormat::Json).is_ok());
+
+        let ir = compile_to_ir(&nested(2_000), &parser::Reader::new(), TransformRegistry::new(), false, IrOutput::default()).unwrap();
+        let rust_code = compile_from_ir(&ir, ir::IrFormat::Json, Pipeline::new()).unwrap();
+        assert!(rust_code.contains("vec![vec![vec!["));
+    }
+
+    #[test]
+    fn test_ir_include_spans() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(* 2 (twice 4))";
+        let json = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput { include_spans: true, ..IrOutput::default() }).unwrap();
+        let document: ir::IrDocument = serde_json::from_slice(&json).unwrap();
+        assert_eq!(document.exprs[0].to_string(), "(* 2 (+ 4 4))");
+
+        let tree = &document.spans.unwrap()[0];
+        assert_eq!((tree.line, tree.column, tree.expanded_from.as_deref()), (Some(2), Some(1), None));
+        assert_eq!(tree.children[1].span, Some(ast::Span::new(35, 36)));
+        // (+ 4 4) comes from the (twice 4) call; each 4 is the argument itself
+        let expansion = &tree.children[2];
+        assert_eq!(expansion.span, Some(ast::Span::new(37, 46)));
+        assert_eq!(expansion.expanded_from.as_deref(), Some("twice"));
+        assert_eq!(expansion.children[0].expanded_from.as_deref(), Some("twice"));
+        assert_eq!(expansion.children[1].span, Some(ast::Span::new(44, 45)));
+        assert_eq!(expansion.children[1].expanded_from, None);
+
+        let json = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput::default()).unwrap();
+        assert!(serde_json::from_slice::<ir::IrDocument>(&json).unwrap().spans.is_none());
+    }
+
+    #[test]
+    fn test_ir_stream_round_trip() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(* 2 (twice 4))\n(list \"done\")";
+        let mut ndjson = Vec::new();
+        compile_to_ir_stream(source, &parser::Reader::new(), TransformRegistry::new(), false, IrStage::Expanded, false, &mut ndjson).unwrap();
+        assert_eq!(String::from_utf8(ndjson.clone()).unwrap().lines().count(), 3);
+
+        let streamed = compile_from_ir_stream(&ndjson[..], Pipeline::new().with_validation(true)).unwrap();
+        assert_eq!(streamed, compile_lisp(source, TransformRegistry::new(), false, None, None).unwrap());
+
+        let mut unexpanded = Vec::new();
+        compile_to_ir_stream(source, &parser::Reader::new(), TransformRegistry::new(), false, IrStage::Parsed, false, &mut unexpanded).unwrap();
+        assert_eq!(compile_from_ir_stream(&unexpanded[..], Pipeline::new()).unwrap(), streamed);
+    }
+
+    #[test]
+    fn test_deterministic_ir_stream() {
+        let source = "(defmacro twice (x) `(let ((v ,x)) (+ v v)))\n(twice 1)\n(twice 2)";
+        let mut ndjson = Vec::new();
+        compile_to_ir_stream(source, &parser::Reader::new(), TransformRegistry::new(), false, IrStage::Expanded, true, &mut ndjson).unwrap();
+        let lines: Vec<String> = String::from_utf8(ndjson).unwrap().lines().map(str::to_string).collect();
+        // Both expansions bind the same gensym once numbered per form
+        assert!(lines[1].contains("v#g1") && lines[2].contains("v#g1"), "{:?}", lines);
+        assert_eq!(lines[1].replace("1.0", "2.0"), lines[2]);
+    }
+
+    #[test]
+    fn test_ir_to_lisp_round_trip() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(* 2 (twice 4))";
+        let ir = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput { stage: IrStage::Parsed, format: ir::IrFormat::Cbor, ..IrOutput::default() }).unwrap();
+        let lisp = ir_to_lisp(&ir::decode(&ir, ir::IrFormat::Cbor).unwrap());
+        assert_eq!(lisp, "(defmacro twice (x) `(+ ,x ,x))\n\n(* 2 (twice 4))\n");
+        assert_eq!(
+            compile_lisp(&lisp, TransformRegistry::new(), false, None, None).unwrap(),
+            compile_lisp(source, TransformRegistry::new(), false, None, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ir_canonical_ignores_unrelated_gensyms() {
+        let swap = "(defmacro swap (a b) `(let ((tmp ,a)) (setq ,a ,b) (setq ,b tmp)))";
+        let with_unused = format!("{}\n(defmacro noise (x) `(let ((tmp ,x)) tmp))\n(noise 1)", swap);
+        let canonical = IrOutput { canonical: true, ..IrOutput::default() };
+        let program = |prefix: &str| {
+            let source = format!("{}\n(swap x y)", prefix);
+            let ir = compile_to_ir(&source, &parser::Reader::new(), TransformRegistry::new(), false, canonical).unwrap();
+            ir::decode(&ir, ir::IrFormat::Json).unwrap().pop().unwrap()
+        };
+        assert_eq!(program(swap), program(&with_unused));
+
+        let ir = compile_to_ir("(list -0.0 1.5)", &parser::Reader::new(), TransformRegistry::new(), false, canonical).unwrap();
+        assert_eq!(String::from_utf8(ir).unwrap(), format!(
+            r#"{{"exprs":[{{"List":[{{"Symbol":"list"}},{{"Number":0.0}},{{"Number":1.5}}]}}],"version":"{}"}}"#,
+            ir::IR_VERSION
+        ));
+
+        let interned = IrOutput { intern: true, ..canonical };
+        let ir = compile_to_ir("(list x (list x))", &parser::Reader::new(), TransformRegistry::new(), false, interned).unwrap();
+        assert_eq!(String::from_utf8(ir.clone()).unwrap(), format!(
+            r#"{{"exprs":[{{"List":[{{"Symbol":0}},{{"Symbol":1}},{{"List":[{{"Symbol":0}},{{"Symbol":1}}]}}]}}],"strings":["list","x"],"version":"{}"}}"#,
+            ir::IR_VERSION
+        ));
+        assert_eq!(ir::decode(&ir, ir::IrFormat::Json).unwrap()[0].to_string(), "(list x (list x))");
+    }
+
+    #[test]
+    fn test_check_program() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(+ \"a\" 1)\n(twice 1 2)\n(twice 3)";
+        let diagnostics = check_program(source, &parser::Reader::new(), "example.lisp", false, &lint::LintLevels::new());
+        let findings: Vec<(&str, usize)> = diagnostics
+            .findings()
+            .iter()
+            .map(|finding| (finding.rule.as_str(), finding.span.as_ref().unwrap().start_line))
+            .collect();
+        assert_eq!(findings, vec![("TypeSafety", 2), ("MacroExpansion", 3)]);
+
+        // Validation after expansion, as when compiling, without repeating earlier findings
+        let source = "(defmacro call-op (op x) `(,op ,x))\n(call-op ffi-call 1)\n(defun add-one (x) (+ x 1))\n(add-one \"s\")";
+        let diagnostics = check_program(source, &parser::Reader::new(), "example.lisp", false, &lint::LintLevels::new());
+        let findings: Vec<(&str, usize)> = diagnostics
+            .findings()
+            .iter()
+            .map(|finding| (finding.rule.as_str(), finding.span.as_ref().unwrap().start_line))
+            .collect();
+        assert_eq!(findings, vec![("TypeSafety", 4), ("FFIRestrictions", 2)]);
+
+        assert!(check_program("(twice 3)", &parser::Reader::new(), "clean.lisp", false, &lint::LintLevels::new()).findings().is_empty());
+        let syntax = check_program("(+ 1", &parser::Reader::new(), "broken.lisp", false, &lint::LintLevels::new());
+        assert_eq!(syntax.findings()[0].rule, "Syntax");
+        assert_eq!(syntax.count_severity(Severity::Error), 1);
+        let include = check_program("(include \"missing.lisp\")", &parser::Reader::new(), "broken.lisp", false, &lint::LintLevels::new());
+        assert_eq!(include.findings()[0].rule, "Include");
+
+        assert!(check_program(source, &parser::Reader::new(), "example.lisp", false, &lint::LintLevels::new()).metrics().is_empty());
+        let stats = check_program("(defun sq (n) (* n n))
+(sq 2)", &parser::Reader::new(), "stats.lisp", true, &lint::LintLevels::new());
+        assert_eq!(stats.to_text(), "stats.lisp: 1 function(s)\n  sq: cyclomatic complexity 1, 9 form(s), 1 argument(s)\n");
+
+        let source = "(defun old (x) (declare (deprecated \"use new\")) x)\n(+ 1 (old 2))";
+        let deprecated = check_program(source, &parser::Reader::new(), "old.lisp", false, &lint::LintLevels::new());
+        let finding = &deprecated.findings()[0];
+        assert_eq!((finding.rule.as_str(), finding.severity.as_str()), ("Deprecated", "warning"));
+        assert_eq!(finding.span.as_ref().map(|span| (span.start_line, span.start_column)), Some((2, 6)));
+        assert_eq!(finding.fix.as_ref().map(|fix| fix.replacement.as_str()), Some("(new 2)"));
+        let denied = lint::LintLevels::new().with_level(lint::Lint::Deprecated, lint::LintLevel::Deny);
+        assert_eq!(check_program(source, &parser::Reader::new(), "old.lisp", false, &denied).count_severity(Severity::Error), 1);
+        let allowed = lint::LintLevels::new().with_level(lint::Lint::Warnings, lint::LintLevel::Allow);
+        assert!(check_program(source, &parser::Reader::new(), "old.lisp", false, &allowed).findings().is_empty());
+
+        let shadowed = check_program("(defmacro if (c a b) `(cond (,c ,a) (t ,b)))", &parser::Reader::new(), "if.lisp", false, &lint::LintLevels::new());
+        let finding = &shadowed.findings()[0];
+        assert_eq!((finding.rule.as_str(), finding.severity.as_str(), finding.code.as_deref()), ("ShadowedBuiltin", "warning", Some("L0313")));
+    }
+
+    #[test]
+    fn test_check_program_runs_no_commands() {
+        let marker = std::env::temp_dir().join(format!("lisp-compiler-macro-if-{}", process::id()));
+        let source = format!("(defmacro evil () (macro-if (run-command \"touch\" {:?}) 1 2))\n(evil)", marker.display().to_string());
+        let diagnostics = check_program(&source, &parser::Reader::new(), "evil.lisp", false, &lint::LintLevels::new());
+        assert!(!marker.exists());
+        let finding = &diagnostics.findings()[0];
+        assert_eq!(finding.rule, "MacroExpansion");
+        assert!(finding.message.contains("Sandbox violation"), "{}", finding.message);
+    }
+
+    #[test]
+    fn test_test_program() {
+        assert_eq!(test_program("(+ 1 2)", &parser::Reader::new()).unwrap(), None);
+
+        let source = "(defmacro twice (x) `(* ,x 2))\n(deftest ok (assert-equal 10 (twice 5)))\n(deftest fails (assert-equal \"a\" \"b\"))";
+        let rust_code = test_program(source, &parser::Reader::new()).unwrap().unwrap();
+        let out_dir = std::env::temp_dir().join(format!("lisp-compiler-test-{}", process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let binary = runner::build(&rust_code, runner::Target::Native, &out_dir).unwrap();
+        let output = process::Command::new(binary).output().unwrap();
+        fs::remove_dir_all(&out_dir).unwrap();
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(stdout.contains("test ok ... ok\ntest fails ... FAILED\n"));
+        assert!(stdout.contains("(assert-equal \"a\" \"b\") failed\n  - expected: \"a\"\n  + actual:   \"b\"\n"));
+        assert!(stdout.ends_with("test result: FAILED. 1 passed; 1 failed\n"));
+    }
+
+    #[test]
+    fn test_bench_program() {
+        assert_eq!(bench_program("(deftest t (assert-true (< 1 2)))", &parser::Reader::new(), 1, 1).unwrap(), None);
+
+        let source = "(defmacro twice (x) `(* ,x 2))\n(defbench doubling (twice 21))";
+        let rust_code = bench_program(source, &parser::Reader::new(), 1, 5).unwrap().unwrap();
+        let out_dir = std::env::temp_dir().join(format!("lisp-compiler-bench-{}", process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let binary = runner::build(&rust_code, runner::Target::Native, &out_dir).unwrap();
+        let output = process::Command::new(binary).output().unwrap();
+        fs::remove_dir_all(&out_dir).unwrap();
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(output.status.success());
+        assert!(stdout.starts_with("running 1 benchmarks (1 warmup, 5 iterations)\nbench doubling ... mean "));
+        assert!(stdout.contains(", median ") && stdout.contains(", stddev "));
+    }
+
+    #[test]
+    fn test_hygienic_macros_build_with_rustc() {
+        let source = "(defmacro double-it (a) `(let ((tmp-val ,a)) (+ tmp-val tmp-val)))\n\
+                      (defmacro sum-to (n) `(let sum-loop ((i 0) (acc 0)) (if (> i ,n) acc (sum-loop (+ i 1) (+ acc i)))))\n\
+                      (let ((__g_tmp_val_1 10)) (double-it __g_tmp_val_1))\n\
+                      (let ((i 5)) (+ i (sum-to i)))\n\
+                      (defun helper (x) (* x 2))\n\
+                      (defmacro twice-helper (v) `(let ((tmp ,v)) (+ (helper tmp) tmp)))\n\
+                      (twice-helper 5)";
+        let rust_code = compile_lisp(source, TransformRegistry::new(), false, None, None).unwrap();
+        assert!(!rust_code.contains('#'), "{}", rust_code);
+        let out_dir = std::env::temp_dir().join(format!("lisp-compiler-gensyms-{}", process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let binary = runner::build(&rust_code, runner::Target::Native, &out_dir);
+        let output = binary.map(|binary| process::Command::new(binary).output().unwrap());
+        fs::remove_dir_all(&out_dir).unwrap();
+        assert_eq!(String::from_utf8(output.unwrap().stdout).unwrap(), "20.0\n20.0\n15.0\n");
+    }
+
+    #[test]
+    fn test_compiler_macro_example_builds_with_rustc() {
+        // The example in the README
+        let source = "(defun power (base exponent)\n  (if (= exponent 0) 1 (* base (power base (- exponent 1)))))\n\
+                      (define-compiler-macro power (base exponent)\n  (if (= exponent 2) (list '* base base) nil))\n\
+                      (power 7 2)\n(power 2 10)";
+        let rust_code = compile_lisp(source, TransformRegistry::new(), false, None, None).unwrap();
+        assert!(rust_code.contains("println!(\"{:?}\", (7.0 * 7.0));"), "{}", rust_code);
+        let out_dir = std::env::temp_dir().join(format!("lisp-compiler-compiler-macro-{}", process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let binary = runner::build(&rust_code, runner::Target::Native, &out_dir);
+        let output = binary.map(|binary| process::Command::new(binary).output().unwrap());
+        fs::remove_dir_all(&out_dir).unwrap();
+        assert_eq!(String::from_utf8(output.unwrap().stdout).unwrap(), "49.0\n1024.0\n");
+    }
+
+    #[test]
+    fn test_contracts_build_with_rustc() {
+        let source = "(defun half (n) (declare (requires (> n 0)) (ensures (< result n))) (/ n 2))\n(half -4)";
+        let out_dir = std::env::temp_dir().join(format!("lisp-compiler-contracts-{}", process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let run = |contracts: bool| {
+            let rust_code = Pipeline::new().with_contracts(contracts).compile(source).unwrap();
+            let binary = runner::build(&rust_code, runner::Target::Native, &out_dir).unwrap();
+            process::Command::new(binary).output().unwrap()
+        };
+        let (checked, unchecked) = (run(true), run(false));
+        fs::remove_dir_all(&out_dir).unwrap();
+        assert!(!checked.status.success());
+        assert!(String::from_utf8(checked.stderr).unwrap().contains("Precondition of 'half' failed: (> n 0)"));
+        assert_eq!(String::from_utf8(unchecked.stdout).unwrap(), "-2.0\n");
+    }
+
+    #[test]
+    fn test_write_artifact_creates_directories() {
+        let dir = std::env::temp_dir().join(format!("lisp-compiler-out-{}", process::id()));
+        let path = dir.join("nested").join("example.rs");
+        write_artifact(Some(&path), b"fn main() {}\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_project_with_required_crates() {
+        let source = "(defmacro use-regex () `(require-crate \"regex\" \"1\"))\n(use-regex)\n(+ 1 2)";
+        let mut required = RequiredCrates::default();
+        let rust_code = rust_pipeline(TransformRegistry::new(), true, None, None).observe(&mut required).compile(source).unwrap();
+        let required = required.0;
+        assert_eq!(required[0].name, "regex");
+
+        let dir = std::env::temp_dir().join(format!("lisp-compiler-project-{}", process::id()));
+        write_project(&dir, "examples/word count.lisp", &rust_code, &required);
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        assert!(manifest.starts_with("[package]\nname = \"word-count\"\n"));
+        assert!(manifest.ends_with("[dependencies]\nregex = \"1\"\n"));
+        assert!(fs::read_to_string(dir.join("src").join("main.rs")).unwrap().starts_with("#[allow(unused_imports)]\nuse regex;\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_program_at_stage() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(twice 4)";
+        let registry = TransformRegistry::new();
+        let (parsed, parsed_spans) = program_at_stage(source, &parser::Reader::new(), &registry, IrStage::Parsed).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed[0].is_macro());
+        assert_eq!(parsed_spans[1].line, Some(2));
+
+        let (expanded, expanded_spans) = program_at_stage(source, &parser::Reader::new(), &registry, IrStage::Expanded).unwrap();
+        assert_eq!(expanded.iter().map(ToString::to_string).collect::<Vec<_>>(), vec!["(+ 4 4)"]);
+        assert_eq!(expanded_spans[0].expanded_from.as_deref(), Some("twice"));
+        assert_eq!((expanded_spans[0].line, expanded_spans[0].column), (Some(2), Some(1)));
+    }
+
+    #[test]
+    fn test_pipeline_stages() {
+        let mut registry = TransformRegistry::new();
+        registry.register(Box::new(EchoTransform::new()));
+        let stages = pipeline_stages("(defmacro twice (x) `(+ ,x ,x))\n(twice 4)", &parser::Reader::new(), &registry).unwrap();
+
+        let names: Vec<&str> = stages.iter().map(|stage| stage.name.as_str()).collect();
+        assert_eq!(names, vec!["parsed", "transform: echo", "macro expansion"]);
+        assert_eq!(stages[1].exprs, stages[0].exprs);
+        assert_eq!(stages[2].exprs.len(), 1);
+    }
+
+    #[test]
+    fn test_binary_ir_round_trip() {
+        let source = "(+ 1 (* 2 3))";
+        for format in [ir::IrFormat::Cbor, ir::IrFormat::MessagePack] {
+            let bytes = compile_to_ir(source, &parser::Reader::new(), TransformRegistry::new(), false, IrOutput { format, ..IrOutput::default() }).unwrap();
+            let detected = ir::IrFormat::detect(&bytes);
+            let rust_code = compile_from_ir(&bytes, detected, Pipeline::new()).unwrap();
+            assert!(rust_code.contains("(1 + (2 * 3))"));
+        }
+    }
+
+    #[test]
+    fn test_apply_suggested_fixes_rewrites_changed_forms_only() {
+        let source = "; totals\n(+ 1   2)\n(+ \"40\" 2) ; fixed\n(define (spin) (spin))\n";
+
+        let (fixed, applied) = apply_suggested_fixes(source.as_bytes(), &parser::Reader::new(), None).unwrap();
+
+        assert_eq!(applied, 1);
+        assert_eq!(String::from_utf8(fixed).unwrap(), "; totals\n(+ 1   2)\n(+ 40 2) ; fixed\n(define (spin) (spin))\n");
+    }
+
+    #[test]
+    fn test_apply_suggested_fixes_from_ir() {
+        let json = r#"[{"List":[{"Symbol":"+"},{"String":"3"},{"Number":1.0}]}]"#;
+
+        let (fixed, applied) = apply_suggested_fixes(json.as_bytes(), &parser::Reader::new(), Some(ir::IrFormat::Json)).unwrap();
+        assert_eq!(applied, 1);
+        let ast = ir::decode(&fixed, ir::IrFormat::Json).unwrap();
+        assert_eq!(ast[0].to_string(), "(+ 3 1)");
+    }
+
+    #[test]
+    fn test_validation_report_from_ir_has_no_spans() {
+        let json = r#"[{"List":[{"Symbol":"+"},{"String":"a"},{"Number":1.0}]}]"#;
+
+        let registry = TransformRegistry::new();
+        let validation = build_validation_report(json.as_bytes(), &parser::Reader::new(), "example.json", &registry, Some(ir::IrFormat::Json), None, pipeline::ValidationStage::default(), &[]).unwrap();
+
+        assert_eq!(validation.findings().len(), 1);
+        assert!(validation.findings()[0].span.is_none());
+    }
+
+    #[test]
+    fn test_sandbox_mode_embeds_runtime() {
+        let source = r#"(read-file "/etc/passwd")"#;
+
+        let registry = TransformRegistry::new();
+        let config = sandbox::SandboxConfig::new();
+        let result = compile_lisp(source, registry, false, Some(&config), None).unwrap();
+
+        assert!(result.contains("mod sandbox_rt"));
+        assert!(result.contains(r#"sandbox_rt::read_file(&"/etc/passwd")"#));
+    }
+
+    #[test]
+    fn test_sandbox_mode_from_ir() {
+        let json = r#"[{"List":[{"Symbol":"list"},{"Number":1.0}]}]"#;
+
+        let config = sandbox::SandboxConfig::new();
+        let result = compile_from_ir(json.as_bytes(), ir::IrFormat::Json, Pipeline::new().with_sandbox(&config)).unwrap();
+
+        assert!(result.contains("sandbox_rt::track(vec![1])"));
+    }
+
+    #[test]
+    fn test_sandbox_mode_rejects_oversized_literals() {
+        let source = r#"(make-list 1000 "abcdefgh")"#;
+
+        let config = sandbox::SandboxConfig::new().with_max_memory(1024);
+        let error = compile_lisp(source, TransformRegistry::new(), false, Some(&config), None).unwrap_err();
+        assert!(error.message().contains("MemoryBounds violation: Estimated allocation of 16000 bytes"));
+
+        let config = sandbox::SandboxConfig::new();
+        assert!(compile_lisp(source, TransformRegistry::new(), false, Some(&config), None).is_err_and(|e| e.message().contains("Unknown function")));
+    }
+
+    #[test]
+    fn test_infer_capabilities_through_macros() {
+        let source = r#"
+            (defmacro load (path) `(read-file ,path))
+            (load "/data/input.txt")
+        "#;
+
+        let capability_report = infer_capabilities(source, &parser::Reader::new()).unwrap();
+        assert_eq!(
+            capability_report.allow_flags(),
+            vec!["--allow-capability FileRead:/data/input.txt"]
+        );
+    }
+
+    #[test]
+    fn test_inferred_flags_parse_as_capabilities() {
+        let source = r#"(write-file "/tmp/out" "x") (http-get "http://example.com")"#;
+
+        let capability_report = infer_capabilities(source, &parser::Reader::new()).unwrap();
+        for capability in capability_report.capabilities() {
+            assert_eq!(parse_capability(&capability.to_string()).unwrap(), capability);
+        }
+    }
+
+    #[test]
+    fn test_load_capability_manifest() {
+        let path = std::env::temp_dir().join(format!("lisp-manifest-{}.toml", std::process::id()));
+        fs::write(&path, "[filesystem]\nread = [\"/data/*.csv\"]\n[runtime]\nprocess_spawn = true\n").unwrap();
+
+        let manifest = load_capability_manifest(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let config = manifest.apply_to(sandbox::SandboxConfig::new()).unwrap();
+
+        assert!(config.has_capability(&sandbox::Capability::ProcessSpawn));
+        let rust_code = compile_lisp(r#"(read-file "/data/a.csv")"#, TransformRegistry::new(), false, Some(&config), None).unwrap();
+        assert!(rust_code.contains(r#"const READ_PATHS: &[&str] = &["/data/*.csv"];"#));
+    }
+
+    #[test]
+    fn test_sandbox_options_layering() {
+        let manifest = manifest::CapabilityManifest::from_toml(
+            "[profiles.ci]\nprofile = \"strict\"\nlimits.timeout = \"60s\"\n[runtime]\nsystem_time = true\n",
+        )
+        .unwrap();
+        let options = SandboxOptions {
+            sandbox_mode: true,
+            profile: Some("ci".to_string()),
+            manifest: Some(manifest),
+            max_memory: Some(1024),
+            timeout: None,
+            max_fuel: Some(500),
+            max_expansion_nodes: Some(10_000),
+            granted: vec![sandbox::Capability::ProcessSpawn],
+            denied: Vec::new(),
+            audit_log: None,
+        };
+
+        let config = options.build().unwrap();
+        assert_eq!(config.max_memory, 1024);
+        assert_eq!(config.max_fuel, Some(500));
+        assert_eq!(config.max_expansion_nodes, Some(10_000));
+        assert_eq!(config.max_execution_time, std::time::Duration::from_secs(60));
+        assert!(config.has_capability(&sandbox::Capability::SystemTime));
+        assert!(config.has_capability(&sandbox::Capability::ProcessSpawn));
+    }
+
+    #[test]
+    fn test_sandbox_options_builtin_profile() {
+        let options = SandboxOptions {
+            profile: Some("permissive".to_string()),
+            ..SandboxOptions::default()
+        };
+        assert!(options.enabled());
+        assert!(!SandboxOptions::default().enabled());
+        assert!(options.build().unwrap().has_capability(&sandbox::Capability::NetworkHTTP));
+
+        let denied = SandboxOptions {
+            profile: Some("permissive".to_string()),
+            granted: vec![sandbox::Capability::ProcessSpawn],
+            denied: vec![sandbox::Capability::ProcessSpawn],
+            ..SandboxOptions::default()
+        };
+        let config = denied.build().unwrap();
+        assert!(!config.has_capability(&sandbox::Capability::ProcessSpawn));
+        assert_eq!(config.conflicts().len(), 1);
+
+        let unknown = SandboxOptions {
+            profile: Some("custom".to_string()),
+            ..SandboxOptions::default()
+        };
+        assert!(unknown.build().is_err());
+    }
+
     // Sandbox CLI parsing tests
 
     #[test]
@@ -840,6 +2649,45 @@ mod tests {
         assert_eq!(parse_capability("SystemTime").unwrap(), Capability::SystemTime);
         assert_eq!(parse_capability("ProcessSpawn").unwrap(), Capability::ProcessSpawn);
         assert_eq!(parse_capability("UnsafeRust").unwrap(), Capability::UnsafeRust);
+        assert_eq!(parse_capability("ThreadSpawn").unwrap(), Capability::ThreadSpawn);
+        assert_eq!(parse_capability("NetworkHttp").unwrap_err(), "Unknown capability: NetworkHttp (did you mean 'NetworkHTTP'?)");
+        assert_eq!(parse_capability("FileRaed:/data").unwrap_err(), "Unknown capability: FileRaed:/data (did you mean 'FileRead:/data'?)");
+        assert_eq!(parse_capability("Teleport").unwrap_err(), "Unknown capability: Teleport");
+    }
+
+    #[test]
+    fn test_parse_capability_network() {
+        use sandbox::Capability;
+
+        assert_eq!(
+            parse_capability("Network:api.example.com:443").unwrap(),
+            Capability::Network { host_pattern: "api.example.com".to_string(), port: Some(443) }
+        );
+        assert_eq!(
+            parse_capability("Network:*.example.com").unwrap(),
+            Capability::Network { host_pattern: "*.example.com".to_string(), port: None }
+        );
+        assert!(parse_capability("Network:api.example.com:https").is_err());
+        assert!(parse_capability("Network:https://api.example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_capability_env_read() {
+        use sandbox::Capability;
+
+        assert_eq!(parse_capability("EnvRead:HOME").unwrap(), Capability::EnvRead("HOME".to_string()));
+        assert_eq!(parse_capability("EnvRead:APP_*").unwrap(), Capability::EnvRead("APP_*".to_string()));
+        assert!(parse_capability("EnvRead:").is_err());
+        assert!(parse_capability("EnvRead:A=B").is_err());
+    }
+
+    #[test]
+    fn test_parse_capability_crate() {
+        use sandbox::Capability;
+
+        assert_eq!(parse_capability("Crate:serde-*").unwrap(), Capability::Crate("serde-*".to_string()));
+        assert!(parse_capability("Crate:").is_err());
+        assert!(parse_capability("Crate:../regex").is_err());
     }
 
     #[test]
@@ -0,0 +1,200 @@
+//! Rust names for Lisp names.
+//!
+//! Generated Rust refers to every function, field, method, parameter, and variable by
+//! `identifier(name)`:
+//!
+//! - `-` becomes `_`, so `to-string` becomes `to_string`
+//! - other punctuation becomes its code point between underscores, so `reset!`
+//!   becomes `reset_21_` and `empty?` becomes `empty_3f_`
+//! - a leading digit gets a `_` in front, and Rust keywords are written as raw
+//!   identifiers (`r#type`), or with a trailing `_` for `self`, `super`, and the other
+//!   keywords raw identifiers cannot spell
+//!
+//! Definitions are private to the generated program unless they `(declare (export))`,
+//! which makes them `pub` and keeps their Lisp name as a `#[doc(alias)]`. An exported
+//! function is also `#[no_mangle]`, or `#[export_name = "symbol"]` with
+//! `(declare (export "symbol"))`, so other code can link against it. `names` lists what
+//! each of a program's definitions is called in Rust (`build --name-map`).
+
+use crate::ast::LispExpr;
+use crate::compiler::is_item;
+use crate::declarations::{self, Export};
+use crate::structs::StructDef;
+use crate::traits::TraitDef;
+use crate::types;
+use serde::Serialize;
+
+/// Keywords of Rust 2021 and the ones it reserves, which need `r#` to be identifiers
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn", "else", "enum", "extern",
+    "false", "final", "fn", "for", "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override",
+    "priv", "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+/// Keywords that cannot be raw identifiers
+const UNRAWABLE: &[&str] = &["crate", "self", "Self", "super", "_"];
+
+/// A Lisp name as a Rust identifier
+pub fn identifier(name: &str) -> String {
+    let mut identifier = String::new();
+    for c in name.chars() {
+        match c {
+            '-' => identifier.push('_'),
+            c if c.is_ascii_alphanumeric() || c == '_' => identifier.push(c),
+            c => identifier.push_str(&format!("_{:x}_", c as u32)),
+        }
+    }
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    } else if UNRAWABLE.contains(&identifier.as_str()) {
+        identifier.push('_');
+    } else if KEYWORDS.contains(&identifier.as_str()) {
+        identifier.insert_str(0, "r#");
+    }
+    identifier
+}
+
+/// The attributes that export a definition, before its `pub` item. `rust_name` is
+/// the identifier of the item, which for structs is not `identifier(name)`.
+pub fn export_attributes(name: &str, rust_name: &str, export: &Export, function: bool) -> String {
+    let mut attributes = String::new();
+    if rust_name != name {
+        attributes.push_str(&format!("#[doc(alias = {:?})]\n", name));
+    }
+    match (&export.symbol, function) {
+        (Some(symbol), _) => attributes.push_str(&format!("#[export_name = {:?}]\n", symbol)),
+        (None, true) => attributes.push_str("#[no_mangle]\n"),
+        (None, false) => {}
+    }
+    attributes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameKind {
+    Function,
+    Struct,
+    Field,
+    Trait,
+    Method,
+}
+
+/// What a definition is called in Lisp and in the generated Rust
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MangledName {
+    pub kind: NameKind,
+    /// Fields and methods are named after their struct or trait: `point.x`
+    pub lisp: String,
+    pub rust: String,
+    pub exported: bool,
+    /// The linker symbol of an exported function
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+}
+
+impl MangledName {
+    fn new(kind: NameKind, lisp: String, rust: String) -> Self {
+        MangledName { kind, lisp, rust, exported: false, symbol: None }
+    }
+}
+
+/// The Rust names of the functions, structs, fields, traits, and methods `program`
+/// defines at the top level, in source order. Malformed definitions are left out;
+/// compiling the program reports them.
+pub fn names(program: &[LispExpr]) -> Vec<MangledName> {
+    let mut names = Vec::new();
+    for expr in program.iter().filter(|expr| is_item(expr)) {
+        let elements = expr.as_list().unwrap();
+        match (elements[0].as_symbol(), &elements[1..]) {
+            (Some("defun"), [LispExpr::Symbol(name), ..]) => {
+                let export = declarations::declared(expr).and_then(|declared| declared.declarations().ok()).and_then(|declarations| declarations.export);
+                let name = types::split_generics(name).0;
+                let mut mangled = MangledName::new(NameKind::Function, name.to_string(), identifier(name));
+                if let Some(export) = export {
+                    mangled.exported = true;
+                    mangled.symbol = Some(export.symbol.unwrap_or_else(|| mangled.rust.clone()));
+                }
+                names.push(mangled);
+            }
+            (Some("defstruct"), args) => {
+                let Ok(definition) = StructDef::parse(args) else { continue };
+                let exported = definition.declarations.export.is_some();
+                let mut mangled = MangledName::new(NameKind::Struct, definition.name.to_string(), definition.rust_name());
+                mangled.exported = exported;
+                names.push(mangled);
+                for field in &definition.fields {
+                    let mut mangled = MangledName::new(NameKind::Field, format!("{}.{}", definition.name, field.name), identifier(&field.name));
+                    mangled.exported = exported;
+                    names.push(mangled);
+                }
+                for method in &definition.methods {
+                    names.push(MangledName::new(NameKind::Method, format!("{}.{}", definition.name, method.name), method.rust_name()));
+                }
+            }
+            (Some("deftrait"), args) => {
+                let Ok(definition) = TraitDef::parse(args) else { continue };
+                names.push(MangledName::new(NameKind::Trait, definition.name.to_string(), definition.rust_name()));
+                for method in &definition.methods {
+                    names.push(MangledName::new(NameKind::Method, format!("{}.{}", definition.name, method.name), identifier(&method.name)));
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// `{"names": [{"kind": "function", "lisp": "reset!", "rust": "reset_21_", "exported": false}, ...]}`
+pub fn to_json(names: &[MangledName]) -> String {
+    #[derive(Serialize)]
+    struct NameMap<'a> {
+        names: &'a [MangledName],
+    }
+    serde_json::to_string_pretty(&NameMap { names }).expect("names serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier("to-string"), "to_string");
+        assert_eq!(identifier("my-func!"), "my_func_21_");
+        assert_eq!(identifier("empty?"), "empty_3f_");
+        assert_eq!(identifier("->list"), "__3e_list");
+        assert_eq!(identifier("1+"), "_1_2b_");
+        assert_eq!(identifier("type"), "r#type");
+        assert_eq!(identifier("self"), "self_");
+        assert_eq!(identifier("snake_case"), "snake_case");
+    }
+
+    #[test]
+    fn test_names() {
+        let source = "(defun reset! (x) (declare (export)) x)\n\
+                      (defun add<T> (a b) (+ a b))\n\
+                      (defun area (r) (declare (export \"lisp_area\")) r)\n\
+                      (defstruct line-segment (start-x) (declare (export)))\n\
+                      (deftrait shape (area? (self) bool))\n\
+                      (print 1)";
+        let names = names(&parse(tokenize(source).unwrap()).unwrap());
+        let summary: Vec<(NameKind, &str, &str, bool, Option<&str>)> =
+            names.iter().map(|name| (name.kind, name.lisp.as_str(), name.rust.as_str(), name.exported, name.symbol.as_deref())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (NameKind::Function, "reset!", "reset_21_", true, Some("reset_21_")),
+                (NameKind::Function, "add", "add", false, None),
+                (NameKind::Function, "area", "area", true, Some("lisp_area")),
+                (NameKind::Struct, "line-segment", "LineSegment", true, None),
+                (NameKind::Field, "line-segment.start-x", "start_x", true, None),
+                (NameKind::Trait, "shape", "Shape", false, None),
+                (NameKind::Method, "shape.area?", "area_3f_", false, None),
+            ]
+        );
+        assert!(to_json(&names[..1]).contains("\"kind\": \"function\",\n      \"lisp\": \"reset!\",\n      \"rust\": \"reset_21_\""));
+    }
+}
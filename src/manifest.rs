@@ -0,0 +1,506 @@
+use crate::capabilities::CapabilityReport;
+use crate::sandbox::{self, Capability, SandboxConfig, SandboxProfile};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Limit on `profile` chains between custom profiles
+const MAX_PROFILE_DEPTH: usize = 16;
+
+/// Capability manifest loaded with `--capabilities manifest.toml`
+///
+/// ```toml
+/// profile = "standard"   # optional base profile
+///
+/// [limits]
+/// max_memory = "64MB"
+/// timeout = "10s"
+/// max_fuel = 1000000
+/// max_expansion_nodes = 100000
+///
+/// [filesystem]
+/// read = ["/data/**/*.csv"]
+/// write = ["/tmp/out"]
+///
+/// [network]
+/// allow_hosts = ["api.example.com", "*.example.org"]
+///
+/// [environment]
+/// read = ["HOME", "APP_*"]
+///
+/// [crates]
+/// allow = ["regex", "serde*"]
+///
+/// [runtime]
+/// process_spawn = false
+/// thread_spawn = false
+/// system_time = false
+/// unsafe_rust = false
+///
+/// # Custom profiles for --sandbox-profile, using the same sections
+/// [profiles.ci]
+/// profile = "strict"
+/// filesystem.read = ["/workspace/**"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapabilityManifest {
+    /// Profile (built-in or custom) these grants are added to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub limits: ManifestLimits,
+    pub filesystem: FilesystemGrants,
+    pub network: NetworkGrants,
+    pub environment: EnvironmentGrants,
+    pub crates: CrateGrants,
+    pub runtime: RuntimeGrants,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, CapabilityManifest>,
+    /// Comments written at the top of a generated manifest
+    #[serde(skip)]
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    /// Evaluated forms allowed before aborting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fuel: Option<u64>,
+    /// Nodes one macro call may generate at compile time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_expansion_nodes: Option<usize>,
+}
+
+/// Path or glob patterns (`*`, `?`, `**`) granted for reading and writing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesystemGrants {
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+}
+
+/// Hosts that HTTP requests may reach; `"*"` allows any host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkGrants {
+    pub allow_hosts: Vec<String>,
+}
+
+/// Environment variable names or globs `getenv` may read
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvironmentGrants {
+    pub read: Vec<String>,
+}
+
+/// Crate names or globs `require-crate` may depend on
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrateGrants {
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeGrants {
+    pub process_spawn: bool,
+    pub thread_spawn: bool,
+    pub system_time: bool,
+    pub unsafe_rust: bool,
+}
+
+impl CapabilityManifest {
+    pub fn from_toml(source: &str) -> Result<Self, String> {
+        let manifest: CapabilityManifest =
+            toml::from_str(source).map_err(|e| format!("Invalid capability manifest: {}", e))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Serialize as TOML, with any notes as leading comments
+    pub fn to_toml(&self) -> Result<String, String> {
+        let body = toml::to_string(self).map_err(|e| format!("Manifest serialization error: {}", e))?;
+        let mut output = String::new();
+        for note in &self.notes {
+            output.push_str(&format!("# {}\n", note));
+        }
+        if !self.notes.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&body);
+        Ok(output)
+    }
+
+    /// Check limits, paths, host patterns, and profile references, reporting every problem found
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = self.grant_problems();
+
+        let known_profile = |name: &str| self.profiles.contains_key(name) || SandboxProfile::parse(name).is_ok();
+        if let Some(name) = &self.profile
+            && !known_profile(name)
+        {
+            problems.push(format!("profile: unknown profile '{}'", name));
+        }
+
+        for (name, profile) in &self.profiles {
+            if SandboxProfile::parse(name).is_ok() {
+                problems.push(format!("profiles.{}: cannot redefine a built-in profile", name));
+            }
+            if !profile.profiles.is_empty() {
+                problems.push(format!("profiles.{}: profiles cannot be nested", name));
+            }
+            if let Some(parent) = &profile.profile
+                && !known_profile(parent)
+            {
+                problems.push(format!("profiles.{}.profile: unknown profile '{}'", name, parent));
+            }
+            for problem in profile.grant_problems() {
+                problems.push(format!("profiles.{}.{}", name, problem));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid capability manifest:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+
+    fn grant_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Some(size) = &self.limits.max_memory
+            && let Err(e) = sandbox::parse_memory_size(size)
+        {
+            problems.push(format!("limits.max_memory: {}", e));
+        }
+        if let Some(timeout) = &self.limits.timeout
+            && let Err(e) = sandbox::parse_duration(timeout)
+        {
+            problems.push(format!("limits.timeout: {}", e));
+        }
+        if self.limits.max_fuel == Some(0) {
+            problems.push("limits.max_fuel: must be greater than zero".to_string());
+        }
+        if self.limits.max_expansion_nodes == Some(0) {
+            problems.push("limits.max_expansion_nodes: must be greater than zero".to_string());
+        }
+
+        for (section, patterns) in [("filesystem.read", &self.filesystem.read), ("filesystem.write", &self.filesystem.write)] {
+            for pattern in patterns {
+                let path = Path::new(pattern);
+                if !path.is_absolute() {
+                    problems.push(format!("{}: path must be absolute: '{}'", section, pattern));
+                } else if path.components().any(|c| matches!(c, Component::ParentDir)) {
+                    problems.push(format!("{}: path must not contain '..': '{}'", section, pattern));
+                }
+            }
+        }
+
+        for host in &self.network.allow_hosts {
+            let wildcard_ok = host == "*" || !host.trim_start_matches("*.").contains('*');
+            if host.is_empty() || host.contains("://") || host.contains('/') || !wildcard_ok {
+                problems.push(format!(
+                    "network.allow_hosts: expected a host name, '*.domain', or '*': '{}'",
+                    host
+                ));
+            }
+        }
+
+        for name in &self.environment.read {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '*' | '?')) {
+                problems.push(format!("environment.read: expected a variable name or glob: '{}'", name));
+            }
+        }
+
+        for name in &self.crates.allow {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*' | '?')) {
+                problems.push(format!("crates.allow: expected a crate name or glob: '{}'", name));
+            }
+        }
+
+        problems
+    }
+
+    /// Grant the manifest's capabilities and limits on top of an existing config
+    pub fn apply_to(&self, mut config: SandboxConfig) -> Result<SandboxConfig, String> {
+        let problems = self.grant_problems();
+        if !problems.is_empty() {
+            return Err(format!("Invalid capability manifest:\n  - {}", problems.join("\n  - ")));
+        }
+
+        if let Some(size) = &self.limits.max_memory {
+            config = config.with_max_memory(sandbox::parse_memory_size(size)?);
+        }
+        if let Some(timeout) = &self.limits.timeout {
+            config = config.with_max_execution_time(sandbox::parse_duration(timeout)?);
+        }
+        if let Some(fuel) = self.limits.max_fuel {
+            config = config.with_max_fuel(fuel);
+        }
+        if let Some(nodes) = self.limits.max_expansion_nodes {
+            config = config.with_max_expansion_nodes(nodes);
+        }
+        for pattern in &self.filesystem.read {
+            config.add_capability(Capability::FileRead(PathBuf::from(pattern)));
+        }
+        for pattern in &self.filesystem.write {
+            config.add_capability(Capability::FileWrite(PathBuf::from(pattern)));
+        }
+        if !self.network.allow_hosts.is_empty() {
+            config.add_capability(Capability::NetworkHTTP);
+            if !self.network.allow_hosts.iter().any(|h| h == "*") {
+                config.allowed_network_hosts.extend(self.network.allow_hosts.iter().cloned());
+            }
+        }
+        for name in &self.environment.read {
+            config.add_capability(Capability::EnvRead(name.clone()));
+        }
+        for name in &self.crates.allow {
+            config.add_capability(Capability::Crate(name.clone()));
+        }
+        if self.runtime.process_spawn {
+            config.add_capability(Capability::ProcessSpawn);
+        }
+        if self.runtime.thread_spawn {
+            config.add_capability(Capability::ThreadSpawn);
+        }
+        if self.runtime.system_time {
+            config.add_capability(Capability::SystemTime);
+        }
+        if self.runtime.unsafe_rust {
+            config.add_capability(Capability::UnsafeRust);
+        }
+
+        Ok(config)
+    }
+
+    /// Configuration for a named profile: a custom one from `[profiles]` or a built-in
+    pub fn profile_config(&self, name: &str) -> Result<SandboxConfig, String> {
+        self.resolve_profile(name, 0)
+    }
+
+    /// Configuration these grants start from: the `profile` key, or the sandbox defaults
+    pub fn base_config(&self) -> Result<SandboxConfig, String> {
+        match &self.profile {
+            Some(name) => self.profile_config(name),
+            None => Ok(SandboxConfig::new()),
+        }
+    }
+
+    fn resolve_profile(&self, name: &str, depth: usize) -> Result<SandboxConfig, String> {
+        if depth > MAX_PROFILE_DEPTH {
+            return Err(format!("Sandbox profile '{}' extends itself", name));
+        }
+        match self.profiles.get(name) {
+            Some(custom) => {
+                let base = match &custom.profile {
+                    Some(parent) => self.resolve_profile(parent, depth + 1)?,
+                    None => SandboxConfig::new(),
+                };
+                custom.apply_to(base)
+            }
+            None => Ok(SandboxProfile::parse(name)?.config()),
+        }
+    }
+
+    /// Build the minimal manifest granting what the capability report inferred
+    pub fn from_report(report: &CapabilityReport) -> Self {
+        let mut manifest = CapabilityManifest::default();
+        manifest.notes.push("Capability manifest inferred by `lisp-compiler capabilities`".to_string());
+
+        for capability_use in report.uses() {
+            let target = capability_use.target.clone();
+            match (&capability_use.capability, target) {
+                (Capability::FileRead(_), Some(path)) => push_unique(&mut manifest.filesystem.read, path),
+                (Capability::FileWrite(_), Some(path)) => push_unique(&mut manifest.filesystem.write, path),
+                (Capability::FileRead(_) | Capability::FileWrite(_), None) => manifest.notes.push(format!(
+                    "'{}' uses a path only known at runtime; add a pattern that covers it",
+                    capability_use.operation
+                )),
+                (Capability::NetworkHTTP, _) => push_unique(&mut manifest.network.allow_hosts, "*".to_string()),
+                (Capability::Network { host_pattern, .. }, _) => {
+                    push_unique(&mut manifest.network.allow_hosts, host_pattern.clone())
+                }
+                (Capability::EnvRead(_), Some(name)) => push_unique(&mut manifest.environment.read, name),
+                (Capability::EnvRead(_), None) => manifest.notes.push(format!(
+                    "'{}' reads a variable only known at runtime; add a name or glob that covers it",
+                    capability_use.operation
+                )),
+                (Capability::Crate(_), Some(name)) => push_unique(&mut manifest.crates.allow, name),
+                (Capability::Crate(_), None) => manifest.notes.push(format!(
+                    "'{}' names a crate only known at runtime; add a name or glob that covers it",
+                    capability_use.operation
+                )),
+                (Capability::ProcessSpawn, _) => manifest.runtime.process_spawn = true,
+                (Capability::ThreadSpawn, _) => manifest.runtime.thread_spawn = true,
+                (Capability::SystemTime, _) => manifest.runtime.system_time = true,
+                (Capability::UnsafeRust, _) => manifest.runtime.unsafe_rust = true,
+            }
+        }
+
+        manifest
+    }
+}
+
+fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use std::time::Duration;
+
+    const SAMPLE: &str = r#"
+[limits]
+max_memory = "64MB"
+timeout = "10s"
+max_fuel = 5000
+max_expansion_nodes = 20000
+
+[filesystem]
+read = ["/data/**/*.csv"]
+write = ["/tmp/out"]
+
+[network]
+allow_hosts = ["api.example.com"]
+
+[environment]
+read = ["HOME"]
+
+[crates]
+allow = ["serde*"]
+
+[runtime]
+process_spawn = true
+"#;
+
+    #[test]
+    fn test_manifest_applies_to_config() {
+        let manifest = CapabilityManifest::from_toml(SAMPLE).unwrap();
+        let config = manifest.apply_to(SandboxConfig::new()).unwrap();
+
+        assert_eq!(config.max_memory, 64 * 1024 * 1024);
+        assert_eq!(config.max_execution_time, Duration::from_secs(10));
+        assert_eq!(config.max_fuel, Some(5000));
+        assert_eq!(config.max_expansion_nodes, Some(20000));
+        assert!(config.has_capability(&Capability::FileRead(PathBuf::from("/data/**/*.csv"))));
+        assert!(config.has_capability(&Capability::FileWrite(PathBuf::from("/tmp/out"))));
+        assert!(config.has_capability(&Capability::NetworkHTTP));
+        assert!(config.has_capability(&Capability::ProcessSpawn));
+        assert!(config.has_capability(&Capability::EnvRead("HOME".to_string())));
+        assert!(config.crate_allowed("serde-json"));
+        assert!(!config.has_capability(&Capability::UnsafeRust));
+        assert_eq!(config.allowed_network_hosts, vec!["api.example.com"]);
+    }
+
+    #[test]
+    fn test_manifest_validation_reports_all_problems() {
+        let source = r#"
+[limits]
+max_memory = "lots"
+
+[filesystem]
+read = ["data/*.csv", "/tmp/../etc"]
+
+[network]
+allow_hosts = ["https://example.com", "a*.example.com"]
+
+[environment]
+read = ["PATH=/bin"]
+
+[crates]
+allow = ["../regex"]
+"#;
+        let error = CapabilityManifest::from_toml(source).unwrap_err();
+        assert!(error.contains("limits.max_memory"));
+        assert!(error.contains("must be absolute"));
+        assert!(error.contains("must not contain '..'"));
+        assert!(error.contains("https://example.com"));
+        assert!(error.contains("a*.example.com"));
+        assert!(error.contains("environment.read"));
+        assert!(error.contains("crates.allow"));
+    }
+
+    #[test]
+    fn test_custom_profiles() {
+        let source = r#"
+profile = "ci"
+
+[profiles.ci]
+profile = "strict"
+limits.timeout = "60s"
+filesystem.read = ["/workspace/**"]
+
+[profiles.ci-net]
+profile = "ci"
+network.allow_hosts = ["*.example.com"]
+"#;
+        let manifest = CapabilityManifest::from_toml(source).unwrap();
+
+        let ci = manifest.base_config().unwrap();
+        assert_eq!(ci.max_memory, 32 * 1024 * 1024);
+        assert_eq!(ci.max_execution_time, Duration::from_secs(60));
+        assert!(ci.has_capability(&Capability::FileRead(PathBuf::from("/workspace/**"))));
+        assert!(!ci.has_capability(&Capability::NetworkHTTP));
+
+        let ci_net = manifest.profile_config("ci-net").unwrap();
+        assert!(ci_net.has_capability(&Capability::NetworkHTTP));
+        assert_eq!(ci_net.max_execution_time, Duration::from_secs(60));
+
+        assert!(manifest.profile_config("permissive").unwrap().has_capability(&Capability::ProcessSpawn));
+    }
+
+    #[test]
+    fn test_profile_errors() {
+        assert!(CapabilityManifest::from_toml("profile = \"nope\"").unwrap_err().contains("unknown profile"));
+        assert!(CapabilityManifest::from_toml("[profiles.strict]").unwrap_err().contains("built-in"));
+
+        let cyclic = CapabilityManifest::from_toml("[profiles.a]\nprofile = \"b\"\n[profiles.b]\nprofile = \"a\"").unwrap();
+        assert!(cyclic.profile_config("a").is_err());
+    }
+
+    #[test]
+    fn test_manifest_rejects_unknown_fields() {
+        assert!(CapabilityManifest::from_toml("[filesystem]\nexecute = []\n").is_err());
+    }
+
+    #[test]
+    fn test_manifest_from_report_round_trips() {
+        let ast = parse(tokenize(r#"
+            (read-file "/data/in.txt")
+            (read-file (read-line))
+            (http-get "https://api.example.com/v1")
+            (run-command "ls")
+            (getenv "HOME")
+            (require-crate "regex" "1")
+        "#).unwrap()).unwrap();
+        let manifest = CapabilityManifest::from_report(&CapabilityReport::infer(&ast));
+
+        assert_eq!(manifest.filesystem.read, vec!["/data/in.txt"]);
+        assert_eq!(manifest.network.allow_hosts, vec!["api.example.com"]);
+        assert_eq!(manifest.environment.read, vec!["HOME"]);
+        assert_eq!(manifest.crates.allow, vec!["regex"]);
+        assert!(manifest.runtime.process_spawn);
+
+        let toml = manifest.to_toml().unwrap();
+        assert!(toml.starts_with("# Capability manifest inferred"));
+        assert!(toml.contains("only known at runtime"));
+        let reloaded = CapabilityManifest::from_toml(&toml).unwrap();
+        assert_eq!(reloaded.filesystem, manifest.filesystem);
+        assert_eq!(reloaded.network, manifest.network);
+        assert_eq!(reloaded.environment, manifest.environment);
+        assert_eq!(reloaded.crates, manifest.crates);
+        assert_eq!(reloaded.runtime, manifest.runtime);
+    }
+}
@@ -0,0 +1,607 @@
+//! The numeric subset of the language, compiled by the backends other than Rust.
+//!
+//! `lower` checks that an expanded program uses only number and boolean literals,
+//! variables, `+ - * /`, `= < > <= >=`, `if`, `let`, `let*`, named `let` loops, and
+//! top-level `defun`s, and gives every expression a type. Numbers are 64-bit floats.
+//! Parameters and results are numbers unless annotated `bool`; an unannotated result
+//! has the type of the function's body. Arithmetic has the interpreter's meaning:
+//! `(- x)` negates, `(/ x)` is `1/x`, and `(+)` and `(*)` are 0 and 1.
+
+use crate::ast::{grow_stack, LispExpr};
+use crate::explain::CompileError;
+use crate::compiler::{bench_name, test_name};
+use crate::contracts::{self, Contract};
+use crate::lambda_list::LambdaList;
+use crate::symbol::Symbol;
+use crate::types;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    Bool,
+    /// The type of code that never finishes, such as a named let calling its loop again
+    Never,
+}
+
+impl Type {
+    /// `number` (the default) or `bool`; `what` names the annotated thing, for messages
+    fn annotated(type_name: Option<Symbol>, backend: &str, what: impl FnOnce() -> String) -> Result<Type, CompileError> {
+        match type_name.as_deref() {
+            None | Some("number") => Ok(Type::Number),
+            Some("bool") => Ok(Type::Bool),
+            Some(other) => Err(CompileError::new("L0403", format!("{} has type {}, but the {} backend supports only number and bool", what(), other, backend))),
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Type::Number => "a number",
+            Type::Bool => "a boolean",
+            Type::Never => "no value",
+        }
+    }
+
+    /// Whether code of this type can be used where a value of type `expected` is
+    pub fn fits(self, expected: Type) -> bool {
+        self == expected || self == Type::Never
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+}
+
+/// An expression and the type of its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub expr: Expr,
+    pub ty: Type,
+}
+
+/// Variables are indexes into their function's `variables`, and loops are numbered
+/// within their function
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Bool(bool),
+    Variable(usize),
+    Negate(Box<Node>),
+    Arithmetic(Arithmetic, Box<Node>, Box<Node>),
+    /// Two numbers, or for `Equal` two values of the same type
+    Compare(Comparison, Box<Node>, Box<Node>),
+    If(Box<Node>, Box<Node>, Box<Node>),
+    /// Each value is assigned to its variable before the next is computed
+    Let(Vec<(usize, Node)>, Box<Node>),
+    /// A named let: the variables are initialized, then the body runs until it
+    /// produces a value rather than going round again
+    Loop { id: usize, name: Symbol, bindings: Vec<(usize, Node)>, body: Box<Node> },
+    /// A tail call back to loop `id`: every value is computed before any variable is assigned
+    Again { id: usize, bindings: Vec<(usize, Node)> },
+    /// A call to one of the program's functions, by index
+    Call(usize, Vec<Node>),
+}
+
+impl Node {
+    fn new(expr: Expr, ty: Type) -> Node {
+        Node { expr, ty }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.expr.take_children(&mut pending);
+        while let Some(mut node) = pending.pop() {
+            node.expr.take_children(&mut pending);
+        }
+    }
+}
+
+impl Expr {
+    /// Move the subexpressions into `pending`, leaving a leaf
+    fn take_children(&mut self, pending: &mut Vec<Node>) {
+        match std::mem::replace(self, Expr::Bool(false)) {
+            Expr::Negate(operand) => pending.push(*operand),
+            Expr::Arithmetic(_, left, right) | Expr::Compare(_, left, right) => pending.extend([*left, *right]),
+            Expr::If(condition, then_node, else_node) => pending.extend([*condition, *then_node, *else_node]),
+            Expr::Let(bindings, body) | Expr::Loop { bindings, body, .. } => {
+                pending.extend(bindings.into_iter().map(|(_, value)| value));
+                pending.push(*body);
+            }
+            Expr::Again { bindings, .. } => pending.extend(bindings.into_iter().map(|(_, value)| value)),
+            Expr::Call(_, args) => pending.extend(args),
+            Expr::Number(_) | Expr::Bool(_) | Expr::Variable(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: Symbol,
+    pub ty: Type,
+}
+
+/// A contract condition and its source, for the message when it fails
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub check: Node,
+    pub source: LispExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Symbol,
+    /// Every variable of the function, its parameters first
+    pub variables: Vec<Variable>,
+    pub parameter_count: usize,
+    pub result: Type,
+    pub requires: Vec<Condition>,
+    /// The body's forms; the value of the last is the result
+    pub body: Vec<Node>,
+    pub ensures: Vec<Condition>,
+    /// The variable the postconditions see the result as, when there are any
+    pub result_variable: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    /// The top-level forms other than definitions, tests, and benchmarks, whose values
+    /// the program prints, as the body of a function without parameters
+    pub main: Function,
+}
+
+/// A `defun` as written, before its body is lowered
+struct Definition {
+    name: Symbol,
+    parameters: Vec<(Symbol, Type)>,
+    return_type: Option<Type>,
+    contract: Contract,
+    body: Vec<LispExpr>,
+}
+
+impl Definition {
+    /// `(defun name (params...) [: type] body...)` with only required parameters
+    fn parse(args: &[LispExpr], backend: &str) -> Result<Definition, CompileError> {
+        let [LispExpr::Symbol(name), LispExpr::List(parameters), body @ ..] = args else {
+            return Err(CompileError::new("L0406", "'defun' requires a name, a parameter list, and a body"));
+        };
+        if types::split_generics(name).1.is_some() {
+            return Err(CompileError::new("L0403", format!("'{}' has type parameters, which the {} backend does not support", name, backend)));
+        }
+        if *name == "main" {
+            return Err(format!("'main' is the entry point of the {} program and cannot be defined with 'defun'", backend).into());
+        }
+        let lambda_list = LambdaList::parse(parameters)?;
+        if !lambda_list.is_fixed() {
+            return Err(CompileError::new("L0403", format!("'{}' has &optional, &rest, or &key parameters, which the {} backend does not support", name, backend)));
+        }
+        let parameters = lambda_list
+            .required
+            .iter()
+            .zip(&lambda_list.required_types)
+            .map(|(parameter, type_name)| {
+                Ok((parameter.clone(), Type::annotated(type_name.clone(), backend, || format!("'{}' parameter '{}'", name, parameter))?))
+            })
+            .collect::<Result<_, CompileError>>()?;
+        let (return_type, body) = types::return_type(body);
+        let return_type = return_type
+            .map(|type_name| Type::annotated(Some(type_name), backend, || format!("The result of '{}'", name)))
+            .transpose()?;
+        let (contract, body) = Contract::parse(body)?;
+        if body.is_empty() {
+            return Err(CompileError::new("L0406", format!("'defun' {} requires a body", name)));
+        }
+        Ok(Definition { name: name.clone(), parameters, return_type, contract, body: body.to_vec() })
+    }
+}
+
+/// A function's parameter and result types, as its callers see them
+struct Signature {
+    name: Symbol,
+    parameters: Vec<Type>,
+    result: Type,
+}
+
+/// Lower an expanded program for `backend`, which names the backend in messages
+pub fn lower(program: &[LispExpr], backend: &str) -> Result<Program, CompileError> {
+    let mut definitions: Vec<Definition> = Vec::new();
+    let mut statements = Vec::new();
+    for form in program.iter().filter(|form| test_name(form).is_none() && bench_name(form).is_none()) {
+        match form.as_list().and_then(|elements| elements.split_first()) {
+            Some((head, args)) if head.as_symbol() == Some("defun") => {
+                let definition = Definition::parse(args, backend)?;
+                if definitions.iter().any(|other| other.name == definition.name) {
+                    return Err(CompileError::new("L0405", format!("'defun' {} is defined more than once", definition.name)));
+                }
+                definitions.push(definition);
+            }
+            _ => statements.push(form.clone()),
+        }
+    }
+
+    // An unannotated result has the type of the function's body, which can depend on
+    // the results of the functions it calls. Until a body is typed, calls to it fit
+    // anywhere; bodies are typed again until no result changes.
+    let mut signatures: Vec<Signature> = definitions
+        .iter()
+        .map(|definition| Signature {
+            name: definition.name.clone(),
+            parameters: definition.parameters.iter().map(|(_, ty)| *ty).collect(),
+            result: definition.return_type.unwrap_or(Type::Never),
+        })
+        .collect();
+    for _ in 0..=definitions.len() {
+        let mut changed = false;
+        for (index, definition) in definitions.iter().enumerate() {
+            if definition.return_type.is_some() {
+                continue;
+            }
+            if let Ok(function) = Lowerer::new(&signatures, backend).function(definition)
+                && function.result != Type::Never
+                && function.result != signatures[index].result
+            {
+                signatures[index].result = function.result;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for signature in signatures.iter_mut().filter(|signature| signature.result == Type::Never) {
+        signature.result = Type::Number;
+    }
+
+    let mut functions = Vec::new();
+    for (definition, signature) in definitions.iter().zip(&signatures) {
+        let mut function = Lowerer::new(&signatures, backend).function(definition)?;
+        if !function.result.fits(signature.result) {
+            return Err(format!(
+                "'{}' is declared to return {}, but its body returns {}",
+                definition.name,
+                signature.result.describe(),
+                function.result.describe()
+            )
+            .into());
+        }
+        function.result = signature.result;
+        functions.push(function);
+    }
+
+    let main = Definition { name: "main".into(), parameters: Vec::new(), return_type: None, contract: Contract::default(), body: statements };
+    let mut lowerer = Lowerer::new(&signatures, backend);
+    let body = main.body.iter().map(|form| lowerer.expression(form, None)).collect::<Result<Vec<_>, _>>()?;
+    let main = Function {
+        name: main.name,
+        variables: lowerer.variables,
+        parameter_count: 0,
+        result: Type::Never,
+        requires: Vec::new(),
+        body,
+        ensures: Vec::new(),
+        result_variable: None,
+    };
+    Ok(Program { functions, main })
+}
+
+/// Lower one expression outside any program, as the only form of `main`
+pub fn lower_expression(expr: &LispExpr, backend: &str) -> Result<Program, CompileError> {
+    let program = lower(std::slice::from_ref(expr), backend)?;
+    if program.functions.is_empty() && program.main.body.len() == 1 {
+        Ok(program)
+    } else {
+        Err(format!("{} is a definition, which the {} backend compiles only as part of a program", expr, backend).into())
+    }
+}
+
+/// A named let being lowered: its name, number, and variables
+struct Loop {
+    name: Symbol,
+    id: usize,
+    variables: Vec<usize>,
+}
+
+/// Lowers the body of one function
+struct Lowerer<'a> {
+    signatures: &'a [Signature],
+    backend: &'a str,
+    /// Variables in scope, innermost last
+    scope: Vec<(Symbol, usize)>,
+    variables: Vec<Variable>,
+    /// The named lets being lowered, innermost last
+    loops: Vec<Loop>,
+    loop_count: usize,
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(signatures: &'a [Signature], backend: &'a str) -> Self {
+        Lowerer { signatures, backend, scope: Vec::new(), variables: Vec::new(), loops: Vec::new(), loop_count: 0 }
+    }
+
+    fn function(mut self, definition: &Definition) -> Result<Function, CompileError> {
+        for (parameter, ty) in &definition.parameters {
+            self.variable(parameter.clone(), *ty);
+        }
+        let requires = self.conditions(&definition.contract.requires)?;
+        let body = definition.body.iter().map(|form| self.expression(form, None)).collect::<Result<Vec<_>, _>>()?;
+        let result = body.last().unwrap().ty;
+        let result_variable = match definition.contract.ensures.is_empty() {
+            true => None,
+            false => Some(self.variable(contracts::RESULT.into(), result)),
+        };
+        let ensures = self.conditions(&definition.contract.ensures)?;
+        Ok(Function {
+            name: definition.name.clone(),
+            variables: self.variables,
+            parameter_count: definition.parameters.len(),
+            result,
+            requires,
+            body,
+            ensures,
+            result_variable,
+        })
+    }
+
+    fn conditions(&mut self, conditions: &[LispExpr]) -> Result<Vec<Condition>, CompileError> {
+        conditions
+            .iter()
+            .map(|condition| Ok(Condition { check: self.typed(condition, Type::Bool, "declare")?, source: condition.clone() }))
+            .collect()
+    }
+
+    /// A new variable `name`, brought into scope
+    fn variable(&mut self, name: Symbol, ty: Type) -> usize {
+        let ty = if ty == Type::Never { Type::Number } else { ty };
+        self.variables.push(Variable { name: name.clone(), ty });
+        self.scope.push((name, self.variables.len() - 1));
+        self.variables.len() - 1
+    }
+
+    /// `expr` lowered; `tail` is the named let, if any, whose loop it may go round again
+    fn expression(&mut self, expr: &LispExpr, tail: Option<Symbol>) -> Result<Node, CompileError> {
+        grow_stack(|| match expr {
+            LispExpr::Number(n) => Ok(Node::new(Expr::Number(*n), Type::Number)),
+            LispExpr::Bool(b) => Ok(Node::new(Expr::Bool(*b), Type::Bool)),
+            LispExpr::Symbol(name) | LispExpr::Gensym(name) => match self.scope.iter().rev().find(|(variable, _)| variable == name) {
+                Some((_, index)) => Ok(Node::new(Expr::Variable(*index), self.variables[*index].ty)),
+                None => Err(format!("Unknown variable: {}", name).into()),
+            },
+            LispExpr::List(elements) => self.call(elements, tail),
+            other => Err(CompileError::new("L0403", format!("{} is not supported by the {} backend, which compiles numbers and booleans only", other, self.backend))),
+        })
+    }
+
+    /// `expr` lowered to a value of type `expected`; `form` is what requires it, for messages
+    fn typed(&mut self, expr: &LispExpr, expected: Type, form: &str) -> Result<Node, CompileError> {
+        let node = self.expression(expr, None)?;
+        if !node.ty.fits(expected) {
+            return Err(format!("'{}' requires {}, got {}", form, expected.describe(), expr).into());
+        }
+        Ok(node)
+    }
+
+    fn call(&mut self, elements: &[LispExpr], tail: Option<Symbol>) -> Result<Node, CompileError> {
+        let Some((head, args)) = elements.split_first() else {
+            return Err(CompileError::new("L0403", format!("() is not supported by the {} backend, which compiles numbers and booleans only", self.backend)));
+        };
+        match head.as_symbol() {
+            Some(op @ ("+" | "-" | "*" | "/")) => self.arithmetic(op, args),
+            Some(op @ ("=" | "<" | ">" | "<=" | ">=")) => self.comparison(op, args),
+            Some("if") => self.conditional(args, tail),
+            Some("let") if matches!(args.first(), Some(LispExpr::Symbol(_))) => self.named_let(args),
+            Some(form @ ("let" | "let*")) => self.let_form(form, args),
+            Some(name) if self.loops.iter().any(|named_let| named_let.name == name) => match tail {
+                Some(tail) if tail == name => self.again(args),
+                _ => Err(CompileError::new("L0404", format!("'{}' can only be called in tail position of its named let", name))),
+            },
+            Some(name) => match self.signatures.iter().position(|signature| signature.name == name) {
+                Some(index) => self.function_call(index, args),
+                None => Err(CompileError::new("L0401", format!("Unknown function: {} (the {} backend compiles arithmetic, comparisons, if, let, and defun)", name, self.backend))),
+            },
+            None => Err(CompileError::new("L0406", "First element of list must be a symbol")),
+        }
+    }
+
+    fn arithmetic(&mut self, op: &str, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let mut operands = args.iter().map(|arg| self.typed(arg, Type::Number, op)).collect::<Result<Vec<_>, _>>()?.into_iter();
+        let operator = match op {
+            "+" => Arithmetic::Add,
+            "-" => Arithmetic::Subtract,
+            "*" => Arithmetic::Multiply,
+            _ => Arithmetic::Divide,
+        };
+        let number = |expr| Node::new(expr, Type::Number);
+        let expr = match (operator, operands.next(), operands.len()) {
+            (Arithmetic::Add, None, _) => Expr::Number(0.0),
+            (Arithmetic::Multiply, None, _) => Expr::Number(1.0),
+            (_, None, _) => return Err(CompileError::new("L0402", format!("Arithmetic operation '{}' requires at least one argument", op))),
+            (Arithmetic::Subtract, Some(operand), 0) => Expr::Negate(Box::new(operand)),
+            (Arithmetic::Divide, Some(operand), 0) => Expr::Arithmetic(operator, Box::new(number(Expr::Number(1.0))), Box::new(operand)),
+            (_, Some(first), _) => return Ok(operands.fold(first, |acc, operand| number(Expr::Arithmetic(operator, Box::new(acc), Box::new(operand))))),
+        };
+        Ok(number(expr))
+    }
+
+    fn comparison(&mut self, op: &str, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let [left, right] = args else {
+            return Err(CompileError::new("L0402", format!("Comparison operation '{}' requires exactly 2 arguments", op)));
+        };
+        let comparison = match op {
+            "=" => Comparison::Equal,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            _ => Comparison::GreaterEqual,
+        };
+        let (left, right) = match comparison {
+            Comparison::Equal => (self.expression(left, None)?, self.expression(right, None)?),
+            _ => (self.typed(left, Type::Number, op)?, self.typed(right, Type::Number, op)?),
+        };
+        if !left.ty.fits(right.ty) && !right.ty.fits(left.ty) {
+            return Err(format!("'=' cannot compare {} with {}", left.ty.describe(), right.ty.describe()).into());
+        }
+        Ok(Node::new(Expr::Compare(comparison, Box::new(left), Box::new(right)), Type::Bool))
+    }
+
+    fn conditional(&mut self, args: &[LispExpr], tail: Option<Symbol>) -> Result<Node, CompileError> {
+        let [condition, then_expr, else_expr] = args else {
+            return Err(CompileError::new("L0402", "'if' requires exactly 3 arguments: condition, then-expr, else-expr"));
+        };
+        let condition = self.typed(condition, Type::Bool, "if")?;
+        let (then_node, else_node) = (self.expression(then_expr, tail.clone())?, self.expression(else_expr, tail)?);
+        let ty = match (then_node.ty, else_node.ty) {
+            (Type::Never, ty) | (ty, Type::Never) => ty,
+            (then_ty, else_ty) if then_ty == else_ty => then_ty,
+            (then_ty, else_ty) => {
+                return Err(format!("'if' branches must have the same type, got {} and {}", then_ty.describe(), else_ty.describe()).into());
+            }
+        };
+        Ok(Node::new(Expr::If(Box::new(condition), Box::new(then_node), Box::new(else_node)), ty))
+    }
+
+    /// `let` and `let*` both bind in sequence, each value seeing the bindings before it
+    fn let_form(&mut self, form: &str, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let [bindings, body] = args else {
+            return Err(CompileError::new("L0402", format!("'{}' requires exactly 2 arguments: bindings and body", form)));
+        };
+        let depth = self.scope.len();
+        let mut lowered = Vec::new();
+        for (name, value) in let_bindings(form, bindings)? {
+            let value = self.expression(value, None)?;
+            lowered.push((self.variable(name, value.ty), value));
+        }
+        let body = self.expression(body, None);
+        self.scope.truncate(depth);
+        let body = body?;
+        let ty = body.ty;
+        Ok(Node::new(Expr::Let(lowered, Box::new(body)), ty))
+    }
+
+    /// `(let name ((var init) ...) body)`. Calls to `name` in tail position rebind the
+    /// variables and go round again; any other result ends the loop.
+    fn named_let(&mut self, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let [LispExpr::Symbol(name), bindings, body] = args else {
+            return Err(CompileError::new("L0402", "Named 'let' requires exactly 3 arguments: name, bindings, and body"));
+        };
+        let bindings = let_bindings("let", bindings)?;
+        let values = bindings.iter().map(|(_, value)| self.expression(value, None)).collect::<Result<Vec<_>, _>>()?;
+
+        let depth = self.scope.len();
+        let bindings: Vec<(usize, Node)> = bindings.iter().zip(values).map(|((variable, _), value)| (self.variable(variable.clone(), value.ty), value)).collect();
+        let id = self.loop_count;
+        self.loop_count += 1;
+        self.loops.push(Loop { name: name.clone(), id, variables: bindings.iter().map(|(variable, _)| *variable).collect() });
+        let body = self.expression(body, Some(name.clone()));
+        self.loops.pop();
+        self.scope.truncate(depth);
+        let body = body?;
+        let ty = body.ty;
+        Ok(Node::new(Expr::Loop { id, name: name.clone(), bindings, body: Box::new(body) }, ty))
+    }
+
+    /// A tail call to the innermost named let
+    fn again(&mut self, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let named_let = self.loops.last().unwrap();
+        let (name, id, variables) = (named_let.name.clone(), named_let.id, named_let.variables.clone());
+        if args.len() != variables.len() {
+            return Err(CompileError::new("L0402", format!("'{}' expects {} argument(s), got {}", name, variables.len(), args.len())));
+        }
+        let bindings = args
+            .iter()
+            .zip(variables)
+            .map(|(arg, variable)| Ok((variable, self.typed(arg, self.variables[variable].ty, &name)?)))
+            .collect::<Result<_, CompileError>>()?;
+        Ok(Node::new(Expr::Again { id, bindings }, Type::Never))
+    }
+
+    fn function_call(&mut self, index: usize, args: &[LispExpr]) -> Result<Node, CompileError> {
+        let signatures = self.signatures;
+        let signature = &signatures[index];
+        if args.len() != signature.parameters.len() {
+            return Err(CompileError::new("L0402", format!("'{}' expects {} argument(s), got {}", signature.name, signature.parameters.len(), args.len())));
+        }
+        let args = args.iter().zip(&signature.parameters).map(|(arg, ty)| self.typed(arg, *ty, &signature.name)).collect::<Result<_, _>>()?;
+        Ok(Node::new(Expr::Call(index, args), signature.result))
+    }
+}
+
+fn let_bindings<'e>(form: &str, bindings: &'e LispExpr) -> Result<Vec<(Symbol, &'e LispExpr)>, CompileError> {
+    let LispExpr::List(bindings) = bindings else {
+        return Err(CompileError::new("L0406", format!("First argument to '{}' must be a list of bindings", form)));
+    };
+    bindings
+        .iter()
+        .map(|binding| match binding.as_list() {
+            Some([LispExpr::Symbol(name) | LispExpr::Gensym(name), value]) => Ok((name.clone(), value)),
+            Some([_, _]) => Err(CompileError::new("L0406", "Variable name must be a symbol")),
+            _ => Err(CompileError::new("L0406", "Each binding must be a list of [variable, value]")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn lower_source(source: &str) -> Result<Program, CompileError> {
+        lower(&parse(tokenize(source).unwrap()).unwrap(), "test")
+    }
+
+    #[test]
+    fn test_lower_types_functions() {
+        let program = lower_source("(defun even? (n) (if (= n 0) true (odd? (- n 1)))) (defun odd? (n) (if (= n 0) false (even? (- n 1)))) (deftest t (assert-true true)) (odd? 3)").unwrap();
+        assert_eq!(program.functions.iter().map(|function| function.result).collect::<Vec<_>>(), vec![Type::Bool, Type::Bool]);
+        assert_eq!(program.main.body, vec![Node::new(Expr::Call(1, vec![Node::new(Expr::Number(3.0), Type::Number)]), Type::Bool)]);
+
+        let program = lower_source("(let loop ((i 0)) (if (> i 3) i (loop (+ i 1)))) (- 2) (/ 4) (+)").unwrap();
+        assert_eq!(program.main.variables, vec![Variable { name: "i".into(), ty: Type::Number }]);
+        let Expr::Loop { body, .. } = &program.main.body[0].expr else {
+            panic!("expected a loop");
+        };
+        let Expr::If(_, _, again) = &body.expr else {
+            panic!("expected if");
+        };
+        assert_eq!(again.ty, Type::Never);
+        let number = |n| Box::new(Node::new(Expr::Number(n), Type::Number));
+        assert_eq!(program.main.body[1].expr, Expr::Negate(number(2.0)));
+        assert_eq!(program.main.body[2].expr, Expr::Arithmetic(Arithmetic::Divide, number(1.0), number(4.0)));
+        assert_eq!(program.main.body[3].expr, Expr::Number(0.0));
+    }
+
+    #[test]
+    fn test_lower_rejects_unsupported_programs() {
+        let error = |source: &str| lower_source(source).unwrap_err().to_string();
+        assert!(error("(concat \"a\" \"b\")").contains("Unknown function: concat"));
+        assert!(error("(+ 1 \"two\")").contains("is not supported by the test backend"));
+        assert_eq!(error("(+ 1 (< 1 2))"), "'+' requires a number, got (< 1 2)");
+        assert_eq!(error("(if 1 2 3)"), "'if' requires a boolean, got 1");
+        assert_eq!(error("(= 1 true)"), "'=' cannot compare a number with a boolean");
+        assert_eq!(error("(if (< 1 2) 1 false)"), "'if' branches must have the same type, got a number and a boolean");
+        assert_eq!(error("(defun f ((s : string)) 1)"), "'f' parameter 's' has type string, but the test backend supports only number and bool");
+        assert_eq!(error("(defun f (x) : bool x)"), "'f' is declared to return a boolean, but its body returns a number");
+        assert_eq!(error("(let loop ((i 0)) (+ 1 (loop i)))"), "'loop' can only be called in tail position of its named let");
+        assert!(error("(defun id<T> (x) x)").contains("type parameters"));
+        assert!(error("(defstruct point (x y))").contains("Unknown function: defstruct"));
+        let definition = parse(tokenize("(defun f (x) x)").unwrap()).unwrap();
+        assert_eq!(
+            lower_expression(&definition[0], "test").unwrap_err().message(),
+            "(defun f (x) x) is a definition, which the test backend compiles only as part of a program"
+        );
+    }
+}
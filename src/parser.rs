@@ -1,25 +1,225 @@
-use crate::ast::LispExpr;
-use crate::lexer::Token;
+use crate::ast::{grow_stack, Comment, CommentTree, LispExpr, Span, SpanTree};
+use crate::explain::CompileError;
+use crate::features::{Features, UNLESS_FEATURE, WHEN_FEATURE};
+use crate::include::SearchDir;
+use crate::lexer::{self, tokenize_with_comments, LexerLimits, SpannedTokens, Token};
+use crate::symbol::Symbol;
+use std::rc::Rc;
 
-pub fn parse(tokens: Vec<Token>) -> Result<Vec<LispExpr>, String> {
-    let mut parser = Parser::new(tokens);
+/// Top-level expressions with the spans of all their nodes, as `Reader::read_with_span_trees` returns them
+pub type SpannedForms = Vec<(LispExpr, SpanTree)>;
+
+/// How a program is read: the features its conditionals are tested against (see
+/// `features`), the limits the lexer stops at, and where included files are looked
+/// for (see `include`). The free functions of this module read with no features
+/// enabled, and the `lexer` functions tokenize without limits.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reader {
+    features: Features,
+    limits: LexerLimits,
+    search_path: Vec<SearchDir>,
+}
+
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn features(&self) -> &Features {
+        &self.features
+    }
+
+    pub fn with_limits(mut self, limits: LexerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> LexerLimits {
+        self.limits
+    }
+
+    /// Look for included files in `dirs` when they are not next to the including file
+    pub fn with_search_path(mut self, dirs: Vec<SearchDir>) -> Self {
+        self.search_path = dirs;
+        self
+    }
+
+    pub fn search_path(&self) -> &[SearchDir] {
+        &self.search_path
+    }
+
+    /// Tokenize `source` within this reader's limits, recording the span of every token
+    pub fn tokenize(&self, source: &str) -> Result<SpannedTokens, CompileError> {
+        lexer::tokenize_with_limits(source, self.limits).map(|(tokens, _)| tokens)
+    }
+
+    /// Parse tokens into expressions, dropping the forms that feature conditionals disable
+    pub fn parse(&self, tokens: Vec<Token>) -> Result<Vec<LispExpr>, CompileError> {
+        let mut parser = Parser::new(tokens, &self.features);
+        let mut expressions = Vec::new();
+
+        while !parser.is_at_end() {
+            expressions.extend(parser.parse_elements()?);
+            parser.nodes.clear();
+        }
+
+        Ok(expressions)
+    }
+
+    /// Parse spanned tokens, returning each top-level expression with its source span
+    pub fn parse_with_spans(&self, tokens: Vec<(Token, Span)>) -> Result<Vec<(LispExpr, Span)>, CompileError> {
+        let forms = self.parse_with_span_trees(tokens)?;
+        Ok(forms.into_iter().map(|(expr, tree)| (expr, tree.span.expect("parsed nodes have spans"))).collect())
+    }
+
+    /// Parse spanned tokens, returning each top-level expression with the spans of all its nodes
+    pub fn parse_with_span_trees(&self, tokens: Vec<(Token, Span)>) -> Result<SpannedForms, CompileError> {
+        parse_spanned(tokens, |tokens| Parser::new(tokens, &self.features))
+    }
+
+    /// Tokenize and parse `source`
+    pub fn read(&self, source: &str) -> Result<Vec<LispExpr>, CompileError> {
+        self.parse(self.tokenize(source)?.into_iter().map(|(token, _)| token).collect())
+    }
+
+    pub fn read_with_spans(&self, source: &str) -> Result<Vec<(LispExpr, Span)>, CompileError> {
+        self.parse_with_spans(self.tokenize(source)?)
+    }
+
+    pub fn read_with_span_trees(&self, source: &str) -> Result<SpannedForms, CompileError> {
+        self.parse_with_span_trees(self.tokenize(source)?)
+    }
+}
+
+/// Parse tokens into expressions with no features enabled; see `Reader::parse`
+pub fn parse(tokens: Vec<Token>) -> Result<Vec<LispExpr>, CompileError> {
+    Reader::new().parse(tokens)
+}
+
+/// Parse spanned tokens with no features enabled; see `Reader::parse_with_spans`
+pub fn parse_with_spans(tokens: Vec<(Token, Span)>) -> Result<Vec<(LispExpr, Span)>, CompileError> {
+    Reader::new().parse_with_spans(tokens)
+}
+
+/// Parse spanned tokens with no features enabled; see `Reader::parse_with_span_trees`
+pub fn parse_with_span_trees(tokens: Vec<(Token, Span)>) -> Result<Vec<(LispExpr, SpanTree)>, CompileError> {
+    Reader::new().parse_with_span_trees(tokens)
+}
+
+fn parse_spanned<'a>(
+    tokens: Vec<(Token, Span)>,
+    parser: impl FnOnce(Vec<Token>) -> Parser<'a>,
+) -> Result<Vec<(LispExpr, SpanTree)>, CompileError> {
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = tokens.into_iter().unzip();
+    let mut parser = parser(tokens);
     let mut expressions = Vec::new();
-    
+
     while !parser.is_at_end() {
-        expressions.push(parser.parse_expression()?);
+        let forms = parser.parse_elements()?;
+        // One node was recorded for each expression read
+        let trees = std::mem::take(&mut parser.nodes);
+        for (expr, tree) in forms.into_iter().zip(trees) {
+            // Node spans are token ranges until mapped to bytes here
+            let tree = tree.map_spans(&|tokens| Span::new(spans[tokens.start].start, spans[tokens.end - 1].end));
+            expressions.push((expr, tree));
+        }
     }
-    
+
     Ok(expressions)
 }
 
-struct Parser {
+/// A top-level expression with the spans and comments of its nodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommentedForm {
+    pub expr: LispExpr,
+    pub spans: SpanTree,
+    pub comments: CommentTree,
+}
+
+/// Parse source keeping its comments, for formatters and documentation tools. A
+/// form's `leading` comments are the comment lines directly above it, with no blank
+/// line between; comments inside it attach as in `CommentTree::attach`. Comments
+/// that belong to no form, such as a file header, are returned as detached. Feature
+/// conditionals are kept as written: `#+feature` is a symbol before its form.
+pub fn parse_with_comments(source: &str) -> Result<(Vec<CommentedForm>, Vec<Comment>), CompileError> {
+    let (tokens, comments) = tokenize_with_comments(source)?;
+    let features = Features::default();
+    let mut forms: Vec<CommentedForm> = parse_spanned(tokens, |tokens| Parser::new(tokens, &features).keeping_conditionals())?
+        .into_iter()
+        .map(|(expr, spans)| {
+            let comments = CommentTree::attach(&spans, &comments, source);
+            CommentedForm { expr, spans, comments }
+        })
+        .collect();
+
+    let blank_line_between = |end: usize, start: usize| source[end..start].matches('\n').count() > 1;
+    let starts: Vec<usize> = forms.iter().map(|form| form.spans.span.map_or(0, |span| span.start)).collect();
+    let mut detached = Vec::new();
+    // Comments on their own lines since the last form
+    let mut pending: Vec<Comment> = Vec::new();
+    let mut next_form = 0;
+    for comment in comments {
+        while next_form < forms.len() && starts[next_form] < comment.span.start {
+            take_leading(&mut forms[next_form], &mut pending, &mut detached, &blank_line_between);
+            next_form += 1;
+        }
+        let previous_end = next_form.checked_sub(1).and_then(|index| forms[index].spans.span).map(|span| span.end);
+        match previous_end {
+            // Inside the previous form, so already attached
+            Some(end) if comment.span.start < end => {}
+            Some(end) if !source[end..comment.span.start].contains('\n') => {
+                forms[next_form - 1].comments.trailing = Some(comment);
+            }
+            _ => pending.push(comment),
+        }
+    }
+    if next_form < forms.len() {
+        take_leading(&mut forms[next_form], &mut pending, &mut detached, &blank_line_between);
+    }
+    detached.append(&mut pending);
+    Ok((forms, detached))
+}
+
+/// Give `form` the pending comments that run up to it without a blank line
+fn take_leading(
+    form: &mut CommentedForm,
+    pending: &mut Vec<Comment>,
+    detached: &mut Vec<Comment>,
+    blank_line_between: &impl Fn(usize, usize) -> bool,
+) {
+    let mut end = form.spans.span.map_or(0, |span| span.start);
+    let mut split = pending.len();
+    while split > 0 && !blank_line_between(pending[split - 1].span.end, end) {
+        split -= 1;
+        end = pending[split].span.start;
+    }
+    form.comments.leading = pending.split_off(split);
+    detached.append(pending);
+}
+
+struct Parser<'a> {
     tokens: Vec<Token>,
     current: usize,
+    /// Token ranges of the expressions parsed so far; an expression adopts the
+    /// nodes recorded while parsing it as its children
+    nodes: Vec<SpanTree>,
+    features: &'a Features,
+    /// Read feature conditionals as ordinary forms instead of applying them
+    keep_conditionals: bool,
 }
 
-impl Parser {
-    fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+impl<'a> Parser<'a> {
+    fn new(tokens: Vec<Token>, features: &'a Features) -> Self {
+        Self { tokens, current: 0, nodes: Vec::new(), features, keep_conditionals: false }
+    }
+
+    fn keeping_conditionals(self) -> Self {
+        Self { keep_conditionals: true, ..self }
     }
     
     fn is_at_end(&self) -> bool {
@@ -37,7 +237,87 @@ impl Parser {
         self.tokens.get(self.current - 1)
     }
     
-    fn parse_expression(&mut self) -> Result<LispExpr, String> {
+    /// The expressions the next element reads as: the element itself, or the forms a
+    /// feature conditional keeps, which may be none
+    fn parse_elements(&mut self) -> Result<Vec<LispExpr>, CompileError> {
+        if self.keep_conditionals {
+            return Ok(vec![self.parse_expression()?]);
+        }
+        match (self.peek(), self.tokens.get(self.current + 1)) {
+            (Some(Token::Symbol(s)), _) if s.starts_with("#+") || s.starts_with("#-") => self.parse_reader_conditional(),
+            (Some(Token::LeftParen), Some(Token::Symbol(s))) if s == WHEN_FEATURE || s == UNLESS_FEATURE => self.parse_feature_form(),
+            _ => Ok(vec![self.parse_expression()?]),
+        }
+    }
+
+    /// `#+feature form` or `#-feature form`, with a compound feature after a bare `#+` or `#-`
+    fn parse_reader_conditional(&mut self) -> Result<Vec<LispExpr>, CompileError> {
+        let Some(Token::Symbol(prefix)) = self.advance().cloned() else {
+            unreachable!("checked by parse_elements")
+        };
+        let depth = self.nodes.len();
+        let holds = match &prefix[2..] {
+            "" if matches!(self.peek(), None | Some(Token::RightParen)) => {
+                return Err(CompileError::new("L0106", format!("Expected a feature after '{}'", prefix)));
+            }
+            "" => {
+                let feature = self.parse_expression()?;
+                self.features.holds(&feature)?
+            }
+            name => self.features.is_enabled(name),
+        };
+        self.nodes.truncate(depth);
+        if matches!(self.peek(), None | Some(Token::RightParen)) {
+            return Err(CompileError::new("L0106", format!("Expected a form after '{}'", prefix)));
+        }
+        let forms = self.parse_elements()?;
+        self.kept(forms, holds == prefix.starts_with("#+"), depth)
+    }
+
+    /// `(when-feature feature form...)` or `(unless-feature feature form...)`
+    fn parse_feature_form(&mut self) -> Result<Vec<LispExpr>, CompileError> {
+        self.advance();
+        let Some(Token::Symbol(head)) = self.advance().cloned() else {
+            unreachable!("checked by parse_elements")
+        };
+        let depth = self.nodes.len();
+        if matches!(self.peek(), None | Some(Token::RightParen)) {
+            return Err(CompileError::new("L0106", format!("'{}' requires a feature", head)));
+        }
+        let feature = self.parse_expression()?;
+        let holds = self.features.holds(&feature)?;
+        self.nodes.truncate(depth);
+        let mut forms = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::RightParen) => break,
+                Some(_) => forms.extend(self.parse_elements()?),
+                None => return Err(CompileError::new("L0103", "Unclosed list - missing ')'")),
+            }
+        }
+        self.advance();
+        self.kept(forms, holds == (head == WHEN_FEATURE), depth)
+    }
+
+    /// `forms` when `keep`, otherwise nothing, forgetting their nodes
+    fn kept(&mut self, forms: Vec<LispExpr>, keep: bool, depth: usize) -> Result<Vec<LispExpr>, CompileError> {
+        if keep {
+            return Ok(forms);
+        }
+        self.nodes.truncate(depth);
+        Ok(Vec::new())
+    }
+
+    fn parse_expression(&mut self) -> Result<LispExpr, CompileError> {
+        let start = self.current;
+        let depth = self.nodes.len();
+        let expr = grow_stack(|| self.parse_form())?;
+        let children = self.nodes.split_off(depth);
+        self.nodes.push(SpanTree::new(Span::new(start, self.current), children));
+        Ok(expr)
+    }
+
+    fn parse_form(&mut self) -> Result<LispExpr, CompileError> {
         match self.peek() {
             Some(Token::LeftParen) => self.parse_list(),
             Some(Token::Number(n)) => {
@@ -46,7 +326,7 @@ impl Parser {
                 Ok(LispExpr::Number(num))
             },
             Some(Token::Symbol(s)) => {
-                let sym = s.clone();
+                let sym = Symbol::from(s);
                 self.advance();
                 Ok(LispExpr::Symbol(sym))
             },
@@ -67,36 +347,36 @@ impl Parser {
             Some(Token::Quote) => {
                 self.advance();
                 match self.peek() {
-                    None => Err("Expected expression after quote".to_string()),
+                    None => Err(CompileError::new("L0105", "Expected expression after quote")),
                     _ => {
                         let expr = self.parse_expression()?;
-                        Ok(LispExpr::Quote(Box::new(expr)))
+                        Ok(LispExpr::Quote(Rc::new(expr)))
                     }
                 }
             },
             Some(Token::Quasiquote) => {
                 self.advance();
                 let expr = self.parse_expression()?;
-                Ok(LispExpr::Quasiquote(Box::new(expr)))
+                Ok(LispExpr::Quasiquote(Rc::new(expr)))
             },
             Some(Token::Unquote) => {
                 self.advance();
                 let expr = self.parse_expression()?;
-                Ok(LispExpr::Unquote(Box::new(expr)))
+                Ok(LispExpr::Unquote(Rc::new(expr)))
             },
             Some(Token::Splice) => {
                 self.advance();
                 let expr = self.parse_expression()?;
-                Ok(LispExpr::Splice(Box::new(expr)))
+                Ok(LispExpr::Splice(Rc::new(expr)))
             },
             Some(Token::RightParen) => {
-                Err("Unexpected ')' - missing opening parenthesis".to_string())
+                Err(CompileError::new("L0103", "Unexpected ')' - missing opening parenthesis"))
             },
-            None => Err("Unexpected end of input".to_string()),
+            None => Err(CompileError::new("L0103", "Unexpected end of input")),
         }
     }
     
-    fn parse_list(&mut self) -> Result<LispExpr, String> {
+    fn parse_list(&mut self) -> Result<LispExpr, CompileError> {
         self.advance();
         
         // Check for special forms
@@ -117,29 +397,29 @@ impl Parser {
             match token {
                 Token::RightParen => {
                     self.advance();
-                    return Ok(LispExpr::List(elements));
+                    return Ok(LispExpr::List(elements.into()));
                 },
                 _ => {
-                    elements.push(self.parse_expression()?);
+                    elements.extend(self.parse_elements()?);
                 }
             }
         }
         
-        Err("Unclosed list - missing ')'".to_string())
+        Err(CompileError::new("L0103", "Unclosed list - missing ')'"))
     }
 
-    fn parse_defmacro(&mut self) -> Result<LispExpr, String> {
+    fn parse_defmacro(&mut self) -> Result<LispExpr, CompileError> {
         // Consume 'defmacro'
         self.advance();
         
         // Parse macro name
         let name = match self.peek() {
             Some(Token::Symbol(s)) => {
-                let name = s.clone();
+                let name = Symbol::from(s);
                 self.advance();
                 name
             },
-            _ => return Err("Missing macro name after 'defmacro'".to_string()),
+            _ => return Err(CompileError::new("L0104", "Missing macro name after 'defmacro'")),
         };
         
         // Parse parameter list
@@ -147,17 +427,25 @@ impl Parser {
             Some(Token::LeftParen) => {
                 self.parse_parameter_list()?
             },
-            _ => return Err("Missing parameter list for macro definition".to_string()),
+            _ => return Err(CompileError::new("L0104", "Missing parameter list for macro definition")),
         };
         
-        // Parse macro body
-        let body = match self.peek() {
-            Some(Token::RightParen) => return Err("Missing macro body".to_string()),
-            Some(_) => {
-                let body = self.parse_expression()?;
-                Box::new(body)
-            },
-            None => return Err("Missing macro body".to_string()),
+        // Parse macro body, after any `(declare ...)` forms
+        let mut declarations = Vec::new();
+        let body = loop {
+            let depth = self.nodes.len();
+            let form = match self.peek() {
+                Some(Token::RightParen) => return Err(CompileError::new("L0104", "Missing macro body")),
+                Some(_) => self.parse_expression()?,
+                None => return Err(CompileError::new("L0104", "Missing macro body")),
+            };
+            let is_declaration = matches!(&form, LispExpr::List(elements) if elements.first().and_then(LispExpr::as_symbol) == Some("declare"));
+            if !is_declaration || self.peek() == Some(&Token::RightParen) {
+                break form;
+            }
+            // Spans are only tracked for the body, the macro's one child
+            self.nodes.truncate(depth);
+            declarations.push(form);
         };
         
         // Consume closing paren
@@ -165,13 +453,13 @@ impl Parser {
             Some(Token::RightParen) => {
                 self.advance();
             },
-            _ => return Err("Expected ')' after macro definition".to_string()),
+            _ => return Err(CompileError::new("L0104", "Expected ')' after macro definition")),
         }
         
-        Ok(LispExpr::Macro { name, parameters, body })
+        Ok(LispExpr::Macro { name, parameters, body: Rc::new(body), declarations })
     }
     
-    fn parse_parameter_list(&mut self) -> Result<Vec<String>, String> {
+    fn parse_parameter_list(&mut self) -> Result<Vec<Symbol>, CompileError> {
         // Consume opening paren
         self.advance();
         
@@ -184,17 +472,17 @@ impl Parser {
                     return Ok(parameters);
                 },
                 Token::Symbol(s) => {
-                    parameters.push(s.clone());
+                    parameters.push(Symbol::from(s));
                     self.advance();
                 },
-                _ => return Err("Expected symbol in parameter list".to_string()),
+                _ => return Err(CompileError::new("L0104", "Expected symbol in parameter list")),
             }
         }
         
-        Err("Unclosed parameter list - missing ')'".to_string())
+        Err(CompileError::new("L0103", "Unclosed parameter list - missing ')'"))
     }
     
-    fn parse_quote_longhand(&mut self) -> Result<LispExpr, String> {
+    fn parse_quote_longhand(&mut self) -> Result<LispExpr, CompileError> {
         self.advance(); // consume 'quote'
         
         let expr = self.parse_expression()?;
@@ -203,13 +491,13 @@ impl Parser {
         match self.peek() {
             Some(Token::RightParen) => {
                 self.advance();
-                Ok(LispExpr::Quote(Box::new(expr)))
+                Ok(LispExpr::Quote(Rc::new(expr)))
             },
-            _ => Err("Expected ')' after quote expression".to_string()),
+            _ => Err(CompileError::new("L0105", "Expected ')' after quote expression")),
         }
     }
     
-    fn parse_quasiquote_longhand(&mut self) -> Result<LispExpr, String> {
+    fn parse_quasiquote_longhand(&mut self) -> Result<LispExpr, CompileError> {
         self.advance(); // consume 'quasiquote'
         
         let expr = self.parse_expression()?;
@@ -218,13 +506,13 @@ impl Parser {
         match self.peek() {
             Some(Token::RightParen) => {
                 self.advance();
-                Ok(LispExpr::Quasiquote(Box::new(expr)))
+                Ok(LispExpr::Quasiquote(Rc::new(expr)))
             },
-            _ => Err("Expected ')' after quasiquote expression".to_string()),
+            _ => Err(CompileError::new("L0105", "Expected ')' after quasiquote expression")),
         }
     }
     
-    fn parse_unquote_longhand(&mut self) -> Result<LispExpr, String> {
+    fn parse_unquote_longhand(&mut self) -> Result<LispExpr, CompileError> {
         self.advance(); // consume 'unquote'
         
         let expr = self.parse_expression()?;
@@ -233,13 +521,13 @@ impl Parser {
         match self.peek() {
             Some(Token::RightParen) => {
                 self.advance();
-                Ok(LispExpr::Unquote(Box::new(expr)))
+                Ok(LispExpr::Unquote(Rc::new(expr)))
             },
-            _ => Err("Expected ')' after unquote expression".to_string()),
+            _ => Err(CompileError::new("L0105", "Expected ')' after unquote expression")),
         }
     }
     
-    fn parse_splice_longhand(&mut self) -> Result<LispExpr, String> {
+    fn parse_splice_longhand(&mut self) -> Result<LispExpr, CompileError> {
         self.advance(); // consume 'unquote-splicing'
         
         let expr = self.parse_expression()?;
@@ -248,9 +536,9 @@ impl Parser {
         match self.peek() {
             Some(Token::RightParen) => {
                 self.advance();
-                Ok(LispExpr::Splice(Box::new(expr)))
+                Ok(LispExpr::Splice(Rc::new(expr)))
             },
-            _ => Err("Expected ')' after unquote-splicing expression".to_string()),
+            _ => Err(CompileError::new("L0105", "Expected ')' after unquote-splicing expression")),
         }
     }
 }
@@ -273,10 +561,10 @@ mod tests {
         let ast = parse(tokens).unwrap();
         assert_eq!(ast, vec![
             LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
+                LispExpr::Symbol("+".into()),
                 LispExpr::Number(1.0),
                 LispExpr::Number(2.0),
-            ])
+            ].into())
         ]);
     }
     
@@ -286,17 +574,66 @@ mod tests {
         let ast = parse(tokens).unwrap();
         assert_eq!(ast, vec![
             LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
+                LispExpr::Symbol("*".into()),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("+".to_string()),
+                    LispExpr::Symbol("+".into()),
                     LispExpr::Number(1.0),
                     LispExpr::Number(2.0),
-                ]),
+                ].into()),
                 LispExpr::Number(3.0),
-            ])
+            ].into())
         ]);
     }
 
+    #[test]
+    fn test_parse_with_spans() {
+        use crate::lexer::tokenize_with_spans;
+
+        let source = "(+ 1 2)\n  'x (list (a) b)";
+        let parsed = parse_with_spans(tokenize_with_spans(source).unwrap()).unwrap();
+
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(&source[parsed[0].1.start..parsed[0].1.end], "(+ 1 2)");
+        assert_eq!(&source[parsed[1].1.start..parsed[1].1.end], "'x");
+        assert_eq!(&source[parsed[2].1.start..parsed[2].1.end], "(list (a) b)");
+    }
+
+    #[test]
+    fn test_parse_with_span_trees() {
+        use crate::lexer::tokenize_with_spans;
+
+        let source = "(list (a) 'b)";
+        let parsed = parse_with_span_trees(tokenize_with_spans(source).unwrap()).unwrap();
+        let text = |tree: &SpanTree| tree.span.map(|s| &source[s.start..s.end]);
+
+        let tree = &parsed[0].1;
+        assert_eq!(text(tree), Some("(list (a) 'b)"));
+        assert_eq!(text(&tree.children[1]), Some("(a)"));
+        assert_eq!(text(&tree.children[1].children[0]), Some("a"));
+        assert_eq!(text(&tree.children[2]), Some("'b"));
+        assert_eq!(text(&tree.children[2].children[0]), Some("b"));
+    }
+
+    #[test]
+    fn test_parse_with_comments() {
+        let source = ";;; Header\n\n;; Adds one\n;; to n\n(defun inc (n) ; doc\n  ;; the sum\n  (+ n 1)\n  ; end\n  )\n\n; trailer";
+        let (forms, detached) = parse_with_comments(source).unwrap();
+        let texts = |comments: &[Comment]| comments.iter().map(|c| c.text.clone()).collect::<Vec<_>>();
+
+        assert_eq!(forms.len(), 1);
+        let comments = &forms[0].comments;
+        assert_eq!(texts(&comments.leading), vec![";; Adds one", ";; to n"]);
+        assert_eq!(comments.trailing, None);
+        assert_eq!(comments.children[2].trailing.as_ref().map(|c| c.text.as_str()), Some("; doc"));
+        assert_eq!(texts(&comments.children[3].leading), vec![";; the sum"]);
+        assert_eq!(texts(&comments.inner), vec!["; end"]);
+        assert_eq!(texts(&detached), vec![";;; Header", "; trailer"]);
+
+        let (forms, _) = parse_with_comments("(a) ; first\n(b)").unwrap();
+        assert_eq!(forms[0].comments.trailing.as_ref().map(|c| c.text.as_str()), Some("; first"));
+        assert!(forms[1].comments.leading.is_empty());
+    }
+
     #[test]
     fn test_parse_basic_defmacro() {
         let tokens = tokenize("(defmacro when (condition) body)").unwrap();
@@ -304,10 +641,10 @@ mod tests {
         
         assert_eq!(ast.len(), 1);
         match &ast[0] {
-            LispExpr::Macro { name, parameters, body } => {
+            LispExpr::Macro { name, parameters, body, .. } => {
                 assert_eq!(name, "when");
                 assert_eq!(parameters, &vec!["condition".to_string()]);
-                assert_eq!(**body, LispExpr::Symbol("body".to_string()));
+                assert_eq!(**body, LispExpr::Symbol("body".into()));
             },
             _ => panic!("Expected Macro variant"),
         }
@@ -320,7 +657,7 @@ mod tests {
         
         assert_eq!(ast.len(), 1);
         match &ast[0] {
-            LispExpr::Macro { name, parameters, body } => {
+            LispExpr::Macro { name, parameters, body, .. } => {
                 assert_eq!(name, "when");
                 assert_eq!(parameters, &vec!["condition".to_string(), "&rest".to_string(), "body".to_string()]);
                 assert_eq!(**body, LispExpr::Nil);
@@ -336,14 +673,14 @@ mod tests {
         
         assert_eq!(ast.len(), 1);
         match &ast[0] {
-            LispExpr::Macro { name, parameters, body } => {
+            LispExpr::Macro { name, parameters, body, .. } => {
                 assert_eq!(name, "when");
                 assert_eq!(parameters, &vec!["condition".to_string(), "&rest".to_string(), "body".to_string()]);
                 // Body should be a list representing (if condition (progn body) nil)
                 match body.as_ref() {
                     LispExpr::List(elements) => {
                         assert_eq!(elements.len(), 4);
-                        assert_eq!(elements[0], LispExpr::Symbol("if".to_string()));
+                        assert_eq!(elements[0], LispExpr::Symbol("if".into()));
                     },
                     _ => panic!("Expected List for macro body"),
                 }
@@ -357,7 +694,7 @@ mod tests {
         let tokens = tokenize("(defmacro)").unwrap();
         let result = parse(tokens);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Missing macro name"));
+        assert!(result.unwrap_err().message().contains("Missing macro name"));
     }
 
     #[test]
@@ -365,7 +702,7 @@ mod tests {
         let tokens = tokenize("(defmacro test)").unwrap();
         let result = parse(tokens);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Missing parameter list"));
+        assert!(result.unwrap_err().message().contains("Missing parameter list"));
     }
 
     #[test]
@@ -373,7 +710,7 @@ mod tests {
         let tokens = tokenize("(defmacro test ())").unwrap();
         let result = parse(tokens);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Missing macro body"));
+        assert!(result.unwrap_err().message().contains("Missing macro body"));
     }
 
     #[test]
@@ -384,7 +721,7 @@ mod tests {
         assert_eq!(ast.len(), 1);
         match &ast[0] {
             LispExpr::Quote(expr) => {
-                assert_eq!(**expr, LispExpr::Symbol("x".to_string()));
+                assert_eq!(**expr, LispExpr::Symbol("x".into()));
             },
             _ => panic!("Expected Quote variant"),
         }
@@ -398,7 +735,7 @@ mod tests {
         assert_eq!(ast.len(), 1);
         match &ast[0] {
             LispExpr::Quote(expr) => {
-                assert_eq!(**expr, LispExpr::Symbol("x".to_string()));
+                assert_eq!(**expr, LispExpr::Symbol("x".into()));
             },
             _ => panic!("Expected Quote variant"),
         }
@@ -415,11 +752,11 @@ mod tests {
                 match expr.as_ref() {
                     LispExpr::List(elements) => {
                         assert_eq!(elements.len(), 3);
-                        assert_eq!(elements[0], LispExpr::Symbol("+".to_string()));
+                        assert_eq!(elements[0], LispExpr::Symbol("+".into()));
                         // Check that ,x becomes an Unquote
                         match &elements[1] {
                             LispExpr::Unquote(unquoted) => {
-                                assert_eq!(**unquoted, LispExpr::Symbol("x".to_string()));
+                                assert_eq!(**unquoted, LispExpr::Symbol("x".into()));
                             },
                             _ => panic!("Expected Unquote variant for ,x"),
                         }
@@ -443,10 +780,10 @@ mod tests {
                 match expr.as_ref() {
                     LispExpr::List(elements) => {
                         assert_eq!(elements.len(), 3);
-                        assert_eq!(elements[0], LispExpr::Symbol("+".to_string()));
+                        assert_eq!(elements[0], LispExpr::Symbol("+".into()));
                         match &elements[1] {
                             LispExpr::Unquote(unquoted) => {
-                                assert_eq!(**unquoted, LispExpr::Symbol("x".to_string()));
+                                assert_eq!(**unquoted, LispExpr::Symbol("x".into()));
                             },
                             _ => panic!("Expected Unquote variant"),
                         }
@@ -470,10 +807,10 @@ mod tests {
                 match expr.as_ref() {
                     LispExpr::List(elements) => {
                         assert_eq!(elements.len(), 2);
-                        assert_eq!(elements[0], LispExpr::Symbol("list".to_string()));
+                        assert_eq!(elements[0], LispExpr::Symbol("list".into()));
                         match &elements[1] {
                             LispExpr::Splice(spliced) => {
-                                assert_eq!(**spliced, LispExpr::Symbol("items".to_string()));
+                                assert_eq!(**spliced, LispExpr::Symbol("items".into()));
                             },
                             _ => panic!("Expected Splice variant for ,@items"),
                         }
@@ -496,10 +833,10 @@ mod tests {
                 match expr.as_ref() {
                     LispExpr::List(elements) => {
                         assert_eq!(elements.len(), 2);
-                        assert_eq!(elements[0], LispExpr::Symbol("list".to_string()));
+                        assert_eq!(elements[0], LispExpr::Symbol("list".into()));
                         match &elements[1] {
                             LispExpr::Splice(spliced) => {
-                                assert_eq!(**spliced, LispExpr::Symbol("items".to_string()));
+                                assert_eq!(**spliced, LispExpr::Symbol("items".into()));
                             },
                             _ => panic!("Expected Splice variant"),
                         }
@@ -524,10 +861,10 @@ mod tests {
                         match inner.as_ref() {
                             LispExpr::List(elements) => {
                                 assert_eq!(elements.len(), 2);
-                                assert_eq!(elements[0], LispExpr::Symbol("+".to_string()));
+                                assert_eq!(elements[0], LispExpr::Symbol("+".into()));
                                 match &elements[1] {
                                     LispExpr::Unquote(unquoted) => {
-                                        assert_eq!(**unquoted, LispExpr::Symbol("x".to_string()));
+                                        assert_eq!(**unquoted, LispExpr::Symbol("x".into()));
                                     },
                                     _ => panic!("Expected Unquote variant"),
                                 }
@@ -547,6 +884,32 @@ mod tests {
         let tokens = tokenize("'").unwrap();
         let result = parse(tokens);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Expected expression after quote"));
+        assert!(result.unwrap_err().message().contains("Expected expression after quote"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_deeply_nested_input() {
+        let depth = 100_000;
+        let source = format!("{}x{}", "(f ".repeat(depth), ")".repeat(depth));
+        let ast = parse(tokenize(&source).unwrap()).unwrap();
+
+        let mut expr = &ast[0];
+        let mut levels = 0;
+        while let LispExpr::List(elements) = expr {
+            expr = &elements[1];
+            levels += 1;
+        }
+        assert_eq!(levels, depth);
+        assert_eq!(ast[0].clone(), ast[0]);
+    }
+
+    #[test]
+    fn test_reader_limits() {
+        let source = "(f \"a long string\")";
+        let limited = Reader::new().with_limits(LexerLimits::unlimited().with_max_string_size(4));
+        assert_eq!(limited.read(source).unwrap_err().message(), "String longer than the limit of 4 bytes at 1:4 (bytes 3..18)");
+        assert!(limited.read_with_spans("(f \"abc\")").is_ok());
+        assert_eq!(Reader::new().read(source).unwrap().len(), 1);
+        assert!(tokenize(source).is_ok());
+    }
+}
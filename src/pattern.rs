@@ -0,0 +1,227 @@
+//! Destructuring patterns, shared by macro lambda lists, `destructuring-bind`, and `let`.
+//!
+//! A pattern is a variable, or a list of patterns that may end in `&rest name`:
+//! `(a (b c) &rest r)` matches a list of at least two elements whose second is a
+//! two-element list, binding `r` to the elements after the second.
+
+use crate::ast::LispExpr;
+use crate::symbol::Symbol;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Variable(Symbol),
+    List { elements: Vec<Pattern>, rest: Option<Symbol> },
+}
+
+/// A malformed pattern: the offending part, and what is wrong with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    pub pattern: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid pattern '{}': {}", self.pattern, self.reason)
+    }
+}
+
+/// A value that does not have the shape of the pattern it is matched against
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchError {
+    /// A list pattern matched against a list of the wrong length
+    Arity { expected: usize, rest: bool, actual: usize },
+    /// A list pattern matched against something that is not a list
+    NotAList(String),
+}
+
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchError::Arity { expected, rest, actual } => {
+                let at_least = if *rest { "at least " } else { "" };
+                write!(f, "expected {}{} element(s), got {}", at_least, expected, actual)
+            }
+            MatchError::NotAList(value) => write!(f, "expected a list, got {}", value),
+        }
+    }
+}
+
+/// Values a pattern can take apart: source expressions for macros, runtime values for the interpreter
+pub trait Destructure: Clone + fmt::Display {
+    fn elements(&self) -> Option<&[Self]>;
+    fn from_elements(elements: Vec<Self>) -> Self;
+}
+
+impl Destructure for LispExpr {
+    fn elements(&self) -> Option<&[Self]> {
+        self.as_list()
+    }
+
+    fn from_elements(elements: Vec<Self>) -> Self {
+        LispExpr::List(elements.into())
+    }
+}
+
+impl Pattern {
+    pub fn parse(expr: &LispExpr) -> Result<Pattern, PatternError> {
+        match expr {
+            LispExpr::Symbol(s) if s == "&rest" => Err(PatternError {
+                pattern: expr.to_string(),
+                reason: "&rest can only appear in a list pattern".to_string(),
+            }),
+            LispExpr::Symbol(s) | LispExpr::Gensym(s) => Ok(Pattern::Variable(s.clone())),
+            LispExpr::List(elements) => Self::parse_list(elements.iter().map(|element| match element {
+                LispExpr::Symbol(s) if s == "&rest" => Err(element),
+                other => Ok(other),
+            })),
+            other => Err(PatternError { pattern: other.to_string(), reason: "Patterns are symbols or lists of patterns".to_string() }),
+        }
+    }
+
+    /// A macro's lambda list, stored as the names it was written with
+    pub fn from_parameters(parameters: &[impl AsRef<str>]) -> Result<Pattern, PatternError> {
+        let parameters: Vec<LispExpr> = parameters.iter().map(|p| LispExpr::Symbol(p.as_ref().into())).collect();
+        Self::parse_list(parameters.iter().map(|p| if p.as_symbol() == Some("&rest") { Err(p) } else { Ok(p) }))
+    }
+
+    /// Elements are `Ok(pattern)`, or `Err(marker)` for `&rest`
+    fn parse_list<'a>(mut elements: impl Iterator<Item = Result<&'a LispExpr, &'a LispExpr>>) -> Result<Pattern, PatternError> {
+        let mut patterns = Vec::new();
+        while let Some(element) = elements.next() {
+            match element {
+                Ok(element) => patterns.push(Self::parse(element)?),
+                Err(marker) => {
+                    let rest = match elements.next() {
+                        Some(Ok(LispExpr::Symbol(name) | LispExpr::Gensym(name))) => name.clone(),
+                        _ => {
+                            return Err(PatternError {
+                                pattern: marker.to_string(),
+                                reason: "&rest must be followed by a parameter name".to_string(),
+                            });
+                        }
+                    };
+                    if elements.next().is_some() {
+                        return Err(PatternError {
+                            pattern: format!("&rest {}", rest),
+                            reason: "Parameters cannot appear after &rest parameter".to_string(),
+                        });
+                    }
+                    return Ok(Pattern::List { elements: patterns, rest: Some(rest) });
+                }
+            }
+        }
+        Ok(Pattern::List { elements: patterns, rest: None })
+    }
+
+    /// The variables the pattern binds, left to right
+    pub fn variables(&self) -> Vec<Symbol> {
+        let mut variables = Vec::new();
+        self.collect_variables(&mut variables);
+        variables
+    }
+
+    fn collect_variables(&self, variables: &mut Vec<Symbol>) {
+        match self {
+            Pattern::Variable(name) => variables.push(name.clone()),
+            Pattern::List { elements, rest } => {
+                for element in elements {
+                    element.collect_variables(variables);
+                }
+                variables.extend(rest.clone());
+            }
+        }
+    }
+
+    /// Bind the pattern's variables to the parts of `value`, in the order of `variables`
+    pub fn bind<T: Destructure>(&self, value: &T, bindings: &mut Vec<(Symbol, T)>) -> Result<(), MatchError> {
+        match self {
+            Pattern::Variable(name) => {
+                bindings.push((name.clone(), value.clone()));
+                Ok(())
+            }
+            Pattern::List { .. } => match value.elements() {
+                Some(values) => self.bind_elements(values, bindings),
+                None => Err(MatchError::NotAList(value.to_string())),
+            },
+        }
+    }
+
+    /// Bind a list pattern to the elements of a list, such as a macro call's arguments
+    pub fn bind_elements<T: Destructure>(&self, values: &[T], bindings: &mut Vec<(Symbol, T)>) -> Result<(), MatchError> {
+        let Pattern::List { elements, rest } = self else {
+            bindings.push((self.variables().swap_remove(0), T::from_elements(values.to_vec())));
+            return Ok(());
+        };
+        let arity_ok = match rest {
+            Some(_) => values.len() >= elements.len(),
+            None => values.len() == elements.len(),
+        };
+        if !arity_ok {
+            return Err(MatchError::Arity { expected: elements.len(), rest: rest.is_some(), actual: values.len() });
+        }
+        for (pattern, value) in elements.iter().zip(values) {
+            pattern.bind(value, bindings)?;
+        }
+        if let Some(rest) = rest {
+            bindings.push((rest.clone(), T::from_elements(values[elements.len()..].to_vec())));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Variable(name) => write!(f, "{}", name),
+            Pattern::List { elements, rest } => {
+                let mut parts: Vec<String> = elements.iter().map(Pattern::to_string).collect();
+                if let Some(rest) = rest {
+                    parts.push(format!("&rest {}", rest));
+                }
+                write!(f, "({})", parts.join(" "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn expr(source: &str) -> LispExpr {
+        parse(tokenize(source).unwrap()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_parse_and_bind() {
+        let pattern = Pattern::parse(&expr("(a (b c) &rest r)")).unwrap();
+        assert_eq!(pattern.to_string(), "(a (b c) &rest r)");
+        assert_eq!(pattern.variables(), vec![Symbol::from("a"), "b".into(), "c".into(), "r".into()]);
+
+        let mut bindings = Vec::new();
+        pattern.bind(&expr("(1 (2 3) 4 5)"), &mut bindings).unwrap();
+        let bound: Vec<String> = bindings.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        assert_eq!(bound, vec!["a=1", "b=2", "c=3", "r=(4 5)"]);
+
+        let mut bindings = Vec::new();
+        assert_eq!(
+            pattern.bind(&expr("(1 (2 3 4))"), &mut bindings),
+            Err(MatchError::Arity { expected: 2, rest: false, actual: 3 })
+        );
+        assert_eq!(pattern.bind(&expr("(1 2)"), &mut bindings), Err(MatchError::NotAList("2".to_string())));
+        assert_eq!(MatchError::Arity { expected: 2, rest: true, actual: 1 }.to_string(), "expected at least 2 element(s), got 1");
+    }
+
+    #[test]
+    fn test_malformed_patterns() {
+        assert_eq!(Pattern::parse(&expr("(a &rest)")).unwrap_err().reason, "&rest must be followed by a parameter name");
+        assert_eq!(Pattern::parse(&expr("(a &rest r s)")).unwrap_err().pattern, "&rest r");
+        assert_eq!(Pattern::parse(&expr("(a 1)")).unwrap_err().to_string(), "Invalid pattern '1': Patterns are symbols or lists of patterns");
+        let parameters = vec!["a".to_string(), "&rest".to_string(), "body".to_string()];
+        assert_eq!(Pattern::from_parameters(&parameters).unwrap().to_string(), "(a &rest body)");
+    }
+}
@@ -0,0 +1,828 @@
+//! The Lisp-to-Rust pipeline as a library call.
+//!
+//! Source goes through the lexer, the parser, the registered transforms, the
+//! safety validators (when enabled, before and after expansion), the macro expander, and code generation by a
+//! `Backend`: Rust by default, or any of `backend::BACKENDS`, all from the same expanded program.
+//! Observers registered with `Pipeline::observe` see the result of each stage and
+//! can stop the pipeline by returning an error, which `Pipeline::compile` returns
+//! as is. A `CompileBudget` stops it when compiling takes too long or the program
+//! grows too large, naming the stage that went over.
+
+use crate::ast::{walk_expr, LispExpr, SpanTree, Visitor};
+use crate::backend::Backend;
+use crate::compiler::RustBackend;
+use crate::explain::CompileError;
+use crate::include::Source;
+use crate::library::Library;
+use crate::lexer::Token;
+use crate::sandbox::{SandboxConfig, SandboxMonitor};
+use crate::source_map::LocationTree;
+use crate::transform::TransformRegistry;
+use crate::validator::{CompositeValidator, MemoryEstimateValidator, Severity, ValidationError};
+use crate::parser::{Reader, SpannedForms};
+use crate::{compiler, contracts, ir, logging, macro_expander};
+use macro_expander::MacroError;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Hooks called as each stage finishes. Every method defaults to accepting the
+/// stage; return an error to veto it.
+pub trait PipelineObserver {
+    fn on_tokens(&mut self, _tokens: &[Token]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_parsed(&mut self, _program: &[LispExpr]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_transformed(&mut self, _program: &[LispExpr]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called only when safety validation is enabled and the program passed it: the
+    /// transformed program before expansion, then the expanded one, as the
+    /// `ValidationStage` selects
+    fn on_validated(&mut self, _program: &[LispExpr]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The program after macro expansion, without the macro definitions
+    fn on_expanded(&mut self, _program: &[LispExpr]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The code the backend generated
+    fn on_codegen(&mut self, _code: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Lets a caller keep ownership of its observer and read it after compiling
+impl<T: PipelineObserver + ?Sized> PipelineObserver for &mut T {
+    fn on_tokens(&mut self, tokens: &[Token]) -> Result<(), String> {
+        (**self).on_tokens(tokens)
+    }
+
+    fn on_parsed(&mut self, program: &[LispExpr]) -> Result<(), String> {
+        (**self).on_parsed(program)
+    }
+
+    fn on_transformed(&mut self, program: &[LispExpr]) -> Result<(), String> {
+        (**self).on_transformed(program)
+    }
+
+    fn on_validated(&mut self, program: &[LispExpr]) -> Result<(), String> {
+        (**self).on_validated(program)
+    }
+
+    fn on_expanded(&mut self, program: &[LispExpr]) -> Result<(), String> {
+        (**self).on_expanded(program)
+    }
+
+    fn on_codegen(&mut self, code: &str) -> Result<(), String> {
+        (**self).on_codegen(code)
+    }
+}
+
+/// Which programs `Pipeline::with_validation` runs the safety validators on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValidationStage {
+    /// The transformed program, before macro expansion
+    Pre,
+    /// The expanded program, including the code macros generate
+    Post,
+    #[default]
+    Both,
+}
+
+impl ValidationStage {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "pre" => Ok(ValidationStage::Pre),
+            "post" => Ok(ValidationStage::Post),
+            "both" => Ok(ValidationStage::Both),
+            other => Err(format!("Unknown validation stage '{}' (expected pre, post, or both)", other)),
+        }
+    }
+
+    pub fn pre(self) -> bool {
+        self != ValidationStage::Post
+    }
+
+    pub fn post(self) -> bool {
+        self != ValidationStage::Pre
+    }
+}
+
+/// Limits on the compiler's own work (`--timeout` and `--max-memory` when building).
+/// Time is checked after each form a stage processes and before each macro call is
+/// expanded; memory is the `LispExpr::footprint` of the program the stage has built
+/// so far. Unset limits are not checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompileBudget {
+    /// Wall-clock time for the whole compilation
+    pub time: Option<Duration>,
+    /// Estimated bytes the program may take at any stage
+    pub memory: Option<usize>,
+}
+
+impl CompileBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn with_memory(mut self, bytes: usize) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+}
+
+/// A `CompileBudget` being spent by one compilation
+struct Meter {
+    budget: CompileBudget,
+    start: Instant,
+    /// The footprint of the current stage's program so far
+    memory: usize,
+}
+
+impl Meter {
+    fn new(budget: CompileBudget) -> Self {
+        Meter { budget, start: Instant::now(), memory: 0 }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.budget.time.map(|time| self.start + time)
+    }
+
+    /// Count `program` as the whole of `stage`'s output, then check the budget
+    fn measure(&mut self, stage: &str, program: &[LispExpr]) -> Result<(), CompileError> {
+        self.memory = 0;
+        program.iter().try_for_each(|expr| self.add(stage, expr))
+    }
+
+    /// Count one more form of `stage`'s output, then check the budget
+    fn add(&mut self, stage: &str, expr: &LispExpr) -> Result<(), CompileError> {
+        if self.budget.memory.is_some() {
+            self.memory += expr.footprint();
+        }
+        self.check(stage)
+    }
+
+    fn check(&self, stage: &str) -> Result<(), CompileError> {
+        if self.budget.memory.is_some_and(|limit| self.memory > limit) {
+            return Err(self.over_memory(stage, &format!("the program is estimated at {} bytes", self.memory)));
+        }
+        if self.deadline().is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(self.over_time(stage));
+        }
+        Ok(())
+    }
+
+    fn over_memory(&self, stage: &str, estimate: &str) -> CompileError {
+        let limit = self.budget.memory.unwrap_or_default();
+        CompileError::new("L0507", format!("Compilation exceeded the memory limit of {} bytes during {} ({})", limit, stage, estimate))
+    }
+
+    fn over_time(&self, stage: &str) -> CompileError {
+        let limit = self.budget.time.unwrap_or_default();
+        CompileError::new("L0506", format!("Compilation exceeded the time limit of {:?} during {} ({:.2?} elapsed)", limit, stage, self.start.elapsed()))
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline<'a> {
+    reader: Reader,
+    registry: TransformRegistry,
+    backend: Option<&'a dyn Backend>,
+    validate_safety: bool,
+    validation_stage: ValidationStage,
+    sandbox: Option<&'a SandboxConfig>,
+    seed: Option<u64>,
+    deterministic: bool,
+    skip_contracts: bool,
+    budget: CompileBudget,
+    /// The source being compiled, when generated code is marked with source locations
+    source_map: Option<&'a Source>,
+    libraries: Vec<&'a Library>,
+    observers: Vec<Box<dyn PipelineObserver + 'a>>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the source with `reader`'s features
+    pub fn with_reader(mut self, reader: Reader) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Transforms to apply between parsing and macro expansion
+    pub fn with_transforms(mut self, registry: TransformRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Run every safety validator on the transformed program before expanding it,
+    /// and on the expanded program after
+    pub fn with_validation(mut self, validate_safety: bool) -> Self {
+        self.validate_safety = validate_safety;
+        self
+    }
+
+    /// Validate only before or only after macro expansion
+    pub fn with_validation_stage(mut self, stage: ValidationStage) -> Self {
+        self.validation_stage = stage;
+        self
+    }
+
+    /// Generate code with `backend` instead of Rust
+    pub fn with_backend(mut self, backend: &'a dyn Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Generate Rust that enforces `config` at run time, and expand macros under it
+    pub fn with_sandbox(mut self, config: &'a SandboxConfig) -> Self {
+        self.sandbox = Some(config);
+        self
+    }
+
+    /// Generate Rust whose `random` and `random-int` start from `seed`
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Renumber gensyms per form before code generation (see `ir::canonicalize_program`)
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Generate code that checks each `defun`'s contract (the default), or leave the checks out
+    pub fn with_contracts(mut self, enabled: bool) -> Self {
+        self.skip_contracts = !enabled;
+        self
+    }
+
+    /// Stop compiling with an error when it goes over `budget`
+    pub fn with_budget(mut self, budget: CompileBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Start the code generated from each top-level form with a `// lisp:` comment
+    /// naming where the form is in `source`, whose text is then what `compile` is
+    /// given, or in the file `source` included it from; see `source_map`
+    pub fn with_source_map(mut self, source: &'a Source) -> Self {
+        self.source_map = Some(source);
+        self
+    }
+
+    /// Compile as if the source of `library` came before the program: its macros are
+    /// defined before expanding, and its forms are compiled ahead of the program's
+    pub fn with_library(mut self, library: &'a Library) -> Self {
+        self.libraries.push(library);
+        self
+    }
+
+    /// Call `observer` after each stage, after the observers added before it
+    pub fn observe(mut self, observer: impl PipelineObserver + 'a) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Compile `source` to Rust, or with the backend chosen by `with_backend`
+    pub fn compile(&mut self, source: &str) -> Result<String, CompileError> {
+        self.check_backend()?;
+        let meter = Meter::new(self.budget);
+        let spanned = self.reader.tokenize(source)?;
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| token.clone()).collect();
+        self.notify(|observer| observer.on_tokens(&tokens))?;
+
+        // With a source map each form's spans are followed through the rewrites below
+        let start = Instant::now();
+        let (ast, trees) = match self.source_map {
+            Some(_) => {
+                let (ast, trees): (Vec<LispExpr>, Vec<SpanTree>) = self.reader.parse_with_span_trees(spanned)?.into_iter().unzip();
+                (ast, Some(trees))
+            }
+            None => (self.reader.parse(tokens)?, None),
+        };
+        log_stage("parser", start, format_args!("{} forms", ast.len()), &[("forms", ast.len().into())]);
+        self.compile_parsed(ast, trees, meter)
+    }
+
+    /// Compile forms already read with their span trees, such as ones the caller
+    /// has also linted, as `compile` does once it has parsed them. Observers are
+    /// not given the tokens.
+    pub fn compile_read(&mut self, forms: SpannedForms) -> Result<String, CompileError> {
+        self.check_backend()?;
+        let meter = Meter::new(self.budget);
+        let (ast, trees): (Vec<LispExpr>, Vec<SpanTree>) = forms.into_iter().unzip();
+        let trees = self.source_map.is_some().then_some(trees);
+        self.compile_parsed(ast, trees, meter)
+    }
+
+    /// Transform, validate, expand, and generate code for the parsed `ast`
+    fn compile_parsed(&mut self, ast: Vec<LispExpr>, mut trees: Option<Vec<SpanTree>>, mut meter: Meter) -> Result<String, CompileError> {
+        meter.measure("parsing", &ast)?;
+        self.notify(|observer| observer.on_parsed(&ast))?;
+
+        // Apply AST transformations (between parsing and macro expansion)
+        let start = Instant::now();
+        let mut transformed_ast = Vec::new();
+        meter.memory = 0;
+        for (index, mut expr) in ast.into_iter().enumerate() {
+            let original = trees.is_some().then(|| expr.clone());
+            self.registry.apply_all(&mut expr)?;
+            if let (Some(trees), Some(original)) = (&mut trees, original) {
+                trees[index] = trees[index].follow(&original, &expr, &HashSet::new());
+            }
+            meter.add("transforms", &expr)?;
+            transformed_ast.push(expr);
+        }
+        log_stage(
+            "transform",
+            start,
+            format_args!("{} transform(s) applied to {} forms", self.registry.count(), transformed_ast.len()),
+            &[("transforms", self.registry.count().into())],
+        );
+        self.notify(|observer| observer.on_transformed(&transformed_ast))?;
+
+        self.compile_transformed(transformed_ast, trees, meter)
+    }
+
+    /// Compile a program that is already parsed and transformed, such as one read from
+    /// IR: it is validated, expanded, and generated as `compile` does after transforms
+    pub fn compile_ast(&mut self, program: Vec<LispExpr>) -> Result<String, CompileError> {
+        self.check_backend()?;
+        let mut meter = Meter::new(self.budget);
+        meter.measure("reading IR", &program)?;
+        self.compile_transformed(program, None, meter)
+    }
+
+    /// Validate, expand, and generate code for `transformed_ast`, following each form's
+    /// spans in `trees` when generating a source map
+    fn compile_transformed(
+        &mut self,
+        transformed_ast: Vec<LispExpr>,
+        trees: Option<Vec<SpanTree>>,
+        mut meter: Meter,
+    ) -> Result<String, CompileError> {
+        let backend = self.backend.unwrap_or(&RustBackend);
+        // Validate AST if safety checks are enabled (pre-macro expansion)
+        if self.validate_safety && self.validation_stage.pre() {
+            validate_ast(&transformed_ast)?;
+            meter.check("validation")?;
+            self.notify(|observer| observer.on_validated(&transformed_ast))?;
+        }
+
+        // Expand macros in the transformed AST
+        let start = Instant::now();
+        let mut expander = macro_expander::MacroExpander::new();
+        if let Some(deadline) = meter.deadline() {
+            expander = expander.with_deadline(deadline);
+        }
+        if let Some(bytes) = self.budget.memory {
+            expander = expander.with_allocation_limit(bytes);
+        }
+        // Macros run at compile time under the same sandbox as the program
+        if let Some(config) = self.sandbox {
+            expander = expander.with_sandbox(SandboxMonitor::new(config.clone()));
+        }
+        let mut expanded_ast = Vec::new();
+        let mut macros = HashSet::new();
+        let mut locations = Vec::new();
+        let post_validation = self.validate_safety && self.validation_stage.post();
+        // The macros called in each expanded form, to name in post-expansion errors
+        let mut origins = Vec::new();
+        for library in &self.libraries {
+            library.define_macros(&mut expander)?;
+            macros.extend(library.macro_names().map(str::to_string));
+        }
+        macros.extend(expander.macro_names());
+
+        meter.memory = 0;
+        for (index, expr) in transformed_ast.into_iter().enumerate() {
+            if let LispExpr::Macro { name, .. } = &expr {
+                macros.insert(name.to_string());
+            }
+            let origin = post_validation.then(|| macro_calls(&expr, &macros));
+            let original = trees.is_some().then(|| expr.clone());
+            let expanded = expander.expand_all(expr).map_err(|e| match e {
+                MacroError::DeadlineExceeded { macro_name } => meter.over_time(&format!("macro expansion of '{}'", macro_name)),
+                MacroError::AllocationLimitExceeded { macro_name, allocated } => meter.over_memory(
+                    &format!("macro expansion of '{}'", macro_name),
+                    &format!("expansions are estimated at {} bytes", allocated),
+                ),
+                e => e.into(),
+            })?;
+            // Compiler macros rewrite function calls once the form's macros are expanded
+            let expanded = expander.apply_compiler_macros(expanded)?;
+            macros.extend(expander.compiler_macro_names());
+            meter.add("macro expansion", &expanded)?;
+
+            // Skip Nil expressions (from macro definitions)
+            if !matches!(expanded, LispExpr::Nil) {
+                if let (Some(trees), Some(original), Some(source)) = (&trees, original, self.source_map) {
+                    locations.push(LocationTree::new(source, &trees[index].follow(&original, &expanded, &macros)));
+                }
+                origins.extend(origin);
+                expanded_ast.push(expanded);
+            }
+        }
+        let expansions = expander.expansion_count();
+        log_stage("expand", start, format_args!("{} macro call(s) expanded", expansions), &[("expansions", expansions.into())]);
+
+        // Validate the expanded program, which includes the code macros generated
+        if post_validation {
+            validate_expanded(&expanded_ast, &origins)?;
+            meter.check("validation")?;
+            self.notify(|observer| observer.on_validated(&expanded_ast))?;
+        }
+        let library_forms: Vec<LispExpr> = self.libraries.iter().flat_map(|library| library.forms.iter().cloned()).collect();
+        if self.source_map.is_some() {
+            locations.splice(0..0, std::iter::repeat_with(LocationTree::default).take(library_forms.len()));
+        }
+        expanded_ast.splice(0..0, library_forms);
+        if self.skip_contracts {
+            expanded_ast = expanded_ast.iter().map(contracts::strip).collect();
+        }
+        if self.deterministic {
+            ir::canonicalize_program(&mut expanded_ast);
+        }
+        self.notify(|observer| observer.on_expanded(&expanded_ast))?;
+
+        let start = Instant::now();
+        let code = match self.source_map {
+            Some(_) => backend.compile_program_mapped(&expanded_ast, self.sandbox, self.seed, &locations)?,
+            None => backend.compile_program(&expanded_ast, self.sandbox, self.seed)?,
+        };
+        meter.check("code generation")?;
+        log_stage(
+            "codegen",
+            start,
+            format_args!("{} bytes from the {} backend", code.len(), backend.name()),
+            &[("backend", backend.name().into()), ("bytes", code.len().into())],
+        );
+        self.notify(|observer| observer.on_codegen(&code))?;
+        Ok(code)
+    }
+
+    fn check_backend(&self) -> Result<(), String> {
+        let backend = self.backend.unwrap_or(&RustBackend);
+        if !backend.supports_sandbox() && (self.sandbox.is_some() || self.seed.is_some()) {
+            return Err(format!("The {} backend cannot enforce a sandbox or seed random numbers", backend.name()));
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, mut hook: impl FnMut(&mut dyn PipelineObserver) -> Result<(), String>) -> Result<(), String> {
+        self.observers.iter_mut().try_for_each(|observer| hook(observer.as_mut()))
+    }
+}
+
+/// Debug-log a finished pipeline stage with how long it took since `start`
+fn log_stage(stage: &str, start: Instant, summary: std::fmt::Arguments, fields: &[(&str, serde_json::Value)]) {
+    let elapsed = start.elapsed();
+    let mut fields = fields.to_vec();
+    fields.push(("elapsed_us", logging::micros(elapsed)));
+    logging::debug(stage, format_args!("{} in {:.2?}", summary, elapsed), &fields);
+}
+
+/// Generates Rust, embedding sandbox enforcement when a sandbox config is given and
+/// seeding `random` when a seed is. Sandboxed programs whose literal data cannot fit
+/// in the memory limit are rejected here.
+pub fn compile_rust(ast: &[LispExpr], sandbox: Option<&SandboxConfig>, seed: Option<u64>) -> Result<String, CompileError> {
+    compile_rust_mapped(ast, sandbox, seed, &[])
+}
+
+/// `compile_rust`, marking the code from each expression with its entry of `locations`
+pub fn compile_rust_mapped(
+    ast: &[LispExpr],
+    sandbox: Option<&SandboxConfig>,
+    seed: Option<u64>,
+    locations: &[LocationTree],
+) -> Result<String, CompileError> {
+    if let Some(config) = sandbox {
+        let memory = MemoryEstimateValidator::new().with_max_memory(config.max_memory);
+        check_validation_results(CompositeValidator::new().add_validator(Box::new(memory)).validate_program(ast))?;
+    }
+    compiler::compile_to_rust_mapped(ast, sandbox, seed, locations)
+}
+
+/// Validates AST expressions using all available validators
+pub fn validate_ast(ast: &[LispExpr]) -> Result<(), CompileError> {
+    check_validation_results(CompositeValidator::with_all_validators().validate_program(ast))
+}
+
+/// Validates a macro-expanded program; `origins` holds the macros called in each
+/// expression before expansion, which errors in that expression name
+pub fn validate_expanded(ast: &[LispExpr], origins: &[Vec<String>]) -> Result<(), CompileError> {
+    let results = CompositeValidator::with_all_validators().validate_program(ast);
+    let mut names: Vec<&str> = Vec::new();
+    for (index, result) in results.iter().enumerate() {
+        if result.is_err() {
+            for name in origins.get(index).into_iter().flatten() {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+    }
+    let origin = match names.as_slice() {
+        [] => String::new(),
+        [name] => format!(" in code expanded from macro '{}'", name),
+        names => format!(" in code expanded from macros '{}'", names.join("', '")),
+    };
+    failure_of(results, |counts| format!("Validation after macro expansion failed with {}{}", counts, origin))
+}
+
+/// Fails with every error of every top-level expression
+pub fn check_validation_results(results: Vec<Result<(), Vec<ValidationError>>>) -> Result<(), CompileError> {
+    failure_of(results, |counts| format!("Validation failed with {}", counts))
+}
+
+/// Fails when any of `results` is an error, with the heading `heading` makes from
+/// the counts of all the findings, followed by each finding on its own line, and the
+/// code of the first. The counts are those a validation report of the same results shows.
+fn failure_of(results: Vec<Result<(), Vec<ValidationError>>>, heading: impl FnOnce(String) -> String) -> Result<(), CompileError> {
+    let errors: Vec<ValidationError> = results.into_iter().filter_map(Result::err).flatten().collect();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let warnings = errors.iter().filter(|error| error.severity == Severity::Warning).count();
+    let counts = match warnings {
+        0 => format!("{} error(s)", errors.len()),
+        warnings => format!("{} error(s) and {} warning(s)", errors.len() - warnings, warnings),
+    };
+    Err(CompileError::new(errors[0].rule.code(), validation_failure(&heading(counts), &errors)))
+}
+
+/// `heading`, then each of `errors` on its own line
+fn validation_failure(heading: &str, errors: &[ValidationError]) -> String {
+    // Format all validation errors into a single error message
+    let error_messages: Vec<String> = errors
+        .iter()
+        .map(|e| format!("  - {}", e))
+        .collect();
+    format!("{}:\n{}", heading, error_messages.join("\n"))
+}
+
+/// Expand each form of `program` the way `compile` does, macros and then compiler
+/// macros, pairing the result with the index of the form. Forms that only define
+/// macros are left out. Expansion goes on after a failure, since later forms may
+/// still use the macros defined before it.
+pub fn expand_forms(expander: &mut macro_expander::MacroExpander, program: Vec<LispExpr>) -> Vec<(usize, Result<LispExpr, MacroError>)> {
+    program
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, expr)| match expander.expand_all(expr).and_then(|expanded| expander.apply_compiler_macros(expanded)) {
+            Ok(LispExpr::Nil) => None,
+            result => Some((index, result)),
+        })
+        .collect()
+}
+
+/// Validate the forms `expand_forms` expanded without error as one program, pairing
+/// each result with the index of the form it was expanded from
+pub fn validate_expanded_forms(expanded: &[(usize, Result<LispExpr, MacroError>)]) -> Vec<(usize, Result<(), Vec<ValidationError>>)> {
+    let (indices, forms): (Vec<usize>, Vec<LispExpr>) =
+        expanded.iter().filter_map(|(index, result)| Some((*index, result.as_ref().ok()?.clone()))).unzip();
+    indices.into_iter().zip(CompositeValidator::with_all_validators().validate_program(&forms)).collect()
+}
+
+/// The names of the macros `expr` calls, once each in the order they first appear
+fn macro_calls(expr: &LispExpr, macros: &HashSet<String>) -> Vec<String> {
+    struct Calls<'m> {
+        macros: &'m HashSet<String>,
+        names: Vec<String>,
+    }
+
+    impl Visitor<'_> for Calls<'_> {
+        fn visit_expr(&mut self, expr: &LispExpr) {
+            let name = match expr {
+                LispExpr::List(elements) => elements.first().and_then(LispExpr::as_symbol).filter(|name| self.macros.contains(*name)),
+                LispExpr::MacroCall { name, .. } => Some(name.as_str()),
+                _ => None,
+            };
+            if let Some(name) = name.filter(|name| !self.names.iter().any(|known| known == name)) {
+                self.names.push(name.to_string());
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    let mut calls = Calls { macros, names: Vec::new() };
+    calls.visit_expr(expr);
+    calls.names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::backend;
+    use crate::c::CBackend;
+    use crate::wat::WatBackend;
+
+    #[derive(Default)]
+    struct Recorder {
+        stages: Vec<String>,
+    }
+
+    impl PipelineObserver for Recorder {
+        fn on_tokens(&mut self, tokens: &[Token]) -> Result<(), String> {
+            self.stages.push(format!("tokens {}", tokens.len()));
+            Ok(())
+        }
+
+        fn on_parsed(&mut self, program: &[LispExpr]) -> Result<(), String> {
+            self.stages.push(format!("parsed {}", program.len()));
+            Ok(())
+        }
+
+        fn on_validated(&mut self, program: &[LispExpr]) -> Result<(), String> {
+            self.stages.push(format!("validated {}", program.len()));
+            Ok(())
+        }
+
+        fn on_expanded(&mut self, program: &[LispExpr]) -> Result<(), String> {
+            self.stages.push(format!("expanded {}", program.len()));
+            Ok(())
+        }
+
+        fn on_codegen(&mut self, rust_code: &str) -> Result<(), String> {
+            self.stages.push(format!("codegen {}", rust_code.contains("fn main")));
+            Ok(())
+        }
+    }
+
+    struct Veto;
+
+    impl PipelineObserver for Veto {
+        fn on_expanded(&mut self, program: &[LispExpr]) -> Result<(), String> {
+            match program.iter().any(|expr| expr.to_string().contains("run-command")) {
+                true => Err("run-command is not allowed here".to_string()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_observers_see_each_stage() {
+        let source = "(defmacro twice (x) `(+ ,x ,x)) (* (twice 2) 3)";
+        let mut recorder = Recorder::default();
+        let rust_code = Pipeline::new().with_validation(true).observe(&mut recorder).compile(source).unwrap();
+        assert!(rust_code.contains("fn main"));
+        assert_eq!(recorder.stages, vec!["tokens 23", "parsed 2", "validated 2", "validated 1", "expanded 1", "codegen true"]);
+    }
+
+    #[test]
+    fn test_validation_stages() {
+        let source = "(defmacro call-op (op x) `(,op ,x)) (call-op ffi-call 1)";
+        let error = Pipeline::new().with_validation(true).compile(source).unwrap_err();
+        assert!(error.message().starts_with("Validation after macro expansion failed with 1 error(s) in code expanded from macro 'call-op':\n"), "{}", error);
+        assert!(error.message().contains("unsafe operation 'ffi-call' is not allowed"), "{}", error);
+        let error = Pipeline::new().with_validation(true).with_validation_stage(ValidationStage::Pre).compile(source).unwrap_err();
+        assert!(!error.message().contains("Validation"), "{}", error);
+
+        let mut recorder = Recorder::default();
+        let pipeline = Pipeline::new().with_validation(true).with_validation_stage(ValidationStage::Post);
+        pipeline.observe(&mut recorder).compile("(defmacro twice (x) `(+ ,x ,x)) (twice 2)").unwrap();
+        assert_eq!(recorder.stages, vec!["tokens 19", "parsed 2", "validated 1", "expanded 1", "codegen true"]);
+        assert_eq!(ValidationStage::parse("post"), Ok(ValidationStage::Post));
+        assert!(ValidationStage::parse("late").is_err());
+    }
+
+    #[test]
+    fn test_observer_vetoes_stage() {
+        let mut recorder = Recorder::default();
+        let mut pipeline = Pipeline::new().observe(Veto).observe(&mut recorder);
+        assert_eq!(pipeline.compile("(run-command \"ls\")").unwrap_err().message(), "run-command is not allowed here");
+        assert!(pipeline.compile("(+ 1 2)").is_ok());
+        drop(pipeline);
+        assert_eq!(recorder.stages, vec!["tokens 4", "parsed 1", "tokens 5", "parsed 1", "expanded 1", "codegen true"]);
+    }
+
+    #[test]
+    fn test_backends() {
+        let source = "(defmacro twice (x) `(+ ,x ,x)) (* (twice 2) 3)";
+        let mut recorder = Recorder::default();
+        let wat = Pipeline::new().with_backend(backend("wat").unwrap()).observe(&mut recorder).compile(source).unwrap();
+        assert!(wat.contains("(call $print_number (f64.mul (f64.add (f64.const 2) (f64.const 2)) (f64.const 3)))"));
+        assert_eq!(recorder.stages.last().unwrap(), "codegen false");
+        let c = Pipeline::new().with_backend(&CBackend).compile(source).unwrap();
+        assert!(c.contains("print_number(((2.0 + 2.0) * 3.0));"));
+
+        let config = SandboxConfig::new();
+        let error = Pipeline::new().with_backend(&WatBackend).with_sandbox(&config).compile(source).unwrap_err();
+        assert_eq!(error.message(), "The wat backend cannot enforce a sandbox or seed random numbers");
+    }
+
+    #[test]
+    fn test_library() {
+        let library = Library::compile(Reader::new().read("(defmacro twice (x) `(+ ,x ,x))\n(defun four () (twice 2))").unwrap(), &[]).unwrap();
+        let rust_code = Pipeline::new().with_library(&library).compile("(twice (four))").unwrap();
+        assert!(rust_code.find("fn four()") < rust_code.find("fn main()"), "{}", rust_code);
+        assert!(rust_code.contains("    (2.0 + 2.0)\n"), "{}", rust_code);
+        assert!(rust_code.contains("println!(\"{:?}\", (four() + four()));"), "{}", rust_code);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let macros = "(defmacro twice (x) `(let ((v ,x)) (+ v v)))\n(defmacro square (x) `(let ((v ,x)) (* v v)))\n";
+        let alone = format!("{}(square 3)", macros);
+        let after = format!("{}(twice 1)\n(square 3)", macros);
+        assert!(Pipeline::new().compile(&after).unwrap().contains("let __g_v_2 = 3; (__g_v_2 * __g_v_2)"));
+        // Each form's gensyms are numbered from 1, whatever was expanded before it
+        let square = "let __g_v_1 = 3; (__g_v_1 * __g_v_1)";
+        assert!(Pipeline::new().with_deterministic(true).compile(&alone).unwrap().contains(square));
+        assert!(Pipeline::new().with_deterministic(true).compile(&after).unwrap().contains(square));
+    }
+
+    #[test]
+    fn test_contracts() {
+        let source = "(defun half (n) (declare (requires (> n 0)) (ensures (< result n))) (/ n 2))\n(half 4)";
+        let checked = Pipeline::new().compile(source).unwrap();
+        assert!(checked.contains("assert!((n > 0.0), \"{}\", \"Precondition of 'half' failed: (> n 0)\");"), "{}", checked);
+        assert!(checked.contains("let result = (n / 2.0);\n    assert!((result < n), \"{}\", \"Postcondition of 'half' failed: (< result n)\");"), "{}", checked);
+        let unchecked = Pipeline::new().with_contracts(false).compile(source).unwrap();
+        assert!(!unchecked.contains("assert!"), "{}", unchecked);
+        let c = Pipeline::new().with_backend(&CBackend).with_contracts(false).compile(source).unwrap();
+        assert!(!c.contains("Precondition"), "{}", c);
+    }
+
+    #[test]
+    fn test_budget() {
+        let nested = (0..12).fold("1".to_string(), |inner, _| format!("(dup {})", inner));
+        let source = format!("(defmacro dup (x) `(list ,x ,x))\n{}", nested);
+        let budget = CompileBudget::new().with_memory(64 * 1024).with_time(Duration::from_secs(60));
+        let error = Pipeline::new().with_budget(budget).compile(&source).unwrap_err();
+        assert!(error.message().starts_with("Compilation exceeded the memory limit of 65536 bytes during macro expansion of 'dup' (expansions are estimated at "), "{}", error);
+        assert_eq!(error.code(), Some("L0507"));
+
+        let error = Pipeline::new().with_budget(CompileBudget::new().with_memory(1)).compile(&source).unwrap_err();
+        assert!(error.message().contains("memory limit of 1 bytes during parsing"), "{}", error);
+        let error = Pipeline::new().with_budget(CompileBudget::new().with_time(Duration::ZERO)).compile(&source).unwrap_err();
+        assert!(error.message().starts_with("Compilation exceeded the time limit of 0ns during parsing ("), "{}", error);
+        assert!(Pipeline::new().with_budget(CompileBudget::new().with_memory(32 * 1024 * 1024)).compile(&source).is_ok());
+    }
+
+    #[test]
+    fn test_sandboxed_macros() {
+        let nested = (0..12).fold("1".to_string(), |inner, _| format!("(dup {})", inner));
+        let source = format!("(defmacro dup (x) `(list ,x ,x))\n{}", nested);
+        let config = SandboxConfig::new().with_max_fuel(100);
+        let error = Pipeline::new().with_sandbox(&config).compile(&source).unwrap_err();
+        assert_eq!(error.message(), "Macro expansion error: Sandbox violation expanding macro 'dup': Fuel exhausted: limit=100 forms");
+
+        // The outermost call copies its argument, 11 unexpanded calls deep, twice
+        let config = SandboxConfig::new().with_max_expansion_nodes(40);
+        let error = Pipeline::new().with_sandbox(&config).compile(&source).unwrap_err();
+        assert!(error.message().ends_with("Macro expansion too large: limit=40 nodes, generated=48 nodes"), "{}", error);
+        assert!(Pipeline::new().with_sandbox(&SandboxConfig::new()).compile(&source).is_ok());
+    }
+
+    #[test]
+    fn test_compiler_macros() {
+        let source = "(defun power (base exponent) (* base exponent))\n\
+                      (define-compiler-macro power (base exponent) (if (= exponent 2) (list '* base base) nil))\n\
+                      (power 7 2)\n(power 7 3)";
+        let rust_code = Pipeline::new().compile(source).unwrap();
+        assert!(rust_code.contains("(7.0 * 7.0)"), "{}", rust_code);
+        assert!(rust_code.contains("power((7.0).into(), (3.0).into())"), "{}", rust_code);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = "(defmacro twice (x) `(+ ,x ,x))\n(defun sq (n) (* n n))\n  (* (twice 2) 3)\n(sq 4)";
+        let mapped = Source::new("x.lisp", source);
+        let rust_code = Pipeline::new().with_source_map(&mapped).compile(source).unwrap();
+        assert!(rust_code.contains("// lisp: x.lisp:2:1\nfn sq("), "{}", rust_code);
+        assert!(rust_code.contains("    // lisp: x.lisp:3:3\n    println!"), "{}", rust_code);
+        assert!(rust_code.contains("    // lisp: x.lisp:4:1\n    println!"), "{}", rust_code);
+        assert!(!Pipeline::new().compile(source).unwrap().contains("// lisp:"));
+        // Forms read beforehand compile as the source they were read from does
+        let forms = Reader::new().read_with_span_trees(source).unwrap();
+        assert_eq!(Pipeline::new().with_source_map(&mapped).compile_read(forms).unwrap(), rust_code);
+
+        let generated = Source::new("x.lisp", "(defmacro sq2 () `(* 2 2))\n(sq2)");
+        let rust_code = Pipeline::new().with_source_map(&generated).compile(&generated.text).unwrap();
+        assert!(rust_code.contains("// lisp: x.lisp:2:1 (expanded from sq2)"), "{}", rust_code);
+        // Each form in a function body is marked too
+        let body = Source::new("x.lisp", "(defmacro twice (x) `(+ ,x ,x))\n(defun f (n)\n  (* n 2)\n  (twice n))");
+        let rust_code = Pipeline::new().with_source_map(&body).compile(&body.text).unwrap();
+        assert!(rust_code.contains("    // lisp: x.lisp:3:3\n    let _ = (n * 2.0);\n    // lisp: x.lisp:4:3 (expanded from twice)\n    (n + n)\n}"), "{}", rust_code);
+        let error = Pipeline::new().with_backend(&WatBackend).with_source_map(&mapped).compile(source).unwrap_err();
+        assert_eq!(error.message(), "The wat backend cannot map generated code back to Lisp source");
+    }
+}
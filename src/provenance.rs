@@ -0,0 +1,174 @@
+//! How generated code was produced.
+//!
+//! `build --provenance` starts the generated Rust with a comment block recording the
+//! compiler version, the SHA-256 of every input file (the program, the files it
+//! includes, and the `--lib` bundles), the transforms and validators that ran, the
+//! sandbox settings, and when it was built. The same record, with the hash of the
+//! generated file itself, is written as JSON next to the output, so whoever consumes
+//! the code can check it against the inputs they trust. `--deterministic` builds
+//! leave out the timestamp; otherwise it is `SOURCE_DATE_EPOCH` when set, as for
+//! other reproducible builds, or the current time.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifies a provenance manifest
+pub const PROVENANCE_FORMAT: &str = "rusty-lisp-provenance";
+
+/// The variable that fixes the timestamp of reproducible builds
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputFile {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// The sandbox the generated code enforces
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SandboxRecord {
+    /// The `--sandbox-profile`, if one was named
+    pub profile: Option<String>,
+    /// Granted and denied capabilities, sorted
+    pub granted: Vec<String>,
+    pub denied: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Provenance {
+    pub format: &'static str,
+    pub version: &'static str,
+    pub target: String,
+    pub inputs: Vec<InputFile>,
+    pub transforms: Vec<String>,
+    /// The validation rules checked before code generation
+    pub validators: Vec<String>,
+    /// `None` for unsandboxed code
+    pub sandbox: Option<SandboxRecord>,
+    /// Seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// The generated file, header included, once it is written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<InputFile>,
+}
+
+impl Provenance {
+    pub fn new(target: &str) -> Self {
+        Provenance {
+            format: PROVENANCE_FORMAT,
+            version: env!("CARGO_PKG_VERSION"),
+            target: target.to_string(),
+            inputs: Vec::new(),
+            transforms: Vec::new(),
+            validators: Vec::new(),
+            sandbox: None,
+            timestamp: None,
+            output: None,
+        }
+    }
+
+    pub fn with_input(mut self, path: &str, contents: &[u8]) -> Self {
+        self.inputs.push(InputFile { path: path.to_string(), sha256: sha256(contents) });
+        self
+    }
+
+    pub fn with_transforms(mut self, transforms: Vec<String>) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    pub fn with_validators(mut self, validators: Vec<String>) -> Self {
+        self.validators = validators;
+        self
+    }
+
+    pub fn with_sandbox(mut self, sandbox: SandboxRecord) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Record the generated file at `path`
+    pub fn with_output(mut self, path: &str, contents: &[u8]) -> Self {
+        self.output = Some(InputFile { path: path.to_string(), sha256: sha256(contents) });
+        self
+    }
+
+    /// `//` comments for the top of the generated Rust
+    pub fn header(&self) -> String {
+        let list = |items: &[String]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+        let mut header = format!("// Generated by rusty-lisp {} for target {}\n", self.version, self.target);
+        for input in &self.inputs {
+            header.push_str(&format!("// input: {} sha256:{}\n", input.path, input.sha256));
+        }
+        header.push_str(&format!("// transforms: {}\n", list(&self.transforms)));
+        header.push_str(&format!("// validators: {}\n", list(&self.validators)));
+        match &self.sandbox {
+            Some(sandbox) => header.push_str(&format!(
+                "// sandbox: profile {}; granted {}; denied {}\n",
+                sandbox.profile.as_deref().unwrap_or("none"),
+                list(&sandbox.granted),
+                list(&sandbox.denied)
+            )),
+            None => header.push_str("// sandbox: none\n"),
+        }
+        if let Some(timestamp) = self.timestamp {
+            header.push_str(&format!("// timestamp: {}\n", timestamp));
+        }
+        header.push('\n');
+        header
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("provenance serializes")
+    }
+}
+
+/// `SOURCE_DATE_EPOCH` when it is set to a number of seconds, or else the current time
+pub fn timestamp(source_date_epoch: Option<&str>) -> u64 {
+    source_date_epoch
+        .and_then(|seconds| seconds.trim().parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+}
+
+/// Lowercase hex SHA-256 of `contents`
+pub fn sha256(contents: &[u8]) -> String {
+    Sha256::digest(contents).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provenance() {
+        assert_eq!(sha256(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(timestamp(Some("1700000000")), 1700000000);
+        assert!(timestamp(Some("yesterday")) > 1700000000);
+
+        let provenance = Provenance::new("rust")
+            .with_input("main.lisp", b"abc")
+            .with_validators(vec!["TypeSafety".to_string(), "Arity".to_string()])
+            .with_sandbox(SandboxRecord { profile: Some("strict".to_string()), granted: vec!["SystemTime".to_string()], denied: Vec::new() });
+        let header = provenance.header();
+        assert!(header.starts_with(&format!("// Generated by rusty-lisp {} for target rust\n", env!("CARGO_PKG_VERSION"))));
+        assert!(header.contains(
+            "// input: main.lisp sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n\
+             // transforms: none\n// validators: TypeSafety, Arity\n// sandbox: profile strict; granted SystemTime; denied none\n\n"
+        ));
+        assert!(!provenance.to_json().contains("timestamp"));
+
+        let provenance = provenance.with_timestamp(1700000000).with_output("main.rs", b"");
+        assert!(provenance.header().contains("// timestamp: 1700000000\n"));
+        let json: serde_json::Value = serde_json::from_str(&provenance.to_json()).unwrap();
+        assert_eq!(json["format"], PROVENANCE_FORMAT);
+        assert_eq!(json["timestamp"], 1700000000);
+        assert_eq!(json["output"]["path"], "main.rs");
+    }
+}
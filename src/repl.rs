@@ -0,0 +1,490 @@
+//! Interactive read-eval-print loop over the interpreter, with line editing,
+//! continuation prompts until parentheses balance, persistent history, and tab
+//! completion of the symbols defined so far.
+//!
+//! Entries starting with `:` are meta-commands that show a form as it passes
+//! through the compiler pipeline instead of evaluating it.
+
+use crate::ast::LispExpr;
+use crate::compiler;
+use crate::deps::{self, DependencyGraph};
+use crate::interpreter::{BUILTINS, Interpreter, SPECIAL_FORMS, Value};
+use crate::logging;
+use crate::parser::Reader;
+use crate::suggest;
+use crate::validator::{CompositeValidator, TypeSafetyValidator};
+use crate::visualizer::VisualFormat;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+const PROMPT: &str = "lisp> ";
+const CONTINUATION_PROMPT: &str = "   .. ";
+const HISTORY_FILE: &str = ".rusty_lisp_history";
+
+/// Commands that take a form, completed along with symbols
+const META_COMMANDS: &[&str] = &[":expand", ":expand1", ":macro", ":rust", ":ast", ":type", ":validate", ":load", ":help"];
+
+const META_HELP: &str = "\
+:expand FORM    FORM after macro expansion
+:expand1 FORM   FORM after expanding its macro call one step
+:macro NAME     the definition of the macro NAME
+:rust FORM      the Rust generated for FORM, after expansion, with the session's functions and structs
+:ast FORM       FORM's syntax tree
+:type FORM      the type inferred for FORM after expansion, given the session's definitions
+:validate FORM  findings of every validator for FORM
+:load FILE      evaluate FILE's new and changed forms, removing deleted definitions
+:help           this list";
+
+/// One session's interpreter: definitions and macros persist between entries
+pub struct Repl {
+    interpreter: Interpreter,
+    reader: Reader,
+    /// Forms evaluated without error, for checks that follow definitions
+    forms: Vec<LispExpr>,
+    /// The forms last loaded from each file, by identity
+    loaded: HashMap<String, HashMap<FormKey, LispExpr>>,
+}
+
+/// Identifies a top-level form across reloads: a definition by the name it defines,
+/// so an edited definition replaces the old one, and any other form by its text
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FormKey {
+    Definition(String),
+    Expression(String),
+}
+
+impl FormKey {
+    fn of(expr: &LispExpr) -> FormKey {
+        match deps::defined_name(expr) {
+            Some((name, _)) => FormKey::Definition(name.to_string()),
+            None => FormKey::Expression(expr.to_string()),
+        }
+    }
+}
+
+/// What `Repl::reload` did
+#[derive(Debug, Default, PartialEq)]
+pub struct Reload {
+    /// Each new or changed form and its value, in source order
+    pub evaluated: Vec<(LispExpr, Value)>,
+    pub unchanged: usize,
+    /// Definitions no longer in the file, now undefined
+    pub removed: Vec<String>,
+    /// Why evaluation stopped early; the failed form is retried on the next reload
+    pub error: Option<String>,
+}
+
+impl Reload {
+    /// `file: 2 evaluated, 3 unchanged, 1 removed (old-name)`
+    pub fn summary(&self, file: &str) -> String {
+        let mut summary = format!(
+            "{}: {} evaluated, {} unchanged, {} removed",
+            file,
+            self.evaluated.len(),
+            self.unchanged,
+            self.removed.len()
+        );
+        if !self.removed.is_empty() {
+            summary.push_str(&format!(" ({})", self.removed.join(", ")));
+        }
+        summary
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl { interpreter: Interpreter::new(), reader: Reader::new(), forms: Vec::new(), loaded: HashMap::new() }
+    }
+
+    /// Read entries and files with `reader`'s features
+    pub fn with_reader(mut self, reader: Reader) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Check function contracts on each call, or skip them
+    pub fn with_contracts(mut self, enabled: bool) -> Self {
+        self.interpreter = std::mem::take(&mut self.interpreter).with_contracts(enabled);
+        self
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    /// The session's functions, structs, traits, and impls after expansion, each in its latest version
+    fn definitions(&mut self) -> Result<Vec<LispExpr>, String> {
+        // Expanded in order, so macro definitions among the forms end as they are now
+        let mut expanded = Vec::new();
+        for form in &self.forms {
+            expanded.push(self.interpreter.expand(form)?);
+        }
+        let mut seen = HashSet::new();
+        let mut definitions: Vec<LispExpr> = expanded
+            .into_iter()
+            .rev()
+            .filter(|form| compiler::is_item(form) && seen.insert(FormKey::of(form)))
+            .collect();
+        definitions.reverse();
+        Ok(definitions)
+    }
+
+    /// Evaluate every form in `source`, returning each form's value
+    pub fn eval_source(&mut self, source: &str) -> Result<Vec<Value>, String> {
+        let program = self.reader.read(source)?;
+        let mut values = Vec::new();
+        for expr in program {
+            values.push(self.interpreter.eval_program(std::slice::from_ref(&expr))?);
+            self.forms.push(expr);
+        }
+        Ok(values)
+    }
+
+    /// Evaluate the top-level forms of `source`, read from `file`, that are new or
+    /// changed since `file` was last loaded, and undefine the definitions it no
+    /// longer contains. Redefinitions replace earlier functions and macros. Forms
+    /// that depend on a changed or removed definition, directly or through other
+    /// definitions, run again too; the rest of the file is left alone.
+    pub fn reload(&mut self, file: &str, source: &str) -> Result<Reload, String> {
+        let program = self.reader.read(source)?;
+        let mut previous = self.loaded.remove(file).unwrap_or_default();
+        let mut current = HashMap::new();
+        let mut reload = Reload::default();
+
+        // Undefine first, so forms still using a removed definition fail now
+        let keys: HashSet<FormKey> = program.iter().map(FormKey::of).collect();
+        let mut removed = HashSet::new();
+        for key in previous.keys().filter(|key| !keys.contains(*key)) {
+            if let FormKey::Definition(name) = key {
+                removed.insert(name.clone());
+                if self.interpreter.undefine(name) {
+                    reload.removed.push(name.clone());
+                }
+            }
+        }
+        previous.retain(|key, _| keys.contains(key));
+        reload.removed.sort();
+
+        let uses_removed = |expr: &LispExpr| deps::referenced_symbols(expr).iter().any(|symbol| removed.contains(*symbol));
+        let changed: Vec<&str> = program
+            .iter()
+            .filter_map(|expr| {
+                let (name, _) = deps::defined_name(expr)?;
+                let edited = previous.get(&FormKey::Definition(name.to_string())) != Some(expr);
+                (edited || uses_removed(expr)).then_some(name)
+            })
+            .collect();
+        let affected: HashSet<String> = DependencyGraph::new(&program).affected(&changed).into_iter().collect();
+        let is_affected = |expr: &LispExpr| match deps::defined_name(expr) {
+            Some((name, _)) => affected.contains(name),
+            None => {
+                uses_removed(expr) || deps::referenced_symbols(expr).iter().any(|symbol| affected.contains(*symbol))
+            }
+        };
+
+        for expr in program {
+            let key = FormKey::of(&expr);
+            if previous.get(&key) == Some(&expr) && !is_affected(&expr) {
+                reload.unchanged += 1;
+                current.insert(key, expr);
+                continue;
+            }
+            match self.interpreter.eval_program(std::slice::from_ref(&expr)) {
+                Ok(value) => {
+                    self.forms.push(expr.clone());
+                    current.insert(key, expr.clone());
+                    reload.evaluated.push((expr, value));
+                }
+                Err(err) => {
+                    // Forms after the failure have not been looked at, so keep the old ones
+                    reload.error = Some(err);
+                    previous.extend(current);
+                    self.loaded.insert(file.to_string(), previous);
+                    return Ok(reload);
+                }
+            }
+        }
+
+        self.loaded.insert(file.to_string(), current);
+        Ok(reload)
+    }
+
+    /// Run a `:command FORM` entry, returning what to print
+    pub fn meta_command(&mut self, entry: &str) -> Result<String, String> {
+        let (command, argument) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+        if command == ":help" {
+            return Ok(META_HELP.to_string());
+        }
+        if !META_COMMANDS.contains(&command) {
+            return Err(format!("Unknown command '{}' (try :help)", command));
+        }
+        if command == ":load" {
+            let file = argument.trim();
+            let source = std::fs::read_to_string(file).map_err(|e| format!("Cannot read '{}': {}", file, e))?;
+            let reload = self.reload(file, &source)?;
+            let mut lines: Vec<String> = reload.evaluated.iter().map(|(_, value)| value.to_string()).collect();
+            lines.push(reload.summary(file));
+            if let Some(err) = reload.error {
+                lines.push(format!("Error: {}", err));
+            }
+            return Ok(lines.join("\n"));
+        }
+        let form = match self.reader.read(argument)?.as_slice() {
+            [form] => form.clone(),
+            _ => return Err(format!("'{}' takes one form", command)),
+        };
+
+        match command {
+            ":expand" => Ok(self.interpreter.expand(&form)?.to_source()),
+            ":expand1" => Ok(self.interpreter.expand_once(&form)?.to_source()),
+            ":macro" => {
+                let expander = self.interpreter.expander();
+                let name = form.as_symbol().ok_or_else(|| "':macro' takes a macro name".to_string())?;
+                let definition = expander.macro_definition(name).ok_or_else(|| {
+                    let names = expander.macro_names();
+                    suggest::with_suggestion(format!("Unknown macro '{}'", name), name, names.iter().map(String::as_str))
+                })?;
+                Ok(format!("{}\n  {}", definition.signature(), definition.body.to_source()))
+            }
+            ":rust" => Ok(compiler::compile_expression_with(&self.definitions()?, &self.interpreter.expand(&form)?)?),
+            ":ast" => Ok(VisualFormat::Tree.render(&[form]).trim_end().to_string()),
+            ":type" => {
+                let expanded = self.interpreter.expand(&form)?;
+                Ok(TypeSafetyValidator::new().with_definitions(&self.forms).infer_type(&expanded).to_string())
+            }
+            _ => {
+                let mut program = self.forms.clone();
+                program.push(form);
+                let results = CompositeValidator::with_all_validators().validate_program(&program);
+                match results.into_iter().last() {
+                    Some(Err(errors)) => Ok(errors
+                        .iter()
+                        .map(|error| format!("{}: {}", error.severity, error))
+                        .collect::<Vec<_>>()
+                        .join("\n")),
+                    _ => Ok("No problems found".to_string()),
+                }
+            }
+        }
+    }
+
+    /// What tab completion offers: session bindings, macros, builtins, special forms,
+    /// and meta-commands
+    pub fn symbols(&self) -> Vec<String> {
+        let mut symbols = self.interpreter.global().names();
+        symbols.extend(self.interpreter.expander().macro_names());
+        symbols.extend(BUILTINS.iter().chain(SPECIAL_FORMS).chain(META_COMMANDS).map(|name| name.to_string()));
+        symbols.sort();
+        symbols.dedup();
+        symbols
+    }
+}
+
+/// Whether every parenthesis opened in `source` outside strings and comments is closed
+pub fn is_complete(source: &str) -> bool {
+    let mut depth = 0i32;
+    let mut chars = source.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ';' => {
+                chars.by_ref().find(|&ch| ch == '\n');
+            }
+            '"' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some('"') => break,
+                    Some(_) => {}
+                    None => return false,
+                }
+            },
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+/// The symbols starting with `prefix`
+pub fn completions(symbols: &[String], prefix: &str) -> Vec<String> {
+    symbols.iter().filter(|symbol| symbol.starts_with(prefix)).cloned().collect()
+}
+
+/// `~/.rusty_lisp_history`, when there is a home directory
+pub fn history_path() -> Option<PathBuf> {
+    std::env::home_dir().map(|home| home.join(HISTORY_FILE))
+}
+
+struct ReplHelper {
+    symbols: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|ch: char| ch.is_whitespace() || "()'`,\"".contains(ch))
+            .map_or(0, |i| i + 1);
+        Ok((start, completions(&self.symbols, &line[start..pos])))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Read and evaluate entries until end of input, keeping history in `history`
+pub fn run(history: Option<PathBuf>, contracts: bool, reader: Reader) -> Result<(), String> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new().map_err(|e| e.to_string())?;
+    let mut repl = Repl::new().with_contracts(contracts).with_reader(reader);
+    editor.set_helper(Some(ReplHelper { symbols: repl.symbols() }));
+    if let Some(path) = &history {
+        // No history file yet on the first run
+        let _ = editor.load_history(path);
+    }
+
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                entry.push_str(&line);
+                entry.push('\n');
+                if !is_complete(&entry) {
+                    continue;
+                }
+                let source = std::mem::take(&mut entry);
+                let source = source.trim();
+                if source.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(source);
+                let result = if source.starts_with(':') {
+                    repl.meta_command(source).map(|text| println!("{}", text))
+                } else {
+                    repl.eval_source(source).map(|values| values.iter().for_each(|value| println!("{}", value)))
+                };
+                if let Err(err) = result {
+                    logging::error(format_args!("Error: {}", err));
+                }
+                if let Some(helper) = editor.helper_mut() {
+                    helper.symbols = repl.symbols();
+                }
+            }
+            // Ctrl-C abandons the entry being typed
+            Err(ReadlineError::Interrupted) => entry.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(err) = editor.save_history(path)
+    {
+        logging::warn(format_args!("Cannot save history to {}: {}", path.display(), err));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("(+ 1 2)"));
+        assert!(is_complete("42"));
+        assert!(!is_complete("(defun f (x)\n"));
+        assert!(is_complete("(print \")(\") ; (unclosed in a comment\n"));
+        assert!(!is_complete("(print \"still \\\" open"));
+    }
+
+    #[test]
+    fn test_session() {
+        let mut repl = Repl::new();
+        let values = repl.eval_source("(defun square (x) (* x x)) (square 4)").unwrap();
+        assert_eq!(values, vec![Value::Symbol("square".into()), Value::Number(16.0)]);
+        repl.eval_source("(defmacro swap-args (f a b) (f b a))").unwrap();
+        assert_eq!(repl.eval_source("(swap-args - 1 10)").unwrap(), vec![Value::Number(9.0)]);
+        assert!(repl.eval_source("(square").is_err());
+
+        let symbols = repl.symbols();
+        assert_eq!(completions(&symbols, "squ"), vec!["square"]);
+        assert_eq!(completions(&symbols, "swap"), vec!["swap-args"]);
+        assert!(completions(&symbols, "string-").contains(&"string-append".to_string()));
+        assert_eq!(completions(&symbols, ":ex"), vec![":expand", ":expand1"]);
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut repl = Repl::new();
+        let first = repl.reload("lib.lisp", "(defun f (x) (* x 2)) (defun g (x) (f x)) (define unused 1) (g 5)").unwrap();
+        assert_eq!(first.evaluated.len(), 4);
+        assert_eq!(first.evaluated[3].1, Value::Number(10.0));
+
+        // The edited definition runs again, with what depends on it; `h` does not
+        let second = repl.reload("lib.lisp", "(defun f (x) (* x 3))\n(defun g (x) (f x))\n(defun h () 2)\n(g 5)\n(h)").unwrap();
+        let evaluated: Vec<String> = second.evaluated.iter().map(|(_, value)| value.to_string()).collect();
+        assert_eq!(evaluated, vec!["f", "g", "h", "15", "2"]);
+        assert_eq!(second.removed, vec!["unused"]);
+        let third = repl.reload("lib.lisp", "(defun f (x) (* x 4))\n(defun g (x) (f x))\n(defun h () 2)\n(g 5)\n(h)").unwrap();
+        let evaluated: Vec<String> = third.evaluated.iter().map(|(_, value)| value.to_string()).collect();
+        assert_eq!(evaluated, vec!["f", "g", "20"]);
+        assert_eq!(third.summary("lib.lisp"), "lib.lisp: 3 evaluated, 2 unchanged, 0 removed");
+
+        // Forms that still use a removed definition run again and fail
+        let removed = repl.reload("lib.lisp", "(defun f (x) (* x 3)) (defun h () 2) (g 5)").unwrap();
+        assert_eq!(removed.removed, vec!["g"]);
+        assert!(removed.error.is_some());
+        assert!(repl.eval_source("(g 5)").is_err());
+
+        let failed = repl.reload("lib.lisp", "(defun f (x) (* x 3)) (car 1) (defun h () 1)").unwrap();
+        assert!(failed.error.is_some());
+        assert!(repl.reload("lib.lisp", "(defun f (x) (* x 3)) (defun h () 1)").unwrap().error.is_none());
+    }
+
+    #[test]
+    fn test_meta_commands() {
+        let mut repl = Repl::new();
+        repl.eval_source("(defmacro twice (x) (+ x x)) (define n 4)").unwrap();
+        assert_eq!(repl.meta_command(":expand (twice (* n 2))").unwrap(), "(+ (* n 2) (* n 2))");
+        assert_eq!(repl.meta_command(":rust (twice 3)").unwrap(), "(3 + 3)");
+        // Calls see the session's functions in their latest version
+        repl.eval_source("(defun f (x) x) (defun f (x) (twice x))").unwrap();
+        assert_eq!(repl.meta_command(":rust (f 1)").unwrap(), "fn f(x: f64) -> f64 {\n    (x + x)\n}\n\nf((1.0).into())");
+        assert_eq!(repl.meta_command(":rust (defun g () (f 2))").unwrap(), "fn f(x: f64) -> f64 {\n    (x + x)\n}\n\nfn g() -> impl std::fmt::Debug {\n    f((2.0).into())\n}");
+        repl.eval_source("(defmacro quad (x) (twice (twice x)))").unwrap();
+        assert_eq!(repl.meta_command(":expand1 (quad n)").unwrap(), "(twice (twice n))");
+        assert_eq!(repl.meta_command(":expand1 (+ n 1)").unwrap(), "(+ n 1)");
+        assert_eq!(repl.meta_command(":macro quad").unwrap(), "(quad x)\n  (twice (twice x))");
+        assert_eq!(repl.meta_command(":macro quads").unwrap_err(), "Unknown macro 'quads' (did you mean 'quad'?)");
+        assert_eq!(repl.meta_command(":ast (twice 3)").unwrap(), "List\n├── twice\n└── 3");
+        assert_eq!(repl.meta_command(":type (twice n)").unwrap(), "number");
+        assert_eq!(repl.meta_command(":validate (+ n 1)").unwrap(), "No problems found");
+        assert!(repl.meta_command(":validate (+ \"a\" 1)").unwrap().starts_with("error: TypeSafety violation"));
+        assert_eq!(repl.meta_command(":type 1 2").unwrap_err(), "':type' takes one form");
+        assert_eq!(repl.meta_command(":run").unwrap_err(), "Unknown command ':run' (try :help)");
+    }
+}
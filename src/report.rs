@@ -0,0 +1,453 @@
+use crate::ast::Span;
+use crate::include::Source;
+use crate::validator::{FunctionMetrics, Severity, ValidationError, ValidationRule};
+use serde::Serialize;
+use serde_json::json;
+
+const TOOL_NAME: &str = "lisp-compiler";
+const TOOL_URI: &str = "https://github.com/justin4957/rusty-lisp";
+
+/// Output format for validation reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One `file:line:column: severity[rule]: message` entry per finding
+    Text,
+    Json,
+    Sarif,
+}
+
+impl ReportFormat {
+    /// Parse a format name as given on the command line
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "sarif" => Ok(ReportFormat::Sarif),
+            other => Err(format!("Unknown report format: {} (expected text, json, or sarif)", other)),
+        }
+    }
+
+    /// Pick a format from the report file extension, defaulting to JSON
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".sarif") || path.ends_with(".sarif.json") {
+            ReportFormat::Sarif
+        } else {
+            ReportFormat::Json
+        }
+    }
+}
+
+/// Line/column region of a finding in the source file (1-based)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceRegion {
+    pub start: usize,
+    pub end: usize,
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl SourceRegion {
+    pub fn from_span(span: Span, source: &str) -> Self {
+        let (start_line, start_column) = Span::line_col(source, span.start);
+        let (end_line, end_column) = Span::line_col(source, span.end);
+        SourceRegion {
+            start: span.start,
+            end: span.end,
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+        }
+    }
+}
+
+/// Machine-readable rewrite attached to a finding
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FixReplacement {
+    pub target: String,
+    pub replacement: String,
+    pub applicability: String,
+}
+
+/// A single validator finding in a report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    /// The included file the finding is in, when not the report's file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub rule: String,
+    /// Stable error code, explained by `explain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub severity: String,
+    pub message: String,
+    pub context: Option<String>,
+    pub span: Option<SourceRegion>,
+    pub suggested_fix: Option<String>,
+    pub fix: Option<FixReplacement>,
+}
+
+/// Collected validation findings for one input file
+#[derive(Debug, Clone)]
+pub struct ValidationReport {
+    file: String,
+    findings: Vec<Finding>,
+    /// Complexity of each function, for quality gates
+    metrics: Vec<FunctionMetrics>,
+}
+
+impl ValidationReport {
+    pub fn new(file: &str) -> Self {
+        ValidationReport {
+            file: file.to_string(),
+            findings: Vec::new(),
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Include the complexity metrics of the program's functions
+    pub fn set_metrics(&mut self, metrics: Vec<FunctionMetrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> &[FunctionMetrics] {
+        &self.metrics
+    }
+
+    /// The file a span of `source` is in, when it is not this report's, and its region there
+    fn locate(&self, span: Option<Span>, source: &Source) -> (Option<String>, Option<SourceRegion>) {
+        let Some(span) = span else { return (None, None) };
+        let (file, span) = source.locate(span);
+        ((file.path != self.file).then(|| file.path.clone()), Some(SourceRegion::from_span(span, &file.text)))
+    }
+
+    /// Record validation errors for one expression, located by its span in `source`
+    pub fn add_errors(&mut self, errors: &[ValidationError], span: Option<Span>, source: &Source) {
+        let (file, region) = self.locate(span, source);
+        for error in errors {
+            self.findings.push(Finding {
+                file: file.clone(),
+                rule: format!("{:?}", error.rule),
+                code: Some(error.rule.code().to_string()),
+                severity: error.severity.to_string(),
+                message: error.message.clone(),
+                context: error.context.clone(),
+                span: region.clone(),
+                suggested_fix: error.suggestion.clone(),
+                fix: error.fix.as_ref().map(|fix| FixReplacement {
+                    target: fix.target.to_string(),
+                    replacement: fix.replacement.to_string(),
+                    applicability: fix.applicability.to_string(),
+                }),
+            });
+        }
+    }
+
+    /// Record a finding from outside the validators, such as a parse or macro expansion error
+    pub fn add_diagnostic(&mut self, rule: &str, code: Option<&str>, severity: Severity, message: &str, span: Option<Span>, source: &Source) {
+        let (file, region) = self.locate(span, source);
+        self.findings.push(Finding {
+            file,
+            rule: rule.to_string(),
+            code: code.map(str::to_string),
+            severity: severity.to_string(),
+            message: message.to_string(),
+            context: None,
+            span: region,
+            suggested_fix: None,
+            fix: None,
+        });
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn count_severity(&self, severity: Severity) -> usize {
+        let name = severity.to_string();
+        self.findings.iter().filter(|f| f.severity == name).count()
+    }
+
+    /// Render the report for people, in the style of compiler diagnostics
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for finding in &self.findings {
+            let file = finding.file.as_ref().unwrap_or(&self.file);
+            let location = match &finding.span {
+                Some(region) => format!("{}:{}:{}", file, region.start_line, region.start_column),
+                None => file.clone(),
+            };
+            match &finding.code {
+                // The rule follows the first line of the message, which may go on with details
+                Some(code) => {
+                    let (headline, details) = finding.message.split_once('\n').map_or((finding.message.as_str(), None), |(headline, details)| (headline, Some(details)));
+                    text.push_str(&format!("{}: {}[{}]: {} [{}]\n", location, finding.severity, code, headline, finding.rule));
+                    if let Some(details) = details {
+                        text.push_str(&format!("{}\n", details));
+                    }
+                }
+                None => text.push_str(&format!("{}: {}[{}]: {}\n", location, finding.severity, finding.rule, finding.message)),
+            }
+            if let Some(context) = &finding.context {
+                text.push_str(&format!("  context: {}\n", context));
+            }
+            if let Some(suggestion) = &finding.suggested_fix {
+                text.push_str(&format!("  help: {}\n", suggestion));
+            }
+        }
+        if !self.findings.is_empty() {
+            text.push_str(&format!(
+                "{}: {} error(s), {} warning(s)\n",
+                self.file,
+                self.count_severity(Severity::Error),
+                self.count_severity(Severity::Warning)
+            ));
+        }
+        if let Some(code) = self.findings.iter().find_map(|finding| finding.code.as_ref()) {
+            text.push_str(&format!("For more information about an error, try `{} explain {}`\n", TOOL_NAME, code));
+        }
+        if !self.metrics.is_empty() {
+            text.push_str(&format!("{}: {} function(s)\n", self.file, self.metrics.len()));
+            for metrics in &self.metrics {
+                text.push_str(&format!(
+                    "  {}: cyclomatic complexity {}, {} form(s), {} argument(s)\n",
+                    metrics.name, metrics.cyclomatic_complexity, metrics.forms, metrics.arguments
+                ));
+            }
+        }
+        text
+    }
+
+    /// Render the report as plain JSON
+    pub fn to_json(&self) -> String {
+        let mut report = json!({
+            "tool": TOOL_NAME,
+            "version": env!("CARGO_PKG_VERSION"),
+            "file": self.file,
+            "findings": self.findings,
+            "summary": {
+                "errors": self.count_severity(Severity::Error),
+                "warnings": self.count_severity(Severity::Warning),
+                "notes": self.count_severity(Severity::Note),
+            },
+        });
+        if !self.metrics.is_empty() {
+            report["metrics"] = json!(self.metrics);
+        }
+        serde_json::to_string_pretty(&report).unwrap()
+    }
+
+    /// Render the report as SARIF 2.1.0 for code scanning tools
+    pub fn to_sarif(&self) -> String {
+        let rules: Vec<_> = ValidationRule::ALL
+        .iter()
+        .map(|rule| {
+            json!({
+                "id": format!("{:?}", rule),
+                "shortDescription": { "text": rule.description() },
+                "properties": { "code": rule.code() },
+            })
+        })
+        .collect();
+
+        let results: Vec<_> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let mut result = json!({
+                    "ruleId": finding.rule,
+                    "level": finding.severity,
+                    "message": { "text": finding.message },
+                });
+                let mut physical_location = json!({
+                    "artifactLocation": { "uri": finding.file.as_ref().unwrap_or(&self.file) },
+                });
+                if let Some(region) = &finding.span {
+                    physical_location["region"] = json!({
+                        "startLine": region.start_line,
+                        "startColumn": region.start_column,
+                        "endLine": region.end_line,
+                        "endColumn": region.end_column,
+                    });
+                }
+                result["locations"] = json!([{ "physicalLocation": physical_location }]);
+                if let Some(code) = &finding.code {
+                    result["properties"]["code"] = json!(code);
+                }
+                if let Some(suggestion) = &finding.suggested_fix {
+                    result["properties"]["suggestedFix"] = json!(suggestion);
+                }
+                if let Some(fix) = &finding.fix {
+                    result["properties"]["fix"] = json!(fix);
+                }
+                result
+            })
+            .collect();
+
+        let mut sarif = json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": TOOL_NAME,
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "informationUri": TOOL_URI,
+                        "rules": rules,
+                    }
+                },
+                "results": results,
+            }],
+        });
+        if !self.metrics.is_empty() {
+            sarif["runs"][0]["properties"] = json!({ "metrics": self.metrics });
+        }
+        serde_json::to_string_pretty(&sarif).unwrap()
+    }
+
+    /// Render the report in the requested format
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => self.to_text(),
+            ReportFormat::Json => self.to_json(),
+            ReportFormat::Sarif => self.to_sarif(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::LispExpr;
+    use crate::validator::{Applicability, SuggestedFix};
+
+    fn sample_error() -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::TypeSafety,
+            severity: Severity::Error,
+            message: "Type mismatch".to_string(),
+            context: Some("\"hello\"".to_string()),
+            suggestion: Some("Convert the operand to a number".to_string()),
+            fix: Some(Box::new(SuggestedFix::new(
+                LispExpr::String("hello".to_string()),
+                LispExpr::List(vec![
+                    LispExpr::Symbol("string->number".into()),
+                    LispExpr::String("hello".to_string()),
+                ].into()),
+                Applicability::MaybeIncorrect,
+            ))),
+        }
+    }
+
+    #[test]
+    fn test_report_format_parse() {
+        assert_eq!(ReportFormat::parse("json").unwrap(), ReportFormat::Json);
+        assert_eq!(ReportFormat::parse("SARIF").unwrap(), ReportFormat::Sarif);
+        assert_eq!(ReportFormat::parse("text").unwrap(), ReportFormat::Text);
+        assert!(ReportFormat::parse("xml").is_err());
+    }
+
+    #[test]
+    fn test_report_format_from_path() {
+        assert_eq!(ReportFormat::from_path("out.sarif"), ReportFormat::Sarif);
+        assert_eq!(ReportFormat::from_path("out.sarif.json"), ReportFormat::Sarif);
+        assert_eq!(ReportFormat::from_path("out.json"), ReportFormat::Json);
+    }
+
+    #[test]
+    fn test_json_report_contents() {
+        let source = Source::new("example.lisp", "(+ 1 2)\n(+ \"hello\" 42)");
+        let mut report = ValidationReport::new("example.lisp");
+        report.add_errors(&[sample_error()], Some(Span::new(8, 22)), &source);
+
+        let value: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        let finding = &value["findings"][0];
+        assert_eq!(finding["rule"], "TypeSafety");
+        assert_eq!(finding["code"], "L0301");
+        assert_eq!(finding["severity"], "error");
+        assert_eq!(finding["span"]["start_line"], 2);
+        assert_eq!(finding["span"]["start_column"], 1);
+        assert_eq!(finding["suggested_fix"], "Convert the operand to a number");
+        assert_eq!(finding["fix"]["replacement"], "(string->number \"hello\")");
+        assert_eq!(finding["fix"]["applicability"], "maybe-incorrect");
+        assert_eq!(value["summary"]["errors"], 1);
+    }
+
+    #[test]
+    fn test_sarif_report_structure() {
+        let source = Source::new("example.lisp", "(+ \"hello\" 42)");
+        let mut report = ValidationReport::new("example.lisp");
+        report.add_errors(&[sample_error()], Some(Span::new(0, 14)), &source);
+
+        let value: serde_json::Value = serde_json::from_str(&report.to_sarif()).unwrap();
+        assert_eq!(value["version"], "2.1.0");
+        let run = &value["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "lisp-compiler");
+        let result = &run["results"][0];
+        assert_eq!(result["ruleId"], "TypeSafety");
+        assert_eq!(result["properties"]["code"], "L0301");
+        assert_eq!(result["properties"]["suggestedFix"], "Convert the operand to a number");
+        assert_eq!(result["level"], "error");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "example.lisp");
+        assert_eq!(location["region"]["startLine"], 1);
+        assert_eq!(location["region"]["endColumn"], 15);
+    }
+
+    #[test]
+    fn test_text_report() {
+        let source = Source::new("example.lisp", "(+ 1 2)\n(+ \"hello\" 42)");
+        let mut report = ValidationReport::new("example.lisp");
+        report.add_errors(&[sample_error()], Some(Span::new(8, 22)), &source);
+        report.add_diagnostic("Syntax", None, Severity::Warning, "Unexpected token", None, &source);
+
+        assert_eq!(
+            report.to_text(),
+            "example.lisp:2:1: error[L0301]: Type mismatch [TypeSafety]\n\
+             \x20 context: \"hello\"\n\
+             \x20 help: Convert the operand to a number\n\
+             example.lisp: warning[Syntax]: Unexpected token\n\
+             example.lisp: 1 error(s), 1 warning(s)\n\
+             For more information about an error, try `lisp-compiler explain L0301`\n"
+        );
+        assert_eq!(ValidationReport::new("clean.lisp").to_text(), "");
+    }
+
+    #[test]
+    fn test_findings_in_included_files() {
+        let dir = std::env::temp_dir().join(format!("rusty-lisp-report-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.lisp"), "(defun f () 1)\n(+ \"hello\" 42)").unwrap();
+        let main = dir.join("main.lisp").to_string_lossy().into_owned();
+        let source = Source::resolve(&main, "(include \"lib.lisp\")\n(f)".to_string(), &crate::parser::Reader::new(), None).unwrap();
+        let start = source.text.find("(+").unwrap();
+
+        let mut report = ValidationReport::new(&main);
+        report.add_errors(&[sample_error()], Some(Span::new(start, start + 14)), &source);
+        let lib = dir.join("lib.lisp").to_string_lossy().into_owned();
+        assert!(report.to_text().starts_with(&format!("{}:2:1: error[L0301]", lib)));
+        let value: serde_json::Value = serde_json::from_str(&report.to_sarif()).unwrap();
+        assert_eq!(value["runs"][0]["results"][0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"], lib.as_str());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_report_metrics() {
+        let mut report = ValidationReport::new("example.lisp");
+        report.set_metrics(vec![FunctionMetrics { name: "f".to_string(), cyclomatic_complexity: 3, forms: 12, arguments: 2 }]);
+        assert_eq!(report.to_text(), "example.lisp: 1 function(s)\n  f: cyclomatic complexity 3, 12 form(s), 2 argument(s)\n");
+        let value: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(value["metrics"][0]["cyclomatic_complexity"], 3);
+        let value: serde_json::Value = serde_json::from_str(&report.to_sarif()).unwrap();
+        assert_eq!(value["runs"][0]["properties"]["metrics"][0]["name"], "f");
+    }
+
+    #[test]
+    fn test_empty_report() {
+        let report = ValidationReport::new("clean.lisp");
+        let value: serde_json::Value = serde_json::from_str(&report.to_sarif()).unwrap();
+        assert!(value["runs"][0]["results"].as_array().unwrap().is_empty());
+    }
+}
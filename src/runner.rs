@@ -0,0 +1,153 @@
+use crate::bytecode::Module;
+use crate::sandbox::{SandboxConfig, SandboxMonitor};
+use crate::source_map::{self, SourceMap};
+use crate::vm::Vm;
+use crate::wasm;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// rustc target for the WASM backend
+pub const WASM_TARGET: &str = "wasm32-wasip1";
+
+/// Where `run` executes the generated program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Host binary; sandboxing relies on the checks compiled into it
+    Native,
+    /// wasm32-wasip1 module in the embedded WASM runtime, isolated by WASI
+    Wasm,
+}
+
+impl Target {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "native" => Ok(Target::Native),
+            "wasm" => Ok(Target::Wasm),
+            other => Err(format!("Unknown target '{}' (expected native or wasm)", other)),
+        }
+    }
+}
+
+/// What executes the program for `run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Generated Rust built with rustc
+    Rustc,
+    /// Bytecode in the built-in VM, for programs in the numeric subset
+    Vm,
+}
+
+impl Engine {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "rustc" => Ok(Engine::Rustc),
+            "vm" => Ok(Engine::Vm),
+            other => Err(format!("Unknown engine '{}' (expected rustc or vm)", other)),
+        }
+    }
+}
+
+/// Compile generated Rust source with rustc into `out_dir`, returning the binary or module path.
+/// Errors in code marked with `source_map` comments are reported at their Lisp locations.
+pub fn build(rust_source: &str, target: Target, out_dir: &Path) -> Result<PathBuf, String> {
+    let (rust_source, map) = SourceMap::read(rust_source);
+    let source_path = out_dir.join("main.rs");
+    fs::write(&source_path, rust_source).map_err(|e| format!("Cannot write '{}': {}", source_path.display(), e))?;
+
+    let output_path = out_dir.join(match target {
+        Target::Native => "program",
+        Target::Wasm => "program.wasm",
+    });
+    let mut rustc = Command::new("rustc");
+    rustc.arg("--edition=2021").arg("--error-format=json").arg("-O").arg("-o").arg(&output_path).arg(&source_path);
+    if target == Target::Wasm {
+        rustc.arg("--target").arg(WASM_TARGET);
+    }
+
+    let output = rustc.output().map_err(|e| format!("Cannot run rustc: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rustc failed:\n{}", source_map::render_rustc_diagnostics(&stderr, &map).trim_end()));
+    }
+    Ok(output_path)
+}
+
+/// Build and execute the generated program, returning its exit code
+pub fn run(rust_source: &str, target: Target, config: &SandboxConfig) -> Result<i32, String> {
+    if target == Target::Wasm && !wasm::RUNTIME_AVAILABLE {
+        return wasm::run_module(Path::new(""), config);
+    }
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let out_dir = std::env::temp_dir().join(format!(
+        "lisp-compiler-run-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&out_dir).map_err(|e| format!("Cannot create '{}': {}", out_dir.display(), e))?;
+
+    let result = build(rust_source, target, &out_dir).and_then(|program| match target {
+        Target::Native => Command::new(&program)
+            .status()
+            .map(|status| status.code().unwrap_or(1))
+            .map_err(|e| format!("Cannot run '{}': {}", program.display(), e)),
+        Target::Wasm => wasm::run_module(&program, config),
+    });
+    let _ = fs::remove_dir_all(&out_dir);
+    result
+}
+
+/// Execute a bytecode module in the VM, metered by `config` when given. The outer
+/// error is a module that cannot be loaded; the inner one stops the program as a
+/// contract or the sandbox would stop a compiled one.
+pub fn run_bytecode(text: &str, config: Option<&SandboxConfig>) -> Result<Result<(), String>, String> {
+    let module = Module::parse(text)?;
+    let mut vm = Vm::new(&module);
+    if let Some(config) = config {
+        vm = vm.with_monitor(SandboxMonitor::new(config.clone()));
+    }
+    Ok(vm.run(&mut io::stdout().lock()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_parse() {
+        assert_eq!(Target::parse("native").unwrap(), Target::Native);
+        assert_eq!(Target::parse("wasm").unwrap(), Target::Wasm);
+        assert!(Target::parse("arm").is_err());
+        assert_eq!(Engine::parse("vm").unwrap(), Engine::Vm);
+        assert!(Engine::parse("jit").is_err());
+    }
+
+    #[test]
+    fn test_run_native_returns_exit_code() {
+        let source = "fn main() { std::process::exit(3) }";
+        assert_eq!(run(source, Target::Native, &SandboxConfig::new()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_run_bytecode_returns_runtime_errors() {
+        use crate::backend::Backend;
+        use crate::bytecode::BytecodeBackend;
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        let program = parse(tokenize("(defun half (x) (declare (requires (>= x 0))) (/ x 2)) (half -1)").unwrap()).unwrap();
+        let text = BytecodeBackend.compile_program(&program, None, None).unwrap();
+        assert_eq!(run_bytecode(&text, None).unwrap().unwrap_err(), "Precondition of 'half' failed: (>= x 0)");
+        assert!(run_bytecode("not bytecode", None).is_err());
+    }
+
+    #[test]
+    fn test_rustc_errors_point_at_lisp() {
+        let source = "fn main() {\n    // lisp: bad.lisp:3:1\n    println!(\"{:?}\", (\"a\" + \"a\"));\n}\n";
+        let error = run(source, Target::Native, &SandboxConfig::new()).unwrap_err();
+        assert!(error.starts_with("rustc failed:\nbad.lisp:3:1: error[E0369]: cannot add `&str` to `&str`\n"), "{}", error);
+        assert!(error.contains("  generated Rust: println!(\"{:?}\", (\"a\" + \"a\"));\n"), "{}", error);
+    }
+}
@@ -1,6 +1,9 @@
+use serde::Serialize;
+use std::cell::RefCell;
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Represents specific capabilities that can be granted to sandboxed code
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -9,14 +12,61 @@ pub enum Capability {
     FileRead(PathBuf),
     /// Allow writing to a specific file path
     FileWrite(PathBuf),
-    /// Allow HTTP network requests
+    /// Allow HTTP network requests to any host
     NetworkHTTP,
+    /// Allow HTTP network requests to matching hosts (`*.example.com` wildcards allowed),
+    /// optionally only on one port
+    Network { host_pattern: String, port: Option<u16> },
     /// Allow accessing system time
     SystemTime,
     /// Allow spawning child processes
     ProcessSpawn,
+    /// Allow spawning threads (`spawn`, `thread`, `async`)
+    ThreadSpawn,
     /// Allow using unsafe Rust features
     UnsafeRust,
+    /// Allow reading environment variables matching a name or glob (e.g. `HOME`, `APP_*`)
+    EnvRead(String),
+    /// Allow compiled code to depend on crates matching a name or glob (e.g. `regex`, `serde*`)
+    Crate(String),
+}
+
+impl Capability {
+    /// Whether holding this capability includes `requested`: paths beneath a granted path
+    /// or glob, hosts matching a host pattern, variables matching a name glob
+    pub fn covers(&self, requested: &Capability) -> bool {
+        match (self, requested) {
+            (Capability::FileRead(granted), Capability::FileRead(path))
+            | (Capability::FileWrite(granted), Capability::FileWrite(path)) => path_matches(granted, path),
+            (Capability::NetworkHTTP, Capability::Network { .. }) => true,
+            (
+                Capability::Network { host_pattern, port },
+                Capability::Network { host_pattern: host, port: requested },
+            ) => network_rule_matches(host_pattern, *port, host, *requested),
+            (Capability::EnvRead(pattern), Capability::EnvRead(name))
+            | (Capability::Crate(pattern), Capability::Crate(name)) => env_matches(pattern, name),
+            _ => self == requested,
+        }
+    }
+}
+
+/// Formats a capability the way `--allow-capability` accepts it (e.g. `FileRead:/tmp`)
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::FileRead(path) => write!(f, "FileRead:{}", path.display()),
+            Capability::FileWrite(path) => write!(f, "FileWrite:{}", path.display()),
+            Capability::NetworkHTTP => write!(f, "NetworkHTTP"),
+            Capability::Network { host_pattern, port: Some(port) } => write!(f, "Network:{}:{}", host_pattern, port),
+            Capability::Network { host_pattern, port: None } => write!(f, "Network:{}", host_pattern),
+            Capability::SystemTime => write!(f, "SystemTime"),
+            Capability::ProcessSpawn => write!(f, "ProcessSpawn"),
+            Capability::ThreadSpawn => write!(f, "ThreadSpawn"),
+            Capability::UnsafeRust => write!(f, "UnsafeRust"),
+            Capability::EnvRead(name) => write!(f, "EnvRead:{}", name),
+            Capability::Crate(name) => write!(f, "Crate:{}", name),
+        }
+    }
 }
 
 /// Configuration for the sandbox execution environment
@@ -26,6 +76,11 @@ pub struct SandboxConfig {
     pub max_memory: usize,
     /// Maximum execution time
     pub max_execution_time: Duration,
+    /// Number of forms that may be evaluated before aborting, independent of machine speed;
+    /// `None` disables metering
+    pub max_fuel: Option<u64>,
+    /// Nodes one macro call may generate when the program is compiled; `None` disables the limit
+    pub max_expansion_nodes: Option<usize>,
     /// Allowed file paths for read/write operations
     pub allowed_file_paths: Vec<PathBuf>,
     /// Whether network access is permitted
@@ -34,6 +89,12 @@ pub struct SandboxConfig {
     pub safe_rust_apis: HashSet<String>,
     /// Set of granted capabilities
     pub capabilities: HashSet<Capability>,
+    /// Hosts network requests may reach (`*.example.com` wildcards allowed); empty allows any host
+    pub allowed_network_hosts: Vec<String>,
+    /// Revoked capabilities; these win over any grant, including from profiles and manifests
+    pub denied_capabilities: HashSet<Capability>,
+    /// JSONL file every capability check is appended to
+    pub audit_log: Option<PathBuf>,
 }
 
 impl SandboxConfig {
@@ -42,10 +103,15 @@ impl SandboxConfig {
         SandboxConfig {
             max_memory: 100 * 1024 * 1024, // 100MB default
             max_execution_time: Duration::from_secs(30), // 30 seconds default
+            max_fuel: None,
+            max_expansion_nodes: None,
             allowed_file_paths: Vec::new(),
             permitted_network_access: false,
             safe_rust_apis: Self::default_safe_apis(),
             capabilities: HashSet::new(),
+            allowed_network_hosts: Vec::new(),
+            denied_capabilities: HashSet::new(),
+            audit_log: None,
         }
     }
 
@@ -73,9 +139,107 @@ impl SandboxConfig {
         self.capabilities.insert(capability);
     }
 
-    /// Check if a capability is granted
+    /// Check if a capability is granted and not denied
     pub fn has_capability(&self, capability: &Capability) -> bool {
-        self.capabilities.contains(capability)
+        self.capabilities.contains(capability) && !self.is_denied(capability)
+    }
+
+    /// Whether compiled code may depend on the crate `name`
+    pub fn crate_allowed(&self, name: &str) -> bool {
+        let requested = Capability::Crate(name.to_string());
+        self.capabilities.iter().any(|capability| capability.covers(&requested)) && !self.is_denied(&requested)
+    }
+
+    /// Whether `path` may be read, by an allowed file path or a `FileRead` capability
+    pub fn file_read_allowed(&self, path: &Path) -> bool {
+        let requested = Capability::FileRead(path.to_path_buf());
+        (self.allowed_file_paths.iter().any(|allowed| path_matches(allowed, path))
+            || self.capabilities.iter().any(|capability| capability.covers(&requested)))
+            && !self.is_denied(&requested)
+    }
+
+    /// Whether `path` may be written, by an allowed file path or a `FileWrite` capability
+    pub fn file_write_allowed(&self, path: &Path) -> bool {
+        let requested = Capability::FileWrite(path.to_path_buf());
+        (self.allowed_file_paths.iter().any(|allowed| path_matches(allowed, path))
+            || self.capabilities.iter().any(|capability| capability.covers(&requested)))
+            && !self.is_denied(&requested)
+    }
+
+    /// Revoke a capability. Path capabilities revoke everything beneath the path,
+    /// even when a broader path is granted.
+    pub fn deny_capability(&mut self, capability: Capability) {
+        self.denied_capabilities.insert(capability);
+    }
+
+    /// Check if a capability is covered by a deny
+    pub fn is_denied(&self, capability: &Capability) -> bool {
+        self.denied_capabilities.iter().any(|denied| denied.covers(capability))
+    }
+
+    /// Whether HTTP requests may reach any host (still subject to `allowed_network_hosts`)
+    pub fn network_unrestricted(&self) -> bool {
+        (self.permitted_network_access || self.capabilities.contains(&Capability::NetworkHTTP))
+            && !self.is_denied(&Capability::NetworkHTTP)
+    }
+
+    /// Whether HTTP requests are allowed at all, to any host or to specific ones
+    pub fn network_enabled(&self) -> bool {
+        self.network_unrestricted() || !self.network_rules().is_empty()
+    }
+
+    /// Granted `Network` capabilities as (host pattern, port) pairs
+    pub fn network_rules(&self) -> Vec<(&str, Option<u16>)> {
+        let mut rules: Vec<(&str, Option<u16>)> = self
+            .capabilities
+            .iter()
+            .filter(|capability| !self.is_denied(capability))
+            .filter_map(|capability| match capability {
+                Capability::Network { host_pattern, port } => Some((host_pattern.as_str(), *port)),
+                _ => None,
+            })
+            .collect();
+        rules.sort();
+        rules
+    }
+
+    /// Granted capabilities that a deny fully or partially revokes, as (granted, denied) pairs
+    pub fn conflicts(&self) -> Vec<(Capability, Capability)> {
+        let mut conflicts = Vec::new();
+        for granted in &self.capabilities {
+            for denied in &self.denied_capabilities {
+                let overlaps = match (granted, denied) {
+                    (Capability::FileRead(g), Capability::FileRead(d))
+                    | (Capability::FileWrite(g), Capability::FileWrite(d)) => {
+                        path_matches(d, g) || path_matches(g, d)
+                    }
+                    (Capability::NetworkHTTP | Capability::Network { .. }, Capability::NetworkHTTP)
+                    | (Capability::NetworkHTTP, Capability::Network { .. }) => true,
+                    (
+                        Capability::Network { host_pattern: g, port: g_port },
+                        Capability::Network { host_pattern: d, port: d_port },
+                    ) => {
+                        network_rule_matches(d, *d_port, g, *g_port)
+                            || network_rule_matches(g, *g_port, d, *d_port)
+                    }
+                    (Capability::EnvRead(g), Capability::EnvRead(d)) | (Capability::Crate(g), Capability::Crate(d)) => {
+                        env_matches(d, g) || env_matches(g, d)
+                    }
+                    _ => granted == denied,
+                };
+                if overlaps {
+                    conflicts.push((granted.clone(), denied.clone()));
+                }
+            }
+        }
+        if self.permitted_network_access
+            && self.denied_capabilities.contains(&Capability::NetworkHTTP)
+            && !self.capabilities.contains(&Capability::NetworkHTTP)
+        {
+            conflicts.push((Capability::NetworkHTTP, Capability::NetworkHTTP));
+        }
+        conflicts.sort_by_key(|(g, d)| (g.to_string(), d.to_string()));
+        conflicts
     }
 
     /// Set maximum memory limit in bytes
@@ -90,6 +254,24 @@ impl SandboxConfig {
         self
     }
 
+    /// Set the fuel budget in evaluated forms
+    pub fn with_max_fuel(mut self, fuel: u64) -> Self {
+        self.max_fuel = Some(fuel);
+        self
+    }
+
+    /// Set the number of nodes one macro call may generate
+    pub fn with_max_expansion_nodes(mut self, nodes: usize) -> Self {
+        self.max_expansion_nodes = Some(nodes);
+        self
+    }
+
+    /// Record every capability check to a JSONL audit log
+    pub fn with_audit_log(mut self, path: PathBuf) -> Self {
+        self.audit_log = Some(path);
+        self
+    }
+
     /// Add an allowed file path
     pub fn allow_file_path(mut self, path: PathBuf) -> Self {
         self.allowed_file_paths.push(path);
@@ -101,6 +283,134 @@ impl SandboxConfig {
         self.permitted_network_access = enabled;
         self
     }
+
+    /// Restrict network access to a host (or `*.domain` pattern)
+    pub fn allow_network_host(mut self, host: String) -> Self {
+        self.allowed_network_hosts.push(host);
+        self
+    }
+}
+
+/// Parse memory size string (e.g., "100MB", "1GB", "512KB") into bytes
+pub fn parse_memory_size(s: &str) -> Result<usize, String> {
+    let s = s.trim().to_uppercase();
+
+    // Try to extract number and unit
+    let (num_str, unit) = if let Some(pos) = s.find(|c: char| c.is_alphabetic()) {
+        s.split_at(pos)
+    } else {
+        // No unit specified, assume bytes
+        return s.parse::<usize>()
+            .map_err(|e| format!("Invalid memory size: {}", e));
+    };
+
+    let num: usize = num_str.trim().parse()
+        .map_err(|e| format!("Invalid memory size number: {}", e))?;
+
+    let multiplier = match unit.trim() {
+        "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        other => return Err(format!("Unknown memory unit: {}", other)),
+    };
+
+    Ok(num * multiplier)
+}
+
+/// Parse duration string (e.g., "30s", "5m", "1h") into Duration
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+
+    // Try to extract number and unit
+    let (num_str, unit) = if let Some(pos) = s.find(|c: char| c.is_alphabetic()) {
+        s.split_at(pos)
+    } else {
+        // No unit specified, assume seconds
+        let secs: u64 = s.parse()
+            .map_err(|e| format!("Invalid duration: {}", e))?;
+        return Ok(Duration::from_secs(secs));
+    };
+
+    let num: u64 = num_str.trim().parse()
+        .map_err(|e| format!("Invalid duration number: {}", e))?;
+
+    match unit.trim() {
+        "s" | "sec" | "secs" => Ok(Duration::from_secs(num)),
+        "m" | "min" | "mins" => Ok(Duration::from_secs(num * 60)),
+        "h" | "hour" | "hours" => Ok(Duration::from_secs(num * 3600)),
+        other => Err(format!("Unknown duration unit: {}", other)),
+    }
+}
+
+/// Match a path against an allowed path. Plain paths allow everything beneath them;
+/// patterns may use `*` and `?` within a component and `**` across components.
+/// Paths containing `..` never match.
+pub fn path_matches(pattern: &Path, path: &Path) -> bool {
+    if path.components().any(|c| matches!(c, Component::ParentDir)) {
+        return false;
+    }
+    let pattern_str = pattern.to_string_lossy();
+    if pattern_str.contains(['*', '?']) {
+        glob_match(pattern_str.as_bytes(), path.to_string_lossy().as_bytes())
+    } else {
+        path.starts_with(pattern)
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            (0..=text.len()).any(|i| glob_match(&pattern[2..], &text[i..]))
+        }
+        Some(b'*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        Some(b'?') => !text.is_empty() && text[0] != b'/' && glob_match(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Match an environment variable name against an exact name or glob (`*`, `?`)
+pub fn env_matches(pattern: &str, name: &str) -> bool {
+    glob_match(pattern.as_bytes(), name.as_bytes())
+}
+
+include!("host_match.rs");
+
+/// Match a host and port against a `Network` rule; a rule without a port allows any port
+pub fn network_rule_matches(pattern: &str, rule_port: Option<u16>, host: &str, port: Option<u16>) -> bool {
+    host_matches(pattern, host) && rule_port.is_none_or(|rule_port| port == Some(rule_port))
+}
+
+/// Port a URL connects to: the explicit port, or the scheme's default for http/https
+pub fn url_port(url: &str) -> Option<u16> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host_port = authority.rsplit('@').next()?;
+    match host_port.split_once(':') {
+        Some((_, port)) => port.parse().ok(),
+        None => match scheme.to_lowercase().as_str() {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        },
+    }
+}
+
+/// Whether `url` names an `http` or `https` scheme, the only ones `http-get` and
+/// `http-post` may use: curl would read `file://` and others without a network
+pub fn is_http_url(url: &str) -> bool {
+    url.split_once("://").is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))
+}
+
+/// Extract the host from a URL such as `https://user@api.example.com:443/path`
+pub fn url_host(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if host.is_empty() { None } else { Some(host) }
 }
 
 impl Default for SandboxConfig {
@@ -109,6 +419,76 @@ impl Default for SandboxConfig {
     }
 }
 
+/// Built-in sandbox presets selectable with `--sandbox-profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxProfile {
+    /// Small limits and no capabilities
+    Strict,
+    /// Default limits with system time and the temp directory
+    Standard,
+    /// Generous limits with file, network, time, and process access (never unsafe Rust)
+    Permissive,
+}
+
+impl SandboxProfile {
+    pub const ALL: [SandboxProfile; 3] = [SandboxProfile::Strict, SandboxProfile::Standard, SandboxProfile::Permissive];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "strict" => Ok(SandboxProfile::Strict),
+            "standard" => Ok(SandboxProfile::Standard),
+            "permissive" => Ok(SandboxProfile::Permissive),
+            other => Err(format!("Unknown sandbox profile: {} (expected strict, standard, or permissive)", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SandboxProfile::Strict => "strict",
+            SandboxProfile::Standard => "standard",
+            SandboxProfile::Permissive => "permissive",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            SandboxProfile::Strict => "32MB, 5s, no capabilities",
+            SandboxProfile::Standard => "100MB, 30s, SystemTime, read/write the temp directory",
+            SandboxProfile::Permissive => "1GB, 5m, read anywhere, write temp, network, processes, threads",
+        }
+    }
+
+    /// The sandbox configuration this profile stands for
+    pub fn config(&self) -> SandboxConfig {
+        let temp_dir = std::env::temp_dir();
+        match self {
+            SandboxProfile::Strict => SandboxConfig::new()
+                .with_max_memory(32 * 1024 * 1024)
+                .with_max_execution_time(Duration::from_secs(5)),
+            SandboxProfile::Standard => {
+                let mut config = SandboxConfig::new();
+                config.add_capability(Capability::SystemTime);
+                config.add_capability(Capability::FileRead(temp_dir.clone()));
+                config.add_capability(Capability::FileWrite(temp_dir));
+                config
+            }
+            SandboxProfile::Permissive => {
+                let mut config = SandboxConfig::new()
+                    .with_max_memory(1024 * 1024 * 1024)
+                    .with_max_execution_time(Duration::from_secs(300))
+                    .with_network_access(true);
+                config.add_capability(Capability::SystemTime);
+                config.add_capability(Capability::FileRead(PathBuf::from("/")));
+                config.add_capability(Capability::FileWrite(temp_dir));
+                config.add_capability(Capability::NetworkHTTP);
+                config.add_capability(Capability::ProcessSpawn);
+                config.add_capability(Capability::ThreadSpawn);
+                config
+            }
+        }
+    }
+}
+
 /// Represents a violation of sandbox security boundaries
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SandboxViolation {
@@ -122,12 +502,33 @@ pub enum SandboxViolation {
         limit: Duration,
         elapsed: Duration,
     },
+    /// Evaluated more forms than the fuel budget allows
+    FuelExhausted {
+        limit: u64,
+    },
+    /// A macro call generated more nodes than allowed
+    ExpansionTooLarge {
+        limit: usize,
+        nodes: usize,
+    },
     /// Attempted to access unauthorized file path
     UnauthorizedFileAccess {
         path: PathBuf,
     },
     /// Attempted network access without permission
     UnauthorizedNetworkAccess,
+    /// Attempted network access to a host outside the allowlist
+    UnauthorizedNetworkHost {
+        host: String,
+    },
+    /// Attempted network access with a URL whose scheme is not `http` or `https`
+    UnsupportedUrlScheme {
+        url: String,
+    },
+    /// Attempted to read an environment variable without an `EnvRead` capability
+    UnauthorizedEnvAccess {
+        name: String,
+    },
     /// Attempted to use unsafe Rust feature without permission
     UnsafeRustNotPermitted,
     /// Attempted to spawn process without permission
@@ -159,12 +560,27 @@ impl std::fmt::Display for SandboxViolation {
                     limit, elapsed
                 )
             }
+            SandboxViolation::FuelExhausted { limit } => {
+                write!(f, "Fuel exhausted: limit={} forms", limit)
+            }
+            SandboxViolation::ExpansionTooLarge { limit, nodes } => {
+                write!(f, "Macro expansion too large: limit={} nodes, generated={} nodes", limit, nodes)
+            }
             SandboxViolation::UnauthorizedFileAccess { path } => {
                 write!(f, "Unauthorized file access: {}", path.display())
             }
             SandboxViolation::UnauthorizedNetworkAccess => {
                 write!(f, "Unauthorized network access attempted")
             }
+            SandboxViolation::UnauthorizedNetworkHost { host } => {
+                write!(f, "Unauthorized network host: {}", host)
+            }
+            SandboxViolation::UnsupportedUrlScheme { url } => {
+                write!(f, "Only http and https URLs are allowed: {}", url)
+            }
+            SandboxViolation::UnauthorizedEnvAccess { name } => {
+                write!(f, "Unauthorized environment variable access: {}", name)
+            }
             SandboxViolation::UnsafeRustNotPermitted => {
                 write!(f, "Unsafe Rust features not permitted in sandbox mode")
             }
@@ -183,21 +599,114 @@ impl std::fmt::Display for SandboxViolation {
 
 impl std::error::Error for SandboxViolation {}
 
-/// Runtime monitor for sandbox execution
+/// One capability check, as written to the audit log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    /// Which check ran: `file_access`, `network_access`, `env_access`, `capability`, or `api_usage`
+    pub check: String,
+    /// Capability, path, URL, or API that was checked
+    pub capability: String,
+    /// `allow` or `deny`
+    pub decision: String,
+    /// Lisp source of the form that triggered the check, when known
+    pub form: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Runtime monitor for sandbox execution, and for macro expansion at compile time
+#[derive(Debug)]
 pub struct SandboxMonitor {
     config: SandboxConfig,
     start_time: Instant,
     current_memory: usize,
+    fuel_used: u64,
+    /// Recorded checks; `None` when auditing is off
+    audit: Option<RefCell<Vec<AuditEntry>>>,
+    current_form: Option<String>,
+    /// Capabilities declared by the functions currently executing, innermost last
+    scopes: Vec<Vec<Capability>>,
 }
 
 impl SandboxMonitor {
     /// Create a new sandbox monitor with the given configuration
     pub fn new(config: SandboxConfig) -> Self {
+        let audit = config.audit_log.as_ref().map(|_| RefCell::new(Vec::new()));
         SandboxMonitor {
             config,
             start_time: Instant::now(),
             current_memory: 0,
+            fuel_used: 0,
+            audit,
+            current_form: None,
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SandboxConfig {
+        &self.config
+    }
+
+    /// Enter a function that declared `capabilities`; until the matching `exit_scope`
+    /// checks must pass both the config and this declaration
+    pub fn enter_scope(&mut self, capabilities: Vec<Capability>) {
+        self.scopes.push(capabilities);
+    }
+
+    pub fn exit_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Whether every active function scope declared a capability covering `requested`,
+    /// so a callee's declaration can only narrow its caller's
+    fn scope_allows(&self, requested: &Capability) -> bool {
+        self.scopes
+            .iter()
+            .all(|declared| declared.iter().any(|capability| capability.covers(requested)))
+    }
+
+    /// Record every check, even without a configured audit log path
+    pub fn with_audit(mut self) -> Self {
+        self.audit.get_or_insert_with(|| RefCell::new(Vec::new()));
+        self
+    }
+
+    /// Set the form that subsequent checks are attributed to
+    pub fn set_current_form(&mut self, form: Option<String>) {
+        self.current_form = form;
+    }
+
+    /// Checks recorded so far
+    pub fn audit_entries(&self) -> Vec<AuditEntry> {
+        self.audit.as_ref().map(|entries| entries.borrow().clone()).unwrap_or_default()
+    }
+
+    /// Append the recorded checks to `path`, one JSON object per line
+    pub fn write_audit_log(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        for entry in self.audit_entries() {
+            writeln!(file, "{}", entry.to_json_line())?;
         }
+        Ok(())
+    }
+
+    fn record(&self, check: &str, capability: String, result: Result<(), SandboxViolation>) -> Result<(), SandboxViolation> {
+        if let Some(entries) = &self.audit {
+            entries.borrow_mut().push(AuditEntry {
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+                check: check.to_string(),
+                capability,
+                decision: if result.is_ok() { "allow" } else { "deny" }.to_string(),
+                form: self.current_form.clone(),
+            });
+        }
+        result
     }
 
     /// Check if execution time limit has been exceeded
@@ -231,42 +740,139 @@ impl SandboxMonitor {
         Ok(())
     }
 
+    /// Consume fuel for evaluated forms, failing once the budget is spent
+    pub fn consume_fuel(&mut self, amount: u64) -> Result<(), SandboxViolation> {
+        self.fuel_used = self.fuel_used.saturating_add(amount);
+        match self.config.max_fuel {
+            Some(limit) if self.fuel_used > limit => Err(SandboxViolation::FuelExhausted { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that a macro call generating `nodes` nodes stays within `max_expansion_nodes`
+    pub fn check_expansion_size(&self, nodes: usize) -> Result<(), SandboxViolation> {
+        match self.config.max_expansion_nodes {
+            Some(limit) if nodes > limit => Err(SandboxViolation::ExpansionTooLarge { limit, nodes }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fuel consumed so far
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used
+    }
+
     /// Record a memory deallocation
     pub fn deallocate_memory(&mut self, size: usize) {
         self.current_memory = self.current_memory.saturating_sub(size);
     }
 
     /// Check if file path access is allowed
-    pub fn check_file_access(&self, path: &PathBuf) -> Result<(), SandboxViolation> {
+    pub fn check_file_access(&self, path: &Path) -> Result<(), SandboxViolation> {
+        let denied = self.config.is_denied(&Capability::FileRead(path.to_path_buf()))
+            || self.config.is_denied(&Capability::FileWrite(path.to_path_buf()));
         // Check if path matches any allowed paths
-        for allowed_path in &self.config.allowed_file_paths {
-            if path.starts_with(allowed_path) {
-                return Ok(());
-            }
+        let in_scope = self.scope_allows(&Capability::FileRead(path.to_path_buf()))
+            || self.scope_allows(&Capability::FileWrite(path.to_path_buf()));
+        let permitted = !denied
+            && in_scope
+            && self.config.allowed_file_paths.iter().any(|allowed| path_matches(allowed, path));
+        let result = if permitted {
+            Ok(())
+        } else {
+            Err(SandboxViolation::UnauthorizedFileAccess { path: path.to_path_buf() })
+        };
+        self.record("file_access", path.display().to_string(), result)
+    }
+
+    /// Check if reading `path` is allowed by an allowed file path or a `FileRead` capability
+    pub fn check_file_read(&self, path: &Path) -> Result<(), SandboxViolation> {
+        let requested = Capability::FileRead(path.to_path_buf());
+        let permitted = self.config.file_read_allowed(path) && self.scope_allows(&requested);
+        self.record_file_check(path, requested, permitted)
+    }
+
+    /// Check if writing `path` is allowed by an allowed file path or a `FileWrite` capability
+    pub fn check_file_write(&self, path: &Path) -> Result<(), SandboxViolation> {
+        let requested = Capability::FileWrite(path.to_path_buf());
+        let permitted = self.config.file_write_allowed(path) && self.scope_allows(&requested);
+        self.record_file_check(path, requested, permitted)
+    }
+
+    fn record_file_check(&self, path: &Path, requested: Capability, permitted: bool) -> Result<(), SandboxViolation> {
+        let result = if permitted {
+            Ok(())
+        } else {
+            Err(SandboxViolation::UnauthorizedFileAccess { path: path.to_path_buf() })
+        };
+        self.record("file_access", requested.to_string(), result)
+    }
+
+    /// Check if a request to `url` is allowed by the network settings, host allowlist,
+    /// and `Network` capabilities. Only `http` and `https` URLs are.
+    pub fn check_network_access(&self, url: &str) -> Result<(), SandboxViolation> {
+        if !self.config.network_enabled() {
+            return self.record("network_access", url.to_string(), Err(SandboxViolation::UnauthorizedNetworkAccess));
         }
-        Err(SandboxViolation::UnauthorizedFileAccess { path: path.clone() })
+        if !is_http_url(url) {
+            return self.record("network_access", url.to_string(), Err(SandboxViolation::UnsupportedUrlScheme { url: url.to_string() }));
+        }
+        let host = url_host(url).unwrap_or_default();
+        let port = url_port(url);
+        let requested = Capability::Network { host_pattern: host.to_string(), port };
+        let permitted = if self.config.network_unrestricted() {
+            self.config.allowed_network_hosts.is_empty()
+                || self.config.allowed_network_hosts.iter().any(|pattern| host_matches(pattern, host))
+        } else {
+            self.config
+                .network_rules()
+                .iter()
+                .any(|(pattern, rule_port)| network_rule_matches(pattern, *rule_port, host, port))
+        };
+        let result = if permitted && !self.config.is_denied(&requested) && self.scope_allows(&requested) {
+            Ok(())
+        } else {
+            Err(SandboxViolation::UnauthorizedNetworkHost { host: host.to_string() })
+        };
+        self.record("network_access", url.to_string(), result)
+    }
+
+    /// Check if reading the environment variable `name` is covered by an `EnvRead` capability
+    pub fn check_env_access(&self, name: &str) -> Result<(), SandboxViolation> {
+        let requested = Capability::EnvRead(name.to_string());
+        let granted = self.config.capabilities.iter().any(|capability| {
+            matches!(capability, Capability::EnvRead(pattern) if env_matches(pattern, name))
+        });
+        let result = if granted && !self.config.is_denied(&requested) && self.scope_allows(&requested) {
+            Ok(())
+        } else {
+            Err(SandboxViolation::UnauthorizedEnvAccess { name: name.to_string() })
+        };
+        self.record("env_access", requested.to_string(), result)
     }
 
     /// Check if a capability is granted
     pub fn check_capability(&self, capability: &Capability) -> Result<(), SandboxViolation> {
-        if self.config.has_capability(capability) {
+        let result = if self.config.has_capability(capability) && self.scope_allows(capability) {
             Ok(())
         } else {
             Err(SandboxViolation::MissingCapability {
                 capability: capability.clone(),
             })
-        }
+        };
+        self.record("capability", capability.to_string(), result)
     }
 
     /// Check if an API is allowed
     pub fn check_api_usage(&self, api: &str) -> Result<(), SandboxViolation> {
-        if self.config.safe_rust_apis.contains(api) {
+        let result = if self.config.safe_rust_apis.contains(api) {
             Ok(())
         } else {
             Err(SandboxViolation::DisallowedAPIUsage {
                 api: api.to_string(),
             })
-        }
+        };
+        self.record("api_usage", api.to_string(), result)
     }
 
     /// Get current memory usage
@@ -318,6 +924,218 @@ mod tests {
         assert!(config.has_capability(&cap));
     }
 
+    #[test]
+    fn test_sandbox_profiles() {
+        let strict = SandboxProfile::parse("strict").unwrap().config();
+        assert_eq!(strict.max_memory, 32 * 1024 * 1024);
+        assert!(strict.capabilities.is_empty());
+
+        let standard = SandboxProfile::parse("Standard").unwrap().config();
+        assert!(standard.has_capability(&Capability::SystemTime));
+        assert!(!standard.has_capability(&Capability::NetworkHTTP));
+
+        let permissive = SandboxProfile::parse("permissive").unwrap().config();
+        assert!(permissive.has_capability(&Capability::ProcessSpawn));
+        assert!(!permissive.has_capability(&Capability::UnsafeRust));
+
+        assert!(SandboxProfile::parse("yolo").is_err());
+    }
+
+    #[test]
+    fn test_deny_overrides_grants() {
+        let mut config = SandboxProfile::Permissive.config();
+        config.deny_capability(Capability::ProcessSpawn);
+        config.deny_capability(Capability::NetworkHTTP);
+        config.deny_capability(Capability::FileRead(PathBuf::from("/etc")));
+
+        assert!(!config.has_capability(&Capability::ProcessSpawn));
+        assert!(!config.network_enabled());
+        assert!(config.is_denied(&Capability::FileRead(PathBuf::from("/etc/passwd"))));
+        assert!(!config.is_denied(&Capability::FileRead(PathBuf::from("/home"))));
+
+        let monitor = SandboxMonitor::new(config.clone());
+        assert!(monitor.check_capability(&Capability::ProcessSpawn).is_err());
+        assert_eq!(
+            monitor.check_network_access("https://example.com"),
+            Err(SandboxViolation::UnauthorizedNetworkAccess)
+        );
+
+        let conflicts = config.conflicts();
+        assert!(conflicts.contains(&(Capability::ProcessSpawn, Capability::ProcessSpawn)));
+        assert!(conflicts.contains(&(
+            Capability::FileRead(PathBuf::from("/")),
+            Capability::FileRead(PathBuf::from("/etc"))
+        )));
+    }
+
+    #[test]
+    fn test_deny_blocks_allowed_file_paths() {
+        let mut config = SandboxConfig::new().allow_file_path(PathBuf::from("/data"));
+        config.deny_capability(Capability::FileRead(PathBuf::from("/data/secrets")));
+        let monitor = SandboxMonitor::new(config);
+
+        assert!(monitor.check_file_access(Path::new("/data/public.txt")).is_ok());
+        assert!(monitor.check_file_access(Path::new("/data/secrets/key")).is_err());
+    }
+
+    #[test]
+    fn test_network_capability_scopes_host_and_port() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::Network { host_pattern: "api.example.com".to_string(), port: Some(443) });
+        config.add_capability(Capability::Network { host_pattern: "*.internal".to_string(), port: None });
+        let monitor = SandboxMonitor::new(config.clone());
+
+        assert!(monitor.check_network_access("https://api.example.com/v1").is_ok());
+        assert!(monitor.check_network_access("http://api.example.com/v1").is_err());
+        assert!(monitor.check_network_access("https://other.example.com").is_err());
+        assert!(monitor.check_network_access("http://db.internal:5984").is_ok());
+        assert!(monitor.check_capability(&Capability::NetworkHTTP).is_err());
+
+        config.deny_capability(Capability::Network { host_pattern: "db.internal".to_string(), port: None });
+        let monitor = SandboxMonitor::new(config);
+        assert!(monitor.check_network_access("http://db.internal:5984").is_err());
+        assert!(monitor.check_network_access("http://cache.internal").is_ok());
+    }
+
+    #[test]
+    fn test_network_deny_narrows_network_http() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::NetworkHTTP);
+        config.deny_capability(Capability::Network { host_pattern: "evil.com".to_string(), port: None });
+        let monitor = SandboxMonitor::new(config.clone());
+
+        assert!(monitor.check_network_access("https://example.com").is_ok());
+        assert!(monitor.check_network_access("https://evil.com").is_err());
+        assert_eq!(config.conflicts().len(), 1);
+    }
+
+    #[test]
+    fn test_function_scopes_narrow_grants() {
+        let mut config = SandboxConfig::new().allow_file_path(PathBuf::from("/data"));
+        config.add_capability(Capability::ProcessSpawn);
+        let mut monitor = SandboxMonitor::new(config);
+
+        monitor.enter_scope(vec![Capability::FileRead(PathBuf::from("/data/in"))]);
+        assert!(monitor.check_file_access(Path::new("/data/in/a.csv")).is_ok());
+        assert!(monitor.check_file_access(Path::new("/data/other")).is_err());
+        assert!(monitor.check_capability(&Capability::ProcessSpawn).is_err());
+
+        // Scopes never widen what the config grants, nor what an enclosing scope declared
+        monitor.enter_scope(vec![Capability::FileRead(PathBuf::from("/etc")), Capability::ProcessSpawn]);
+        assert!(monitor.check_file_access(Path::new("/etc/passwd")).is_err());
+        assert!(monitor.check_capability(&Capability::ProcessSpawn).is_err());
+        monitor.exit_scope();
+        monitor.exit_scope();
+        assert!(monitor.check_file_access(Path::new("/data/other")).is_ok());
+
+        monitor.enter_scope(vec![Capability::ProcessSpawn]);
+        assert!(monitor.check_capability(&Capability::ProcessSpawn).is_ok());
+        monitor.exit_scope();
+    }
+
+    #[test]
+    fn test_capability_covers() {
+        let data = Capability::FileRead(PathBuf::from("/data"));
+        assert!(data.covers(&Capability::FileRead(PathBuf::from("/data/a"))));
+        assert!(!data.covers(&Capability::FileWrite(PathBuf::from("/data/a"))));
+        assert!(Capability::NetworkHTTP.covers(&Capability::Network { host_pattern: "a.com".to_string(), port: None }));
+        assert!(Capability::EnvRead("APP_*".to_string()).covers(&Capability::EnvRead("APP_X".to_string())));
+        assert!(!Capability::SystemTime.covers(&Capability::ProcessSpawn));
+    }
+
+    #[test]
+    fn test_audit_records_every_check() {
+        let config = SandboxConfig::new().allow_file_path(PathBuf::from("/data"));
+        let mut monitor = SandboxMonitor::new(config).with_audit();
+        monitor.set_current_form(Some("(read-file \"/data/a\")".to_string()));
+        assert!(monitor.check_file_access(Path::new("/data/a")).is_ok());
+        monitor.set_current_form(None);
+        assert!(monitor.check_capability(&Capability::ProcessSpawn).is_err());
+        assert!(monitor.check_api_usage("std::vec::Vec").is_ok());
+
+        let entries = monitor.audit_entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].check, "file_access");
+        assert_eq!(entries[0].decision, "allow");
+        assert_eq!(entries[0].form.as_deref(), Some("(read-file \"/data/a\")"));
+        assert_eq!(entries[1].capability, "ProcessSpawn");
+        assert_eq!(entries[1].decision, "deny");
+
+        let line: serde_json::Value = serde_json::from_str(&entries[1].to_json_line()).unwrap();
+        assert_eq!(line["decision"], "deny");
+        assert!(line["form"].is_null());
+    }
+
+    #[test]
+    fn test_audit_off_by_default() {
+        let monitor = SandboxMonitor::new(SandboxConfig::new());
+        assert!(monitor.check_capability(&Capability::SystemTime).is_err());
+        assert!(monitor.audit_entries().is_empty());
+    }
+
+    #[test]
+    fn test_fuel_budget() {
+        let mut monitor = SandboxMonitor::new(SandboxConfig::new().with_max_fuel(10));
+        assert!(monitor.consume_fuel(10).is_ok());
+        assert_eq!(monitor.consume_fuel(1), Err(SandboxViolation::FuelExhausted { limit: 10 }));
+        assert_eq!(monitor.fuel_used(), 11);
+
+        let mut unmetered = SandboxMonitor::new(SandboxConfig::new());
+        assert!(unmetered.consume_fuel(u64::MAX).is_ok());
+        assert!(unmetered.check_expansion_size(usize::MAX).is_ok());
+
+        let monitor = SandboxMonitor::new(SandboxConfig::new().with_max_expansion_nodes(100));
+        assert!(monitor.check_expansion_size(100).is_ok());
+        assert_eq!(monitor.check_expansion_size(101), Err(SandboxViolation::ExpansionTooLarge { limit: 100, nodes: 101 }));
+    }
+
+    #[test]
+    fn test_env_read_capability() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("HOME".to_string()));
+        config.add_capability(Capability::EnvRead("APP_*".to_string()));
+        config.deny_capability(Capability::EnvRead("APP_SECRET".to_string()));
+        let monitor = SandboxMonitor::new(config.clone());
+
+        assert!(monitor.check_env_access("HOME").is_ok());
+        assert!(monitor.check_env_access("APP_PORT").is_ok());
+        assert_eq!(
+            monitor.check_env_access("APP_SECRET"),
+            Err(SandboxViolation::UnauthorizedEnvAccess { name: "APP_SECRET".to_string() })
+        );
+        assert!(monitor.check_env_access("PATH").is_err());
+        assert_eq!(config.conflicts().len(), 1);
+        assert_eq!(Capability::EnvRead("APP_*".to_string()).to_string(), "EnvRead:APP_*");
+    }
+
+    #[test]
+    fn test_crate_capabilities() {
+        let mut config = SandboxConfig::new();
+        assert!(!config.crate_allowed("regex"));
+        config.add_capability(Capability::Crate("serde*".to_string()));
+        config.deny_capability(Capability::Crate("serde-yaml".to_string()));
+        assert!(config.crate_allowed("serde-json"));
+        assert!(!config.crate_allowed("serde-yaml"));
+        assert!(!config.crate_allowed("regex"));
+        assert_eq!(config.conflicts().len(), 1);
+        assert_eq!(Capability::Crate("serde*".to_string()).to_string(), "Crate:serde*");
+    }
+
+    #[test]
+    fn test_url_port() {
+        assert_eq!(url_port("https://example.com/a"), Some(443));
+        assert_eq!(url_port("http://example.com"), Some(80));
+        assert_eq!(url_port("http://user@example.com:8080/a"), Some(8080));
+        assert_eq!(url_port("example.com"), None);
+    }
+
+    #[test]
+    fn test_capability_display() {
+        assert_eq!(Capability::FileRead(PathBuf::from("/tmp")).to_string(), "FileRead:/tmp");
+        assert_eq!(Capability::ProcessSpawn.to_string(), "ProcessSpawn");
+        assert_eq!(Capability::ThreadSpawn.to_string(), "ThreadSpawn");
+    }
+
     #[test]
     fn test_sandbox_monitor_time_limit() {
         let config = SandboxConfig::new()
@@ -363,6 +1181,81 @@ mod tests {
         assert!(monitor.check_file_access(&PathBuf::from("/etc/passwd")).is_err());
     }
 
+    #[test]
+    fn test_file_read_and_write_checks() {
+        let mut config = SandboxConfig::new().allow_file_path(PathBuf::from("/tmp"));
+        config.add_capability(Capability::FileRead(PathBuf::from("/etc")));
+        config.add_capability(Capability::FileWrite(PathBuf::from("/out")));
+        config.deny_capability(Capability::FileWrite(PathBuf::from("/tmp/locked")));
+        let monitor = SandboxMonitor::new(config);
+
+        // Allowed paths grant both; capabilities only what they name
+        assert!(monitor.check_file_read(Path::new("/tmp/a")).is_ok());
+        assert!(monitor.check_file_write(Path::new("/tmp/a")).is_ok());
+        assert!(monitor.check_file_read(Path::new("/etc/hosts")).is_ok());
+        assert!(monitor.check_file_write(Path::new("/etc/hosts")).is_err());
+        assert!(monitor.check_file_write(Path::new("/out/report.txt")).is_ok());
+        assert!(monitor.check_file_read(Path::new("/out/report.txt")).is_err());
+        assert!(monitor.check_file_read(Path::new("/tmp/locked/a")).is_ok());
+        assert!(monitor.check_file_write(Path::new("/tmp/locked/a")).is_err());
+        assert!(monitor.check_file_read(Path::new("/etc/../root/key")).is_err());
+    }
+
+    #[test]
+    fn test_file_access_globs() {
+        let config = SandboxConfig::new()
+            .allow_file_path(PathBuf::from("/data/*.csv"))
+            .allow_file_path(PathBuf::from("/logs/**/app.log"));
+        let monitor = SandboxMonitor::new(config);
+
+        assert!(monitor.check_file_access(Path::new("/data/a.csv")).is_ok());
+        assert!(monitor.check_file_access(Path::new("/data/sub/a.csv")).is_err());
+        assert!(monitor.check_file_access(Path::new("/logs/2024/01/app.log")).is_ok());
+        assert!(monitor.check_file_access(Path::new("/logs/app.txt")).is_err());
+    }
+
+    #[test]
+    fn test_file_access_rejects_parent_dir() {
+        let config = SandboxConfig::new().allow_file_path(PathBuf::from("/tmp"));
+        let monitor = SandboxMonitor::new(config);
+
+        assert!(monitor.check_file_access(Path::new("/tmp/../etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_network_host_allowlist() {
+        let config = SandboxConfig::new()
+            .with_network_access(true)
+            .allow_network_host("api.example.com".to_string())
+            .allow_network_host("*.example.org".to_string());
+        let monitor = SandboxMonitor::new(config);
+
+        assert!(monitor.check_network_access("https://api.example.com/v1").is_ok());
+        assert!(monitor.check_network_access("http://cdn.example.org:8080/x").is_ok());
+        assert_eq!(
+            monitor.check_network_access("https://evil.com/"),
+            Err(SandboxViolation::UnauthorizedNetworkHost { host: "evil.com".to_string() })
+        );
+        // curl would read these without touching the network
+        for url in ["file:///etc/passwd", "gopher://api.example.com", "dict://api.example.com:2628", "api.example.com"] {
+            assert_eq!(monitor.check_network_access(url), Err(SandboxViolation::UnsupportedUrlScheme { url: url.to_string() }));
+        }
+        assert!(monitor.check_network_access("HTTPS://api.example.com").is_ok());
+
+        let offline = SandboxMonitor::new(SandboxConfig::new());
+        assert_eq!(
+            offline.check_network_access("https://api.example.com"),
+            Err(SandboxViolation::UnauthorizedNetworkAccess)
+        );
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(url_host("https://user@Example.com:443/a?b"), Some("Example.com"));
+        assert_eq!(url_host("example.com/path"), Some("example.com"));
+        assert_eq!(url_host("https:///path"), None);
+    }
+
     #[test]
     fn test_capability_check() {
         let mut config = SandboxConfig::new();
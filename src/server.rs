@@ -0,0 +1,262 @@
+use crate::parser::Reader;
+use crate::visualizer::{escape_html, HtmlVisualizer};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Milliseconds between the page's checks for a changed file
+const RELOAD_INTERVAL_MS: u32 = 1000;
+
+/// Serves the HTML visualizer for one Lisp file, re-reading it on every request.
+///
+/// Routes: `/` (the visualization), `/source` (the file with `#L<n>` line anchors
+/// that nodes link to), and `/version` (a token the page polls to reload itself).
+pub struct VisualizerServer {
+    path: PathBuf,
+    listener: TcpListener,
+    reader: Reader,
+}
+
+/// A token that changes whenever the file at `path` is modified
+pub fn file_version(path: &Path) -> String {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+            format!("{}-{}", modified.map_or(0, |time| time.as_nanos()), metadata.len())
+        }
+        Err(_) => "missing".to_string(),
+    }
+}
+
+/// An HTTP response before serialization
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl VisualizerServer {
+    /// Listen on localhost; port 0 picks a free port
+    pub fn bind(path: impl Into<PathBuf>, port: u16) -> Result<Self, String> {
+        let listener =
+            TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+        Ok(VisualizerServer { path: path.into(), listener, reader: Reader::new() })
+    }
+
+    /// Read the file with `reader`'s features
+    pub fn with_reader(mut self, reader: Reader) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.listener.local_addr().map_err(|e| e.to_string())
+    }
+
+    /// Handle connections until the process exits
+    pub fn run(&self) -> Result<(), String> {
+        for stream in self.listener.incoming() {
+            let stream = stream.map_err(|e| e.to_string())?;
+            // A client hanging up mid-request should not stop the server
+            let _ = self.handle(stream);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let response = match request_line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["GET", target, ..] => self.route(target),
+            _ => Response { status: 405, content_type: "text/plain", body: "Only GET is supported\n".to_string() },
+        };
+        write_response(&stream, &response)
+    }
+
+    /// Build the response for a request target
+    pub fn route(&self, target: &str) -> Response {
+        let path = target.split(['?', '#']).next().unwrap_or("");
+        let html = |body| Response { status: 200, content_type: "text/html; charset=utf-8", body };
+        match path {
+            "/" => html(self.visualization_page()),
+            "/source" => match fs::read_to_string(&self.path) {
+                Ok(source) => html(source_page(&self.path, &source)),
+                Err(err) => self.read_error(err),
+            },
+            "/version" => Response { status: 200, content_type: "text/plain", body: file_version(&self.path) },
+            _ => Response { status: 404, content_type: "text/plain", body: format!("Not found: {}\n", path) },
+        }
+    }
+
+    fn visualization_page(&self) -> String {
+        let source = match fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(err) => return with_live_reload(&error_page(&format!("Error reading file: {}", err))),
+        };
+        let parsed = self.reader.read_with_span_trees(&source);
+        let page = match parsed {
+            Ok(parsed) => {
+                let (exprs, spans): (Vec<_>, Vec<_>) = parsed.into_iter().unzip();
+                let spans = spans.into_iter().map(|tree| tree.with_lines(&source)).collect();
+                HtmlVisualizer::new()
+                    .with_spans(spans)
+                    .with_source(source)
+                    .with_source_href("/source")
+                    .visualize(&exprs)
+            }
+            Err(err) => error_page(&format!("Parse error: {}", err)),
+        };
+        with_live_reload(&page)
+    }
+
+    fn read_error(&self, err: std::io::Error) -> Response {
+        Response {
+            status: 500,
+            content_type: "text/plain",
+            body: format!("Error reading '{}': {}\n", self.path.display(), err),
+        }
+    }
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+/// Inject a script that polls `/version` and reloads the page when it changes
+fn with_live_reload(page: &str) -> String {
+    let script = format!(
+        r#"<script>
+(function() {{
+  let version = null;
+  setInterval(function() {{
+    fetch('/version').then(function(response) {{ return response.text(); }}).then(function(current) {{
+      if (version !== null && current !== version) location.reload();
+      version = current;
+    }}).catch(function() {{}});
+  }}, {});
+}})();
+</script>
+"#,
+        RELOAD_INTERVAL_MS
+    );
+    match page.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &page[..index], script, &page[index..]),
+        None => format!("{}{}", page, script),
+    }
+}
+
+fn error_page(message: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"UTF-8\">\n<title>AST Visualization</title>\n</head>\n<body>\n<pre style=\"color: #c0392b\">{}</pre>\n</body>\n</html>\n",
+        escape_html(message)
+    )
+}
+
+/// The source file with one anchored, numbered row per line
+fn source_page(path: &Path, source: &str) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"UTF-8\">\n<title>{}</title>\n<style>\n\
+         body {{ font-family: 'Courier New', monospace; }}\n\
+         .line {{ white-space: pre; }}\n\
+         .line:target {{ background: #fff3b0; }}\n\
+         .line a {{ display: inline-block; width: 4em; color: #999; text-decoration: none; }}\n\
+         </style>\n</head>\n<body>\n",
+        escape_html(&path.display().to_string())
+    );
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        page.push_str(&format!(
+            "<div class=\"line\" id=\"L{}\"><a href=\"#L{}\">{}</a>{}</div>\n",
+            number,
+            number,
+            number,
+            escape_html(line)
+        ));
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_file(contents: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lisp-serve-{}-{}.lisp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_routes() {
+        let path = temp_file("(+ 1\n   (* 2 3))");
+        let server = VisualizerServer::bind(&path, 0).unwrap();
+
+        let page = server.route("/");
+        assert_eq!(page.status, 200);
+        assert!(page.body.contains("<a class=\"source-link\" href=\"/source#L2\">2:4</a>"));
+        assert!(page.body.contains("fetch('/version')"));
+
+        let source = server.route("/source");
+        assert!(source.body.contains("<div class=\"line\" id=\"L2\"><a href=\"#L2\">2</a>   (* 2 3))</div>"));
+
+        let version = server.route("/version?t=1").body;
+        fs::write(&path, "(+ 1").unwrap();
+        assert_ne!(server.route("/version").body, version);
+        let broken = server.route("/");
+        assert!(broken.body.contains("Parse error"));
+        assert!(broken.body.contains("fetch('/version')"));
+
+        assert_eq!(server.route("/missing").status, 404);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_serves_http() {
+        let path = temp_file("(list 1 2)");
+        let server = VisualizerServer::bind(&path, 0).unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /source HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html"));
+        assert!(response.ends_with("(list 1 2)</div>\n</body>\n</html>\n"));
+        fs::remove_file(&path).unwrap();
+    }
+}
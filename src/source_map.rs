@@ -0,0 +1,246 @@
+//! Source maps from generated Rust back to the Lisp it came from.
+//!
+//! With `--source-map`, the code generated for each top-level form starts with a
+//! comment naming where the form is, and the macro it was expanded from when a macro
+//! call produced it: `// lisp: fact.lisp:3:1 (expanded from twice)`. Each form in a
+//! function body, which gets its own lines in the function, is marked the same way. `SourceMap::read`
+//! collects the comments back into a map from generated lines to Lisp locations, which
+//! `--source-map json` writes next to the code instead of keeping the comments.
+//!
+//! `run` always generates the comments, so when rustc rejects the program
+//! `render_rustc_diagnostics` can report its errors at the Lisp forms they came from.
+
+use crate::ast::SpanTree;
+use crate::include::Source;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Starts the comment marking the code generated from a form
+pub const MARKER: &str = "// lisp: ";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: String,
+    /// 1-based line and column
+    pub line: usize,
+    pub column: usize,
+    /// The macro whose expansion produced the form, which is then at the macro call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expanded_from: Option<String>,
+}
+
+impl SourceLocation {
+    /// Where the form `tree` describes is, in `source` or the file it included the
+    /// form from; `None` when it has no span
+    pub fn from_tree(source: &Source, tree: &SpanTree) -> Option<SourceLocation> {
+        let (file, span) = source.locate(tree.span?);
+        let (line, column) = file.line_col(span.start);
+        Some(SourceLocation { file: file.path.clone(), line, column, expanded_from: tree.expanded_from.clone() })
+    }
+
+    /// The marker comment, indented by `indent`
+    pub fn comment(&self, indent: &str) -> String {
+        format!("{}{}{}\n", indent, MARKER, self)
+    }
+
+    /// Read what `Display` writes
+    fn parse(text: &str) -> Option<SourceLocation> {
+        let (position, expanded_from) = match text.strip_suffix(')').and_then(|text| text.split_once(" (expanded from ")) {
+            Some((position, name)) => (position, Some(name.to_string())),
+            None => (text, None),
+        };
+        let mut parts = position.rsplitn(3, ':');
+        let column = parts.next()?.parse().ok()?;
+        let line = parts.next()?.parse().ok()?;
+        Some(SourceLocation { file: parts.next()?.to_string(), line, column, expanded_from })
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+        if let Some(name) = &self.expanded_from {
+            write!(f, " (expanded from {})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a top-level form and the forms directly in it are, the ones the generated
+/// code marks. The children mirror the form's `LispExpr::children`, like those of
+/// the `SpanTree` it is made from, and have none of their own.
+#[derive(Debug, Default)]
+pub struct LocationTree {
+    pub location: Option<SourceLocation>,
+    pub children: Vec<LocationTree>,
+}
+
+impl LocationTree {
+    /// The locations of the form `tree` describes and of its children, in `source`
+    pub fn new(source: &Source, tree: &SpanTree) -> Self {
+        let children = tree.children.iter().map(|child| LocationTree { location: SourceLocation::from_tree(source, child), children: Vec::new() }).collect();
+        LocationTree { location: SourceLocation::from_tree(source, tree), children }
+    }
+}
+
+/// The Lisp location of the generated lines from `line` up to the next mapping's
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// 1-based line in the generated code
+    pub line: usize,
+    pub source: SourceLocation,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// In order of `line`
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Remove the marker comments from `code`, returning the code without them and
+    /// the map of its lines
+    pub fn read(code: &str) -> (String, SourceMap) {
+        let mut stripped = String::new();
+        let mut map = SourceMap::default();
+        let mut pending = None;
+        let mut line = 0;
+        for text in code.lines() {
+            if let Some(location) = text.trim_start().strip_prefix(MARKER).and_then(SourceLocation::parse) {
+                pending = Some(location);
+                continue;
+            }
+            line += 1;
+            if let Some(source) = pending.take() {
+                map.mappings.push(Mapping { line, source });
+            }
+            stripped.push_str(text);
+            stripped.push('\n');
+        }
+        (stripped, map)
+    }
+
+    /// The location of the form generated line `line` belongs to. Lines before the
+    /// first mapping, such as runtime support code, have none.
+    pub fn lookup(&self, line: usize) -> Option<&SourceLocation> {
+        let index = self.mappings.partition_point(|mapping| mapping.line <= line);
+        index.checked_sub(1).map(|index| &self.mappings[index].source)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("source maps serialize")
+    }
+}
+
+/// The parts of a rustc `--error-format=json` diagnostic that are rendered
+#[derive(Debug, Deserialize)]
+struct RustcDiagnostic {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    spans: Vec<RustcSpan>,
+    children: Vec<RustcDiagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcSpan {
+    line_start: usize,
+    is_primary: bool,
+    label: Option<String>,
+    text: Vec<RustcSpanText>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcSpanText {
+    text: String,
+}
+
+/// The errors in rustc's JSON diagnostics `stderr`, one per line, for code whose lines
+/// `map` describes. An error in a mapped line is reported at its Lisp location as
+/// `file:line:column: error[code]: message`, followed by the generated line and
+/// rustc's labels, notes, and help; others keep rustc's own rendering. Warnings and
+/// rustc's closing summary are left out.
+pub fn render_rustc_diagnostics(stderr: &str, map: &SourceMap) -> String {
+    let mut out = String::new();
+    for line in stderr.lines() {
+        let Ok(diagnostic) = serde_json::from_str::<RustcDiagnostic>(line) else {
+            // Not a diagnostic, such as a linker's output
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        if diagnostic.level != "error" || diagnostic.spans.is_empty() && diagnostic.message.starts_with("aborting due to") {
+            continue;
+        }
+        let primary = diagnostic.spans.iter().find(|span| span.is_primary);
+        let Some((span, location)) = primary.and_then(|span| Some((span, map.lookup(span.line_start)?))) else {
+            out.push_str(diagnostic.rendered.as_deref().unwrap_or(&diagnostic.message).trim_end());
+            out.push('\n');
+            continue;
+        };
+        let code = diagnostic.code.as_ref().map_or(String::new(), |code| format!("[{}]", code.code));
+        out.push_str(&format!("{}:{}:{}: error{}: {}\n", location.file, location.line, location.column, code, diagnostic.message));
+        if let Some(name) = &location.expanded_from {
+            out.push_str(&format!("  in the expansion of {}\n", name));
+        }
+        if let Some(text) = span.text.first() {
+            out.push_str(&format!("  generated Rust: {}\n", text.text.trim()));
+        }
+        if let Some(label) = &span.label {
+            out.push_str(&format!("  {}\n", label));
+        }
+        for child in &diagnostic.children {
+            out.push_str(&format!("  {}: {}\n", child.level, child.message));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_and_lookup() {
+        let code = "mod support {}\n\nfn twice() {}\n\nfn main() {\n    // lisp: a:b.lisp:3:1 (expanded from twice)\n    println!(\"{:?}\", 4);\n    // lisp: a:b.lisp:4:1\n    println!(\"{:?}\", 5);\n}\n";
+        let (stripped, map) = SourceMap::read(code);
+        assert_eq!(stripped, "mod support {}\n\nfn twice() {}\n\nfn main() {\n    println!(\"{:?}\", 4);\n    println!(\"{:?}\", 5);\n}\n");
+        assert_eq!(map.mappings.len(), 2);
+        assert_eq!(map.lookup(1), None);
+        let location = map.lookup(6).unwrap();
+        assert_eq!((location.file.as_str(), location.line, location.column), ("a:b.lisp", 3, 1));
+        assert_eq!(location.to_string(), "a:b.lisp:3:1 (expanded from twice)");
+        assert_eq!(map.lookup(8).unwrap().to_string(), "a:b.lisp:4:1");
+        assert_eq!(serde_json::from_str::<SourceMap>(&map.to_json()).unwrap(), map);
+    }
+
+    #[test]
+    fn test_render_rustc_diagnostics() {
+        let (_, map) = SourceMap::read("fn main() {\n    // lisp: bad.lisp:3:1 (expanded from twice)\n    println!(\"{:?}\", (\"a\" + \"a\"));\n}\n");
+        let span = |line: usize, is_primary: bool, label: &str| {
+            serde_json::json!({ "line_start": line, "is_primary": is_primary, "label": label, "text": [{ "text": "    println!(\"{:?}\", (\"a\" + \"a\"));" }] })
+        };
+        let error = serde_json::json!({
+            "message": "cannot add `&str` to `&str`", "code": { "code": "E0369" }, "level": "error",
+            "spans": [span(2, false, "&str"), span(2, true, "`+` cannot be used to concatenate two `&str` strings")],
+            "children": [{ "message": "string concatenation requires an owned `String` on the left", "code": null, "level": "note", "spans": [], "children": [], "rendered": null }],
+            "rendered": "error[E0369]: cannot add `&str` to `&str`",
+        });
+        let unmapped = serde_json::json!({ "message": "main function not found", "code": null, "level": "error", "spans": [span(1, true, "")], "children": [], "rendered": "error: main function not found\n\n" });
+        let warning = serde_json::json!({ "message": "unused", "code": null, "level": "warning", "spans": [span(2, true, "")], "children": [], "rendered": "warning: unused" });
+        let summary = serde_json::json!({ "message": "aborting due to 2 previous errors", "code": null, "level": "error", "spans": [], "children": [], "rendered": "error: aborting due to 2 previous errors" });
+        let stderr = [error, unmapped, warning, summary].map(|diagnostic| diagnostic.to_string()).join("\n");
+        assert_eq!(
+            render_rustc_diagnostics(&format!("{}\nerror: linking with `cc` failed", stderr), &map),
+            "bad.lisp:3:1: error[E0369]: cannot add `&str` to `&str`\n  in the expansion of twice\n  generated Rust: println!(\"{:?}\", (\"a\" + \"a\"));\n  \
+             `+` cannot be used to concatenate two `&str` strings\n  note: string concatenation requires an owned `String` on the left\n\
+             error: main function not found\nerror: linking with `cc` failed\n"
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// Step through the pages of a step report (macro expansions, pipeline stages)
+// with the slider or the arrow keys
+
+document.addEventListener('DOMContentLoaded', function() {
+  const slider = document.getElementById('step');
+  if (!slider) {
+    return;
+  }
+  const steps = document.querySelectorAll('.step');
+  const label = document.getElementById('step-label');
+
+  function show(index) {
+    steps.forEach((step, i) => {
+      step.hidden = i !== index;
+    });
+    slider.value = index;
+    label.textContent = 'Step ' + (index + 1) + ' of ' + steps.length;
+  }
+
+  slider.addEventListener('input', () => show(Number(slider.value)));
+  document.getElementById('prev').addEventListener('click', () => show(Math.max(0, Number(slider.value) - 1)));
+  document.getElementById('next').addEventListener('click', () => show(Math.min(steps.length - 1, Number(slider.value) + 1)));
+  document.addEventListener('keydown', e => {
+    if (e.key === 'ArrowLeft') {
+      show(Math.max(0, Number(slider.value) - 1));
+    } else if (e.key === 'ArrowRight') {
+      show(Math.min(steps.length - 1, Number(slider.value) + 1));
+    }
+  });
+
+  show(0);
+});
@@ -0,0 +1,170 @@
+//! Record types defined with `defstruct`.
+//!
+//! `(defstruct point (x y (label : string)) :derive (Clone PartialEq) :impl ((sum (self) : number (+ (point-x self) (point-y self)))))`
+//! defines a type with the number fields `x` and `y` and the string field `label`.
+//! `(make-point 1 2 "origin")` builds one, `(point-x p)` reads a field, and `(sum p)`
+//! calls a method, whose first parameter is always `self`. Fields and method
+//! parameters are numbers unless annotated with `(name : type)`.
+//!
+//! Compiled Rust gets `struct Point` carrying the listed derives plus `Debug`, which
+//! printing the program's results needs, and an `impl Point` block with the methods.
+//! In the interpreter an instance prints as `#s(point :x 1 :y 2 :label "origin")`.
+
+use crate::ast::LispExpr;
+use crate::declarations::{self, Declarations};
+use crate::deps::DefinitionKind;
+use crate::mangle;
+use crate::symbol::Symbol;
+use crate::types::{self, Typed};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Method {
+    pub name: Symbol,
+    /// The parameters after `self`
+    pub parameters: Vec<Typed>,
+    pub return_type: Option<Symbol>,
+    pub body: LispExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructDef {
+    pub name: Symbol,
+    pub fields: Vec<Typed>,
+    pub derives: Vec<Symbol>,
+    pub methods: Vec<Method>,
+    pub declarations: Declarations,
+}
+
+impl StructDef {
+    /// The arguments of a `defstruct` form: a name, a field list, then `:derive` and `:impl`
+    /// options, among which `(declare ...)` forms are skipped
+    pub fn parse(args: &[LispExpr]) -> Result<StructDef, String> {
+        let [LispExpr::Symbol(name), LispExpr::List(fields), options @ ..] = args else {
+            return Err("'defstruct' requires a name and a list of fields".to_string());
+        };
+        let (declared, options): (Vec<&LispExpr>, Vec<&LispExpr>) = options.iter().partition(|option| declarations::is_declaration(option));
+        let mut definition = StructDef {
+            name: name.clone(),
+            fields: fields.iter().map(|field| Typed::parse(field, "field")).collect::<Result<_, _>>()?,
+            derives: Vec::new(),
+            methods: Vec::new(),
+            declarations: Declarations::parse(declared, DefinitionKind::Struct).map_err(|e| format!("In '{}': {}", name, e))?,
+        };
+        for (i, field) in definition.fields.iter().enumerate() {
+            if definition.fields[..i].iter().any(|earlier| earlier.name == field.name) {
+                return Err(format!("'defstruct' {} has field '{}' more than once", name, field.name));
+            }
+        }
+
+        let (mut derive_seen, mut impl_seen) = (false, false);
+        for option in options.chunks(2) {
+            let [option, LispExpr::List(values)] = *option else {
+                return Err(format!("'defstruct' {} options must be :derive or :impl followed by a list", name));
+            };
+            let seen = match option.as_symbol() {
+                Some(":derive") => &mut derive_seen,
+                Some(":impl") => &mut impl_seen,
+                _ => return Err(format!("'defstruct' {} has unknown option {} (expected :derive or :impl)", name, option)),
+            };
+            if std::mem::replace(seen, true) {
+                return Err(format!("'defstruct' {} option {} is given more than once", name, option));
+            }
+            if option.as_symbol() == Some(":derive") {
+                for derive in values.iter() {
+                    match derive {
+                        LispExpr::Symbol(derive) => definition.derives.push(derive.clone()),
+                        other => return Err(format!("'defstruct' {} derives must be trait names, got {}", name, other)),
+                    }
+                }
+            } else {
+                definition.methods = values.iter().map(|method| Method::parse("defstruct", name, method)).collect::<Result<_, _>>()?;
+            }
+        }
+        Ok(definition)
+    }
+
+    /// `make-point` for `point`
+    pub fn constructor(&self) -> String {
+        format!("make-{}", self.name)
+    }
+
+    /// `point-x` for the field `x` of `point`
+    pub fn accessor(&self, field: &Typed) -> String {
+        format!("{}-{}", self.name, field.name)
+    }
+
+    /// The field read by `function`, if it is one of this struct's accessors
+    pub fn field_of_accessor(&self, function: &str) -> Option<&Typed> {
+        self.fields.iter().find(|field| self.accessor(field) == function)
+    }
+
+    /// The name of the Rust type: `line-segment` becomes `LineSegment`
+    pub fn rust_name(&self) -> String {
+        types::rust_type_name(&self.name)
+    }
+}
+
+impl Method {
+    /// `(name (self params...) [: type] body)` in a `form` defining methods for `owner`
+    pub fn parse(form: &str, owner: &str, method: &LispExpr) -> Result<Method, String> {
+        let invalid = || format!("'{}' {} methods must be (name (self params...) [: type] body), got {}", form, owner, method);
+        let Some([LispExpr::Symbol(name), LispExpr::List(parameters), rest @ ..]) = method.as_list() else {
+            return Err(invalid());
+        };
+        let (return_type, [body]) = types::return_type(rest) else {
+            return Err(invalid());
+        };
+        let Some((_, parameters)) = parameters.split_first().filter(|(receiver, _)| receiver.as_symbol() == Some("self")) else {
+            return Err(format!("'{}' {} method '{}' must take self as its first parameter", form, owner, name));
+        };
+        Ok(Method {
+            name: name.clone(),
+            parameters: parameters.iter().map(|parameter| Typed::parse(parameter, "parameter")).collect::<Result<_, _>>()?,
+            return_type,
+            body: body.clone(),
+        })
+    }
+
+    /// The Rust method name: `to-string` becomes `to_string`
+    pub fn rust_name(&self) -> String {
+        mangle::identifier(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn defstruct(source: &str) -> Result<StructDef, String> {
+        let form = parse(tokenize(source).unwrap()).unwrap().remove(0);
+        StructDef::parse(&form.as_list().unwrap()[1..])
+    }
+
+    #[test]
+    fn test_parse_defstruct() {
+        let point = defstruct("(defstruct line-segment (x (label : string)) :derive (Clone PartialEq) :impl ((scale (self k) : number (* (line-segment-x self) k)) (show (self) label)))").unwrap();
+        assert_eq!(point.rust_name(), "LineSegment");
+        assert_eq!(point.fields[1], Typed { name: "label".into(), type_name: Some("string".into()) });
+        assert_eq!(point.derives, vec![Symbol::from("Clone"), "PartialEq".into()]);
+        assert_eq!(point.constructor(), "make-line-segment");
+        assert_eq!(point.field_of_accessor("line-segment-label"), Some(&point.fields[1]));
+        assert_eq!(point.methods[0].parameters, vec![Typed { name: "k".into(), type_name: None }]);
+        assert_eq!(point.methods[0].return_type, Some("number".into()));
+        assert_eq!(point.methods[1].return_type, None);
+    }
+
+    #[test]
+    fn test_malformed_defstruct() {
+        assert_eq!(defstruct("(defstruct point)").unwrap_err(), "'defstruct' requires a name and a list of fields");
+        assert_eq!(defstruct("(defstruct point (x x))").unwrap_err(), "'defstruct' point has field 'x' more than once");
+        assert!(defstruct("(defstruct point (x) :copy (y))").unwrap_err().contains("unknown option :copy"));
+        assert!(defstruct("(defstruct point (x) :derive (Clone) :derive (Debug))").unwrap_err().contains("option :derive is given more than once"));
+        assert_eq!(
+            defstruct("(defstruct point (x) :impl ((norm (p) x)))").unwrap_err(),
+            "'defstruct' point method 'norm' must take self as its first parameter"
+        );
+        assert!(defstruct("(defstruct point ((x f64)))").unwrap_err().contains("must be (name : type)"));
+    }
+}
@@ -0,0 +1,82 @@
+//! "Did you mean" suggestions: the known name closest to a misspelled one, by edit
+//! distance.
+
+/// Levenshtein distance between `a` and `b`, ignoring ASCII case: the fewest
+/// characters inserted, deleted, or replaced to turn one into the other
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().map(|c| c.to_ascii_lowercase()).collect();
+    // Distances from the prefix of `a` read so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().map(|c| c.to_ascii_lowercase()).enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether the characters of `name` appear in `candidate` in order, as when letters
+/// were left out while typing it
+fn is_subsequence(name: &str, candidate: &str) -> bool {
+    let mut remaining = candidate.chars().map(|c| c.to_ascii_lowercase());
+    name.chars().all(|c| remaining.any(|other| other == c.to_ascii_lowercase()))
+}
+
+/// The candidate closest to `name`, when it is close enough to be what was meant:
+/// at most one edit per three characters of `name`, and at least one. Ties go to a
+/// candidate `name` leaves letters out of, then to the earlier candidate.
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, candidate)| (*distance, !is_subsequence(name, candidate)))
+        .map(|(_, candidate)| candidate)
+}
+
+/// `did you mean 'list'?` for the candidate closest to `name`, if any is close
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    closest(name, candidates).map(|candidate| format!("did you mean '{}'?", candidate))
+}
+
+/// `message`, followed by ` (did you mean 'list'?)` when a candidate is close to `name`
+pub fn with_suggestion<'a>(message: String, name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    match did_you_mean(name, candidates) {
+        Some(suggestion) => format!("{} ({})", message, suggestion),
+        None => message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("lst", "list"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("NetworkHttp", "NetworkHTTP"), 0);
+        assert_eq!(edit_distance("string->numbr", "string->number"), 1);
+    }
+
+    #[test]
+    fn test_closest() {
+        let names = ["let", "list", "length", "print"];
+        assert_eq!(closest("lst", names), Some("list"));
+        assert_eq!(closest("lenght", names), Some("length"));
+        assert_eq!(closest("prnt", names), Some("print"));
+        assert_eq!(closest("frobnicate", names), None);
+        // Short names allow one edit, so unrelated short names are not suggested
+        assert_eq!(closest("xy", names), None);
+        assert_eq!(did_you_mean("ech", ["echo"]), Some("did you mean 'echo'?".to_string()));
+        assert_eq!(with_suggestion("Unknown function: lst".to_string(), "lst", names), "Unknown function: lst (did you mean 'list'?)");
+        assert_eq!(with_suggestion("Unknown function: zzz".to_string(), "zzz", names), "Unknown function: zzz");
+    }
+}
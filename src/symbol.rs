@@ -0,0 +1,217 @@
+//! Interned symbol names.
+//!
+//! Every distinct name in use is stored once, and a `Symbol` is a counted handle to
+//! it: cloning an AST copies no strings, and comparing or hashing symbols looks only
+//! at the handle. The table drops a name once no symbol holds it, checking as it
+//! grows, so a long-running process that expands macros doesn't keep every gensym
+//! it ever generated.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Entries the table may hold before the first purge
+const MIN_PURGE_AT: usize = 1024;
+
+static INTERNER: LazyLock<Mutex<Interner>> =
+    LazyLock::new(|| Mutex::new(Interner { names: HashSet::new(), purge_at: MIN_PURGE_AT }));
+
+struct Interner {
+    names: HashSet<Arc<str>>,
+    /// Size at which `purge` runs next: twice what the last purge kept, so the
+    /// table is swept once per doubling and interning stays amortized O(1)
+    purge_at: usize,
+}
+
+impl Interner {
+    /// Drop the names only the table still holds
+    fn purge(&mut self) {
+        self.names.retain(|name| Arc::strong_count(name) > 1);
+        self.purge_at = (self.names.len() * 2).max(MIN_PURGE_AT);
+    }
+}
+
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+/// The names in `symbols` separated by `separator`, like `[String]::join`
+pub fn join(symbols: &[Symbol], separator: &str) -> String {
+    symbols.iter().map(Symbol::as_str).collect::<Vec<_>>().join(separator)
+}
+
+impl Symbol {
+    /// The symbol for `name`, storing it if no symbol holds it yet
+    pub fn intern(name: &str) -> Symbol {
+        let mut interner = INTERNER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(existing) = interner.names.get(name) {
+            return Symbol(existing.clone());
+        }
+        if interner.names.len() >= interner.purge_at {
+            interner.purge();
+        }
+        let stored: Arc<str> = Arc::from(name);
+        interner.names.insert(stored.clone());
+        Symbol(stored)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A name stays in the table while any symbol holds it, so equal names in live
+/// symbols are the same allocation and identity is equality
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(Arc::as_ptr(&self.0), state);
+    }
+}
+
+/// Alphabetical, like the names themselves
+impl Ord for Symbol {
+    fn cmp(&self, other: &Symbol) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl PartialEq<String> for Symbol {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl PartialEq<Symbol> for String {
+    fn eq(&self, other: &Symbol) -> bool {
+        **self == *other.0
+    }
+}
+
+impl PartialEq<Symbol> for &str {
+    fn eq(&self, other: &Symbol) -> bool {
+        **self == *other.0
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(symbol: Symbol) -> String {
+        symbol.0.to_string()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+/// Serialized as the plain name, so the IR is unchanged
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Symbol, D::Error> {
+        String::deserialize(deserializer).map(Symbol::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let a = Symbol::intern("interned-name");
+        let b = Symbol::from(String::from("interned-name"));
+        assert_eq!(a, b);
+        assert!(std::ptr::eq(a.as_str(), b.as_str()));
+        assert_ne!(a, Symbol::intern("other-name"));
+        assert_eq!(a, "interned-name");
+        assert!(Symbol::intern("a") < Symbol::intern("b"));
+        assert_eq!(format!("{} {:?}", a, a), "interned-name \"interned-name\"");
+        assert_eq!(serde_json::to_string(&a).unwrap(), "\"interned-name\"");
+        assert_eq!(serde_json::from_str::<Symbol>("\"interned-name\"").unwrap(), a);
+    }
+
+    #[test]
+    fn test_unused_names_are_purged() {
+        let is_interned = |name: &str| INTERNER.lock().unwrap().names.contains(name);
+        let kept = Symbol::intern("purge-test-kept");
+        drop(Symbol::intern("purge-test-dropped"));
+        assert!(is_interned("purge-test-dropped"));
+        INTERNER.lock().unwrap().purge();
+        assert!(!is_interned("purge-test-dropped"));
+        assert!(is_interned("purge-test-kept"));
+        assert_eq!(kept, Symbol::intern("purge-test-kept"));
+    }
+}
@@ -0,0 +1,308 @@
+//! Random programs for fuzzing the reader and printer.
+//!
+//! `Generator` draws expressions from the grammar the parser reads: atoms, lists,
+//! quote forms, and `defmacro`. It never produces `MacroCall` or `Gensym`, which only
+//! macro expansion creates and which print as plain lists and symbols. `source`
+//! writes a program out with random spacing, comments, and longhand quotes, so the
+//! text it returns must parse back to exactly that program.
+//!
+//! `round_trip` is the oracle: a parsed program, printed with `Display` or
+//! `to_source`, has to parse back to itself. A generator built with `from_bytes`
+//! takes its choices from a fuzzer's input instead of a seed, running out into the
+//! smallest choices when the input does; `selftest` runs both oracles over seeded
+//! programs.
+
+use crate::ast::LispExpr;
+use crate::interpreter::splitmix64;
+use crate::lexer::tokenize;
+use crate::parser::parse;
+use crate::symbol::Symbol;
+use std::rc::Rc;
+
+/// Symbols that read as themselves anywhere. Names the parser treats specially at
+/// the head of a list (`defmacro`, `quote`, `when-feature`, ...) are left out, as are
+/// ones the lexer splits, like `->list`.
+const SYMBOLS: &[&str] = &[
+    "x", "y", "items", "foo-bar", "empty?", "set!", "*global*", "a.b", "&rest", "str<T>", "+", "-", "*", "/", "<=", "=", "λ",
+    "naïve", "if", "let", "defun", "lambda", "declare", "list",
+];
+
+/// Characters appended to symbols that start with a letter
+const SYMBOL_SUFFIXES: &[char] = &['a', 'z', '0', '9', '-', '_', '!', '?', '*', '>'];
+
+/// Characters of generated strings, including the ones the printer escapes
+const STRING_CHARS: &[char] = &['a', 'Z', '5', ' ', '"', '\\', '\n', '\t', '\r', '(', ';', '\'', 'é', '🦀'];
+
+enum Entropy {
+    Seeded(u64),
+    Bytes(Vec<u8>, usize),
+}
+
+pub struct Generator {
+    entropy: Entropy,
+    max_depth: usize,
+    max_width: usize,
+}
+
+impl Generator {
+    /// The same seed gives the same expressions
+    pub fn new(seed: u64) -> Self {
+        Generator { entropy: Entropy::Seeded(seed), max_depth: 5, max_width: 5 }
+    }
+
+    /// Choices taken from `data`, one byte each, as a fuzzer mutates them
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Generator { entropy: Entropy::Bytes(data.to_vec(), 0), max_depth: 5, max_width: 5 }
+    }
+
+    /// How deeply lists and quotes nest (default 5)
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// The most elements in a list, parameters of a macro, or forms of a program (default 5)
+    pub fn with_max_width(mut self, max_width: usize) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    fn byte(&mut self) -> u8 {
+        match &mut self.entropy {
+            Entropy::Seeded(state) => splitmix64(state) as u8,
+            Entropy::Bytes(data, position) => {
+                let byte = data.get(*position).copied().unwrap_or(0);
+                *position += 1;
+                byte
+            }
+        }
+    }
+
+    /// A choice in `0..n`, for `n` up to 256
+    fn below(&mut self, n: usize) -> usize {
+        self.byte() as usize % n
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    /// A program of up to `max_width` top-level forms
+    pub fn program(&mut self) -> Vec<LispExpr> {
+        let forms = self.below(self.max_width + 1);
+        (0..forms).map(|_| self.expr()).collect()
+    }
+
+    pub fn expr(&mut self) -> LispExpr {
+        self.expr_at(self.max_depth)
+    }
+
+    fn expr_at(&mut self, depth: usize) -> LispExpr {
+        // The first choices are atoms, so exhausted input ends the expression
+        let kinds = if depth == 0 { 5 } else { 11 };
+        match self.below(kinds) {
+            0 => LispExpr::Nil,
+            1 => LispExpr::Bool(self.chance(50)),
+            2 => LispExpr::Number(self.number()),
+            3 => LispExpr::String(self.string()),
+            4 => LispExpr::Symbol(self.symbol()),
+            5..=7 => LispExpr::List(self.exprs(depth - 1).into()),
+            8 => LispExpr::Quote(Rc::new(self.expr_at(depth - 1))),
+            9 => match self.below(3) {
+                0 => LispExpr::Quasiquote(Rc::new(self.expr_at(depth - 1))),
+                1 => LispExpr::Unquote(Rc::new(self.expr_at(depth - 1))),
+                _ => LispExpr::Splice(Rc::new(self.expr_at(depth - 1))),
+            },
+            _ => self.defmacro(depth - 1),
+        }
+    }
+
+    fn exprs(&mut self, depth: usize) -> Vec<LispExpr> {
+        let width = self.below(self.max_width + 1);
+        (0..width).map(|_| self.expr_at(depth)).collect()
+    }
+
+    fn defmacro(&mut self, depth: usize) -> LispExpr {
+        let name = self.symbol();
+        let parameters = (0..self.below(self.max_width + 1)).map(|_| self.symbol()).collect();
+        let declarations = (0..self.below(2))
+            .map(|_| {
+                let declaration = LispExpr::List(vec![LispExpr::Symbol(self.symbol()), self.expr_at(0)].into());
+                LispExpr::List(vec![LispExpr::Symbol(Symbol::from("declare")), declaration].into())
+            })
+            .collect();
+        LispExpr::Macro { name, parameters, body: Rc::new(self.expr_at(depth)), declarations }
+    }
+
+    fn number(&mut self) -> f64 {
+        match self.below(4) {
+            0 => self.below(10) as f64,
+            1 => -(self.below(256) as f64),
+            2 => (self.below(256) as f64 - 128.0) / 8.0,
+            _ => {
+                let bits = (0..8).fold(0u64, |bits, _| bits << 8 | self.byte() as u64);
+                let number = f64::from_bits(bits);
+                if number.is_finite() { number } else { 0.5 }
+            }
+        }
+    }
+
+    fn string(&mut self) -> String {
+        (0..self.below(8)).map(|_| self.pick(STRING_CHARS)).collect()
+    }
+
+    fn symbol(&mut self) -> Symbol {
+        let mut symbol = self.pick(SYMBOLS).to_string();
+        if symbol.starts_with(char::is_alphabetic) {
+            for _ in 0..self.below(3) {
+                symbol.push(self.pick(SYMBOL_SUFFIXES));
+            }
+        }
+        Symbol::from(symbol)
+    }
+
+    /// `program` as source text, laid out at random
+    pub fn source(&mut self, program: &[LispExpr]) -> String {
+        let mut source = String::new();
+        for expr in program {
+            self.space(&mut source);
+            self.write(expr, &mut source);
+        }
+        self.space(&mut source);
+        source
+    }
+
+    /// Whitespace and comments, of which there is always some
+    fn space(&mut self, out: &mut String) {
+        out.push(self.pick(&[' ', '\n', '\t']));
+        for _ in 0..self.below(3) {
+            match self.below(4) {
+                0 => out.push_str("; (not code) \"\n"),
+                _ => out.push(self.pick(&[' ', '\n', '\t', '\r'])),
+            }
+        }
+    }
+
+    fn write(&mut self, expr: &LispExpr, out: &mut String) {
+        match expr {
+            LispExpr::Number(n) if n.is_sign_positive() && self.chance(25) => out.push_str(&format!("+{}", n)),
+            LispExpr::List(elements) => self.write_list("", elements.iter(), out),
+            LispExpr::Macro { name, parameters, body, declarations } => {
+                out.push_str("(defmacro");
+                self.space(out);
+                out.push_str(name);
+                self.space(out);
+                let parameters: Vec<LispExpr> = parameters.iter().cloned().map(LispExpr::Symbol).collect();
+                self.write_list("", parameters.iter(), out);
+                for declaration in declarations {
+                    self.space(out);
+                    self.write(declaration, out);
+                }
+                self.space(out);
+                self.write(body, out);
+                out.push(')');
+            }
+            LispExpr::Quote(inner) => self.write_prefixed("'", "quote", inner, out),
+            LispExpr::Quasiquote(inner) => self.write_prefixed("`", "quasiquote", inner, out),
+            LispExpr::Unquote(inner) => self.write_prefixed(",", "unquote", inner, out),
+            LispExpr::Splice(inner) => self.write_prefixed(",@", "unquote-splicing", inner, out),
+            atom => out.push_str(&atom.to_string()),
+        }
+    }
+
+    fn write_list<'a>(&mut self, head: &str, elements: impl Iterator<Item = &'a LispExpr>, out: &mut String) {
+        out.push('(');
+        out.push_str(head);
+        for (i, element) in elements.enumerate() {
+            if i > 0 || !head.is_empty() || self.chance(20) {
+                self.space(out);
+            }
+            self.write(element, out);
+        }
+        if self.chance(20) {
+            self.space(out);
+        }
+        out.push(')');
+    }
+
+    /// `'x`, or `(quote x)` as often
+    fn write_prefixed(&mut self, prefix: &str, longhand: &str, inner: &LispExpr, out: &mut String) {
+        if self.chance(50) {
+            self.write_list(longhand, std::iter::once(inner), out);
+        } else {
+            out.push_str(prefix);
+            self.write(inner, out);
+        }
+    }
+}
+
+/// Check that `program`, as the parser read it, parses back to itself after printing
+/// with `Display` and with `to_source`
+pub fn round_trip(program: &[LispExpr]) -> Result<(), String> {
+    for (printer, print) in [("Display", LispExpr::to_string as fn(&LispExpr) -> String), ("to_source", LispExpr::to_source)] {
+        let printed: Vec<String> = program.iter().map(print).collect();
+        let printed = printed.join("\n");
+        let reparsed = tokenize(&printed).and_then(parse).map_err(|e| format!("{} output does not parse: {}\n{}", printer, e, printed))?;
+        if reparsed.len() != program.len() {
+            return Err(format!("{} output parses as {} forms instead of {}:\n{}", printer, reparsed.len(), program.len(), printed));
+        }
+        if let Some((expr, again)) = program.iter().zip(&reparsed).find(|(expr, again)| expr != again) {
+            return Err(format!("{} output parses as a different form:\n{}\nparsed back as\n{:?}\ninstead of\n{:?}", printer, printed, again, expr));
+        }
+    }
+    Ok(())
+}
+
+/// One `selftest` case: generate a program and its source, check the source parses
+/// to the program, and round-trip it
+pub fn check_case(seed: u64) -> Result<(), String> {
+    let mut generator = Generator::new(seed);
+    let program = generator.program();
+    let source = generator.source(&program);
+    let parsed = tokenize(&source).and_then(parse).map_err(|e| format!("Generated source does not parse: {}\n{}", e, source))?;
+    if parsed != program {
+        return Err(format!("Generated source parses as\n{:?}\ninstead of\n{:?}\n{}", parsed, program, source));
+    }
+    round_trip(&parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generator_is_seeded() {
+        let program = Generator::new(7).program();
+        assert_eq!(Generator::new(7).program(), program);
+        assert_ne!((0..8).map(|seed| Generator::new(seed).program()).collect::<Vec<_>>(), vec![program; 8]);
+
+        let shallow = Generator::new(7).with_max_depth(0).with_max_width(3).program();
+        assert!(shallow.len() <= 3);
+        assert!(shallow.iter().all(|expr| !matches!(expr, LispExpr::List(_) | LispExpr::Quote(_) | LispExpr::Macro { .. })));
+
+        // Exhausted input makes the smallest choices
+        assert_eq!(Generator::from_bytes(&[]).program(), Vec::new());
+        assert_eq!(Generator::from_bytes(&[1, 5, 3]).program(), vec![LispExpr::List(vec![LispExpr::Nil, LispExpr::Nil, LispExpr::Nil].into())]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for seed in 0..500 {
+            check_case(seed).unwrap_or_else(|err| panic!("seed {}: {}", seed, err));
+        }
+        for data in [&b"\x05\x07\x0a\x01\x02\x03\x09\x08"[..], b"fuzz me, please", &[0xff; 64]] {
+            let mut generator = Generator::from_bytes(data);
+            let program = generator.program();
+            round_trip(&program).unwrap();
+        }
+
+        let quoted = parse(tokenize("'(a ,b ,@c) (defmacro m (x) (declare (pure)) `(+ ,x 1))").unwrap()).unwrap();
+        assert_eq!(round_trip(&quoted), Ok(()));
+        let gensym = [LispExpr::Gensym(Symbol::from("nil"))];
+        assert!(round_trip(&gensym).unwrap_err().starts_with("Display output parses as a different form:\nnil\n"));
+    }
+}
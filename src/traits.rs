@@ -0,0 +1,152 @@
+//! Traits declared with `deftrait` and implemented with `defimpl`.
+//!
+//! `(deftrait shape (area (self) number) (describe (self) : string "a shape"))` declares
+//! each method by its parameters and return type, optionally followed by a default body.
+//! `(defimpl shape circle (area (self) (* 3 (circle-r self) (circle-r self))))` implements
+//! the trait for a struct, or any other type, taking the parameter and return types from
+//! the trait. Trait methods are called like functions with the instance first, `(area c)`.
+
+use crate::ast::LispExpr;
+use crate::structs::Method;
+use crate::symbol::Symbol;
+use crate::types::{self, Typed};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraitMethod {
+    pub name: Symbol,
+    /// The parameters after `self`
+    pub parameters: Vec<Typed>,
+    pub return_type: Symbol,
+    /// The body used by implementations that do not define the method
+    pub default: Option<LispExpr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraitDef {
+    pub name: Symbol,
+    pub methods: Vec<TraitMethod>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImplDef {
+    pub trait_name: Symbol,
+    pub type_name: Symbol,
+    pub methods: Vec<Method>,
+}
+
+impl TraitDef {
+    /// The arguments of a `deftrait` form: a name, then `(method (self params...) [:] type [default])` signatures
+    pub fn parse(args: &[LispExpr]) -> Result<TraitDef, String> {
+        let [LispExpr::Symbol(name), signatures @ ..] = args else {
+            return Err("'deftrait' requires a name and method signatures".to_string());
+        };
+        let mut methods: Vec<TraitMethod> = Vec::new();
+        for signature in signatures {
+            let invalid = || format!("'deftrait' {} methods must be (name (self params...) type [default]), got {}", name, signature);
+            let Some([LispExpr::Symbol(method), LispExpr::List(parameters), rest @ ..]) = signature.as_list() else {
+                return Err(invalid());
+            };
+            let rest = match rest {
+                [colon, rest @ ..] if colon.as_symbol() == Some(":") => rest,
+                rest => rest,
+            };
+            let (return_type, default) = match rest {
+                [LispExpr::Symbol(return_type)] => (return_type.clone(), None),
+                [LispExpr::Symbol(return_type), default] => (return_type.clone(), Some(default.clone())),
+                _ => return Err(invalid()),
+            };
+            let Some((_, parameters)) = parameters.split_first().filter(|(receiver, _)| receiver.as_symbol() == Some("self")) else {
+                return Err(format!("'deftrait' {} method '{}' must take self as its first parameter", name, method));
+            };
+            if methods.iter().any(|other| other.name == *method) {
+                return Err(format!("'deftrait' {} declares method '{}' more than once", name, method));
+            }
+            methods.push(TraitMethod {
+                name: method.clone(),
+                parameters: parameters.iter().map(|parameter| Typed::parse(parameter, "parameter")).collect::<Result<_, _>>()?,
+                return_type,
+                default,
+            });
+        }
+        Ok(TraitDef { name: name.clone(), methods })
+    }
+
+    /// The name of the Rust trait: `shape` becomes `Shape`
+    pub fn rust_name(&self) -> String {
+        types::rust_type_name(&self.name)
+    }
+}
+
+impl ImplDef {
+    /// The arguments of a `defimpl` form: a trait, a type, then `(method (self params...) body)` definitions
+    pub fn parse(args: &[LispExpr]) -> Result<ImplDef, String> {
+        let [LispExpr::Symbol(trait_name), LispExpr::Symbol(type_name), methods @ ..] = args else {
+            return Err("'defimpl' requires a trait, a type, and method definitions".to_string());
+        };
+        let owner = format!("{} for {}", trait_name, type_name);
+        Ok(ImplDef {
+            trait_name: trait_name.clone(),
+            type_name: type_name.clone(),
+            methods: methods.iter().map(|method| Method::parse("defimpl", &owner, method)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Each of the trait's methods with this implementation's definition of it,
+    /// or `None` where the trait's default applies
+    pub fn resolve<'a>(&'a self, definition: &'a TraitDef) -> Result<Vec<(&'a TraitMethod, Option<&'a Method>)>, String> {
+        let owner = format!("'defimpl' {} for {}", self.trait_name, self.type_name);
+        if let Some(unknown) = self.methods.iter().find(|method| definition.methods.iter().all(|declared| declared.name != method.name)) {
+            return Err(format!("{} defines '{}', which the trait does not declare", owner, unknown.name));
+        }
+        definition
+            .methods
+            .iter()
+            .map(|declared| match self.methods.iter().find(|method| method.name == declared.name) {
+                Some(method) if method.parameters.len() != declared.parameters.len() => Err(format!(
+                    "{} method '{}' takes {} parameter(s) after self, but the trait declares {}",
+                    owner,
+                    method.name,
+                    method.parameters.len(),
+                    declared.parameters.len()
+                )),
+                Some(method) => Ok((declared, Some(method))),
+                None if declared.default.is_some() => Ok((declared, None)),
+                None => Err(format!("{} is missing method '{}'", owner, declared.name)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn arguments(source: &str) -> Vec<LispExpr> {
+        parse(tokenize(source).unwrap()).unwrap().remove(0).as_list().unwrap()[1..].to_vec()
+    }
+
+    #[test]
+    fn test_parse_trait_and_impl() {
+        let shape = TraitDef::parse(&arguments("(deftrait shape (area (self) number) (scale (self (k : number)) : shape) (describe (self) string \"a shape\"))")).unwrap();
+        assert_eq!(shape.rust_name(), "Shape");
+        assert_eq!(shape.methods[1].return_type, "shape");
+        assert_eq!(shape.methods[1].parameters[0].type_name, Some("number".into()));
+        assert!(shape.methods[2].default.is_some());
+
+        let circle = ImplDef::parse(&arguments("(defimpl shape circle (area (self) 3) (scale (self k) self))")).unwrap();
+        let resolved = circle.resolve(&shape).unwrap();
+        assert_eq!(resolved.iter().map(|(declared, method)| (declared.name.clone(), method.is_some())).collect::<Vec<_>>(), vec![
+            ("area".into(), true),
+            ("scale".into(), true),
+            ("describe".into(), false)
+        ]);
+
+        let error = |source: &str| ImplDef::parse(&arguments(source)).and_then(|definition| definition.resolve(&shape).map(|_| ())).unwrap_err();
+        assert_eq!(error("(defimpl shape circle (scale (self k) self))"), "'defimpl' shape for circle is missing method 'area'");
+        assert_eq!(error("(defimpl shape circle (area (self) 1) (volume (self) 1))"), "'defimpl' shape for circle defines 'volume', which the trait does not declare");
+        assert!(error("(defimpl shape circle (area (self) 1) (scale (self) 1))").contains("takes 0 parameter(s) after self, but the trait declares 1"));
+        assert!(TraitDef::parse(&arguments("(deftrait shape (area () number))")).unwrap_err().contains("must take self"));
+    }
+}
@@ -1,4 +1,6 @@
 use crate::ast::LispExpr;
+use crate::explain::CompileError;
+use crate::logging;
 use std::fmt;
 
 /// Error type for AST transformations
@@ -22,6 +24,24 @@ impl fmt::Display for TransformError {
     }
 }
 
+impl TransformError {
+    /// Stable error code, explained by `explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransformError::TransformFailed(_) => "L0501",
+            TransformError::InvalidAst(_) => "L0502",
+            TransformError::PluginNotFound(_) => "L0503",
+        }
+    }
+}
+
+/// The error as a stage reports it, after "Transform error: "
+impl From<TransformError> for CompileError {
+    fn from(error: TransformError) -> Self {
+        CompileError::new(error.code(), format!("Transform error: {}", error))
+    }
+}
+
 impl std::error::Error for TransformError {}
 
 /// Trait for AST transformation plugins
@@ -65,10 +85,25 @@ impl TransformRegistry {
     pub fn apply_all(&self, ast: &mut LispExpr) -> Result<(), TransformError> {
         for transform in &self.transforms {
             transform.transform(ast)?;
+            logging::trace("transform", format_args!("applied {}", transform.name()), &[("transform", transform.name().into())]);
         }
         Ok(())
     }
 
+    /// Apply each transform to the whole program in turn, returning the program
+    /// after every transform, labeled with its name
+    pub fn apply_each(&self, program: &[LispExpr]) -> Result<Vec<(String, Vec<LispExpr>)>, TransformError> {
+        let mut current = program.to_vec();
+        let mut snapshots = Vec::new();
+        for transform in &self.transforms {
+            for expr in &mut current {
+                transform.transform(expr)?;
+            }
+            snapshots.push((transform.name().to_string(), current.clone()));
+        }
+        Ok(snapshots)
+    }
+
     /// Get the number of registered transforms
     pub fn count(&self) -> usize {
         self.transforms.len()
@@ -115,14 +150,14 @@ impl EchoTransform {
             LispExpr::Nil => format!("{}Nil", prefix),
             LispExpr::List(items) => {
                 let mut result = format!("{}List[\n", prefix);
-                for item in items {
+                for item in items.iter() {
                     result.push_str(&self.format_expr(item, indent + 1));
                     result.push('\n');
                 }
                 result.push_str(&format!("{}]", prefix));
                 result
             }
-            LispExpr::Macro { name, parameters, body: _ } => {
+            LispExpr::Macro { name, parameters, .. } => {
                 format!("{}Macro(name: {}, parameters: {:?}, body: ...)", prefix, name, parameters)
             }
             LispExpr::MacroCall { name, args } => {
@@ -170,6 +205,7 @@ impl ASTTransform for EchoTransform {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
 
     #[test]
     fn test_transform_error_display() {
@@ -220,10 +256,10 @@ mod tests {
     fn test_echo_transform_list() {
         let transform = EchoTransform::new();
         let mut ast = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
 
         let result = transform.transform(&mut ast);
         assert!(result.is_ok());
@@ -268,7 +304,7 @@ mod tests {
         assert_eq!(registry.count(), 2);
         assert_eq!(registry.transform_names(), vec!["echo", "echo"]);
 
-        let mut ast = LispExpr::Symbol("test".to_string());
+        let mut ast = LispExpr::Symbol("test".into());
         let result = registry.apply_all(&mut ast);
         assert!(result.is_ok());
     }
@@ -288,7 +324,7 @@ mod tests {
                     Ok(())
                 }
                 LispExpr::List(items) => {
-                    for item in items.iter_mut() {
+                    for item in Rc::make_mut(items) {
                         self.transform(item)?;
                     }
                     Ok(())
@@ -315,14 +351,14 @@ mod tests {
     fn test_custom_transform_recursive() {
         let transform = DoubleNumberTransform;
         let mut ast = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
 
         transform.transform(&mut ast).unwrap();
 
-        match ast {
+        match &ast {
             LispExpr::List(items) => {
                 assert_eq!(items.len(), 3);
                 match &items[1] {
@@ -338,6 +374,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_registry_apply_each() {
+        let mut registry = TransformRegistry::new();
+        registry.register(Box::new(DoubleNumberTransform));
+        registry.register(Box::new(EchoTransform::new()));
+        registry.register(Box::new(DoubleNumberTransform));
+
+        let program = vec![LispExpr::Number(1.0), LispExpr::Symbol("x".into())];
+        let snapshots = registry.apply_each(&program).unwrap();
+
+        let names: Vec<&str> = snapshots.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["double", "echo", "double"]);
+        assert_eq!(snapshots[1].1[0], LispExpr::Number(2.0));
+        assert_eq!(snapshots[2].1, vec![LispExpr::Number(4.0), LispExpr::Symbol("x".into())]);
+    }
+
     #[test]
     fn test_registry_with_custom_transform() {
         let mut registry = TransformRegistry::new();
@@ -0,0 +1,108 @@
+//! Type annotations.
+//!
+//! Parameters and fields are annotated `(name : type)`, a function's return type
+//! with `: type` after its parameter list, and a function's type parameters in its
+//! name: `id<T>`, or `pair<A,B>` with bounds written as in Rust, `largest<T:PartialOrd>`.
+//! The interpreter ignores annotations. Code generation turns `number`, `string`,
+//! and `bool` into `f64`, `String`, and `bool`, struct and trait names into their
+//! Rust types, and passes any other type through unchanged.
+
+use crate::ast::LispExpr;
+use crate::symbol::Symbol;
+
+/// A field or parameter, with its annotated type if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct Typed {
+    pub name: Symbol,
+    pub type_name: Option<Symbol>,
+}
+
+impl Typed {
+    /// `name` or `(name : type)`; `kind` names what is being parsed, for messages
+    pub fn parse(expr: &LispExpr, kind: &str) -> Result<Typed, String> {
+        match expr {
+            LispExpr::Symbol(name) => Ok(Typed { name: name.clone(), type_name: None }),
+            LispExpr::List(_) => Self::annotated(expr).ok_or_else(|| format!("A typed {} must be (name : type), got {}", kind, expr)),
+            other => Err(format!("A {} must be a name or (name : type), got {}", kind, other)),
+        }
+    }
+
+    /// `(name : type)`, and nothing else
+    pub fn annotated(expr: &LispExpr) -> Option<Typed> {
+        match expr.as_list()? {
+            [LispExpr::Symbol(name) | LispExpr::Gensym(name), colon, LispExpr::Symbol(type_name)] if colon.as_symbol() == Some(":") => {
+                Some(Typed { name: name.clone(), type_name: Some(type_name.clone()) })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The `: type` annotation at the start of `forms`, and the forms after it
+pub fn return_type(forms: &[LispExpr]) -> (Option<Symbol>, &[LispExpr]) {
+    match forms {
+        [colon, LispExpr::Symbol(type_name), rest @ ..] if colon.as_symbol() == Some(":") => (Some(type_name.clone()), rest),
+        _ => (None, forms),
+    }
+}
+
+/// `id<T>` as `("id", Some("T"))`; a name without type parameters as `(name, None)`
+pub fn split_generics(name: &str) -> (&str, Option<&str>) {
+    match name.find('<') {
+        Some(start) if start > 0 && name.ends_with('>') => (&name[..start], Some(&name[start + 1..name.len() - 1])),
+        _ => (name, None),
+    }
+}
+
+/// The names declared by type parameters: `A` and `B` for `A,B:Into<f64>`
+pub fn generic_names(generics: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, ch) in generics.char_indices() {
+        match ch {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                names.push(&generics[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    names.push(&generics[start..]);
+    names.into_iter().map(|parameter| parameter.split(':').next().unwrap_or_default().trim()).filter(|name| !name.is_empty()).collect()
+}
+
+/// The Rust name of a struct or trait: `line-segment` becomes `LineSegment`
+pub fn rust_type_name(name: &str) -> String {
+    name.split(['-', '_'])
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_annotations() {
+        let forms = parse(tokenize("(x : T) : number (* x x)").unwrap()).unwrap();
+        assert_eq!(Typed::parse(&forms[0], "parameter").unwrap(), Typed { name: "x".into(), type_name: Some("T".into()) });
+        assert_eq!(Typed::parse(&forms[3], "parameter").unwrap_err(), "A typed parameter must be (name : type), got (* x x)");
+        assert_eq!(return_type(&forms[1..]), (Some("number".into()), &forms[3..]));
+        assert_eq!(return_type(&forms[3..]), (None, &forms[3..]));
+    }
+
+    #[test]
+    fn test_generics() {
+        assert_eq!(split_generics("id<T>"), ("id", Some("T")));
+        assert_eq!(split_generics("<="), ("<=", None));
+        assert_eq!(generic_names("A,B:Into<f64>,C:PartialOrd+Clone"), vec!["A", "B", "C"]);
+        assert_eq!(rust_type_name("line-segment"), "LineSegment");
+    }
+}
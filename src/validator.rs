@@ -1,4 +1,18 @@
-use crate::ast::LispExpr;
+use crate::ast::{grow_stack, walk_expr, walk_expr_mut, LispExpr, Visitor, VisitorMut};
+use crate::capabilities::{self, CapabilityReport, CapabilityUse};
+use crate::contracts::{self, Contract};
+use crate::crates::{self, CrateRequirement};
+use crate::declarations;
+use crate::deps::{defined_name, Definition, DefinitionKind, DependencyGraph};
+use crate::interpreter::{Interpreter, Value, BUILTINS, SPECIAL_FORMS};
+use crate::lambda_list::{self, LambdaList};
+use crate::logging;
+use crate::pattern::Pattern;
+use crate::sandbox::{Capability, SandboxConfig};
+use crate::suggest;
+use crate::symbol::Symbol;
+use crate::types;
+use serde::Serialize;
 use std::collections::{HashSet, HashMap};
 use std::fmt;
 
@@ -13,6 +27,24 @@ pub enum ValidationRule {
     FFIRestrictions,
     /// Computational complexity limits
     ComplexityLimits,
+    /// Untrusted data reaching FFI/process forms
+    TaintTracking,
+    /// Functions using capabilities outside their `(declare (capabilities ...))`
+    CapabilityScope,
+    /// Statically known allocations exceeding the sandbox memory limit
+    MemoryBounds,
+    /// Malformed `(declare (requires ...) (ensures ...))` contracts and calls that break them
+    Contracts,
+    /// Calls that do not match the parameter list of the function they call
+    Arity,
+    /// Malformed, conflicting, or disallowed `require-crate` dependencies
+    Crates,
+    /// `(declare ...)` clauses outside the schema, and `pure` functions with side effects
+    Declarations,
+    /// Calls to definitions declared `deprecated`, reported by the `deprecated` lint
+    Deprecated,
+    /// Macros, functions, and variables named after built-in forms, reported by the `shadowed-builtins` lint
+    ShadowedBuiltin,
 }
 
 /// Type information for basic type inference
@@ -27,12 +59,191 @@ pub enum InferredType {
     Any,
 }
 
+/// Lisp-style names: `number`, `(list string)`
+impl fmt::Display for InferredType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InferredType::Number => write!(f, "number"),
+            InferredType::String => write!(f, "string"),
+            InferredType::Bool => write!(f, "bool"),
+            InferredType::List(element) => write!(f, "(list {})", element),
+            InferredType::Symbol => write!(f, "symbol"),
+            InferredType::Unknown => write!(f, "unknown"),
+            InferredType::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl ValidationRule {
+    /// Every rule, in the order of their error codes
+    pub const ALL: [ValidationRule; 13] = [
+        ValidationRule::TypeSafety,
+        ValidationRule::ResourceBounds,
+        ValidationRule::FFIRestrictions,
+        ValidationRule::ComplexityLimits,
+        ValidationRule::TaintTracking,
+        ValidationRule::CapabilityScope,
+        ValidationRule::MemoryBounds,
+        ValidationRule::Contracts,
+        ValidationRule::Arity,
+        ValidationRule::Crates,
+        ValidationRule::Declarations,
+        ValidationRule::Deprecated,
+        ValidationRule::ShadowedBuiltin,
+    ];
+
+    /// Stable error code for findings of this rule, explained by `explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationRule::TypeSafety => "L0301",
+            ValidationRule::ResourceBounds => "L0302",
+            ValidationRule::FFIRestrictions => "L0303",
+            ValidationRule::ComplexityLimits => "L0304",
+            ValidationRule::TaintTracking => "L0305",
+            ValidationRule::CapabilityScope => "L0306",
+            ValidationRule::MemoryBounds => "L0307",
+            ValidationRule::Contracts => "L0308",
+            ValidationRule::Arity => "L0309",
+            ValidationRule::Crates => "L0310",
+            ValidationRule::Declarations => "L0311",
+            ValidationRule::Deprecated => "L0312",
+            ValidationRule::ShadowedBuiltin => "L0313",
+        }
+    }
+
+    /// Short human-readable description of what the rule checks
+    pub fn description(&self) -> &'static str {
+        match self {
+            ValidationRule::TypeSafety => "Operands must have types compatible with the operation",
+            ValidationRule::ResourceBounds => "Functions must not recurse without a base case",
+            ValidationRule::FFIRestrictions => "Unsafe Rust and FFI operations must be explicitly allowed",
+            ValidationRule::ComplexityLimits => "Expressions must stay within configured complexity limits",
+            ValidationRule::TaintTracking => "Untrusted data must not reach FFI, unsafe, or process forms",
+            ValidationRule::CapabilityScope => "Functions must stay within the capabilities they declare",
+            ValidationRule::MemoryBounds => "Statically known allocations must fit within the sandbox memory limit",
+            ValidationRule::Contracts => "Contracts must only use their function's parameters and hold for literal arguments",
+            ValidationRule::Arity => "Calls must pass the arguments and keywords their function's parameter list accepts",
+            ValidationRule::Crates => "Required crates must be well-formed, top-level, consistent, and allowed",
+            ValidationRule::Declarations => "Declarations must follow the schema, and pure functions must have no side effects",
+            ValidationRule::Deprecated => "Deprecated functions, macros, and structs should be replaced",
+            ValidationRule::ShadowedBuiltin => "Macros, functions, and variables should not reuse the names of built-in forms",
+        }
+    }
+}
+
+/// How serious a validation finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// How confidently a suggested fix can be applied without review
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applicability {
+    /// Unambiguous; safe for `--apply-suggestions` to apply
+    MachineApplicable,
+    /// Probably right, but may change behaviour
+    MaybeIncorrect,
+    /// A template with `<placeholder>` symbols to fill in by hand
+    HasPlaceholders,
+}
+
+impl fmt::Display for Applicability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Applicability::MachineApplicable => write!(f, "machine-applicable"),
+            Applicability::MaybeIncorrect => write!(f, "maybe-incorrect"),
+            Applicability::HasPlaceholders => write!(f, "has-placeholders"),
+        }
+    }
+}
+
+/// Rewrite of a single sub-expression that resolves a validation error
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuggestedFix {
+    pub target: LispExpr,
+    pub replacement: LispExpr,
+    pub applicability: Applicability,
+}
+
+impl SuggestedFix {
+    pub fn new(target: LispExpr, replacement: LispExpr, applicability: Applicability) -> Self {
+        SuggestedFix { target, replacement, applicability }
+    }
+
+    /// Replace the target inside `expr`. Only applies when the target occurs
+    /// exactly once, so the rewrite is unambiguous.
+    pub fn apply_to(&self, expr: &mut LispExpr) -> bool {
+        let mut occurrences = Occurrences { target: &self.target, count: 0 };
+        occurrences.visit_expr(expr);
+        if occurrences.count != 1 {
+            return false;
+        }
+        let mut replace = Replace { fix: self, replaced: false };
+        replace.visit_expr_mut(expr);
+        replace.replaced
+    }
+}
+
+/// Counts the subtrees equal to `target`, not looking inside a match
+struct Occurrences<'a> {
+    target: &'a LispExpr,
+    count: usize,
+}
+
+impl Visitor<'_> for Occurrences<'_> {
+    fn visit_expr(&mut self, expr: &LispExpr) {
+        if expr == self.target {
+            self.count += 1;
+        } else {
+            walk_expr(self, expr);
+        }
+    }
+}
+
+/// Replaces the first subtree equal to the fix's target
+struct Replace<'a> {
+    fix: &'a SuggestedFix,
+    replaced: bool,
+}
+
+impl VisitorMut for Replace<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut LispExpr) {
+        if self.replaced {
+            return;
+        }
+        if *expr == self.fix.target {
+            *expr = self.fix.replacement.clone();
+            self.replaced = true;
+        } else {
+            walk_expr_mut(self, expr);
+        }
+    }
+}
+
 /// Validation errors with context
 #[derive(Debug, Clone, PartialEq)]
 pub struct ValidationError {
     pub rule: ValidationRule,
+    pub severity: Severity,
     pub message: String,
     pub context: Option<String>,
+    /// Suggested way to fix the problem, when one is known
+    pub suggestion: Option<String>,
+    /// Machine-readable rewrite implementing the suggestion
+    pub fix: Option<Box<SuggestedFix>>,
 }
 
 impl fmt::Display for ValidationError {
@@ -41,6 +252,12 @@ impl fmt::Display for ValidationError {
         if let Some(ctx) = &self.context {
             write!(f, "\n  Context: {}", ctx)?;
         }
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n  Help: {}", suggestion)?;
+        }
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  Fix ({}): replace `{}` with `{}`", fix.applicability, fix.target, fix.replacement)?;
+        }
         Ok(())
     }
 }
@@ -49,10 +266,19 @@ impl std::error::Error for ValidationError {}
 
 pub type ValidationResult = Result<(), ValidationError>;
 
+/// Upper bound on validate/fix rounds in `CompositeValidator::apply_fixes`
+const MAX_FIX_PASSES: usize = 32;
+
 /// Main validator trait for composable validation
 pub trait ASTValidator {
     fn validate(&self, expr: &LispExpr) -> ValidationResult;
     fn enabled_rules(&self) -> Vec<ValidationRule>;
+
+    /// Validate a whole program, returning one result per top-level expression.
+    /// Validators that track definitions across expressions override this.
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        program.iter().map(|expr| self.validate(expr)).collect()
+    }
 }
 
 /// Composite validator that runs multiple validation rules
@@ -67,11 +293,37 @@ impl CompositeValidator {
         }
     }
 
+    /// Every validator, with default settings
+    pub fn with_all_validators() -> Self {
+        CompositeValidator::new()
+            .add_validator(Box::new(TypeSafetyValidator::new()))
+            .add_validator(Box::new(ResourceBoundsValidator::new()))
+            .add_validator(Box::new(FFIRestrictionsValidator::new()))
+            .add_validator(Box::new(ComplexityLimitsValidator::new()))
+            .add_validator(Box::new(TaintTrackingValidator::new()))
+            .add_validator(Box::new(CapabilityScopeValidator::new()))
+            .add_validator(Box::new(ContractValidator::new()))
+            .add_validator(Box::new(ArityValidator::new()))
+            .add_validator(Box::new(CrateValidator::new()))
+            .add_validator(Box::new(DeclarationValidator::new()))
+    }
+
     pub fn add_validator(mut self, validator: Box<dyn ASTValidator>) -> Self {
         self.validators.push(validator);
         self
     }
 
+    /// The rules of every validator, in the order they run
+    pub fn enabled_rules(&self) -> Vec<ValidationRule> {
+        let mut rules: Vec<ValidationRule> = Vec::new();
+        for rule in self.validators.iter().flat_map(|validator| validator.enabled_rules()) {
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+        rules
+    }
+
     pub fn validate_all(&self, expr: &LispExpr) -> Result<(), Vec<ValidationError>> {
         let errors: Vec<ValidationError> = self
             .validators
@@ -85,29 +337,321 @@ impl CompositeValidator {
             Err(errors)
         }
     }
+
+    /// Validate a whole program, returning the errors of each top-level expression
+    pub fn validate_program(&self, program: &[LispExpr]) -> Vec<Result<(), Vec<ValidationError>>> {
+        let start = std::time::Instant::now();
+        let mut errors: Vec<Vec<ValidationError>> = vec![Vec::new(); program.len()];
+        for validator in &self.validators {
+            for (index, result) in validator.validate_program(program).into_iter().enumerate() {
+                if let Err(error) = result {
+                    errors[index].push(error);
+                }
+            }
+        }
+
+        let elapsed = start.elapsed();
+        let count = |severity| errors.iter().flatten().filter(|error| error.severity == severity).count();
+        let (error_count, warning_count) = (count(Severity::Error), count(Severity::Warning));
+        logging::debug(
+            "validate",
+            format_args!(
+                "{} validators on {} forms: {} error(s), {} warning(s) in {:.2?}",
+                self.validators.len(),
+                program.len(),
+                error_count,
+                warning_count,
+                elapsed
+            ),
+            &[
+                ("validators", self.validators.len().into()),
+                ("errors", error_count.into()),
+                ("warnings", warning_count.into()),
+                ("elapsed_us", logging::micros(elapsed)),
+            ],
+        );
+
+        errors
+            .into_iter()
+            .map(|errors| if errors.is_empty() { Ok(()) } else { Err(errors) })
+            .collect()
+    }
+
+    /// Apply machine-applicable fixes until no more apply, returning how many were applied.
+    /// Validators stop at their first error, so this re-validates after each round.
+    pub fn apply_fixes(&self, program: &mut [LispExpr]) -> usize {
+        let mut applied = 0;
+        for _ in 0..MAX_FIX_PASSES {
+            let mut changed = false;
+            for (index, result) in self.validate_program(program).into_iter().enumerate() {
+                let Err(errors) = result else {
+                    continue;
+                };
+                for error in errors {
+                    if let Some(fix) = error.fix
+                        && fix.applicability == Applicability::MachineApplicable
+                        && fix.apply_to(&mut program[index])
+                    {
+                        applied += 1;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        applied
+    }
+}
+
+impl Default for CompositeValidator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Type safety validator
+#[derive(Clone)]
 pub struct TypeSafetyValidator {
     type_environment: HashMap<String, InferredType>,
+    function_types: HashMap<String, InferredType>,
+    /// The type each required parameter of a top-level function takes, where known
+    parameter_types: HashMap<String, Vec<InferredType>>,
+    /// Every `(set! name value)` in the program whose definitions are recorded
+    assignments: Vec<(String, LispExpr)>,
 }
 
 impl TypeSafetyValidator {
     pub fn new() -> Self {
         TypeSafetyValidator {
             type_environment: HashMap::new(),
+            function_types: HashMap::new(),
+            parameter_types: HashMap::new(),
+            assignments: Vec::new(),
+        }
+    }
+
+    /// Seed the type environment with a known variable type
+    pub fn with_binding(mut self, name: String, inferred_type: InferredType) -> Self {
+        self.type_environment.insert(name, inferred_type);
+        self
+    }
+
+    /// Seed the environment with a known function return type
+    pub fn with_function(mut self, name: String, return_type: InferredType) -> Self {
+        self.function_types.insert(name, return_type);
+        self
+    }
+
+    fn unbind(&mut self, name: &str) {
+        self.type_environment.remove(name);
+        self.function_types.remove(name);
+        self.parameter_types.remove(name);
+    }
+
+    /// Copy of this validator with the given parameters shadowing outer definitions
+    fn shadowed(&self, params: &[LispExpr]) -> TypeSafetyValidator {
+        let mut scope = self.clone();
+        for param in params {
+            match param {
+                // A destructuring pattern binds every variable in it
+                LispExpr::List(pattern) => scope = scope.shadowed(pattern),
+                _ => {
+                    if let Some(name) = param.as_symbol() {
+                        scope.unbind(name);
+                    }
+                }
+            }
+        }
+        scope
+    }
+
+    /// The name and value form of each `(name value)` binding
+    fn binding_pairs(bindings: &[LispExpr]) -> impl Iterator<Item = (&str, Option<&LispExpr>)> {
+        bindings.iter().filter_map(|binding| {
+            let pair = binding.as_list()?;
+            Some((pair.first()?.as_symbol()?, pair.get(1)))
+        })
+    }
+
+    /// Scope for the body of a binding form and the index where the body starts
+    fn body_scope(&self, elements: &[LispExpr]) -> Option<(TypeSafetyValidator, usize)> {
+        match (elements[0].as_symbol()?, elements.get(1)?) {
+            ("define", LispExpr::List(signature)) => Some((self.shadowed(signature), 2)),
+            ("lambda", LispExpr::List(params)) => Some((self.shadowed(params), 2)),
+            ("defun", _) => match elements.get(2)? {
+                LispExpr::List(params) => Some((self.shadowed(params), 3)),
+                _ => None,
+            },
+            // Bindings are sequential, so each value is typed in the scope so far
+            ("let" | "let*" | "let-values", LispExpr::List(bindings)) => {
+                let mut scope = self.clone();
+                for binding in bindings.iter() {
+                    match binding.as_list() {
+                        Some([pattern @ LispExpr::List(_), ..]) => scope = scope.shadowed(std::slice::from_ref(pattern)),
+                        Some([name, value @ ..]) if let Some(name) = name.as_symbol() => {
+                            let value_type = value.first().map(|value| scope.infer_type(value)).unwrap_or(InferredType::Unknown);
+                            let value_type = scope.assigned_type(name, value_type, &Self::assignments(&elements[2..]));
+                            scope.unbind(name);
+                            scope.type_environment.insert(name.to_string(), value_type);
+                        }
+                        _ => {}
+                    }
+                }
+                Some((scope, 2))
+            }
+            ("destructuring-bind", pattern) if elements.len() >= 3 => Some((self.shadowed(std::slice::from_ref(pattern)), 3)),
+            // Named let: the loop variables take their initial types, the loop name is a function
+            ("let", LispExpr::Symbol(function)) => {
+                let LispExpr::List(bindings) = elements.get(2)? else {
+                    return None;
+                };
+                let mut scope = self.shadowed(&[LispExpr::Symbol(function.clone())]);
+                for (name, value) in Self::binding_pairs(bindings) {
+                    let value_type = value.map(|value| self.infer_type(value)).unwrap_or(InferredType::Unknown);
+                    scope.unbind(name);
+                    scope.type_environment.insert(name.to_string(), value_type);
+                }
+                Some((scope, 3))
+            }
+            // Values may refer to any binding, so nothing is known about them up front
+            ("letrec", LispExpr::List(bindings)) => {
+                let mut scope = self.clone();
+                for (name, _) in Self::binding_pairs(bindings) {
+                    scope.unbind(name);
+                }
+                Some((scope, 2))
+            }
+            _ => None,
+        }
+    }
+
+    /// Every `(set! name value)` in `exprs`
+    fn assignments(exprs: &[LispExpr]) -> Vec<(String, LispExpr)> {
+        struct Assignments(Vec<(String, LispExpr)>);
+
+        impl Visitor<'_> for Assignments {
+            fn visit_expr(&mut self, expr: &LispExpr) {
+                if let Some([op, LispExpr::Symbol(name), value]) = expr.as_list()
+                    && op.as_symbol() == Some("set!")
+                {
+                    self.0.push((name.to_string(), value.clone()));
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut assignments = Assignments(Vec::new());
+        exprs.iter().for_each(|expr| assignments.visit_expr(expr));
+        assignments.0
+    }
+
+    /// The type of a variable bound to a `value_type`: unknown when one of
+    /// `assignments` gives it a value of another type
+    fn assigned_type(&self, name: &str, value_type: InferredType, assignments: &[(String, LispExpr)]) -> InferredType {
+        let reassigned = assignments.iter().any(|(assigned, value)| assigned == name && self.infer_type(value) != value_type);
+        if reassigned { InferredType::Unknown } else { value_type }
+    }
+
+    /// Record the types of the top-level definitions in `program`
+    pub fn with_definitions(mut self, program: &[LispExpr]) -> Self {
+        self.assignments = Self::assignments(program);
+        for expr in program {
+            self.register_definition(expr);
+        }
+        self
+    }
+
+    /// Record the type introduced by a top-level `define`/`defun`
+    fn register_definition(&mut self, expr: &LispExpr) {
+        let LispExpr::List(elements) = expr else {
+            return;
+        };
+        if elements.len() < 3 {
+            return;
         }
+
+        match (elements[0].as_symbol(), &elements[1]) {
+            (Some("define"), LispExpr::Symbol(name)) => {
+                let value_type = self.assigned_type(name, self.infer_type(&elements[2]), &self.assignments);
+                self.unbind(name);
+                self.type_environment.insert(name.to_string(), value_type);
+            }
+            (Some("define"), LispExpr::List(signature)) if !signature.is_empty() => {
+                if let Some(name) = signature[0].as_symbol() {
+                    let return_type = self.shadowed(&signature[1..]).infer_type(&elements[elements.len() - 1]);
+                    self.unbind(name);
+                    self.function_types.insert(name.to_string(), return_type);
+                }
+            }
+            (Some("defun"), LispExpr::Symbol(name)) if elements.len() >= 4 => {
+                let params = match &elements[2] {
+                    LispExpr::List(params) => &params[..],
+                    _ => &[],
+                };
+                let return_type = self.shadowed(params).infer_type(&elements[elements.len() - 1]);
+                self.unbind(name);
+                self.function_types.insert(name.to_string(), return_type);
+                if let Ok(list) = LambdaList::parse(params) {
+                    self.parameter_types.insert(name.to_string(), Self::parameter_types(&list, &elements[3..]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The type of each required parameter: the annotated one, else a number when the
+    /// body uses the parameter as an arithmetic operand
+    fn parameter_types(list: &LambdaList, body: &[LispExpr]) -> Vec<InferredType> {
+        struct Arithmetic<'a> {
+            names: &'a [crate::symbol::Symbol],
+            used: HashSet<crate::symbol::Symbol>,
+        }
+
+        impl Visitor<'_> for Arithmetic<'_> {
+            fn visit_expr(&mut self, expr: &LispExpr) {
+                if let Some([op, args @ ..]) = expr.as_list()
+                    && matches!(op.as_symbol(), Some("+" | "-" | "*" | "/" | "mod"))
+                {
+                    for arg in args {
+                        if let LispExpr::Symbol(name) = arg
+                            && self.names.contains(name)
+                        {
+                            self.used.insert(name.clone());
+                        }
+                    }
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut arithmetic = Arithmetic { names: &list.required, used: HashSet::new() };
+        body.iter().for_each(|expr| arithmetic.visit_expr(expr));
+        list.required
+            .iter()
+            .zip(&list.required_types)
+            .map(|(name, type_name)| match type_name.as_ref().map(|type_name| type_name.as_str()) {
+                Some("number") => InferredType::Number,
+                Some("string") => InferredType::String,
+                Some("bool") => InferredType::Bool,
+                Some(_) => InferredType::Unknown,
+                None if arithmetic.used.contains(name) => InferredType::Number,
+                None => InferredType::Unknown,
+            })
+            .collect()
     }
 
-    fn infer_type(&self, expr: &LispExpr) -> InferredType {
-        match expr {
+    /// The type `expr` evaluates to, given the definitions seen so far
+    pub fn infer_type(&self, expr: &LispExpr) -> InferredType {
+        grow_stack(|| match expr {
             LispExpr::Number(_) => InferredType::Number,
             LispExpr::String(_) => InferredType::String,
             LispExpr::Bool(_) => InferredType::Bool,
             LispExpr::Nil => InferredType::Symbol,
             LispExpr::Symbol(s) => {
                 self.type_environment
-                    .get(s)
+                    .get(s.as_str())
                     .cloned()
                     .unwrap_or(InferredType::Unknown)
             }
@@ -117,8 +661,13 @@ impl TypeSafetyValidator {
                 } else if let Some(op) = elements[0].as_symbol() {
                     // Infer return type based on operation
                     match op {
-                        "+" | "-" | "*" | "/" => InferredType::Number,
+                        "+" | "-" | "*" | "/" | "mod" => InferredType::Number,
                         "<" | ">" | "<=" | ">=" | "=" => InferredType::Bool,
+                        "string->number" | "now" | "elapsed" | "random" | "random-int" => InferredType::Number,
+                        "number->string" | "symbol->string" | "json-stringify" => InferredType::String,
+                        "read-file" | "getenv" | "run-command" | "http-get" | "http-post" => InferredType::String,
+                        "string->symbol" => InferredType::Symbol,
+                        "to-bool" | "not" => InferredType::Bool,
                         "if" => {
                             // if expressions return the type of their branches
                             if elements.len() >= 3 {
@@ -127,7 +676,11 @@ impl TypeSafetyValidator {
                                 InferredType::Unknown
                             }
                         }
-                        _ => InferredType::Unknown,
+                        _ => self
+                            .function_types
+                            .get(op)
+                            .cloned()
+                            .unwrap_or(InferredType::Unknown),
                     }
                 } else {
                     let first_type = self.infer_type(&elements[0]);
@@ -136,23 +689,26 @@ impl TypeSafetyValidator {
             }
             LispExpr::Quote(_) | LispExpr::Quasiquote(_) => InferredType::Any,
             _ => InferredType::Unknown,
-        }
+        })
     }
 
     fn validate_operation(&self, op: &str, args: &[LispExpr]) -> ValidationResult {
         match op {
-            "+" | "-" | "*" | "/" => {
+            "+" | "-" | "*" | "/" | "mod" => {
                 // Arithmetic operations require numeric operands
                 for arg in args {
                     let arg_type = self.infer_type(arg);
                     if !matches!(arg_type, InferredType::Number | InferredType::Unknown) {
                         return Err(ValidationError {
                             rule: ValidationRule::TypeSafety,
+                            severity: Severity::Error,
                             message: format!(
-                                "Type mismatch: arithmetic operation '{}' requires numeric operands, got {:?}",
+                                "Type mismatch: arithmetic operation '{}' requires numeric operands, got {}",
                                 op, arg_type
                             ),
-                            context: Some(format!("{:?}", arg)),
+                            context: Some(arg.to_string()),
+                            suggestion: Some("Convert the operand to a number (e.g. with string->number) or use a string operation instead".to_string()),
+                            fix: Self::numeric_conversion_fix(arg, &arg_type),
                         });
                     }
                 }
@@ -167,17 +723,92 @@ impl TypeSafetyValidator {
                     if !self.types_compatible(&left_type, &right_type) {
                         return Err(ValidationError {
                             rule: ValidationRule::TypeSafety,
+                            severity: Severity::Error,
                             message: format!(
-                                "Type mismatch: comparison '{}' requires compatible types, got {:?} and {:?}",
+                                "Type mismatch: comparison '{}' requires compatible types, got {} and {}",
                                 op, left_type, right_type
                             ),
-                            context: Some(format!("{:?} vs {:?}", args[0], args[1])),
+                            context: Some(format!("{} vs {}", args[0], args[1])),
+                            suggestion: Some("Compare values of the same type, converting one side if necessary".to_string()),
+                            fix: match (&left_type, &right_type) {
+                                (InferredType::String, InferredType::Number) => Self::numeric_conversion_fix(&args[0], &left_type),
+                                (InferredType::Number, InferredType::String) => Self::numeric_conversion_fix(&args[1], &right_type),
+                                _ => None,
+                            },
+                        });
+                    }
+                }
+                Ok(())
+            }
+            "string->number" | "number->string" | "symbol->string" | "json-parse" => {
+                // Conversions require the type they convert from
+                let expected = match op {
+                    "string->number" | "json-parse" => InferredType::String,
+                    "number->string" => InferredType::Number,
+                    _ => InferredType::Symbol,
+                };
+                match args {
+                    [arg] => {
+                        let arg_type = self.infer_type(arg);
+                        if !self.types_compatible(&expected, &arg_type) {
+                            return Err(ValidationError {
+                                rule: ValidationRule::TypeSafety,
+                                severity: Severity::Error,
+                                message: format!("Type mismatch: '{}' requires a {} argument, got {}", op, expected, arg_type),
+                                context: Some(arg.to_string()),
+                                suggestion: Some("Pass a value of the type being converted from, or drop the conversion".to_string()),
+                                fix: None,
+                            });
+                        }
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => {
+                // Calls to top-level functions pass what their parameters take
+                let Some(types) = self.parameter_types.get(op) else {
+                    return Ok(()); // Unknown operations pass through
+                };
+                for (position, (arg, expected)) in args.iter().zip(types).enumerate() {
+                    let arg_type = self.infer_type(arg);
+                    if !self.types_compatible(expected, &arg_type) {
+                        return Err(ValidationError {
+                            rule: ValidationRule::TypeSafety,
+                            severity: Severity::Error,
+                            message: format!("Type mismatch: argument {} of '{}' must be a {}, got {}", position + 1, op, expected, arg_type),
+                            context: Some(arg.to_string()),
+                            suggestion: Some(format!("Pass a {} to '{}', converting the argument if necessary", expected, op)),
+                            fix: match expected {
+                                InferredType::Number => Self::numeric_conversion_fix(arg, &arg_type),
+                                _ => None,
+                            },
                         });
                     }
                 }
                 Ok(())
             }
-            _ => Ok(()), // Unknown operations pass through
+        }
+    }
+
+    /// Fix for a string used where a number is expected: numeric string
+    /// literals become numbers, anything else is wrapped in `string->number`
+    fn numeric_conversion_fix(arg: &LispExpr, arg_type: &InferredType) -> Option<Box<SuggestedFix>> {
+        match (arg, arg_type) {
+            (LispExpr::String(s), _) => match s.trim().parse::<f64>() {
+                Ok(n) => Some(Box::new(SuggestedFix::new(arg.clone(), LispExpr::Number(n), Applicability::MachineApplicable))),
+                Err(_) => Some(Box::new(SuggestedFix::new(
+                    arg.clone(),
+                    LispExpr::List(vec![LispExpr::Symbol("string->number".into()), arg.clone()].into()),
+                    Applicability::MaybeIncorrect,
+                ))),
+            },
+            (_, InferredType::String) => Some(Box::new(SuggestedFix::new(
+                arg.clone(),
+                LispExpr::List(vec![LispExpr::Symbol("string->number".into()), arg.clone()].into()),
+                Applicability::MachineApplicable,
+            ))),
+            _ => None,
         }
     }
 
@@ -192,24 +823,43 @@ impl TypeSafetyValidator {
     }
 }
 
+impl Default for TypeSafetyValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ASTValidator for TypeSafetyValidator {
     fn validate(&self, expr: &LispExpr) -> ValidationResult {
-        match expr {
+        grow_stack(|| match expr {
             LispExpr::List(elements) if !elements.is_empty() => {
                 // Check if this is an operation
                 if let Some(op) = elements[0].as_symbol() {
                     self.validate_operation(op, &elements[1..])?;
                 }
 
-                // Recursively validate all elements
-                for elem in elements {
-                    self.validate(elem)?;
+                // Recursively validate all elements, with binding forms
+                // checking their body against the bound names
+                match self.body_scope(elements) {
+                    Some((scope, body_start)) => {
+                        for elem in &elements[..body_start] {
+                            self.validate(elem)?;
+                        }
+                        for elem in &elements[body_start..] {
+                            scope.validate(elem)?;
+                        }
+                    }
+                    None => {
+                        for elem in elements.iter() {
+                            self.validate(elem)?;
+                        }
+                    }
                 }
                 Ok(())
             }
             LispExpr::List(elements) => {
                 // Empty list or nested lists
-                for elem in elements {
+                for elem in elements.iter() {
                     self.validate(elem)?;
                 }
                 Ok(())
@@ -226,12 +876,27 @@ impl ASTValidator for TypeSafetyValidator {
                 Ok(())
             }
             _ => Ok(()), // Atoms are always valid
-        }
+        })
     }
 
     fn enabled_rules(&self) -> Vec<ValidationRule> {
         vec![ValidationRule::TypeSafety]
     }
+
+    /// A global's type is tracked from its `define` on, unless the program `set!`s it
+    /// to a value of another type
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut scope = self.clone();
+        scope.assignments = Self::assignments(program);
+        program
+            .iter()
+            .map(|expr| {
+                let result = scope.validate(expr);
+                scope.register_definition(expr);
+                result
+            })
+            .collect()
+    }
 }
 
 /// Resource bounds validator (detects infinite loops/recursion)
@@ -251,77 +916,188 @@ impl ResourceBoundsValidator {
         self
     }
 
-    fn check_immediate_recursion(&self, expr: &LispExpr, context: &HashSet<String>) -> ValidationResult {
-        match expr {
+    fn check_immediate_recursion(&self, expr: &LispExpr) -> ValidationResult {
+        grow_stack(|| match expr {
             LispExpr::List(elements) if !elements.is_empty() => {
-                // Check for (define (foo) (foo)) pattern
-                if let Some("define") = elements[0].as_symbol() {
-                    if elements.len() >= 3 {
-                        // Extract function name
-                        let fn_name = if let Some(LispExpr::List(def_list)) = elements.get(1) {
-                            def_list.get(0).and_then(|e| e.as_symbol())
-                        } else {
-                            elements.get(1).and_then(|e| e.as_symbol())
-                        };
-
-                        if let Some(name) = fn_name {
-                            // Check if body immediately calls itself without any base case
-                            let body = &elements[2];
-                            if self.is_immediate_self_call(body, name) {
-                                return Err(ValidationError {
-                                    rule: ValidationRule::ResourceBounds,
-                                    message: format!(
-                                        "Infinite recursion detected: function '{}' calls itself without any conditional base case",
-                                        name
-                                    ),
-                                    context: Some(format!("{:?}", expr)),
-                                });
-                            }
-                        }
-                    }
+                // Check for (define (foo) (foo)) pattern, also through begin/let wrappers
+                if let Some((name, body)) = function_body(expr)
+                    && let Some(call) = body.iter().find(|form| unconditional_calls(form).contains(&name))
+                {
+                    return Err(ValidationError {
+                        rule: ValidationRule::ResourceBounds,
+                        severity: Severity::Error,
+                        message: format!(
+                            "Infinite recursion detected: function '{}' calls itself without any conditional base case",
+                            name
+                        ),
+                        context: Some(expr.to_string()),
+                        suggestion: Some(format!("Wrap the recursive call in a conditional base case, e.g. (if <base-case> <value> ({} ...))", name)),
+                        fix: Some(Box::new(base_case_fix(call))),
+                    });
                 }
 
                 // Recursively check nested expressions
-                for elem in elements {
-                    self.check_immediate_recursion(elem, context)?;
+                for elem in elements.iter() {
+                    self.check_immediate_recursion(elem)?;
                 }
                 Ok(())
             }
             LispExpr::Quote(inner)
             | LispExpr::Quasiquote(inner)
             | LispExpr::Unquote(inner)
-            | LispExpr::Splice(inner) => self.check_immediate_recursion(inner, context),
-            LispExpr::Macro { body, .. } => self.check_immediate_recursion(body, context),
+            | LispExpr::Splice(inner) => self.check_immediate_recursion(inner),
+            LispExpr::Macro { body, .. } => self.check_immediate_recursion(body),
             LispExpr::MacroCall { args, .. } => {
                 for arg in args {
-                    self.check_immediate_recursion(arg, context)?;
+                    self.check_immediate_recursion(arg)?;
                 }
                 Ok(())
             }
             _ => Ok(()),
+        })
+    }
+
+    /// Top-level functions that call each other unconditionally, such as
+    /// `(define (f) (g)) (define (g) (f))`: each cycle of the call graph that
+    /// only follows unconditional calls never reaches a base case
+    fn check_mutual_recursion(&self, program: &[LispExpr], results: &mut [ValidationResult]) {
+        let mut locations = HashMap::new();
+        let mut definitions = Vec::new();
+        for (index, expr) in program.iter().enumerate() {
+            let Some((name, body)) = function_body(expr) else {
+                continue;
+            };
+            locations.insert(name, index);
+            let references = body.iter().flat_map(unconditional_calls).map(str::to_string).collect();
+            definitions.push(Definition { name: name.to_string(), kind: DefinitionKind::Function, references });
+        }
+
+        let graph = DependencyGraph::from_definitions(definitions);
+        for component in graph.components().into_iter().filter(|component| component.len() > 1) {
+            let names = format!("'{}'", component.join("', '"));
+            for name in &component {
+                let index = locations[name.as_str()];
+                if results[index].is_err() {
+                    continue;
+                }
+                let expr = &program[index];
+                let Some((_, body)) = function_body(expr) else {
+                    continue;
+                };
+                // Every member of a cycle calls another member
+                let Some(callee) = graph.get(name).and_then(|definition| definition.references.iter().find(|callee| component.contains(callee))) else {
+                    continue;
+                };
+                let call = body.iter().find(|form| unconditional_calls(form).contains(&callee.as_str()));
+                results[index] = Err(ValidationError {
+                    rule: ValidationRule::ResourceBounds,
+                    severity: Severity::Error,
+                    message: format!(
+                        "Infinite recursion detected: functions {} call each other without any conditional base case",
+                        names
+                    ),
+                    context: Some(expr.to_string()),
+                    suggestion: Some(format!("Wrap the call to '{}' in a conditional base case, e.g. (if <base-case> <value> ({} ...))", callee, callee)),
+                    fix: call.map(|call| Box::new(base_case_fix(call))),
+                });
+            }
         }
     }
+}
 
-    fn is_immediate_self_call(&self, expr: &LispExpr, fn_name: &str) -> bool {
-        match expr {
-            LispExpr::List(elements) if !elements.is_empty() => {
-                // Check if this is a direct call to the function
-                if let Some(call_name) = elements[0].as_symbol() {
-                    if call_name == fn_name {
-                        return true;
+/// The name and body forms of a function definition, `(define (f ...) body...)`
+/// or `(defun f (...) body...)`
+fn function_body(expr: &LispExpr) -> Option<(&str, &[LispExpr])> {
+    let (name, DefinitionKind::Function) = defined_name(expr)? else {
+        return None;
+    };
+    let LispExpr::List(elements) = expr else {
+        return None;
+    };
+    let body = match elements[0].as_symbol() {
+        Some("defun") => elements.get(3..)?,
+        _ => &elements[2..],
+    };
+    Some((name, body))
+}
+
+/// The functions `expr` always calls when evaluated: the head of a call and the
+/// calls in its arguments, looking through `begin`/`progn` and `let`/`let*`
+/// wrappers but not into conditionals, lambdas, quoted data, or nested definitions
+fn unconditional_calls(expr: &LispExpr) -> Vec<&str> {
+    let mut calls = Vec::new();
+    collect_unconditional_calls(expr, &mut calls);
+    calls
+}
+
+fn collect_unconditional_calls<'e>(expr: &'e LispExpr, calls: &mut Vec<&'e str>) {
+    grow_stack(|| {
+        let LispExpr::List(elements) = expr else {
+            return;
+        };
+        match elements.first().and_then(LispExpr::as_symbol) {
+            Some("begin" | "progn") => {
+                for form in &elements[1..] {
+                    collect_unconditional_calls(form, calls);
+                }
+            }
+            Some("let" | "let*") => {
+                // A named let puts its name before the bindings
+                let skip = if matches!(elements.get(1), Some(LispExpr::Symbol(_))) { 2 } else { 1 };
+                if let Some(LispExpr::List(bindings)) = elements.get(skip) {
+                    for binding in bindings.iter() {
+                        if let LispExpr::List(binding) = binding {
+                            for value in binding.iter().skip(1) {
+                                collect_unconditional_calls(value, calls);
+                            }
+                        }
                     }
                 }
-                false
+                for form in elements.iter().skip(skip + 1) {
+                    collect_unconditional_calls(form, calls);
+                }
             }
-            _ => false,
+            Some("if" | "cond" | "when" | "unless" | "and" | "or" | "lambda" | "quote" | "define" | "defun" | "defmacro") => {}
+            Some(name) => {
+                calls.push(name);
+                for arg in &elements[1..] {
+                    collect_unconditional_calls(arg, calls);
+                }
+            }
+            None => {}
         }
+    })
+}
+
+/// Wraps `call` in an `if` with placeholders for the base case
+fn base_case_fix(call: &LispExpr) -> SuggestedFix {
+    SuggestedFix::new(
+        call.clone(),
+        LispExpr::List(vec![
+            LispExpr::Symbol("if".into()),
+            LispExpr::Symbol("<base-case>".into()),
+            LispExpr::Symbol("<base-value>".into()),
+            call.clone(),
+        ].into()),
+        Applicability::HasPlaceholders,
+    )
+}
+
+impl Default for ResourceBoundsValidator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl ASTValidator for ResourceBoundsValidator {
     fn validate(&self, expr: &LispExpr) -> ValidationResult {
-        let context = HashSet::new();
-        self.check_immediate_recursion(expr, &context)
+        self.check_immediate_recursion(expr)
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut results: Vec<ValidationResult> = program.iter().map(|expr| self.validate(expr)).collect();
+        self.check_mutual_recursion(program, &mut results);
+        results
     }
 
     fn enabled_rules(&self) -> Vec<ValidationRule> {
@@ -347,26 +1123,28 @@ impl FFIRestrictionsValidator {
     }
 
     fn check_unsafe_operations(&self, expr: &LispExpr) -> ValidationResult {
-        match expr {
+        grow_stack(|| match expr {
             LispExpr::List(elements) if !elements.is_empty() => {
                 // Check for unsafe Rust operations
-                if let Some(op) = elements[0].as_symbol() {
-                    if op.starts_with("rust-unsafe") || op.starts_with("ffi-") {
-                        if !self.allowed_ffi_functions.contains(op) {
-                            return Err(ValidationError {
-                                rule: ValidationRule::FFIRestrictions,
-                                message: format!(
-                                    "FFI restriction: unsafe operation '{}' is not allowed",
-                                    op
-                                ),
-                                context: Some(format!("{:?}", expr)),
-                            });
-                        }
-                    }
+                if let Some(op) = elements[0].as_symbol()
+                    && (op.starts_with("rust-unsafe") || op.starts_with("ffi-"))
+                    && !self.allowed_ffi_functions.contains(op)
+                {
+                    return Err(ValidationError {
+                        rule: ValidationRule::FFIRestrictions,
+                        severity: Severity::Error,
+                        message: format!(
+                            "FFI restriction: unsafe operation '{}' is not allowed",
+                            op
+                        ),
+                        context: Some(expr.to_string()),
+                        suggestion: Some(format!("Remove the call or explicitly allow '{}' in the FFI restrictions", op)),
+                        fix: None,
+                    });
                 }
 
                 // Recursively check nested expressions
-                for elem in elements {
+                for elem in elements.iter() {
                     self.check_unsafe_operations(elem)?;
                 }
                 Ok(())
@@ -383,7 +1161,13 @@ impl FFIRestrictionsValidator {
                 Ok(())
             }
             _ => Ok(()),
-        }
+        })
+    }
+}
+
+impl Default for FFIRestrictionsValidator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -397,80 +1181,1566 @@ impl ASTValidator for FFIRestrictionsValidator {
     }
 }
 
-/// Complexity limits validator
-pub struct ComplexityLimitsValidator {
-    max_nesting_depth: usize,
+/// Programs that run their arguments as commands
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "fish", "cmd", "cmd.exe", "powershell", "pwsh"];
+
+/// Taint-tracking validator (untrusted data flowing into FFI/process forms)
+///
+/// Values produced by input forms such as `read-file` are tainted, and taint
+/// propagates through `let` bindings, the parameters of a lambda applied to
+/// tainted arguments, calls to functions that return tainted data, and any call
+/// that receives a tainted argument. Reaching a sink (`rust-unsafe*`, `ffi-*`,
+/// process spawning) with a tainted value is a violation, and so is passing one
+/// to a function parameter that reaches a sink.
+#[derive(Clone)]
+pub struct TaintTrackingValidator {
+    taint_sources: HashSet<String>,
+    process_sinks: HashSet<String>,
+    taint_string_literals: bool,
+    /// Top-level functions whose result is tainted, with where the taint comes from
+    tainted_functions: HashMap<String, String>,
+    /// Top-level functions with parameters that reach a sink
+    sink_functions: HashMap<String, SinkSummary>,
 }
 
-impl ComplexityLimitsValidator {
+/// The parameters of a function that carry what they receive to a sink
+#[derive(Clone)]
+struct SinkSummary {
+    params: Vec<LispExpr>,
+    /// Each parameter that reaches a sink, with the violation it causes when tainted
+    /// by `parameter_origin`
+    reaches: Vec<(String, ValidationError)>,
+}
+
+impl TaintTrackingValidator {
     pub fn new() -> Self {
-        ComplexityLimitsValidator {
-            max_nesting_depth: 50,
+        let taint_sources = ["read-file", "read-line", "read-stdin", "http-get", "http-post", "getenv"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let process_sinks = ["run-command", "spawn-process", "process-spawn", "shell", "exec"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        TaintTrackingValidator {
+            taint_sources,
+            process_sinks,
+            taint_string_literals: false,
+            tainted_functions: HashMap::new(),
+            sink_functions: HashMap::new(),
         }
     }
 
-    pub fn with_max_nesting(mut self, depth: usize) -> Self {
-        self.max_nesting_depth = depth;
+    /// Register an additional form whose result is considered tainted
+    pub fn with_taint_source(mut self, name: String) -> Self {
+        self.taint_sources.insert(name);
         self
     }
 
-    fn check_nesting_depth(&self, expr: &LispExpr, current_depth: usize) -> ValidationResult {
-        if current_depth > self.max_nesting_depth {
-            return Err(ValidationError {
-                rule: ValidationRule::ComplexityLimits,
-                message: format!(
-                    "Complexity limit exceeded: nesting depth {} exceeds maximum {}",
-                    current_depth, self.max_nesting_depth
-                ),
-                context: None,
-            });
-        }
+    /// Treat string literals themselves as untrusted (for fully untrusted AI code)
+    pub fn with_tainted_literals(mut self, enabled: bool) -> Self {
+        self.taint_string_literals = enabled;
+        self
+    }
 
-        match expr {
-            LispExpr::List(elements) => {
-                for elem in elements {
-                    self.check_nesting_depth(elem, current_depth + 1)?;
-                }
-                Ok(())
-            }
-            LispExpr::Quote(inner)
-            | LispExpr::Quasiquote(inner)
-            | LispExpr::Unquote(inner)
+    fn is_sink(&self, op: &str) -> bool {
+        op.starts_with("rust-unsafe") || op.starts_with("ffi-") || self.process_sinks.contains(op)
+    }
+
+    /// Returns a description of where the taint came from, if the expression is tainted
+    fn taint_origin(&self, expr: &LispExpr, tainted_vars: &HashMap<String, String>) -> Option<String> {
+        grow_stack(|| match expr {
+            LispExpr::String(_) if self.taint_string_literals => Some("string literal".to_string()),
+            LispExpr::Symbol(name) => tainted_vars.get(name.as_str()).cloned(),
+            LispExpr::List(elements) if !elements.is_empty() => {
+                match elements[0].as_symbol() {
+                    Some(op) if self.taint_sources.contains(op) => Some(format!("'{}'", op)),
+                    Some(op) if let Some(origin) = self.tainted_functions.get(op) => {
+                        Some(format!("function '{}' (returning {})", op, origin))
+                    }
+                    None if let Some((params, body)) = Self::lambda_parts(&elements[0]) => {
+                        let scoped = self.bind_lambda(params, &elements[1..], tainted_vars);
+                        body.iter().find_map(|e| self.taint_origin(e, &scoped))
+                    }
+                    Some(op @ ("let" | "let*" | "let-values")) if elements.len() >= 3 => {
+                        let scoped = self.bind_let(&elements[1], op == "let*", tainted_vars);
+                        elements[2..].iter().find_map(|e| self.taint_origin(e, &scoped))
+                    }
+                    Some("destructuring-bind") if elements.len() >= 4 => {
+                        let scoped = self.bind_destructuring(elements, tainted_vars);
+                        elements[3..].iter().find_map(|e| self.taint_origin(e, &scoped))
+                    }
+                    Some("if") => elements
+                        .get(2..)
+                        .unwrap_or(&[])
+                        .iter()
+                        .find_map(|e| self.taint_origin(e, tainted_vars)),
+                    _ => elements[1..].iter().find_map(|e| self.taint_origin(e, tainted_vars)),
+                }
+            }
+            LispExpr::Quasiquote(inner) | LispExpr::Unquote(inner) | LispExpr::Splice(inner) => {
+                self.taint_origin(inner, tainted_vars)
+            }
+            _ => None,
+        })
+    }
+
+    /// Extend the tainted variable set with the tainted bindings of a `let`. The
+    /// values of a `let*` (`sequential`) see the bindings before them.
+    fn bind_let(&self, bindings: &LispExpr, sequential: bool, tainted_vars: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut scoped = tainted_vars.clone();
+        if let LispExpr::List(bindings) = bindings {
+            for binding in bindings.iter() {
+                if let LispExpr::List(pair) = binding
+                    && pair.len() == 2
+                {
+                    let origin = self.taint_origin(&pair[1], if sequential { &scoped } else { tainted_vars });
+                    self.bind_pattern(&pair[0], origin, &mut scoped);
+                }
+            }
+        }
+        scoped
+    }
+
+    /// Every variable a pattern binds is as tainted as the value it takes apart,
+    /// which comes from `origin` when it is tainted
+    fn bind_pattern(&self, pattern: &LispExpr, origin: Option<String>, scoped: &mut HashMap<String, String>) {
+        let Ok(pattern) = Pattern::parse(pattern) else { return };
+        for name in pattern.variables() {
+            match &origin {
+                Some(origin) => scoped.insert(name.to_string(), format!("variable '{}' (from {})", name, origin)),
+                None => scoped.remove(name.as_str()),
+            };
+        }
+    }
+
+    /// The taint of the variables a `destructuring-bind` introduces for its body
+    fn bind_destructuring(&self, elements: &[LispExpr], tainted_vars: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut scoped = tainted_vars.clone();
+        self.bind_pattern(&elements[1], self.taint_origin(&elements[2], tainted_vars), &mut scoped);
+        scoped
+    }
+
+    /// The parameters and body of a `lambda` (or `fn`) form
+    fn lambda_parts(expr: &LispExpr) -> Option<(&[LispExpr], &[LispExpr])> {
+        match expr.as_list()? {
+            [head, LispExpr::List(params), body @ ..] if matches!(head.as_symbol(), Some("lambda" | "fn")) => Some((params, body)),
+            _ => None,
+        }
+    }
+
+    /// Bind the parameters of a lambda applied directly to `args`, each as tainted as
+    /// the argument it receives
+    fn bind_lambda(&self, params: &[LispExpr], args: &[LispExpr], tainted_vars: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut scoped = tainted_vars.clone();
+        for (name, origin) in self.argument_origins(params, args, tainted_vars) {
+            match origin {
+                Some(origin) => scoped.insert(name.to_string(), format!("parameter '{}' (from {})", name, origin)),
+                None => scoped.remove(&*name),
+            };
+        }
+        scoped
+    }
+
+    /// Where the value each parameter receives from `args` is tainted from, or its
+    /// default's when it receives none. When the arguments don't fit the parameters,
+    /// every parameter is as tainted as any argument.
+    fn argument_origins(&self, params: &[LispExpr], args: &[LispExpr], tainted_vars: &HashMap<String, String>) -> Vec<(Symbol, Option<String>)> {
+        let origin = |arg: Option<&LispExpr>| arg.and_then(|arg| self.taint_origin(arg, tainted_vars));
+        let matched = LambdaList::parse(params).and_then(|list| {
+            let arguments = list.match_arguments("lambda", args.iter().collect(), |arg| match arg {
+                LispExpr::Symbol(s) if lambda_list::is_keyword(s) => Some(s.as_str()),
+                _ => None,
+            });
+            arguments.map(|arguments| (list, arguments))
+        });
+        match matched {
+            Ok((list, arguments)) => {
+                let defaulted = |parameters: &[lambda_list::Parameter], values: Vec<Option<&LispExpr>>| {
+                    parameters
+                        .iter()
+                        .zip(values)
+                        .map(|(parameter, value)| (parameter.name.clone(), origin(value.or(parameter.default.as_ref()))))
+                        .collect::<Vec<_>>()
+                };
+                let required = list.required.iter().cloned().zip(arguments.required.into_iter().map(|arg| origin(Some(arg))));
+                let rest = list.rest.clone().map(|name| (name, arguments.rest.into_iter().find_map(|arg| origin(Some(arg)))));
+                required
+                    .chain(defaulted(&list.optional, arguments.optional))
+                    .chain(rest)
+                    .chain(defaulted(&list.keys, arguments.keys))
+                    .collect()
+            }
+            Err(_) => {
+                let any = args.iter().find_map(|arg| origin(Some(arg)));
+                Self::parameter_names(params).into_iter().map(|name| (Symbol::from(name), any.clone())).collect()
+            }
+        }
+    }
+
+    /// The names a parameter list binds
+    fn parameter_names(params: &[LispExpr]) -> Vec<&str> {
+        params
+            .iter()
+            .filter_map(|param| param.as_symbol().or_else(|| param.as_list()?.first()?.as_symbol()))
+            .filter(|name| !name.starts_with('&'))
+            .collect()
+    }
+
+    /// Where a summary of `function` says the taint of its parameter `param` comes from
+    fn parameter_origin(function: &str, param: &str) -> String {
+        format!("parameter '{}' of '{}'", param, function)
+    }
+
+    /// The name, parameters, and body of a top-level `defun` or `(define (name ...) ...)`
+    fn function_definition(expr: &LispExpr) -> Option<(&str, &[LispExpr], &[LispExpr])> {
+        match expr.as_list()? {
+            [head, LispExpr::Symbol(name), LispExpr::List(params), body @ ..] if head.as_symbol() == Some("defun") => {
+                Some((name, params, body))
+            }
+            [head, LispExpr::List(signature), body @ ..] if head.as_symbol() == Some("define") => {
+                let (name, params) = signature.split_first()?;
+                Some((name.as_symbol()?, params, body))
+            }
+            _ => None,
+        }
+    }
+
+    /// Record which functions in `program` return tainted data, given the tainted
+    /// `globals`, repeating until callers of newly tainted functions are followed.
+    /// Parameters are trusted here: a call with a tainted argument is tainted anyway.
+    /// Then record which parameters reach a sink.
+    fn summarize_functions(&mut self, program: &[LispExpr], globals: &HashMap<String, String>) {
+        loop {
+            let mut changed = false;
+            for (name, params, body) in program.iter().filter_map(Self::function_definition) {
+                if self.tainted_functions.contains_key(name) {
+                    continue;
+                }
+                let mut scoped = globals.clone();
+                for param in Self::parameter_names(params) {
+                    scoped.remove(param);
+                }
+                if let Some(origin) = body.iter().find_map(|e| self.taint_origin(e, &scoped)) {
+                    self.tainted_functions.insert(name.to_string(), origin);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.summarize_sinks(program);
+    }
+
+    /// Record which parameters of the functions in `program` reach a sink, directly
+    /// or through the parameters of the functions they call, repeating until callers
+    /// of newly recorded functions are followed. A function whose body reaches a sink
+    /// without any tainted parameter is reported where it is defined instead.
+    fn summarize_sinks(&mut self, program: &[LispExpr]) {
+        loop {
+            let mut changed = false;
+            for (name, params, body) in program.iter().filter_map(Self::function_definition) {
+                let check = |scoped: &HashMap<String, String>| body.iter().try_for_each(|e| self.check_taint_flow(e, scoped));
+                if check(&HashMap::new()).is_err() {
+                    continue;
+                }
+                let recorded = self.sink_functions.get(name).map_or(0, |summary| summary.reaches.len());
+                let reaches: Vec<(String, ValidationError)> = Self::parameter_names(params)
+                    .into_iter()
+                    .filter_map(|param| {
+                        let scoped = HashMap::from([(param.to_string(), Self::parameter_origin(name, param))]);
+                        check(&scoped).err().map(|error| (param.to_string(), error))
+                    })
+                    .collect();
+                if reaches.len() > recorded {
+                    self.sink_functions.insert(name.to_string(), SinkSummary { params: params.to_vec(), reaches });
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// A call passing tainted data to a parameter of `function` that reaches a sink,
+    /// reported as the violation inside it, with the parameter's taint followed back
+    /// to the call's argument
+    fn check_call(&self, expr: &LispExpr, function: &str, args: &[LispExpr], tainted_vars: &HashMap<String, String>) -> ValidationResult {
+        let Some(summary) = self.sink_functions.get(function) else {
+            return Ok(());
+        };
+        for (param, origin) in self.argument_origins(&summary.params, args, tainted_vars) {
+            let Some(origin) = origin else { continue };
+            if let Some((_, error)) = summary.reaches.iter().find(|(name, _)| *name == *param) {
+                let parameter = Self::parameter_origin(function, &param);
+                return Err(ValidationError {
+                    message: error.message.replacen(&parameter, &format!("{} (from {})", parameter, origin), 1),
+                    context: Some(expr.to_string()),
+                    ..error.clone()
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `run-command` passes its arguments to the program without a shell, so untrusted
+    /// data is only safe as an argument after a literal `"--"`, where it cannot be read
+    /// as an option, and never as the program or as the script of a shell's `-c`.
+    /// A function passing a parameter to such a position is summarized as a sink.
+    fn check_argv(&self, expr: &LispExpr, argv: &[LispExpr], tainted_vars: &HashMap<String, String>) -> ValidationResult {
+        let shell = matches!(argv.first(), Some(LispExpr::String(program)) if SHELLS.contains(&program.rsplit('/').next().unwrap_or_default()));
+        let mut end_of_options = false;
+        for (position, arg) in argv.iter().enumerate() {
+            if matches!(arg, LispExpr::String(s) if s == "--") {
+                end_of_options = true;
+            }
+            let Some(origin) = self.taint_origin(arg, tainted_vars) else {
+                continue;
+            };
+            let (message, suggestion) = if position == 0 {
+                (
+                    format!("Taint violation: untrusted data from {} chooses the program 'run-command' runs", origin),
+                    "Run a fixed program and pass untrusted data only as its arguments",
+                )
+            } else if shell {
+                (
+                    format!("Taint violation: untrusted data from {} reaches a shell through 'run-command'", origin),
+                    "Run the program directly instead of through a shell",
+                )
+            } else if !end_of_options {
+                (
+                    format!("Argv injection: untrusted data from {} reaches 'run-command' where it could be read as an option", origin),
+                    "Pass \"--\" before untrusted arguments so the program reads them as operands",
+                )
+            } else {
+                continue;
+            };
+            return Err(ValidationError {
+                rule: ValidationRule::TaintTracking,
+                severity: Severity::Error,
+                message,
+                context: Some(expr.to_string()),
+                suggestion: Some(suggestion.to_string()),
+                fix: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_taint_flow(&self, expr: &LispExpr, tainted_vars: &HashMap<String, String>) -> ValidationResult {
+        grow_stack(|| match expr {
+            LispExpr::List(elements) if !elements.is_empty() => {
+                if let Some(op) = elements[0].as_symbol() {
+                    if op == "run-command" {
+                        self.check_argv(expr, &elements[1..], tainted_vars)?;
+                    } else if !self.is_sink(op) {
+                        self.check_call(expr, op, &elements[1..], tainted_vars)?;
+                    } else {
+                        for arg in &elements[1..] {
+                            if let Some(origin) = self.taint_origin(arg, tainted_vars) {
+                                return Err(ValidationError {
+                                    rule: ValidationRule::TaintTracking,
+                                    severity: Severity::Error,
+                                    message: format!(
+                                        "Taint violation: untrusted data from {} reaches '{}'",
+                                        origin, op
+                                    ),
+                                    context: Some(expr.to_string()),
+                                    suggestion: Some("Validate or sanitize the value before passing it to this form".to_string()),
+                                    fix: None,
+                                });
+                            }
+                        }
+                    }
+
+                    if matches!(op, "let" | "let*" | "let-values") && elements.len() >= 3 {
+                        let scoped = match elements[1].as_list() {
+                            // Each value of a `let*` sees the bindings before it
+                            Some(bindings) if op == "let*" => {
+                                let mut scoped = tainted_vars.clone();
+                                for binding in bindings {
+                                    self.check_taint_flow(binding, &scoped)?;
+                                    if let Some([pattern, value]) = binding.as_list() {
+                                        let origin = self.taint_origin(value, &scoped);
+                                        self.bind_pattern(pattern, origin, &mut scoped);
+                                    }
+                                }
+                                scoped
+                            }
+                            _ => {
+                                self.check_taint_flow(&elements[1], tainted_vars)?;
+                                self.bind_let(&elements[1], false, tainted_vars)
+                            }
+                        };
+                        for body_expr in &elements[2..] {
+                            self.check_taint_flow(body_expr, &scoped)?;
+                        }
+                        return Ok(());
+                    }
+
+                    if op == "destructuring-bind" && elements.len() >= 4 {
+                        self.check_taint_flow(&elements[2], tainted_vars)?;
+                        let scoped = self.bind_destructuring(elements, tainted_vars);
+                        for body_expr in &elements[3..] {
+                            self.check_taint_flow(body_expr, &scoped)?;
+                        }
+                        return Ok(());
+                    }
+                }
+
+                // A lambda applied directly runs its body with the arguments bound
+                if let Some((params, body)) = Self::lambda_parts(&elements[0]) {
+                    for arg in &elements[1..] {
+                        self.check_taint_flow(arg, tainted_vars)?;
+                    }
+                    let scoped = self.bind_lambda(params, &elements[1..], tainted_vars);
+                    for body_expr in body {
+                        self.check_taint_flow(body_expr, &scoped)?;
+                    }
+                    return Ok(());
+                }
+
+                // Recursively check nested expressions
+                for elem in elements.iter() {
+                    self.check_taint_flow(elem, tainted_vars)?;
+                }
+                Ok(())
+            }
+            LispExpr::Quasiquote(inner)
+            | LispExpr::Unquote(inner)
+            | LispExpr::Splice(inner) => self.check_taint_flow(inner, tainted_vars),
+            LispExpr::Macro { body, .. } => self.check_taint_flow(body, tainted_vars),
+            LispExpr::MacroCall { args, .. } => {
+                for arg in args {
+                    self.check_taint_flow(arg, tainted_vars)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        })
+    }
+}
+
+impl Default for TaintTrackingValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for TaintTrackingValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.check_taint_flow(expr, &HashMap::new())
+    }
+
+    /// Globals a top-level `define` or `set!` gives a tainted value stay tainted in
+    /// the forms after it, until a later one gives them a trusted value
+    /// Calls to functions returning tainted data are tainted, wherever the function is
+    /// defined; once a global is tainted, so are the functions that return it.
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut validator = self.clone();
+        let mut globals = HashMap::new();
+        validator.summarize_functions(program, &globals);
+        program
+            .iter()
+            .map(|expr| {
+                let result = validator.check_taint_flow(expr, &globals);
+                if let Some([head, LispExpr::Symbol(name), value]) = expr.as_list()
+                    && matches!(head.as_symbol(), Some("define" | "set!"))
+                {
+                    match validator.taint_origin(value, &globals) {
+                        Some(origin) => {
+                            globals.insert(name.to_string(), format!("global '{}' (from {})", name, origin));
+                            validator.summarize_functions(program, &globals);
+                        }
+                        None => {
+                            globals.remove(name.as_str());
+                        }
+                    };
+                }
+                result
+            })
+            .collect()
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::TaintTracking]
+    }
+}
+
+/// Complexity limits validator: nesting depth everywhere, and the size and
+/// branching of each top-level function
+pub struct ComplexityLimitsValidator {
+    max_nesting_depth: usize,
+    max_cyclomatic_complexity: usize,
+    max_forms: usize,
+    max_arguments: usize,
+}
+
+impl ComplexityLimitsValidator {
+    pub fn new() -> Self {
+        ComplexityLimitsValidator {
+            max_nesting_depth: 50,
+            max_cyclomatic_complexity: 15,
+            max_forms: 500,
+            max_arguments: 8,
+        }
+    }
+
+    pub fn with_max_nesting(mut self, depth: usize) -> Self {
+        self.max_nesting_depth = depth;
+        self
+    }
+
+    /// Warn about functions with more decision points than this
+    pub fn with_max_cyclomatic_complexity(mut self, complexity: usize) -> Self {
+        self.max_cyclomatic_complexity = complexity;
+        self
+    }
+
+    /// Warn about functions made of more forms than this
+    pub fn with_max_forms(mut self, forms: usize) -> Self {
+        self.max_forms = forms;
+        self
+    }
+
+    /// Warn about functions taking more arguments than this
+    pub fn with_max_arguments(mut self, arguments: usize) -> Self {
+        self.max_arguments = arguments;
+        self
+    }
+
+    fn check_nesting_depth(&self, expr: &LispExpr, current_depth: usize) -> ValidationResult {
+        if current_depth > self.max_nesting_depth {
+            return Err(ValidationError {
+                rule: ValidationRule::ComplexityLimits,
+                severity: Severity::Error,
+                message: format!(
+                    "Complexity limit exceeded: nesting depth {} exceeds maximum {}",
+                    current_depth, self.max_nesting_depth
+                ),
+                context: None,
+                suggestion: Some("Split deeply nested expressions into smaller named definitions".to_string()),
+                fix: None,
+            });
+        }
+
+        grow_stack(|| match expr {
+            LispExpr::List(elements) => {
+                for elem in elements.iter() {
+                    self.check_nesting_depth(elem, current_depth + 1)?;
+                }
+                Ok(())
+            }
+            LispExpr::Quote(inner)
+            | LispExpr::Quasiquote(inner)
+            | LispExpr::Unquote(inner)
             | LispExpr::Splice(inner) => self.check_nesting_depth(inner, current_depth + 1),
             LispExpr::Macro { body, .. } => self.check_nesting_depth(body, current_depth + 1),
             LispExpr::MacroCall { args, .. } => {
                 for arg in args {
                     self.check_nesting_depth(arg, current_depth + 1)?;
                 }
-                Ok(())
+                Ok(())
+            }
+            _ => Ok(()),
+        })
+    }
+
+    fn check_function_metrics(&self, expr: &LispExpr) -> ValidationResult {
+        let Some(metrics) = FunctionMetrics::of(expr) else {
+            return Ok(());
+        };
+        let exceeded = [
+            ("cyclomatic complexity", metrics.cyclomatic_complexity, self.max_cyclomatic_complexity, "Move branches into helper functions or replace nested ifs with cond"),
+            ("form count", metrics.forms, self.max_forms, "Split the function into smaller named definitions"),
+            ("argument count", metrics.arguments, self.max_arguments, "Group related arguments into a struct"),
+        ]
+        .into_iter()
+        .find(|(_, value, limit, _)| value > limit);
+        match exceeded {
+            Some((metric, value, limit, suggestion)) => Err(ValidationError {
+                rule: ValidationRule::ComplexityLimits,
+                severity: Severity::Warning,
+                message: format!("Complexity limit exceeded: {} of '{}' is {}, above the maximum {}", metric, metrics.name, value, limit),
+                context: None,
+                suggestion: Some(suggestion.to_string()),
+                fix: None,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for ComplexityLimitsValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for ComplexityLimitsValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.check_nesting_depth(expr, 0)?;
+        self.check_function_metrics(expr)
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::ComplexityLimits]
+    }
+}
+
+/// Size and branching of one top-level function
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FunctionMetrics {
+    pub name: String,
+    /// One plus the decision points: each `if`, `when`, `unless`, and loop, each
+    /// non-`else` clause of `cond`, each clause after the first of `case` and
+    /// `match`, and each operand after the first of `and` and `or`
+    pub cyclomatic_complexity: usize,
+    /// Every expression in the definition, the definition itself included
+    pub forms: usize,
+    pub arguments: usize,
+}
+
+impl FunctionMetrics {
+    /// The metrics of a `defun` or `(define (f ...) ...)` form
+    pub fn of(expr: &LispExpr) -> Option<Self> {
+        let (name, _) = function_body(expr)?;
+        let parameters = function_parameters(expr)?;
+        let arguments = LambdaList::parse(parameters).map_or(parameters.len(), |list| list.variables().len());
+        let mut counter = MetricsCounter { decisions: 0, forms: 0 };
+        counter.visit_expr(expr);
+        Some(FunctionMetrics { name: name.to_string(), cyclomatic_complexity: counter.decisions + 1, forms: counter.forms, arguments })
+    }
+}
+
+/// The metrics of every top-level function in `program`, in source order
+pub fn complexity_metrics(program: &[LispExpr]) -> Vec<FunctionMetrics> {
+    program.iter().filter_map(FunctionMetrics::of).collect()
+}
+
+/// The parameter list of a function definition
+fn function_parameters(expr: &LispExpr) -> Option<&[LispExpr]> {
+    let LispExpr::List(elements) = expr else {
+        return None;
+    };
+    match &elements[..] {
+        [head, _, LispExpr::List(parameters), ..] if head.as_symbol() == Some("defun") => Some(parameters),
+        [head, LispExpr::List(signature), ..] if head.as_symbol() == Some("define") => signature.get(1..),
+        _ => None,
+    }
+}
+
+struct MetricsCounter {
+    decisions: usize,
+    forms: usize,
+}
+
+impl Visitor<'_> for MetricsCounter {
+    fn visit_expr(&mut self, expr: &LispExpr) {
+        self.forms += 1;
+        if let LispExpr::List(elements) = expr {
+            let operands = elements.len().saturating_sub(1);
+            self.decisions += match elements.first().and_then(LispExpr::as_symbol) {
+                Some("if" | "when" | "unless" | "while" | "dotimes" | "dolist" | "loop" | "do") => 1,
+                Some("cond") => elements[1..]
+                    .iter()
+                    .filter(|clause| !matches!(clause, LispExpr::List(clause) if clause.first().and_then(LispExpr::as_symbol) == Some("else")))
+                    .count(),
+                Some("case" | "match") => operands.saturating_sub(2),
+                Some("and" | "or") => operands.saturating_sub(1),
+                _ => 0,
+            };
+        }
+        walk_expr(self, expr);
+    }
+}
+
+/// Capability scoping validator
+///
+/// A `defun` may declare `(declare (capabilities ...))`. Everything it does,
+/// including through the functions it calls, must then be covered by that
+/// declaration. Functions without a declaration are not restricted.
+pub struct CapabilityScopeValidator;
+
+impl CapabilityScopeValidator {
+    pub fn new() -> Self {
+        CapabilityScopeValidator
+    }
+
+    fn check_function(
+        &self,
+        expr: &LispExpr,
+        defun: &[LispExpr],
+        functions: &HashMap<&str, &[LispExpr]>,
+    ) -> ValidationResult {
+        let Some(declared) = capabilities::declared_capabilities(defun) else {
+            return Ok(());
+        };
+        let name = defun[1].as_symbol().unwrap_or_default();
+        let declared = declared.map_err(|message| ValidationError {
+            rule: ValidationRule::CapabilityScope,
+            severity: Severity::Error,
+            message: format!("Invalid capability declaration in '{}': {}", name, message),
+            context: Some(expr.to_string()),
+            suggestion: None,
+            fix: None,
+        })?;
+
+        let mut visited = HashSet::from([name]);
+        let mut uses = Vec::new();
+        Self::collect_uses(defun, None, functions, &mut visited, &mut uses);
+        let missing: Vec<(CapabilityUse, Option<&str>)> = uses
+            .into_iter()
+            .filter(|(capability_use, _)| !Self::is_covered(&declared, capability_use))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let described: Vec<String> = missing
+            .iter()
+            .map(|(capability_use, via)| {
+                let capability = if capability_use.is_resolved() {
+                    capability_use.capability.to_string()
+                } else {
+                    format!("{}<dynamic>", capability_use.capability)
+                };
+                match via {
+                    Some(callee) => format!("{} ({} via '{}')", capability, capability_use.operation, callee),
+                    None => format!("{} ({})", capability, capability_use.operation),
+                }
+            })
+            .collect();
+        let mut additions: Vec<String> = missing
+            .iter()
+            .filter(|(capability_use, _)| capability_use.is_resolved())
+            .map(|(capability_use, _)| capabilities::declaration(&capability_use.capability))
+            .collect();
+        additions.dedup();
+
+        Err(ValidationError {
+            rule: ValidationRule::CapabilityScope,
+            severity: Severity::Error,
+            message: format!(
+                "Function '{}' exceeds its declared capabilities: {}",
+                name,
+                described.join(", ")
+            ),
+            context: Some(expr.to_string()),
+            suggestion: Some(if additions.is_empty() {
+                "Declare a directory, host, or variable pattern covering the dynamic targets".to_string()
+            } else {
+                format!("Add {} to its (declare (capabilities ...))", additions.join(" "))
+            }),
+            fix: None,
+        })
+    }
+
+    /// Capability uses in a function body and, transitively, in the functions it calls
+    fn collect_uses<'a>(
+        defun: &'a [LispExpr],
+        via: Option<&'a str>,
+        functions: &HashMap<&'a str, &'a [LispExpr]>,
+        visited: &mut HashSet<&'a str>,
+        uses: &mut Vec<(CapabilityUse, Option<&'a str>)>,
+    ) {
+        let body = defun.get(3..).unwrap_or_default();
+        uses.extend(CapabilityReport::infer(body).uses().iter().cloned().map(|u| (u, via)));
+
+        let mut callees = Vec::new();
+        for form in body {
+            Self::collect_calls(form, functions, &mut callees);
+        }
+        for callee in callees {
+            if visited.insert(callee) {
+                Self::collect_uses(functions[callee], via.or(Some(callee)), functions, visited, uses);
+            }
+        }
+    }
+
+    fn collect_calls<'a>(expr: &LispExpr, functions: &HashMap<&'a str, &'a [LispExpr]>, calls: &mut Vec<&'a str>) {
+        if let LispExpr::List(elements) = expr {
+            if let Some((name, _)) = elements.first().and_then(|e| e.as_symbol()).and_then(|op| functions.get_key_value(op)) {
+                calls.push(name);
+            }
+            grow_stack(|| {
+                for elem in elements.iter() {
+                    Self::collect_calls(elem, functions, calls);
+                }
+            });
+        }
+    }
+
+    /// Targets only known at runtime are covered by any declaration of the same kind;
+    /// the runtime scope check narrows them
+    fn is_covered(declared: &[Capability], capability_use: &CapabilityUse) -> bool {
+        let requested = &capability_use.capability;
+        if capability_use.target.is_some() {
+            return declared.iter().any(|capability| capability.covers(requested));
+        }
+        declared.iter().any(|capability| match (capability, requested) {
+            (Capability::NetworkHTTP | Capability::Network { .. }, Capability::NetworkHTTP) => true,
+            (Capability::FileRead(_), Capability::FileRead(_))
+            | (Capability::FileWrite(_), Capability::FileWrite(_))
+            | (Capability::EnvRead(_), Capability::EnvRead(_))
+            | (Capability::Crate(_), Capability::Crate(_)) => true,
+            _ => capability == requested,
+        })
+    }
+}
+
+impl Default for CapabilityScopeValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for CapabilityScopeValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.validate_program(std::slice::from_ref(expr)).remove(0)
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::CapabilityScope]
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let defuns: Vec<Option<&[LispExpr]>> = program
+            .iter()
+            .map(|expr| match expr {
+                LispExpr::List(elements)
+                    if elements.len() >= 3
+                        && elements[0].as_symbol() == Some("defun")
+                        && elements[1].as_symbol().is_some() =>
+                {
+                    Some(&elements[..])
+                }
+                _ => None,
+            })
+            .collect();
+        let functions: HashMap<&str, &[LispExpr]> = defuns
+            .iter()
+            .flatten()
+            .map(|defun| (defun[1].as_symbol().unwrap_or_default(), *defun))
+            .collect();
+
+        program
+            .iter()
+            .zip(&defuns)
+            .map(|(expr, defun)| match defun {
+                Some(defun) => self.check_function(expr, defun, &functions),
+                None => Ok(()),
+            })
+            .collect()
+    }
+}
+
+/// Fuel a precondition may use when evaluated against a call's literal arguments
+const CONTRACT_FUEL: u64 = 10_000;
+
+/// Contract validator
+///
+/// Checks each `defun`'s `(declare (requires ...) (ensures ...))`: conditions may
+/// only mention the parameters, global definitions, and builtins, with `result`
+/// reserved for postconditions. A condition that is a constant can never fail,
+/// or always does. Calls with only literal arguments have the callee's
+/// preconditions evaluated in a sandboxed interpreter, so a violation is reported
+/// before the program runs.
+pub struct ContractValidator;
+
+/// A function's parameters and contract, for checking its calls
+struct ContractedFunction {
+    parameters: Vec<Symbol>,
+    variadic: bool,
+    contract: Contract,
+}
+
+impl ContractValidator {
+    pub fn new() -> Self {
+        ContractValidator
+    }
+
+    fn error(severity: Severity, message: String, expr: &LispExpr) -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::Contracts,
+            severity,
+            message,
+            context: Some(expr.to_string()),
+            suggestion: None,
+            fix: None,
+        }
+    }
+
+    fn check_definition(
+        &self,
+        expr: &LispExpr,
+        name: &str,
+        function: &ContractedFunction,
+        globals: &HashSet<&str>,
+    ) -> ValidationResult {
+        let checked = [("Precondition", &function.contract.requires), ("Postcondition", &function.contract.ensures)];
+        for (kind, conditions) in checked {
+            for condition in conditions {
+                let mut free = Vec::new();
+                Self::free_variables(condition, &mut HashSet::new(), &mut free);
+                let is_parameter = |symbol: &&str| function.parameters.iter().any(|parameter| parameter == symbol);
+                if let Some(unknown) = free
+                    .iter()
+                    .find(|symbol| !is_parameter(symbol) && !globals.contains(**symbol) && **symbol != contracts::RESULT)
+                {
+                    let visible = function.parameters.iter().map(Symbol::as_str).chain((kind == "Postcondition").then_some(contracts::RESULT));
+                    let mut error = Self::error(
+                        Severity::Error,
+                        format!("{} of '{}' refers to unknown variable '{}': {}", kind, name, unknown, condition),
+                        expr,
+                    );
+                    error.suggestion = suggest::closest(unknown, visible).map(|parameter| format!("Did you mean '{}'?", parameter));
+                    return Err(error);
+                }
+                if kind == "Precondition" && free.contains(&contracts::RESULT) && !is_parameter(&contracts::RESULT) {
+                    return Err(Self::error(
+                        Severity::Error,
+                        format!("Precondition of '{}' refers to '{}', which only postconditions can see: {}", name, contracts::RESULT, condition),
+                        expr,
+                    ));
+                }
+                if matches!(condition, LispExpr::Bool(false) | LispExpr::Nil) {
+                    return Err(Self::error(Severity::Error, format!("{} of '{}' can never hold: {}", kind, name, condition), expr));
+                }
+                if !free.iter().any(|symbol| is_parameter(symbol) || *symbol == contracts::RESULT) {
+                    return Err(Self::error(
+                        Severity::Warning,
+                        format!("{} of '{}' does not depend on its arguments or result: {}", kind, name, condition),
+                        expr,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Symbols `expr` reads that are not bound inside it, in order of appearance
+    fn free_variables<'a>(expr: &'a LispExpr, bound: &mut HashSet<Symbol>, free: &mut Vec<&'a str>) {
+        grow_stack(|| match expr {
+            LispExpr::Symbol(symbol) | LispExpr::Gensym(symbol) => {
+                let name = symbol.as_str();
+                if !bound.contains(symbol) && !BUILTINS.contains(&name) && !free.contains(&name) {
+                    free.push(name);
+                }
+            }
+            LispExpr::List(elements) => {
+                let Some((head, args)) = elements.split_first() else { return };
+                match head.as_symbol() {
+                    Some("quote") => {}
+                    Some("let" | "let*" | "lambda" | "fn") => {
+                        let mut inner = bound.clone();
+                        if let Some(LispExpr::List(bindings)) = args.first() {
+                            for binding in bindings.iter() {
+                                match binding {
+                                    LispExpr::List(pair) => {
+                                        if let Some(Ok(pattern)) = pair.first().map(Pattern::parse) {
+                                            inner.extend(pattern.variables());
+                                        }
+                                        pair.iter().skip(1).for_each(|value| Self::free_variables(value, bound, free));
+                                    }
+                                    other => inner.extend(other.as_symbol().map(Symbol::from)),
+                                }
+                            }
+                        }
+                        args.iter().skip(1).for_each(|body| Self::free_variables(body, &mut inner, free));
+                    }
+                    Some("destructuring-bind") if args.len() >= 2 => {
+                        let mut inner = bound.clone();
+                        if let Ok(pattern) = Pattern::parse(&args[0]) {
+                            inner.extend(pattern.variables());
+                        }
+                        Self::free_variables(&args[1], bound, free);
+                        args[2..].iter().for_each(|body| Self::free_variables(body, &mut inner, free));
+                    }
+                    Some(form) if SPECIAL_FORMS.contains(&form) => args.iter().for_each(|arg| Self::free_variables(arg, bound, free)),
+                    _ => elements.iter().for_each(|element| Self::free_variables(element, bound, free)),
+                }
+            }
+            LispExpr::Quote(_) => {}
+            other => other.children().into_iter().for_each(|child| Self::free_variables(child, bound, free)),
+        })
+    }
+
+    /// Preconditions broken by calls in `expr` whose arguments are all literals
+    fn check_calls(&self, expr: &LispExpr, functions: &HashMap<&str, ContractedFunction>) -> ValidationResult {
+        struct Calls<'a, 'f> {
+            functions: &'f HashMap<&'f str, ContractedFunction>,
+            broken: Option<(&'a LispExpr, &'f str, &'f LispExpr)>,
+        }
+        impl<'a> Visitor<'a> for Calls<'a, '_> {
+            fn visit_expr(&mut self, expr: &'a LispExpr) {
+                if self.broken.is_some() {
+                    return;
+                }
+                if let Some([head, args @ ..]) = expr.as_list()
+                    && let Some((name, function)) = head.as_symbol().and_then(|name| self.functions.get_key_value(name))
+                    && !function.variadic
+                    && args.len() == function.parameters.len()
+                    && args.iter().all(|arg| matches!(arg, LispExpr::Number(_) | LispExpr::String(_) | LispExpr::Bool(_) | LispExpr::Nil))
+                {
+                    self.broken = function
+                        .contract
+                        .requires
+                        .iter()
+                        .find(|condition| ContractValidator::fails(condition, &function.parameters, args))
+                        .map(|condition| (expr, *name, condition));
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut calls = Calls { functions, broken: None };
+        calls.visit_expr(expr);
+        match calls.broken {
+            Some((call, name, condition)) => Err(Self::error(
+                Severity::Error,
+                format!("Call {} breaks the precondition of '{}': {}", call, name, condition),
+                expr,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `condition` is false with `parameters` bound to `args`; conditions that
+    /// cannot be evaluated without the rest of the program are given the benefit of the doubt
+    fn fails(condition: &LispExpr, parameters: &[Symbol], args: &[LispExpr]) -> bool {
+        let bindings = parameters
+            .iter()
+            .zip(args)
+            .map(|(parameter, arg)| LispExpr::List(vec![LispExpr::Symbol(parameter.clone()), arg.clone()].into()))
+            .collect();
+        let check = LispExpr::List(vec![LispExpr::Symbol("let".into()), LispExpr::List(bindings), condition.clone()].into());
+        let mut interpreter = Interpreter::new().with_sandbox(SandboxConfig::new().with_max_fuel(CONTRACT_FUEL));
+        matches!(interpreter.eval(&check), Ok(Value::Bool(false) | Value::Nil))
+    }
+}
+
+impl Default for ContractValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for ContractValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.validate_program(std::slice::from_ref(expr)).remove(0)
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::Contracts]
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut globals: HashSet<&str> = SPECIAL_FORMS.iter().copied().collect();
+        let mut functions = HashMap::new();
+        let mut invalid = HashMap::new();
+        for (index, expr) in program.iter().enumerate() {
+            let Some([head, name, rest @ ..]) = expr.as_list() else { continue };
+            let Some((name, _)) = name.as_symbol().map(types::split_generics) else { continue };
+            match head.as_symbol() {
+                Some("define" | "defmacro") => {
+                    globals.insert(name);
+                }
+                Some("defun") => {
+                    globals.insert(name);
+                    let Some((LispExpr::List(parameters), body)) = rest.split_first() else { continue };
+                    // Malformed parameter lists are the arity validator's to report
+                    let Ok(parameters) = LambdaList::parse(parameters) else { continue };
+                    let (_, body) = types::return_type(body);
+                    match Contract::parse(body) {
+                        Ok((contract, _)) if !contract.is_empty() => {
+                            let variadic = !parameters.is_fixed();
+                            let parameters = parameters.variables();
+                            functions.insert(name, ContractedFunction { parameters, variadic, contract });
+                        }
+                        Ok(_) => {}
+                        Err(message) => {
+                            invalid.insert(index, Self::error(Severity::Error, format!("Invalid contract in '{}': {}", name, message), expr));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        program
+            .iter()
+            .enumerate()
+            .map(|(index, expr)| {
+                if let Some(error) = invalid.remove(&index) {
+                    return Err(error);
+                }
+                if let Some([head, name, ..]) = expr.as_list()
+                    && head.as_symbol() == Some("defun")
+                    && let Some((name, function)) = name.as_symbol().and_then(|name| functions.get_key_value(types::split_generics(name).0))
+                {
+                    self.check_definition(expr, name, function, &globals)?;
+                }
+                self.check_calls(expr, &functions)
+            })
+            .collect()
+    }
+}
+
+/// Arity validator
+///
+/// Checks each call to one of the program's `defun`s against its parameter list:
+/// the number of positional arguments, and the keyword arguments, which must be
+/// known to the function, passed once, and followed by a value. A malformed
+/// parameter list is reported where the function is defined.
+pub struct ArityValidator;
+
+impl ArityValidator {
+    pub fn new() -> Self {
+        ArityValidator
+    }
+
+    fn error(message: String, expr: &LispExpr) -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::Arity,
+            severity: Severity::Error,
+            message,
+            context: Some(expr.to_string()),
+            suggestion: None,
+            fix: None,
+        }
+    }
+
+    fn check_calls(expr: &LispExpr, functions: &HashMap<&str, LambdaList>) -> ValidationResult {
+        let LispExpr::List(elements) = expr else {
+            return Ok(());
+        };
+        let Some((head, args)) = elements.split_first() else {
+            return Ok(());
+        };
+        // Parameter lists and quoted data are not calls
+        let calls = match head.as_symbol() {
+            Some("quote") => &[][..],
+            Some("defun") => args.get(2..).unwrap_or_default(),
+            Some("lambda" | "fn") => args.get(1..).unwrap_or_default(),
+            Some(name) if let Some(parameters) = functions.get(name) => {
+                parameters
+                    .match_arguments(name, args.iter().collect(), |arg| match arg {
+                        LispExpr::Symbol(s) if lambda_list::is_keyword(s) => Some(s.as_str()),
+                        _ => None,
+                    })
+                    .map_err(|message| Self::error(message, expr))?;
+                args
+            }
+            _ => elements,
+        };
+        grow_stack(|| calls.iter().try_for_each(|call| Self::check_calls(call, functions)))
+    }
+}
+
+impl Default for ArityValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for ArityValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.validate_program(std::slice::from_ref(expr)).remove(0)
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::Arity]
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let defuns: Vec<Option<(&str, Result<LambdaList, String>)>> = program
+            .iter()
+            .map(|expr| match expr.as_list() {
+                Some([head, name, LispExpr::List(parameters), ..]) if head.as_symbol() == Some("defun") => {
+                    name.as_symbol().map(|name| (types::split_generics(name).0, LambdaList::parse(parameters)))
+                }
+                _ => None,
+            })
+            .collect();
+        let functions: HashMap<&str, LambdaList> = defuns
+            .iter()
+            .flatten()
+            .filter_map(|(name, parameters)| Some((*name, parameters.as_ref().ok()?.clone())))
+            .collect();
+
+        program
+            .iter()
+            .zip(&defuns)
+            .map(|(expr, defun)| {
+                if let Some((name, Err(message))) = defun {
+                    return Err(Self::error(format!("Invalid parameter list for '{}': {}", name, message), expr));
+                }
+                Self::check_calls(expr, &functions)
+            })
+            .collect()
+    }
+}
+
+/// Crate validator
+///
+/// Checks the program's `require-crate` forms: each must be well-formed and at the
+/// top level, a crate must not be required with two different versions, and when an
+/// allowlist is configured the crate must match one of its names or globs.
+pub struct CrateValidator {
+    /// Crate names or globs that may be required; `None` allows any crate
+    allowed_crates: Option<Vec<String>>,
+}
+
+impl CrateValidator {
+    pub fn new() -> Self {
+        CrateValidator { allowed_crates: None }
+    }
+
+    /// Allow crates matching `pattern`, and reject crates no allowed pattern matches
+    pub fn allow_crate(mut self, pattern: String) -> Self {
+        self.allowed_crates.get_or_insert_with(Vec::new).push(pattern);
+        self
+    }
+
+    fn error(message: String, expr: &LispExpr, suggestion: Option<String>) -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::Crates,
+            severity: Severity::Error,
+            message,
+            context: Some(expr.to_string()),
+            suggestion,
+            fix: None,
+        }
+    }
+
+    fn check_requirement(&self, expr: &LispExpr, earlier: &mut Vec<CrateRequirement>) -> ValidationResult {
+        let requirement = CrateRequirement::parse(&expr.as_list().unwrap()[1..]).map_err(|message| Self::error(message, expr, None))?;
+        if let Some(allowed) = &self.allowed_crates
+            && !allowed.iter().any(|pattern| Capability::Crate(pattern.clone()).covers(&requirement.capability()))
+        {
+            return Err(Self::error(
+                format!("Crate '{}' is not in the allowed crates", requirement.name),
+                expr,
+                Some(format!("Remove the requirement or allow '{}'", requirement.name)),
+            ));
+        }
+        if let Some(other) = earlier.iter().find(|other| other.name == requirement.name && other.version != requirement.version) {
+            return Err(Self::error(
+                format!("Crate '{}' is required with both version {:?} and {:?}", requirement.name, other.version, requirement.version),
+                expr,
+                Some(format!("Require '{}' with a single version", requirement.name)),
+            ));
+        }
+        earlier.push(requirement);
+        Ok(())
+    }
+
+    fn check_nested(expr: &LispExpr) -> ValidationResult {
+        let LispExpr::List(elements) = expr else {
+            return Ok(());
+        };
+        if elements.first().and_then(|e| e.as_symbol()) == Some("quote") {
+            return Ok(());
+        }
+        grow_stack(|| {
+            elements.iter().try_for_each(|element| {
+                if crates::is_require_crate(element) {
+                    return Err(Self::error(
+                        "'require-crate' can only appear at the top level of a program".to_string(),
+                        element,
+                        Some("Move the requirement to the top level".to_string()),
+                    ));
+                }
+                Self::check_nested(element)
+            })
+        })
+    }
+}
+
+impl Default for CrateValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for CrateValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        self.validate_program(std::slice::from_ref(expr)).remove(0)
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::Crates]
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut earlier = Vec::new();
+        program
+            .iter()
+            .map(|expr| {
+                if crates::is_require_crate(expr) {
+                    self.check_requirement(expr, &mut earlier)
+                } else {
+                    Self::check_nested(expr)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Forms with side effects that need no capability, which a `pure` function may not use
+const IMPURE_FORMS: &[&str] = &["set!", "print", "random", "random-int", "spawn", "thread", "async"];
+
+/// Declaration validator
+///
+/// Checks each definition's `(declare ...)` forms against the declaration schema,
+/// and that a function declared `pure` neither assigns, prints, draws random
+/// numbers, spawns work, nor uses a capability in its own body.
+pub struct DeclarationValidator;
+
+impl DeclarationValidator {
+    pub fn new() -> Self {
+        DeclarationValidator
+    }
+
+    fn error(message: String, expr: &LispExpr, suggestion: Option<String>) -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::Declarations,
+            severity: Severity::Error,
+            message,
+            context: Some(expr.to_string()),
+            suggestion,
+            fix: None,
+        }
+    }
+
+    fn check_pure(name: &str, body: &[LispExpr], expr: &LispExpr) -> ValidationResult {
+        let mut effects = ImpureForms::default();
+        for form in body {
+            effects.visit_expr(form);
+        }
+        let mut found: Vec<String> = effects.found.iter().map(|form| form.to_string()).collect();
+        for capability_use in CapabilityReport::infer(body).uses() {
+            if !found.contains(&capability_use.operation) {
+                found.push(capability_use.operation.clone());
+            }
+        }
+        match found.is_empty() {
+            true => Ok(()),
+            false => Err(Self::error(
+                format!("Function '{}' is declared pure but uses {}", name, found.join(", ")),
+                expr,
+                Some("Remove the side effects or the (pure) declaration".to_string()),
+            )),
+        }
+    }
+}
+
+/// Collects the names of `IMPURE_FORMS` called in an expression, skipping quoted data
+#[derive(Default)]
+struct ImpureForms<'a> {
+    found: Vec<&'a str>,
+}
+
+impl<'a> Visitor<'a> for ImpureForms<'a> {
+    fn visit_expr(&mut self, expr: &'a LispExpr) {
+        let LispExpr::List(elements) = expr else {
+            if !matches!(expr, LispExpr::Quote(_)) {
+                walk_expr(self, expr);
+            }
+            return;
+        };
+        match elements.first().and_then(|e| e.as_symbol()) {
+            Some("quote") => return,
+            Some(head) if IMPURE_FORMS.contains(&head) && !self.found.contains(&head) => self.found.push(head),
+            _ => {}
+        }
+        walk_expr(self, expr);
+    }
+}
+
+impl Default for DeclarationValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for DeclarationValidator {
+    fn validate(&self, expr: &LispExpr) -> ValidationResult {
+        let Some(declared) = declarations::declared(expr) else {
+            return Ok(());
+        };
+        let declarations = declared
+            .declarations()
+            .map_err(|message| Self::error(format!("Invalid declaration on '{}': {}", declared.name, message), expr, None))?;
+        match function_body(expr) {
+            Some((name, body)) if declarations.pure => Self::check_pure(name, types::return_type(body).1, expr),
+            _ => Ok(()),
+        }
+    }
+
+    fn enabled_rules(&self) -> Vec<ValidationRule> {
+        vec![ValidationRule::Declarations]
+    }
+}
+
+/// Bytes counted for each element slot of a list, vector, or map literal
+const SLOT_BYTES: usize = 8;
+
+/// Memory estimate validator
+///
+/// Estimates the worst-case allocation of literal data (list, vector, and map
+/// literals, strings, constant-size constructors, and loops with constant
+/// bounds) and rejects the program before it runs when the running total would
+/// exceed the sandbox memory limit. Allocations are never freed by the sandbox
+/// runtime, so the total accumulates across top-level forms.
+pub struct MemoryEstimateValidator {
+    max_memory: usize,
+}
+
+impl MemoryEstimateValidator {
+    pub fn new() -> Self {
+        MemoryEstimateValidator {
+            max_memory: 100 * 1024 * 1024,
+        }
+    }
+
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// Worst-case bytes allocated by evaluating `expr`
+    pub fn estimate(expr: &LispExpr) -> usize {
+        grow_stack(|| match expr {
+            LispExpr::String(s) => s.len(),
+            LispExpr::Quote(inner) => Self::estimate_data(inner),
+            LispExpr::Quasiquote(inner) | LispExpr::Unquote(inner) | LispExpr::Splice(inner) => Self::estimate(inner),
+            LispExpr::MacroCall { args, .. } => args.iter().map(Self::estimate).fold(0, usize::saturating_add),
+            LispExpr::List(elements) if !elements.is_empty() => Self::estimate_form(elements),
+            _ => 0,
+        })
+    }
+
+    fn estimate_form(elements: &[LispExpr]) -> usize {
+        let args = &elements[1..];
+        let sum = |exprs: &[LispExpr]| exprs.iter().map(Self::estimate).fold(0, usize::saturating_add);
+        let count = |expr: &LispExpr| match expr {
+            LispExpr::Number(n) if *n >= 0.0 => Some(*n as usize),
+            _ => None,
+        };
+
+        match elements[0].as_symbol() {
+            // Definitions only allocate when their body runs
+            Some("defun" | "defmacro" | "lambda" | "declare") => 0,
+            Some("list" | "vector" | "vec" | "hash-map" | "map") => {
+                args.len().saturating_mul(SLOT_BYTES).saturating_add(sum(args))
+            }
+            Some("make-list" | "make-vector") => match args.first().and_then(count) {
+                Some(length) => length.saturating_mul(SLOT_BYTES.saturating_add(args.get(1).map_or(0, Self::estimate))),
+                None => sum(args),
+            },
+            Some("make-string") => args.first().and_then(count).unwrap_or_else(|| sum(args)),
+            // (dotimes (i N) body...) and (repeat N body...) run their body N times
+            Some("dotimes") => match args.first() {
+                Some(LispExpr::List(spec)) if spec.len() == 2 && let Some(times) = count(&spec[1]) => {
+                    times.saturating_mul(sum(&args[1..]))
+                }
+                _ => sum(args),
+            },
+            Some("repeat") => match args.first().and_then(count) {
+                Some(times) => times.saturating_mul(sum(&args[1..])),
+                None => sum(args),
+            },
+            // Only one branch runs; assume the larger one
+            Some("if") if args.len() >= 2 => {
+                let branches = args[1..].iter().map(Self::estimate).max().unwrap_or_default();
+                Self::estimate(&args[0]).saturating_add(branches)
             }
-            _ => Ok(()),
+            _ => sum(args),
+        }
+    }
+
+    /// Quoted data is built as a whole: one slot per element plus its contents
+    fn estimate_data(expr: &LispExpr) -> usize {
+        grow_stack(|| match expr {
+            LispExpr::String(s) => s.len(),
+            LispExpr::List(elements) => elements
+                .iter()
+                .map(|e| SLOT_BYTES.saturating_add(Self::estimate_data(e)))
+                .fold(0, usize::saturating_add),
+            _ => 0,
+        })
+    }
+
+    fn exceeded(&self, expr: &LispExpr, total: usize) -> ValidationError {
+        ValidationError {
+            rule: ValidationRule::MemoryBounds,
+            severity: Severity::Error,
+            message: format!(
+                "Estimated allocation of {} bytes exceeds the sandbox memory limit of {} bytes",
+                total, self.max_memory
+            ),
+            context: Some(expr.to_string()),
+            suggestion: Some("Raise --max-memory or build large data incrementally instead of as literals".to_string()),
+            fix: None,
         }
     }
 }
 
-impl ASTValidator for ComplexityLimitsValidator {
+impl Default for MemoryEstimateValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ASTValidator for MemoryEstimateValidator {
     fn validate(&self, expr: &LispExpr) -> ValidationResult {
-        self.check_nesting_depth(expr, 0)
+        let total = Self::estimate(expr);
+        if total > self.max_memory {
+            return Err(self.exceeded(expr, total));
+        }
+        Ok(())
     }
 
     fn enabled_rules(&self) -> Vec<ValidationRule> {
-        vec![ValidationRule::ComplexityLimits]
+        vec![ValidationRule::MemoryBounds]
+    }
+
+    fn validate_program(&self, program: &[LispExpr]) -> Vec<ValidationResult> {
+        let mut total: usize = 0;
+        program
+            .iter()
+            .map(|expr| {
+                let before = total;
+                total = total.saturating_add(Self::estimate(expr));
+                // Report only the form that crosses the limit
+                if total > self.max_memory && before <= self.max_memory {
+                    Err(self.exceeded(expr, total))
+                } else {
+                    Ok(())
+                }
+            })
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builder::sym;
 
     #[test]
     fn test_type_safety_arithmetic_with_numbers() {
         let validator = TypeSafetyValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
         assert!(validator.validate(&expr).is_ok());
     }
 
@@ -478,10 +2748,10 @@ mod tests {
     fn test_type_safety_arithmetic_with_string_fails() {
         let validator = TypeSafetyValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::String("hello".to_string()),
             LispExpr::Number(42.0),
-        ]);
+        ].into());
         let result = validator.validate(&expr);
         assert!(result.is_err());
         if let Err(e) = result {
@@ -494,14 +2764,14 @@ mod tests {
     fn test_type_safety_nested_arithmetic() {
         let validator = TypeSafetyValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
+                LispExpr::Symbol("*".into()),
                 LispExpr::Number(2.0),
                 LispExpr::Number(3.0),
-            ]),
-        ]);
+            ].into()),
+        ].into());
         assert!(validator.validate(&expr).is_ok());
     }
 
@@ -509,10 +2779,10 @@ mod tests {
     fn test_resource_bounds_immediate_recursion() {
         let validator = ResourceBoundsValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("define".to_string()),
-            LispExpr::List(vec![LispExpr::Symbol("infinite-loop".to_string())]),
-            LispExpr::List(vec![LispExpr::Symbol("infinite-loop".to_string())]),
-        ]);
+            LispExpr::Symbol("define".into()),
+            LispExpr::List(vec![LispExpr::Symbol("infinite-loop".into())].into()),
+            LispExpr::List(vec![LispExpr::Symbol("infinite-loop".into())].into()),
+        ].into());
         let result = validator.validate(&expr);
         assert!(result.is_err());
         if let Err(e) = result {
@@ -527,40 +2797,82 @@ mod tests {
         // (define (countdown n) (if (= n 0) 0 (countdown (- n 1))))
         // This has a conditional, so it should pass
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("define".to_string()),
+            LispExpr::Symbol("define".into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("countdown".to_string()),
-                LispExpr::Symbol("n".to_string()),
-            ]),
+                LispExpr::Symbol("countdown".into()),
+                LispExpr::Symbol("n".into()),
+            ].into()),
             LispExpr::List(vec![
-                LispExpr::Symbol("if".to_string()),
+                LispExpr::Symbol("if".into()),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("=".to_string()),
-                    LispExpr::Symbol("n".to_string()),
+                    LispExpr::Symbol("=".into()),
+                    LispExpr::Symbol("n".into()),
                     LispExpr::Number(0.0),
-                ]),
+                ].into()),
                 LispExpr::Number(0.0),
                 LispExpr::List(vec![
-                    LispExpr::Symbol("countdown".to_string()),
+                    LispExpr::Symbol("countdown".into()),
                     LispExpr::List(vec![
-                        LispExpr::Symbol("-".to_string()),
-                        LispExpr::Symbol("n".to_string()),
+                        LispExpr::Symbol("-".into()),
+                        LispExpr::Symbol("n".into()),
                         LispExpr::Number(1.0),
-                    ]),
-                ]),
-            ]),
-        ]);
+                    ].into()),
+                ].into()),
+            ].into()),
+        ].into());
         // This should pass because the body is an if expression, not a direct call
         assert!(validator.validate(&expr).is_ok());
     }
 
+    #[test]
+    fn test_resource_bounds_wrapped_and_mutual_recursion() {
+        let validator = ResourceBoundsValidator::new();
+        let messages = |source: &str| -> Vec<Option<String>> {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|r| r.err().map(|e| e.message)).collect()
+        };
+        assert!(messages("(define (f x) (print x) (begin (let ((y (f x))) y)))")[0].as_ref().unwrap().contains("function 'f' calls itself"));
+        assert!(messages("(defun spin (n) (let loop ((i n)) (spin i)))")[0].is_some());
+
+        let mutual = messages("(define (f) (g)) (define (g) (begin (f))) (define (h) (f))");
+        let expected = "Infinite recursion detected: functions 'f', 'g' call each other without any conditional base case";
+        assert_eq!(mutual, vec![Some(expected.to_string()), Some(expected.to_string()), None]);
+
+        // Calls behind a conditional or in a lambda have a way out
+        let guarded = "(define (f n) (if (= n 0) 0 (g n))) (define (g n) (f (- n 1)))
+                       (define (c) (lambda () (d))) (define (d) (c))";
+        assert!(messages(guarded).iter().all(Option::is_none));
+
+        let program = crate::parser::parse(crate::lexer::tokenize("(define (f) (g)) (define (g) (f))").unwrap()).unwrap();
+        let error = validator.validate_program(&program).remove(1).unwrap_err();
+        assert_eq!(error.fix.unwrap().replacement.to_string(), "(if <base-case> <base-value> (f))");
+    }
+
+    #[test]
+    fn test_resource_bounds_recursion_in_arguments() {
+        let validator = ResourceBoundsValidator::new();
+        let messages = |source: &str| -> Vec<Option<String>> {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|r| r.err().map(|e| e.message)).collect()
+        };
+        let expected = "Infinite recursion detected: function 'f' calls itself without any conditional base case";
+        assert_eq!(messages("(defun f (n) (* n (f (- n 1))))"), vec![Some(expected.to_string())]);
+        assert!(messages("(define (a) (print (b))) (define (b) (a))").iter().all(Option::is_some));
+
+        // Arguments of special forms are not evaluated unconditionally
+        let guarded = "(defun f (n) (* n (if (= n 0) 1 (f (- n 1)))))
+                       (defun g (n) (and (> n 0) (g (- n 1))))
+                       (defun h (n) (list (quote (h n)) (lambda () (h n))))";
+        assert!(messages(guarded).iter().all(Option::is_none));
+    }
+
     #[test]
     fn test_ffi_restrictions_unsafe_operation() {
         let validator = FFIRestrictionsValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("rust-unsafe".to_string()),
+            LispExpr::Symbol("rust-unsafe".into()),
             LispExpr::String("std::ptr::null()".to_string()),
-        ]);
+        ].into());
         let result = validator.validate(&expr);
         assert!(result.is_err());
         if let Err(e) = result {
@@ -574,9 +2886,9 @@ mod tests {
         let validator = FFIRestrictionsValidator::new()
             .allow_function("rust-unsafe".to_string());
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("rust-unsafe".to_string()),
+            LispExpr::Symbol("rust-unsafe".into()),
             LispExpr::String("std::ptr::null()".to_string()),
-        ]);
+        ].into());
         assert!(validator.validate(&expr).is_ok());
     }
 
@@ -584,10 +2896,10 @@ mod tests {
     fn test_complexity_limits_shallow_nesting() {
         let validator = ComplexityLimitsValidator::new();
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
         assert!(validator.validate(&expr).is_ok());
     }
 
@@ -599,10 +2911,10 @@ mod tests {
         let mut expr = LispExpr::Number(1.0);
         for _ in 0..10 {
             expr = LispExpr::List(vec![
-                LispExpr::Symbol("+".to_string()),
+                LispExpr::Symbol("+".into()),
                 expr,
                 LispExpr::Number(1.0),
-            ]);
+            ].into());
         }
 
         let result = validator.validate(&expr);
@@ -613,6 +2925,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_complexity_metrics_and_thresholds() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (defun classify (n &optional (scale 1))
+              (cond ((< n 0) "negative") ((and (> n 10) (< n 20) (odd? n)) "teen") (else (if (= scale 1) "small" "scaled"))))
+            (define (pick x) (case x (1 "one") (2 "two") (else "many")))
+            (define limit 10)
+        "#).unwrap()).unwrap();
+        let metrics = complexity_metrics(&program);
+        let summary: Vec<(&str, usize, usize)> = metrics.iter().map(|m| (m.name.as_str(), m.cyclomatic_complexity, m.arguments)).collect();
+        assert_eq!(summary, vec![("classify", 6, 2), ("pick", 3, 1)]);
+        assert_eq!(metrics[1].forms, 17);
+
+        assert!(ComplexityLimitsValidator::new().validate_program(&program).iter().all(Result::is_ok));
+        let strict = ComplexityLimitsValidator::new().with_max_cyclomatic_complexity(5).with_max_arguments(1).with_max_forms(16);
+        let errors: Vec<(Severity, String)> = strict.validate_program(&program).into_iter().filter_map(Result::err).map(|e| (e.severity, e.message)).collect();
+        assert_eq!(errors, vec![
+            (Severity::Warning, "Complexity limit exceeded: cyclomatic complexity of 'classify' is 6, above the maximum 5".to_string()),
+            (Severity::Warning, "Complexity limit exceeded: form count of 'pick' is 17, above the maximum 16".to_string()),
+        ]);
+    }
+
     #[test]
     fn test_composite_validator_all_pass() {
         let composite = CompositeValidator::new()
@@ -622,12 +2956,16 @@ mod tests {
             .add_validator(Box::new(ComplexityLimitsValidator::new()));
 
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ]);
+        ].into());
 
         assert!(composite.validate_all(&expr).is_ok());
+        assert_eq!(
+            composite.enabled_rules(),
+            vec![ValidationRule::TypeSafety, ValidationRule::ResourceBounds, ValidationRule::FFIRestrictions, ValidationRule::ComplexityLimits]
+        );
     }
 
     #[test]
@@ -638,13 +2976,13 @@ mod tests {
 
         // This expression has both type safety and FFI issues
         let expr = LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::String("hello".to_string()),
             LispExpr::List(vec![
-                LispExpr::Symbol("rust-unsafe".to_string()),
+                LispExpr::Symbol("rust-unsafe".into()),
                 LispExpr::String("dangerous".to_string()),
-            ]),
-        ]);
+            ].into()),
+        ].into());
 
         let result = composite.validate_all(&expr);
         assert!(result.is_err());
@@ -666,16 +3004,718 @@ mod tests {
         assert_eq!(validator.infer_type(&LispExpr::Bool(true)), InferredType::Bool);
     }
 
+    #[test]
+    fn test_type_safety_tracks_defined_variables() {
+        // (define x "s") (+ x 1)
+        let program = vec![
+            LispExpr::List(vec![sym("define"), sym("x"), LispExpr::String("s".to_string())].into()),
+            LispExpr::List(vec![sym("+"), sym("x"), LispExpr::Number(1.0)].into()),
+        ];
+
+        let results = TypeSafetyValidator::new().validate_program(&program);
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert!(error.message.ends_with("got string"));
+        assert_eq!(error.context.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn test_type_safety_tracks_function_return_types() {
+        // (define (greet name) "hello") (+ (greet "bob") 1)
+        let program = vec![
+            LispExpr::List(vec![
+                sym("define"),
+                LispExpr::List(vec![sym("greet"), sym("name")].into()),
+                LispExpr::String("hello".to_string()),
+            ].into()),
+            LispExpr::List(vec![
+                sym("+"),
+                LispExpr::List(vec![sym("greet"), LispExpr::String("bob".to_string())].into()),
+                LispExpr::Number(1.0),
+            ].into()),
+        ];
+
+        let results = TypeSafetyValidator::new().validate_program(&program);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn test_type_safety_let_forms() {
+        let validator = TypeSafetyValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().all(|result| result.is_ok())
+        };
+        // Later bindings see the types of earlier ones
+        assert!(!check("(let* ((s \"a\") (t s)) (+ t 1))"));
+        assert!(check("(define s \"a\") (let* ((s 1) (t s)) (+ t 1))"));
+        assert!(!check("(let loop ((s \"a\")) (+ s 1))"));
+        assert!(check("(define i \"a\") (let loop ((i 0)) (if (> i 3) i (loop (+ i 1))))"));
+        assert!(check("(define f \"a\") (letrec ((f (lambda (n) n))) (+ (f 1) 1))"));
+    }
+
+    #[test]
+    fn test_incomplete_binding_forms() {
+        for source in ["(destructuring-bind (a b))", "(destructuring-bind)", "(let loop)", "(defun f)"] {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            // Malformed forms may fail validation, but never panic it
+            let _ = CompositeValidator::with_all_validators().validate_program(&program);
+        }
+    }
+
+    #[test]
+    fn test_type_safety_conversions() {
+        let validator = TypeSafetyValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().all(|result| result.is_ok())
+        };
+        // Converted values type-check as their new type downstream
+        assert!(check("(define s \"4\") (+ (string->number s) 1)"));
+        assert!(!check("(+ (number->string 1) 1)"));
+        assert!(!check("(< (symbol->string 'a) 1)"));
+        assert!(check("(define flag (to-bool 0)) (= flag true)"));
+        // Arguments must have the type being converted from
+        assert!(!check("(string->number 4)"));
+        assert!(!check("(number->string \"4\")"));
+        assert!(check("(symbol->string 'a)"));
+        assert!(!check("(json-parse 1)"));
+        assert!(!check("(+ (json-stringify (list 1)) 1)"));
+        // I/O built-ins produce strings
+        assert!(!check("(+ (read-file \"n.txt\") 1)"));
+        assert!(check("(string->number (getenv \"PORT\"))"));
+    }
+
+    #[test]
+    fn test_type_safety_call_arguments() {
+        let validator = TypeSafetyValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().find_map(Result::err).map(|error| error.message)
+        };
+        assert_eq!(
+            check("(defun add-one (x) (+ x 1)) (add-one \"s\")").as_deref(),
+            Some("Type mismatch: argument 1 of 'add-one' must be a number, got string")
+        );
+        assert!(check("(defun add-one (x) (+ x 1)) (add-one 2)").is_none());
+        // Only annotated parameters and arithmetic operands have a known type
+        assert!(check("(defun greet (name) (string-append \"hi \" name)) (greet \"bob\")").is_none());
+        assert!(check("(defun greet ((name : string)) name) (greet 1)").is_some());
+        assert!(check("(defun add-one (x) (+ x 1)) (let ((add-one (lambda (s) s))) (add-one \"s\"))").is_none());
+    }
+
+    #[test]
+    fn test_type_safety_parameters_shadow_definitions() {
+        // (define x "s") (define (inc x) (+ x 1)) (let ((x 2)) (+ x 1))
+        let program = vec![
+            LispExpr::List(vec![sym("define"), sym("x"), LispExpr::String("s".to_string())].into()),
+            LispExpr::List(vec![
+                sym("define"),
+                LispExpr::List(vec![sym("inc"), sym("x")].into()),
+                LispExpr::List(vec![sym("+"), sym("x"), LispExpr::Number(1.0)].into()),
+            ].into()),
+            LispExpr::List(vec![
+                sym("let"),
+                LispExpr::List(vec![LispExpr::List(vec![sym("x"), LispExpr::Number(2.0)].into())].into()),
+                LispExpr::List(vec![sym("+"), sym("x"), LispExpr::Number(1.0)].into()),
+            ].into()),
+        ];
+
+        let results = TypeSafetyValidator::new().validate_program(&program);
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_type_safety_reassigned_variables() {
+        let validator = TypeSafetyValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().find_map(Result::err).map(|error| error.message)
+        };
+        assert!(check("(define x \"s\") (set! x 1) (+ x 1)").is_none());
+        assert!(check("(define x \"s\") (defun reset () (set! x 1)) (+ x 1)").is_none());
+        assert!(check("(let ((x \"s\")) (set! x 1) (+ x 1))").is_none());
+        // Assigning a value of the same type keeps it
+        assert!(check("(define x \"s\") (set! x \"t\") (+ x 1)").is_some());
+        assert!(check("(let ((x \"s\")) (set! x \"t\") (+ x 1))").is_some());
+    }
+
+    #[test]
+    fn test_type_safety_seeded_environment() {
+        let validator = TypeSafetyValidator::new()
+            .with_binding("name".to_string(), InferredType::String);
+        let expr = LispExpr::List(vec![sym("*"), sym("name"), LispExpr::Number(2.0)].into());
+        assert!(validator.validate(&expr).is_err());
+    }
+
+    #[test]
+    fn test_composite_validate_program_per_expression() {
+        let program = vec![
+            LispExpr::List(vec![sym("define"), sym("x"), LispExpr::String("s".to_string())].into()),
+            LispExpr::List(vec![sym("+"), sym("x"), LispExpr::Number(1.0)].into()),
+            LispExpr::List(vec![sym("+"), LispExpr::Number(1.0), LispExpr::Number(2.0)].into()),
+        ];
+
+        let composite = CompositeValidator::new()
+            .add_validator(Box::new(TypeSafetyValidator::new()))
+            .add_validator(Box::new(FFIRestrictionsValidator::new()));
+        let results = composite.validate_program(&program);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().len(), 1);
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn test_taint_tracking_direct_source_to_sink() {
+        let validator = TaintTrackingValidator::new();
+        // (rust-unsafe (read-file "payload.rs"))
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("rust-unsafe".into()),
+            LispExpr::List(vec![
+                LispExpr::Symbol("read-file".into()),
+                LispExpr::String("payload.rs".to_string()),
+            ].into()),
+        ].into());
+        let result = validator.validate(&expr);
+        assert!(result.is_err());
+        if let Err(e) = result {
+            assert_eq!(e.rule, ValidationRule::TaintTracking);
+            assert!(e.message.contains("'read-file'"));
+            assert!(e.message.contains("'rust-unsafe'"));
+        }
+    }
+
+    #[test]
+    fn test_taint_tracking_through_let_binding() {
+        let validator = TaintTrackingValidator::new();
+        // (let ((cmd (read-line))) (run-command cmd))
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("let".into()),
+            LispExpr::List(vec![LispExpr::List(vec![
+                LispExpr::Symbol("cmd".into()),
+                LispExpr::List(vec![LispExpr::Symbol("read-line".into())].into()),
+            ].into())].into()),
+            LispExpr::List(vec![
+                LispExpr::Symbol("run-command".into()),
+                LispExpr::Symbol("cmd".into()),
+            ].into()),
+        ].into());
+        let result = validator.validate(&expr);
+        assert!(result.is_err());
+        if let Err(e) = result {
+            assert!(e.message.contains("variable 'cmd'"));
+            assert!(e.message.contains("'run-command'"));
+        }
+    }
+
+    #[test]
+    fn test_taint_tracking_through_sequential_let() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate(&program[0]).map_err(|e| e.message)
+        };
+        let error = check("(let* ((a (read-file \"x\")) (b a)) (ffi-call b))").unwrap_err();
+        assert!(error.contains("variable 'b' (from variable 'a' (from 'read-file'))"), "{}", error);
+        let error = check("(let* ((a (read-file \"x\")) (b (ffi-call a))) b)").unwrap_err();
+        assert!(error.contains("variable 'a'"), "{}", error);
+        // `let` values see only the enclosing bindings
+        assert!(check("(let ((a (read-file \"x\")) (b a)) (ffi-call b))").is_ok());
+    }
+
+    #[test]
+    fn test_taint_tracking_run_command_argv() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate(&program[0]).map_err(|e| e.message)
+        };
+        let error = check("(let ((f (read-line))) (run-command \"rm\" f))").unwrap_err();
+        assert!(error.starts_with("Argv injection: untrusted data from variable 'f'"), "{}", error);
+        assert!(check("(let ((f (read-line))) (run-command \"rm\" \"--\" f))").is_ok());
+        let error = check("(run-command \"/bin/sh\" \"-c\" (read-line))").unwrap_err();
+        assert!(error.contains("reaches a shell"), "{}", error);
+        let error = check("(run-command (getenv \"EDITOR\") \"--\" \"notes.txt\")").unwrap_err();
+        assert!(error.contains("chooses the program"), "{}", error);
+    }
+
+    #[test]
+    fn test_taint_tracking_run_command_argv_through_helpers() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|result| result.map_err(|e| e.message)).collect::<Vec<_>>()
+        };
+        let results = check("(defun list-dir (path) (run-command \"ls\" path))\n(list-dir (read-line))");
+        let error = results[1].clone().unwrap_err();
+        assert!(error.starts_with("Argv injection: untrusted data from parameter 'path' of 'list-dir' (from 'read-line')"), "{}", error);
+        let results = check("(defun script (s) (run-command \"sh\" \"-c\" s))\n(defun run (s) (script s))\n(run (read-file \"x\"))");
+        let error = results[2].clone().unwrap_err();
+        assert!(error.contains("reaches a shell"), "{}", error);
+        let results = check("(defun list-dir (path) (run-command \"ls\" \"--\" path))\n(list-dir (read-line))");
+        assert!(results.iter().all(Result::is_ok), "{:?}", results);
+    }
+
+    #[test]
+    fn test_taint_tracking_through_patterns() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate(&program[0]).map_err(|e| e.message)
+        };
+        let error = check("(destructuring-bind (prog &rest args) (read-line) (run-command prog))").unwrap_err();
+        assert!(error.contains("variable 'prog'"), "{}", error);
+        let error = check("(let (((a b) (read-line))) (run-command \"ls\" b))").unwrap_err();
+        assert!(error.contains("variable 'b'"), "{}", error);
+        assert!(check("(destructuring-bind (a b) (list \"x\" \"y\") (run-command \"ls\" a))").is_ok());
+    }
+
+    #[test]
+    fn test_taint_tracking_through_top_level_define() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|result| result.map_err(|e| e.message)).collect::<Vec<_>>()
+        };
+        let results = check("(define x (read-file \"p\"))\n(run-command \"ls\" x)");
+        assert!(results[0].is_ok());
+        let error = results[1].clone().unwrap_err();
+        assert!(error.contains("global 'x' (from"), "{}", error);
+
+        // A trusted value given later clears the taint
+        let results = check("(define x (read-file \"p\"))\n(set! x \"notes.txt\")\n(run-command \"ls\" x)");
+        assert!(results.iter().all(Result::is_ok), "{:?}", results);
+        // A local binding of the same name shadows the global
+        let results = check("(define x (read-line))\n(let ((x \"a\")) (run-command \"ls\" x))");
+        assert!(results.iter().all(Result::is_ok), "{:?}", results);
+    }
+
+    #[test]
+    fn test_taint_tracking_through_function_returns() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|result| result.map_err(|e| e.message)).collect::<Vec<_>>()
+        };
+        let results = check("(defun get () (read-file \"x\"))\n(run-command \"sh\" \"-c\" (get))");
+        let error = results[1].clone().unwrap_err();
+        assert!(error.contains("function 'get' (returning 'read-file')"), "{}", error);
+
+        // Through other functions, defined in any order, and through globals
+        let results = check("(defun outer () (inner))\n(defun inner () (getenv \"CMD\"))\n(run-command (outer))");
+        assert!(results[2].is_err());
+        let results = check("(define cmd (read-line))\n(defun get () cmd)\n(run-command (get))");
+        assert!(results[2].is_err());
+        let results = check("(defun get (path) path)\n(defun fixed () \"ls\")\n(run-command (get \"ls\"))\n(run-command (fixed))");
+        assert!(results.iter().all(Result::is_ok), "{:?}", results);
+    }
+
+    #[test]
+    fn test_taint_tracking_through_function_parameters() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate_program(&program).into_iter().map(|result| result.map_err(|e| e.message)).collect::<Vec<_>>()
+        };
+        let results = check("(defun sink (x) (run-command x))\n(sink (read-file \"x\"))\n(sink \"ls\")");
+        assert!(results[0].is_ok());
+        let error = results[1].clone().unwrap_err();
+        assert!(error.contains("parameter 'x' of 'sink' (from 'read-file') chooses the program"), "{}", error);
+        assert!(results[2].is_ok());
+
+        let results = check("(defun call (x) (ffi-call x))\n(call (read-file \"x\"))");
+        let error = results[1].clone().unwrap_err();
+        assert!(error.contains("parameter 'x' of 'call' (from 'read-file') reaches 'ffi-call'"), "{}", error);
+
+        // Through the parameters of the functions they call, defined in any order
+        let results = check("(defun outer (a y) (inner y))\n(defun inner (x) (ffi-call x))\n(outer (read-line) \"ok\")\n(outer \"ok\" (getenv \"X\"))");
+        assert!(results[2].is_ok(), "{:?}", results);
+        let error = results[3].clone().unwrap_err();
+        assert!(error.contains("parameter 'x' of 'inner' (from parameter 'y' of 'outer' (from 'getenv'))"), "{}", error);
+    }
+
+    #[test]
+    fn test_taint_tracking_through_lambda_application() {
+        let validator = TaintTrackingValidator::new();
+        let check = |source: &str| {
+            let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+            validator.validate(&program[0]).map_err(|e| e.message)
+        };
+        let error = check("((lambda (d) (run-command \"sh\" \"-c\" d)) (read-file \"x\"))").unwrap_err();
+        assert!(error.contains("parameter 'd' (from 'read-file')"), "{}", error);
+        assert!(check("(run-command ((lambda (d) d) (read-line)))").is_err());
+        assert!(check("((lambda (a &optional (b (getenv \"X\"))) (run-command b)) \"ls\")").is_err());
+        assert!(check("((lambda (d s) (run-command s \"--\" d)) (read-file \"x\") \"ls\")").is_ok());
+    }
+
+    #[test]
+    fn test_taint_tracking_propagates_through_calls() {
+        let validator = TaintTrackingValidator::new();
+        // (ffi-call (string-append "prefix" (getenv "PATH")))
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("ffi-call".into()),
+            LispExpr::List(vec![
+                LispExpr::Symbol("string-append".into()),
+                LispExpr::String("prefix".to_string()),
+                LispExpr::List(vec![
+                    LispExpr::Symbol("getenv".into()),
+                    LispExpr::String("PATH".to_string()),
+                ].into()),
+            ].into()),
+        ].into());
+        assert!(validator.validate(&expr).is_err());
+    }
+
+    #[test]
+    fn test_taint_tracking_untainted_flow_passes() {
+        let validator = TaintTrackingValidator::new();
+        // (let ((data (read-file "a.txt"))) (rust-unsafe "std::ptr::null()"))
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("let".into()),
+            LispExpr::List(vec![LispExpr::List(vec![
+                LispExpr::Symbol("data".into()),
+                LispExpr::List(vec![
+                    LispExpr::Symbol("read-file".into()),
+                    LispExpr::String("a.txt".to_string()),
+                ].into()),
+            ].into())].into()),
+            LispExpr::List(vec![
+                LispExpr::Symbol("rust-unsafe".into()),
+                LispExpr::String("std::ptr::null()".to_string()),
+            ].into()),
+        ].into());
+        assert!(validator.validate(&expr).is_ok());
+    }
+
+    #[test]
+    fn test_taint_tracking_tainted_literals() {
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("rust-unsafe".into()),
+            LispExpr::String("std::ptr::null()".to_string()),
+        ].into());
+        assert!(TaintTrackingValidator::new().validate(&expr).is_ok());
+
+        let strict = TaintTrackingValidator::new().with_tainted_literals(true);
+        let result = strict.validate(&expr);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().message.contains("string literal"));
+    }
+
+    #[test]
+    fn test_taint_tracking_custom_source() {
+        let validator = TaintTrackingValidator::new()
+            .with_taint_source("fetch-prompt".to_string());
+        let expr = LispExpr::List(vec![
+            LispExpr::Symbol("shell".into()),
+            LispExpr::List(vec![LispExpr::Symbol("fetch-prompt".into())].into()),
+        ].into());
+        assert!(validator.validate(&expr).is_err());
+    }
+
     #[test]
     fn test_validation_error_display() {
         let error = ValidationError {
             rule: ValidationRule::TypeSafety,
+            severity: Severity::Error,
             message: "Type mismatch".to_string(),
             context: Some("line 42".to_string()),
+            suggestion: None,
+            fix: None,
         };
         let display = format!("{}", error);
         assert!(display.contains("TypeSafety"));
         assert!(display.contains("Type mismatch"));
         assert!(display.contains("line 42"));
+        assert!(!display.contains("Help"));
+    }
+
+    #[test]
+    fn test_numeric_string_literal_fix_is_machine_applicable() {
+        let validator = TypeSafetyValidator::new();
+        let mut expr = LispExpr::List(vec![sym("+"), LispExpr::String("42".to_string()), LispExpr::Number(1.0)].into());
+
+        let fix = validator.validate(&expr).unwrap_err().fix.unwrap();
+        assert_eq!(fix.applicability, Applicability::MachineApplicable);
+        assert!(fix.apply_to(&mut expr));
+        assert_eq!(expr.to_string(), "(+ 42 1)");
+        assert!(validator.validate(&expr).is_ok());
+    }
+
+    #[test]
+    fn test_string_variable_fix_wraps_with_string_to_number() {
+        let validator = TypeSafetyValidator::new().with_binding("input".to_string(), InferredType::String);
+        let mut expr = LispExpr::List(vec![sym("*"), sym("input"), LispExpr::Number(2.0)].into());
+
+        let fix = validator.validate(&expr).unwrap_err().fix.unwrap();
+        assert!(fix.apply_to(&mut expr));
+        assert_eq!(expr.to_string(), "(* (string->number input) 2)");
+    }
+
+    #[test]
+    fn test_composite_apply_fixes() {
+        // (+ "1" "2") (define (loop) (loop))
+        let mut program = vec![
+            LispExpr::List(vec![sym("+"), LispExpr::String("1".to_string()), LispExpr::String("2".to_string())].into()),
+            LispExpr::List(vec![sym("define"), LispExpr::List(vec![sym("loop")].into()), LispExpr::List(vec![sym("loop")].into())].into()),
+        ];
+
+        let composite = CompositeValidator::new()
+            .add_validator(Box::new(TypeSafetyValidator::new()))
+            .add_validator(Box::new(ResourceBoundsValidator::new()));
+        assert_eq!(composite.apply_fixes(&mut program), 2);
+        assert_eq!(program[0].to_string(), "(+ 1 2)");
+        // Placeholder fixes are left for the user
+        assert_eq!(program[1].to_string(), "(define (loop) (loop))");
+    }
+
+    #[test]
+    fn test_ambiguous_fix_is_not_applied() {
+        let fix = SuggestedFix::new(sym("x"), LispExpr::Number(1.0), Applicability::MachineApplicable);
+        let mut expr = LispExpr::List(vec![sym("+"), sym("x"), sym("x")].into());
+        assert!(!fix.apply_to(&mut expr));
+        assert_eq!(expr.to_string(), "(+ x x)");
+    }
+
+    #[test]
+    fn test_recursion_fix_has_placeholders() {
+        let validator = ResourceBoundsValidator::new();
+        let expr = LispExpr::List(vec![
+            sym("define"),
+            LispExpr::List(vec![sym("loop")].into()),
+            LispExpr::List(vec![sym("loop")].into()),
+        ].into());
+
+        let fix = validator.validate(&expr).unwrap_err().fix.unwrap();
+        assert_eq!(fix.applicability, Applicability::HasPlaceholders);
+        assert_eq!(fix.replacement.to_string(), "(if <base-case> <base-value> (loop))");
+    }
+
+    #[test]
+    fn test_validation_error_display_with_suggestion() {
+        let error = ValidationError {
+            rule: ValidationRule::FFIRestrictions,
+            severity: Severity::Error,
+            message: "FFI restriction".to_string(),
+            context: None,
+            suggestion: Some("Remove the call".to_string()),
+            fix: None,
+        };
+        let display = format!("{}", error);
+        assert!(display.contains("Help: Remove the call"));
+        assert_eq!(error.severity.to_string(), "error");
+    }
+
+    fn scope_results(source: &str) -> Vec<ValidationResult> {
+        let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        CapabilityScopeValidator::new().validate_program(&program)
+    }
+
+    fn contract_results(source: &str) -> Vec<ValidationResult> {
+        let program = crate::parser::parse(crate::lexer::tokenize(source).unwrap()).unwrap();
+        ContractValidator::new().validate_program(&program)
+    }
+
+    #[test]
+    fn test_contracts_checked_against_parameters_and_literal_calls() {
+        let results = contract_results(r#"
+            (define limit 100)
+            (defun clamp (n)
+              (declare (requires (>= n 0) (<= n limit)) (ensures (<= result limit)))
+              (min n limit))
+            (clamp 5)
+            (+ 1 (clamp -3))
+            (clamp (- 0 3))
+        "#);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[2].is_ok() && results[4].is_ok());
+        let error = results[3].as_ref().unwrap_err();
+        assert_eq!(error.rule, ValidationRule::Contracts);
+        assert_eq!(error.message, "Call (clamp -3) breaks the precondition of 'clamp': (>= n 0)");
+
+        let messages: Vec<(Severity, String)> = contract_results(r#"
+            (defun a (x) (declare (requires (> y 0))) x)
+            (defun b (x) (declare (requires (> result 0))) x)
+            (defun c (x) (declare (ensures false)) x)
+            (defun d (x) (declare (requires (> 2 1))) x)
+            (defun e (x) (declare (ensures (let ((y (* x 2))) (= result y)))) (* x 2))
+            (defun f (x) (declare (requires)) x)
+        "#)
+        .into_iter()
+        .map(|result| result.map_or_else(|e| (e.severity, e.message), |_| (Severity::Note, String::new())))
+        .collect();
+        assert_eq!(messages, vec![
+            (Severity::Error, "Precondition of 'a' refers to unknown variable 'y': (> y 0)".to_string()),
+            (Severity::Error, "Precondition of 'b' refers to 'result', which only postconditions can see: (> result 0)".to_string()),
+            (Severity::Error, "Postcondition of 'c' can never hold: false".to_string()),
+            (Severity::Warning, "Precondition of 'd' does not depend on its arguments or result: (> 2 1)".to_string()),
+            (Severity::Note, String::new()),
+            (Severity::Error, "Invalid contract in 'f': 'requires' requires at least one condition: (requires)".to_string()),
+        ]);
+
+        let results = contract_results("(defun g (count) (declare (requires (> cont 0))) count)");
+        assert_eq!(results[0].as_ref().unwrap_err().suggestion.as_deref(), Some("Did you mean 'count'?"));
+    }
+
+    #[test]
+    fn test_arity_of_calls_to_defined_functions() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (defun greet (name &key (greeting "Hello")) (string-append greeting ", " name))
+            (greet "Ann" :greeting "Hi")
+            (list (greet "Ann" :greting "Hi"))
+            (greet)
+            (defun broken (&rest r &key k) r)
+            '(greet)
+            (defun shadow (greet) greet)
+        "#).unwrap()).unwrap();
+        let results = ArityValidator::new().validate_program(&program);
+        let messages: Vec<Option<String>> = results.into_iter().map(|r| r.err().map(|e| e.message)).collect();
+        assert_eq!(messages, vec![
+            None,
+            None,
+            Some("'greet' has no keyword argument :greting (expected one of :greeting)".to_string()),
+            Some("'greet' expects 1 argument(s), got 0".to_string()),
+            Some("Invalid parameter list for 'broken': '&rest' and '&key' cannot be combined".to_string()),
+            None,
+            None,
+        ]);
+    }
+
+    #[test]
+    fn test_crate_requirements() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (require-crate "regex" "1" :use (Regex))
+            (require-crate "serde-json" "1")
+            (require-crate "regex" "2")
+            (require-crate "rand" "0.8")
+            (defun f () (require-crate "regex" "1"))
+            (require-crate "regex")
+        "#).unwrap()).unwrap();
+        let validator = CrateValidator::new().allow_crate("regex".to_string()).allow_crate("serde*".to_string());
+        let messages: Vec<Option<String>> = validator.validate_program(&program).into_iter().map(|r| r.err().map(|e| e.message)).collect();
+        assert_eq!(messages, vec![
+            None,
+            None,
+            Some("Crate 'regex' is required with both version \"1\" and \"2\"".to_string()),
+            Some("Crate 'rand' is not in the allowed crates".to_string()),
+            Some("'require-crate' can only appear at the top level of a program".to_string()),
+            Some("'require-crate' requires a crate name, a version string, and optionally :use (paths...)".to_string()),
+        ]);
+        assert!(CrateValidator::new().validate_program(&program[3..4])[0].is_ok());
+    }
+
+    #[test]
+    fn test_declarations() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (defun square (x) : number (declare (pure) (inline)) (* x x))
+            (defun noisy (x) (declare (pure)) (print x) (set! x 1) (read-file "/etc/hosts"))
+            (define (quoted) (declare (pure)) '(print 1))
+            (defun f () (declare (fast)) 1)
+            (defmacro m (x) (declare (inline)) x)
+            (defstruct point (x y) (declare (deprecated "use vec2")))
+        "#).unwrap()).unwrap();
+        let validator = DeclarationValidator::new();
+        let messages: Vec<Option<String>> = program.iter().map(|expr| validator.validate(expr).err().map(|e| e.message)).collect();
+        assert_eq!(messages, vec![
+            None,
+            Some("Function 'noisy' is declared pure but uses print, set!, read-file".to_string()),
+            None,
+            Some("Invalid declaration on 'f': Unknown declaration 'fast' (expected one of inline, pure, deprecated, since, export, capabilities, requires, ensures, precondition, postcondition)".to_string()),
+            Some("Invalid declaration on 'm': 'inline' cannot be declared on a macro".to_string()),
+            None,
+        ]);
+    }
+
+    #[test]
+    fn test_capability_scope_within_declaration() {
+        let results = scope_results(r#"
+            (defun load-data ()
+              (declare (capabilities (file-read "/data")))
+              (read-file "/data/in.csv"))
+            (defun anything () (run-command "ls"))
+        "#);
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_capability_scope_exceeded_directly_and_through_calls() {
+        let results = scope_results(r#"
+            (defun helper () (run-command "ls"))
+            (defun load-data ()
+              (declare (capabilities (file-read "/data")))
+              (read-file "/etc/passwd")
+              (helper))
+        "#);
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error.rule, ValidationRule::CapabilityScope);
+        assert!(error.message.contains("FileRead:/etc/passwd (read-file)"));
+        assert!(error.message.contains("ProcessSpawn (run-command via 'helper')"));
+        assert_eq!(
+            error.suggestion.as_deref(),
+            Some(r#"Add (file-read "/etc/passwd") process-spawn to its (declare (capabilities ...))"#)
+        );
+    }
+
+    #[test]
+    fn test_capability_scope_dynamic_targets_and_bad_declarations() {
+        let results = scope_results(r#"
+            (defun fetch (url)
+              (declare (capabilities (network "api.example.com")))
+              (http-get url))
+            (defun broken ()
+              (declare (capabilities (teleport "moon")))
+              1)
+        "#);
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().unwrap_err().message.contains("Invalid capability declaration in 'broken'"));
+    }
+
+    #[test]
+    fn test_memory_estimate_literals_and_loops() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (list 1 2 "abcd")
+            '(1 (2 3))
+            (dotimes (i 10) (list 1 2))
+            (make-list 4 "xy")
+            (if (> 1 2) (list 1) (list 1 2 3))
+            (defun f () (make-list 1000000 0))
+        "#).unwrap()).unwrap();
+        let estimates: Vec<usize> = program.iter().map(MemoryEstimateValidator::estimate).collect();
+        assert_eq!(estimates, vec![28, 32, 160, 40, 24, 0]);
+    }
+
+    #[test]
+    fn test_memory_estimate_accumulates_across_forms() {
+        let program = crate::parser::parse(crate::lexer::tokenize(r#"
+            (make-string 60)
+            (make-string 60)
+            (make-string 60)
+        "#).unwrap()).unwrap();
+        let validator = MemoryEstimateValidator::new().with_max_memory(100);
+
+        let results = validator.validate_program(&program);
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error.rule, ValidationRule::MemoryBounds);
+        assert!(error.message.contains("120 bytes exceeds the sandbox memory limit of 100 bytes"));
+        assert!(results[2].is_ok());
+        assert!(validator.validate(&program[0]).is_ok());
+    }
+
+    #[test]
+    fn test_validators_handle_deeply_nested_input() {
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        let depth = 100_000;
+        let source = format!("(defun f (x) {}x{})", "(+ 1 ".repeat(depth), ")".repeat(depth));
+        let program = parse(tokenize(&source).unwrap()).unwrap();
+
+        let results = CompositeValidator::with_all_validators().validate_program(&program);
+        let errors = results[0].as_ref().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].rule, ValidationRule::ComplexityLimits);
+        assert_eq!(MemoryEstimateValidator::new().validate(&program[0]), Ok(()));
     }
 }
@@ -1,26 +1,564 @@
-use crate::ast::LispExpr;
+use crate::ast::{grow_stack, walk_expr, LispExpr, SpanTree, Visitor};
+use crate::macro_expander::{ExpansionStep, MacroExpander};
+use crate::symbol;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Write as FmtWrite;
 
+/// Output format of `--visualize`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisualFormat {
+    Dot,
+    Html,
+    Mermaid,
+    Svg,
+    Tree,
+    /// Each macro expansion step of the program, before and after
+    Expansion,
+}
+
+impl VisualFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "dot" => Ok(VisualFormat::Dot),
+            "html" => Ok(VisualFormat::Html),
+            "mermaid" => Ok(VisualFormat::Mermaid),
+            "svg" => Ok(VisualFormat::Svg),
+            "tree" => Ok(VisualFormat::Tree),
+            "expansion" => Ok(VisualFormat::Expansion),
+            other => Err(format!(
+                "Unknown visualization format '{}' (expected dot, html, mermaid, svg, tree, or expansion)",
+                other
+            )),
+        }
+    }
+
+    /// File extension for output in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            VisualFormat::Dot => "dot",
+            VisualFormat::Html | VisualFormat::Expansion => "html",
+            VisualFormat::Mermaid => "mmd",
+            VisualFormat::Svg => "svg",
+            VisualFormat::Tree => "txt",
+        }
+    }
+
+    pub fn render(self, exprs: &[LispExpr]) -> String {
+        match self {
+            VisualFormat::Dot => DotVisualizer::new().visualize(exprs),
+            VisualFormat::Html => HtmlVisualizer::new().visualize(exprs),
+            VisualFormat::Mermaid => MermaidVisualizer::new().visualize(exprs),
+            VisualFormat::Svg => SvgVisualizer::new().visualize(exprs),
+            VisualFormat::Tree => TreeVisualizer::new().visualize(exprs),
+            VisualFormat::Expansion => ExpansionVisualizer::new().visualize(exprs),
+        }
+    }
+}
+
+/// A node as the graph visualizers draw it: label, fill color (a CSS/Graphviz
+/// color name), and children with their edge labels
+struct VisualNode {
+    label: String,
+    color: &'static str,
+    children: Vec<(String, VisualNode)>,
+}
+
+impl VisualNode {
+    /// Same labels, colors, and edges as `DotVisualizer`
+    fn from_expr(expr: &LispExpr) -> Self {
+        let node = |label: String, color, children| VisualNode { label, color, children };
+        let indexed = |items: &[LispExpr], prefix: &str| {
+            items.iter().enumerate().map(|(i, item)| (format!("{}{}", prefix, i), grow_stack(|| Self::from_expr(item)))).collect()
+        };
+        let only = |inner: &LispExpr| vec![(String::new(), grow_stack(|| Self::from_expr(inner)))];
+
+        match expr {
+            LispExpr::Number(n) => node(n.to_string(), "lightblue", vec![]),
+            LispExpr::Symbol(s) => node(s.to_string(), "lightgreen", vec![]),
+            LispExpr::String(s) => node(format!("\"{}\"", s), "lightyellow", vec![]),
+            LispExpr::Bool(b) => node(b.to_string(), "lightcoral", vec![]),
+            LispExpr::Nil => node("nil".to_string(), "lightgray", vec![]),
+            LispExpr::List(items) => node("List".to_string(), "wheat", indexed(items, "")),
+            LispExpr::Macro { name, parameters, body, .. } => {
+                let params = node(format!("Parameters: {}", symbol::join(parameters, ", ")), "thistle", vec![]);
+                let children = vec![("params".to_string(), params), ("body".to_string(), grow_stack(|| Self::from_expr(body)))];
+                node(format!("Macro: {}", name), "plum", children)
+            }
+            LispExpr::MacroCall { name, args } => node(format!("MacroCall: {}", name), "violet", indexed(args, "arg")),
+            LispExpr::Quote(inner) => node("Quote".to_string(), "lightpink", only(inner)),
+            LispExpr::Quasiquote(inner) => node("Quasiquote".to_string(), "lightsalmon", only(inner)),
+            LispExpr::Unquote(inner) => node("Unquote".to_string(), "lightseagreen", only(inner)),
+            LispExpr::Splice(inner) => node("Splice".to_string(), "lightsteelblue", only(inner)),
+            LispExpr::Gensym(name) => node(format!("Gensym: {}", name), "lavender", vec![]),
+        }
+    }
+}
+
+/// Generates a Mermaid `graph TD` flowchart, which GitHub renders in Markdown
+pub struct MermaidVisualizer {
+    node_counter: usize,
+}
+
+impl MermaidVisualizer {
+    pub fn new() -> Self {
+        MermaidVisualizer { node_counter: 0 }
+    }
+
+    pub fn visualize(&mut self, exprs: &[LispExpr]) -> String {
+        let mut output = String::from("graph TD\n");
+        for expr in exprs {
+            self.visualize_node(&VisualNode::from_expr(expr), &mut output);
+        }
+        output
+    }
+
+    fn visualize_node(&mut self, node: &VisualNode, output: &mut String) -> String {
+        let node_id = format!("n{}", self.node_counter);
+        self.node_counter += 1;
+        writeln!(output, "  {}[\"{}\"]", node_id, escape_mermaid(&node.label)).unwrap();
+        writeln!(output, "  style {} fill:{}", node_id, node.color).unwrap();
+
+        for (edge, child) in &node.children {
+            let child_id = grow_stack(|| self.visualize_node(child, output));
+            if edge.is_empty() {
+                writeln!(output, "  {} --> {}", node_id, child_id).unwrap();
+            } else {
+                writeln!(output, "  {} -->|{}| {}", node_id, escape_mermaid(edge), child_id).unwrap();
+            }
+        }
+        node_id
+    }
+}
+
+impl Default for MermaidVisualizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Renders the AST as a standalone SVG tree using its own layout, so Graphviz isn't needed
+pub struct SvgVisualizer;
+
+const SVG_NODE_HEIGHT: usize = 28;
+const SVG_LEVEL_HEIGHT: usize = 70;
+const SVG_GAP: usize = 16;
+const SVG_MARGIN: usize = 20;
+
+impl SvgVisualizer {
+    pub fn new() -> Self {
+        SvgVisualizer
+    }
+
+    pub fn visualize(&self, exprs: &[LispExpr]) -> String {
+        let roots: Vec<VisualNode> = exprs.iter().map(VisualNode::from_expr).collect();
+        let widths: Vec<usize> = roots.iter().map(subtree_width).collect();
+        let width = widths.iter().sum::<usize>() + SVG_GAP * roots.len().saturating_sub(1) + 2 * SVG_MARGIN;
+        let height = roots.iter().map(depth).max().unwrap_or(0) * SVG_LEVEL_HEIGHT + 2 * SVG_MARGIN;
+
+        let mut edges = String::new();
+        let mut nodes = String::new();
+        let mut left = SVG_MARGIN;
+        for (root, root_width) in roots.iter().zip(&widths) {
+            self.place(root, left, SVG_MARGIN, &mut edges, &mut nodes);
+            left += root_width + SVG_GAP;
+        }
+
+        let mut output = String::new();
+        writeln!(
+            output,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" font-family=\"monospace\" font-size=\"12\">",
+            width, height, width, height
+        )
+        .unwrap();
+        output.push_str(&edges);
+        output.push_str(&nodes);
+        output.push_str("</svg>\n");
+        output
+    }
+
+    /// Draws `node` centered over the `subtree_width` columns starting at `left`;
+    /// returns its center x
+    fn place(&self, node: &VisualNode, left: usize, top: usize, edges: &mut String, nodes: &mut String) -> usize {
+        let center = left + subtree_width(node) / 2;
+        let children_width: usize =
+            node.children.iter().map(|(_, child)| subtree_width(child)).sum::<usize>() + SVG_GAP * node.children.len().saturating_sub(1);
+        let mut child_left = center - children_width / 2;
+        let child_top = top + SVG_LEVEL_HEIGHT;
+
+        for (edge, child) in &node.children {
+            let child_center = grow_stack(|| self.place(child, child_left, child_top, edges, nodes));
+            writeln!(
+                edges,
+                "  <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"gray\"/>",
+                center, top + SVG_NODE_HEIGHT, child_center, child_top
+            )
+            .unwrap();
+            if !edge.is_empty() {
+                writeln!(
+                    edges,
+                    "  <text x=\"{}\" y=\"{}\" font-size=\"10\" fill=\"dimgray\">{}</text>",
+                    (center + child_center) / 2 + 3, (top + SVG_NODE_HEIGHT + child_top) / 2, escape_html(edge)
+                )
+                .unwrap();
+            }
+            child_left += subtree_width(child) + SVG_GAP;
+        }
+
+        let width = node_width(node);
+        writeln!(
+            nodes,
+            "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"6\" fill=\"{}\" stroke=\"gray\"/>",
+            center - width / 2, top, width, SVG_NODE_HEIGHT, node.color
+        )
+        .unwrap();
+        writeln!(
+            nodes,
+            "  <text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            center, top + SVG_NODE_HEIGHT / 2 + 4, escape_html(&node.label)
+        )
+        .unwrap();
+        center
+    }
+}
+
+impl Default for SvgVisualizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Prints the AST as an indented tree for the terminal, like `cargo tree`
+pub struct TreeVisualizer {
+    color: bool,
+}
+
+impl TreeVisualizer {
+    pub fn new() -> Self {
+        TreeVisualizer { color: false }
+    }
+
+    /// Color node labels by kind with ANSI escapes
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn visualize(&self, exprs: &[LispExpr]) -> String {
+        let mut output = String::new();
+        for expr in exprs {
+            let node = VisualNode::from_expr(expr);
+            writeln!(output, "{}", self.label(&node)).unwrap();
+            self.visualize_children(&node, "", &mut output);
+        }
+        output
+    }
+
+    fn visualize_children(&self, node: &VisualNode, prefix: &str, output: &mut String) {
+        for (i, (edge, child)) in node.children.iter().enumerate() {
+            let last = i + 1 == node.children.len();
+            let (branch, continuation) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+            // Positional edges ("0", "arg1") are implied by the order
+            let edge = match edge.as_str() {
+                "params" | "body" => format!("{}: ", edge),
+                _ => String::new(),
+            };
+            writeln!(output, "{}{}{}{}", prefix, branch, edge, self.label(child)).unwrap();
+            grow_stack(|| self.visualize_children(child, &format!("{}{}", prefix, continuation), output));
+        }
+    }
+
+    fn label(&self, node: &VisualNode) -> String {
+        if !self.color {
+            return node.label.clone();
+        }
+        let code = match node.color {
+            "lightblue" => "34",
+            "lightgreen" => "32",
+            "lightyellow" => "33",
+            "lightcoral" => "31",
+            "lightgray" => "90",
+            "wheat" => "1",
+            "plum" | "thistle" | "violet" | "lavender" => "35",
+            _ => "36",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, node.label)
+    }
+}
+
+impl Default for TreeVisualizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The program as it stands after one step of the compilation pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineStage {
+    pub name: String,
+    pub exprs: Vec<LispExpr>,
+}
+
+impl PipelineStage {
+    pub fn new(name: impl Into<String>, exprs: Vec<LispExpr>) -> Self {
+        PipelineStage { name: name.into(), exprs }
+    }
+}
+
+/// HTML report auditing the pipeline: one page per step, with the program the
+/// step received and what it produced; top-level forms it changed are marked
+pub struct PipelineVisualizer;
+
+impl PipelineVisualizer {
+    pub fn new() -> Self {
+        PipelineVisualizer
+    }
+
+    /// `stages[0]` is the parsed program; every later stage is one step's output
+    pub fn visualize(&self, stages: &[PipelineStage]) -> String {
+        let mut output = String::new();
+        output.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n");
+        output.push_str("  <meta charset=\"UTF-8\">\n");
+        output.push_str("  <title>Pipeline Report</title>\n");
+        output.push_str("  <style>\n");
+        output.push_str(include_str!("steps_style.css"));
+        output.push_str("  </style>\n</head>\n<body>\n<div class=\"container\">\n");
+        output.push_str("<h1>Pipeline Report</h1>\n");
+
+        let steps: Vec<_> = stages.windows(2).collect();
+        if steps.is_empty() {
+            output.push_str("<p>No pipeline steps to show.</p>\n");
+        } else {
+            writeln!(
+                output,
+                "<div class=\"controls\"><button id=\"prev\">&larr;</button><input type=\"range\" id=\"step\" min=\"0\" max=\"{}\" value=\"0\"><button id=\"next\">&rarr;</button><span id=\"step-label\"></span></div>",
+                steps.len() - 1
+            )
+            .unwrap();
+        }
+        for (index, pair) in steps.iter().enumerate() {
+            let (input, step) = (&pair[0], &pair[1]);
+            let changed = (0..input.exprs.len().max(step.exprs.len()))
+                .filter(|&i| input.exprs.get(i) != step.exprs.get(i))
+                .count();
+            writeln!(output, "<div class=\"step\" data-step=\"{}\">", index).unwrap();
+            writeln!(
+                output,
+                "<h2>{} <span class=\"depth\">{}</span></h2>",
+                escape_html(&step.name),
+                match changed {
+                    0 => "no forms changed".to_string(),
+                    n => format!("{} of {} top-level forms changed", n, input.exprs.len().max(step.exprs.len())),
+                }
+            )
+            .unwrap();
+            output.push_str("<div class=\"panes\">\n");
+            write_pane(&mut output, &format!("Input ({})", escape_html(&input.name)), &input.exprs);
+            write_pane(&mut output, "Output", &step.exprs);
+            output.push_str("</div>\n</div>\n");
+        }
+
+        output.push_str("</div>\n<script>\n");
+        output.push_str(include_str!("steps_script.js"));
+        output.push_str("</script>\n</body>\n</html>\n");
+        output
+    }
+}
+
+impl Default for PipelineVisualizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// HTML page stepping through every macro expansion of a program with a slider:
+/// each step shows the call and what it expanded to as side-by-side graphs, plus
+/// the hygiene renames it made
+pub struct ExpansionVisualizer;
+
+impl ExpansionVisualizer {
+    pub fn new() -> Self {
+        ExpansionVisualizer
+    }
+
+    /// Expands `exprs` (an unexpanded program) and renders the recorded steps. An
+    /// expansion error is shown after the steps that succeeded.
+    pub fn visualize(&self, exprs: &[LispExpr]) -> String {
+        let mut expander = MacroExpander::new().with_trace();
+        let mut expanded = Vec::new();
+        let mut error = None;
+        for expr in exprs {
+            match expander.expand_all(expr.clone()) {
+                Ok(LispExpr::Nil) => {}
+                Ok(expr) => expanded.push(expr),
+                Err(e) => {
+                    error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        let steps = expander.trace();
+
+        let mut output = String::new();
+        output.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n");
+        output.push_str("  <meta charset=\"UTF-8\">\n");
+        output.push_str("  <title>Macro Expansion</title>\n");
+        output.push_str("  <style>\n");
+        output.push_str(include_str!("steps_style.css"));
+        output.push_str("  </style>\n</head>\n<body>\n<div class=\"container\">\n");
+        output.push_str("<h1>Macro Expansion</h1>\n");
+
+        if steps.is_empty() {
+            output.push_str("<p>No macro calls to expand.</p>\n");
+        } else {
+            writeln!(
+                output,
+                "<div class=\"controls\"><button id=\"prev\">&larr;</button><input type=\"range\" id=\"step\" min=\"0\" max=\"{}\" value=\"0\"><button id=\"next\">&rarr;</button><span id=\"step-label\"></span></div>",
+                steps.len() - 1
+            )
+            .unwrap();
+            for (index, step) in steps.iter().enumerate() {
+                self.visualize_step(index, step, &mut output);
+            }
+        }
+        if let Some(error) = error {
+            writeln!(output, "<div class=\"error\">Expansion stopped: {}</div>", escape_html(&error)).unwrap();
+        }
+
+        output.push_str("<h2>Whole program</h2>\n<div class=\"panes\">\n");
+        write_pane(&mut output, "Before expansion", exprs);
+        write_pane(&mut output, "After expansion", &expanded);
+        output.push_str("</div>\n</div>\n<script>\n");
+        output.push_str(include_str!("steps_script.js"));
+        output.push_str("</script>\n</body>\n</html>\n");
+        output
+    }
+
+    fn visualize_step(&self, index: usize, step: &ExpansionStep, output: &mut String) {
+        writeln!(output, "<div class=\"step\" data-step=\"{}\">", index).unwrap();
+        let nesting = match step.depth {
+            0 => "written in the source".to_string(),
+            depth => format!("produced by an expansion, depth {}", depth),
+        };
+        writeln!(
+            output,
+            "<h2>{} <span class=\"depth\">{}</span></h2>",
+            escape_html(&step.macro_name),
+            nesting
+        )
+        .unwrap();
+        output.push_str("<div class=\"panes\">\n");
+        write_pane(output, "Call", std::slice::from_ref(&step.call));
+        write_pane(output, "Expansion", std::slice::from_ref(&step.expansion));
+        output.push_str("</div>\n");
+
+        if step.renames.is_empty() {
+            output.push_str("<p>No symbols renamed for hygiene.</p>\n");
+        } else {
+            output.push_str("<table class=\"renames\"><tr><th>Introduced symbol</th><th>Renamed to</th></tr>\n");
+            for (symbol, renamed) in &step.renames {
+                writeln!(output, "<tr><td>{}</td><td>{}</td></tr>", escape_html(symbol), escape_html(renamed)).unwrap();
+            }
+            output.push_str("</table>\n");
+        }
+        output.push_str("</div>\n");
+    }
+}
+
+impl Default for ExpansionVisualizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A titled pane with the forms as source and as an SVG graph
+fn write_pane(output: &mut String, title: &str, exprs: &[LispExpr]) {
+    writeln!(output, "<div class=\"pane\"><h3>{}</h3>", title).unwrap();
+    let source: Vec<String> = exprs.iter().map(LispExpr::to_source).collect();
+    writeln!(output, "<pre>{}</pre>", escape_html(&source.join("\n"))).unwrap();
+    output.push_str(&SvgVisualizer::new().visualize(exprs));
+    output.push_str("</div>\n");
+}
+
+fn node_width(node: &VisualNode) -> usize {
+    (node.label.chars().count() * 8 + 16).max(40)
+}
+
+fn subtree_width(node: &VisualNode) -> usize {
+    let children: usize = node.children.iter().map(|(_, child)| grow_stack(|| subtree_width(child))).sum::<usize>()
+        + SVG_GAP * node.children.len().saturating_sub(1);
+    node_width(node).max(children)
+}
+
+fn depth(node: &VisualNode) -> usize {
+    1 + node.children.iter().map(|(_, child)| grow_stack(|| depth(child))).max().unwrap_or(0)
+}
+
 /// Generates DOT graph representation of AST for Graphviz
 pub struct DotVisualizer {
     node_counter: usize,
+    max_depth: Option<usize>,
+    collapse_above: Option<usize>,
+    focus: Option<String>,
+    /// `subtree_size` of every node in the form being drawn, keyed by address;
+    /// filled only for `collapse_above`, which needs the size at every node
+    sizes: HashMap<*const LispExpr, usize>,
 }
 
 impl DotVisualizer {
     pub fn new() -> Self {
-        DotVisualizer { node_counter: 0 }
+        DotVisualizer { node_counter: 0, max_depth: None, collapse_above: None, focus: None, sizes: HashMap::new() }
+    }
+
+    /// Draw `depth` levels below each top-level form; deeper subtrees become summary nodes
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Replace subtrees of more than `nodes` nodes (below the top level) with summary nodes
+    pub fn with_collapse_above(mut self, nodes: usize) -> Self {
+        self.collapse_above = Some(nodes);
+        self
+    }
+
+    /// Draw only the definitions of the function or macro `name` (see `find_definitions`)
+    pub fn with_focus(mut self, name: impl Into<String>) -> Self {
+        self.focus = Some(name.into());
+        self
     }
 
     /// Generate DOT graph for an AST
     pub fn visualize(&mut self, exprs: &[LispExpr]) -> String {
+        self.visualize_graph("AST", exprs)
+    }
+
+    /// One graph per pipeline stage in a single DOT file; `dot -O` renders each
+    pub fn visualize_pipeline(&mut self, stages: &[PipelineStage]) -> String {
+        stages
+            .iter()
+            .map(|stage| self.visualize_graph(&format!("\"{}\"", escape_dot(&stage.name)), &stage.exprs))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn visualize_graph(&mut self, name: &str, exprs: &[LispExpr]) -> String {
         let mut output = String::new();
-        output.push_str("digraph AST {\n");
+        writeln!(output, "digraph {} {{", name).unwrap();
         output.push_str("  node [shape=box, style=rounded];\n");
         output.push_str("  rankdir=TB;\n\n");
 
-        for expr in exprs {
-            self.visualize_expr(expr, &mut output, None);
+        let roots = match &self.focus {
+            Some(name) => find_definitions(exprs, name),
+            None => exprs.iter().collect(),
+        };
+        for expr in roots {
+            if self.collapse_above.is_some() {
+                self.sizes.clear();
+                subtree_sizes(expr, &mut self.sizes);
+            }
+            self.visualize_expr(expr, &mut output, 0);
         }
+        self.sizes.clear();
 
         output.push_str("}\n");
         output
@@ -32,9 +570,33 @@ impl DotVisualizer {
         id
     }
 
-    fn visualize_expr(&mut self, expr: &LispExpr, output: &mut String, parent_id: Option<&str>) -> String {
+    fn visualize_expr(&mut self, expr: &LispExpr, output: &mut String, depth: usize) -> String {
         let node_id = self.next_node_id();
 
+        // A subtree cut off by `max_depth` is counted once here and never
+        // descended into, so sizes are only ever precomputed for `collapse_above`
+        let too_deep = self.max_depth.is_some_and(|max| depth >= max);
+        let collapsible = depth > 0 && self.collapse_above.is_some();
+        let size = if too_deep || collapsible {
+            self.sizes.get(&(expr as *const LispExpr)).copied().unwrap_or_else(|| subtree_size(expr))
+        } else {
+            1
+        };
+        let too_big = collapsible && self.collapse_above.is_some_and(|max| size > max);
+        if size > 1 && (too_deep || too_big) {
+            let node = VisualNode::from_expr(expr);
+            writeln!(
+                output,
+                "  {} [label=\"{}\\n({} hidden)\", fillcolor=\"{}\", style=\"filled,rounded,dashed\"];",
+                node_id,
+                escape_dot(&node.label),
+                size - 1,
+                node.color
+            )
+            .unwrap();
+            return node_id;
+        }
+
         match expr {
             LispExpr::Number(n) => {
                 writeln!(output, "  {} [label=\"{}\", fillcolor=\"lightblue\", style=\"filled,rounded\"];", node_id, n).unwrap();
@@ -54,46 +616,46 @@ impl DotVisualizer {
             LispExpr::List(items) => {
                 writeln!(output, "  {} [label=\"List\", fillcolor=\"wheat\", style=\"filled,rounded\"];", node_id).unwrap();
                 for (i, item) in items.iter().enumerate() {
-                    let child_id = self.visualize_expr(item, output, Some(&node_id));
+                    let child_id = grow_stack(|| self.visualize_expr(item, output, depth + 1));
                     writeln!(output, "  {} -> {} [label=\"{}\"];", node_id, child_id, i).unwrap();
                 }
             }
-            LispExpr::Macro { name, parameters, body } => {
+            LispExpr::Macro { name, parameters, body, .. } => {
                 writeln!(output, "  {} [label=\"Macro: {}\", fillcolor=\"plum\", style=\"filled,rounded\"];", node_id, escape_dot(name)).unwrap();
 
                 let params_id = self.next_node_id();
-                let params_label = format!("Parameters: {}", parameters.join(", "));
+                let params_label = format!("Parameters: {}", symbol::join(parameters, ", "));
                 writeln!(output, "  {} [label=\"{}\", fillcolor=\"thistle\", style=\"filled,rounded\"];", params_id, escape_dot(&params_label)).unwrap();
                 writeln!(output, "  {} -> {} [label=\"params\"];", node_id, params_id).unwrap();
 
-                let body_id = self.visualize_expr(body, output, Some(&node_id));
+                let body_id = grow_stack(|| self.visualize_expr(body, output, depth + 1));
                 writeln!(output, "  {} -> {} [label=\"body\"];", node_id, body_id).unwrap();
             }
             LispExpr::MacroCall { name, args } => {
                 writeln!(output, "  {} [label=\"MacroCall: {}\", fillcolor=\"violet\", style=\"filled,rounded\"];", node_id, escape_dot(name)).unwrap();
                 for (i, arg) in args.iter().enumerate() {
-                    let child_id = self.visualize_expr(arg, output, Some(&node_id));
+                    let child_id = grow_stack(|| self.visualize_expr(arg, output, depth + 1));
                     writeln!(output, "  {} -> {} [label=\"arg{}\"];", node_id, child_id, i).unwrap();
                 }
             }
             LispExpr::Quote(inner) => {
                 writeln!(output, "  {} [label=\"Quote\", fillcolor=\"lightpink\", style=\"filled,rounded\"];", node_id).unwrap();
-                let child_id = self.visualize_expr(inner, output, Some(&node_id));
+                let child_id = grow_stack(|| self.visualize_expr(inner, output, depth + 1));
                 writeln!(output, "  {} -> {};", node_id, child_id).unwrap();
             }
             LispExpr::Quasiquote(inner) => {
                 writeln!(output, "  {} [label=\"Quasiquote\", fillcolor=\"lightsalmon\", style=\"filled,rounded\"];", node_id).unwrap();
-                let child_id = self.visualize_expr(inner, output, Some(&node_id));
+                let child_id = grow_stack(|| self.visualize_expr(inner, output, depth + 1));
                 writeln!(output, "  {} -> {};", node_id, child_id).unwrap();
             }
             LispExpr::Unquote(inner) => {
                 writeln!(output, "  {} [label=\"Unquote\", fillcolor=\"lightseagreen\", style=\"filled,rounded\"];", node_id).unwrap();
-                let child_id = self.visualize_expr(inner, output, Some(&node_id));
+                let child_id = grow_stack(|| self.visualize_expr(inner, output, depth + 1));
                 writeln!(output, "  {} -> {};", node_id, child_id).unwrap();
             }
             LispExpr::Splice(inner) => {
                 writeln!(output, "  {} [label=\"Splice\", fillcolor=\"lightsteelblue\", style=\"filled,rounded\"];", node_id).unwrap();
-                let child_id = self.visualize_expr(inner, output, Some(&node_id));
+                let child_id = grow_stack(|| self.visualize_expr(inner, output, depth + 1));
                 writeln!(output, "  {} -> {};", node_id, child_id).unwrap();
             }
             LispExpr::Gensym(name) => {
@@ -101,10 +663,6 @@ impl DotVisualizer {
             }
         }
 
-        if let Some(_parent) = parent_id {
-            // Edge already created in parent
-        }
-
         node_id
     }
 }
@@ -116,11 +674,34 @@ impl Default for DotVisualizer {
 }
 
 /// Generates HTML visualization of AST
-pub struct HtmlVisualizer;
+pub struct HtmlVisualizer {
+    spans: Vec<SpanTree>,
+    source: Option<String>,
+    source_href: Option<String>,
+}
 
 impl HtmlVisualizer {
     pub fn new() -> Self {
-        HtmlVisualizer
+        HtmlVisualizer { spans: Vec::new(), source: None, source_href: None }
+    }
+
+    /// Annotate nodes with their source location, one tree per top-level expression
+    /// (from `parser::parse_with_span_trees`, with `SpanTree::with_lines` applied)
+    pub fn with_spans(mut self, spans: Vec<SpanTree>) -> Self {
+        self.spans = spans;
+        self
+    }
+
+    /// Show `source` beside the tree; hovering a node highlights the text its span covers
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Link each located node to `{href}#L{line}` instead of its text in the source pane
+    pub fn with_source_href(mut self, href: impl Into<String>) -> Self {
+        self.source_href = Some(href.into());
+        self
     }
 
     /// Generate interactive HTML visualization
@@ -140,13 +721,20 @@ impl HtmlVisualizer {
         output.push_str("<body>\n");
         output.push_str("  <div class=\"container\">\n");
         output.push_str("    <h1>AST Visualization</h1>\n");
+        if let Some(source) = &self.source {
+            output.push_str("    <div class=\"source-split\">\n");
+            self.write_source_pane(source, &mut output);
+        }
         output.push_str("    <div class=\"ast-tree\">\n");
 
-        for expr in exprs {
-            self.visualize_expr(expr, &mut output, 0);
+        for (i, expr) in exprs.iter().enumerate() {
+            self.visualize_expr(expr, &mut output, 0, self.spans.get(i));
         }
 
         output.push_str("    </div>\n");
+        if self.source.is_some() {
+            output.push_str("    </div>\n");
+        }
         output.push_str("  </div>\n");
         output.push_str("  <script>\n");
         output.push_str(include_str!("visualizer_script.js"));
@@ -157,54 +745,120 @@ impl HtmlVisualizer {
         output
     }
 
-    fn visualize_expr(&self, expr: &LispExpr, output: &mut String, depth: usize) {
+    /// The source cut at every span boundary, so a node's text is a run of whole
+    /// segments; each segment records the offset it starts at
+    fn write_source_pane(&self, source: &str, output: &mut String) {
+        fn collect(tree: &SpanTree, boundaries: &mut BTreeSet<usize>) {
+            if let Some(span) = tree.span {
+                boundaries.extend([span.start, span.end]);
+            }
+            for child in &tree.children {
+                grow_stack(|| collect(child, boundaries));
+            }
+        }
+        let mut boundaries = BTreeSet::from([0, source.len()]);
+        for tree in &self.spans {
+            collect(tree, &mut boundaries);
+        }
+        boundaries.retain(|&offset| source.is_char_boundary(offset));
+
+        output.push_str("      <pre class=\"source-pane\">");
+        let offsets: Vec<usize> = boundaries.into_iter().collect();
+        for pair in offsets.windows(2) {
+            write!(
+                output,
+                "<span id=\"src-{}\" data-at=\"{}\">{}</span>",
+                pair[0],
+                pair[0],
+                escape_html(&source[pair[0]..pair[1]])
+            )
+            .unwrap();
+        }
+        output.push_str("</pre>\n");
+    }
+
+    /// `data-*` attributes and the source link for a node's span
+    fn span_markup(&self, span: Option<&SpanTree>, indent: &str) -> (String, String) {
+        let Some(tree) = span else {
+            return (String::new(), String::new());
+        };
+        let mut attributes = String::new();
+        if let Some(range) = tree.span {
+            write!(attributes, " data-start=\"{}\" data-end=\"{}\"", range.start, range.end).unwrap();
+        }
+        let (Some(line), Some(column)) = (tree.line, tree.column) else {
+            return (attributes, String::new());
+        };
+        write!(attributes, " data-line=\"{}\" data-column=\"{}\"", line, column).unwrap();
+        let target = match (&self.source_href, &self.source, tree.span) {
+            (Some(href), _, _) => Some(format!("{}#L{}", escape_html(href), line)),
+            (None, Some(_), Some(range)) => Some(format!("#src-{}", range.start)),
+            _ => None,
+        };
+        let link = match target {
+            Some(target) => format!("{}  <a class=\"source-link\" href=\"{}\">{}:{}</a>\n", indent, target, line, column),
+            None => format!("{}  <span class=\"source-link\">{}:{}</span>\n", indent, line, column),
+        };
+        (attributes, link)
+    }
+
+    fn visualize_expr(&self, expr: &LispExpr, output: &mut String, depth: usize, span: Option<&SpanTree>) {
         let indent = "  ".repeat(depth + 3);
+        let (attributes, link) = self.span_markup(span, &indent);
+        let child = |i: usize| span.and_then(|tree| tree.children.get(i));
 
         match expr {
             LispExpr::Number(n) => {
-                writeln!(output, "{}<div class=\"ast-node ast-number\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-number\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">Number</span>", indent).unwrap();
                 writeln!(output, "{}  <span class=\"node-value\">{}</span>", indent, n).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Symbol(s) => {
-                writeln!(output, "{}<div class=\"ast-node ast-symbol\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-symbol\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">Symbol</span>", indent).unwrap();
                 writeln!(output, "{}  <span class=\"node-value\">{}</span>", indent, escape_html(s)).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::String(s) => {
-                writeln!(output, "{}<div class=\"ast-node ast-string\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-string\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">String</span>", indent).unwrap();
                 writeln!(output, "{}  <span class=\"node-value\">\"{}\"</span>", indent, escape_html(s)).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Bool(b) => {
-                writeln!(output, "{}<div class=\"ast-node ast-bool\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-bool\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">Bool</span>", indent).unwrap();
                 writeln!(output, "{}  <span class=\"node-value\">{}</span>", indent, b).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Nil => {
-                writeln!(output, "{}<div class=\"ast-node ast-nil\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-nil\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">Nil</span>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::List(items) => {
-                writeln!(output, "{}<div class=\"ast-node ast-list\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-list\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">List</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                for item in items {
-                    self.visualize_expr(item, output, depth + 1);
+                for (i, item) in items.iter().enumerate() {
+                    grow_stack(|| self.visualize_expr(item, output, depth + 1, child(i)));
                 }
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
-            LispExpr::Macro { name, parameters, body } => {
-                writeln!(output, "{}<div class=\"ast-node ast-macro\">", indent).unwrap();
+            LispExpr::Macro { name, parameters, body, .. } => {
+                writeln!(output, "{}<div class=\"ast-node ast-macro\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">Macro</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-value\">{}</span>", indent, escape_html(name)).unwrap();
@@ -212,75 +866,81 @@ impl HtmlVisualizer {
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
                 writeln!(output, "{}    <div class=\"param-list\">", indent).unwrap();
-                writeln!(output, "{}      <span class=\"label\">Parameters: {}</span>", indent, parameters.join(", ")).unwrap();
+                writeln!(output, "{}      <span class=\"label\">Parameters: {}</span>", indent, symbol::join(parameters, ", ")).unwrap();
                 writeln!(output, "{}    </div>", indent).unwrap();
                 writeln!(output, "{}    <div class=\"macro-body\">", indent).unwrap();
                 writeln!(output, "{}      <span class=\"label\">Body:</span>", indent).unwrap();
-                self.visualize_expr(body, output, depth + 2);
+                grow_stack(|| self.visualize_expr(body, output, depth + 2, child(0)));
                 writeln!(output, "{}    </div>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::MacroCall { name, args } => {
-                writeln!(output, "{}<div class=\"ast-node ast-macro-call\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-macro-call\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">MacroCall</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-value\">{}</span>", indent, escape_html(name)).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                for arg in args {
-                    self.visualize_expr(arg, output, depth + 1);
+                for (i, arg) in args.iter().enumerate() {
+                    grow_stack(|| self.visualize_expr(arg, output, depth + 1, child(i)));
                 }
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Quote(inner) => {
-                writeln!(output, "{}<div class=\"ast-node ast-quote\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-quote\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">Quote</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                self.visualize_expr(inner, output, depth + 1);
+                grow_stack(|| self.visualize_expr(inner, output, depth + 1, child(0)));
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Quasiquote(inner) => {
-                writeln!(output, "{}<div class=\"ast-node ast-quasiquote\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-quasiquote\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">Quasiquote</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                self.visualize_expr(inner, output, depth + 1);
+                grow_stack(|| self.visualize_expr(inner, output, depth + 1, child(0)));
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Unquote(inner) => {
-                writeln!(output, "{}<div class=\"ast-node ast-unquote\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-unquote\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">Unquote</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                self.visualize_expr(inner, output, depth + 1);
+                grow_stack(|| self.visualize_expr(inner, output, depth + 1, child(0)));
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Splice(inner) => {
-                writeln!(output, "{}<div class=\"ast-node ast-splice\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-splice\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <div class=\"node-header\">", indent).unwrap();
                 writeln!(output, "{}    <span class=\"node-type\">Splice</span>", indent).unwrap();
                 writeln!(output, "{}    <span class=\"toggle\">▼</span>", indent).unwrap();
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}  <div class=\"node-children\">", indent).unwrap();
-                self.visualize_expr(inner, output, depth + 1);
+                grow_stack(|| self.visualize_expr(inner, output, depth + 1, child(0)));
                 writeln!(output, "{}  </div>", indent).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
             }
             LispExpr::Gensym(name) => {
-                writeln!(output, "{}<div class=\"ast-node ast-gensym\">", indent).unwrap();
+                writeln!(output, "{}<div class=\"ast-node ast-gensym\"{}>", indent, attributes).unwrap();
+                output.push_str(&link);
                 writeln!(output, "{}  <span class=\"node-type\">Gensym</span>", indent).unwrap();
                 writeln!(output, "{}  <span class=\"node-value\">{}</span>", indent, escape_html(name)).unwrap();
                 writeln!(output, "{}</div>", indent).unwrap();
@@ -304,8 +964,74 @@ fn escape_dot(s: &str) -> String {
         .replace('\t', "\\t")
 }
 
+/// Escape characters Mermaid treats specially inside quoted labels
+fn escape_mermaid(s: &str) -> String {
+    s.replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+        .replace('\n', " ")
+}
+
+/// Nodes `DotVisualizer` draws for `expr`, counting a macro's parameter node
+fn subtree_size(expr: &LispExpr) -> usize {
+    struct Size(usize);
+    impl Visitor<'_> for Size {
+        fn visit_expr(&mut self, expr: &LispExpr) {
+            self.0 += 1 + usize::from(expr.is_macro());
+            walk_expr(self, expr);
+        }
+    }
+
+    let mut size = Size(0);
+    size.visit_expr(expr);
+    size.0
+}
+
+/// `subtree_size` of `expr` and of every node below it, computed bottom-up in one pass
+fn subtree_sizes(expr: &LispExpr, sizes: &mut HashMap<*const LispExpr, usize>) -> usize {
+    let below: usize = expr.children().into_iter().map(|child| grow_stack(|| subtree_sizes(child, sizes))).sum();
+    let size = 1 + usize::from(expr.is_macro()) + below;
+    sizes.insert(expr, size);
+    size
+}
+
+/// Definitions of `name` anywhere in `exprs`: `defmacro` (parsed or as a list),
+/// `defun`, and `define` of a variable or function
+pub fn find_definitions<'a>(exprs: &'a [LispExpr], name: &str) -> Vec<&'a LispExpr> {
+    struct Definitions<'a, 'n> {
+        name: &'n str,
+        found: Vec<&'a LispExpr>,
+    }
+    impl<'a> Visitor<'a> for Definitions<'a, '_> {
+        fn visit_expr(&mut self, expr: &'a LispExpr) {
+            let defines = match expr {
+                LispExpr::Macro { name, .. } => name == self.name,
+                LispExpr::List(elements) => match (elements.first().and_then(LispExpr::as_symbol), elements.get(1)) {
+                    (Some("defun" | "defmacro" | "define"), Some(LispExpr::Symbol(defined))) => defined == self.name,
+                    (Some("define"), Some(LispExpr::List(signature))) => {
+                        signature.first().and_then(LispExpr::as_symbol) == Some(self.name)
+                    }
+                    _ => false,
+                },
+                _ => false,
+            };
+            if defines {
+                self.found.push(expr);
+            } else {
+                walk_expr(self, expr);
+            }
+        }
+    }
+
+    let mut definitions = Definitions { name, found: Vec::new() };
+    for expr in exprs {
+        definitions.visit_expr(expr);
+    }
+    definitions.found
+}
+
 /// Escape special characters for HTML
-fn escape_html(s: &str) -> String {
+pub(crate) fn escape_html(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
         .replace('>', "&gt;")
@@ -316,6 +1042,172 @@ fn escape_html(s: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_visual_format() {
+        let exprs = vec![LispExpr::Symbol("x".into())];
+        assert!(VisualFormat::parse("dot").unwrap().render(&exprs).starts_with("digraph AST"));
+        assert!(VisualFormat::parse("html").unwrap().render(&exprs).contains("<html"));
+        assert!(VisualFormat::parse("png").is_err());
+        assert_eq!(VisualFormat::Expansion.extension(), "html");
+    }
+
+    #[test]
+    fn test_tree_visualizer() {
+        let exprs = vec![
+            LispExpr::Macro {
+                declarations: Vec::new(),
+                name: "twice".into(),
+                parameters: vec!["x".into()],
+                body: Rc::new(LispExpr::List(vec![LispExpr::Symbol("+".into()), LispExpr::Number(1.0)].into())),
+            },
+            LispExpr::Nil,
+        ];
+        assert_eq!(
+            TreeVisualizer::new().visualize(&exprs),
+            "Macro: twice\n\
+             ├── params: Parameters: x\n\
+             └── body: List\n    \
+                 ├── +\n    \
+                 └── 1\n\
+             nil\n"
+        );
+        let colored = TreeVisualizer::new().with_color(true).visualize(&exprs[1..]);
+        assert_eq!(colored, "\x1b[90mnil\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_expansion_visualizer_shows_each_step() {
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        let source = "(defmacro swap (a b) `(let ((tmp ,a)) (setq ,a ,b) (setq ,b tmp)))\n\
+                      (defmacro swap-twice (a b) `(progn (swap ,a ,b) (swap ,a ,b)))\n\
+                      (swap-twice x y)";
+        let output = ExpansionVisualizer::new().visualize(&parse(tokenize(source).unwrap()).unwrap());
+
+        assert_eq!(output.matches("<div class=\"step\"").count(), 3);
+        assert!(output.contains("max=\"2\""));
+        assert!(output.contains("<h2>swap-twice <span class=\"depth\">written in the source</span></h2>"));
+        assert!(output.contains("produced by an expansion, depth 1"));
+        assert!(output.contains("<tr><td>tmp</td><td>tmp#g"));
+        assert!(!output.contains("Expansion stopped"));
+
+        let failing = ExpansionVisualizer::new().visualize(&parse(tokenize(&format!("{}\n(swap x)", source)).unwrap()).unwrap());
+        assert!(failing.contains("<div class=\"error\">Expansion stopped:"));
+    }
+
+    #[test]
+    fn test_pipeline_visualizers() {
+        let parsed = vec![LispExpr::Symbol("x".into()), LispExpr::Number(1.0)];
+        let mut renamed = parsed.clone();
+        renamed[0] = LispExpr::Symbol("y".into());
+        let stages = vec![
+            PipelineStage::new("parsed", parsed.clone()),
+            PipelineStage::new("echo", parsed),
+            PipelineStage::new("rename", renamed),
+        ];
+
+        let html = PipelineVisualizer::new().visualize(&stages);
+        assert_eq!(html.matches("<div class=\"step\"").count(), 2);
+        assert!(html.contains("<h2>echo <span class=\"depth\">no forms changed</span></h2>"));
+        assert!(html.contains("<h2>rename <span class=\"depth\">1 of 2 top-level forms changed</span></h2>"));
+        assert!(html.contains("<h3>Input (echo)</h3>"));
+
+        let dot = DotVisualizer::new().visualize_pipeline(&stages);
+        assert_eq!(dot.matches("digraph ").count(), 3);
+        assert!(dot.starts_with("digraph \"parsed\" {\n"));
+        assert!(dot.contains("digraph \"rename\" {\n"));
+        assert!(DotVisualizer::new().visualize(&stages[0].exprs).starts_with("digraph AST {\n"));
+    }
+
+    #[test]
+    fn test_html_visualizer_source_links() {
+        use crate::lexer::tokenize_with_spans;
+        use crate::parser::parse_with_span_trees;
+
+        let source = "(+ 1\n   'x)";
+        let (exprs, spans): (Vec<_>, Vec<_>) =
+            parse_with_span_trees(tokenize_with_spans(source).unwrap()).unwrap().into_iter().unzip();
+        let spans = spans.into_iter().map(|tree| tree.with_lines(source)).collect();
+        let output = HtmlVisualizer::new().with_spans(spans).with_source_href("/source").visualize(&exprs);
+
+        assert!(output.contains("<div class=\"ast-node ast-list\" data-start=\"0\" data-end=\"11\" data-line=\"1\" data-column=\"1\">"));
+        assert!(output.contains("<div class=\"ast-node ast-symbol\" data-start=\"9\" data-end=\"10\" data-line=\"2\" data-column=\"5\">"));
+        assert!(output.contains("<a class=\"source-link\" href=\"/source#L2\">2:4</a>"));
+        assert!(!HtmlVisualizer::new().visualize(&exprs).contains("data-line"));
+    }
+
+    #[test]
+    fn test_html_visualizer_source_pane() {
+        use crate::lexer::tokenize_with_spans;
+        use crate::parser::parse_with_span_trees;
+
+        let source = "; sum\n(+ 1 \"<b>\")";
+        let (exprs, spans): (Vec<_>, Vec<_>) =
+            parse_with_span_trees(tokenize_with_spans(source).unwrap()).unwrap().into_iter().unzip();
+        let spans = spans.into_iter().map(|tree| tree.with_lines(source)).collect();
+        let output = HtmlVisualizer::new().with_spans(spans).with_source(source).visualize(&exprs);
+
+        assert!(output.contains(concat!(
+            "<pre class=\"source-pane\"><span id=\"src-0\" data-at=\"0\">; sum\n</span>",
+            "<span id=\"src-6\" data-at=\"6\">(</span><span id=\"src-7\" data-at=\"7\">+</span>",
+            "<span id=\"src-8\" data-at=\"8\"> </span><span id=\"src-9\" data-at=\"9\">1</span>",
+            "<span id=\"src-10\" data-at=\"10\"> </span>",
+            "<span id=\"src-11\" data-at=\"11\">&quot;&lt;b&gt;&quot;</span><span id=\"src-16\" data-at=\"16\">)</span></pre>"
+        )));
+        assert!(output.contains("<a class=\"source-link\" href=\"#src-11\">2:6</a>"));
+        assert!(output.contains("<div class=\"source-split\">"));
+    }
+
+    #[test]
+    fn test_mermaid_visualizer() {
+        let exprs = vec![LispExpr::List(vec![
+            LispExpr::Symbol("print".into()),
+            LispExpr::String("a \"b\"".to_string()),
+        ].into())];
+        let output = MermaidVisualizer::new().visualize(&exprs);
+
+        assert!(output.starts_with("graph TD\n"));
+        assert!(output.contains("  n0[\"List\"]\n  style n0 fill:wheat\n"));
+        assert!(output.contains("  n0 -->|0| n1\n"));
+        assert!(output.contains("n2[\"#quot;a #quot;b#quot;#quot;\"]"));
+    }
+
+    #[test]
+    fn test_svg_visualizer_lays_out_children_below_parent() {
+        let exprs = vec![LispExpr::List(vec![
+            LispExpr::Symbol("+".into()),
+            LispExpr::Number(1.0),
+            LispExpr::Quote(Rc::new(LispExpr::Symbol("x<y".into()))),
+        ].into())];
+        let output = SvgVisualizer::new().visualize(&exprs);
+
+        assert!(output.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(output.trim_end().ends_with("</svg>"));
+        assert_eq!(output.matches("<rect").count(), 5);
+        assert_eq!(output.matches("<line").count(), 4);
+        assert!(output.contains(">x&lt;y</text>"));
+
+        // Children sit on the next level, spread left to right
+        let rects: Vec<(usize, usize)> = output
+            .lines()
+            .filter(|line| line.contains("<rect"))
+            .map(|line| {
+                let attr = |name: &str| {
+                    let start = line.find(&format!(" {}=\"", name)).unwrap() + name.len() + 3;
+                    line[start..].split('"').next().unwrap().parse().unwrap()
+                };
+                (attr("x"), attr("y"))
+            })
+            .collect();
+        // Drawn children first: +, 1, x<y, Quote, then the List itself
+        let list = rects[4];
+        assert_eq!(rects[0].1, list.1 + SVG_LEVEL_HEIGHT);
+        assert_eq!(rects[2].1, list.1 + 2 * SVG_LEVEL_HEIGHT);
+        assert!(rects[0].0 < rects[1].0 && rects[1].0 < rects[3].0);
+    }
 
     #[test]
     fn test_dot_visualizer_number() {
@@ -331,7 +1223,7 @@ mod tests {
     #[test]
     fn test_dot_visualizer_symbol() {
         let mut viz = DotVisualizer::new();
-        let exprs = vec![LispExpr::Symbol("+".to_string())];
+        let exprs = vec![LispExpr::Symbol("+".into())];
         let output = viz.visualize(&exprs);
 
         assert!(output.contains("+"));
@@ -342,10 +1234,10 @@ mod tests {
     fn test_dot_visualizer_list() {
         let mut viz = DotVisualizer::new();
         let exprs = vec![LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
             LispExpr::Number(2.0),
-        ])];
+        ].into())];
         let output = viz.visualize(&exprs);
 
         assert!(output.contains("List"));
@@ -369,9 +1261,9 @@ mod tests {
     fn test_html_visualizer_list() {
         let viz = HtmlVisualizer::new();
         let exprs = vec![LispExpr::List(vec![
-            LispExpr::Symbol("+".to_string()),
+            LispExpr::Symbol("+".into()),
             LispExpr::Number(1.0),
-        ])];
+        ].into())];
         let output = viz.visualize(&exprs);
 
         assert!(output.contains("ast-list"));
@@ -391,17 +1283,42 @@ mod tests {
         assert_eq!(escape_html("a & b"), "a &amp; b");
     }
 
+    #[test]
+    fn test_dot_size_controls() {
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        let exprs = parse(tokenize("(defun f (x) (* x (+ x 1)))\n(defun g (y) (f y))").unwrap()).unwrap();
+
+        let shallow = DotVisualizer::new().with_max_depth(1).visualize(&exprs);
+        assert!(shallow.contains("node3 [label=\"List\\n(1 hidden)\", fillcolor=\"wheat\", style=\"filled,rounded,dashed\"];"));
+        assert!(shallow.contains("node4 [label=\"List\\n(6 hidden)\""));
+        assert_eq!(shallow.matches(" -> ").count(), 8);
+
+        let collapsed = DotVisualizer::new().with_collapse_above(3).visualize(&exprs);
+        assert!(collapsed.contains("[label=\"List\\n(6 hidden)\""));
+        assert!(!collapsed.contains("(1 hidden)"));
+        assert!(!collapsed.contains("label=\"*\""));
+
+        let focused = DotVisualizer::new().with_focus("g").visualize(&exprs);
+        assert!(focused.contains("label=\"g\""));
+        assert!(!focused.contains("label=\"*\""));
+        assert_eq!(find_definitions(&exprs, "f").len(), 1);
+        assert!(find_definitions(&exprs, "h").is_empty());
+    }
+
     #[test]
     fn test_dot_visualizer_macro() {
         let mut viz = DotVisualizer::new();
         let exprs = vec![LispExpr::Macro {
-            name: "double".to_string(),
-            parameters: vec!["x".to_string()],
-            body: Box::new(LispExpr::List(vec![
-                LispExpr::Symbol("*".to_string()),
-                LispExpr::Symbol("x".to_string()),
+            declarations: Vec::new(),
+            name: "double".into(),
+            parameters: vec!["x".into()],
+            body: Rc::new(LispExpr::List(vec![
+                LispExpr::Symbol("*".into()),
+                LispExpr::Symbol("x".into()),
                 LispExpr::Number(2.0),
-            ])),
+            ].into())),
         }];
         let output = viz.visualize(&exprs);
 
@@ -414,9 +1331,10 @@ mod tests {
     fn test_html_visualizer_macro() {
         let viz = HtmlVisualizer::new();
         let exprs = vec![LispExpr::Macro {
-            name: "double".to_string(),
-            parameters: vec!["x".to_string()],
-            body: Box::new(LispExpr::Number(2.0)),
+            declarations: Vec::new(),
+            name: "double".into(),
+            parameters: vec!["x".into()],
+            body: Rc::new(LispExpr::Number(2.0)),
         }];
         let output = viz.visualize(&exprs);
 
@@ -424,4 +1342,18 @@ mod tests {
         assert!(output.contains("double"));
         assert!(output.contains("Parameters"));
     }
+
+    #[test]
+    fn test_visualizers_handle_deeply_nested_input() {
+        let depth = 2_000;
+        let mut expr = LispExpr::Number(1.0);
+        for _ in 0..depth {
+            expr = LispExpr::List(vec![LispExpr::Symbol("list".into()), expr].into());
+        }
+        let exprs = vec![expr];
+        for format in ["dot", "html", "mermaid", "svg", "tree"] {
+            assert!(VisualFormat::parse(format).unwrap().render(&exprs).contains('1'), "{}", format);
+        }
+        assert_eq!(TreeVisualizer::new().visualize(&exprs).lines().count(), 2 * depth + 1);
+    }
 }
@@ -29,6 +29,9 @@ document.addEventListener('DOMContentLoaded', function() {
 
   // Add node highlighting on hover
   addHoverHighlighting();
+
+  // Highlight a hovered node's text in the source pane
+  addSourceHighlighting();
 });
 
 function addExpandCollapseButtons() {
@@ -97,7 +100,7 @@ function addSearchBox() {
   });
 
   searchContainer.appendChild(searchInput);
-  container.insertBefore(searchContainer, container.querySelector('.ast-tree'));
+  container.insertBefore(searchContainer, container.querySelector('.source-split') || container.querySelector('.ast-tree'));
 }
 
 function addHoverHighlighting() {
@@ -113,3 +116,33 @@ function addHoverHighlighting() {
     });
   });
 }
+
+function addSourceHighlighting() {
+  const pane = document.querySelector('.source-pane');
+  if (!pane) return;
+  const segments = Array.from(pane.querySelectorAll('[data-at]'));
+
+  function clear() {
+    segments.forEach(segment => segment.classList.remove('highlighted'));
+  }
+
+  document.querySelectorAll('.ast-node[data-start]').forEach(node => {
+    // mouseover bubbles, so stop at the innermost node under the pointer
+    node.addEventListener('mouseover', function(e) {
+      e.stopPropagation();
+      clear();
+      const start = Number(this.dataset.start);
+      const end = Number(this.dataset.end);
+      const covered = segments.filter(segment => {
+        const at = Number(segment.dataset.at);
+        return at >= start && at < end;
+      });
+      covered.forEach(segment => segment.classList.add('highlighted'));
+      if (covered.length > 0) {
+        covered[0].scrollIntoView({ block: 'nearest' });
+      }
+    });
+  });
+
+  pane.parentElement.addEventListener('mouseleave', clear);
+}
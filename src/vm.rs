@@ -0,0 +1,206 @@
+//! A stack machine executing `bytecode` modules.
+//!
+//! `run --engine vm` runs programs here instead of building them with rustc. Values
+//! are `f64`s on one stack and locals live in one array, each call's after its
+//! caller's. With `--sandbox-mode` every instruction costs one unit of the fuel
+//! budget and the time limit is checked as the program runs; failed contracts and
+//! sandbox violations stop it with an error.
+
+use crate::bytecode::{Chunk, Module, Op};
+use crate::sandbox::{SandboxMonitor, SandboxViolation};
+use std::io::Write;
+
+/// Calls nested deeper than this fail rather than growing the stacks without bound
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+/// Instructions executed between checks of the time limit
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+/// Where a call returns to
+struct Frame<'m> {
+    chunk: &'m Chunk,
+    pc: usize,
+    /// The caller's first local
+    base: usize,
+}
+
+pub struct Vm<'m> {
+    module: &'m Module,
+    monitor: Option<SandboxMonitor>,
+    stack: Vec<f64>,
+    locals: Vec<f64>,
+    frames: Vec<Frame<'m>>,
+}
+
+impl<'m> Vm<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        Vm { module, monitor: None, stack: Vec::new(), locals: Vec::new(), frames: Vec::new() }
+    }
+
+    /// Meter fuel and check the time limit against the monitor's config
+    pub fn with_monitor(mut self, monitor: SandboxMonitor) -> Self {
+        self.monitor = Some(monitor);
+        self
+    }
+
+    pub fn monitor(&self) -> Option<&SandboxMonitor> {
+        self.monitor.as_ref()
+    }
+
+    /// Run `main`, printing its values to `out`
+    pub fn run(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        let mut chunk = &self.module.main;
+        let (mut pc, mut base) = (0, 0);
+        self.locals.resize(chunk.locals, 0.0);
+        let mut executed: u64 = 0;
+        loop {
+            if let Some(monitor) = &mut self.monitor {
+                monitor.consume_fuel(1).map_err(violation)?;
+                executed += 1;
+                if executed.is_multiple_of(TIME_CHECK_INTERVAL) {
+                    monitor.check_time_limit().map_err(violation)?;
+                }
+            }
+            let Some(op) = chunk.code.get(pc) else {
+                return Err(format!("'{}' ran past its last instruction", chunk.name));
+            };
+            pc += 1;
+            match op {
+                Op::Const(n) => self.stack.push(*n),
+                Op::Load(slot) => self.stack.push(self.locals[base + slot]),
+                Op::Store(slot) => self.locals[base + slot] = self.pop()?,
+                Op::Negate => {
+                    let x = self.pop()?;
+                    self.stack.push(-x);
+                }
+                Op::Add => self.binary(|a, b| a + b)?,
+                Op::Subtract => self.binary(|a, b| a - b)?,
+                Op::Multiply => self.binary(|a, b| a * b)?,
+                Op::Divide => self.binary(|a, b| a / b)?,
+                Op::Equal => self.binary(|a, b| truth(a == b))?,
+                Op::Less => self.binary(|a, b| truth(a < b))?,
+                Op::Greater => self.binary(|a, b| truth(a > b))?,
+                Op::LessEqual => self.binary(|a, b| truth(a <= b))?,
+                Op::GreaterEqual => self.binary(|a, b| truth(a >= b))?,
+                Op::Jump(target) => pc = *target,
+                Op::JumpUnless(target) => {
+                    if self.pop()? == 0.0 {
+                        pc = *target;
+                    }
+                }
+                Op::Call(index) => {
+                    let callee = &self.module.functions[*index];
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        return Err(format!("Maximum call depth of {} exceeded in '{}'", MAX_CALL_DEPTH, callee.name));
+                    }
+                    let Some(first) = self.stack.len().checked_sub(callee.parameters) else {
+                        return Err(format!("'{}' called with too few values on the stack", callee.name));
+                    };
+                    self.frames.push(Frame { chunk, pc, base });
+                    base = self.locals.len();
+                    self.locals.extend(self.stack.drain(first..));
+                    self.locals.resize(base + callee.locals, 0.0);
+                    (chunk, pc) = (callee, 0);
+                }
+                Op::Return => {
+                    let Some(frame) = self.frames.pop() else {
+                        return Ok(());
+                    };
+                    self.locals.truncate(base);
+                    (chunk, pc, base) = (frame.chunk, frame.pc, frame.base);
+                }
+                Op::Pop => {
+                    self.pop()?;
+                }
+                Op::PrintNumber => {
+                    let x = self.pop()?;
+                    writeln!(out, "{:?}", x).map_err(|e| e.to_string())?;
+                }
+                Op::PrintBool => {
+                    let b = self.pop()? != 0.0;
+                    writeln!(out, "{}", b).map_err(|e| e.to_string())?;
+                }
+                Op::Check(message) => {
+                    if self.pop()? == 0.0 {
+                        return Err(message.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Result<f64, String> {
+        self.stack.pop().ok_or_else(|| "Bytecode popped an empty stack".to_string())
+    }
+
+    fn binary(&mut self, operation: impl Fn(f64, f64) -> f64) -> Result<(), String> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(operation(a, b));
+        Ok(())
+    }
+}
+
+fn truth(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+fn violation(violation: SandboxViolation) -> String {
+    format!("Sandbox violation: {}", violation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{self, HEADER};
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use crate::sandbox::SandboxConfig;
+
+    fn run(source: &str, monitor: Option<SandboxMonitor>) -> (String, Result<(), String>) {
+        let module = bytecode::compile(&parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let mut vm = Vm::new(&module);
+        if let Some(monitor) = monitor {
+            vm = vm.with_monitor(monitor);
+        }
+        let mut out = Vec::new();
+        let result = vm.run(&mut out);
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    #[test]
+    fn test_run_programs() {
+        let (out, result) = run(
+            "(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) \
+             (defun even? (n) (if (= n 0) true (odd? (- n 1)))) (defun odd? (n) (if (= n 0) false (even? (- n 1)))) \
+             (fact 20) (odd? 7) (let loop ((i 0) (acc 0)) (if (> i 10) acc (loop (+ i 1) (+ acc i)))) (/ 1 0) (- 0.5)",
+            None,
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "2.43290200817664e18\ntrue\n55.0\ninf\n-0.5\n");
+    }
+
+    #[test]
+    fn test_contracts_and_limits() {
+        let (out, result) = run("(defun half (x) (declare (requires (>= x 0))) (/ x 2)) (half 3) (half -1) (half 5)", None);
+        assert_eq!(out, "1.5\n");
+        assert_eq!(result.unwrap_err(), "Precondition of 'half' failed: (>= x 0)");
+
+        let spin = "(let loop ((i 0)) (loop (+ i 1)))";
+        let (_, result) = run(spin, Some(SandboxMonitor::new(SandboxConfig::new().with_max_fuel(1000))));
+        assert_eq!(result.unwrap_err(), "Sandbox violation: Fuel exhausted: limit=1000 forms");
+        let (_, result) = run(spin, Some(SandboxMonitor::new(SandboxConfig::new().with_max_execution_time(std::time::Duration::ZERO))));
+        assert!(result.unwrap_err().starts_with("Sandbox violation: Execution time exceeded"));
+
+        let (_, result) = run("(defun down (n) (+ 1 (down n))) (down 1)", None);
+        assert_eq!(result.unwrap_err(), "Maximum call depth of 10000 exceeded in 'down'");
+    }
+
+    #[test]
+    fn test_malformed_code_fails_cleanly() {
+        let module = Module::parse(&format!("{}\nmain locals 0\n  add\nend\n", HEADER)).unwrap();
+        assert_eq!(Vm::new(&module).run(&mut Vec::new()).unwrap_err(), "Bytecode popped an empty stack");
+        let module = Module::parse(&format!("{}\nmain locals 0\n  const 1.0\n  pop\nend\n", HEADER)).unwrap();
+        assert_eq!(Vm::new(&module).run(&mut Vec::new()).unwrap_err(), "'main' ran past its last instruction");
+    }
+}
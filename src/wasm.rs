@@ -0,0 +1,294 @@
+use crate::sandbox::{env_matches, path_matches, Capability, SandboxConfig};
+use std::path::{Component, Path, PathBuf};
+
+/// Whether this build embeds the WASM runtime (the `wasm` feature)
+pub const RUNTIME_AVAILABLE: bool = cfg!(feature = "wasm");
+
+/// Directory made visible to the WASM guest under the same absolute path
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasiPreopen {
+    pub host: PathBuf,
+    pub guest: String,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// WASI resources granted to a module, derived from a `SandboxConfig`.
+/// Only granted directories are preopened, with no more than the access granted,
+/// and the guest gets no sockets, so anything outside the grants is unreachable
+/// rather than merely checked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WasiPermissions {
+    pub preopens: Vec<WasiPreopen>,
+    /// Environment variables passed to the guest (only those `EnvRead` covers)
+    pub env: Vec<(String, String)>,
+    /// Granted capabilities WASI cannot provide
+    pub unsupported: Vec<Capability>,
+}
+
+impl WasiPermissions {
+    /// Fails for grants WASI cannot enforce: a single file or a glob, which could only
+    /// be preopened with the rest of its directory, and a directory with a denied
+    /// path inside it
+    pub fn from_config(config: &SandboxConfig) -> Result<Self, String> {
+        let mut permissions = WasiPermissions::default();
+
+        let reads = config.allowed_file_paths.iter().chain(config.capabilities.iter().filter_map(|c| match c {
+            Capability::FileRead(path) => Some(path),
+            _ => None,
+        }));
+        for path in reads {
+            permissions.preopen(&Capability::FileRead(path.clone()), config)?;
+        }
+        for capability in &config.capabilities {
+            match capability {
+                Capability::FileWrite(_) => permissions.preopen(capability, config)?,
+                Capability::NetworkHTTP
+                | Capability::Network { .. }
+                | Capability::ProcessSpawn
+                | Capability::ThreadSpawn => {
+                    permissions.unsupported.push(capability.clone())
+                }
+                _ => {}
+            }
+        }
+        if config.permitted_network_access && !permissions.unsupported.contains(&Capability::NetworkHTTP) {
+            permissions.unsupported.push(Capability::NetworkHTTP);
+        }
+        permissions.unsupported.sort_by_key(|c| c.to_string());
+
+        for (name, value) in std::env::vars() {
+            let requested = Capability::EnvRead(name.clone());
+            let granted = config
+                .capabilities
+                .iter()
+                .any(|c| matches!(c, Capability::EnvRead(pattern) if env_matches(pattern, &name)));
+            if granted && !config.is_denied(&requested) {
+                permissions.env.push((name, value));
+            }
+        }
+        permissions.env.sort();
+
+        permissions.preopens.sort_by(|a, b| a.guest.cmp(&b.guest));
+        Ok(permissions)
+    }
+
+    /// Preopen the directory a `FileRead` or `FileWrite` grant names, unless a deny
+    /// covers all of it. Missing directories and paths with `..` are skipped.
+    fn preopen(&mut self, grant: &Capability, config: &SandboxConfig) -> Result<(), String> {
+        let (dir, writable) = match grant {
+            Capability::FileRead(path) => (path, false),
+            Capability::FileWrite(path) => (path, true),
+            _ => return Ok(()),
+        };
+        if dir.components().any(|component| matches!(component, Component::ParentDir)) {
+            return Ok(());
+        }
+        if dir.to_string_lossy().contains(['*', '?']) || dir.is_file() {
+            return Err(format!(
+                "{} grants part of a directory, but WASI can only preopen whole directories; grant the directory instead",
+                grant
+            ));
+        }
+        if !dir.is_dir() {
+            return Ok(());
+        }
+
+        for denied in &config.denied_capabilities {
+            let pattern = match (denied, writable) {
+                (Capability::FileRead(pattern), false) | (Capability::FileWrite(pattern), true) => pattern,
+                _ => continue,
+            };
+            if path_matches(pattern, dir) {
+                return Ok(());
+            }
+            if overlaps(pattern, dir) {
+                return Err(format!(
+                    "Denied {} lies inside {}, which WASI cannot enforce; deny the whole directory or grant a narrower one",
+                    denied, grant
+                ));
+            }
+        }
+
+        let guest = dir.display().to_string();
+        let preopen = match self.preopens.iter_mut().find(|p| p.guest == guest) {
+            Some(existing) => existing,
+            None => {
+                self.preopens.push(WasiPreopen { host: dir.clone(), guest, readable: false, writable: false });
+                self.preopens.last_mut().unwrap()
+            }
+        };
+        if writable { preopen.writable = true } else { preopen.readable = true }
+        Ok(())
+    }
+}
+
+/// Whether a denied path or glob could match something inside `dir`: its part
+/// before any glob is inside `dir`, or is an ancestor of it followed by a glob
+fn overlaps(pattern: &Path, dir: &Path) -> bool {
+    let mut prefix = PathBuf::new();
+    let mut glob = false;
+    for component in pattern.components() {
+        if component.as_os_str().to_string_lossy().contains(['*', '?']) {
+            glob = true;
+            break;
+        }
+        prefix.push(component);
+    }
+    prefix.starts_with(dir) || (glob && dir.starts_with(&prefix))
+}
+
+/// Run a wasm32-wasip1 module with only the resources `config` grants. Memory is
+/// capped by the store and the timeout interrupts the guest; returns its exit code.
+#[cfg(feature = "wasm")]
+pub fn run_module(module_path: &Path, config: &SandboxConfig) -> Result<i32, String> {
+    use wasmtime::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+    use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+    use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+    struct Host {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+    }
+
+    let permissions = WasiPermissions::from_config(config)?;
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .inherit_stdout()
+        .inherit_stderr()
+        .allow_tcp(false)
+        .allow_udp(false)
+        .allow_ip_name_lookup(false);
+    for (name, value) in &permissions.env {
+        builder.env(name, value);
+    }
+    for preopen in &permissions.preopens {
+        let (mut dir_perms, mut file_perms) = (DirPerms::empty(), FilePerms::empty());
+        if preopen.readable {
+            dir_perms |= DirPerms::READ;
+            file_perms |= FilePerms::READ;
+        }
+        if preopen.writable {
+            dir_perms |= DirPerms::MUTATE;
+            file_perms |= FilePerms::WRITE;
+        }
+        builder
+            .preopened_dir(&preopen.host, &preopen.guest, dir_perms, file_perms)
+            .map_err(|e| format!("Cannot preopen '{}': {}", preopen.host.display(), e))?;
+    }
+
+    let mut engine_config = wasmtime::Config::new();
+    engine_config.epoch_interruption(true);
+    let engine = Engine::new(&engine_config).map_err(|e| e.to_string())?;
+    let module = Module::from_file(&engine, module_path).map_err(|e| format!("Invalid WASM module: {}", e))?;
+    let mut linker: Linker<Host> = Linker::new(&engine);
+    preview1::add_to_linker_sync(&mut linker, |host| &mut host.wasi).map_err(|e| e.to_string())?;
+
+    let host = Host {
+        wasi: builder.build_p1(),
+        limits: StoreLimitsBuilder::new().memory_size(config.max_memory).build(),
+    };
+    let mut store = Store::new(&engine, host);
+    store.limiter(|host| &mut host.limits);
+    store.set_epoch_deadline(1);
+
+    let timeout = config.max_execution_time;
+    let watchdog = engine.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        watchdog.increment_epoch();
+    });
+
+    let instance = linker.instantiate(&mut store, &module).map_err(|e| e.to_string())?;
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .map_err(|e| format!("WASM module has no entry point: {}", e))?;
+    match start.call(&mut store, ()) {
+        Ok(()) => Ok(0),
+        Err(error) => {
+            if let Some(exit) = error.downcast_ref::<I32Exit>() {
+                Ok(exit.0)
+            } else if error.downcast_ref::<Trap>() == Some(&Trap::Interrupt) {
+                Err(format!("Sandbox violation: Execution time exceeded: limit={:?}", timeout))
+            } else {
+                Err(format!("WASM execution failed: {:?}", error))
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+pub fn run_module(_module_path: &Path, _config: &SandboxConfig) -> Result<i32, String> {
+    Err("this build has no WASM runtime; rebuild with `cargo build --features wasm`".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissions_preopen_granted_directories() {
+        let temp = std::env::temp_dir();
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::FileWrite(temp.clone()));
+        config.add_capability(Capability::ProcessSpawn);
+
+        let permissions = WasiPermissions::from_config(&config).unwrap();
+        assert_eq!(permissions.preopens, vec![WasiPreopen {
+            host: temp.clone(),
+            guest: temp.display().to_string(),
+            readable: false,
+            writable: true,
+        }]);
+        assert_eq!(permissions.unsupported, vec![Capability::ProcessSpawn]);
+
+        config.add_capability(Capability::FileRead(temp.clone()));
+        assert!(WasiPermissions::from_config(&config).unwrap().preopens[0].readable);
+    }
+
+    #[test]
+    fn test_permissions_refuse_partial_directories() {
+        let temp = std::env::temp_dir().join(format!("rusty-lisp-wasi-{}", std::process::id()));
+        std::fs::create_dir_all(temp.join("secret")).unwrap();
+        std::fs::write(temp.join("data.csv"), "1").unwrap();
+        let refused = |grant: Capability, deny: Option<Capability>| {
+            let mut config = SandboxConfig::new();
+            config.add_capability(grant);
+            config.denied_capabilities.extend(deny);
+            WasiPermissions::from_config(&config).unwrap_err()
+        };
+
+        assert!(refused(Capability::FileRead(temp.join("data.csv")), None).contains("WASI can only preopen whole directories"));
+        assert!(refused(Capability::FileRead(temp.join("*.csv")), None).contains("WASI can only preopen whole directories"));
+        let error = refused(Capability::FileRead(temp.clone()), Some(Capability::FileRead(temp.join("secret"))));
+        assert!(error.starts_with("Denied FileRead:") && error.contains("which WASI cannot enforce"), "{}", error);
+        assert!(refused(Capability::FileWrite(temp.clone()), Some(Capability::FileWrite(PathBuf::from("/**/secret")))).contains("cannot enforce"));
+
+        // A deny covering the whole directory withholds it; one of the other kind does not matter
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::FileRead(temp.join("secret")));
+        config.add_capability(Capability::FileWrite(temp.join("secret")));
+        config.deny_capability(Capability::FileWrite(temp.clone()));
+        let permissions = WasiPermissions::from_config(&config).unwrap();
+        assert_eq!((permissions.preopens.len(), permissions.preopens[0].readable, permissions.preopens[0].writable), (1, true, false));
+        std::fs::remove_dir_all(&temp).unwrap();
+    }
+
+    #[test]
+    fn test_permissions_skip_missing_and_escaping_paths() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::FileRead(PathBuf::from("/definitely/not/here/file")));
+        config.add_capability(Capability::FileRead(PathBuf::from("/tmp/../etc")));
+
+        assert!(WasiPermissions::from_config(&config).unwrap().preopens.is_empty());
+    }
+
+    #[test]
+    fn test_permissions_pass_only_granted_env() {
+        let mut config = SandboxConfig::new();
+        config.add_capability(Capability::EnvRead("PATH".to_string()));
+
+        let permissions = WasiPermissions::from_config(&config).unwrap();
+        assert!(permissions.env.iter().all(|(name, _)| name == "PATH"));
+    }
+}
@@ -0,0 +1,340 @@
+//! WebAssembly text generation for the numeric subset of the language.
+//!
+//! `build --target wat` lowers the expanded program to a WAT module instead of Rust,
+//! so that it runs in a browser or a WASI runtime without a Rust toolchain. Numbers
+//! are `f64` and booleans `i32`; see `numeric` for the forms supported. Failed
+//! contracts trap.
+//!
+//! Each `defun` is exported under its name. The remaining top-level forms make up the
+//! exported `main`, which passes each result to the host's `env.print_number` or
+//! `env.print_bool`. Tests and benchmarks are left out, as in compiled Rust.
+
+use crate::ast::{grow_stack, LispExpr};
+use crate::explain::CompileError;
+use crate::numeric::{self, Arithmetic, Comparison, Expr, Function, Node, Program, Type};
+use crate::backend::Backend;
+use crate::sandbox::SandboxConfig;
+
+/// The host functions `main` prints results with
+const IMPORTS: &str = "  (import \"env\" \"print_number\" (func $print_number (param f64)))\n  (import \"env\" \"print_bool\" (func $print_bool (param i32)))\n";
+
+pub struct WatBackend;
+
+impl Backend for WatBackend {
+    fn name(&self) -> &'static str {
+        "wat"
+    }
+
+    fn extension(&self) -> &'static str {
+        "wat"
+    }
+
+    /// The module's opening and the host functions it imports
+    fn emit_prelude(&self, _sandbox: Option<&SandboxConfig>) -> String {
+        format!("(module\n{}", IMPORTS)
+    }
+
+    /// The instructions computing `expr`, which cannot bind variables, as locals are
+    /// declared by the function around them
+    fn compile_expr(&self, expr: &LispExpr) -> Result<String, CompileError> {
+        let program = numeric::lower_expression(expr, "WAT")?;
+        if !program.main.variables.is_empty() {
+            return Err(CompileError::new("L0403", format!("{} binds variables, which WebAssembly declares only in a function", expr)));
+        }
+        FunctionWriter { program: &program, function: &program.main }.node(&program.main.body[0])
+    }
+
+    fn compile_program(&self, program: &[LispExpr], _sandbox: Option<&SandboxConfig>, _seed: Option<u64>) -> Result<String, CompileError> {
+        compile_to_wat(program)
+    }
+}
+
+/// Compile an expanded program to a WAT module
+pub fn compile_to_wat(program: &[LispExpr]) -> Result<String, CompileError> {
+    let program = numeric::lower(program, "WAT")?;
+    let mut module = WatBackend.emit_prelude(None);
+    for function in &program.functions {
+        let writer = FunctionWriter { program: &program, function };
+        let parameters: String = function.variables[..function.parameter_count]
+            .iter()
+            .enumerate()
+            .map(|(index, parameter)| Ok(format!("(param {} {}) ", writer.local(index)?, wasm_type(parameter.ty))))
+            .collect::<Result<_, CompileError>>()?;
+        let header = format!(
+            "(func {} (export {:?}) {}(result {})",
+            identifier(&function.name)?,
+            function.name.as_str(),
+            parameters,
+            wasm_type(function.result)
+        );
+        module.push_str(&writer.finish(&header, &writer.body()?)?);
+    }
+
+    let writer = FunctionWriter { program: &program, function: &program.main };
+    let mut main = Vec::new();
+    for node in &program.main.body {
+        let text = writer.node(node)?;
+        main.push(match node.ty {
+            Type::Number => format!("(call $print_number {})", text),
+            Type::Bool => format!("(call $print_bool {})", text),
+            Type::Never => text,
+        });
+    }
+    module.push_str(&writer.finish("(func $main (export \"main\")", &main)?);
+    module.push_str(")\n");
+    Ok(module)
+}
+
+/// The WebAssembly value type. Code that never finishes fits any, so it gets `f64`.
+fn wasm_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Bool => "i32",
+        Type::Number | Type::Never => "f64",
+    }
+}
+
+/// A WAT identifier for a Lisp name: `$` followed by the name, whose characters must
+/// all be allowed in identifiers
+fn identifier(name: &str) -> Result<String, CompileError> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c)) {
+        Ok(format!("${}", name))
+    } else {
+        Err(format!("'{}' cannot be used as a name in WebAssembly text", name).into())
+    }
+}
+
+fn number_literal(n: f64) -> String {
+    match n {
+        _ if n.is_nan() => "nan".to_string(),
+        _ if n.is_infinite() => if n > 0.0 { "inf" } else { "-inf" }.to_string(),
+        _ => n.to_string(),
+    }
+}
+
+/// Generates one WebAssembly function
+struct FunctionWriter<'a> {
+    program: &'a Program,
+    function: &'a Function,
+}
+
+impl FunctionWriter<'_> {
+    /// `(func ...)` with `header`, the locals after the parameters, and `body`
+    fn finish(&self, header: &str, body: &[String]) -> Result<String, CompileError> {
+        let mut func = format!("  {}\n", header);
+        for (index, variable) in self.function.variables.iter().enumerate().skip(self.function.parameter_count) {
+            func.push_str(&format!("    (local {} {})\n", self.local(index)?, wasm_type(variable.ty)));
+        }
+        for instruction in body {
+            func.push_str(&format!("    {}\n", instruction));
+        }
+        func.push_str("  )\n");
+        Ok(func)
+    }
+
+    /// Parameters are named as in the source; other locals are numbered, as a
+    /// function can bind the same name more than once
+    fn local(&self, index: usize) -> Result<String, CompileError> {
+        let name = identifier(&self.function.variables[index].name)?;
+        Ok(if index < self.function.parameter_count { name } else { format!("{}.{}", name, index) })
+    }
+
+    /// The instructions of the function's body, checking its contract
+    fn body(&self) -> Result<Vec<String>, CompileError> {
+        let function = self.function;
+        let mut body = Vec::new();
+        for condition in &function.requires {
+            body.push(self.trap_unless(&condition.check)?);
+        }
+        let (last, init) = function.body.split_last().unwrap();
+        for node in init {
+            let text = self.node(node)?;
+            body.push(if node.ty == Type::Never { text } else { format!("(drop {})", text) });
+        }
+        let result = self.node(last)?;
+        match function.result_variable {
+            None => body.push(result),
+            Some(variable) => {
+                let local = self.local(variable)?;
+                body.push(format!("(local.set {} {})", local, result));
+                for condition in &function.ensures {
+                    body.push(self.trap_unless(&condition.check)?);
+                }
+                body.push(format!("(local.get {})", local));
+            }
+        }
+        Ok(body)
+    }
+
+    fn trap_unless(&self, condition: &Node) -> Result<String, CompileError> {
+        Ok(format!("(if (i32.eqz {}) (then unreachable))", self.node(condition)?))
+    }
+
+    fn node(&self, node: &Node) -> Result<String, CompileError> {
+        let mut text = String::new();
+        self.write(node, &mut text)?;
+        Ok(text)
+    }
+
+    /// Append the instructions of `node` to `out`
+    fn write(&self, node: &Node, out: &mut String) -> Result<(), CompileError> {
+        grow_stack(|| self.write_unstacked(node, out))
+    }
+
+    fn write_unstacked(&self, node: &Node, out: &mut String) -> Result<(), CompileError> {
+        match &node.expr {
+            Expr::Number(n) => out.push_str(&format!("(f64.const {})", number_literal(*n))),
+            Expr::Bool(b) => out.push_str(&format!("(i32.const {})", *b as i32)),
+            Expr::Variable(index) => out.push_str(&format!("(local.get {})", self.local(*index)?)),
+            Expr::Negate(operand) => {
+                out.push_str("(f64.neg ");
+                self.write(operand, out)?;
+                out.push(')');
+            }
+            Expr::Arithmetic(operator, left, right) => {
+                let instruction = match operator {
+                    Arithmetic::Add => "f64.add",
+                    Arithmetic::Subtract => "f64.sub",
+                    Arithmetic::Multiply => "f64.mul",
+                    Arithmetic::Divide => "f64.div",
+                };
+                self.write_operation(instruction, left, right, out)?;
+            }
+            Expr::Compare(comparison, left, right) => {
+                let instruction = match comparison {
+                    Comparison::Equal if left.ty == Type::Bool || right.ty == Type::Bool => "i32.eq",
+                    Comparison::Equal => "f64.eq",
+                    Comparison::Less => "f64.lt",
+                    Comparison::Greater => "f64.gt",
+                    Comparison::LessEqual => "f64.le",
+                    Comparison::GreaterEqual => "f64.ge",
+                };
+                self.write_operation(instruction, left, right, out)?;
+            }
+            Expr::If(condition, then_node, else_node) => {
+                match node.ty {
+                    // Neither branch finishes, so neither leaves a value for a result type to describe
+                    Type::Never => out.push_str("(if "),
+                    ty => out.push_str(&format!("(if (result {}) ", wasm_type(ty))),
+                }
+                self.write(condition, out)?;
+                out.push_str(" (then ");
+                self.write(then_node, out)?;
+                out.push_str(") (else ");
+                self.write(else_node, out)?;
+                out.push_str(if node.ty == Type::Never { ")) (unreachable)" } else { "))" });
+            }
+            Expr::Let(bindings, body) => {
+                self.write_assignments(bindings, out)?;
+                self.write(body, out)?;
+            }
+            Expr::Loop { id, bindings, body, .. } => {
+                self.write_assignments(bindings, out)?;
+                match node.ty {
+                    Type::Never => out.push_str(&format!("(loop $loop.{} ", id)),
+                    ty => out.push_str(&format!("(loop $loop.{} (result {}) ", id, wasm_type(ty))),
+                }
+                self.write(body, out)?;
+                out.push_str(if node.ty == Type::Never { ") (unreachable)" } else { ")" });
+            }
+            Expr::Again { id, bindings } => {
+                // Every value is on the stack before the first variable is reassigned
+                for (_, value) in bindings {
+                    self.write(value, out)?;
+                    out.push(' ');
+                }
+                for (variable, _) in bindings.iter().rev() {
+                    out.push_str(&format!("(local.set {}) ", self.local(*variable)?));
+                }
+                out.push_str(&format!("(br $loop.{})", id));
+            }
+            Expr::Call(index, args) => {
+                out.push_str(&format!("(call {}", identifier(&self.program.functions[*index].name)?));
+                for arg in args {
+                    out.push(' ');
+                    self.write(arg, out)?;
+                }
+                out.push(')');
+            }
+        }
+        Ok(())
+    }
+
+    /// `(instruction left right)`
+    fn write_operation(&self, instruction: &str, left: &Node, right: &Node, out: &mut String) -> Result<(), CompileError> {
+        out.push_str(&format!("({} ", instruction));
+        self.write(left, out)?;
+        out.push(' ');
+        self.write(right, out)?;
+        out.push(')');
+        Ok(())
+    }
+
+    /// `(local.set ...)` for each binding in order
+    fn write_assignments(&self, bindings: &[(usize, Node)], out: &mut String) -> Result<(), CompileError> {
+        for (variable, value) in bindings {
+            out.push_str(&format!("(local.set {} ", self.local(*variable)?));
+            self.write(value, out)?;
+            out.push_str(") ");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn wat(source: &str) -> Result<String, CompileError> {
+        compile_to_wat(&parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_compile_functions_and_main() {
+        let module = wat("(defun square (x) (* x x)) (defun positive? (x) (> x 0)) (deftest t (assert-true true)) (square 3) (positive? -1)").unwrap();
+        assert_eq!(
+            module,
+            "(module\n".to_string()
+                + IMPORTS
+                + "  (func $square (export \"square\") (param $x f64) (result f64)\n    (f64.mul (local.get $x) (local.get $x))\n  )\n"
+                + "  (func $positive? (export \"positive?\") (param $x f64) (result i32)\n    (f64.gt (local.get $x) (f64.const 0))\n  )\n"
+                + "  (func $main (export \"main\")\n    (call $print_number (call $square (f64.const 3)))\n    (call $print_bool (call $positive? (f64.const -1)))\n  )\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_control_flow() {
+        let module = wat("(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (let loop ((i 0) (acc 0)) (if (> i 10) acc (loop (+ i 1) (+ acc i))))").unwrap();
+        assert!(module.contains("(if (result f64) (f64.le (local.get $n) (f64.const 1)) (then (f64.const 1)) (else (f64.mul (local.get $n) (call $fact (f64.sub (local.get $n) (f64.const 1))))))"));
+        assert!(module.contains("(local $i.0 f64)\n    (local $acc.1 f64)\n"));
+        assert!(module.contains("(loop $loop.0 (result f64) (if (result f64) (f64.gt (local.get $i.0) (f64.const 10)) (then (local.get $acc.1)) (else (f64.add (local.get $i.0) (f64.const 1)) (f64.add (local.get $acc.1) (local.get $i.0)) (local.set $acc.1) (local.set $i.0) (br $loop.0))))"));
+
+        let module = wat("(defun even? (n) (if (= n 0) true (odd? (- n 1)))) (defun odd? (n) (if (= n 0) false (even? (- n 1)))) (let* ((x 2) (y (+ x 1))) (* x y))").unwrap();
+        assert!(module.contains("(param $n f64) (result i32)"));
+        assert!(module.contains("(call $print_number (local.set $x.0 (f64.const 2)) (local.set $y.1 (f64.add (local.get $x.0) (f64.const 1))) (f64.mul (local.get $x.0) (local.get $y.1)))"));
+    }
+
+    #[test]
+    fn test_compile_contracts() {
+        let module = wat("(defun safe-sqrt ((x : number)) : number (declare (requires (>= x 0)) (ensures (>= result 0))) (/ x 2))").unwrap();
+        assert!(module.contains(
+            "    (if (i32.eqz (f64.ge (local.get $x) (f64.const 0))) (then unreachable))\n    (local.set $result.1 (f64.div (local.get $x) (f64.const 2)))\n"
+        ));
+        assert!(module.contains("(if (i32.eqz (f64.ge (local.get $result.1) (f64.const 0))) (then unreachable))\n    (local.get $result.1)\n"));
+    }
+
+    #[test]
+    fn test_unsupported_programs() {
+        assert!(wat("(+ 1 \"two\")").unwrap_err().message().contains("is not supported by the WAT backend"));
+        assert_eq!(wat("(defun f (x) : bool x)").unwrap_err().message(), "'f' is declared to return a boolean, but its body returns a number");
+        assert_eq!(wat("(defun f (x) (let ((café 1)) x))").unwrap_err().message(), "'café' cannot be used as a name in WebAssembly text");
+    }
+
+    #[test]
+    fn test_compile_deeply_nested_expression() {
+        let depth = 100_000;
+        let module = wat(&format!("{}1{}", "(+ 1 ".repeat(depth), ")".repeat(depth))).unwrap();
+        assert!(module.contains("    (call $print_number (f64.add (f64.const 1) (f64.add (f64.const 1) "));
+    }
+}